@@ -0,0 +1,69 @@
+//! compares the default file reads against io_uring (server.io = "uring")
+//! by streaming a distfile to many concurrent clients
+//!
+//! cargo bench --features uring --bench file_io
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use portcache::uring::UringReader;
+use std::path::Path;
+use tokio::io::BufReader;
+
+/// size of the served file
+const FILE_SIZE: usize = 32 * 1024 * 1024;
+
+/// clients reading the file at once
+const CLIENTS: usize = 16;
+
+/// stream the file to every client with tokio's blocking thread pool reads
+async fn tokio_reads(path: &Path) {
+    let clients = (0..CLIENTS).map(|_| async {
+        let file = tokio::fs::File::open(path).await.unwrap();
+        let mut body = BufReader::new(file);
+        tokio::io::copy_buf(&mut body, &mut tokio::io::sink())
+            .await
+            .unwrap()
+    });
+    for read in futures::future::join_all(clients).await {
+        assert_eq!(read, FILE_SIZE as u64);
+    }
+}
+
+/// stream the file to every client through the io_uring thread
+async fn uring_reads(uring: &UringReader, path: &Path) {
+    let clients = (0..CLIENTS).map(|_| async {
+        let mut body = uring.read(std::fs::File::open(path).unwrap(), 0);
+        tokio::io::copy_buf(&mut body, &mut tokio::io::sink())
+            .await
+            .unwrap()
+    });
+    for read in futures::future::join_all(clients).await {
+        assert_eq!(read, FILE_SIZE as u64);
+    }
+}
+
+fn file_io(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("bench-1.0.tar.xz");
+    let content: Vec<u8> = (0..FILE_SIZE).map(|_| fastrand::u8(..)).collect();
+    std::fs::write(&path, content).unwrap();
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let uring = UringReader::start().expect("io_uring is unavailable");
+
+    let mut group = c.benchmark_group("serve");
+    group.throughput(Throughput::Bytes((FILE_SIZE * CLIENTS) as u64));
+    group.sample_size(20);
+    group.bench_function("default", |b| {
+        b.to_async(&runtime).iter(|| tokio_reads(&path))
+    });
+    group.bench_function("uring", |b| {
+        b.to_async(&runtime).iter(|| uring_reads(&uring, &path))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, file_io);
+criterion_main!(benches);
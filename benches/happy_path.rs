@@ -0,0 +1,172 @@
+//! throughput of the hot paths every request and sync cycle goes through:
+//! Manifest parsing, hash directory names of distfiles and batched database inserts
+//!
+//! cargo bench --bench happy_path
+//! compare a redesign against the current state with criterion baselines:
+//! cargo bench --bench happy_path -- --save-baseline main
+//! cargo bench --bench happy_path -- --baseline main
+
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use futures::{StreamExt, pin_mut};
+use portcache::config::{Config, LayoutCheck};
+use portcache::manifest_walker::{ManifestDepth, ManifestEntry, ManifestWalker};
+use portcache::repo_db::RepoDB;
+use portcache::utils;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// packages of the synthetic tree
+const PACKAGES: usize = 500;
+
+/// DIST lines per Manifest of the synthetic tree
+const DISTFILES: usize = 8;
+
+/// a DIST line like the ones in ::gentoo
+fn dist_line(file: &str) -> String {
+    let checksum = || -> String { (0..128).map(|_| fastrand::alphanumeric()).collect() };
+    format!(
+        "DIST {} {} BLAKE2B {} SHA512 {}",
+        file,
+        fastrand::u64(1024..1 << 30),
+        checksum(),
+        checksum()
+    )
+}
+
+/// name of a distfile in the synthetic tree
+fn distfile(package: usize, version: usize) -> String {
+    format!("pkg{}-{}.{}.tar.xz", package, version / 4, version % 4)
+}
+
+/// write a repo with PACKAGES packages of DISTFILES distfiles each
+fn synthetic_tree(root: &Path) {
+    std::fs::create_dir_all(root.join("metadata")).unwrap();
+    std::fs::write(root.join("metadata/layout.conf"), "masters = \n").unwrap();
+    for package in 0..PACKAGES {
+        let dir = root
+            .join(format!("cat-{}", package % 20))
+            .join(format!("pkg{}", package));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut manifest: String = (0..DISTFILES)
+            .map(|version| dist_line(&distfile(package, version)) + "\n")
+            .collect();
+        manifest.push_str("EBUILD pkg-1.0.ebuild 1234 BLAKE2B abc SHA512 def\n");
+        std::fs::write(dir.join("Manifest"), manifest).unwrap();
+    }
+}
+
+/// a config with the storage at location
+fn config(location: &Path) -> Config {
+    let path = location.join("portcache.toml");
+    std::fs::write(
+        &path,
+        format!(
+            "[storage]\nlocation = \"{}\"\n\n[repo]\nrepos = []\n",
+            location.to_string_lossy()
+        ),
+    )
+    .unwrap();
+    Config::parse(Some(path.to_string_lossy().to_string())).unwrap()
+}
+
+fn manifest_parsing(c: &mut Criterion) {
+    let origin = PathBuf::from("/var/db/repos/gentoo/app-misc/hello/Manifest");
+    let lines: Vec<String> = (0..1000)
+        .map(|version| dist_line(&distfile(0, version)))
+        .collect();
+    let bytes: usize = lines.iter().map(|line| line.len() + 1).sum();
+
+    let mut group = c.benchmark_group("manifest");
+    group.throughput(Throughput::Bytes(bytes as u64));
+    group.bench_function("parse_lines", |b| {
+        b.iter(|| {
+            for line in &lines {
+                ManifestEntry::parse(&origin, line).unwrap().unwrap();
+            }
+        })
+    });
+
+    let tree = TempDir::new().unwrap();
+    synthetic_tree(tree.path());
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    group.throughput(Throughput::Elements((PACKAGES * DISTFILES) as u64));
+    group.sample_size(20);
+    group.bench_function("walk_tree", |b| {
+        b.to_async(&runtime).iter(|| async {
+            let mut walker = ManifestWalker::new(
+                tree.path().to_path_buf(),
+                LayoutCheck::Require,
+                ManifestDepth::Exactly(3),
+            )
+            .unwrap();
+            let entries = walker.entries();
+            pin_mut!(entries);
+            let mut found = 0;
+            while entries.next().await.is_some() {
+                found += 1;
+            }
+            assert_eq!(found, PACKAGES * DISTFILES);
+        })
+    });
+    group.finish();
+}
+
+fn filename_hashing(c: &mut Criterion) {
+    let files: Vec<String> = (0..1000).map(|version| distfile(0, version)).collect();
+
+    let mut group = c.benchmark_group("filename_hash");
+    group.throughput(Throughput::Elements(files.len() as u64));
+    for bits in [8, 16] {
+        group.bench_with_input(BenchmarkId::from_parameter(bits), &bits, |b, &bits| {
+            b.iter(|| {
+                for file in &files {
+                    utils::filename_hash_dir(file, bits);
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn db_inserts(c: &mut Criterion) {
+    let storage = TempDir::new().unwrap();
+    let repo_db = &RepoDB::new(&config(storage.path())).unwrap();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let origin = PathBuf::from("/var/db/repos/gentoo/app-misc/hello/Manifest");
+
+    let mut group = c.benchmark_group("db_insert");
+    // new names every iteration so rows get inserted instead of only touched
+    let mut next = 0;
+    for batch in [1, 100, 1000] {
+        group.throughput(Throughput::Elements(batch as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(batch), &batch, |b, &batch| {
+            b.to_async(&runtime).iter_batched(
+                || {
+                    (0..batch)
+                        .map(|_| {
+                            next += 1;
+                            let line = dist_line(&format!("bench-{}.tar.gz", next));
+                            ManifestEntry::parse(&origin, &line).unwrap().unwrap()
+                        })
+                        .collect::<Vec<_>>()
+                },
+                |entries| async move {
+                    repo_db
+                        .insert_manifest_entries("gentoo", &entries)
+                        .await
+                        .unwrap();
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, manifest_parsing, filename_hashing, db_inserts);
+criterion_main!(benches);
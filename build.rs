@@ -2,6 +2,7 @@ use std::env;
 use std::fs::{File, read_to_string};
 use std::io::Write;
 use std::path::Path;
+use std::process::Command;
 
 fn main() {
     let out_dir = env::var("OUT_DIR").unwrap();
@@ -14,16 +15,63 @@ fn main() {
     // read SRC_URI helper scrip to variable
     let src_uri_helper_py = read_to_string("meta/src_uri_helper.py").unwrap();
 
+    // commit the binary is built from
+    // packagers building from a tarball can pass it in
+    let git_commit = env::var("PORTCACHE_GIT_COMMIT")
+        .ok()
+        .or_else(|| {
+            let output = Command::new("git")
+                .args(["rev-parse", "HEAD"])
+                .output()
+                .ok()?;
+            output
+                .status
+                .success()
+                .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        })
+        .unwrap_or("unknown".into());
+
+    // cargo features enabled for this build
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| {
+            key.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+    let features: Vec<String> = features
+        .iter()
+        .map(|feature| format!("\"{}\"", feature.escape_default()))
+        .collect();
+
     // Write the variables to the generated file.
     write!(
         f,
-        "pub const PORTAGE_PYTHON: &str = \"{}\";\npub const SRC_URI_HELPER_PY: &str = \"{}\";\n",
+        "/// python interpreter used for portage integration\n\
+         pub const PORTAGE_PYTHON: &str = \"{}\";\n\
+         /// helper script extracting SRC_URIs from ebuilds\n\
+         pub const SRC_URI_HELPER_PY: &str = \"{}\";\n\
+         /// git commit the binary was built from\n\
+         pub const GIT_COMMIT: &str = \"{}\";\n\
+         /// cargo features enabled at build time\n\
+         pub const FEATURES: &[&str] = &[{}];\n",
         portage_python.escape_default(),
-        src_uri_helper_py.escape_default()
+        src_uri_helper_py.escape_default(),
+        git_commit.escape_default(),
+        features.join(", ")
     )
     .unwrap();
 
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-changed=meta/src_uri_helper.py");
     println!("cargo:rerun-if-env-changed=PORTAGE_PYTHON");
+    println!("cargo:rerun-if-env-changed=PORTCACHE_GIT_COMMIT");
+
+    // rebuild when HEAD moves
+    if let Ok(head) = read_to_string(".git/HEAD") {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        if let Some(reference) = head.trim().strip_prefix("ref: ") {
+            println!("cargo:rerun-if-changed=.git/{}", reference);
+        }
+    }
 }
@@ -0,0 +1,305 @@
+//! end-to-end load test: simulated clients requesting distfiles from a portcache
+//! backed by a mock mirror, the first request of every distfile fetches it
+//!
+//! cargo run --release --example load_test -- --clients 64 --output run.json > /dev/null
+//! cargo run --release --example load_test -- --clients 64 --baseline run.json > /dev/null
+//!
+//! the server logs to stdout, the summary goes to stderr
+//! with --baseline the run fails if it got slower than the baseline by more than --tolerance
+
+use clap::Parser;
+use portcache::app::{self, Deps};
+use portcache::config::Config;
+use portcache::utils;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use wiremock::matchers::{method, path, path_regex};
+use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+#[derive(Parser)]
+struct Args {
+    /// concurrent clients
+    #[arg(long, default_value_t = 32)]
+    clients: usize,
+
+    /// requests every client sends one after another
+    #[arg(long, default_value_t = 200)]
+    requests: usize,
+
+    /// distinct distfiles requested
+    #[arg(long, default_value_t = 100)]
+    files: usize,
+
+    /// size of every distfile in KiB
+    #[arg(long, default_value_t = 1024)]
+    size_kib: usize,
+
+    /// latency of the mock mirror in milliseconds
+    #[arg(long, default_value_t = 50)]
+    mirror_delay: u64,
+
+    /// write the summary as JSON to this file
+    #[arg(long)]
+    output: Option<PathBuf>,
+
+    /// summary of an earlier run to compare against
+    #[arg(long)]
+    baseline: Option<PathBuf>,
+
+    /// percent requests per second and p99 latency may be worse than the baseline
+    #[arg(long, default_value_t = 10.0)]
+    tolerance: f64,
+}
+
+/// outcome of a run
+#[derive(Serialize, Deserialize)]
+struct Summary {
+    /// requests sent
+    requests: usize,
+
+    /// requests not answered with the distfile
+    errors: usize,
+
+    /// wall clock time of the run
+    seconds: f64,
+
+    /// requests answered per second
+    requests_per_sec: f64,
+
+    /// bytes served per second in MiB
+    mib_per_sec: f64,
+
+    /// latency percentiles in milliseconds
+    p50_ms: f64,
+    p90_ms: f64,
+    p99_ms: f64,
+    max_ms: f64,
+}
+
+/// mock mirror answering every distfile request with the same content
+struct Distfile {
+    /// content of every distfile
+    content: Arc<Vec<u8>>,
+
+    /// latency of every answer
+    delay: Duration,
+}
+
+impl Respond for Distfile {
+    fn respond(&self, _: &Request) -> ResponseTemplate {
+        ResponseTemplate::new(200)
+            .set_body_bytes(self.content.as_slice())
+            .set_delay(self.delay)
+    }
+}
+
+/// name of a requested distfile
+fn distfile(index: usize) -> String {
+    format!("load-{}-1.0.tar.xz", index)
+}
+
+/// start a mirror serving every distfile after delay
+async fn mock_mirror(content: Arc<Vec<u8>>, delay: Duration) -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/distfiles/layout.conf"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_string("[structure]\n0=filename-hash BLAKE2B 8\n"),
+        )
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/distfiles/[0-9a-f]{2}/load-"))
+        .respond_with(Distfile { content, delay })
+        .mount(&server)
+        .await;
+    server
+}
+
+/// start portcache against the mirror on a free port
+/// returns its base url
+async fn start_portcache(storage: &TempDir, mirror: &MockServer) -> String {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let config_path = storage.path().join("portcache.toml");
+    std::fs::write(
+        &config_path,
+        format!(
+            "[storage]\nlocation = \"{}\"\n\n\
+             [server]\naddress = \"127.0.0.1\"\nport = {}\n\n\
+             [repo]\nrepos = []\n\n\
+             [fetcher]\nmirrors = [\"{}\"]\n",
+            storage.path().to_string_lossy(),
+            port,
+            mirror.uri()
+        ),
+    )
+    .unwrap();
+    let config = Config::parse(Some(config_path.to_string_lossy().to_string())).unwrap();
+
+    let deps = Deps::new(&config).await.unwrap();
+    let rocket = app::build_rocket(&config, deps);
+    let figment = rocket
+        .figment()
+        .clone()
+        .merge(("log_level", rocket::config::LogLevel::Off));
+    rocket::tokio::spawn(rocket.configure(figment).launch());
+
+    let base = format!("http://127.0.0.1:{}", port);
+    let client = reqwest::Client::new();
+    for _ in 0..100 {
+        let ready = client
+            .get(format!("{}/distfiles/layout.conf", base))
+            .send()
+            .await
+            .is_ok_and(|response| response.status().is_success());
+        if ready {
+            return base;
+        }
+        rocket::tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("portcache didn't come up on {}", base);
+}
+
+/// send requests one after another like a single emerge fetching distfiles
+/// returns the latency of every request and whether it got the distfile
+async fn client(
+    http: reqwest::Client,
+    base: Arc<String>,
+    requests: usize,
+    files: usize,
+    size: usize,
+) -> Vec<(Duration, bool)> {
+    let mut results = Vec::with_capacity(requests);
+    for _ in 0..requests {
+        let file = distfile(fastrand::usize(..files));
+        let url = format!(
+            "{}/distfiles/{}/{}",
+            base,
+            utils::filename_hash_dir(&file, 8),
+            file
+        );
+        let start = Instant::now();
+        let ok = match http.get(&url).send().await {
+            Ok(response) if response.status().is_success() => {
+                response.bytes().await.is_ok_and(|body| body.len() == size)
+            }
+            _ => false,
+        };
+        results.push((start.elapsed(), ok));
+    }
+    results
+}
+
+/// latency below which the given fraction of requests finished in milliseconds
+fn percentile(sorted: &[Duration], fraction: f64) -> f64 {
+    let index = ((sorted.len() as f64 * fraction).ceil() as usize).clamp(1, sorted.len()) - 1;
+    sorted[index].as_secs_f64() * 1000.0
+}
+
+/// compare a run against a baseline, returns the regressions
+fn regressions(summary: &Summary, baseline: &Summary, tolerance: f64) -> Vec<String> {
+    let factor = tolerance / 100.0;
+    let mut found = Vec::new();
+    if summary.requests_per_sec < baseline.requests_per_sec * (1.0 - factor) {
+        found.push(format!(
+            "requests/s dropped from {:.1} to {:.1}",
+            baseline.requests_per_sec, summary.requests_per_sec
+        ));
+    }
+    if summary.p99_ms > baseline.p99_ms * (1.0 + factor) {
+        found.push(format!(
+            "p99 latency rose from {:.1}ms to {:.1}ms",
+            baseline.p99_ms, summary.p99_ms
+        ));
+    }
+    if summary.errors > baseline.errors {
+        found.push(format!(
+            "errors rose from {} to {}",
+            baseline.errors, summary.errors
+        ));
+    }
+    found
+}
+
+#[rocket::main]
+async fn main() {
+    let args = Args::parse();
+    let size = args.size_kib * 1024;
+    let files = args.files.max(1);
+
+    let content: Vec<u8> = (0..size).map(|_| fastrand::u8(..)).collect();
+    let mirror = mock_mirror(Arc::new(content), Duration::from_millis(args.mirror_delay)).await;
+    let storage = TempDir::new().unwrap();
+    let base = Arc::new(start_portcache(&storage, &mirror).await);
+
+    let http = reqwest::Client::new();
+    let start = Instant::now();
+    let clients: Vec<_> = (0..args.clients)
+        .map(|_| {
+            rocket::tokio::spawn(client(
+                http.clone(),
+                base.clone(),
+                args.requests,
+                files,
+                size,
+            ))
+        })
+        .collect();
+    let mut results = Vec::new();
+    for client in clients {
+        results.extend(client.await.unwrap());
+    }
+    let seconds = start.elapsed().as_secs_f64();
+
+    let mut latencies: Vec<Duration> = results.iter().map(|(latency, _)| *latency).collect();
+    latencies.sort_unstable();
+    let served = results.iter().filter(|(_, ok)| *ok).count();
+    let summary = Summary {
+        requests: results.len(),
+        errors: results.len() - served,
+        seconds,
+        requests_per_sec: served as f64 / seconds,
+        mib_per_sec: (served * size) as f64 / seconds / (1024.0 * 1024.0),
+        p50_ms: percentile(&latencies, 0.5),
+        p90_ms: percentile(&latencies, 0.9),
+        p99_ms: percentile(&latencies, 0.99),
+        max_ms: percentile(&latencies, 1.0),
+    };
+
+    eprintln!(
+        "{} requests by {} clients in {:.2}s, {} errors",
+        summary.requests, args.clients, summary.seconds, summary.errors
+    );
+    eprintln!(
+        "{:.1} requests/s, {:.1} MiB/s",
+        summary.requests_per_sec, summary.mib_per_sec
+    );
+    eprintln!(
+        "latency p50 {:.1}ms, p90 {:.1}ms, p99 {:.1}ms, max {:.1}ms",
+        summary.p50_ms, summary.p90_ms, summary.p99_ms, summary.max_ms
+    );
+
+    if let Some(output) = &args.output {
+        std::fs::write(output, serde_json::to_string_pretty(&summary).unwrap()).unwrap();
+    }
+
+    if let Some(baseline) = &args.baseline {
+        let baseline: Summary =
+            serde_json::from_str(&std::fs::read_to_string(baseline).unwrap()).unwrap();
+        let found = regressions(&summary, &baseline, args.tolerance);
+        if !found.is_empty() {
+            for regression in found {
+                eprintln!("Regression: {}", regression);
+            }
+            std::process::exit(1);
+        }
+        eprintln!("No regressions against the baseline");
+    }
+}
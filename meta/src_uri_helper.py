@@ -5,49 +5,113 @@ import os, sys, json
 
 # Small helper to get all SRC_URIS of an ebuild
 # return a JSON object like:
-#   "file": ["urls", ...]
+#   "file": [{"uri": "url", "use": ["flag", "!flag"]}, ...]
+# where "use" lists the USE conditionals gating the url
 #
 # Usage:
 # src_uri_helper.py path/to/ebuild
+# src_uri_helper.py --batch
+#
+# In batch mode ebuild paths are read from stdin line by line
+# and every ebuild gets answered with a single line
+#   portcache:{"src_uri": {"file": [{"uri": "url", "use": []}, ...]}}
+# or
+#   portcache:{"error": "message"}
+# Lines without the prefix are noise from portage and to be ignored
+
+# portdbapi per repo, setting one up is expensive
+dbapis = {}
+
+# thirdpartymirrors only need to be read once
+thirdpartymirrors = None
+
+def uri_conditions(src_uri):
+    # map urls in a SRC_URI string to the USE conditionals gating them
+    # e.g. "doc? ( https://a !test? ( https://b ) )"
+    #   -> {"https://a": ["doc"], "https://b": ["doc", "!test"]}
+    # urls listed more than once keep their first conditions
+    # unless they also appear unconditionally
+    conditions = {}
+    groups = []
+    pending = None
+    for token in src_uri.split():
+        if token.endswith("?"):
+            pending = token[:-1]
+        elif token == "(":
+            groups.append(pending)
+            pending = None
+        elif token == ")":
+            if groups:
+                groups.pop()
+        elif "://" in token:
+            gates = [flag for flag in groups if flag is not None]
+            if token not in conditions or not gates:
+                conditions[token] = gates
+
+    return conditions
+
+def fetchmap(ebuild):
+    global thirdpartymirrors
 
-def main():
     # parse ebuild path
-    ebuild = sys.argv[1]
     parts = ebuild.split("/")
     repo = "/".join(parts[:-3])
     cpv = parts[-3] + "/" + parts[-1].removesuffix(".ebuild")
 
     # get fetchmap from dbapi
-    os.environ["PORTDIR_OVERLAY"] = repo
-    dbapi = portdbapi()
-    dbapi._set_porttrees([repo])
-    fetchmap = dbapi.getFetchMap(cpv)
+    if repo not in dbapis:
+        os.environ["PORTDIR_OVERLAY"] = repo
+        dbapi = portdbapi()
+        dbapi._set_porttrees([repo])
+        dbapis[repo] = dbapi
+    fetchmap = dbapis[repo].getFetchMap(cpv)
+    conditions = uri_conditions(dbapis[repo].aux_get(cpv, ["SRC_URI"])[0])
 
     # we need to manually expand mirror:// urls
     # TODO: check if this actually gets mirrors from PORTDIR_OVERLAY
-    thirdpartymirrors = econfig().thirdpartymirrors()
+    if thirdpartymirrors is None:
+        thirdpartymirrors = econfig().thirdpartymirrors()
     expanded_fetchmap = {}
     for file, uris in fetchmap.items():
         expanded_fetchmap[file] = []
         for uri in uris:
+            use = conditions.get(uri, [])
             if uri.startswith("mirror://"):
                 mirror, path = uri.removeprefix("mirror://").split("/", 1)
                 try:
-                    expanded_fetchmap[file].extend([uri + "/" + path for uri in thirdpartymirrors[mirror]])
+                    expanded_fetchmap[file].extend([{"uri": uri + "/" + path, "use": use} for uri in thirdpartymirrors[mirror]])
                 except KeyError as e:
                     print(f"Error resolving mirror uri: {str(e)}", file=sys.stderr)
             else:
-                expanded_fetchmap[file].append(uri)
+                expanded_fetchmap[file].append({"uri": uri, "use": use})
 
+    return expanded_fetchmap
+
+def main():
     # return as json
-    print(json.dumps(expanded_fetchmap))
+    print(json.dumps(fetchmap(sys.argv[1])))
+
+def batch():
+    for line in sys.stdin:
+        ebuild = line.rstrip("\n")
+        if not ebuild:
+            continue
+
+        try:
+            result = {"src_uri": fetchmap(ebuild)}
+        except Exception as e:
+            result = {"error": f"Error parsing ebuild {ebuild}: {str(e)}"}
+
+        print("portcache:" + json.dumps(result), flush=True)
 
 if __name__ == "__main__":
     if len(sys.argv) != 2:
-        print(f"Usage: {sys.argv[0]} <path to ebuild>", file=sys.stderr)
+        print(f"Usage: {sys.argv[0]} <path to ebuild> | --batch", file=sys.stderr)
         exit(1)
+    if sys.argv[1] == "--batch":
+        batch()
+        exit(0)
     try:
         main()
     except Exception as e:
         print(f"Error parsing ebuild {sys.argv[1]}: {str(e)}", file=sys.stderr)
-
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time;
+
+use crate::config::Config;
+use crate::repo_db::RepoDB;
+use crate::utils;
+
+/// distinct blobs with pending access times that trigger a flush before the interval is up
+const MAX_PENDING: usize = 10_000;
+
+/// access times of cached blobs kept in the database
+/// so eviction doesn't rely on the storage being mounted with atime updates
+/// cache hits are collected in memory and written in one transaction per flush_interval,
+/// times in the database lag behind by at most that much
+pub struct AccessLog {
+    /// latest access of each blob not written yet as unix timestamp
+    pending: std::sync::Mutex<HashMap<String, u64>>,
+
+    /// repo database the access times are written to
+    repo_db: Arc<RepoDB>,
+
+    /// interval in which pending access times get written
+    flush_interval: Duration,
+
+    /// notified once MAX_PENDING blobs are pending
+    full: Notify,
+}
+
+impl AccessLog {
+    /// create an AccessLog from config
+    ///
+    /// @param config   a reference to Config
+    /// @param repo_db  repo database the access times are written to
+    pub fn new(config: &Config, repo_db: Arc<RepoDB>) -> Self {
+        Self {
+            pending: std::sync::Mutex::new(HashMap::new()),
+            repo_db,
+            flush_interval: config.storage.access_flush_interval,
+            full: Notify::new(),
+        }
+    }
+
+    /// note an access of a blob
+    ///
+    /// @param file  name of the blob
+    pub fn note(&self, file: &str) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.insert(file.to_string(), utils::unix_time());
+        if pending.len() >= MAX_PENDING {
+            self.full.notify_one();
+        }
+    }
+
+    /// forget the access time of a removed blob
+    ///
+    /// @param file  name of the blob
+    pub async fn forget(&self, file: &str) {
+        self.pending.lock().unwrap().remove(file);
+        if let Err(e) = self.repo_db.remove_access_time(file).await {
+            eprintln!("Failed to forget access time of {}: {}", file, e);
+        }
+    }
+
+    /// latest known access of every blob as unix timestamp
+    /// pending accesses included
+    pub async fn times(&self) -> Result<HashMap<String, u64>, String> {
+        let mut times = self
+            .repo_db
+            .get_access_times()
+            .await
+            .map_err(|e| format!("Failed to query access times: {}", e))?;
+        for (file, accessed) in self.pending.lock().unwrap().iter() {
+            let known = times.entry(file.clone()).or_default();
+            *known = (*known).max(*accessed);
+        }
+        Ok(times)
+    }
+
+    /// write pending access times to the database
+    /// returns the number of blobs written
+    /// on failure they stay pending for the next flush
+    pub async fn flush(&self) -> Result<usize, String> {
+        let batch: Vec<(String, u64)> = self.pending.lock().unwrap().drain().collect();
+        if batch.is_empty() {
+            return Ok(0);
+        }
+
+        if let Err(e) = self.repo_db.set_access_times(&batch).await {
+            let mut pending = self.pending.lock().unwrap();
+            for (file, accessed) in batch {
+                let known = pending.entry(file).or_default();
+                *known = (*known).max(accessed);
+            }
+            return Err(format!("Failed to write access times: {}", e));
+        }
+
+        Ok(batch.len())
+    }
+
+    /// flush every flush_interval or once too many blobs are pending
+    /// this is expected to be called from a tokio::spawn
+    pub async fn start(self: Arc<Self>) {
+        let mut interval = time::interval(self.flush_interval);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => (),
+                _ = self.full.notified() => interval.reset(),
+            }
+            if let Err(e) = self.flush().await {
+                eprintln!("{}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,365 @@
+use rocket::http::{ContentType, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::{FromForm, State, delete, get, post};
+
+use crate::app::SharedData;
+use crate::distfile_name::{DistfileName, InvalidName};
+use crate::evictor::EvictionTarget;
+use crate::repo_db::DownloadFilter;
+use crate::stats::csv_field;
+use crate::telemetry::RequestTrace;
+
+/// downloads listed per page of the download history unless asked for fewer
+const DOWNLOADS_PAGE: u64 = 100;
+
+/// most downloads listed per page of the download history
+const DOWNLOADS_PAGE_MAX: u64 = 1000;
+
+/// request guard for the admin API
+/// requires "Authorization: Bearer <admin.token>"
+/// the admin API is unavailable while no token is configured
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = match req
+            .rocket()
+            .state::<SharedData>()
+            .and_then(|shared| shared.admin_token.as_ref())
+        {
+            Some(token) => token,
+            None => return Outcome::Error((Status::NotFound, ())),
+        };
+
+        let given = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        match given {
+            Some(given) if constant_time_eq(given.as_bytes(), token.as_bytes()) => {
+                Outcome::Success(Admin)
+            }
+            _ => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// compare secrets without leaking the position of the first mismatch
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// mark a cached blob stale without deleting it
+/// the old blob keeps getting served until a refetch succeeds
+/// a checksum recorded on its first download is replaced by the one of the refetch
+#[post("/api/v1/admin/stale/<file>")]
+pub(crate) async fn mark_stale(
+    _admin: Admin,
+    file: Result<DistfileName, InvalidName>,
+    shared: &State<SharedData>,
+) -> Status {
+    let file = match file {
+        Ok(file) => file,
+        Err(_) => return Status::BadRequest,
+    };
+
+    match shared.blob_storage.mark_stale(file.as_str()).await {
+        Ok(true) => {
+            println!("Marked {} stale", file);
+            Status::NoContent
+        }
+        Ok(false) => Status::NotFound,
+        Err(e) => {
+            eprintln!("Failed to mark {} stale: {}", file, e);
+            Status::InternalServerError
+        }
+    }
+}
+
+/// bytes served per client subnet in the current quota window
+#[get("/api/v1/admin/usage")]
+pub(crate) async fn usage(
+    _admin: Admin,
+    shared: &State<SharedData>,
+) -> Result<(ContentType, String), Status> {
+    let report = shared.quota.report().await.map_err(|e| {
+        eprintln!("Failed to collect usage report: {}", e);
+        Status::InternalServerError
+    })?;
+
+    let clients: Vec<serde_json::Value> = report
+        .clients
+        .iter()
+        .map(|(subnet, bytes)| serde_json::json!({ "subnet": subnet, "bytes": bytes }))
+        .collect();
+    let body = serde_json::json!({
+        "window_start": report.window_start,
+        "window": report.window,
+        "limit": report.limit,
+        "clients": clients,
+    });
+
+    Ok((ContentType::JSON, body.to_string()))
+}
+
+/// issued API keys with their usage in the current quota window
+#[get("/api/v1/admin/keys")]
+pub(crate) async fn keys(
+    _admin: Admin,
+    shared: &State<SharedData>,
+) -> Result<(ContentType, String), Status> {
+    let report = shared.quota.key_report().await.map_err(|e| {
+        eprintln!("Failed to collect key usage report: {}", e);
+        Status::InternalServerError
+    })?;
+
+    let keys: Vec<serde_json::Value> = report
+        .keys
+        .iter()
+        .map(|(key, limit, usage)| {
+            serde_json::json!({
+                "name": key.name,
+                "limit": limit,
+                "created": key.created,
+                "last_used": key.last_used,
+                "bytes": usage.bytes,
+                "requests": usage.requests,
+            })
+        })
+        .collect();
+    let body = serde_json::json!({
+        "window_start": report.window_start,
+        "window": report.window,
+        "keys": keys,
+    });
+
+    Ok((ContentType::JSON, body.to_string()))
+}
+
+/// evict blobs right away ignoring the configured windows
+/// either down to target_size bytes or until target_free bytes are available
+/// defaults to storage.max_size when neither is given
+/// removals get logged with the id of the request
+#[post("/api/v1/admin/gc?<target_size>&<target_free>")]
+pub(crate) async fn gc(
+    _admin: Admin,
+    trace: RequestTrace,
+    target_size: Option<u64>,
+    target_free: Option<u64>,
+    shared: &State<SharedData>,
+) -> Result<(ContentType, String), Status> {
+    let target = match (target_size, target_free, shared.evictor.max_size()) {
+        (Some(size), None, _) => EvictionTarget::Size(size),
+        (None, Some(free), _) => EvictionTarget::Free(free),
+        (None, None, Some(max_size)) => EvictionTarget::Size(max_size),
+        _ => return Err(Status::BadRequest),
+    };
+
+    let report = trace
+        .within(shared.evictor.run_to(target))
+        .await
+        .map_err(|e| {
+            eprintln!("Manual eviction failed: {}", e);
+            Status::InternalServerError
+        })?;
+
+    println!(
+        "Manual eviction removed {} blobs freeing {} bytes",
+        report.removed, report.freed
+    );
+    let body = serde_json::json!({
+        "removed": report.removed,
+        "freed": report.freed,
+        "remaining": report.remaining,
+    });
+
+    Ok((ContentType::JSON, body.to_string()))
+}
+
+/// ebuilds the portage helper failed on
+#[get("/api/v1/admin/parse_failures")]
+pub(crate) async fn parse_failures(
+    _admin: Admin,
+    shared: &State<SharedData>,
+) -> Result<(ContentType, String), Status> {
+    let failures = shared.repo_db.get_parse_failures().await.map_err(|e| {
+        eprintln!("Failed to collect parse failures: {}", e);
+        Status::InternalServerError
+    })?;
+
+    let failures: Vec<serde_json::Value> = failures
+        .iter()
+        .map(|failure| {
+            serde_json::json!({
+                "path": failure.path,
+                "error": failure.error,
+                "attempts": failure.attempts,
+                "last_attempt": failure.last_attempt,
+                "retry_at": failure.retry_at(),
+            })
+        })
+        .collect();
+    let body = serde_json::json!({ "failures": failures });
+
+    Ok((ContentType::JSON, body.to_string()))
+}
+
+/// filters and page of the download history
+#[derive(FromForm)]
+pub(crate) struct DownloadQuery {
+    /// only files containing this
+    file: Option<String>,
+
+    /// only "fetched", "imported" or "failed" downloads
+    outcome: Option<String>,
+
+    /// only downloads started at or after this unix timestamp
+    since: Option<u64>,
+
+    /// only downloads started before this unix timestamp
+    until: Option<u64>,
+
+    /// page size, at most DOWNLOADS_PAGE_MAX
+    limit: Option<u64>,
+
+    /// matching downloads to skip
+    offset: Option<u64>,
+}
+
+/// upstream downloads of the last fetcher.history_retention, newest first
+/// filtered by file name part, outcome and start time, paged via limit and offset
+#[get("/api/v1/admin/downloads?<query..>")]
+pub(crate) async fn downloads(
+    _admin: Admin,
+    query: DownloadQuery,
+    shared: &State<SharedData>,
+) -> Result<(ContentType, String), Status> {
+    let filter = DownloadFilter {
+        file: query.file,
+        outcome: query.outcome,
+        since: query.since,
+        until: query.until,
+    };
+    let limit = query
+        .limit
+        .unwrap_or(DOWNLOADS_PAGE)
+        .min(DOWNLOADS_PAGE_MAX);
+    let offset = query.offset.unwrap_or(0);
+
+    let (total, downloads) = shared
+        .repo_db
+        .get_downloads(&filter, limit, offset)
+        .await
+        .map_err(|e| {
+            eprintln!("Failed to query download history: {}", e);
+            Status::InternalServerError
+        })?;
+    let body = serde_json::json!({
+        "total": total,
+        "limit": limit,
+        "offset": offset,
+        "downloads": downloads,
+    });
+
+    Ok((ContentType::JSON, body.to_string()))
+}
+
+/// provenance log of the distfiles served within admin.provenance_retention, oldest first
+/// lists checksums, source package, upstream and client of each for audits of software ingress
+/// format is "json" (default) or "csv", since and until are unix timestamps
+#[get("/api/v1/admin/provenance?<format>&<since>&<until>")]
+pub(crate) async fn provenance(
+    _admin: Admin,
+    format: Option<&str>,
+    since: Option<u64>,
+    until: Option<u64>,
+    shared: &State<SharedData>,
+) -> Result<(ContentType, String), Status> {
+    let csv = match format.unwrap_or("json") {
+        "json" => false,
+        "csv" => true,
+        _ => return Err(Status::BadRequest),
+    };
+
+    let served = shared.repo_db.get_served(since, until).await.map_err(|e| {
+        eprintln!("Failed to query provenance log: {}", e);
+        Status::InternalServerError
+    })?;
+
+    if csv {
+        let mut body = String::from(
+            "file,served,client,api_key,bytes,blake2b,sha512,package,upstream,request_id\n",
+        );
+        let field = |value: &Option<String>| csv_field(value.as_deref().unwrap_or_default());
+        for entry in &served {
+            body.push_str(&format!(
+                "{},{},{},{},{},{},{},{},{},{}\n",
+                csv_field(&entry.file),
+                entry.served,
+                field(&entry.client),
+                field(&entry.api_key),
+                entry.bytes,
+                field(&entry.blake2b),
+                field(&entry.sha512),
+                field(&entry.package),
+                field(&entry.upstream),
+                field(&entry.request_id),
+            ));
+        }
+        return Ok((ContentType::CSV, body));
+    }
+
+    let body = serde_json::json!({ "served": served });
+    Ok((ContentType::JSON, body.to_string()))
+}
+
+/// checksums recorded on the first download of distfiles without Manifest hashes
+/// with mismatched only those later downloads didn't match
+#[get("/api/v1/admin/tofu?<mismatched>")]
+pub(crate) async fn tofu(
+    _admin: Admin,
+    mismatched: Option<bool>,
+    shared: &State<SharedData>,
+) -> Result<(ContentType, String), Status> {
+    let checksums = shared
+        .repo_db
+        .get_tofu_checksums(mismatched.unwrap_or(false))
+        .await
+        .map_err(|e| {
+            eprintln!("Failed to query recorded checksums: {}", e);
+            Status::InternalServerError
+        })?;
+    let body = serde_json::json!({ "checksums": checksums });
+
+    Ok((ContentType::JSON, body.to_string()))
+}
+
+/// forget the checksum recorded for a distfile
+/// e.g. after upstream legitimately changed it, the next download gets trusted instead
+#[delete("/api/v1/admin/tofu/<file>")]
+pub(crate) async fn forget_tofu(
+    _admin: Admin,
+    file: Result<DistfileName, InvalidName>,
+    shared: &State<SharedData>,
+) -> Status {
+    let file = match file {
+        Ok(file) => file,
+        Err(_) => return Status::BadRequest,
+    };
+
+    match shared.repo_db.remove_tofu_checksum(file.as_str()).await {
+        Ok(true) => {
+            println!("Forgot recorded checksum of {}", file);
+            Status::NoContent
+        }
+        Ok(false) => Status::NotFound,
+        Err(e) => {
+            eprintln!("Failed to forget recorded checksum of {}: {}", file, e);
+            Status::InternalServerError
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+
+use crate::app::SharedData;
+use crate::repo_db::{ApiKey, RepoDB};
+use crate::utils;
+
+/// prefix of issued keys so they are recognizable in configs
+pub const KEY_PREFIX: &str = "pc_";
+
+/// hash of a key as stored in the database
+///
+/// @param key  the key as sent by clients
+pub fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// generate a new random key
+pub fn generate() -> Result<String, String> {
+    let mut bytes = [0u8; 32];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut bytes))
+        .map_err(|e| format!("Cannot read /dev/urandom: {}", e))?;
+    Ok(format!("{}{}", KEY_PREFIX, hex::encode(bytes)))
+}
+
+/// issue a new key under name
+/// only the hash gets stored so the returned key can't be shown again
+///
+/// @param repo_db  database to store the key in
+/// @param name     name of the key, e.g. the host or team using it
+/// @param limit    bytes per quota window, None for api_keys.default_limit
+pub async fn issue(repo_db: &RepoDB, name: &str, limit: Option<u64>) -> Result<String, String> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.@".contains(c))
+    {
+        return Err(format!(
+            "Bad key name {:?}: Only letters, digits and -_.@ are allowed",
+            name
+        ));
+    }
+
+    let key = generate()?;
+    repo_db
+        .insert_api_key(name, &hash_key(&key), limit, utils::unix_time())
+        .await
+        .map_err(|e| match e.sqlite_error_code() {
+            Some(rusqlite::ErrorCode::ConstraintViolation) => {
+                format!("A key named {} already exists", name)
+            }
+            _ => format!("Failed to store key {}: {}", name, e),
+        })?;
+    Ok(key)
+}
+
+/// request guard resolving the API key a client sent
+/// accepts "Authorization: Bearer <key>" and basic auth with the key
+/// as password so mirror urls like http://user:<key>@host work
+pub enum ClientKey {
+    /// the request carries no key
+    Anonymous,
+
+    /// the request carries an issued key
+    Valid(ApiKey),
+
+    /// the request carries an unknown key while keys are required or issued
+    /// otherwise e.g. mirror url credentials meant for something else count as Anonymous
+    Invalid,
+}
+
+/// key sent in an Authorization header value
+fn sent_key(authorization: &str) -> Option<String> {
+    if let Some(token) = authorization.strip_prefix("Bearer ") {
+        return Some(token.trim().to_string());
+    }
+
+    let credentials = STANDARD
+        .decode(authorization.strip_prefix("Basic ")?.trim())
+        .ok()?;
+    let credentials = String::from_utf8(credentials).ok()?;
+    let (_, key) = credentials.split_once(':')?;
+    Some(key.to_string())
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientKey {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let key = match req.headers().get_one("Authorization").and_then(sent_key) {
+            Some(key) => key,
+            None => return Outcome::Success(ClientKey::Anonymous),
+        };
+        let shared = match req.rocket().state::<SharedData>() {
+            Some(shared) => shared,
+            None => return Outcome::Error((Status::InternalServerError, ())),
+        };
+
+        // unknown keys only matter once keys are in use
+        let keys_in_use = match shared.repo_db.get_api_key(&hash_key(&key)).await {
+            Ok(Some(key)) => return Outcome::Success(ClientKey::Valid(key)),
+            Ok(None) if shared.api_keys_required => Ok(true),
+            Ok(None) => shared.repo_db.has_api_keys().await,
+            Err(e) => Err(e),
+        };
+
+        match keys_in_use {
+            Ok(true) => Outcome::Success(ClientKey::Invalid),
+            Ok(false) => Outcome::Success(ClientKey::Anonymous),
+            Err(e) => {
+                eprintln!("Failed to look up API key: {}", e);
+                Outcome::Error((Status::InternalServerError, ()))
+            }
+        }
+    }
+}
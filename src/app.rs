@@ -0,0 +1,274 @@
+use rocket::{Build, Rocket};
+use std::sync::Arc;
+
+use crate::admin;
+use crate::binhost::{self, Binhost};
+use crate::blob_storage::BlobStorage;
+use crate::checksum_index::{self, ChecksumIndex};
+use crate::config::{Config, FlatLayout};
+use crate::evictor::Evictor;
+use crate::frontend;
+use crate::memory_cache::MemoryCache;
+use crate::quota::Quota;
+use crate::releases::{self, Releases};
+use crate::repo_db::RepoDB;
+use crate::repo_syncer::SyncProgress;
+use crate::request_id::RequestIds;
+use crate::stats;
+use crate::telemetry::{TraceRequests, Tracer};
+use crate::utils;
+use crate::webrsync::{self, Webrsync};
+
+/// state shared between all request handlers
+pub struct SharedData {
+    /// BlobStorage for requesting blobs
+    pub blob_storage: Arc<BlobStorage>,
+
+    /// handling of flat /distfiles/<file> requests
+    pub flat_layout: FlatLayout,
+
+    /// bearer token for the admin API, None disables it
+    pub admin_token: Option<String>,
+
+    /// how long served distfiles are kept in the provenance log, zero logs nothing
+    pub provenance_retention: std::time::Duration,
+
+    /// reject file requests without a valid API key
+    pub api_keys_required: bool,
+
+    /// per client and API key usage tracking and soft quotas
+    pub quota: Quota,
+
+    /// repo database for statistics
+    pub repo_db: Arc<RepoDB>,
+
+    /// Evictor for manual runs through the admin API
+    pub evictor: Evictor,
+
+    /// binary package cache, None without [binhost]
+    pub binhost: Option<Arc<Binhost>>,
+
+    /// release media cache, None without [releases]
+    pub releases: Option<Arc<Releases>>,
+
+    /// progress of the repo syncer
+    pub sync_progress: Arc<SyncProgress>,
+
+    /// repo snapshots for emerge-webrsync, None without [webrsync]
+    pub webrsync: Option<Webrsync>,
+
+    /// free space below which /status reports the node unhealthy
+    pub status_min_free: Option<u64>,
+
+    /// how long caches in front may keep distfiles
+    pub cache_max_age: std::time::Duration,
+
+    /// signed index of the cached distfiles, None without [index]
+    pub index: Option<ChecksumIndex>,
+
+    /// small files served from memory, None without storage.memory_cache
+    pub memory_cache: Option<MemoryCache>,
+
+    /// io_uring reads of served files, None unless server.io = "uring" works on this kernel
+    #[cfg(feature = "uring")]
+    pub uring: Option<crate::uring::UringReader>,
+}
+
+/// components the server is built from
+pub struct Deps {
+    /// repo database shared between storage and syncer
+    pub repo_db: Arc<RepoDB>,
+
+    /// BlobStorage serving and fetching blobs
+    pub blob_storage: Arc<BlobStorage>,
+
+    /// binary package cache if configured
+    pub binhost: Option<Arc<Binhost>>,
+
+    /// release media cache if configured
+    pub releases: Option<Arc<Releases>>,
+
+    /// progress reported by the repo syncer
+    pub sync_progress: Arc<SyncProgress>,
+
+    /// tracer exporting request traces, None without telemetry.otlp_endpoint
+    pub tracer: Option<Arc<Tracer>>,
+}
+
+impl Deps {
+    /// set up all components from config
+    ///
+    /// @param config  a reference to Config
+    pub async fn new(config: &Config) -> Result<Self, String> {
+        utils::set_hash_workers(config.storage.hash_workers);
+
+        let repo_db = Arc::new(
+            RepoDB::new(config).map_err(|e| format!("Failed to initialize database: {}", e))?,
+        );
+
+        let blob_storage = Arc::new(
+            BlobStorage::new(config, repo_db.clone())
+                .await
+                .map_err(|e| format!("Failed to initialize blob storage: {}", e))?,
+        );
+
+        // fail on boot instead of on the first request
+        // when e.g. the storage is mounted read-only
+        repo_db.self_test().await.map_err(|e| {
+            format!(
+                "Database self-test failed: {} (check ownership and permissions of {} and its directory)",
+                e,
+                config.storage.location.join("db.sqlite3").to_string_lossy()
+            )
+        })?;
+        blob_storage.self_test().await.map_err(|e| {
+            format!(
+                "Blob storage self-test failed: {} (check ownership, permissions, mount options and SELinux labels of {})",
+                e,
+                blob_storage.location().to_string_lossy()
+            )
+        })?;
+
+        let binhost = Binhost::new(config)
+            .await
+            .map_err(|e| format!("Failed to initialize binhost cache: {}", e))?
+            .map(Arc::new);
+        let releases = Releases::new(config)
+            .await
+            .map_err(|e| format!("Failed to initialize release cache: {}", e))?
+            .map(Arc::new);
+
+        let tracer =
+            Tracer::new(config).map_err(|e| format!("Failed to initialize tracing: {}", e))?;
+
+        Ok(Self {
+            repo_db,
+            blob_storage,
+            binhost,
+            releases,
+            sync_progress: Arc::new(SyncProgress::default()),
+            tracer,
+        })
+    }
+}
+
+/// build the rocket instance serving portcache
+///
+/// @param config  a reference to Config
+/// @param deps    components to serve from
+pub fn build_rocket(config: &Config, deps: Deps) -> Rocket<Build> {
+    let cfg = rocket::config::Config {
+        address: config.server.address,
+        port: config.server.port,
+        shutdown: rocket::config::Shutdown {
+            grace: config.server.drain_timeout.as_secs() as u32,
+            ..rocket::config::Shutdown::default()
+        },
+        ..rocket::config::Config::default()
+    };
+
+    let index = ChecksumIndex::new(
+        config,
+        deps.blob_storage.clone(),
+        deps.repo_db.clone(),
+        deps.sync_progress.clone(),
+    );
+    let shared = SharedData {
+        flat_layout: config.server.flat_layout,
+        admin_token: config.admin.token.clone(),
+        provenance_retention: config.admin.provenance_retention,
+        api_keys_required: config.api_keys.required,
+        quota: Quota::new(config, deps.repo_db.clone()),
+        evictor: Evictor::manual(config, deps.blob_storage.clone(), deps.repo_db.clone()),
+        blob_storage: deps.blob_storage,
+        repo_db: deps.repo_db,
+        binhost: deps.binhost,
+        releases: deps.releases,
+        sync_progress: deps.sync_progress,
+        webrsync: Webrsync::new(config),
+        status_min_free: config.server.status_min_free,
+        cache_max_age: config.server.cache_max_age,
+        index,
+        memory_cache: MemoryCache::new(config),
+        #[cfg(feature = "uring")]
+        uring: start_uring(config),
+    };
+
+    for host in &config.fetcher.tls.insecure_hosts {
+        eprintln!(
+            "WARNING: Not verifying TLS certificates of {} - anyone in between can tamper with its responses",
+            host
+        );
+    }
+
+    let rocket = match deps.tracer {
+        Some(tracer) => rocket::custom(cfg).attach(TraceRequests(tracer)),
+        None => rocket::custom(cfg),
+    }
+    .attach(RequestIds);
+    #[cfg(feature = "chaos")]
+    let rocket = {
+        eprintln!("Fault injection is compiled in - don't use this build in production");
+        rocket.mount(
+            "/",
+            rocket::routes![
+                crate::chaos::faults,
+                crate::chaos::inject,
+                crate::chaos::clear
+            ],
+        )
+    };
+
+    rocket.manage(shared).mount(
+        "/",
+        rocket::routes![
+            frontend::layout_conf,
+            frontend::distfiles,
+            frontend::distfiles_flat,
+            binhost::packages_index,
+            binhost::packages,
+            releases::releases,
+            webrsync::snapshots,
+            admin::mark_stale,
+            admin::usage,
+            admin::keys,
+            admin::gc,
+            admin::parse_failures,
+            admin::downloads,
+            admin::provenance,
+            admin::tofu,
+            admin::forget_tofu,
+            stats::stats,
+            stats::buckets,
+            stats::new_distfiles,
+            stats::sync_errors,
+            stats::sync,
+            stats::metrics,
+            stats::version,
+            stats::export,
+            stats::cached,
+            stats::status,
+            checksum_index::index,
+            checksum_index::index_key
+        ],
+    )
+}
+
+/// start the io_uring thread if server.io asks for it
+/// falls back to the default reads if the kernel doesn't allow io_uring
+#[cfg(feature = "uring")]
+fn start_uring(config: &Config) -> Option<crate::uring::UringReader> {
+    if config.server.io != crate::config::FileIo::Uring {
+        return None;
+    }
+    match crate::uring::UringReader::start() {
+        Ok(uring) => {
+            println!("Serving files via io_uring");
+            Some(uring)
+        }
+        Err(e) => {
+            eprintln!("{} - serving files with the default reads", e);
+            None
+        }
+    }
+}
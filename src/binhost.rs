@@ -0,0 +1,298 @@
+use rocket::http::{self, ContentType};
+use rocket::tokio::fs::File;
+use rocket::{State, get};
+use std::net::IpAddr;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+
+use crate::api_keys::ClientKey;
+use crate::app::SharedData;
+use crate::config::Config;
+use crate::fetcher::{FetchError, FetchErrorKind, download_part, part_location, tls};
+use crate::frontend::{self, IfModifiedSince, Refused, Served};
+use crate::request_id::{req_eprintln, req_println};
+use crate::telemetry::RequestTrace;
+use crate::utils::PathLocks;
+
+/// suffixes of binary packages a binhost serves
+const PACKAGE_SUFFIXES: &[&str] = &[".gpkg.tar", ".xpak", ".tbz2"];
+
+/// caches the Packages index and binary packages of an upstream binhost
+/// packages are stored under <storage>/packages in the upstream layout
+pub struct Binhost {
+    /// url of the upstream binhost without trailing slash
+    upstream: String,
+
+    /// root of the package cache
+    root: PathBuf,
+
+    /// how long the cached Packages index is served before refetching
+    index_ttl: Duration,
+
+    /// client for upstream requests
+    client: tls::Client,
+
+    /// locks serializing fetches of the same path
+    fetch_locks: PathLocks,
+}
+
+impl Binhost {
+    /// create a Binhost from config
+    /// returns None without a [binhost] section
+    pub async fn new(config: &Config) -> Result<Option<Self>, String> {
+        let binhost = match &config.binhost {
+            Some(binhost) => binhost,
+            None => return Ok(None),
+        };
+
+        reqwest::Url::parse(&binhost.upstream)
+            .map_err(|e| format!("Bad binhost upstream {}: {}", binhost.upstream, e))?;
+
+        let root = config.storage.location.join("packages");
+        fs::create_dir_all(&root)
+            .await
+            .map_err(|e| format!("Cannot create {}: {}", root.to_string_lossy(), e))?;
+
+        Ok(Some(Self {
+            upstream: binhost.upstream.trim_end_matches('/').to_string(),
+            root,
+            index_ttl: binhost.index_ttl,
+            client: tls::Client::new(&config.fetcher.tls)?,
+            fetch_locks: PathLocks::default(),
+        }))
+    }
+
+    /// root of the package cache
+    pub fn location(&self) -> &Path {
+        &self.root
+    }
+
+    /// get the rewritten Packages index
+    /// refetches it once older than index_ttl
+    /// and keeps serving the cached one if upstream fails
+    pub async fn index(&self) -> Result<PathBuf, FetchError> {
+        let path = self.root.join("Packages");
+        let _lock = self.fetch_locks.lock(&path).await;
+
+        let age = fs::metadata(&path)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+        if let Some(age) = age
+            && age < self.index_ttl
+        {
+            return Ok(path);
+        }
+
+        match self.fetch_index(&path).await {
+            Ok(_) => Ok(path),
+            Err(e) if age.is_some() => {
+                req_eprintln!("Failed to refresh binhost index, serving cached one: {}", e);
+                Ok(path)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// download the upstream Packages index and store it rewritten
+    async fn fetch_index(&self, path: &Path) -> Result<(), FetchError> {
+        let url = format!("{}/Packages", self.upstream);
+        req_println!("Fetching {}", url);
+
+        let index = self
+            .client
+            .send(self.client.get(&url))
+            .await?
+            .error_for_status()
+            .map_err(|e| FetchError::from_reqwest(&e))?
+            .text()
+            .await
+            .map_err(|e| FetchError::from_reqwest(&e))?;
+
+        let part = part_location(path);
+        fs::write(&part, rewrite_index(&index))
+            .await
+            .map_err(|e| FetchError::from(e.to_string()))?;
+        fs::rename(&part, path)
+            .await
+            .map_err(|e| FetchError::from(e.to_string()))
+    }
+
+    /// get a binary package from cache or fetch it from upstream
+    ///
+    /// @param package  path of the package relative to the binhost root
+    pub async fn package(&self, package: &Path) -> Result<PathBuf, FetchError> {
+        if !is_package_path(package) {
+            return Err(FetchError::new(
+                FetchErrorKind::Rejected,
+                format!("Not a binary package: {}", package.to_string_lossy()),
+            ));
+        }
+
+        let path = self.root.join(package);
+        let _lock = self.fetch_locks.lock(&path).await;
+        if path.is_file() {
+            req_println!("Cache hit on package {}", package.to_string_lossy());
+            return Ok(path);
+        }
+
+        // the index tells the expected size if upstream lists the package
+        let expected = match self.index().await {
+            Ok(index) => fs::read_to_string(index)
+                .await
+                .ok()
+                .and_then(|index| package_size(&index, &package.to_string_lossy())),
+            Err(_) => None,
+        };
+
+        let url = format!("{}/{}", self.upstream, package.to_string_lossy());
+        self.download(&url, &path, expected).await?;
+        Ok(path)
+    }
+
+    /// download url to path via a temporary file
+    ///
+    /// @param url       url to download
+    /// @param path      where to store the download
+    /// @param expected  size from the Packages index
+    async fn download(
+        &self,
+        url: &str,
+        path: &Path,
+        expected: Option<u64>,
+    ) -> Result<(), FetchError> {
+        let (part, size) = download_part(&self.client, url, path).await?;
+
+        if let Some(expected) = expected
+            && expected != size
+        {
+            let _ = fs::remove_file(&part).await;
+            return Err(FetchError::from(format!(
+                "Size mismatch for {}: Expected {}, Got {}",
+                url, expected, size
+            )));
+        }
+
+        fs::rename(&part, path)
+            .await
+            .map_err(|e| FetchError::from(e.to_string()))
+    }
+}
+
+/// rewrite an upstream Packages index for serving
+/// drops the URI header so clients fetch packages through
+/// PORTAGE_BINHOST i.e. portcache instead of the url upstream announces
+///
+/// @param index  content of the upstream Packages file
+pub fn rewrite_index(index: &str) -> String {
+    let mut rewritten = String::with_capacity(index.len());
+    let mut header = true;
+
+    for line in index.split_inclusive('\n') {
+        if header && line.trim().is_empty() {
+            header = false;
+        }
+        if header && line.starts_with("URI:") {
+            continue;
+        }
+        rewritten.push_str(line);
+    }
+
+    rewritten
+}
+
+/// size of a package as listed in a Packages index
+/// entries without PATH are stored as <CPV>.tbz2
+///
+/// @param index    content of the Packages file
+/// @param package  path of the package relative to the binhost root
+pub fn package_size(index: &str, package: &str) -> Option<u64> {
+    // the first block is the header
+    for entry in index.split("\n\n").skip(1) {
+        let field = |name: &str| {
+            entry
+                .lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                .map(str::trim)
+        };
+
+        let path = match (field("PATH"), field("CPV")) {
+            (Some(path), _) => path.to_string(),
+            (None, Some(cpv)) => format!("{}.tbz2", cpv),
+            (None, None) => continue,
+        };
+        if path == package {
+            return field("SIZE")?.parse().ok();
+        }
+    }
+
+    None
+}
+
+/// whether a requested path is a binary package
+/// i.e. relative, without .. and with a package suffix
+fn is_package_path(path: &Path) -> bool {
+    path.components().all(|c| matches!(c, Component::Normal(_)))
+        && PACKAGE_SUFFIXES
+            .iter()
+            .any(|suffix| path.to_string_lossy().ends_with(suffix))
+}
+
+/// turn a failed binhost fetch into a status
+fn fetch_status(what: &str, e: FetchError) -> http::Status {
+    req_eprintln!("Binhost fetch of {} failed ({:?}): {}", what, e.kind, e);
+    match e.kind {
+        FetchErrorKind::NotFound | FetchErrorKind::Rejected => http::Status::NotFound,
+        FetchErrorKind::Transient | FetchErrorKind::Other => http::Status::BadGateway,
+    }
+}
+
+/// the Packages index with upstream URIs stripped
+#[get("/packages/Packages")]
+pub(crate) async fn packages_index(
+    trace: RequestTrace,
+    shared: &State<SharedData>,
+) -> Result<(ContentType, File), http::Status> {
+    let binhost = shared.binhost.as_ref().ok_or(http::Status::NotFound)?;
+    let index = trace
+        .within(binhost.index())
+        .await
+        .map_err(|e| fetch_status("Packages", e))?;
+    let file = File::open(index)
+        .await
+        .map_err(|_| http::Status::InternalServerError)?;
+    Ok((ContentType::Plain, file))
+}
+
+/// binary packages, fetched from upstream on a miss
+/// the served size gets accounted to the client's subnet and API key
+#[get("/packages/<package..>", rank = 2)]
+pub(crate) async fn packages(
+    package: PathBuf,
+    client: Option<IpAddr>,
+    key: ClientKey,
+    since: IfModifiedSince,
+    trace: RequestTrace,
+    shared: &State<SharedData>,
+) -> Result<Served, Refused> {
+    let binhost = shared.binhost.as_ref().ok_or(http::Status::NotFound)?;
+    let name = package.to_string_lossy();
+
+    trace
+        .within(frontend::open_accounted(
+            &name,
+            client,
+            key,
+            since,
+            shared,
+            async {
+                binhost
+                    .package(&package)
+                    .await
+                    .map_err(|e| fetch_status(&name, e))
+            },
+        ))
+        .await
+}
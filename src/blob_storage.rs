@@ -1,27 +1,245 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
 
 use futures::lock::Mutex;
-use std::sync::Arc;
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use std::os::unix::fs::MetadataExt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::fs;
-use tokio::sync::Notify;
+use tokio::sync::{Notify, Semaphore, SemaphorePermit};
+use tokio::task;
+use tokio::time::{self, Instant};
 
+use crate::access_log::AccessLog;
+#[cfg(feature = "chaos")]
+use crate::chaos::Chaos;
+use crate::compression::{self, Compression};
 use crate::config;
-use crate::fetcher::Fetcher;
+use crate::distdir::LocalDistdir;
+use crate::distfile_name::DistfileName;
+use crate::fetcher::{self, FetchChain, Layout};
 use crate::repo_db::RepoDB;
+use crate::request_id::{req_eprintln, req_println};
+use crate::telemetry::{self, Span, SpanKind};
 use crate::utils;
 
+/// a running or queued fetch other requests of the same file wait on
+#[derive(Default)]
+struct FetchJob {
+    /// notified once the fetch finished
+    notify: Notify,
+
+    /// queue ticket while waiting for a fetch slot, 0 once running
+    ticket: AtomicU64,
+
+    /// blob fetched in read-only mode which waiters link to serve it
+    /// removed once the last of them let go of the job
+    transient: OnceLock<PathBuf>,
+}
+
+impl Drop for FetchJob {
+    fn drop(&mut self) {
+        if let Some(transient) = self.transient.get() {
+            let _ = std::fs::remove_file(transient);
+        }
+    }
+}
+
+/// lock file of a fetch held by this process
+/// removed again once the fetch finished
+pub(crate) struct FetchLock {
+    /// location of the lock file
+    path: PathBuf,
+
+    /// the lock, released on drop
+    _lock: Flock<std::fs::File>,
+}
+
+impl Drop for FetchLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// FIFO queue of fetches waiting for a slot
+struct FetchQueue {
+    /// free fetch slots, None if unlimited
+    permits: Option<Semaphore>,
+
+    /// tickets handed out to queued fetches
+    tickets: AtomicU64,
+
+    /// tickets which left the queue
+    dequeued: AtomicU64,
+
+    /// how long requests wait for a queued fetch
+    wait: Duration,
+
+    /// Retry-After for requests giving up
+    retry_after: Duration,
+}
+
+impl FetchQueue {
+    /// create a FetchQueue from config
+    fn new(config: &config::QueueConfig) -> Self {
+        Self {
+            permits: (config.max_fetches > 0).then(|| Semaphore::new(config.max_fetches)),
+            tickets: AtomicU64::new(0),
+            dequeued: AtomicU64::new(0),
+            wait: config.wait,
+            retry_after: config.retry_after,
+        }
+    }
+
+    /// error for a request giving up on the fetch holding ticket
+    fn busy(&self, ticket: u64) -> QueueBusy {
+        QueueBusy {
+            position: ticket.saturating_sub(self.dequeued.load(Ordering::SeqCst)),
+            retry_after: self.retry_after,
+        }
+    }
+}
+
+/// a request gave up as its fetch didn't leave the queue in time
+#[derive(Debug)]
+pub struct QueueBusy {
+    /// position of the fetch in the queue, 1 being next
+    pub position: u64,
+
+    /// when the client should try again
+    pub retry_after: Duration,
+}
+
+impl std::fmt::Display for QueueBusy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Fetch queued at position {}", self.position)
+    }
+}
+
+impl std::error::Error for QueueBusy {}
+
+/// a complete blob in the storage
+pub struct StoredBlob {
+    /// file name
+    pub file: String,
+
+    /// size in bytes
+    pub size: u64,
+
+    /// last modification i.e. when it got fetched or its upstream mtime
+    pub modified: SystemTime,
+
+    /// last access, falls back to last modification
+    pub accessed: SystemTime,
+}
+
+/// space of the filesystem the storage lives on
+#[derive(Clone, Copy, Debug)]
+pub struct DiskSpace {
+    /// bytes available to unprivileged users
+    pub available: u64,
+
+    /// size of the filesystem in bytes
+    pub total: u64,
+}
+
+/// fetches of this process at a point in time
+#[derive(Clone, Copy, Debug)]
+pub struct FetchCounts {
+    /// fetches downloading right now
+    pub running: u64,
+
+    /// fetches waiting for a slot
+    pub queued: u64,
+}
+
+/// changes to blobs made outside of portcache, e.g. by an operator
+/// counted by the blob watcher
+#[derive(Default)]
+pub struct ExternalChanges {
+    /// blobs deleted
+    pub removed: AtomicU64,
+
+    /// blobs replaced with content matching their Manifest entry or without one
+    pub replaced: AtomicU64,
+
+    /// blobs replaced with content not matching their Manifest entry
+    pub mismatched: AtomicU64,
+}
+
+/// how long a change of this process to a blob is remembered
+/// it has to outlast the delay of file events reaching the blob watcher
+const OWN_CHANGE_WINDOW: Duration = Duration::from_secs(60);
+
 /// storage for downloaded blobs
 pub struct BlobStorage {
     /// root of the blob storage
     location: PathBuf,
 
-    /// Fetcher used for fetching missing files
-    fetcher: Fetcher,
+    /// length of the hash directory names in bits
+    hash_bits: u8,
+
+    /// FetchChain used for fetching missing files
+    fetcher: FetchChain,
 
     /// tracker for Fetcher jobs
-    /// maps file name to notifier to wait on
-    fetch_jobs: Mutex<HashMap<String, Arc<Notify>>>,
+    /// maps file name to job to wait on
+    fetch_jobs: Mutex<HashMap<String, Arc<FetchJob>>>,
+
+    /// limits concurrent fetches
+    queue: FetchQueue,
+
+    /// lock files of running fetches shared with other processes
+    /// using the same storage, e.g. the previous instance during a handoff
+    fetch_locks: PathBuf,
+
+    /// blobs marked stale which get revalidated on next request
+    /// mirrors the stale_blob table of the repo database
+    stale: Mutex<HashSet<String>>,
+
+    /// cached blobs being refetched, fetchers write these to fetch_location()
+    replacing: Mutex<HashSet<String>>,
+
+    /// repo database
+    repo_db: Arc<RepoDB>,
+
+    /// blobs this process changed recently with the time of the change
+    /// so the blob watcher can tell them from external changes
+    own_changes: std::sync::Mutex<HashMap<String, Instant>>,
+
+    /// changes to blobs made outside of portcache
+    external: ExternalChanges,
+
+    /// Portage DISTDIR of this machine blobs get hard linked into
+    local_distdir: Option<LocalDistdir>,
+
+    /// number of changes to blobs so far
+    generation: AtomicU64,
+
+    /// free space below which misses are served without being stored
+    min_free: Option<u64>,
+
+    /// whether free space was below min_free when last checked
+    read_only: AtomicBool,
+
+    /// where blobs fetched in read-only mode wait to be opened by the request that fetched them
+    transient: PathBuf,
+
+    /// numbers blobs in transient apart
+    transient_seq: AtomicU64,
+
+    /// access times of cache hits waiting to be written
+    access_log: Arc<AccessLog>,
+
+    /// compression of fetched blobs matching storage.compress
+    compression: Option<Compression>,
+
+    /// faults injected into fetches
+    #[cfg(feature = "chaos")]
+    chaos: Chaos,
 }
 
 impl BlobStorage {
@@ -39,15 +257,48 @@ impl BlobStorage {
         config: &config::Config,
         repo_db: Arc<RepoDB>,
     ) -> Result<Self, Box<dyn std::error::Error>> {
-        let fetcher = Fetcher::new(config, repo_db.clone()).await?;
+        let location = config.storage.location.join("distfiles");
+        let hash_bits = config.storage.hash_bits;
+        check_layout(&location, hash_bits, &repo_db).await?;
+
+        let fetcher = FetchChain::new(config, repo_db.clone()).await?;
+        let stale = repo_db.get_stale_blobs().await?;
+        let fetch_locks = config.storage.location.join("fetching");
+        fs::create_dir_all(&fetch_locks).await?;
+
+        // leftovers of requests interrupted by a restart
+        let transient = config.storage.location.join("transient");
+        if transient.exists() {
+            fs::remove_dir_all(&transient).await?;
+        }
+        fs::create_dir_all(&transient).await?;
+        let access_log = Arc::new(AccessLog::new(config, repo_db.clone()));
         let new = Self {
-            location: config.storage.location.join("distfiles"),
+            location,
+            hash_bits,
             fetcher,
             fetch_jobs: Mutex::new(HashMap::new()),
+            queue: FetchQueue::new(&config.fetcher.queue),
+            fetch_locks,
+            stale: Mutex::new(stale.into_iter().collect()),
+            replacing: Mutex::new(HashSet::new()),
+            repo_db,
+            own_changes: std::sync::Mutex::new(HashMap::new()),
+            external: ExternalChanges::default(),
+            local_distdir: LocalDistdir::new(config),
+            generation: AtomicU64::new(0),
+            min_free: config.storage.min_free,
+            read_only: AtomicBool::new(false),
+            transient,
+            transient_seq: AtomicU64::new(0),
+            access_log,
+            compression: Compression::new(config),
+            #[cfg(feature = "chaos")]
+            chaos: Chaos::default(),
         };
 
         if !new.location.exists() {
-            println!(
+            req_println!(
                 "Initializing blob storage at {}",
                 new.location.to_string_lossy()
             );
@@ -57,43 +308,312 @@ impl BlobStorage {
         Ok(new)
     }
 
+    /// check blobs can be stored by writing, reading back
+    /// and deleting a probe file in the storage root
+    pub async fn self_test(&self) -> Result<(), String> {
+        let probe = self.location.join(".portcache-probe");
+        let content = b"portcache";
+
+        fs::write(&probe, content)
+            .await
+            .map_err(|e| format!("Cannot write {}: {}", probe.to_string_lossy(), e))?;
+        let read = fs::read(&probe)
+            .await
+            .map_err(|e| format!("Cannot read {}: {}", probe.to_string_lossy(), e))?;
+        fs::remove_file(&probe)
+            .await
+            .map_err(|e| format!("Cannot delete {}: {}", probe.to_string_lossy(), e))?;
+
+        if read != content {
+            return Err(format!(
+                "{} read back different content",
+                probe.to_string_lossy()
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// root of the blob storage
+    pub fn location(&self) -> &Path {
+        &self.location
+    }
+
+    /// faults injected into fetches
+    #[cfg(feature = "chaos")]
+    pub fn chaos(&self) -> &Chaos {
+        &self.chaos
+    }
+
+    /// length of the hash directory names in bits
+    pub fn hash_bits(&self) -> u8 {
+        self.hash_bits
+    }
+
+    /// directory a blob is stored in relative to the storage root
+    /// @param name  Name of the blob
+    pub fn hash_dir(&self, name: &str) -> String {
+        utils::filename_hash_dir(name, self.hash_bits)
+    }
+
+    /// layout.conf describing the storage layout
+    /// @param flat  whether flat /distfiles/<file> requests get answered too
+    pub fn layout_conf(&self, flat: bool) -> String {
+        utils::layout_conf(self.hash_bits, flat)
+    }
+
+    /// space of the filesystem the storage lives on
+    pub fn disk_space(&self) -> Result<DiskSpace, String> {
+        let stat = nix::sys::statvfs::statvfs(&self.location)
+            .map_err(|e| format!("Failed to query free space: {}", e))?;
+        Ok(DiskSpace {
+            available: stat.blocks_available() * stat.fragment_size(),
+            total: stat.blocks() * stat.fragment_size(),
+        })
+    }
+
+    /// number of fetches running and waiting for a slot
+    pub async fn fetch_counts(&self) -> FetchCounts {
+        let jobs = self.fetch_jobs.lock().await.len() as u64;
+        let queued = self
+            .queue
+            .tickets
+            .load(Ordering::SeqCst)
+            .saturating_sub(self.queue.dequeued.load(Ordering::SeqCst));
+        FetchCounts {
+            running: jobs.saturating_sub(queued),
+            queued,
+        }
+    }
+
     /// get storage location for a blob
     /// @param name  Name of the blob
-    pub async fn blob_location(&self, name: &String) -> Result<std::path::PathBuf, String> {
-        let path = self
-            .location
-            .join(utils::filename_hash_dir_blake2b(name).map_err(|x| x.to_string())?)
-            .join(name);
-        Ok(std::path::PathBuf::from(&path))
+    pub async fn blob_location(&self, name: &str) -> Result<std::path::PathBuf, String> {
+        Ok(self.location.join(self.hash_dir(name)).join(name))
+    }
+
+    /// get the location fetchers write a blob to
+    /// a cached blob being refetched keeps getting served until the refetch verified,
+    /// so the refetch goes next to it and only replaces it afterwards
+    /// @param name  Name of the blob
+    pub async fn fetch_location(&self, name: &str) -> Result<std::path::PathBuf, String> {
+        let path = self.blob_location(name).await?;
+        match self.replacing.lock().await.contains(name) {
+            true => Ok(fetcher::part_location(&path)),
+            false => Ok(path),
+        }
+    }
+
+    /// number of blobs in each hash directory
+    /// empty directories are left out
+    pub async fn bucket_counts(&self) -> Result<BTreeMap<String, u64>, String> {
+        let location = self.location.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut buckets = BTreeMap::new();
+            let dirs = std::fs::read_dir(&location)
+                .map_err(|e| format!("Cannot read {}: {}", location.to_string_lossy(), e))?;
+            for dir in dirs.flatten() {
+                if !dir.file_type().is_ok_and(|kind| kind.is_dir()) {
+                    continue;
+                }
+                let blobs = std::fs::read_dir(dir.path())
+                    .map(|blobs| blobs.flatten().count() as u64)
+                    .unwrap_or(0);
+                if blobs > 0 {
+                    buckets.insert(dir.file_name().to_string_lossy().to_string(), blobs);
+                }
+            }
+            Ok(buckets)
+        })
+        .await
+        .map_err(|e| e.to_string())?
+    }
+
+    /// access times of cache hits waiting to be written
+    pub fn access_log(&self) -> &Arc<AccessLog> {
+        &self.access_log
+    }
+
+    /// all complete blobs in storage
+    /// last accesses are the later of the recorded cache hits and the filesystem atime
+    pub async fn blobs(&self) -> Result<Vec<StoredBlob>, String> {
+        let root = self.location.clone();
+        let mut blobs = tokio::task::spawn_blocking(move || collect_blobs(root))
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let times = self.access_log.times().await?;
+        for blob in &mut blobs {
+            if let Some(accessed) = times.get(&blob.file) {
+                let accessed = UNIX_EPOCH + Duration::from_secs(*accessed);
+                blob.accessed = blob.accessed.max(accessed);
+            }
+        }
+        Ok(blobs)
     }
 
     /// get a PathBuf to the requested file
     /// if the file isn't cached we will request the fetcher to fetch it
-    /// @param file    file name
+    /// gives up with QueueBusy if the fetch doesn't start within the queue's wait budget
+    /// and fails for files not cached while in read-only mode
+    /// @param file    normalized file name
     pub async fn request(
+        &self,
+        file: &DistfileName,
+    ) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+        self.traced_lookup(file, false, false).await
+    }
+
+    /// like request() but for serving file to a client right away
+    /// in read-only mode files not cached get fetched to a transient location,
+    /// see is_transient(), which the caller removes once it opened the file
+    /// @param file    normalized file name
+    pub async fn serve(
+        &self,
+        file: &DistfileName,
+    ) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+        self.traced_lookup(file, true, false).await
+    }
+
+    /// like serve() but a cached blob gets refetched first
+    /// it keeps getting served until the refetch verified and atomically replaces it
+    /// fails if the refetch does, leaving the cached blob in place
+    /// @param file    normalized file name
+    pub async fn refresh(
+        &self,
+        file: &DistfileName,
+    ) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+        self.traced_lookup(file, true, true).await
+    }
+
+    /// whether a path returned by serve() is a transient blob not kept in the storage
+    ///
+    /// @param path  the returned path
+    pub fn is_transient(&self, path: &Path) -> bool {
+        path.starts_with(&self.transient)
+    }
+
+    /// whether free space is below storage.min_free
+    /// so files not cached get served without being stored
+    /// checked on every call, entering and leaving read-only mode gets logged
+    pub fn read_only(&self) -> bool {
+        let Some(min_free) = self.min_free else {
+            return false;
+        };
+        let low = match self.disk_space() {
+            Ok(disk) => disk.available < min_free,
+            Err(e) => {
+                req_eprintln!("{}", e);
+                self.read_only.load(Ordering::Relaxed)
+            }
+        };
+
+        if self.read_only.swap(low, Ordering::Relaxed) != low {
+            match low {
+                true => req_eprintln!(
+                    "Free space below storage.min_free - serving misses without storing them"
+                ),
+                false => req_println!("Free space reclaimed - storing fetched distfiles again"),
+            }
+        }
+        low
+    }
+
+    /// lookup() within a span
+    ///
+    /// @param file     normalized file name
+    /// @param serve    whether file gets served right away
+    /// @param refresh  whether a cached blob gets refetched
+    async fn traced_lookup(
+        &self,
+        file: &DistfileName,
+        serve: bool,
+        refresh: bool,
+    ) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+        let mut span = telemetry::span("blob_storage.request", SpanKind::Internal);
+        span.set("portcache.distfile", file.as_str());
+        let file = file.to_string();
+        let result = telemetry::within(
+            span.context(),
+            self.lookup(&file, serve, refresh, &mut span),
+        )
+        .await;
+        if let Err(e) = &result {
+            span.fail(e);
+        }
+        result
+    }
+
+    /// serve file from cache or fetch it, see request(), serve() and refresh()
+    ///
+    /// @param file     file name
+    /// @param serve    whether file gets served right away
+    /// @param refresh  whether a cached blob gets refetched
+    /// @param span     span of the request to annotate
+    async fn lookup(
         &self,
         file: &String,
+        serve: bool,
+        refresh: bool,
+        span: &mut Span,
     ) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
         // where we expect the file in storage
         let path = self.blob_location(file).await?;
 
+        // renamed upstream files are served from the blob they're identical to
+        if !path.is_file()
+            && !refresh
+            && let Some((blob, blob_path)) = self.aliased(file).await
+        {
+            req_println!("Cache hit on {} as alias of {}", file, blob);
+            span.set("portcache.cache_hit", true);
+            self.access_log.note(&blob);
+            self.link_local(file, &blob_path).await;
+            return Ok(blob_path);
+        }
+
+        // low on space misses only get passed through to clients
+        let read_only = !path.is_file() && self.read_only();
+        if read_only && !serve {
+            return Err(format!("Not fetching {} - storage is in read-only mode", file).into());
+        }
+
+        // when to give up waiting for a queued fetch
+        let deadline = Instant::now() + self.queue.wait;
+
+        // whether an existing stale blob gets refetched
+        let mut revalidate = false;
+
         // this tokio::select! abonination is needed
         // to get scoped fetch_jobs so the lock gets released again...
-        let active_job = tokio::select! {
+        let (job, waiting) = tokio::select! {
             mut fetch_jobs = self.fetch_jobs.lock() => {
                 match fetch_jobs.get(file) {
                     // fetch job running so we should wait
-                    Some(job) => Some(job.clone()),
+                    Some(job) => (job.clone(), true),
                     // no running fetch job
                     None => {
-                        if path.is_file() {
+                        let elsewhere = self.fetching_elsewhere(file);
+                        if path.is_file()
+                            && !elsewhere
+                            && !refresh
+                            && !self.stale.lock().await.contains(file)
+                        {
                             // file should always fully exist in this case
-                            println!("Cache hit on {}", file);
+                            req_println!("Cache hit on {}", file);
+                            span.set("portcache.cache_hit", true);
+                            self.access_log.note(file);
+                            self.link_local(file, &path).await;
                             return Ok(path.to_path_buf());
                         } else {
-                            // not fetched yet, this thread should fetch
-                            fetch_jobs.insert(file.to_string(), Arc::new(Notify::new()));
-                            None
+                            // not fetched yet or stale, this thread should fetch
+                            // a blob another process is still writing isn't stale
+                            revalidate = path.is_file() && !elsewhere;
+                            span.set("portcache.cache_hit", false);
+                            span.set("portcache.revalidate", revalidate);
+                            let job = Arc::new(FetchJob::default());
+                            fetch_jobs.insert(file.to_string(), job.clone());
+                            (job, false)
                         }
                     }
                 }
@@ -101,9 +621,19 @@ impl BlobStorage {
         };
 
         // wait outside the above to get lock on fetch_jobs released
-        if let Some(active_job) = active_job {
-            println!("Already fetching {} - waiting until complete", file);
-            active_job.notified().await;
+        if waiting {
+            req_println!("Already fetching {} - waiting until complete", file);
+            span.set("portcache.cache_hit", false);
+            self.wait_for(&job, deadline).await?;
+            // fetched in read-only mode, each request serves its own link of it
+            if let Some(transient) = job.transient.get() {
+                if !serve {
+                    return Err(
+                        format!("Not fetching {} - storage is in read-only mode", file).into(),
+                    );
+                }
+                return Ok(self.link_transient(file, transient).await?);
+            }
             if path.is_file() {
                 // usually the file should exist now
                 // unless an error ocurred
@@ -111,42 +641,821 @@ impl BlobStorage {
             }
         }
 
-        // then ask fetcher
-        if self.fetcher.fetch(file, self).await.is_err() {
-            // cleanup failed file
-            if path.is_file() {
-                fs::remove_file(&path)
-                    .await
-                    .expect("could not clean up bad fetch");
-            }
-            // if we have tasks waiting notify one to retry, else drop the job
-            tokio::select! {
-                mut fetch_jobs = self.fetch_jobs.lock() => {
-                    if let Some(notify) = fetch_jobs.get(file) {
-                        if Arc::strong_count(notify) > 1 {
-                            eprintln!("Notifying waiting threads to retry download for {}", file);
-                            notify.notify_one();
-                            return Err(format!("Could not download file {}", file).into());
-                        } else {
-                            eprintln!("No waiting threads - not retrying download for {}", file);
-                            fetch_jobs.remove(file);
-                        }
+        let permit = self.fetch_slot(&job, deadline).await;
+        drop(job);
+        let permit = match permit {
+            Ok(permit) => permit,
+            Err(busy) => {
+                req_eprintln!(
+                    "Fetch of {} still queued at position {} - giving up",
+                    file,
+                    busy.position
+                );
+                if revalidate && !refresh {
+                    // keep serving the stale blob until there is room for the refetch
+                    if let Some(job) = self.fetch_jobs.lock().await.remove(file) {
+                        job.notify.notify_waiters();
                     }
+                    return Ok(path.to_path_buf());
+                }
+                if !revalidate {
+                    self.hand_over(file).await;
+                } else if let Some(job) = self.fetch_jobs.lock().await.remove(file) {
+                    job.notify.notify_waiters();
                 }
+                return Err(busy.into());
+            }
+        };
+
+        // another process may have fetched the file in the meantime
+        let fetch_lock = self.lock_fetch(file).await;
+        if fetch_lock.is_some() && !revalidate && path.is_file() {
+            drop(permit);
+            if let Some(job) = self.fetch_jobs.lock().await.remove(file) {
+                job.notify.notify_waiters();
+            }
+            return Ok(path.to_path_buf());
+        }
+
+        // the cached blob keeps getting served while the refetch goes next to it
+        let replacement = fetcher::part_location(&path);
+        if revalidate {
+            req_println!("Refetching cached blob {}", file);
+            // a leftover of an interrupted refetch would pass for the download
+            if let Err(e) = fs::remove_file(&replacement).await
+                && e.kind() != std::io::ErrorKind::NotFound
+            {
+                req_eprintln!("Could not remove leftover refetch of {}: {}", file, e);
             }
+            self.replacing.lock().await.insert(file.clone());
+        }
+
+        // then ask fetcher
+        let mut fetched = self.fetcher.fetch(file, self).await.is_ok();
+        drop(permit);
+        let mut refresh_failed = false;
+        if revalidate {
+            self.replacing.lock().await.remove(file);
+            let replaced = self
+                .finish_revalidation(file, &path, &replacement, fetched)
+                .await;
+            refresh_failed = refresh && !replaced;
+            fetched = replaced || path.is_file();
+        } else if !fetched && path.is_file() {
+            // cleanup failed file
+            fs::remove_file(&path)
+                .await
+                .expect("could not clean up bad fetch");
+        }
+        drop(fetch_lock);
+
+        if !fetched && self.hand_over(file).await {
+            return Err(format!("Could not download file {}", file).into());
         }
 
+        self.note_change(file);
+        if fetched && !read_only {
+            self.compress(file, &path).await;
+        }
+
+        // moved out before waiters get woken so none of them serves it from the storage
+        // or fetches it again
+        let transient = match read_only && path.is_file() {
+            true => Some(self.make_transient(file, &path).await),
+            false => None,
+        };
+
         // if we successfully fetched, remove job and notify all
-        if let Some(notify) = self.fetch_jobs.lock().await.remove(file) {
-            println!("Finished downloading {}", file);
-            notify.notify_waiters();
+        if let Some(job) = self.fetch_jobs.lock().await.remove(file) {
+            req_println!("Finished downloading {}", file);
+            job.notify.notify_waiters();
+        }
+        if refresh_failed {
+            return Err(format!("Refetch of {} failed - keeping the cached blob", file).into());
         }
 
         // finish this thread
+        if let Some(transient) = transient {
+            return Ok(transient?);
+        }
         if path.is_file() {
+            self.link_local(file, &path).await;
             return Ok(path.to_path_buf());
         }
 
         Err(format!("Could not download file {}", file).into())
     }
+
+    /// find a cached blob with the same content as file according to the Manifests
+    /// e.g. of a tarball upstream renamed without changing it
+    /// the match is recorded so later requests skip the search
+    /// returns the name and location of that blob
+    ///
+    /// @param file  file name
+    async fn aliased(&self, file: &str) -> Option<(String, std::path::PathBuf)> {
+        let usable = |blob: String| async move {
+            let path = self.blob_location(&blob).await.ok()?;
+            (path.is_file() && !self.stale.lock().await.contains(&blob)).then_some((blob, path))
+        };
+
+        match self.repo_db.get_alias(file).await {
+            Ok(Some(blob)) => match usable(blob).await {
+                Some(found) => return Some(found),
+                None => {
+                    let _ = self.repo_db.remove_aliases(file).await;
+                }
+            },
+            Ok(None) => (),
+            Err(e) => {
+                req_eprintln!("Failed to look up alias of {}: {}", file, e);
+                return None;
+            }
+        }
+
+        let candidates = match self.repo_db.get_same_content(file).await {
+            Ok(candidates) => candidates,
+            Err(e) => {
+                req_eprintln!("Failed to look up distfiles identical to {}: {}", file, e);
+                return None;
+            }
+        };
+        for blob in candidates {
+            if let Some((blob, path)) = usable(blob).await {
+                if let Err(e) = self.repo_db.set_alias(file, &blob).await {
+                    req_eprintln!("Failed to record {} as alias of {}: {}", file, blob, e);
+                }
+                return Some((blob, path));
+            }
+        }
+
+        None
+    }
+
+    /// move a blob fetched in read-only mode out of the storage
+    /// requests waiting on its fetch job get it through the job
+    /// returns the link of it this request serves
+    ///
+    /// @param file  file name
+    /// @param path  location of the blob
+    async fn make_transient(&self, file: &str, path: &Path) -> Result<std::path::PathBuf, String> {
+        let shared = self.transient_location(file);
+        if let Err(e) = fs::rename(path, &shared).await {
+            let _ = fs::remove_file(path).await;
+            return Err(format!("Could not move {} out of the storage: {}", file, e));
+        }
+        req_println!("Serving {} without storing it", file);
+
+        let own = self.link_transient(file, &shared).await;
+        match self.fetch_jobs.lock().await.get(file) {
+            Some(job) => {
+                let _ = job.transient.set(shared);
+            }
+            None => {
+                let _ = fs::remove_file(&shared).await;
+            }
+        }
+        own
+    }
+
+    /// hard link a transient blob for one request to serve and remove
+    ///
+    /// @param file       file name
+    /// @param transient  the blob shared by a fetch job
+    async fn link_transient(
+        &self,
+        file: &str,
+        transient: &Path,
+    ) -> Result<std::path::PathBuf, String> {
+        let link = self.transient_location(file);
+        fs::hard_link(transient, &link)
+            .await
+            .map_err(|e| format!("Could not link transient {}: {}", file, e))?;
+        Ok(link)
+    }
+
+    /// unused location in the transient directory for file
+    ///
+    /// @param file  file name
+    fn transient_location(&self, file: &str) -> std::path::PathBuf {
+        let seq = self.transient_seq.fetch_add(1, Ordering::Relaxed);
+        self.transient.join(format!("{}-{}", seq, file))
+    }
+
+    /// store a freshly fetched blob compressed if it matches storage.compress
+    /// waiters are only woken afterwards so all of them serve the compressed blob
+    ///
+    /// @param file  file name
+    /// @param path  location of the blob
+    async fn compress(&self, file: &str, path: &Path) {
+        let Some(compression) = &self.compression else {
+            return;
+        };
+        if !compression.matches(file) {
+            return;
+        }
+        match compression.compress(path).await {
+            Ok(Some((original, compressed))) => req_println!(
+                "Stored {} compressed ({} -> {} bytes)",
+                file,
+                original,
+                compressed
+            ),
+            Ok(None) => (),
+            Err(e) => req_eprintln!("{}", e),
+        }
+    }
+
+    /// hard link a blob into storage.local_distdir if set
+    /// compressed blobs aren't linked since Portage would see their compressed content
+    ///
+    /// @param file  file name
+    /// @param path  location of the blob
+    async fn link_local(&self, file: &str, path: &Path) {
+        if let Some(distdir) = &self.local_distdir
+            && !compression::is_compressed(path).await
+            && let Err(e) = distdir.link(file, path).await
+        {
+            req_eprintln!("{}", e);
+        }
+    }
+
+    /// remove the link of a blob from storage.local_distdir if set
+    ///
+    /// @param file  file name
+    /// @param path  location of the blob
+    async fn unlink_local(&self, file: &str, path: &Path) {
+        if let Some(distdir) = &self.local_distdir {
+            distdir.unlink(file, path).await;
+        }
+    }
+
+    /// hard link every cached blob into storage.local_distdir
+    /// for blobs cached before it was set, later ones get linked when fetched or requested
+    /// returns the number of links created
+    pub async fn link_local_distdir(&self) -> Result<u64, String> {
+        let Some(distdir) = &self.local_distdir else {
+            return Ok(0);
+        };
+
+        let mut linked = 0;
+        for blob in self.blobs().await? {
+            if self.stale.lock().await.contains(&blob.file) {
+                continue;
+            }
+            let path = self.blob_location(&blob.file).await?;
+            if compression::is_compressed(&path).await {
+                continue;
+            }
+            if distdir.link(&blob.file, &path).await? {
+                linked += 1;
+            }
+        }
+        Ok(linked)
+    }
+
+    /// whether another process sharing the storage is fetching file right now
+    ///
+    /// @param file  file name
+    fn fetching_elsewhere(&self, file: &str) -> bool {
+        let path = self.fetch_locks.join(file);
+        if !path.exists() {
+            return false;
+        }
+        match std::fs::File::open(&path) {
+            Ok(lock) => matches!(
+                Flock::lock(lock, FlockArg::LockSharedNonblock),
+                Err((_, Errno::EWOULDBLOCK))
+            ),
+            Err(_) => false,
+        }
+    }
+
+    /// mark file as being fetched by this process
+    /// waits for other processes sharing the storage to finish fetching it first
+    /// returns None if the lock file can't be used, the fetch goes on unlocked then
+    ///
+    /// @param file  file name
+    pub(crate) async fn lock_fetch(&self, file: &str) -> Option<FetchLock> {
+        let path = self.fetch_locks.join(file);
+        loop {
+            let lock = fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)
+                .await;
+            let lock = match lock {
+                Ok(lock) => lock.into_std().await,
+                Err(e) => {
+                    req_eprintln!("Cannot open fetch lock {}: {}", path.to_string_lossy(), e);
+                    return None;
+                }
+            };
+
+            let result = match Flock::lock(lock, FlockArg::LockExclusiveNonblock) {
+                Err((lock, Errno::EWOULDBLOCK)) => {
+                    req_println!("{} is being fetched by another instance - waiting", file);
+                    match task::spawn_blocking(move || Flock::lock(lock, FlockArg::LockExclusive))
+                        .await
+                    {
+                        Ok(result) => result,
+                        Err(e) => {
+                            req_eprintln!("Cannot wait for {}: {}", path.to_string_lossy(), e);
+                            return None;
+                        }
+                    }
+                }
+                result => result,
+            };
+
+            match result {
+                // the other instance removes the lock file once it's done
+                // so a lock on a file no longer there doesn't keep anyone out
+                Ok(lock) => {
+                    let locked = lock.metadata().ok();
+                    let current = fs::metadata(&path).await.ok();
+                    if let (Some(locked), Some(current)) = (locked, current)
+                        && (locked.dev(), locked.ino()) == (current.dev(), current.ino())
+                    {
+                        return Some(FetchLock { path, _lock: lock });
+                    }
+                }
+                Err((_, e)) => {
+                    req_eprintln!("Cannot lock {}: {}", path.to_string_lossy(), e);
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// wait for the fetch job of another request
+    /// gives up once deadline passed while the fetch is still queued
+    ///
+    /// @param job       the running fetch job
+    /// @param deadline  end of the wait budget
+    async fn wait_for(&self, job: &FetchJob, deadline: Instant) -> Result<(), QueueBusy> {
+        let mut span = telemetry::span("blob_storage.wait_for_fetch", SpanKind::Internal);
+        let notified = job.notify.notified();
+        tokio::pin!(notified);
+        if time::timeout_at(deadline, &mut notified).await.is_ok() {
+            return Ok(());
+        }
+
+        match job.ticket.load(Ordering::SeqCst) {
+            // the fetch is running so it's worth waiting for
+            0 => {
+                notified.await;
+                Ok(())
+            }
+            ticket => {
+                let busy = self.queue.busy(ticket);
+                span.fail(&busy);
+                Err(busy)
+            }
+        }
+    }
+
+    /// wait for a free fetch slot
+    /// returns None if fetches aren't limited
+    ///
+    /// @param job       the fetch job waiting
+    /// @param deadline  end of the wait budget
+    async fn fetch_slot(
+        &self,
+        job: &FetchJob,
+        deadline: Instant,
+    ) -> Result<Option<SemaphorePermit<'_>>, QueueBusy> {
+        let permits = match &self.queue.permits {
+            Some(permits) => permits,
+            None => return Ok(None),
+        };
+        if let Ok(permit) = permits.try_acquire() {
+            return Ok(Some(permit));
+        }
+
+        let ticket = self.queue.tickets.fetch_add(1, Ordering::SeqCst) + 1;
+        job.ticket.store(ticket, Ordering::SeqCst);
+        let mut span = telemetry::span("fetch_queue.wait", SpanKind::Internal);
+        span.set("portcache.queue.position", self.queue.busy(ticket).position);
+        let acquired = time::timeout_at(deadline, permits.acquire()).await;
+        let busy = self.queue.busy(ticket);
+        self.queue.dequeued.fetch_add(1, Ordering::SeqCst);
+        job.ticket.store(0, Ordering::SeqCst);
+
+        match acquired {
+            Ok(permit) => Ok(Some(permit.expect("fetch queue closed"))),
+            Err(_) => {
+                span.fail(&busy);
+                Err(busy)
+            }
+        }
+    }
+
+    /// hand a failed fetch job over to a waiting request to retry
+    /// or drop the job if nobody waits
+    /// returns whether the job got handed over
+    ///
+    /// @param file  file name
+    async fn hand_over(&self, file: &str) -> bool {
+        let mut fetch_jobs = self.fetch_jobs.lock().await;
+        if let Some(job) = fetch_jobs.get(file) {
+            if Arc::strong_count(job) > 1 {
+                req_eprintln!("Notifying waiting threads to retry download for {}", file);
+                job.notify.notify_one();
+                return true;
+            }
+            req_eprintln!("No waiting threads - not retrying download for {}", file);
+            fetch_jobs.remove(file);
+        }
+        false
+    }
+
+    /// replace a cached blob with its refetch once that verified
+    /// or drop the refetch and keep the cached blob
+    /// returns whether the blob got replaced
+    ///
+    /// @param file         file name
+    /// @param path         location of the blob
+    /// @param replacement  where the refetch was written to
+    /// @param fetched      whether the refetch succeeded
+    async fn finish_revalidation(
+        &self,
+        file: &String,
+        path: &Path,
+        replacement: &Path,
+        fetched: bool,
+    ) -> bool {
+        if fetched {
+            self.unlink_local(file, path).await;
+            match fs::rename(replacement, path).await {
+                Ok(_) => {
+                    req_println!("Replaced cached blob {} with its refetch", file);
+                    self.forget_stale(file).await;
+                    self.fetcher.record_refetch(file, path).await;
+                    return true;
+                }
+                Err(e) => req_eprintln!("Could not replace cached blob {}: {}", file, e),
+            }
+        } else {
+            req_eprintln!("Refetch of {} failed - keeping the cached blob", file);
+        }
+
+        if let Err(e) = fs::remove_file(replacement).await
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            req_eprintln!("Could not remove failed refetch of {}: {}", file, e);
+        }
+        false
+    }
+
+    /// mark a cached blob as stale
+    /// it keeps getting served until a refetch on the next request succeeds
+    /// returns false if the blob isn't cached
+    ///
+    /// @param file  file name
+    pub async fn mark_stale(&self, file: &str) -> Result<bool, String> {
+        if !self.blob_location(file).await?.is_file() {
+            return Ok(false);
+        }
+
+        self.repo_db
+            .mark_stale_blob(file)
+            .await
+            .map_err(|e| e.to_string())?;
+        self.stale.lock().await.insert(file.to_string());
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        self.unlink_local(file, &self.blob_location(file).await?)
+            .await;
+
+        Ok(true)
+    }
+
+    /// counter increased by every fetch, removal and stale mark of a blob
+    /// so listings of the blobs can tell when they're outdated
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    /// whether a blob is marked stale and gets refetched on its next request
+    ///
+    /// @param file  file name
+    pub async fn is_stale(&self, file: &str) -> bool {
+        self.stale.lock().await.contains(file)
+    }
+
+    /// remove a cached blob
+    /// blobs currently being fetched are left alone
+    /// returns whether the blob got removed
+    ///
+    /// @param file  file name
+    pub async fn remove(&self, file: &str) -> Result<bool, String> {
+        let path = self.blob_location(file).await?;
+
+        // hold the lock so no fetch of file can start meanwhile
+        let fetch_jobs = self.fetch_jobs.lock().await;
+        if fetch_jobs.contains_key(file) {
+            return Ok(false);
+        }
+
+        self.unlink_local(file, &path).await;
+        fs::remove_file(&path).await.map_err(|e| e.to_string())?;
+        self.note_change(file);
+        drop(fetch_jobs);
+
+        self.forget_stale(file).await;
+        if let Err(e) = self.repo_db.remove_aliases(file).await {
+            req_eprintln!("Failed to forget aliases of {}: {}", file, e);
+        }
+        if let Err(e) = self.repo_db.remove_blob_source(file).await {
+            req_eprintln!("Failed to forget the upstream of {}: {}", file, e);
+        }
+        self.access_log.forget(file).await;
+        Ok(true)
+    }
+
+    /// drop the stale mark of a blob that's gone
+    ///
+    /// @param file  file name
+    pub(crate) async fn forget_stale(&self, file: &str) {
+        if self.stale.lock().await.remove(file)
+            && let Err(e) = self.repo_db.clear_stale_blob(file).await
+        {
+            req_eprintln!("Could not clear stale mark of {}: {}", file, e);
+        }
+    }
+
+    /// remember that this process just changed a blob
+    ///
+    /// @param file  file name
+    pub(crate) fn note_change(&self, file: &str) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        let mut own_changes = self.own_changes.lock().unwrap();
+        own_changes.retain(|_, changed| now.duration_since(*changed) < OWN_CHANGE_WINDOW);
+        own_changes.insert(file.to_string(), now);
+    }
+
+    /// whether a blob is being fetched or was changed by portcache recently
+    /// including fetches of other instances sharing the storage
+    ///
+    /// @param file  file name
+    pub async fn changed_by_us(&self, file: &str) -> bool {
+        let noted = self
+            .own_changes
+            .lock()
+            .unwrap()
+            .get(file)
+            .is_some_and(|changed| changed.elapsed() < OWN_CHANGE_WINDOW);
+        noted
+            || self.fetch_jobs.lock().await.contains_key(file)
+            || self.fetch_locks.join(file).exists()
+    }
+
+    /// changes to blobs made outside of portcache so far
+    pub fn external_changes(&self) -> &ExternalChanges {
+        &self.external
+    }
+}
+
+/// walk the blob storage for blobs
+/// i.e. <root>/<hash>/<file> skipping temporary files
+fn collect_blobs(root: PathBuf) -> Vec<StoredBlob> {
+    let mut blobs = Vec::new();
+    let mut seen = HashSet::new();
+
+    for entry in walkdir::WalkDir::new(root)
+        .min_depth(2)
+        .max_depth(2)
+        .into_iter()
+        .flatten()
+    {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let file = entry.file_name().to_string_lossy().to_string();
+        if file.ends_with(".stale") || file.ends_with(".part") || !seen.insert(file.clone()) {
+            continue;
+        }
+
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        let accessed = metadata.accessed().unwrap_or(modified);
+
+        blobs.push(StoredBlob {
+            file,
+            size: metadata.len(),
+            modified,
+            accessed,
+        });
+    }
+
+    blobs
+}
+
+/// make sure the storage uses the configured hash directory length
+/// storages predating the recorded length use the 8 bit Gentoo mirror layout
+///
+/// @param location  root of the blob storage
+/// @param bits      configured length of the hash directories
+/// @param repo_db   database the length is recorded in
+async fn check_layout(location: &Path, bits: u8, repo_db: &RepoDB) -> Result<(), String> {
+    let recorded = match repo_db.get_hash_bits().await.map_err(|e| e.to_string())? {
+        Some(recorded) => recorded,
+        None => {
+            let recorded = if has_hash_dirs(location) { 8 } else { bits };
+            repo_db
+                .set_hash_bits(recorded)
+                .await
+                .map_err(|e| e.to_string())?;
+            recorded
+        }
+    };
+
+    if recorded != bits {
+        return Err(format!(
+            "Blob storage uses {} bit hash directories but storage.hash_bits is {} - \
+            run `portcache reshard` to move the blobs",
+            recorded, bits
+        ));
+    }
+
+    Ok(())
+}
+
+/// whether a storage root contains any hash directories
+fn has_hash_dirs(location: &Path) -> bool {
+    std::fs::read_dir(location).is_ok_and(|mut entries| {
+        entries.any(|entry| entry.is_ok_and(|entry| entry.path().is_dir()))
+    })
+}
+
+/// outcome of a reshard run
+pub struct ReshardReport {
+    /// blobs moved into a new hash directory
+    pub moved: u64,
+
+    /// blobs already in the right directory
+    pub kept: u64,
+
+    /// hash directories left empty and removed
+    pub removed_dirs: u64,
+}
+
+/// move all blobs into the hash directories of storage.hash_bits
+/// blobs are renamed in place so nothing gets downloaded again
+/// must not run while portcache serves from the same storage
+///
+/// @param config   config with the new layout
+/// @param repo_db  database the layout gets recorded in
+pub async fn reshard(config: &config::Config, repo_db: &RepoDB) -> Result<ReshardReport, String> {
+    let location = config.storage.location.join("distfiles");
+    let bits = config.storage.hash_bits;
+
+    let root = location.clone();
+    let report = tokio::task::spawn_blocking(move || reshard_blobs(&root, bits))
+        .await
+        .map_err(|e| e.to_string())??;
+
+    repo_db
+        .set_hash_bits(bits)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // an rsync module exported from the storage announces the layout
+    let layout_conf = location.join("layout.conf");
+    if layout_conf.is_file() {
+        std::fs::write(&layout_conf, utils::layout_conf(bits, false))
+            .map_err(|e| format!("Cannot write {}: {}", layout_conf.to_string_lossy(), e))?;
+    }
+
+    Ok(report)
+}
+
+/// adopt the hash directory length of the primary mirror for storage.follow_upstream_layout
+/// blobs are moved like `portcache reshard` does if the mirror switched lengths,
+/// the adopted length is recorded so it's kept while the mirror can't be reached
+/// returns the length to serve with
+///
+/// @param config   config with the mirrors and the storage
+/// @param reshard  whether blobs may be moved, not while a predecessor still serves them
+pub async fn follow_upstream_layout(config: &config::Config, reshard: bool) -> Result<u8, String> {
+    let repo_db =
+        Arc::new(RepoDB::new(config).map_err(|e| format!("Failed to initialize database: {}", e))?);
+    let location = config.storage.location.join("distfiles");
+    let current = match repo_db.get_hash_bits().await.map_err(|e| e.to_string())? {
+        Some(recorded) => Some(recorded),
+        None if has_hash_dirs(&location) => Some(8),
+        None => None,
+    };
+    let kept = current.unwrap_or(config.storage.hash_bits);
+
+    let upstream = match fetcher::primary_mirror_layout(config, repo_db.clone()).await {
+        Ok(Layout::FileNameHash(bits)) => bits,
+        Ok(Layout::Flat) => {
+            println!(
+                "Primary mirror has a flat layout, keeping {} bit hash directories",
+                kept
+            );
+            return Ok(kept);
+        }
+        Err(e) => {
+            eprintln!(
+                "Cannot probe the layout of the primary mirror, keeping {} bit hash directories: {}",
+                kept, e
+            );
+            return Ok(kept);
+        }
+    };
+
+    match current {
+        Some(current) if current == upstream => (),
+        Some(current) if !reshard => {
+            println!(
+                "Primary mirror uses {} bit hash directories, moving blobs from {} bit ones on the next restart",
+                upstream, current
+            );
+            return Ok(current);
+        }
+        Some(current) => {
+            println!(
+                "Primary mirror uses {} bit hash directories, moving blobs from {} bit ones",
+                upstream, current
+            );
+            let mut adopted = config.clone();
+            adopted.storage.hash_bits = upstream;
+            let report = self::reshard(&adopted, &repo_db).await?;
+            println!(
+                "Moved {} blobs, {} already in place",
+                report.moved, report.kept
+            );
+        }
+        None => repo_db
+            .set_hash_bits(upstream)
+            .await
+            .map_err(|e| e.to_string())?,
+    }
+
+    Ok(upstream)
+}
+
+/// move the blobs of a storage root into the directories for bits
+/// partial and stale blobs move along with the blob they belong to
+fn reshard_blobs(root: &Path, bits: u8) -> Result<ReshardReport, String> {
+    let mut report = ReshardReport {
+        moved: 0,
+        kept: 0,
+        removed_dirs: 0,
+    };
+
+    let blobs: Vec<PathBuf> = walkdir::WalkDir::new(root)
+        .min_depth(2)
+        .max_depth(2)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.into_path())
+        .collect();
+
+    for blob in blobs {
+        let file = blob.file_name().unwrap_or_default().to_string_lossy();
+        let name = file
+            .strip_suffix(".part")
+            .or_else(|| file.strip_suffix(".stale"))
+            .unwrap_or(&file);
+
+        let target = root.join(utils::filename_hash_dir(name, bits)).join(&*file);
+        if target == blob {
+            report.kept += 1;
+            continue;
+        }
+
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| format!("Cannot create {}: {}", parent.to_string_lossy(), e))?;
+        }
+        std::fs::rename(&blob, &target).map_err(|e| {
+            format!(
+                "Cannot move {} to {}: {}",
+                blob.to_string_lossy(),
+                target.to_string_lossy(),
+                e
+            )
+        })?;
+        report.moved += 1;
+    }
+
+    // directories of the old layout are empty now
+    let dirs = std::fs::read_dir(root)
+        .map_err(|e| format!("Cannot read {}: {}", root.to_string_lossy(), e))?;
+    for dir in dirs.flatten() {
+        if dir.path().is_dir() && std::fs::remove_dir(dir.path()).is_ok() {
+            report.removed_dirs += 1;
+        }
+    }
+
+    Ok(report)
 }
@@ -0,0 +1,195 @@
+use notify::event::{AccessKind, AccessMode, EventKind, ModifyKind};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time;
+
+use crate::blob_storage::BlobStorage;
+use crate::config::Config;
+use crate::fetcher;
+use crate::repo_db::RepoDB;
+
+/// how long to wait for a burst of events on the same blobs to end
+const SETTLE: Duration = Duration::from_secs(1);
+
+/// what happened to a blob according to check()
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlobChange {
+    /// portcache changed it itself
+    Ours,
+
+    /// it got deleted
+    Removed,
+
+    /// it got replaced with content matching its Manifest entry
+    Verified,
+
+    /// it got replaced and has no Manifest entry to verify it against
+    Unverified,
+
+    /// it got replaced with content not matching its Manifest entry
+    /// so it got marked stale
+    Mismatched(String),
+}
+
+/// watches the blob storage for blobs deleted or replaced behind portcache's back
+/// and updates stale marks and the external change counters accordingly
+pub struct BlobWatcher {
+    /// the watched storage
+    blob_storage: Arc<BlobStorage>,
+
+    /// repo database with the Manifest entries blobs are verified against
+    repo_db: Arc<RepoDB>,
+}
+
+impl BlobWatcher {
+    /// create a BlobWatcher from config
+    /// returns None unless storage.watch_blobs is set
+    pub fn new(
+        config: &Config,
+        blob_storage: Arc<BlobStorage>,
+        repo_db: Arc<RepoDB>,
+    ) -> Option<Self> {
+        config.storage.watch_blobs.then_some(Self {
+            blob_storage,
+            repo_db,
+        })
+    }
+
+    /// start the BlobWatcher
+    /// this is expected to be called from a tokio::spawn
+    /// and consumes the BlobWatcher
+    pub async fn start(self) {
+        let root = self.blob_storage.location().to_path_buf();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let _watcher = match watch(&root, tx) {
+            Ok(watcher) => {
+                println!(
+                    "Watching {} for blobs changed outside of portcache",
+                    root.to_string_lossy()
+                );
+                watcher
+            }
+            Err(e) => {
+                eprintln!("Failed to watch {}: {}", root.to_string_lossy(), e);
+                return;
+            }
+        };
+
+        while let Some(file) = rx.recv().await {
+            let mut files = HashSet::from([file]);
+            time::sleep(SETTLE).await;
+            while let Ok(file) = rx.try_recv() {
+                files.insert(file);
+            }
+
+            for file in files {
+                if let Err(e) = self.check(&file).await {
+                    eprintln!("Failed to check changed blob {}: {}", file, e);
+                }
+            }
+        }
+    }
+
+    /// look at a blob a file event was seen for
+    /// removed blobs lose their stale mark
+    /// replaced blobs get verified and marked stale on mismatch
+    ///
+    /// @param file  name of the blob
+    pub async fn check(&self, file: &str) -> Result<BlobChange, String> {
+        if self.blob_storage.changed_by_us(file).await {
+            return Ok(BlobChange::Ours);
+        }
+
+        let external = self.blob_storage.external_changes();
+        let path = self.blob_storage.blob_location(file).await?;
+        if !path.is_file() {
+            println!("Blob {} was removed outside of portcache", file);
+            self.blob_storage.forget_stale(file).await;
+            external.removed.fetch_add(1, Ordering::Relaxed);
+            return Ok(BlobChange::Removed);
+        }
+
+        let entry = self
+            .repo_db
+            .get_manifest_entry(file)
+            .await
+            .map_err(|e| e.to_string())?;
+        let change = match entry {
+            Some(entry) => match fetcher::manifest_mismatch(&path, &entry).await {
+                Ok(Some(mismatch)) => BlobChange::Mismatched(mismatch),
+                Ok(None) => BlobChange::Verified,
+                Err(e) => return Err(e.to_string()),
+            },
+            None => BlobChange::Unverified,
+        };
+
+        match &change {
+            BlobChange::Mismatched(mismatch) => {
+                eprintln!(
+                    "Blob {} was replaced outside of portcache and doesn't match its Manifest \
+                    - marking it stale: {}",
+                    file, mismatch
+                );
+                self.blob_storage.mark_stale(file).await?;
+                external.mismatched.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {
+                println!("Blob {} was replaced outside of portcache", file);
+                external.replaced.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        Ok(change)
+    }
+}
+
+/// name of the blob a path in the storage belongs to
+/// None for anything but <root>/<hash>/<file> and temporary files
+///
+/// @param root  root of the blob storage
+/// @param path  path of a file event
+fn blob_name(root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+    if relative.components().count() != 2 {
+        return None;
+    }
+
+    let file = relative.file_name()?.to_string_lossy().to_string();
+    (!file.starts_with('.') && !file.ends_with(".part") && !file.ends_with(".stale"))
+        .then_some(file)
+}
+
+/// watch the blob storage for deleted and written blobs
+/// dropping the returned watcher stops watching
+///
+/// @param root  root of the blob storage
+/// @param tx    channel the names of changed blobs are sent to
+fn watch(root: &Path, tx: mpsc::UnboundedSender<String>) -> notify::Result<RecommendedWatcher> {
+    let watched = root.to_path_buf();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event
+            && matches!(
+                event.kind,
+                EventKind::Create(_)
+                    | EventKind::Remove(_)
+                    | EventKind::Modify(ModifyKind::Data(_))
+                    | EventKind::Modify(ModifyKind::Name(_))
+                    | EventKind::Access(AccessKind::Close(AccessMode::Write))
+            )
+        {
+            for path in &event.paths {
+                if let Some(file) = blob_name(&watched, path) {
+                    let _ = tx.send(file);
+                }
+            }
+        }
+    })?;
+
+    watcher.watch(root, RecursiveMode::Recursive)?;
+    Ok(watcher)
+}
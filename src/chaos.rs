@@ -0,0 +1,151 @@
+use nix::errno::Errno;
+use rocket::http::{ContentType, Status};
+use rocket::{State, delete, get, put};
+use serde::{Deserialize, Serialize};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use crate::admin::Admin;
+use crate::app::SharedData;
+
+/// faults injected into distfile fetches
+/// for testing how the daemon copes with broken mirrors and disks
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Faults {
+    /// share of upstream responses replaced by upstream_status, 0 to 1
+    pub upstream_error_rate: f64,
+
+    /// status the failing upstream responses get
+    pub upstream_status: u16,
+
+    /// delay per chunk received from upstream in milliseconds
+    pub stream_delay_ms: u64,
+
+    /// fail writes to the blob storage with ENOSPC
+    pub disk_full: bool,
+
+    /// only inject faults for distfiles whose name contains this
+    pub only: Option<String>,
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Self {
+            upstream_error_rate: 0.0,
+            upstream_status: 503,
+            stream_delay_ms: 0,
+            disk_full: false,
+            only: None,
+        }
+    }
+}
+
+impl Faults {
+    /// reasons the faults can't be injected
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if !(0.0..=1.0).contains(&self.upstream_error_rate) {
+            problems.push(format!(
+                "upstream_error_rate must be between 0 and 1, got {}",
+                self.upstream_error_rate
+            ));
+        }
+        if !(400..=599).contains(&self.upstream_status) {
+            problems.push(format!(
+                "upstream_status must be an error status, got {}",
+                self.upstream_status
+            ));
+        }
+        problems
+    }
+
+    /// whether faults apply to file
+    fn applies(&self, file: &str) -> bool {
+        self.only.as_ref().is_none_or(|only| file.contains(only))
+    }
+}
+
+/// faults currently injected, changed at runtime via the admin API
+#[derive(Default)]
+pub struct Chaos {
+    /// the injected faults
+    faults: RwLock<Faults>,
+}
+
+impl Chaos {
+    /// the injected faults
+    pub fn faults(&self) -> Faults {
+        self.faults
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// replace the injected faults
+    pub fn set(&self, faults: Faults) -> Result<(), Vec<String>> {
+        let problems = faults.problems();
+        if !problems.is_empty() {
+            return Err(problems);
+        }
+        *self.faults.write().unwrap_or_else(|e| e.into_inner()) = faults;
+        Ok(())
+    }
+
+    /// status an upstream response for file gets replaced with
+    pub fn upstream_failure(&self, file: &str) -> Option<u16> {
+        let faults = self.faults.read().unwrap_or_else(|e| e.into_inner());
+        (faults.applies(file)
+            && faults.upstream_error_rate > 0.0
+            && fastrand::f64() < faults.upstream_error_rate)
+            .then_some(faults.upstream_status)
+    }
+
+    /// delay before each chunk of file gets stored
+    pub fn stream_delay(&self, file: &str) -> Option<Duration> {
+        let faults = self.faults.read().unwrap_or_else(|e| e.into_inner());
+        (faults.applies(file) && faults.stream_delay_ms > 0)
+            .then(|| Duration::from_millis(faults.stream_delay_ms))
+    }
+
+    /// error writing file to the storage fails with
+    pub fn disk_error(&self, file: &str) -> Option<std::io::Error> {
+        let faults = self.faults.read().unwrap_or_else(|e| e.into_inner());
+        (faults.applies(file) && faults.disk_full).then(|| Errno::ENOSPC.into())
+    }
+}
+
+/// the injected faults
+#[get("/api/v1/admin/chaos")]
+pub(crate) async fn faults(_admin: Admin, shared: &State<SharedData>) -> (ContentType, String) {
+    let faults = shared.blob_storage.chaos().faults();
+    let body = serde_json::to_string(&faults).unwrap_or_else(|_| String::from("{}"));
+    (ContentType::JSON, body)
+}
+
+/// inject faults given as JSON, unset fields are reset to their defaults
+#[put("/api/v1/admin/chaos", data = "<body>")]
+pub(crate) async fn inject(
+    _admin: Admin,
+    body: String,
+    shared: &State<SharedData>,
+) -> Result<Status, (Status, String)> {
+    let faults: Faults =
+        serde_json::from_str(&body).map_err(|e| (Status::BadRequest, e.to_string()))?;
+    shared
+        .blob_storage
+        .chaos()
+        .set(faults.clone())
+        .map_err(|problems| (Status::BadRequest, problems.join("\n")))?;
+
+    eprintln!("Injecting faults: {:?}", faults);
+    Ok(Status::NoContent)
+}
+
+/// stop injecting faults
+#[delete("/api/v1/admin/chaos")]
+pub(crate) async fn clear(_admin: Admin, shared: &State<SharedData>) -> Status {
+    let _ = shared.blob_storage.chaos().set(Faults::default());
+    eprintln!("Stopped injecting faults");
+    Status::NoContent
+}
@@ -0,0 +1,304 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use openssl::pkey::{Id, PKey, Private};
+use openssl::sign::Signer;
+use rocket::http::{self, ContentType, Header};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use rocket::{State, get};
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::app::SharedData;
+use crate::blob_storage::BlobStorage;
+use crate::compression;
+use crate::config::Config;
+use crate::repo_db::RepoDB;
+use crate::repo_syncer::SyncProgress;
+use crate::utils;
+
+/// version of the index format, bumped on incompatible changes
+const INDEX_VERSION: u32 = 1;
+
+/// header carrying the signature of the index
+pub const SIGNATURE_HEADER: &str = "X-Portcache-Signature";
+
+/// ed25519 key signing the index
+/// stored like Nix binary cache keys as <name>:<base64 of seed and public key>
+/// so keys made by `nix-store --generate-binary-cache-key` work too
+pub struct SigningKey {
+    /// name of the key, prefixed to signatures and the public key
+    name: String,
+
+    /// the private key
+    key: PKey<Private>,
+}
+
+impl SigningKey {
+    /// generate a new key
+    ///
+    /// @param name  name of the key, e.g. the host name of the cache
+    pub fn generate(name: &str) -> Result<Self, String> {
+        if name.is_empty() || name.contains(':') {
+            return Err(format!(
+                "Key name \"{}\" must be non-empty without ':'",
+                name
+            ));
+        }
+        let key = PKey::generate_ed25519().map_err(|e| format!("Cannot generate key: {}", e))?;
+        Ok(Self {
+            name: name.to_string(),
+            key,
+        })
+    }
+
+    /// read a key from a file
+    ///
+    /// @param path  the key file
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read {}: {}", path.to_string_lossy(), e))?;
+        let invalid = || format!("{} is no <name>:<base64> key", path.to_string_lossy());
+
+        let (name, encoded) = content.trim().split_once(':').ok_or_else(invalid)?;
+        let secret = STANDARD.decode(encoded).map_err(|_| invalid())?;
+        // the seed, optionally followed by the public key
+        if name.is_empty() || !matches!(secret.len(), 32 | 64) {
+            return Err(invalid());
+        }
+        let key =
+            PKey::private_key_from_raw_bytes(&secret[..32], Id::ED25519).map_err(|_| invalid())?;
+        Ok(Self {
+            name: name.to_string(),
+            key,
+        })
+    }
+
+    /// write the key to a file only the owner may read
+    ///
+    /// @param path  the key file, must not exist yet
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        use std::io::Write;
+
+        let mut secret = self.raw(self.key.raw_private_key())?;
+        secret.extend(self.raw(self.key.raw_public_key())?);
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .map_err(|e| format!("Cannot create {}: {}", path.to_string_lossy(), e))?;
+        writeln!(file, "{}:{}", self.name, STANDARD.encode(secret))
+            .map_err(|e| format!("Cannot write {}: {}", path.to_string_lossy(), e))
+    }
+
+    /// the public key as <name>:<base64> to verify signatures with
+    pub fn public_key(&self) -> Result<String, String> {
+        let public = self.raw(self.key.raw_public_key())?;
+        Ok(format!("{}:{}", self.name, STANDARD.encode(public)))
+    }
+
+    /// sign data
+    /// returns the signature as <name>:<base64>
+    ///
+    /// @param data  the signed data
+    pub fn sign(&self, data: &[u8]) -> Result<String, String> {
+        let signature = Signer::new_without_digest(&self.key)
+            .and_then(|mut signer| signer.sign_oneshot_to_vec(data))
+            .map_err(|e| format!("Cannot sign: {}", e))?;
+        Ok(format!("{}:{}", self.name, STANDARD.encode(signature)))
+    }
+
+    /// unwrap raw key bytes
+    fn raw(&self, bytes: Result<Vec<u8>, openssl::error::ErrorStack>) -> Result<Vec<u8>, String> {
+        bytes.map_err(|e| format!("Cannot export key {}: {}", self.name, e))
+    }
+}
+
+/// the index as last built
+struct Built {
+    /// blob changes and the end of the last sync it reflects
+    state: (u64, Option<u64>),
+
+    /// the serialized index
+    body: String,
+
+    /// signature of body
+    signature: String,
+}
+
+/// signed JSON index of the cached distfiles and their checksums
+/// for caching layers and CDN pre-warmers mirroring the cache
+/// rebuilt on request once fetches, evictions or a sync changed what it lists
+pub struct ChecksumIndex {
+    /// key signing the index
+    key: SigningKey,
+
+    /// storage whose blobs are listed
+    blob_storage: Arc<BlobStorage>,
+
+    /// repo database with the checksums
+    repo_db: Arc<RepoDB>,
+
+    /// progress of the repo syncer, syncs can change the checksums
+    sync_progress: Arc<SyncProgress>,
+
+    /// the index as last built, locked while rebuilding so requests share a rebuild
+    built: Mutex<Option<Arc<Built>>>,
+}
+
+impl ChecksumIndex {
+    /// create a ChecksumIndex from config
+    /// returns None without an [index] section or if its key can't be read
+    ///
+    /// @param config         a reference to Config
+    /// @param blob_storage   storage whose blobs are listed
+    /// @param repo_db        repo database with the checksums
+    /// @param sync_progress  progress of the repo syncer
+    pub fn new(
+        config: &Config,
+        blob_storage: Arc<BlobStorage>,
+        repo_db: Arc<RepoDB>,
+        sync_progress: Arc<SyncProgress>,
+    ) -> Option<Self> {
+        let index = config.index.as_ref()?;
+        let key = match SigningKey::load(&index.signing_key) {
+            Ok(key) => key,
+            Err(e) => {
+                eprintln!("Not serving the checksum index: {}", e);
+                return None;
+            }
+        };
+        Some(Self {
+            key,
+            blob_storage,
+            repo_db,
+            sync_progress,
+            built: Mutex::new(None),
+        })
+    }
+
+    /// the public key signatures of the index can be verified with
+    pub fn public_key(&self) -> Result<String, String> {
+        self.key.public_key()
+    }
+
+    /// the current index, rebuilt if it's outdated
+    async fn current(&self) -> Result<Arc<Built>, String> {
+        let mut built = self.built.lock().await;
+        let state = (
+            self.blob_storage.generation(),
+            self.sync_progress.status().last.map(|last| last.finished),
+        );
+        if let Some(built) = built.as_ref()
+            && built.state == state
+        {
+            return Ok(built.clone());
+        }
+
+        let body = self.build().await?;
+        let signature = self.key.sign(body.as_bytes())?;
+        let fresh = Arc::new(Built {
+            state,
+            body,
+            signature,
+        });
+        *built = Some(fresh.clone());
+        Ok(fresh)
+    }
+
+    /// list the cached blobs with the checksums they were verified against
+    /// checksums come from their Manifest entry or their first download
+    /// stale blobs and blobs without any checksum are left out
+    async fn build(&self) -> Result<String, String> {
+        let mut blobs = self.blob_storage.blobs().await?;
+        blobs.sort_unstable_by(|a, b| a.file.cmp(&b.file));
+
+        let names: Vec<String> = blobs.iter().map(|blob| blob.file.clone()).collect();
+        let entries = self
+            .repo_db
+            .get_manifest_entries(&names)
+            .await
+            .map_err(|e| format!("Failed to query Manifest entries: {}", e))?;
+        let tofu: HashMap<String, String> = self
+            .repo_db
+            .get_tofu_checksums(false)
+            .await
+            .map_err(|e| format!("Failed to query first use checksums: {}", e))?
+            .into_iter()
+            .map(|checksum| (checksum.file, checksum.blake2b))
+            .collect();
+
+        let mut files = Vec::new();
+        for blob in blobs {
+            if self.blob_storage.is_stale(&blob.file).await {
+                continue;
+            }
+            let (blake2b, sha512) = match entries.get(&blob.file) {
+                Some(entry) if entry.blake2b.is_some() || entry.sha512.is_some() => {
+                    (entry.blake2b.clone(), entry.sha512.clone())
+                }
+                _ => match tofu.get(&blob.file) {
+                    Some(blake2b) => (Some(blake2b.clone()), None),
+                    None => continue,
+                },
+            };
+            let path = self.blob_storage.blob_location(&blob.file).await?;
+            files.push(json!({
+                "file": blob.file,
+                "path": format!("distfiles/{}/{}", self.blob_storage.hash_dir(&blob.file), blob.file),
+                "size": compression::content_size(&path, blob.size).await,
+                "blake2b": blake2b,
+                "sha512": sha512,
+            }));
+        }
+
+        Ok(json!({
+            "version": INDEX_VERSION,
+            "generated": utils::unix_time(),
+            "files": files,
+        })
+        .to_string())
+    }
+}
+
+/// the index along with its signature
+pub(crate) struct SignedIndex(Arc<Built>);
+
+impl<'r> Responder<'r, 'static> for SignedIndex {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let body = self.0.body.clone();
+        Response::build()
+            .header(ContentType::JSON)
+            .header(Header::new(SIGNATURE_HEADER, self.0.signature.clone()))
+            .sized_body(body.len(), Cursor::new(body))
+            .ok()
+    }
+}
+
+/// signed index of the cached distfiles and their checksums
+/// the ed25519 signature of the body is sent in the X-Portcache-Signature header
+#[get("/api/v1/index")]
+pub(crate) async fn index(shared: &State<SharedData>) -> Result<SignedIndex, http::Status> {
+    let index = shared.index.as_ref().ok_or(http::Status::NotFound)?;
+    let built = index.current().await.map_err(|e| {
+        eprintln!("Failed to build the checksum index: {}", e);
+        http::Status::InternalServerError
+    })?;
+    Ok(SignedIndex(built))
+}
+
+/// public key the index is signed with as <name>:<base64>
+#[get("/api/v1/index/key")]
+pub(crate) async fn index_key(shared: &State<SharedData>) -> Result<String, http::Status> {
+    let index = shared.index.as_ref().ok_or(http::Status::NotFound)?;
+    index.public_key().map_err(|e| {
+        eprintln!("{}", e);
+        http::Status::InternalServerError
+    })
+}
@@ -0,0 +1,325 @@
+use async_compression::tokio::bufread::ZstdDecoder;
+use std::fs::{File, FileTimes, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncSeek, ReadBuf};
+
+use crate::config::Config;
+use crate::utils;
+
+/// magic number of the zstd skippable frame starting a compressed blob
+const MAGIC: u32 = 0x184D2A5E;
+
+/// payload of the skippable frame before the original size
+const MARKER: &[u8; 9] = b"portcache";
+
+/// length of the skippable frame i.e. magic, frame size, marker and original size
+pub const HEADER_LEN: usize = 4 + 4 + MARKER.len() + 8;
+
+/// blobs smaller than this aren't worth compressing
+const MIN_SIZE: u64 = 4096;
+
+/// compressed blobs have to end up below this fraction of their size to be kept
+const MAX_RATIO: f64 = 0.9;
+
+/// transparent zstd compression of blobs at rest
+/// compressed blobs stay at their location and are a valid zstd stream,
+/// led by a skippable frame holding the original size so they can be told apart
+pub struct Compression {
+    /// globs of file names to compress
+    patterns: Vec<String>,
+
+    /// zstd compression level
+    level: i32,
+}
+
+impl Compression {
+    /// create Compression from config
+    /// returns None unless storage.compress lists any patterns
+    ///
+    /// @param config  a reference to Config
+    pub fn new(config: &Config) -> Option<Self> {
+        if config.storage.compress.is_empty() {
+            return None;
+        }
+        Some(Self {
+            patterns: config.storage.compress.clone(),
+            level: config.storage.compress_level,
+        })
+    }
+
+    /// whether a blob should be stored compressed
+    ///
+    /// @param file  name of the blob
+    pub fn matches(&self, file: &str) -> bool {
+        self.patterns
+            .iter()
+            .any(|pattern| utils::glob_match(pattern, file))
+    }
+
+    /// compress a blob in place keeping its timestamps
+    /// blobs that are already compressed, tiny or don't shrink enough are left alone
+    /// returns the original and compressed size if it got compressed
+    ///
+    /// @param path  location of the blob
+    pub async fn compress(&self, path: &Path) -> Result<Option<(u64, u64)>, String> {
+        let level = self.level;
+        let blob = path.to_path_buf();
+        tokio::task::spawn_blocking(move || compress_blob(&blob, level))
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|e| format!("Failed to compress {}: {}", path.to_string_lossy(), e))
+    }
+}
+
+/// the skippable frame leading a compressed blob
+///
+/// @param size  original size of the blob
+fn header(size: u64) -> [u8; HEADER_LEN] {
+    let mut header = [0; HEADER_LEN];
+    header[..4].copy_from_slice(&MAGIC.to_le_bytes());
+    header[4..8].copy_from_slice(&((HEADER_LEN - 8) as u32).to_le_bytes());
+    header[8..8 + MARKER.len()].copy_from_slice(MARKER);
+    header[8 + MARKER.len()..].copy_from_slice(&size.to_le_bytes());
+    header
+}
+
+/// original size of a blob if header is the skippable frame of a compressed one
+///
+/// @param header  first HEADER_LEN bytes of the blob
+pub fn parse_header(header: &[u8; HEADER_LEN]) -> Option<u64> {
+    let valid = header[..4] == MAGIC.to_le_bytes()
+        && header[4..8] == ((HEADER_LEN - 8) as u32).to_le_bytes()
+        && header[8..8 + MARKER.len()] == *MARKER;
+    valid.then(|| u64::from_le_bytes(header[8 + MARKER.len()..].try_into().unwrap()))
+}
+
+/// read the header of a blob
+/// leaves file after the header if it's compressed and at its start otherwise
+/// returns the original size if it's compressed
+///
+/// @param file  the opened blob
+fn read_header(file: &mut File) -> io::Result<Option<u64>> {
+    let mut header = [0; HEADER_LEN];
+    let size = match file.read_exact(&mut header) {
+        Ok(_) => parse_header(&header),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
+        Err(e) => return Err(e),
+    };
+    if size.is_none() {
+        file.seek(SeekFrom::Start(0))?;
+    }
+    Ok(size)
+}
+
+/// async read_header for serving
+/// returns the original size if it's compressed
+///
+/// @param file  the opened blob
+pub async fn read_header_async(file: &mut tokio::fs::File) -> io::Result<Option<u64>> {
+    use tokio::io::AsyncSeekExt;
+
+    let mut header = [0; HEADER_LEN];
+    let size = match file.read_exact(&mut header).await {
+        Ok(_) => parse_header(&header),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
+        Err(e) => return Err(e),
+    };
+    if size.is_none() {
+        file.seek(SeekFrom::Start(0)).await?;
+    }
+    Ok(size)
+}
+
+/// size of a blob's content, decompressed if it's stored compressed
+///
+/// @param path    location of the blob
+/// @param stored  size of the blob on disk
+pub async fn content_size(path: &Path, stored: u64) -> u64 {
+    if stored < HEADER_LEN as u64 {
+        return stored;
+    }
+    let Ok(mut file) = tokio::fs::File::open(path).await else {
+        return stored;
+    };
+    read_header_async(&mut file)
+        .await
+        .ok()
+        .flatten()
+        .unwrap_or(stored)
+}
+
+/// whether a blob is stored compressed
+///
+/// @param path  location of the blob
+pub async fn is_compressed(path: &Path) -> bool {
+    match tokio::fs::File::open(path).await {
+        Ok(mut file) => matches!(read_header_async(&mut file).await, Ok(Some(_))),
+        Err(_) => false,
+    }
+}
+
+/// open a blob for reading its content, decompressing it on the fly if needed
+///
+/// @param path  location of the blob
+pub fn open(path: &Path) -> io::Result<Box<dyn Read + Send>> {
+    let mut file = File::open(path)?;
+    Ok(match read_header(&mut file)? {
+        Some(_) => Box::new(zstd::Decoder::new(file)?),
+        None => Box::new(file),
+    })
+}
+
+/// open a blob for random access to its content
+/// compressed blobs get decompressed into an unnamed file in scratch first
+///
+/// @param path     location of the blob
+/// @param scratch  directory for the decompressed copy
+pub fn open_unpacked(path: &Path, scratch: &Path) -> io::Result<File> {
+    let mut file = File::open(path)?;
+    if read_header(&mut file)?.is_none() {
+        return Ok(file);
+    }
+
+    let mut unpacked = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .mode(0o600)
+        .custom_flags(nix::libc::O_TMPFILE)
+        .open(scratch)?;
+    io::copy(&mut zstd::Decoder::new(file)?, &mut unpacked)?;
+    unpacked.seek(SeekFrom::Start(0))?;
+    Ok(unpacked)
+}
+
+/// location the compressed copy of a blob is written to
+fn part_location(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".zst.part");
+    PathBuf::from(part)
+}
+
+/// compress a blob in place, see Compression::compress
+fn compress_blob(path: &Path, level: i32) -> io::Result<Option<(u64, u64)>> {
+    let mut input = File::open(path)?;
+    let metadata = input.metadata()?;
+    if metadata.len() < MIN_SIZE || read_header(&mut input)?.is_some() {
+        return Ok(None);
+    }
+
+    let part = part_location(path);
+    let written = (|| {
+        let mut output = File::create(&part)?;
+        output.write_all(&header(metadata.len()))?;
+        let mut encoder = zstd::Encoder::new(output, level)?;
+        encoder.set_pledged_src_size(Some(metadata.len()))?;
+        io::copy(&mut input, &mut encoder)?;
+        let output = encoder.finish()?;
+        output.set_times(
+            FileTimes::new()
+                .set_modified(metadata.modified()?)
+                .set_accessed(metadata.accessed()?),
+        )?;
+        output.sync_all()?;
+        output.metadata().map(|written| written.len())
+    })();
+
+    let compressed = match written {
+        Ok(compressed) => compressed,
+        Err(e) => {
+            let _ = std::fs::remove_file(&part);
+            return Err(e);
+        }
+    };
+    if compressed as f64 >= metadata.len() as f64 * MAX_RATIO {
+        std::fs::remove_file(&part)?;
+        return Ok(None);
+    }
+
+    std::fs::rename(&part, path)?;
+    Ok(Some((metadata.len(), compressed)))
+}
+
+/// content of a compressed blob for streaming to a client
+/// sized bodies are never seeked so seeking is unsupported
+pub struct Decompressed<R>(ZstdDecoder<R>);
+
+impl<R: AsyncBufRead> Decompressed<R> {
+    /// decompress a blob
+    ///
+    /// @param blob  the blob positioned after its header
+    pub fn new(blob: R) -> Self {
+        Self(ZstdDecoder::new(blob))
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for Decompressed<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl<R> AsyncSeek for Decompressed<R> {
+    fn start_seek(self: Pin<&mut Self>, _: SeekFrom) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "decompressed blobs can't be seeked",
+        ))
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compressed_blobs_decompress_to_their_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("foo.patch");
+        let content = "--- a/foo.c\n+++ b/foo.c\n".repeat(1000);
+        std::fs::write(&path, &content).unwrap();
+
+        let (original, compressed) = compress_blob(&path, 3).unwrap().unwrap();
+        assert_eq!(original, content.len() as u64);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), compressed);
+        assert!(!part_location(&path).exists());
+
+        // plain zstd decoders skip the header
+        let decoded = zstd::decode_all(File::open(&path).unwrap()).unwrap();
+        assert_eq!(decoded, content.as_bytes());
+
+        let mut read = String::new();
+        open(&path).unwrap().read_to_string(&mut read).unwrap();
+        assert_eq!(read, content);
+
+        // compressing twice is a no-op
+        assert_eq!(compress_blob(&path, 3).unwrap(), None);
+    }
+
+    #[test]
+    fn incompressible_blobs_stay_plain() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("foo.tar.xz");
+        let content: Vec<u8> = (0..64 * 1024).map(|_| fastrand::u8(..)).collect();
+        std::fs::write(&path, &content).unwrap();
+
+        assert_eq!(compress_blob(&path, 3).unwrap(), None);
+        assert_eq!(std::fs::read(&path).unwrap(), content);
+        assert!(!part_location(&path).exists());
+
+        let mut read = Vec::new();
+        open(&path).unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, content);
+    }
+}
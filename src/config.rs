@@ -1,54 +1,2486 @@
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::net::IpAddr;
-use std::path::PathBuf;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+use crate::fetcher::tls;
+
+/// portcache configuration as read from portcache.toml
 #[derive(Deserialize, Clone)]
 pub struct Config {
+    /// [storage] section
+    #[serde(default)]
     pub storage: StorageConfig,
+
+    /// [fetcher] section
+    #[serde(default)]
     pub fetcher: FetcherConfig,
+
+    /// [server] section
+    #[serde(default)]
     pub server: ServerConfig,
+
+    /// [repo] section
+    /// without repos portcache only passes requests through to the fetchers
+    #[serde(default)]
     pub repo: RepoConfig,
+
+    /// [admin] section
+    #[serde(default)]
+    pub admin: AdminConfig,
+
+    /// [quota] section
+    #[serde(default)]
+    pub quota: QuotaConfig,
+
+    /// [api_keys] section
+    #[serde(default)]
+    pub api_keys: ApiKeysConfig,
+
+    /// [telemetry] section
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+
+    /// [sandbox] section
+    #[serde(default)]
+    pub sandbox: SandboxConfig,
+
+    /// [parser] section
+    #[serde(default)]
+    pub parser: ParserConfig,
+
+    /// [rsync] section
+    #[serde(default)]
+    pub rsync: RsyncConfig,
+
+    /// [binhost] section
+    #[serde(default)]
+    pub binhost: Option<BinhostConfig>,
+
+    /// [releases] section
+    #[serde(default)]
+    pub releases: Option<ReleasesConfig>,
+
+    /// [import] section
+    #[serde(default)]
+    pub import: Option<ImportConfig>,
+
+    /// [webrsync] section
+    #[serde(default)]
+    pub webrsync: Option<WebrsyncConfig>,
+
+    /// [control] section
+    #[serde(default)]
+    pub control: Option<ControlConfig>,
+
+    /// [fuse] section
+    #[serde(default)]
+    pub fuse: Option<FuseConfig>,
+
+    /// [index] section
+    #[serde(default)]
+    pub index: Option<IndexConfig>,
+}
+
+/// portage helper processes extracting SRC_URIs from ebuilds
+#[derive(Deserialize, Clone)]
+pub struct ParserConfig {
+    /// number of helper processes parsing ebuilds concurrently
+    #[serde(default = "default_parser_workers")]
+    pub workers: usize,
+
+    /// ebuilds a helper process parses before getting replaced
+    #[serde(default = "default_parser_worker_ebuilds")]
+    pub worker_ebuilds: u64,
+
+    /// which USE-conditional SRC_URIs get indexed
+    #[serde(default)]
+    pub use_conditionals: UseConditionals,
+
+    /// USE flags assumed enabled with use_conditionals = "set"
+    #[serde(default)]
+    pub use_flags: Vec<String>,
+
+    /// user to run the helper as (requires running as root)
+    #[serde(default)]
+    pub user: Option<String>,
+
+    /// group to run the helper as, defaults to the primary group of user
+    #[serde(default)]
+    pub group: Option<String>,
+
+    /// bubblewrap binary to isolate the helper with
+    /// i.e. read-only root, no network and private /tmp
+    #[serde(default)]
+    pub bwrap: Option<PathBuf>,
+
+    /// CPU time a helper process may use
+    #[serde(default, deserialize_with = "deserialize_opt_secs")]
+    pub cpu_limit: Option<Duration>,
+
+    /// bytes of address space a helper process may use
+    #[serde(default, deserialize_with = "deserialize_opt_size")]
+    pub memory_limit: Option<u64>,
+
+    /// resident memory of all helper processes together
+    /// above which no further helpers get started
+    #[serde(default, deserialize_with = "deserialize_opt_size")]
+    pub memory_high: Option<u64>,
+}
+
+impl Default for ParserConfig {
+    fn default() -> Self {
+        Self {
+            workers: default_parser_workers(),
+            worker_ebuilds: default_parser_worker_ebuilds(),
+            use_conditionals: UseConditionals::default(),
+            use_flags: Vec::new(),
+            user: None,
+            group: None,
+            bwrap: None,
+            cpu_limit: None,
+            memory_limit: None,
+            memory_high: None,
+        }
+    }
+}
+
+/// handling of SRC_URIs inside `flag? ( ... )` groups
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UseConditionals {
+    /// index them regardless of their conditions
+    #[default]
+    All,
+
+    /// only index unconditional SRC_URIs
+    None,
+
+    /// evaluate the conditions against parser.use_flags
+    Set,
+}
+
+fn default_parser_workers() -> usize {
+    2
+}
+
+fn default_parser_worker_ebuilds() -> u64 {
+    500
+}
+
+/// landlock restrictions applied to the whole process on startup
+#[derive(Deserialize, Clone)]
+pub struct SandboxConfig {
+    /// restrict filesystem access to the storage root and system paths
+    /// and TCP connections to the ports of configured upstreams
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// additional paths which may be read and executed
+    #[serde(default)]
+    pub read_only: Vec<PathBuf>,
+
+    /// additional paths which may be written
+    #[serde(default)]
+    pub read_write: Vec<PathBuf>,
+
+    /// TCP ports connections are allowed to
+    /// besides the ones of configured urls
+    #[serde(default = "default_sandbox_connect_ports")]
+    pub connect_ports: Vec<u16>,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            read_only: Vec::new(),
+            read_write: Vec::new(),
+            connect_ports: default_sandbox_connect_ports(),
+        }
+    }
+}
+
+fn default_sandbox_connect_ports() -> Vec<u16> {
+    vec![80, 443]
+}
+
+/// binary package cache in front of an upstream binhost
+#[derive(Deserialize, Clone)]
+pub struct BinhostConfig {
+    /// url PORTAGE_BINHOST would otherwise point at
+    /// i.e. the directory containing the Packages index
+    pub upstream: String,
+
+    /// how long the Packages index is served from cache before refetching
+    #[serde(
+        default = "default_binhost_index_ttl",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub index_ttl: Duration,
+}
+
+fn default_binhost_index_ttl() -> Duration {
+    Duration::from_secs(3600)
+}
+
+/// drop directory distfiles get imported from before asking upstream
+/// e.g. copied from a USB drive on an air-gapped network
+#[derive(Deserialize, Clone)]
+pub struct ImportConfig {
+    /// directory watched for distfiles to import
+    pub directory: PathBuf,
+
+    /// how long a file must stay unchanged before it gets imported
+    /// so files still being copied aren't rejected half-written
+    #[serde(
+        default = "default_import_settle",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub settle: Duration,
+
+    /// also import files without a Manifest entry to verify them against
+    #[serde(default)]
+    pub unverified: bool,
+}
+
+fn default_import_settle() -> Duration {
+    Duration::from_secs(10)
+}
+
+/// snapshot tarballs of the repo checkouts for emerge-webrsync
+#[derive(Deserialize, Clone)]
+pub struct WebrsyncConfig {
+    /// minimum time between snapshots of the same repo
+    /// snapshots are only taken after sync cycles which changed something
+    #[serde(
+        default = "default_webrsync_interval",
+        deserialize_with = "deserialize_minutes"
+    )]
+    pub interval: Duration,
+
+    /// number of dated snapshots to keep per repo
+    #[serde(default = "default_webrsync_keep")]
+    pub keep: usize,
+}
+
+fn default_webrsync_interval() -> Duration {
+    Duration::from_secs(6 * 3600)
+}
+
+fn default_webrsync_keep() -> usize {
+    3
+}
+
+/// local control socket for scripting without the HTTP admin API
+#[derive(Deserialize, Clone)]
+pub struct ControlConfig {
+    /// path of the Unix socket
+    pub socket: PathBuf,
+
+    /// permissions of the socket
+    /// anyone allowed to connect can trigger syncs and evictions
+    #[serde(default = "default_control_mode")]
+    pub mode: u32,
+}
+
+fn default_control_mode() -> u32 {
+    0o600
+}
+
+/// read-only FUSE view of the cache laid out like a mirror
+#[derive(Deserialize, Clone)]
+pub struct FuseConfig {
+    /// directory the view gets mounted at
+    pub mountpoint: PathBuf,
+
+    /// let users other than the one starting portcache access the view
+    /// needed to export it via NFS
+    #[serde(default = "default_fuse_allow_other")]
+    pub allow_other: bool,
+}
+
+fn default_fuse_allow_other() -> bool {
+    true
+}
+
+/// signed index of the cached distfiles and their checksums
+#[derive(Deserialize, Clone)]
+pub struct IndexConfig {
+    /// ed25519 key signing the index as <name>:<base64>
+    /// generate one with `portcache index-key`
+    pub signing_key: PathBuf,
+}
+
+/// cache of Gentoo release media like stage3 tarballs and ISOs
+#[derive(Deserialize, Clone)]
+pub struct ReleasesConfig {
+    /// Gentoo mirrors to fetch releases/ from
+    pub mirrors: Vec<String>,
+
+    /// verify media against their signed .DIGESTS or .asc
+    #[serde(default = "default_releases_verify")]
+    pub verify: bool,
+
+    /// OpenPGP keys releases have to be signed with (armored or binary)
+    #[serde(default = "default_releases_keyring")]
+    pub keyring: PathBuf,
+
+    /// gpgv binary checking the signatures
+    #[serde(default = "default_releases_gpgv")]
+    pub gpgv: PathBuf,
+
+    /// how long signatures, digests and latest-*.txt files
+    /// are served from cache before refetching
+    #[serde(
+        default = "default_releases_metadata_ttl",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub metadata_ttl: Duration,
+}
+
+fn default_releases_verify() -> bool {
+    true
+}
+
+fn default_releases_keyring() -> PathBuf {
+    PathBuf::from("/usr/share/openpgp-keys/gentoo-release.asc")
+}
+
+fn default_releases_gpgv() -> PathBuf {
+    PathBuf::from("gpgv")
+}
+
+fn default_releases_metadata_ttl() -> Duration {
+    Duration::from_secs(3600)
+}
+
+/// rsync daemon exporting the blob storage
+#[derive(Deserialize, Clone)]
+pub struct RsyncConfig {
+    /// generate an rsyncd.conf exporting the blob storage
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// run rsync --daemon with the generated config
+    /// set to false when rsyncd is managed outside of portcache
+    #[serde(default = "default_rsync_spawn")]
+    pub spawn: bool,
+
+    /// rsync binary to run
+    #[serde(default = "default_rsync_binary")]
+    pub binary: PathBuf,
+
+    /// address to listen on, defaults to server.address
+    #[serde(default)]
+    pub address: Option<IpAddr>,
+
+    /// port to listen on
+    #[serde(default = "default_rsync_port")]
+    pub port: u16,
+
+    /// name of the exported module
+    #[serde(default = "default_rsync_module")]
+    pub module: String,
+
+    /// concurrent clients, 0 for unlimited
+    #[serde(default)]
+    pub max_connections: u32,
+
+    /// where to write the generated rsyncd.conf
+    /// defaults to rsyncd.conf in the storage root
+    #[serde(default)]
+    pub config: Option<PathBuf>,
+}
+
+impl Default for RsyncConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            spawn: default_rsync_spawn(),
+            binary: default_rsync_binary(),
+            address: None,
+            port: default_rsync_port(),
+            module: default_rsync_module(),
+            max_connections: 0,
+            config: None,
+        }
+    }
+}
+
+fn default_rsync_spawn() -> bool {
+    true
+}
+
+fn default_rsync_binary() -> PathBuf {
+    PathBuf::from("rsync")
+}
+
+fn default_rsync_port() -> u16 {
+    873
+}
+
+fn default_rsync_module() -> String {
+    "distfiles".to_string()
+}
+
+/// admin API settings
+#[derive(Deserialize, Clone, Default)]
+pub struct AdminConfig {
+    /// bearer token required for /api/v1/admin routes
+    /// the admin API is disabled while unset
+    #[serde(default)]
+    pub token: Option<String>,
+
+    /// how long served distfiles are kept in the provenance log
+    /// 0 logs nothing
+    #[serde(default, deserialize_with = "deserialize_secs")]
+    pub provenance_retention: Duration,
+}
+
+/// API keys for shared caches
+/// keys get issued with `portcache api-key add`
+#[derive(Deserialize, Clone, Default)]
+pub struct ApiKeysConfig {
+    /// reject requests for files which carry no valid key
+    #[serde(default)]
+    pub required: bool,
+
+    /// bytes a key may be served per quota window
+    /// unless the key got its own limit, unset only tracks usage
+    #[serde(default, deserialize_with = "deserialize_opt_size")]
+    pub default_limit: Option<u64>,
+}
+
+/// export of request traces to an OpenTelemetry collector
+#[derive(Deserialize, Clone)]
+pub struct TelemetryConfig {
+    /// base url of an OTLP/HTTP collector e.g. http://localhost:4318
+    /// traces get posted to <otlp_endpoint>/v1/traces, unset disables tracing
+    #[serde(default)]
+    pub otlp_endpoint: Option<String>,
+
+    /// service.name the spans are reported under
+    #[serde(default = "default_telemetry_service_name")]
+    pub service_name: String,
+
+    /// share of requests which get traced between 0 and 1
+    /// requests carrying a traceparent follow the decision of their caller
+    #[serde(default = "default_telemetry_sample_ratio")]
+    pub sample_ratio: f64,
+
+    /// how often finished spans get exported
+    #[serde(
+        default = "default_telemetry_export_interval",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub export_interval: Duration,
+
+    /// finished spans kept until the next export, further ones get dropped
+    #[serde(default = "default_telemetry_max_queue")]
+    pub max_queue: usize,
+
+    /// extra headers sent to the collector e.g. for authentication
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            service_name: default_telemetry_service_name(),
+            sample_ratio: default_telemetry_sample_ratio(),
+            export_interval: default_telemetry_export_interval(),
+            max_queue: default_telemetry_max_queue(),
+            headers: HashMap::new(),
+        }
+    }
+}
+
+fn default_telemetry_service_name() -> String {
+    "portcache".to_string()
+}
+
+fn default_telemetry_sample_ratio() -> f64 {
+    1.0
+}
+
+fn default_telemetry_export_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_telemetry_max_queue() -> usize {
+    2048
+}
+
+/// per client usage tracking and soft quotas
+/// clients are aggregated by subnet
+#[derive(Deserialize, Clone)]
+pub struct QuotaConfig {
+    /// bytes a subnet may be served per window before getting 429s
+    /// unset only tracks usage
+    #[serde(default, deserialize_with = "deserialize_opt_size")]
+    pub limit: Option<u64>,
+
+    /// length of a quota window
+    #[serde(
+        default = "default_quota_window",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub window: Duration,
+
+    /// prefix length IPv4 clients are aggregated by
+    #[serde(default = "default_quota_ipv4_prefix")]
+    pub ipv4_prefix: u8,
+
+    /// prefix length IPv6 clients are aggregated by
+    #[serde(default = "default_quota_ipv6_prefix")]
+    pub ipv6_prefix: u8,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        Self {
+            limit: None,
+            window: default_quota_window(),
+            ipv4_prefix: default_quota_ipv4_prefix(),
+            ipv6_prefix: default_quota_ipv6_prefix(),
+        }
+    }
+}
+
+fn default_quota_window() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+
+fn default_quota_ipv4_prefix() -> u8 {
+    24
+}
+
+fn default_quota_ipv6_prefix() -> u8 {
+    64
+}
+
+/// where portcache keeps its data
+#[derive(Deserialize, Clone)]
+pub struct StorageConfig {
+    /// storage root
+    #[serde(default = "default_storage_location")]
+    pub location: PathBuf,
+
+    /// sqlite settings of the repo database
+    #[serde(default)]
+    pub database: DatabaseConfig,
+
+    /// size in bytes the cached blobs may take up before getting evicted
+    /// unset disables eviction
+    #[serde(default, deserialize_with = "deserialize_opt_size")]
+    pub max_size: Option<u64>,
+
+    /// which blobs get evicted first
+    #[serde(default)]
+    pub eviction: EvictionPolicy,
+
+    /// interval in which to check the storage size
+    #[serde(
+        default = "default_eviction_interval",
+        deserialize_with = "deserialize_minutes"
+    )]
+    pub eviction_interval: Duration,
+
+    /// daily windows scheduled eviction is restricted to
+    /// empty allows eviction at any time
+    #[serde(default)]
+    pub eviction_windows: Vec<TimeWindow>,
+
+    /// length of the hash directory names blobs are sharded into in bits
+    /// changing it requires moving existing blobs with `portcache reshard`
+    #[serde(default = "default_hash_bits")]
+    pub hash_bits: u8,
+
+    /// adopt the hash directory length of the first fetcher.mirrors entry at startup
+    /// moving the blobs if it changed, overrides hash_bits once adopted
+    #[serde(default)]
+    pub follow_upstream_layout: bool,
+
+    /// number of threads verifying checksums at once
+    /// hashing runs outside the async runtime so it never stalls requests
+    #[serde(default = "default_hash_workers")]
+    pub hash_workers: usize,
+
+    /// watch the blobs for changes made outside of portcache
+    /// replaced blobs get verified and marked stale on mismatch
+    #[serde(default)]
+    pub watch_blobs: bool,
+
+    /// free space below which misses are passed through to clients without being stored
+    /// unset keeps storing until the disk is full
+    #[serde(default, deserialize_with = "deserialize_opt_size")]
+    pub min_free: Option<u64>,
+
+    /// Portage DISTDIR of this machine cached blobs get hard linked into
+    /// so its own emerge finds them without an HTTP round trip
+    /// has to be on the same filesystem as location
+    #[serde(default)]
+    pub local_distdir: Option<PathBuf>,
+
+    /// interval in which access times of cache hits are written to the database
+    /// eviction may see access times this much behind
+    #[serde(
+        default = "default_access_flush_interval",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub access_flush_interval: Duration,
+
+    /// globs of distfile names stored zstd compressed, e.g. "*.patch"
+    /// they're decompressed while served unless the client accepts zstd
+    #[serde(default)]
+    pub compress: Vec<String>,
+
+    /// zstd level compressed distfiles are stored with
+    #[serde(default = "default_compress_level")]
+    pub compress_level: i32,
+
+    /// bytes of small files kept in memory to serve them without disk reads
+    /// unset disables the memory cache
+    #[serde(default, deserialize_with = "deserialize_opt_size")]
+    pub memory_cache: Option<u64>,
+
+    /// files larger than this aren't kept in the memory cache
+    #[serde(
+        default = "default_memory_cache_max_file",
+        deserialize_with = "deserialize_size"
+    )]
+    pub memory_cache_max_file: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            location: default_storage_location(),
+            database: DatabaseConfig::default(),
+            max_size: None,
+            eviction: EvictionPolicy::default(),
+            eviction_interval: default_eviction_interval(),
+            eviction_windows: Vec::new(),
+            hash_bits: default_hash_bits(),
+            follow_upstream_layout: false,
+            hash_workers: default_hash_workers(),
+            watch_blobs: false,
+            min_free: None,
+            local_distdir: None,
+            access_flush_interval: default_access_flush_interval(),
+            compress: Vec::new(),
+            compress_level: default_compress_level(),
+            memory_cache: None,
+            memory_cache_max_file: default_memory_cache_max_file(),
+        }
+    }
+}
+
+fn default_access_flush_interval() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_compress_level() -> i32 {
+    3
+}
+
+fn default_memory_cache_max_file() -> u64 {
+    1024 * 1024
+}
+
+fn default_storage_location() -> PathBuf {
+    PathBuf::from("/var/cache/portcache")
+}
+
+fn default_hash_bits() -> u8 {
+    8
+}
+
+fn default_hash_workers() -> usize {
+    2
+}
+
+fn default_eviction_interval() -> Duration {
+    Duration::from_secs(60 * 60)
+}
+
+/// order in which blobs get evicted
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionPolicy {
+    /// least recently used first
+    #[default]
+    Lru,
+
+    /// blobs no longer referenced by any Manifest first
+    /// then least recently used
+    TreeAware,
+
+    /// largest time since last access times size first
+    /// so a big idle tarball goes before hundreds of small patches
+    SizeAware,
+}
+
+/// daily time window in UTC written as "HH:MM-HH:MM"
+/// windows ending before they start wrap around midnight
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct TimeWindow {
+    /// start in minutes after midnight (inclusive)
+    start: u32,
+
+    /// end in minutes after midnight (exclusive)
+    end: u32,
+}
+
+impl TimeWindow {
+    /// whether a point in time falls into this window
+    ///
+    /// @param unix_time  seconds since the unix epoch
+    pub fn contains(&self, unix_time: u64) -> bool {
+        let minute = ((unix_time % (24 * 60 * 60)) / 60) as u32;
+        if self.start <= self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+impl TryFrom<String> for TimeWindow {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let minutes = |time: &str| -> Option<u32> {
+            let (hours, minutes) = time.trim().split_once(':')?;
+            let hours: u32 = hours.parse().ok()?;
+            let minutes: u32 = minutes.parse().ok()?;
+            (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+        };
+
+        value
+            .split_once('-')
+            .and_then(|(start, end)| {
+                Some(Self {
+                    start: minutes(start)?,
+                    end: minutes(end)?,
+                })
+            })
+            .ok_or_else(|| format!("Invalid time window \"{}\", expected HH:MM-HH:MM", value))
+    }
+}
+
+/// sqlite settings of the repo database
+#[derive(Deserialize, Clone)]
+pub struct DatabaseConfig {
+    /// sqlite journal_mode pragma
+    #[serde(default = "default_journal_mode")]
+    pub journal_mode: JournalMode,
+
+    /// sqlite synchronous pragma
+    #[serde(default = "default_synchronous")]
+    pub synchronous: Synchronous,
+
+    /// time to wait for a locked database before failing
+    #[serde(
+        default = "default_busy_timeout",
+        deserialize_with = "deserialize_millis"
+    )]
+    pub busy_timeout: Duration,
+
+    /// number of manifest and SRC_URI lookups kept in memory
+    #[serde(default = "default_cache_capacity")]
+    pub cache_capacity: u64,
+
+    /// interval of automatic snapshots into <storage>/snapshots
+    /// unset disables them
+    #[serde(default, deserialize_with = "deserialize_opt_minutes")]
+    pub snapshot_interval: Option<Duration>,
+
+    /// number of automatic snapshots to keep
+    #[serde(default = "default_snapshot_keep")]
+    pub snapshot_keep: usize,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            journal_mode: default_journal_mode(),
+            synchronous: default_synchronous(),
+            busy_timeout: default_busy_timeout(),
+            cache_capacity: default_cache_capacity(),
+            snapshot_interval: None,
+            snapshot_keep: default_snapshot_keep(),
+        }
+    }
+}
+
+fn default_journal_mode() -> JournalMode {
+    JournalMode::Wal
+}
+
+fn default_synchronous() -> Synchronous {
+    Synchronous::Normal
+}
+
+fn default_busy_timeout() -> Duration {
+    Duration::from_millis(5000)
+}
+
+fn default_cache_capacity() -> u64 {
+    10_000
+}
+
+fn default_snapshot_keep() -> usize {
+    3
+}
+
+/// sqlite journal modes
+/// see https://www.sqlite.org/pragma.html#pragma_journal_mode
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JournalMode {
+    /// rollback journal deleted after each transaction
+    Delete,
+
+    /// rollback journal truncated after each transaction
+    Truncate,
+
+    /// rollback journal kept and its header zeroed
+    Persist,
+
+    /// rollback journal in memory
+    Memory,
+
+    /// write-ahead log - readers don't block writers
+    Wal,
+
+    /// no journal at all
+    Off,
+}
+
+impl JournalMode {
+    /// value of the pragma
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Delete => "delete",
+            Self::Truncate => "truncate",
+            Self::Persist => "persist",
+            Self::Memory => "memory",
+            Self::Wal => "wal",
+            Self::Off => "off",
+        }
+    }
+}
+
+/// sqlite synchronous levels
+/// see https://www.sqlite.org/pragma.html#pragma_synchronous
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Synchronous {
+    /// never wait for data to reach the disk
+    Off,
+
+    /// sync at critical moments - safe with WAL
+    Normal,
+
+    /// sync after every transaction
+    Full,
+
+    /// like full and also sync the directory after deleting journals
+    Extra,
+}
+
+impl Synchronous {
+    /// value of the pragma
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Normal => "normal",
+            Self::Full => "full",
+            Self::Extra => "extra",
+        }
+    }
+}
+
+/// where and how missing files get fetched from
+#[derive(Deserialize, Clone)]
+pub struct FetcherConfig {
+    /// List of mirror urls
+    /// Available mirrors: https://www.gentoo.org/downloads/mirrors/
+    /// Currently only supports HTTP and HTTPS
+    #[serde(default)]
+    pub mirrors: Vec<Mirror>,
+
+    /// order in which fetch backends are tried on a cache miss
+    #[serde(default = "default_fetch_chain")]
+    pub chain: Vec<FetchBackend>,
+
+    /// other portcache instances to ask before going upstream
+    #[serde(default)]
+    pub peers: Vec<String>,
+
+    /// pass-through upstreams serving files by plain name
+    /// i.e. <url>/<file> without a hash directory
+    #[serde(default)]
+    pub proxies: Vec<String>,
+
+    /// IPFS source settings (requires "ipfs" in chain)
+    #[serde(default)]
+    pub ipfs: Option<IpfsConfig>,
+
+    /// metalink source settings
+    #[serde(default)]
+    pub metalink: MetalinkConfig,
+
+    /// parallel chunked mirror download settings
+    #[serde(default)]
+    pub chunked: ChunkedConfig,
+
+    /// retry settings for mirror fetches
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    /// limits of concurrent fetches and how long requests queue for them
+    #[serde(default)]
+    pub queue: QueueConfig,
+
+    /// redirect, cookie and header handling of SRC_URI fetches
+    #[serde(default)]
+    pub src_uri: SrcUriConfig,
+
+    /// CAs, verification and certificate pins of upstream requests
+    #[serde(default)]
+    pub tls: TlsConfig,
+
+    /// how long to remember that no mirror had a file
+    /// so repeated requests go straight to the next fetcher
+    #[serde(
+        default = "default_not_found_ttl",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub not_found_ttl: Duration,
+
+    /// how long repeated identical fetch errors are aggregated for
+    /// 0 logs every error
+    #[serde(default = "default_log_window", deserialize_with = "deserialize_secs")]
+    pub log_window: Duration,
+
+    /// how long upstream downloads are kept in the download history
+    /// 0 keeps no history
+    #[serde(
+        default = "default_history_retention",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub history_retention: Duration,
+
+    /// record the checksum of distfiles without Manifest hashes on their first download
+    /// and reject later downloads with other content
+    #[serde(default = "default_trust_on_first_use")]
+    pub trust_on_first_use: bool,
 }
 
+impl Default for FetcherConfig {
+    fn default() -> Self {
+        Self {
+            mirrors: Vec::new(),
+            chain: default_fetch_chain(),
+            peers: Vec::new(),
+            proxies: Vec::new(),
+            ipfs: None,
+            metalink: MetalinkConfig::default(),
+            chunked: ChunkedConfig::default(),
+            retry: RetryConfig::default(),
+            queue: QueueConfig::default(),
+            src_uri: SrcUriConfig::default(),
+            tls: TlsConfig::default(),
+            not_found_ttl: default_not_found_ttl(),
+            log_window: default_log_window(),
+            history_retention: default_history_retention(),
+            trust_on_first_use: default_trust_on_first_use(),
+        }
+    }
+}
+
+fn default_not_found_ttl() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn default_log_window() -> Duration {
+    Duration::from_secs(600)
+}
+
+fn default_history_retention() -> Duration {
+    Duration::from_secs(30 * 24 * 60 * 60)
+}
+
+fn default_trust_on_first_use() -> bool {
+    true
+}
+
+/// a Gentoo mirror to fetch distfiles from
+/// can be given as plain url, with credentials as user:password@ in it,
+/// or as table keeping the credentials apart from the url
 #[derive(Deserialize, Clone)]
-pub struct StorageConfig {
-    /// storage root
-    pub location: PathBuf,
+#[serde(from = "MirrorEntry")]
+pub struct Mirror {
+    /// url of the mirror, possibly with credentials
+    pub url: String,
+
+    /// user to authenticate as
+    pub username: Option<String>,
+
+    /// password of username
+    pub password: Option<String>,
+
+    /// file with the password of username so it can be kept out of the config
+    /// only its first line is used
+    pub password_file: Option<PathBuf>,
+
+    /// overrides fetcher.tls.upgrade_http for this mirror
+    pub upgrade_http: Option<bool>,
+}
+
+/// credentials sent to a mirror via basic auth
+#[derive(Clone, PartialEq, Debug)]
+pub struct Credentials {
+    /// user to authenticate as
+    pub username: String,
+
+    /// password of username
+    pub password: Option<String>,
+}
+
+impl Mirror {
+    /// url of the mirror with the credentials removed
+    /// safe to log and to build request urls from
+    pub fn public_url(&self) -> String {
+        let public = match reqwest::Url::parse(&self.url) {
+            Ok(mut url) => {
+                let _ = url.set_username("");
+                let _ = url.set_password(None);
+                url.to_string()
+            }
+            Err(_) => self.url.clone(),
+        };
+        public.trim_end_matches('/').to_string()
+    }
+
+    /// credentials to authenticate with, either from the table or the url
+    /// reads password_file if set
+    pub fn credentials(&self) -> Result<Option<Credentials>, String> {
+        let url = reqwest::Url::parse(&self.url).map_err(|e| e.to_string())?;
+        let decode = |part: &str| {
+            percent_encoding::percent_decode_str(part)
+                .decode_utf8()
+                .map(|part| part.to_string())
+                .map_err(|e| format!("Bad credentials in {}: {}", self.public_url(), e))
+        };
+
+        let username = match (&self.username, url.username()) {
+            (Some(_), user) if !user.is_empty() => {
+                return Err(format!(
+                    "{} has credentials in its url and in username",
+                    self.public_url()
+                ));
+            }
+            (Some(username), _) => username.clone(),
+            (None, "") if self.password.is_some() || self.password_file.is_some() => {
+                return Err(format!(
+                    "{} has a password but no username",
+                    self.public_url()
+                ));
+            }
+            (None, "") => return Ok(None),
+            (None, user) => decode(user)?,
+        };
+
+        let password = match (&self.password, &self.password_file, url.password()) {
+            (Some(_), Some(_), _) => {
+                return Err(format!(
+                    "{} has both password and password_file",
+                    self.public_url()
+                ));
+            }
+            (Some(password), None, _) => Some(password.clone()),
+            (None, Some(path), _) => {
+                let content = fs::read_to_string(path).map_err(|e| {
+                    format!(
+                        "Cannot read password_file {}: {}",
+                        path.to_string_lossy(),
+                        e
+                    )
+                })?;
+                Some(content.lines().next().unwrap_or_default().to_string())
+            }
+            (None, None, Some(password)) => Some(decode(password)?),
+            (None, None, None) => None,
+        };
+
+        Ok(Some(Credentials { username, password }))
+    }
+}
+
+/// accepted forms of a mirror in the config
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MirrorEntry {
+    Url(String),
+    Table {
+        url: String,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+        #[serde(default)]
+        password_file: Option<PathBuf>,
+        #[serde(default)]
+        upgrade_http: Option<bool>,
+    },
+}
+
+impl From<MirrorEntry> for Mirror {
+    fn from(entry: MirrorEntry) -> Self {
+        match entry {
+            MirrorEntry::Url(url) => Self {
+                url,
+                username: None,
+                password: None,
+                password_file: None,
+                upgrade_http: None,
+            },
+            MirrorEntry::Table {
+                url,
+                username,
+                password,
+                password_file,
+                upgrade_http,
+            } => Self {
+                url,
+                username,
+                password,
+                password_file,
+                upgrade_http,
+            },
+        }
+    }
 }
 
+/// IPFS fetch backend settings
+/// distfiles are looked up by their Manifest checksums,
+/// so distfiles without a Manifest entry can't be fetched from IPFS
 #[derive(Deserialize, Clone)]
-pub struct FetcherConfig {
-    /// List of mirror urls
-    /// Available mirrors: https://www.gentoo.org/downloads/mirrors/
-    /// Currently only supports HTTP and HTTPS
+pub struct IpfsConfig {
+    /// HTTP gateway used to resolve IPFS paths
+    pub gateway: String,
+
+    /// IPFS paths of content addressed distfile mirrors
+    /// holding <hash>/<checksum> e.g. /ipns/distfiles.example.org/blake2b/<BLAKE2B>
     pub mirrors: Vec<String>,
+
+    /// Kubo RPC API url - when set verified blobs fetched from IPFS get pinned
+    #[serde(default)]
+    pub api: Option<String>,
+
+    /// also add verified blobs of other fetchers to IPFS and pin them, requires api
+    #[serde(default)]
+    pub pin_stored: bool,
+}
+
+/// metalink fetch backend settings
+#[derive(Deserialize, Clone)]
+pub struct MetalinkConfig {
+    /// size of each range request when fetching from multiple sources in bytes
+    #[serde(
+        default = "default_metalink_chunk_size",
+        deserialize_with = "deserialize_size"
+    )]
+    pub chunk_size: u64,
+}
+
+impl Default for MetalinkConfig {
+    fn default() -> Self {
+        Self {
+            chunk_size: default_metalink_chunk_size(),
+        }
+    }
+}
+
+fn default_metalink_chunk_size() -> u64 {
+    16 * 1024 * 1024
+}
+
+/// parallel chunked mirror download settings
+#[derive(Deserialize, Clone)]
+pub struct ChunkedConfig {
+    /// minimum Manifest size in bytes for a file to be fetched in chunks
+    /// from multiple mirrors at once - unset disables chunked downloads
+    #[serde(default, deserialize_with = "deserialize_opt_size")]
+    pub min_size: Option<u64>,
+
+    /// size of each chunk in bytes
+    #[serde(
+        default = "default_chunked_chunk_size",
+        deserialize_with = "deserialize_size"
+    )]
+    pub chunk_size: u64,
+}
+
+impl Default for ChunkedConfig {
+    fn default() -> Self {
+        Self {
+            min_size: None,
+            chunk_size: default_chunked_chunk_size(),
+        }
+    }
+}
+
+fn default_chunked_chunk_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+/// retry settings for mirror fetches
+#[derive(Deserialize, Clone)]
+pub struct RetryConfig {
+    /// attempts per mirror before moving on to the next one
+    #[serde(default = "default_retry_max_attempts")]
+    pub max_attempts: u32,
+
+    /// backoff before the first retry
+    /// doubles with each further retry
+    #[serde(
+        default = "default_retry_initial_backoff",
+        deserialize_with = "deserialize_millis"
+    )]
+    pub initial_backoff: Duration,
+
+    /// upper bound for the backoff
+    #[serde(
+        default = "default_retry_max_backoff",
+        deserialize_with = "deserialize_millis"
+    )]
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_retry_max_attempts(),
+            initial_backoff: default_retry_initial_backoff(),
+            max_backoff: default_retry_max_backoff(),
+        }
+    }
+}
+
+fn default_retry_max_attempts() -> u32 {
+    3
+}
+
+fn default_retry_initial_backoff() -> Duration {
+    Duration::from_millis(500)
+}
+
+fn default_retry_max_backoff() -> Duration {
+    Duration::from_millis(10_000)
+}
+
+/// limits of concurrent fetches
+#[derive(Deserialize, Clone)]
+pub struct QueueConfig {
+    /// fetches running at once, further ones queue up
+    /// 0 for unlimited
+    #[serde(default = "default_queue_max_fetches")]
+    pub max_fetches: usize,
+
+    /// how long a request waits for its queued fetch to start
+    /// before it gets a 503 instead of holding the connection
+    #[serde(default = "default_queue_wait", deserialize_with = "deserialize_secs")]
+    pub wait: Duration,
+
+    /// Retry-After sent with that 503
+    #[serde(
+        default = "default_queue_retry_after",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub retry_after: Duration,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            max_fetches: default_queue_max_fetches(),
+            wait: default_queue_wait(),
+            retry_after: default_queue_retry_after(),
+        }
+    }
+}
+
+fn default_queue_max_fetches() -> usize {
+    16
+}
+
+fn default_queue_wait() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_queue_retry_after() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// redirect, cookie and header handling of SRC_URI fetches
+/// for upstreams which only hand out files after a redirect dance
+#[derive(Deserialize, Clone)]
+pub struct SrcUriConfig {
+    /// redirects followed per SRC_URI
+    #[serde(default = "default_src_uri_max_redirects")]
+    pub max_redirects: usize,
+
+    /// whether cookies set along the redirects get sent back
+    /// the cookie jar only lives for a single fetch
+    #[serde(default = "default_src_uri_cookies")]
+    pub cookies: bool,
+
+    /// extra request headers (e.g. Referer or Cookie) per domain
+    /// a domain also matches its subdomains
+    #[serde(default)]
+    pub headers: HashMap<String, HashMap<String, String>>,
+
+    /// transient failures in a row after which a host is skipped for breaker_cooldown
+    /// 0 never skips hosts
+    #[serde(default = "default_src_uri_breaker_threshold")]
+    pub breaker_threshold: u32,
+
+    /// how long a failing host is skipped before it gets tried again
+    #[serde(
+        default = "default_src_uri_breaker_cooldown",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub breaker_cooldown: Duration,
+}
+
+impl Default for SrcUriConfig {
+    fn default() -> Self {
+        Self {
+            max_redirects: default_src_uri_max_redirects(),
+            cookies: default_src_uri_cookies(),
+            headers: HashMap::new(),
+            breaker_threshold: default_src_uri_breaker_threshold(),
+            breaker_cooldown: default_src_uri_breaker_cooldown(),
+        }
+    }
 }
 
+fn default_src_uri_max_redirects() -> usize {
+    10
+}
+
+fn default_src_uri_cookies() -> bool {
+    true
+}
+
+fn default_src_uri_breaker_threshold() -> u32 {
+    5
+}
+
+fn default_src_uri_breaker_cooldown() -> Duration {
+    Duration::from_secs(300)
+}
+
+/// TLS settings of upstream requests
+/// applies to every fetcher as well as binhost and releases mirrors
+#[derive(Deserialize, Clone)]
+pub struct TlsConfig {
+    /// PEM bundle of CAs trusted in addition to the system ones
+    /// e.g. for internal mirrors or TLS intercepting proxies
+    #[serde(default)]
+    pub ca_bundle: Option<PathBuf>,
+
+    /// hosts whose certificates aren't verified at all
+    /// pins of these hosts are still checked
+    #[serde(default)]
+    pub insecure_hosts: Vec<String>,
+
+    /// SHA-256 fingerprints of the certificates a host may present
+    /// as printed by openssl x509 -noout -fingerprint -sha256
+    /// responses from other certificates or over plain HTTP are rejected
+    #[serde(default)]
+    pub pins: HashMap<String, Vec<String>>,
+
+    /// whether upstreams may be requested over plain http://
+    /// when unset http:// mirrors, peers and proxies are refused at startup
+    /// and http:// SRC_URIs as well as redirects to http:// aren't followed
+    #[serde(default = "default_tls_allow_http")]
+    pub allow_http: bool,
+
+    /// try the https:// variant of http:// mirrors and SRC_URIs first
+    /// falling back to http:// only where it's allowed
+    /// mirrors can override this with upgrade_http in their table
+    #[serde(default)]
+    pub upgrade_http: bool,
+
+    /// domains (and their subdomains) allowed over plain http:// regardless of allow_http
+    #[serde(default)]
+    pub http_hosts: Vec<String>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            ca_bundle: None,
+            insecure_hosts: Vec::new(),
+            pins: HashMap::new(),
+            allow_http: default_tls_allow_http(),
+            upgrade_http: false,
+            http_hosts: Vec::new(),
+        }
+    }
+}
+
+fn default_tls_allow_http() -> bool {
+    true
+}
+
+/// available fetch backends
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum FetchBackend {
+    /// Gentoo mirrors from fetcher.mirrors
+    Mirror,
+
+    /// SRC_URI as parsed from the synced repos
+    SrcUri,
+
+    /// peer portcache instances from fetcher.peers
+    Peer,
+
+    /// pass-through upstreams from fetcher.proxies
+    Proxy,
+
+    /// IPFS mirrors from fetcher.ipfs
+    Ipfs,
+
+    /// metalinks published next to SRC_URIs
+    Metalink,
+}
+
+fn default_fetch_chain() -> Vec<FetchBackend> {
+    vec![FetchBackend::Mirror, FetchBackend::SrcUri]
+}
+
+/// HTTP frontend settings
 #[derive(Deserialize, Clone)]
 pub struct ServerConfig {
     /// address rocket should listen on
+    #[serde(default = "default_server_address")]
     pub address: IpAddr,
 
-    /// port to listen on
+    /// port to listen on, 0 picks a free one
+    #[serde(default = "default_server_port")]
     pub port: u16,
+
+    /// handling of legacy flat /distfiles/<file> requests
+    #[serde(default)]
+    pub flat_layout: FlatLayout,
+
+    /// unprivileged user to switch to once listening
+    /// only used when started as root
+    #[serde(default)]
+    pub user: Option<String>,
+
+    /// group to switch to, defaults to the primary group of user
+    #[serde(default)]
+    pub group: Option<String>,
+
+    /// how long running requests may finish on shutdown or handoff
+    #[serde(
+        default = "default_server_drain_timeout",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub drain_timeout: Duration,
+
+    /// free space of the storage filesystem below which /status answers 503
+    /// so load balancers drain the node, unset never does
+    #[serde(default, deserialize_with = "deserialize_opt_size")]
+    pub status_min_free: Option<u64>,
+
+    /// how long CDNs and proxies in front may cache distfiles
+    /// they're sent as immutable since a name always stands for the same content
+    #[serde(
+        default = "default_server_cache_max_age",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub cache_max_age: Duration,
+
+    /// how served files are read from disk
+    #[serde(default)]
+    pub io: FileIo,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            address: default_server_address(),
+            port: default_server_port(),
+            flat_layout: FlatLayout::default(),
+            user: None,
+            group: None,
+            drain_timeout: default_server_drain_timeout(),
+            status_min_free: None,
+            cache_max_age: default_server_cache_max_age(),
+            io: FileIo::default(),
+        }
+    }
+}
+
+fn default_server_drain_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_server_cache_max_age() -> Duration {
+    Duration::from_secs(365 * 24 * 60 * 60)
+}
+
+fn default_server_address() -> IpAddr {
+    IpAddr::from([127, 0, 0, 1])
+}
+
+fn default_server_port() -> u16 {
+    8000
+}
+
+/// how requests for /distfiles/<file> without a hash directory are handled
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FlatLayout {
+    /// respond with 404 like a filename-hash only mirror
+    #[default]
+    Disabled,
+
+    /// redirect to the hashed path
+    Redirect,
+
+    /// serve the blob directly
+    Serve,
+}
+
+/// how served files are read from disk
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FileIo {
+    /// reads on tokio's blocking thread pool
+    #[default]
+    Default,
+
+    /// reads submitted to io_uring from a dedicated thread
+    /// needs a build with the uring feature
+    Uring,
 }
 
+/// ebuild repositories to sync and index
 #[derive(Deserialize, Clone)]
 pub struct RepoConfig {
-    /// interval in which to sync repos in minutes
-    pub sync_interval: u64,
+    /// interval in which to sync repos
+    #[serde(
+        default = "default_repo_sync_interval",
+        deserialize_with = "deserialize_minutes"
+    )]
+    pub sync_interval: Duration,
+
+    /// list of repos to clone
+    #[serde(default)]
+    pub repos: Vec<Repo>,
+
+    /// run git gc after syncs to drop objects of previous commits
+    /// requires the git cli
+    #[serde(default = "default_repo_gc")]
+    pub gc: bool,
+
+    /// bytes of distfiles new in a sync cycle that get fetched right away
+    /// unset disables prefetching
+    #[serde(default, deserialize_with = "deserialize_opt_size")]
+    pub prefetch_budget: Option<u64>,
+
+    /// don't index distfiles of packages whose every version is
+    /// hard-masked in the repo's profiles/package.mask
+    #[serde(default)]
+    pub skip_masked: bool,
+
+    /// category directories of a tree walked for Manifests at the same time
+    #[serde(default = "default_manifest_concurrency")]
+    pub manifest_concurrency: usize,
+
+    /// how long errors of the repo syncer are kept after they last happened
+    /// 0 keeps none
+    #[serde(
+        default = "default_repo_error_retention",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub error_retention: Duration,
+}
+
+impl Default for RepoConfig {
+    fn default() -> Self {
+        Self {
+            sync_interval: default_repo_sync_interval(),
+            repos: Vec::new(),
+            gc: default_repo_gc(),
+            prefetch_budget: None,
+            skip_masked: false,
+            manifest_concurrency: default_manifest_concurrency(),
+            error_retention: default_repo_error_retention(),
+        }
+    }
+}
+
+fn default_repo_error_retention() -> Duration {
+    Duration::from_secs(7 * 24 * 60 * 60)
+}
+
+fn default_manifest_concurrency() -> usize {
+    4
+}
+
+fn default_repo_sync_interval() -> Duration {
+    Duration::from_secs(5 * 60)
+}
+
+fn default_repo_gc() -> bool {
+    true
+}
+
+/// a repo to clone and index
+/// can be given as plain url or as table with per-repo settings
+#[derive(Deserialize, Clone)]
+#[serde(from = "RepoEntry")]
+pub struct Repo {
+    /// url to clone from
+    /// or absolute path of a local checkout which is used in place
+    pub url: String,
+
+    /// order in which fetch backends are tried for distfiles of this repo
+    /// falls back to fetcher.chain when unset
+    pub fetch_order: Option<Vec<FetchBackend>>,
+
+    /// re-parse Manifests as soon as they change on disk
+    /// defaults to true for local checkouts and false for cloned repos
+    pub watch: Option<bool>,
+
+    /// size in bytes the cached distfiles of this repo may take up
+    /// before they get evicted, unset leaves them to storage.max_size
+    pub max_size: Option<u64>,
+
+    /// how a checkout without metadata/layout.conf is treated
+    pub layout_conf: LayoutCheck,
 
-    /// list of repo urls to clone
-    pub repos: Vec<String>,
+    /// depth below the checkout root its Manifests are at, 0 for any depth
+    /// unset detects the standard category/package/Manifest layout from profiles/categories
+    pub manifest_depth: Option<usize>,
+
+    /// category or category/package globs of the packages that get indexed, empty indexes all
+    pub include: Vec<String>,
+
+    /// category or category/package globs of packages that don't get indexed
+    pub exclude: Vec<String>,
+}
+
+/// handling of repos lacking metadata/layout.conf
+/// like minimal personal overlays
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LayoutCheck {
+    /// refuse to index the repo
+    #[default]
+    Require,
+
+    /// index it anyway but log a warning
+    Warn,
+
+    /// index it anyway
+    Ignore,
+}
+
+impl Repo {
+    /// name of the repo i.e. the last component of its url
+    pub fn name(&self) -> &str {
+        self.url
+            .trim_end_matches('/')
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+    }
+
+    /// location of a local checkout managed outside of portcache
+    /// i.e. the url is an absolute path
+    pub fn local_path(&self) -> Option<&Path> {
+        let path = Path::new(&self.url);
+        path.is_absolute().then_some(path)
+    }
+}
+
+/// accepted forms of a repo in the config
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RepoEntry {
+    Url(String),
+    Table {
+        url: String,
+        #[serde(default)]
+        fetch_order: Option<Vec<FetchBackend>>,
+        #[serde(default)]
+        watch: Option<bool>,
+        #[serde(default, deserialize_with = "deserialize_opt_size")]
+        max_size: Option<u64>,
+        #[serde(default)]
+        layout_conf: LayoutCheck,
+        #[serde(default)]
+        manifest_depth: Option<usize>,
+        #[serde(default)]
+        include: Vec<String>,
+        #[serde(default)]
+        exclude: Vec<String>,
+    },
+}
+
+impl From<RepoEntry> for Repo {
+    fn from(entry: RepoEntry) -> Self {
+        match entry {
+            RepoEntry::Url(url) => Self {
+                url,
+                fetch_order: None,
+                watch: None,
+                max_size: None,
+                layout_conf: LayoutCheck::default(),
+                manifest_depth: None,
+                include: Vec::new(),
+                exclude: Vec::new(),
+            },
+            RepoEntry::Table {
+                url,
+                fetch_order,
+                watch,
+                max_size,
+                layout_conf,
+                manifest_depth,
+                include,
+                exclude,
+            } => Self {
+                url,
+                fetch_order,
+                watch,
+                max_size,
+                layout_conf,
+                manifest_depth,
+                include,
+                exclude,
+            },
+        }
+    }
 }
 
 impl Config {
     /// parse config file
+    /// all problems found by validate() get reported at once
     /// @param config  optional path to config file
     pub fn parse(config: Option<String>) -> Result<Self, Box<dyn std::error::Error>> {
-        let content = fs::read_to_string(config.unwrap_or(String::from("portcache.toml")))?;
-        let config: Config = toml::from_str(&content)?;
+        let path = config.unwrap_or(String::from("portcache.toml"));
+        let content =
+            fs::read_to_string(&path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+        let config: Config = toml::from_str(&content).map_err(|e| format!("{}: {}", path, e))?;
+
+        if let Err(problems) = config.validate() {
+            return Err(format!(
+                "{}: invalid configuration:\n  - {}",
+                path,
+                problems.join("\n  - ")
+            )
+            .into());
+        }
+
         Ok(config)
     }
+
+    /// fetch backends lacking the config they fetch from
+    /// with their name in configs and what is missing
+    fn unconfigured_backends(&self) -> Vec<(FetchBackend, &'static str, &'static str)> {
+        [
+            (
+                FetchBackend::Peer,
+                "peer",
+                "fetcher.peers is empty",
+                self.fetcher.peers.is_empty(),
+            ),
+            (
+                FetchBackend::Proxy,
+                "proxy",
+                "fetcher.proxies is empty",
+                self.fetcher.proxies.is_empty(),
+            ),
+            (
+                FetchBackend::Ipfs,
+                "ipfs",
+                "[fetcher.ipfs] is missing",
+                self.fetcher.ipfs.is_none(),
+            ),
+        ]
+        .into_iter()
+        .filter(|(_, _, _, missing)| *missing)
+        .map(|(backend, name, problem, _)| (backend, name, problem))
+        .collect()
+    }
+
+    /// check values serde can't check on its own
+    /// returns all problems instead of stopping at the first one
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+        let mut check = |ok: bool, problem: String| {
+            if !ok {
+                problems.push(problem);
+            }
+        };
+
+        let storage = &self.storage;
+        check(
+            !storage.eviction_interval.is_zero(),
+            "storage.eviction_interval must be at least 1 minute".to_string(),
+        );
+        check(
+            storage.max_size != Some(0),
+            "storage.max_size must be larger than 0, leave it unset to disable eviction"
+                .to_string(),
+        );
+        check(
+            storage.hash_bits.is_multiple_of(4) && (4..=32).contains(&storage.hash_bits),
+            format!(
+                "storage.hash_bits must be a multiple of 4 between 4 and 32, got {}",
+                storage.hash_bits
+            ),
+        );
+        check(
+            storage.hash_workers > 0,
+            "storage.hash_workers must be at least 1".to_string(),
+        );
+        check(
+            !storage.access_flush_interval.is_zero(),
+            "storage.access_flush_interval must be at least 1 second".to_string(),
+        );
+        check(
+            (1..=19).contains(&storage.compress_level),
+            format!(
+                "storage.compress_level must be between 1 and 19, got {}",
+                storage.compress_level
+            ),
+        );
+        if let Some(memory_cache) = storage.memory_cache {
+            check(
+                (1..=memory_cache).contains(&storage.memory_cache_max_file),
+                "storage.memory_cache_max_file must be between 1 byte and storage.memory_cache"
+                    .to_string(),
+            );
+        }
+        check(
+            self.server.io != FileIo::Uring || cfg!(feature = "uring"),
+            "server.io = \"uring\" needs a build with --features uring".to_string(),
+        );
+        // rsync clients would get the compressed blobs
+        check(
+            storage.compress.is_empty() || !self.rsync.enabled,
+            "storage.compress can't be used with [rsync] enabled".to_string(),
+        );
+        if let Some(distdir) = &storage.local_distdir {
+            check(
+                distdir.is_dir(),
+                format!(
+                    "storage.local_distdir {} is no directory",
+                    distdir.to_string_lossy()
+                ),
+            );
+            check(
+                !distdir.starts_with(&storage.location),
+                "storage.local_distdir must be outside of storage.location".to_string(),
+            );
+            // hard links can't cross filesystems
+            if let (Ok(distdir), Ok(location)) =
+                (fs::metadata(distdir), fs::metadata(&storage.location))
+            {
+                check(
+                    distdir.dev() == location.dev(),
+                    "storage.local_distdir must be on the same filesystem as storage.location"
+                        .to_string(),
+                );
+            }
+        }
+        check(
+            !storage.database.busy_timeout.is_zero(),
+            "storage.database.busy_timeout must be at least 1 millisecond".to_string(),
+        );
+        check(
+            storage.database.snapshot_keep > 0,
+            "storage.database.snapshot_keep must be at least 1".to_string(),
+        );
+        check(
+            storage
+                .database
+                .snapshot_interval
+                .is_none_or(|interval| interval.as_secs() >= 60),
+            "storage.database.snapshot_interval must be at least 1 minute".to_string(),
+        );
+
+        let fetcher = &self.fetcher;
+        let http_policy = tls::HttpPolicy::new(&fetcher.tls);
+        for mirror in &fetcher.mirrors {
+            check(
+                is_http_url(&mirror.url),
+                format!(
+                    "fetcher.mirrors contains \"{}\" which is no http(s) url",
+                    mirror.public_url()
+                ),
+            );
+            if is_http_url(&mirror.url)
+                && let Err(e) = mirror.credentials()
+            {
+                check(false, format!("fetcher.mirrors: {}", e));
+            }
+            let upgrade = mirror.upgrade_http.unwrap_or(fetcher.tls.upgrade_http);
+            check(
+                !http_policy.candidates(&mirror.url, upgrade).is_empty(),
+                format!(
+                    "fetcher.mirrors contains plain http url \"{}\" but fetcher.tls.allow_http is off \
+                    (set upgrade_http or list the host in fetcher.tls.http_hosts)",
+                    mirror.public_url()
+                ),
+            );
+        }
+        check(
+            fetcher.src_uri.breaker_threshold == 0 || !fetcher.src_uri.breaker_cooldown.is_zero(),
+            "fetcher.src_uri.breaker_cooldown must be at least 1 second".to_string(),
+        );
+        check(
+            !self.storage.follow_upstream_layout || !fetcher.mirrors.is_empty(),
+            "storage.follow_upstream_layout needs at least one entry in fetcher.mirrors"
+                .to_string(),
+        );
+        for (key, urls) in [
+            ("fetcher.peers", &fetcher.peers),
+            ("fetcher.proxies", &fetcher.proxies),
+        ] {
+            for url in urls {
+                check(
+                    is_http_url(url),
+                    format!("{} contains \"{}\" which is no http(s) url", key, url),
+                );
+                check(
+                    !http_policy.candidates(url, false).is_empty(),
+                    format!(
+                        "{} contains plain http url \"{}\" but fetcher.tls.allow_http is off",
+                        key, url
+                    ),
+                );
+            }
+        }
+        check(
+            !fetcher.chain.is_empty(),
+            "fetcher.chain must contain at least one backend".to_string(),
+        );
+        for (backend, name, problem) in self.unconfigured_backends() {
+            check(
+                !fetcher.chain.contains(&backend),
+                format!("fetcher.chain contains \"{}\" but {}", name, problem),
+            );
+        }
+        if let Some(ipfs) = &fetcher.ipfs {
+            check(
+                !ipfs.pin_stored || ipfs.api.is_some(),
+                "fetcher.ipfs.pin_stored needs fetcher.ipfs.api".to_string(),
+            );
+        }
+        check(
+            fetcher.metalink.chunk_size > 0,
+            "fetcher.metalink.chunk_size must be larger than 0".to_string(),
+        );
+        check(
+            fetcher.chunked.chunk_size > 0,
+            "fetcher.chunked.chunk_size must be larger than 0".to_string(),
+        );
+        check(
+            fetcher.retry.max_attempts > 0,
+            "fetcher.retry.max_attempts must be at least 1".to_string(),
+        );
+        check(
+            fetcher.retry.initial_backoff <= fetcher.retry.max_backoff,
+            format!(
+                "fetcher.retry.initial_backoff ({:?}) exceeds fetcher.retry.max_backoff ({:?})",
+                fetcher.retry.initial_backoff, fetcher.retry.max_backoff
+            ),
+        );
+        for (domain, headers) in &fetcher.src_uri.headers {
+            for (name, value) in headers {
+                check(
+                    reqwest::header::HeaderName::from_bytes(name.as_bytes()).is_ok()
+                        && reqwest::header::HeaderValue::from_str(value).is_ok(),
+                    format!(
+                        "fetcher.src_uri.headers.\"{}\" contains invalid header \"{}\"",
+                        domain, name
+                    ),
+                );
+            }
+        }
+        if let Some(ca_bundle) = &fetcher.tls.ca_bundle {
+            check(
+                ca_bundle.is_file(),
+                format!(
+                    "fetcher.tls.ca_bundle {} is no file",
+                    ca_bundle.to_string_lossy()
+                ),
+            );
+        }
+        for (host, pins) in &fetcher.tls.pins {
+            check(
+                !pins.is_empty(),
+                format!("fetcher.tls.pins.\"{}\" is empty", host),
+            );
+            for pin in pins {
+                check(
+                    tls::parse_fingerprint(pin).is_some(),
+                    format!(
+                        "fetcher.tls.pins.\"{}\" contains \"{}\" which is no SHA-256 fingerprint",
+                        host, pin
+                    ),
+                );
+            }
+        }
+        check(
+            fetcher.queue.retry_after.as_secs() > 0,
+            "fetcher.queue.retry_after must be at least 1 second".to_string(),
+        );
+
+        let telemetry = &self.telemetry;
+        if let Some(endpoint) = &telemetry.otlp_endpoint {
+            check(
+                is_http_url(endpoint),
+                format!("telemetry.otlp_endpoint \"{}\" is no http(s) url", endpoint),
+            );
+        }
+        check(
+            (0.0..=1.0).contains(&telemetry.sample_ratio),
+            format!(
+                "telemetry.sample_ratio must be between 0 and 1, got {}",
+                telemetry.sample_ratio
+            ),
+        );
+        check(
+            telemetry.export_interval.as_secs() > 0,
+            "telemetry.export_interval must be at least 1 second".to_string(),
+        );
+        check(
+            telemetry.max_queue > 0,
+            "telemetry.max_queue must be at least 1".to_string(),
+        );
+        for (name, value) in &telemetry.headers {
+            check(
+                reqwest::header::HeaderName::from_bytes(name.as_bytes()).is_ok()
+                    && reqwest::header::HeaderValue::from_str(value).is_ok(),
+                format!("telemetry.headers contains invalid header \"{}\"", name),
+            );
+        }
+
+        check(
+            !self.repo.sync_interval.is_zero(),
+            "repo.sync_interval must be at least 1 minute".to_string(),
+        );
+        check(
+            self.repo.manifest_concurrency > 0,
+            "repo.manifest_concurrency must be at least 1".to_string(),
+        );
+        let mut names = HashSet::new();
+        for repo in &self.repo.repos {
+            check(
+                !repo.name().is_empty(),
+                format!("repo.repos contains \"{}\" which has no name", repo.url),
+            );
+            check(
+                names.insert(repo.name()),
+                format!(
+                    "repo.repos contains more than one repo named \"{}\"",
+                    repo.name()
+                ),
+            );
+            check(
+                repo.max_size != Some(0),
+                format!(
+                    "max_size of repo \"{}\" must be larger than 0, leave it unset to disable its quota",
+                    repo.name()
+                ),
+            );
+            for (backend, name, problem) in self.unconfigured_backends() {
+                check(
+                    !repo.fetch_order.iter().flatten().any(|b| *b == backend),
+                    format!(
+                        "fetch_order of repo \"{}\" contains \"{}\" but {}",
+                        repo.name(),
+                        name,
+                        problem
+                    ),
+                );
+            }
+            if let Err(e) = crate::manifest_walker::PackageFilter::new(&repo.include, &repo.exclude)
+            {
+                check(false, format!("filter of repo \"{}\": {}", repo.name(), e));
+            }
+        }
+
+        let quota = &self.quota;
+        check(
+            quota.limit != Some(0),
+            "quota.limit must be larger than 0, leave it unset to only track usage".to_string(),
+        );
+        check(
+            quota.window.as_secs() > 0,
+            "quota.window must be at least 1 second".to_string(),
+        );
+        check(
+            quota.ipv4_prefix <= 32,
+            format!(
+                "quota.ipv4_prefix must be between 0 and 32, got {}",
+                quota.ipv4_prefix
+            ),
+        );
+        check(
+            quota.ipv6_prefix <= 128,
+            format!(
+                "quota.ipv6_prefix must be between 0 and 128, got {}",
+                quota.ipv6_prefix
+            ),
+        );
+
+        check(
+            self.parser.workers > 0,
+            "parser.workers must be at least 1".to_string(),
+        );
+        check(
+            self.parser.worker_ebuilds > 0,
+            "parser.worker_ebuilds must be at least 1".to_string(),
+        );
+        check(
+            self.parser.memory_high != Some(0),
+            "parser.memory_high must be larger than 0, leave it unset to disable the guard"
+                .to_string(),
+        );
+
+        let rsync = &self.rsync;
+        if rsync.enabled {
+            check(rsync.port > 0, "rsync.port must not be 0".to_string());
+            check(
+                !rsync.module.is_empty() && !rsync.module.contains(['[', ']', '/']),
+                format!("rsync.module \"{}\" is no valid module name", rsync.module),
+            );
+        }
+
+        if let Some(binhost) = &self.binhost {
+            check(
+                is_http_url(&binhost.upstream),
+                format!(
+                    "binhost.upstream \"{}\" is no http(s) url",
+                    binhost.upstream
+                ),
+            );
+            check(
+                !http_policy.candidates(&binhost.upstream, false).is_empty(),
+                format!(
+                    "binhost.upstream \"{}\" is plain http but fetcher.tls.allow_http is off",
+                    binhost.upstream
+                ),
+            );
+        }
+
+        if let Some(import) = &self.import {
+            check(
+                import.directory.is_dir(),
+                format!(
+                    "import.directory {} is no directory",
+                    import.directory.to_string_lossy()
+                ),
+            );
+        }
+
+        if let Some(webrsync) = &self.webrsync {
+            check(
+                webrsync.keep > 0,
+                "webrsync.keep must be at least 1".to_string(),
+            );
+        }
+
+        if let Some(control) = &self.control {
+            check(
+                control.socket.is_absolute(),
+                format!(
+                    "control.socket {} must be an absolute path",
+                    control.socket.to_string_lossy()
+                ),
+            );
+            check(
+                control.mode <= 0o777,
+                format!("control.mode {:o} is no permission mode", control.mode),
+            );
+        }
+
+        if let Some(fuse) = &self.fuse {
+            check(
+                fuse.mountpoint.is_dir(),
+                format!(
+                    "fuse.mountpoint {} is no directory",
+                    fuse.mountpoint.to_string_lossy()
+                ),
+            );
+            check(
+                !fuse.mountpoint.starts_with(&self.storage.location),
+                "fuse.mountpoint must be outside of storage.location".to_string(),
+            );
+        }
+
+        if let Some(index) = &self.index
+            && let Err(e) = crate::checksum_index::SigningKey::load(&index.signing_key)
+        {
+            check(false, format!("index.signing_key: {}", e));
+        }
+
+        if let Some(releases) = &self.releases {
+            check(
+                !releases.mirrors.is_empty(),
+                "releases.mirrors must contain at least one mirror".to_string(),
+            );
+            for url in &releases.mirrors {
+                check(
+                    is_http_url(url),
+                    format!(
+                        "releases.mirrors contains \"{}\" which is no http(s) url",
+                        url
+                    ),
+                );
+                check(
+                    !http_policy.candidates(url, false).is_empty(),
+                    format!(
+                        "releases.mirrors contains plain http url \"{}\" but fetcher.tls.allow_http is off",
+                        url
+                    ),
+                );
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+}
+
+/// whether a string is an absolute http or https url
+fn is_http_url(url: &str) -> bool {
+    reqwest::Url::parse(url).is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+/// parse a size in bytes with an optional unit
+/// SI units (KB, MB, GB, TB) are powers of 1000,
+/// IEC units (KiB, MiB, GiB, TiB) and single letters (K, M, G, T) powers of 1024
+/// e.g. "500GB", "1.5 GiB", "512M" or "4096"
+///
+/// @param value  the size as written in the config
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1000,
+        "mb" => 1000_u64.pow(2),
+        "gb" => 1000_u64.pow(3),
+        "tb" => 1000_u64.pow(4),
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        "t" | "tib" => 1 << 40,
+        _ => {
+            return Err(format!(
+                "Invalid size \"{}\": unknown unit \"{}\"",
+                value, unit
+            ));
+        }
+    };
+
+    let bytes = if number.contains('.') {
+        let number: f64 = number
+            .parse()
+            .map_err(|_| format!("Invalid size \"{}\"", value))?;
+        let bytes = number * multiplier as f64;
+        (bytes < u64::MAX as f64).then_some(bytes as u64)
+    } else {
+        let number: u64 = number
+            .parse()
+            .map_err(|_| format!("Invalid size \"{}\"", value))?;
+        number.checked_mul(multiplier)
+    };
+
+    bytes.ok_or_else(|| format!("Invalid size \"{}\": too large", value))
+}
+
+/// parse a duration made up of numbers with units
+/// e.g. "90s", "30m", "1h30m", "500ms" or "2d"
+/// units are ms, s, m, h, d and w
+///
+/// @param value  the duration as written in the config
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let invalid = |reason: &str| format!("Invalid duration \"{}\": {}", value, reason);
+
+    let mut rest = value.trim();
+    if rest.is_empty() {
+        return Err(invalid("empty"));
+    }
+
+    let mut duration = Duration::ZERO;
+    while !rest.is_empty() {
+        let split = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let (number, tail) = rest.split_at(split);
+        let number: u64 = number.parse().map_err(|_| invalid("expected a number"))?;
+
+        let tail = tail.trim_start();
+        let split = tail
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(split);
+        let unit = match unit {
+            "ms" => Duration::from_millis(1),
+            "s" => Duration::from_secs(1),
+            "m" | "min" => Duration::from_secs(60),
+            "h" => Duration::from_secs(60 * 60),
+            "d" => Duration::from_secs(24 * 60 * 60),
+            "w" => Duration::from_secs(7 * 24 * 60 * 60),
+            "" => return Err(invalid("missing unit")),
+            unit => return Err(invalid(&format!("unknown unit \"{}\"", unit))),
+        };
+
+        let part = u32::try_from(number)
+            .ok()
+            .and_then(|number| unit.checked_mul(number))
+            .ok_or_else(|| invalid("too large"))?;
+        duration = duration
+            .checked_add(part)
+            .ok_or_else(|| invalid("too large"))?;
+        rest = tail.trim_start();
+    }
+
+    Ok(duration)
+}
+
+/// accepted forms of a duration in the config
+/// plain numbers are read in the unit the setting used before units were supported
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DurationEntry {
+    Number(u64),
+    Text(String),
+}
+
+impl DurationEntry {
+    /// convert to a Duration
+    ///
+    /// @param unit  what a plain number counts
+    fn into_duration(self, unit: Duration) -> Result<Duration, String> {
+        match self {
+            Self::Number(number) => u32::try_from(number)
+                .ok()
+                .and_then(|number| unit.checked_mul(number))
+                .ok_or_else(|| format!("Invalid duration {}: too large", number)),
+            Self::Text(text) => parse_duration(&text),
+        }
+    }
+}
+
+/// deserialize a duration given as plain number of unit or as string with units
+fn deserialize_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+    unit: Duration,
+) -> Result<Duration, D::Error> {
+    DurationEntry::deserialize(deserializer)?
+        .into_duration(unit)
+        .map_err(serde::de::Error::custom)
+}
+
+/// deserialize a duration where plain numbers are milliseconds
+fn deserialize_millis<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    deserialize_duration(deserializer, Duration::from_millis(1))
+}
+
+/// deserialize a duration where plain numbers are seconds
+fn deserialize_secs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    deserialize_duration(deserializer, Duration::from_secs(1))
+}
+
+/// deserialize a duration where plain numbers are minutes
+fn deserialize_minutes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    deserialize_duration(deserializer, Duration::from_secs(60))
+}
+
+/// deserialize an optional duration where plain numbers are minutes
+fn deserialize_opt_minutes<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    Option::<DurationEntry>::deserialize(deserializer)?
+        .map(|entry| entry.into_duration(Duration::from_secs(60)))
+        .transpose()
+        .map_err(serde::de::Error::custom)
+}
+
+/// deserialize an optional duration where plain numbers are seconds
+fn deserialize_opt_secs<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    Option::<DurationEntry>::deserialize(deserializer)?
+        .map(|entry| entry.into_duration(Duration::from_secs(1)))
+        .transpose()
+        .map_err(serde::de::Error::custom)
+}
+
+/// accepted forms of a size in the config
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SizeEntry {
+    Bytes(u64),
+    Text(String),
+}
+
+impl TryFrom<SizeEntry> for u64 {
+    type Error = String;
+
+    fn try_from(entry: SizeEntry) -> Result<Self, Self::Error> {
+        match entry {
+            SizeEntry::Bytes(bytes) => Ok(bytes),
+            SizeEntry::Text(text) => parse_size(&text),
+        }
+    }
+}
+
+/// deserialize a size given in bytes or as string with unit
+fn deserialize_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    u64::try_from(SizeEntry::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+/// deserialize an optional size given in bytes or as string with unit
+fn deserialize_opt_size<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+    Option::<SizeEntry>::deserialize(deserializer)?
+        .map(u64::try_from)
+        .transpose()
+        .map_err(serde::de::Error::custom)
 }
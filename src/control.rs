@@ -0,0 +1,349 @@
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::app::Deps;
+use crate::blob_storage::BlobStorage;
+use crate::config::Config;
+use crate::distfile_name::DistfileName;
+use crate::evictor::{EvictionTarget, Evictor};
+use crate::repo_db::RepoDB;
+use crate::repo_syncer::SyncProgress;
+use crate::stats;
+
+/// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+
+/// error of a JSON-RPC call
+#[derive(Debug)]
+struct RpcError {
+    /// JSON-RPC error code
+    code: i64,
+
+    /// human readable description
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// parameters of gc, like POST /api/v1/admin/gc
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct GcParams {
+    /// evict down to this many bytes
+    target_size: Option<u64>,
+
+    /// evict until this many bytes are available
+    target_free: Option<u64>,
+}
+
+/// parameters of prefetch
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct PrefetchParams {
+    /// distfiles to fetch into the cache
+    files: Vec<String>,
+}
+
+/// Unix socket taking line-delimited JSON-RPC 2.0 requests
+/// so cron jobs and shell scripts can sync, evict, prefetch and query stats
+/// without the token of the HTTP admin API - access is controlled by the socket's permissions
+pub struct ControlSocket {
+    /// path of the socket
+    socket: PathBuf,
+
+    /// permissions of the socket
+    mode: u32,
+
+    /// repo database for stats
+    repo_db: Arc<RepoDB>,
+
+    /// storage to prefetch into
+    blob_storage: Arc<BlobStorage>,
+
+    /// evictor run by gc
+    evictor: Evictor,
+
+    /// progress of the repo syncer, also used to request syncs
+    sync_progress: Arc<SyncProgress>,
+}
+
+impl ControlSocket {
+    /// create a ControlSocket from config
+    /// returns None without a [control] section
+    ///
+    /// @param config  a reference to Config
+    /// @param deps    components the methods act on
+    pub fn new(config: &Config, deps: &Deps) -> Option<Self> {
+        let control = config.control.as_ref()?;
+        Some(Self {
+            socket: control.socket.clone(),
+            mode: control.mode,
+            repo_db: deps.repo_db.clone(),
+            blob_storage: deps.blob_storage.clone(),
+            evictor: Evictor::manual(config, deps.blob_storage.clone(), deps.repo_db.clone()),
+            sync_progress: deps.sync_progress.clone(),
+        })
+    }
+
+    /// bind the socket and restrict it to mode
+    /// a socket left behind by a crashed instance gets replaced
+    /// while one somebody still listens on is refused
+    pub async fn bind(&self) -> Result<UnixListener, String> {
+        let path = self.socket.to_string_lossy();
+        if let Ok(metadata) = tokio::fs::symlink_metadata(&self.socket).await {
+            if !metadata.file_type().is_socket() {
+                return Err(format!("Control socket {} exists and is no socket", path));
+            }
+            if UnixStream::connect(&self.socket).await.is_ok() {
+                return Err(format!("Control socket {} is in use", path));
+            }
+            tokio::fs::remove_file(&self.socket)
+                .await
+                .map_err(|e| format!("Cannot remove stale control socket {}: {}", path, e))?;
+        }
+
+        // bound next to the final path and moved there once restricted
+        // so nobody can connect in between
+        let name = self
+            .socket
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let bound = self.socket.with_file_name(format!(".{}.new", name));
+        let _ = tokio::fs::remove_file(&bound).await;
+        let listener = UnixListener::bind(&bound)
+            .map_err(|e| format!("Cannot bind control socket {}: {}", path, e))?;
+        tokio::fs::set_permissions(&bound, std::fs::Permissions::from_mode(self.mode))
+            .await
+            .map_err(|e| format!("Cannot set permissions of {}: {}", path, e))?;
+        tokio::fs::rename(&bound, &self.socket)
+            .await
+            .map_err(|e| format!("Cannot move control socket to {}: {}", path, e))?;
+        Ok(listener)
+    }
+
+    /// start the ControlSocket
+    /// this is expected to be called from a tokio::spawn
+    /// and consumes the ControlSocket
+    pub async fn start(self) {
+        let listener = match self.bind().await {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        };
+        println!(
+            "Listening for control commands on {}",
+            self.socket.to_string_lossy()
+        );
+
+        let control = Arc::new(self);
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(control.clone().serve(stream));
+                }
+                Err(e) => eprintln!("Failed to accept control connection: {}", e),
+            }
+        }
+    }
+
+    /// answer the requests of a connection one line at a time
+    ///
+    /// @param stream  the accepted connection
+    async fn serve(self: Arc<Self>, stream: UnixStream) {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(response) = self.handle(&line).await
+                && writer
+                    .write_all(format!("{}\n", response).as_bytes())
+                    .await
+                    .is_err()
+            {
+                return;
+            }
+        }
+    }
+
+    /// handle one JSON-RPC request
+    /// returns the response or None for notifications (requests without id)
+    ///
+    /// @param line  the request
+    pub async fn handle(&self, line: &str) -> Option<String> {
+        let request: Value = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(e) => {
+                return Some(response(
+                    Value::Null,
+                    Err(RpcError::new(PARSE_ERROR, e.to_string())),
+                ));
+            }
+        };
+
+        let id = request.get("id").cloned();
+        let method = request.get("method").and_then(Value::as_str);
+        let result = match (request.get("jsonrpc").and_then(Value::as_str), method) {
+            (Some("2.0"), Some(method)) => {
+                let params = request.get("params").cloned().unwrap_or(Value::Null);
+                self.call(method, params).await
+            }
+            _ => Err(RpcError::new(INVALID_REQUEST, "Not a JSON-RPC 2.0 request")),
+        };
+
+        match id {
+            Some(id) => Some(response(id, result)),
+            None => {
+                if let Err(e) = result {
+                    eprintln!("Control command failed: {}", e.message);
+                }
+                None
+            }
+        }
+    }
+
+    /// run a method
+    ///
+    /// @param method  name of the method
+    /// @param params  its parameters, null if none were given
+    async fn call(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+        match method {
+            "sync" => {
+                self.sync_progress.request_sync();
+                println!("Sync requested via control socket");
+                Ok(Value::Bool(true))
+            }
+            "sync_status" => serde_json::to_value(self.sync_progress.status())
+                .map_err(|e| RpcError::new(INTERNAL_ERROR, e.to_string())),
+            "stats" => stats::report(&self.repo_db, &self.evictor)
+                .await
+                .map_err(|e| RpcError::new(INTERNAL_ERROR, e)),
+            "gc" => self.gc(parse_params(params)?).await,
+            "prefetch" => Ok(self.prefetch(parse_params(params)?).await),
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("Unknown method {}", method),
+            )),
+        }
+    }
+
+    /// evict blobs right away like POST /api/v1/admin/gc
+    ///
+    /// @param params  target to evict to, storage.max_size if none
+    async fn gc(&self, params: GcParams) -> Result<Value, RpcError> {
+        let target = match (
+            params.target_size,
+            params.target_free,
+            self.evictor.max_size(),
+        ) {
+            (Some(size), None, _) => EvictionTarget::Size(size),
+            (None, Some(free), _) => EvictionTarget::Free(free),
+            (None, None, Some(max_size)) => EvictionTarget::Size(max_size),
+            _ => {
+                return Err(RpcError::new(
+                    INVALID_PARAMS,
+                    "Give either target_size or target_free (or set storage.max_size)",
+                ));
+            }
+        };
+
+        let report = self
+            .evictor
+            .run_to(target)
+            .await
+            .map_err(|e| RpcError::new(INTERNAL_ERROR, format!("Eviction failed: {}", e)))?;
+        println!(
+            "Manual eviction removed {} blobs freeing {} bytes",
+            report.removed, report.freed
+        );
+        Ok(json!({
+            "removed": report.removed,
+            "freed": report.freed,
+            "remaining": report.remaining,
+        }))
+    }
+
+    /// fetch distfiles into the cache one after another
+    /// the outcome is reported per file so one failure doesn't hide the others
+    ///
+    /// @param params  distfiles to fetch
+    async fn prefetch(&self, params: PrefetchParams) -> Value {
+        let mut results = Vec::new();
+        for name in params.files {
+            let file = match DistfileName::parse(&name) {
+                Ok(file) => file,
+                Err(e) => {
+                    results
+                        .push(json!({ "file": name, "status": "failed", "error": e.to_string() }));
+                    continue;
+                }
+            };
+
+            let cached = matches!(
+                self.blob_storage.blob_location(file.as_str()).await,
+                Ok(path) if path.is_file()
+            );
+            let result = match cached {
+                true => json!({ "file": name, "status": "cached" }),
+                false => match self.blob_storage.request(&file).await {
+                    Ok(_) => json!({ "file": name, "status": "fetched" }),
+                    Err(e) => {
+                        eprintln!("Failed to prefetch {}: {}", name, e);
+                        json!({ "file": name, "status": "failed", "error": e.to_string() })
+                    }
+                },
+            };
+            results.push(result);
+        }
+        Value::Array(results)
+    }
+}
+
+/// deserialize the params of a call, null meaning none given
+///
+/// @param params  params of the request
+fn parse_params<T: DeserializeOwned + Default>(params: Value) -> Result<T, RpcError> {
+    match params {
+        Value::Null => Ok(T::default()),
+        params => {
+            serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+        }
+    }
+}
+
+/// serialize the response to a request
+///
+/// @param id      id of the request
+/// @param result  outcome of the call
+fn response(id: Value, result: Result<Value, RpcError>) -> String {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(e) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": e.code, "message": e.message },
+        }),
+    }
+    .to_string()
+}
@@ -0,0 +1,75 @@
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+
+/// Portage DISTDIR of the machine running portcache
+/// cached blobs get hard linked into it so the host's own emerge finds them without asking us
+/// links are only ever added for blobs and removed while they still point to the blob,
+/// distfiles Portage downloaded itself are left alone
+pub struct LocalDistdir {
+    /// the DISTDIR, usually /var/cache/distfiles
+    dir: PathBuf,
+}
+
+impl LocalDistdir {
+    /// create a LocalDistdir from config
+    /// returns None unless storage.local_distdir is set
+    pub fn new(config: &Config) -> Option<Self> {
+        let dir = config.storage.local_distdir.clone()?;
+        Some(Self { dir })
+    }
+
+    /// the DISTDIR
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// hard link a blob into the DISTDIR unless something is there already
+    /// returns whether a link was created
+    ///
+    /// @param file  name of the distfile
+    /// @param blob  location of its blob
+    pub async fn link(&self, file: &str, blob: &Path) -> Result<bool, String> {
+        let target = self.dir.join(file);
+        if tokio::fs::symlink_metadata(&target).await.is_ok() {
+            return Ok(false);
+        }
+
+        match tokio::fs::hard_link(blob, &target).await {
+            Ok(_) => Ok(true),
+            // linked by a concurrent request or downloaded by Portage meanwhile
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(false),
+            Err(e) => Err(format!(
+                "Failed to link {} into {}: {}",
+                file,
+                self.dir.to_string_lossy(),
+                e
+            )),
+        }
+    }
+
+    /// remove the link of a blob about to be removed or replaced
+    /// so the DISTDIR doesn't keep its content alive
+    /// a file that isn't the same inode stays
+    ///
+    /// @param file  name of the distfile
+    /// @param blob  location of its blob
+    pub async fn unlink(&self, file: &str, blob: &Path) {
+        let target = self.dir.join(file);
+        let (Ok(linked), Ok(stored)) = (
+            tokio::fs::symlink_metadata(&target).await,
+            tokio::fs::metadata(blob).await,
+        ) else {
+            return;
+        };
+        if linked.dev() != stored.dev() || linked.ino() != stored.ino() {
+            return;
+        }
+
+        if let Err(e) = tokio::fs::remove_file(&target).await {
+            eprintln!("Failed to remove {}: {}", target.to_string_lossy(), e);
+        }
+    }
+}
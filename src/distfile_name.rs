@@ -0,0 +1,109 @@
+use rocket::http::RawStr;
+use rocket::request::FromParam;
+use std::fmt;
+
+/// a distfile name taken from a request path
+/// percent-decoded and guaranteed to be a plain file name
+/// i.e. the normalized form fetch jobs and blobs are keyed on
+/// so foo%2B1.tar.gz and foo+1.tar.gz are the same distfile
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DistfileName(String);
+
+/// reasons a distfile name gets rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidName {
+    /// percent-encoding doesn't decode to valid UTF-8
+    Encoding,
+
+    /// name is empty
+    Empty,
+
+    /// name is "." or ".."
+    PathTraversal,
+
+    /// name contains a path separator
+    Separator,
+
+    /// name contains control characters
+    ControlCharacter,
+}
+
+impl DistfileName {
+    /// validate an already decoded name
+    ///
+    /// @param name  decoded file name
+    pub fn parse(name: &str) -> Result<Self, InvalidName> {
+        if name.is_empty() {
+            return Err(InvalidName::Empty);
+        }
+
+        if name == "." || name == ".." {
+            return Err(InvalidName::PathTraversal);
+        }
+
+        if name.contains(['/', '\\']) {
+            return Err(InvalidName::Separator);
+        }
+
+        if name.chars().any(char::is_control) {
+            return Err(InvalidName::ControlCharacter);
+        }
+
+        Ok(Self(name.to_string()))
+    }
+
+    /// the decoded name
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// percent-encode a decoded distfile name for use as url path segment
+/// so names with reserved characters like # or % reach upstream unchanged
+/// characters allowed in path segments (RFC 3986 pchar) like + and ~ are kept
+///
+/// @param name  decoded file name
+pub fn encode(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+impl<'a> FromParam<'a> for DistfileName {
+    type Error = InvalidName;
+
+    /// rocket hands us the raw segment so decode it first
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        let decoded = RawStr::new(param)
+            .percent_decode()
+            .map_err(|_| InvalidName::Encoding)?;
+        Self::parse(&decoded)
+    }
+}
+
+impl fmt::Display for DistfileName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidName {}
+
+impl fmt::Display for InvalidName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            InvalidName::Encoding => "invalid percent-encoding",
+            InvalidName::Empty => "empty name",
+            InvalidName::PathTraversal => "path traversal",
+            InvalidName::Separator => "contains path separator",
+            InvalidName::ControlCharacter => "contains control characters",
+        };
+        write!(f, "{}", reason)
+    }
+}
@@ -1,66 +1,445 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+use nix::sys::resource::{Resource, setrlimit};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
-use tokio::io;
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncWriteExt;
-use tokio::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::{Notify, Semaphore};
+use tokio::time;
 
 use crate::PORTAGE_PYTHON;
 use crate::SRC_URI_HELPER_PY;
+use crate::config::{ParserConfig, UseConditionals};
+use crate::privileges::RunAs;
 
 /// structure returned by portage helper
-type SrcUriObj = HashMap<String, Vec<String>>;
+/// distfile names mapped to their SRC_URIs
+pub type SrcUriObj = HashMap<String, Vec<SrcUri>>;
 
-/// parse an ebuild file
-pub struct Ebuild {
-    /// object containing the SRC_URIs
-    pub src_uri: SrcUriObj,
+/// a single SRC_URI of a distfile
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SrcUri {
+    /// url the distfile can be fetched from
+    pub uri: String,
+
+    /// USE conditionals gating the url e.g. ["doc", "!test"]
+    #[serde(default, rename = "use")]
+    pub conditions: Vec<String>,
 }
 
-impl Ebuild {
-    /// parse an ebuild file
-    ///
-    /// @param path  PathBuf to ebuild
-    pub async fn parse(path: PathBuf) -> Result<Self, String> {
-        let ebuild = match path.as_os_str().to_str() {
-            Some(s) => s,
-            None => return Err("Could not convert path to str".to_string()),
+impl SrcUri {
+    /// whether the url should be indexed according to the [parser] config
+    pub fn wanted(&self, config: &ParserConfig) -> bool {
+        if self.conditions.is_empty() {
+            return true;
+        }
+
+        match config.use_conditionals {
+            UseConditionals::All => true,
+            UseConditionals::None => false,
+            UseConditionals::Set => {
+                self.conditions
+                    .iter()
+                    .all(|condition| match condition.strip_prefix('!') {
+                        Some(flag) => !config.use_flags.iter().any(|f| f == flag),
+                        None => config.use_flags.iter().any(|f| f == condition),
+                    })
+            }
+        }
+    }
+}
+
+/// prefix of answer lines in the helper's batch mode
+/// anything else on stdout is noise from portage
+const RESPONSE_PREFIX: &str = "portcache:";
+
+/// restrictions the portage helper runs under
+/// ebuilds are arbitrary bash so the helper shouldn't get
+/// the privileges of the daemon
+#[derive(Default)]
+pub struct HelperSandbox {
+    /// user and group to run as
+    run_as: Option<RunAs>,
+
+    /// bubblewrap binary to wrap the helper in
+    bwrap: Option<PathBuf>,
+
+    /// RLIMIT_CPU in seconds
+    cpu_limit: Option<u64>,
+
+    /// RLIMIT_AS in bytes
+    memory_limit: Option<u64>,
+}
+
+impl HelperSandbox {
+    /// create a HelperSandbox from the [parser] config
+    pub fn new(config: &ParserConfig) -> Result<Self, String> {
+        let run_as = match &config.user {
+            Some(user) => Some(RunAs::resolve(user, config.group.as_deref())?),
+            None => None,
+        };
+
+        Ok(Self {
+            run_as,
+            bwrap: config.bwrap.clone(),
+            cpu_limit: config.cpu_limit.map(|limit| limit.as_secs().max(1)),
+            memory_limit: config.memory_limit,
+        })
+    }
+
+    /// command running the helper in batch mode with all restrictions applied
+    fn command(&self) -> Command {
+        let mut command = match &self.bwrap {
+            Some(bwrap) => {
+                let mut command = Command::new(bwrap);
+                command.args([
+                    "--unshare-all",
+                    "--die-with-parent",
+                    "--new-session",
+                    "--ro-bind",
+                    "/",
+                    "/",
+                    "--dev",
+                    "/dev",
+                    "--proc",
+                    "/proc",
+                    "--tmpfs",
+                    "/tmp",
+                    "--",
+                    PORTAGE_PYTHON,
+                ]);
+                command
+            }
+            None => Command::new(PORTAGE_PYTHON),
         };
+        command.args(["-c", SRC_URI_HELPER_PY, "--batch"]);
+
+        if let Some(run_as) = &self.run_as {
+            command.uid(run_as.uid()).gid(run_as.gid());
+        }
+
+        let cpu_limit = self.cpu_limit;
+        let memory_limit = self.memory_limit;
+        if cpu_limit.is_some() || memory_limit.is_some() {
+            // SAFETY: setrlimit is async-signal-safe and doesn't allocate
+            unsafe {
+                command.pre_exec(move || {
+                    if let Some(limit) = cpu_limit {
+                        setrlimit(Resource::RLIMIT_CPU, limit, limit)?;
+                    }
+                    if let Some(limit) = memory_limit {
+                        setrlimit(Resource::RLIMIT_AS, limit, limit)?;
+                    }
+                    Ok(())
+                });
+            }
+        }
+
+        command
+    }
+}
+
+/// answer of the helper for a single ebuild
+#[derive(Deserialize)]
+struct HelperResponse {
+    /// SRC_URIs on success
+    src_uri: Option<SrcUriObj>,
+
+    /// description of the failure
+    error: Option<String>,
+}
+
+/// helper processes currently running
+#[derive(Default)]
+struct Live {
+    /// pids of the helper processes
+    pids: Mutex<HashSet<u32>>,
+
+    /// signalled when a worker becomes idle or exits
+    changed: Notify,
+}
+
+impl Live {
+    /// resident memory of all helper processes in bytes
+    fn rss(&self) -> u64 {
+        let pids: Vec<u32> = self
+            .pids
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .copied()
+            .collect();
+        pids.into_iter().map(tree_rss).sum()
+    }
+
+    /// number of helper processes
+    fn count(&self) -> usize {
+        self.pids.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+}
+
+/// resident memory of a process and all its descendants in bytes
+/// e.g. the helper running below bwrap
+///
+/// @param pid  pid of the process
+fn tree_rss(pid: u32) -> u64 {
+    let rss = std::fs::read_to_string(format!("/proc/{}/status", pid))
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("VmRSS:"))
+                .and_then(|kb| kb.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+        })
+        .unwrap_or(0)
+        * 1024;
+
+    let children: Vec<u32> = std::fs::read_dir(format!("/proc/{}/task", pid))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|task| std::fs::read_to_string(task.path().join("children")).ok())
+        .flat_map(|children| {
+            children
+                .split_whitespace()
+                .filter_map(|child| child.parse().ok())
+                .collect::<Vec<u32>>()
+        })
+        .collect();
+
+    rss + children.into_iter().map(tree_rss).sum::<u64>()
+}
+
+/// a long-lived helper process parsing one ebuild at a time
+struct Worker {
+    /// the helper process, killed when the Worker gets dropped
+    _child: Child,
+
+    /// pid of the helper process
+    pid: Option<u32>,
+
+    /// running helpers this one is registered with
+    live: Arc<Live>,
+
+    /// ebuild paths are written here line by line
+    stdin: ChildStdin,
 
-        // hook into portage python API for processing ebuilds
-        let mut preprocessor = Command::new(PORTAGE_PYTHON)
-            .args(["-", ebuild])
+    /// answers are read from here line by line
+    stdout: io::Lines<io::BufReader<ChildStdout>>,
+
+    /// number of ebuilds this worker parsed
+    parsed: u64,
+}
+
+impl Worker {
+    /// start a new helper process
+    fn spawn(sandbox: &HelperSandbox, live: &Arc<Live>) -> Result<Self, String> {
+        let mut child = sandbox
+            .command()
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
+            .kill_on_drop(true)
             .spawn()
-            .expect("ebuild processor failed to run");
+            .map_err(|e| format!("Failed to run portage helper: {}", e))?;
 
-        // hand script to python
-        let mut stdin = preprocessor.stdin.take().expect("failed to opend stdin");
-        tokio::spawn(async move {
-            stdin
-                .write_all(SRC_URI_HELPER_PY.as_bytes())
-                .await
-                .expect("failed to write stdin");
-        });
+        let stdin = child.stdin.take().ok_or("Portage helper has no stdin")?;
+        let stdout = child.stdout.take().ok_or("Portage helper has no stdout")?;
+
+        let pid = child.id();
+        if let Some(pid) = pid {
+            live.pids
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(pid);
+        }
+
+        Ok(Self {
+            _child: child,
+            pid,
+            live: live.clone(),
+            stdin,
+            stdout: io::BufReader::new(stdout).lines(),
+            parsed: 0,
+        })
+    }
 
-        let stdout = preprocessor.stdout.take().expect("failed to open stdout");
-        let mut src_uri_json = String::new();
-        io::BufReader::new(stdout)
-            .read_to_string(&mut src_uri_json)
+    /// hand an ebuild to the helper and wait for its answer
+    /// Err means the worker is unusable e.g. because it got killed
+    async fn parse(&mut self, ebuild: &str) -> Result<HelperResponse, String> {
+        self.stdin
+            .write_all(format!("{}\n", ebuild).as_bytes())
             .await
-            .map_err(|e| e.to_string())?;
+            .map_err(|e| format!("Failed to write to portage helper: {}", e))?;
+        self.stdin
+            .flush()
+            .await
+            .map_err(|e| format!("Failed to write to portage helper: {}", e))?;
 
-        tokio::spawn(async move {
-            let _ = preprocessor
-                .wait()
+        loop {
+            let line = self
+                .stdout
+                .next_line()
                 .await
-                .expect("preprocessor encountered an error");
-        });
+                .map_err(|e| format!("Failed to read from portage helper: {}", e))?
+                .ok_or_else(|| format!("Portage helper exited while parsing {}", ebuild))?;
+
+            if let Some(response) = line.strip_prefix(RESPONSE_PREFIX) {
+                self.parsed += 1;
+                return serde_json::from_str(response).map_err(|e| e.to_string());
+            }
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if let Some(pid) = self.pid {
+            self.live
+                .pids
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(&pid);
+        }
+        self.live.changed.notify_one();
+    }
+}
+
+/// pool of helper processes so the interpreter and portage
+/// only get set up once per worker instead of once per ebuild
+pub struct HelperPool {
+    /// restrictions workers run under
+    sandbox: HelperSandbox,
+
+    /// workers waiting for an ebuild
+    idle: Mutex<Vec<Worker>>,
+
+    /// limits the number of concurrent workers
+    permits: Semaphore,
+
+    /// maximum number of concurrent workers
+    workers: usize,
+
+    /// workers get replaced after parsing this many ebuilds
+    /// which bounds the resources a single one accumulates
+    max_parsed: u64,
+
+    /// no new workers get started while all of them use more memory than this
+    memory_high: Option<u64>,
+
+    /// running workers, idle or not
+    live: Arc<Live>,
+
+    /// whether starting workers is paused for memory
+    throttled: AtomicBool,
+}
+
+impl HelperPool {
+    /// create a HelperPool from the [parser] config
+    /// workers are started on demand
+    pub fn new(config: &ParserConfig) -> Result<Self, String> {
+        Ok(Self {
+            sandbox: HelperSandbox::new(config)?,
+            idle: Mutex::new(Vec::new()),
+            permits: Semaphore::new(config.workers.max(1)),
+            workers: config.workers.max(1),
+            max_parsed: config.worker_ebuilds.max(1),
+            memory_high: config.memory_high,
+            live: Arc::default(),
+            throttled: AtomicBool::new(false),
+        })
+    }
 
-        let src_uri: SrcUriObj = serde_json::from_str(&src_uri_json).map_err(|e| e.to_string())?;
+    /// number of ebuilds that can be parsed concurrently
+    pub fn size(&self) -> usize {
+        self.workers
+    }
+
+    /// extract the SRC_URIs of an ebuild with an idle or new worker
+    ///
+    /// @param ebuild  path to the ebuild
+    async fn parse(&self, ebuild: &str) -> Result<SrcUriObj, String> {
+        let _permit = self.permits.acquire().await.map_err(|e| e.to_string())?;
+        let mut worker = self.worker().await?;
+
+        // broken workers get dropped which kills them
+        let response = worker.parse(ebuild).await?;
+        if worker.parsed < self.max_parsed {
+            self.idle
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(worker);
+            self.live.changed.notify_one();
+        }
+
+        match (response.src_uri, response.error) {
+            (Some(src_uri), _) => Ok(src_uri),
+            (None, Some(error)) => Err(error),
+            (None, None) => Err(format!("Portage helper returned nothing for {}", ebuild)),
+        }
+    }
+
+    /// take an idle worker or start a new one
+    /// while the running workers use more than memory_high this waits
+    /// for one of them to become idle or exit instead of starting another
+    async fn worker(&self) -> Result<Worker, String> {
+        loop {
+            let idle = self.idle.lock().unwrap_or_else(|e| e.into_inner()).pop();
+            if let Some(worker) = idle {
+                return Ok(worker);
+            }
+
+            // a single worker always gets to run so parsing can't stall
+            let rss = match self.memory_high {
+                Some(_) if self.live.count() > 0 => self.live.rss(),
+                _ => 0,
+            };
+            match self.memory_high {
+                Some(high) if rss > high => {
+                    if !self.throttled.swap(true, Ordering::Relaxed) {
+                        eprintln!(
+                            "Portage helpers use {} MiB (parser.memory_high is {} MiB) - not starting more for now",
+                            rss >> 20,
+                            high >> 20
+                        );
+                    }
+                    // memory can also drop without any worker finishing
+                    let _ =
+                        time::timeout(Duration::from_secs(1), self.live.changed.notified()).await;
+                }
+                _ => {
+                    if self.throttled.swap(false, Ordering::Relaxed) {
+                        println!("Portage helper memory back below parser.memory_high");
+                    }
+                    return Worker::spawn(&self.sandbox, &self.live);
+                }
+            }
+        }
+    }
+}
+
+/// parse an ebuild file
+pub struct Ebuild {
+    /// object containing the SRC_URIs
+    pub src_uri: SrcUriObj,
+}
+
+impl Ebuild {
+    /// parse an ebuild file
+    ///
+    /// @param path    path to ebuild
+    /// @param helper  pool of portage helpers to parse with
+    pub async fn parse(path: &Path, helper: &HelperPool) -> Result<Self, String> {
+        let ebuild = match path.as_os_str().to_str() {
+            Some(s) => s,
+            None => return Err("Could not convert path to str".to_string()),
+        };
 
-        Ok(Self { src_uri })
+        Ok(Self {
+            src_uri: helper.parse(ebuild).await?,
+        })
     }
 }
@@ -0,0 +1,305 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::time;
+
+use crate::blob_storage::{BlobStorage, StoredBlob};
+use crate::config::{Config, EvictionPolicy, TimeWindow};
+use crate::repo_db::RepoDB;
+use crate::request_id::{req_eprintln, req_println};
+use crate::utils;
+
+/// keeps the blob storage below its configured size
+pub struct Evictor {
+    /// storage to evict blobs from
+    blob_storage: Arc<BlobStorage>,
+
+    /// repo database telling which blobs are still referenced
+    repo_db: Arc<RepoDB>,
+
+    /// size in bytes the blobs may take up
+    /// None only allows manual runs
+    max_size: Option<u64>,
+
+    /// size in bytes the cached distfiles of a repo may take up
+    /// keyed by repo name
+    quotas: HashMap<String, u64>,
+
+    /// which blobs get evicted first
+    policy: EvictionPolicy,
+
+    /// interval in which to check the storage size
+    interval: time::Duration,
+
+    /// daily windows scheduled runs are restricted to
+    windows: Vec<TimeWindow>,
+}
+
+/// what a manual eviction run should achieve
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionTarget {
+    /// shrink the blobs to at most this many bytes
+    Size(u64),
+
+    /// evict until the filesystem has this many bytes available
+    Free(u64),
+}
+
+/// bytes taken up by the cached distfiles of a repo
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepoUsage {
+    /// name of the repo
+    pub name: String,
+
+    /// bytes of cached distfiles its Manifests reference
+    pub cached_size: u64,
+
+    /// quota of the repo if configured
+    pub max_size: Option<u64>,
+}
+
+/// result of an eviction run
+#[derive(Debug, Default)]
+pub struct EvictionReport {
+    /// number of blobs removed
+    pub removed: usize,
+
+    /// bytes freed
+    pub freed: u64,
+
+    /// bytes still taken up by blobs
+    pub remaining: u64,
+}
+
+impl Evictor {
+    /// create an Evictor from config
+    /// returns None when neither storage.max_size nor a repo quota is configured
+    pub fn new(
+        config: &Config,
+        blob_storage: Arc<BlobStorage>,
+        repo_db: Arc<RepoDB>,
+    ) -> Option<Self> {
+        let evictor = Self::manual(config, blob_storage, repo_db);
+        (evictor.max_size.is_some() || !evictor.quotas.is_empty()).then_some(evictor)
+    }
+
+    /// create an Evictor for manual runs
+    /// which works without storage.max_size
+    pub fn manual(config: &Config, blob_storage: Arc<BlobStorage>, repo_db: Arc<RepoDB>) -> Self {
+        Self {
+            blob_storage,
+            repo_db,
+            max_size: config.storage.max_size,
+            quotas: config
+                .repo
+                .repos
+                .iter()
+                .filter_map(|repo| Some((repo.name().to_string(), repo.max_size?)))
+                .collect(),
+            policy: config.storage.eviction,
+            interval: config.storage.eviction_interval,
+            windows: config.storage.eviction_windows.clone(),
+        }
+    }
+
+    /// size in bytes the blobs may take up if configured
+    pub fn max_size(&self) -> Option<u64> {
+        self.max_size
+    }
+
+    /// whether scheduled runs may happen at the given time
+    ///
+    /// @param unix_time  seconds since the unix epoch
+    pub fn in_window(&self, unix_time: u64) -> bool {
+        self.windows.is_empty() || self.windows.iter().any(|w| w.contains(unix_time))
+    }
+
+    /// start the Evictor
+    /// this is expected to be called from a tokio::spawn
+    /// and consumes the Evictor
+    pub async fn start(self) {
+        let mut interval = time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            if !self.in_window(utils::unix_time()) {
+                continue;
+            }
+
+            match self.run().await {
+                Ok(report) if report.removed > 0 => println!(
+                    "Evicted {} blobs freeing {} bytes, {} bytes remaining",
+                    report.removed, report.freed, report.remaining
+                ),
+                Ok(_) => (),
+                Err(e) => eprintln!("Eviction failed: {}", e),
+            }
+        }
+    }
+
+    /// evict blobs until every repo fits into its quota
+    /// and the storage fits into max_size
+    /// does nothing without either
+    pub async fn run(&self) -> Result<EvictionReport, String> {
+        match self.max_size {
+            Some(max_size) => self.run_to(EvictionTarget::Size(max_size)).await,
+            None if !self.quotas.is_empty() => self.run_to(EvictionTarget::Size(u64::MAX)).await,
+            None => Ok(EvictionReport::default()),
+        }
+    }
+
+    /// bytes taken up by the cached distfiles of each repo
+    /// includes repos with a quota which have nothing cached
+    pub async fn repo_usage(&self) -> Result<Vec<RepoUsage>, String> {
+        let blobs = self.blob_storage.blobs().await?;
+        let repos = self
+            .repo_db
+            .get_file_repos()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let mut usage: BTreeMap<&str, u64> =
+            self.quotas.keys().map(|name| (name.as_str(), 0)).collect();
+        for blob in &blobs {
+            if let Some(repo) = repos.get(&blob.file) {
+                *usage.entry(repo).or_default() += blob.size;
+            }
+        }
+
+        Ok(usage
+            .into_iter()
+            .map(|(name, cached_size)| RepoUsage {
+                name: name.to_string(),
+                cached_size,
+                max_size: self.quotas.get(name).copied(),
+            })
+            .collect())
+    }
+
+    /// evict blobs of repos over their quota, then further blobs until target is reached
+    /// or nothing is left to evict
+    /// ignores the configured windows
+    ///
+    /// @param target  size or free space to reach
+    pub async fn run_to(&self, target: EvictionTarget) -> Result<EvictionReport, String> {
+        let mut candidates = self.blob_storage.blobs().await?;
+        let mut report = EvictionReport {
+            remaining: candidates.iter().map(|c| c.size).sum(),
+            ..EvictionReport::default()
+        };
+
+        let max_size = match target {
+            EvictionTarget::Size(size) => size,
+            EvictionTarget::Free(free) => {
+                let available = self.blob_storage.disk_space()?.available;
+                report
+                    .remaining
+                    .saturating_sub(free.saturating_sub(available))
+            }
+        };
+
+        // repo of each blob, only needed to enforce quotas
+        let repos = match self.quotas.is_empty() {
+            true => HashMap::new(),
+            false => self
+                .repo_db
+                .get_file_repos()
+                .await
+                .map_err(|e| e.to_string())?,
+        };
+        let mut usage: HashMap<&str, u64> = HashMap::new();
+        for candidate in &candidates {
+            if let Some(repo) = repos.get(&candidate.file) {
+                *usage.entry(repo).or_default() += candidate.size;
+            }
+        }
+        let over_quota = |usage: &HashMap<&str, u64>| {
+            self.quotas
+                .iter()
+                .any(|(repo, quota)| usage.get(repo.as_str()).is_some_and(|used| used > quota))
+        };
+
+        if report.remaining <= max_size && !over_quota(&usage) {
+            return Ok(report);
+        }
+
+        // candidates get evicted from the back
+        match self.policy {
+            EvictionPolicy::Lru => {
+                candidates.sort_by_key(|c| Reverse(c.accessed));
+            }
+            EvictionPolicy::TreeAware => {
+                let referenced = self
+                    .repo_db
+                    .get_referenced_files()
+                    .await
+                    .map_err(|e| e.to_string())?;
+                candidates
+                    .sort_by_key(|c| (Reverse(referenced.contains(&c.file)), Reverse(c.accessed)));
+            }
+            EvictionPolicy::SizeAware => {
+                let now = SystemTime::now();
+                candidates.sort_by_key(|c| {
+                    let idle = now.duration_since(c.accessed).unwrap_or_default();
+                    idle.as_secs() as u128 * c.size as u128
+                });
+            }
+        }
+
+        // repos over their quota give up blobs first
+        // so a huge overlay can't crowd out the distfiles of other repos
+        if over_quota(&usage) {
+            let mut kept = Vec::with_capacity(candidates.len());
+            while let Some(candidate) = candidates.pop() {
+                let repo = match repos.get(&candidate.file) {
+                    Some(repo) => repo.as_str(),
+                    None => {
+                        kept.push(candidate);
+                        continue;
+                    }
+                };
+                let used = usage.get(repo).copied().unwrap_or_default();
+                if self.quotas.get(repo).is_none_or(|quota| used <= *quota) {
+                    kept.push(candidate);
+                    continue;
+                }
+
+                if self.evict(&candidate, &mut report).await {
+                    usage.insert(repo, used - candidate.size);
+                }
+            }
+            kept.reverse();
+            candidates = kept;
+        }
+
+        while report.remaining > max_size {
+            let candidate = match candidates.pop() {
+                Some(candidate) => candidate,
+                None => break,
+            };
+            self.evict(&candidate, &mut report).await;
+        }
+
+        Ok(report)
+    }
+
+    /// remove a blob and account for it in report
+    /// returns whether it got removed
+    /// logs carry the request id when evicting for the admin API
+    async fn evict(&self, candidate: &StoredBlob, report: &mut EvictionReport) -> bool {
+        match self.blob_storage.remove(&candidate.file).await {
+            Ok(true) => {
+                req_println!("Evicting {}", candidate.file);
+                report.removed += 1;
+                report.freed += candidate.size;
+                report.remaining -= candidate.size;
+                true
+            }
+            Ok(false) => false,
+            Err(e) => {
+                req_eprintln!("Failed to evict {}: {}", candidate.file, e);
+                false
+            }
+        }
+    }
+}
@@ -1,262 +1,790 @@
-use futures::lock::Mutex;
+use async_trait::async_trait;
 use futures::stream::StreamExt;
 use futures_core::stream::Stream;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
 use tokio::{
     fs,
     io::{self, AsyncWriteExt},
 };
 
 use crate::blob_storage::BlobStorage;
-use crate::config;
-use crate::repo_db::RepoDB;
-use crate::utils;
-
-#[derive(Clone)]
-enum Layout {
-    FileNameHashBlake2B,
+use crate::config::{self, FetchBackend};
+use crate::import::ImportDir;
+use crate::log_limiter::LogLimiter;
+use crate::manifest_walker::ManifestEntry;
+use crate::repo_db::{BlobSource, Download, RepoDB};
+use crate::request_id::{self, req_eprintln, req_println};
+use crate::telemetry::{self, SpanKind};
+use crate::utils::{self, HashType};
+
+mod breaker;
+mod ipfs;
+mod metalink;
+mod mirror;
+mod peer;
+mod proxy;
+mod ranged;
+mod retry;
+mod src_uri;
+
+/// upstream clients applying CAs, verification exceptions and certificate pins
+pub mod tls;
+
+use ipfs::IpfsFetcher;
+use metalink::MetalinkFetcher;
+pub use mirror::Layout;
+use mirror::MirrorFetcher;
+use peer::PeerFetcher;
+use proxy::ProxyFetcher;
+use src_uri::SrcUriFetcher;
+
+tokio::task_local! {
+    /// url the running fetcher downloads from, kept for the download history
+    static SOURCE: Arc<Mutex<Option<String>>>;
 }
 
-#[derive(Clone)]
-struct Mirror {
-    /// sanitized url of the mirror
-    url: String,
+/// remember url as source of the running fetch
+/// a no-op outside of FetchChain::fetch
+///
+/// @param url  url the blob gets downloaded from
+fn note_source(url: &str) {
+    let _ = SOURCE.try_with(|source| *source.lock().unwrap() = Some(url.to_string()));
 }
 
-pub struct Fetcher {
-    /// list of mirrors to fetch from
-    mirrors: Vec<Mirror>,
+/// a source distfiles can be fetched from
+#[async_trait]
+pub trait Fetcher: Send + Sync {
+    /// short name of this fetcher used in logs
+    fn name(&self) -> &'static str;
 
-    /// next mirror tracker for round robin load balancing
-    next_mirror: Mutex<usize>,
+    /// attempt to fetch a distfile into the storage
+    ///
+    /// @param file  Name of the distfile
+    /// @param store BlobStorage use for storing the file
+    async fn fetch(&self, file: &str, store: &BlobStorage) -> Result<(), FetchError>;
 
-    /// repo database
-    repo_db: Arc<RepoDB>,
+    /// called once another fetcher stored a verified blob
+    ///
+    /// @param file  Name of the distfile
+    /// @param path  location of the verified blob
+    async fn stored(&self, _file: &str, _path: &Path) {}
 }
 
-impl Fetcher {
-    /// create a new Fetcher
-    pub async fn new(config: &config::Config, repo_db: Arc<RepoDB>) -> Result<Self, String> {
-        let mut mirrors: Vec<Mirror> = Vec::new();
+/// classification of a failed fetch attempt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchErrorKind {
+    /// upstream doesn't have the file (404, 410)
+    NotFound,
 
-        for url in config.fetcher.mirrors.clone() {
-            // sanitize url
-            let url = String::from(url.trim_end_matches("/"));
+    /// upstream refused to serve the file (other 4xx)
+    Rejected,
 
-            mirrors.push(Mirror { url })
-        }
+    /// temporary failure worth retrying (timeouts, connection errors, 5xx, 408, 429)
+    Transient,
 
-        if mirrors.is_empty() {
-            return Err("Mirror list is empty".to_string());
-        }
+    /// anything else e.g. local IO errors
+    Other,
+}
 
-        Ok(Self {
-            mirrors,
-            next_mirror: Mutex::new(0),
-            repo_db,
-        })
+/// error of a single fetch attempt
+#[derive(Debug)]
+pub struct FetchError {
+    /// what went wrong
+    pub kind: FetchErrorKind,
+
+    /// human readable description
+    pub message: String,
+}
+
+impl FetchError {
+    /// create a new FetchError
+    pub fn new(kind: FetchErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
     }
 
-    /// select a mirror in round robin fashion
-    async fn select_mirror(&self) -> &Mirror {
-        let mut next = self.next_mirror.lock().await;
-        let mirror = &self.mirrors[*next];
-        *next = if *next == self.mirrors.len() - 1 {
-            0
+    /// combine the errors of several attempts into one
+    /// the result is only NotFound if every attempt was NotFound
+    /// (or there was nothing to attempt)
+    ///
+    /// @param errors   errors of all attempts
+    /// @param message  description of the combined failure
+    pub fn combine(errors: &[FetchError], message: impl Into<String>) -> Self {
+        let has = |kind| errors.iter().any(|e: &FetchError| e.kind == kind);
+        let kind = if errors.iter().all(|e| e.kind == FetchErrorKind::NotFound) {
+            FetchErrorKind::NotFound
+        } else if has(FetchErrorKind::Transient) {
+            FetchErrorKind::Transient
+        } else if has(FetchErrorKind::Rejected) {
+            FetchErrorKind::Rejected
         } else {
-            *next + 1
+            FetchErrorKind::Other
         };
 
-        mirror
+        Self::new(kind, message)
     }
 
-    /// store a blob from a stream in the storage
-    /// @param name  name of the blob
-    /// @param blob  a bytes stream with the blob
-    pub async fn store(
-        &self,
-        name: &String,
-        blob_storage: &BlobStorage,
-        blob: &mut (impl Stream<Item = Result<bytes::Bytes, reqwest::Error>> + std::marker::Unpin),
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let path = blob_storage.blob_location(name).await?;
-
-        // file exists - for now just exit
-        // although best case we don't even attempt to re-download
-        if path.is_file() {
-            return Ok(());
-        }
+    /// classify an error status of upstream
+    pub fn from_status(status: u16, message: impl Into<String>) -> Self {
+        let kind = match status {
+            404 | 410 => FetchErrorKind::NotFound,
+            408 | 429 | 500..=599 => FetchErrorKind::Transient,
+            400..=499 => FetchErrorKind::Rejected,
+            _ => FetchErrorKind::Other,
+        };
+        Self::new(kind, message)
+    }
+
+    /// classify a reqwest error
+    /// its causes are kept so TLS failures don't end up as a bare "error sending request"
+    pub fn from_reqwest(e: &reqwest::Error) -> Self {
+        let kind = match e.status() {
+            Some(status) => Self::from_status(status.as_u16(), "").kind,
+            None if e.is_timeout() || e.is_connect() || e.is_request() || e.is_body() => {
+                FetchErrorKind::Transient
+            }
+            None => FetchErrorKind::Other,
+        };
 
-        // create dir for this blob if needed
-        // assert that parent is not / or empty
-        assert!(path.parent().is_some());
-        if !path.parent().unwrap().is_dir() {
-            fs::create_dir(path.parent().unwrap()).await?;
+        let mut message = e.to_string();
+        let mut source = std::error::Error::source(e);
+        while let Some(cause) = source {
+            let cause_message = cause.to_string();
+            if !message.contains(&cause_message) {
+                message.push_str(&format!(": {}", cause_message));
+            }
+            source = cause.source();
+        }
+        if message.contains("certificate") {
+            message.push_str(" (see fetcher.tls for custom CAs and pinning)");
         }
 
-        // write file chunks
-        let file = fs::File::create(&path).await?;
-        let mut writer = io::BufWriter::new(file);
+        Self { kind, message }
+    }
 
-        while let Some(chunk) = blob.next().await {
-            if chunk.is_err() {
-                writer.flush().await?;
-                eprintln!(
-                    "Error while downloading {}: {}",
-                    name.clone(),
-                    chunk.err().unwrap()
-                );
-                fs::remove_file(&path).await?;
-                return Err("Download failed".into());
+    /// whether retrying the same url might succeed
+    pub fn is_retryable(&self) -> bool {
+        self.kind == FetchErrorKind::Transient
+    }
+}
+
+impl From<String> for FetchError {
+    fn from(message: String) -> Self {
+        Self::new(FetchErrorKind::Other, message)
+    }
+}
+
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// chain of fetchers tried in order until one succeeds
+pub struct FetchChain {
+    /// all fetchers referenced by the config
+    fetchers: HashMap<FetchBackend, Box<dyn Fetcher>>,
+
+    /// default order in which fetchers are tried
+    chain: Vec<FetchBackend>,
+
+    /// per repo overrides of the order
+    repo_orders: HashMap<String, Vec<FetchBackend>>,
+
+    /// repo database used to verify fetched blobs
+    repo_db: Arc<RepoDB>,
+
+    /// drop directory checked before any fetcher
+    import: Option<ImportDir>,
+
+    /// aggregates repeated fetcher failures
+    log: LogLimiter,
+
+    /// how long downloads are kept in the history, zero keeps none
+    history_retention: std::time::Duration,
+
+    /// whether distfiles without Manifest hashes are checked against their first download
+    trust_on_first_use: bool,
+}
+
+impl FetchChain {
+    /// create a new FetchChain from the configured backend order
+    pub async fn new(config: &config::Config, repo_db: Arc<RepoDB>) -> Result<Self, String> {
+        let chain = config.fetcher.chain.clone();
+        let repo_orders: HashMap<String, Vec<FetchBackend>> = config
+            .repo
+            .repos
+            .iter()
+            .filter_map(|repo| Some((repo.name().to_string(), repo.fetch_order.clone()?)))
+            .collect();
+
+        if chain.is_empty() {
+            return Err("Fetcher chain is empty".to_string());
+        }
+
+        // set up every backend used by the chain or any repo
+        let mut fetchers: HashMap<FetchBackend, Box<dyn Fetcher>> = HashMap::new();
+        for backend in chain.iter().chain(repo_orders.values().flatten()) {
+            if fetchers.contains_key(backend) {
+                continue;
             }
 
-            writer.write_all(&chunk?).await?;
+            let fetcher: Box<dyn Fetcher> = match backend {
+                FetchBackend::Mirror => Box::new(MirrorFetcher::new(config, repo_db.clone())?),
+                FetchBackend::SrcUri => Box::new(SrcUriFetcher::new(config, repo_db.clone())?),
+                FetchBackend::Peer => Box::new(PeerFetcher::new(config)?),
+                FetchBackend::Proxy => Box::new(ProxyFetcher::new(config)?),
+                FetchBackend::Ipfs => Box::new(IpfsFetcher::new(config, repo_db.clone())?),
+                FetchBackend::Metalink => Box::new(MetalinkFetcher::new(config, repo_db.clone())?),
+            };
+            fetchers.insert(*backend, fetcher);
         }
 
-        writer.flush().await?;
+        Ok(Self {
+            fetchers,
+            chain,
+            repo_orders,
+            import: ImportDir::new(config, repo_db.clone()),
+            repo_db,
+            log: LogLimiter::new(config.fetcher.log_window),
+            history_retention: config.fetcher.history_retention,
+            trust_on_first_use: config.fetcher.trust_on_first_use,
+        })
+    }
+
+    /// order in which fetchers are tried for file
+    /// repos can override the default chain for their distfiles
+    async fn order(&self, file: &str) -> &[FetchBackend] {
+        if self.repo_orders.is_empty() {
+            return &self.chain;
+        }
 
-        Ok(())
+        match self.repo_db.get_manifest_repo(file).await {
+            Ok(Some(repo)) => match self.repo_orders.get(&repo) {
+                Some(order) => order,
+                None => &self.chain,
+            },
+            _ => &self.chain,
+        }
     }
 
-    /// utility method for fetching from Gentoo mirrors
-    /// will try all configured mirrors before failing
+    /// attempt to fetch a distfile
+    /// imports it from the drop directory if it's there
+    /// and otherwise tries all fetchers in the configured order
+    /// until one produces a blob matching the Manifest
+    /// the outcome ends up in the download history
     ///
     /// @param file  Name of the distfile
     /// @param store BlobStorage use for storing the file
-    async fn fetch_mirror(&self, file: &String, store: &BlobStorage) -> Result<(), String> {
-        for _ in 0..self.mirrors.len() {
-            // select mirror
-            let mirror = self.select_mirror().await;
-
-            // get mirror layout and ignore mirror if it's invalid
-            let layout = match mirror_layout(&mirror.url).await {
-                Ok(layout) => layout,
-                Err(e) => {
-                    eprintln!(
-                        "Ignoring mirror {} due to bad layout.conf: {}",
-                        &mirror.url, e
-                    );
-                    continue;
-                }
-            };
+    pub async fn fetch(&self, file: &String, store: &BlobStorage) -> Result<(), ()> {
+        let mut download = Download {
+            id: 0,
+            file: file.clone(),
+            outcome: String::from("failed"),
+            fetcher: None,
+            source: None,
+            started: utils::unix_time(),
+            duration: 0.0,
+            bytes: None,
+            error: None,
+            request_id: request_id::current(),
+        };
+        let start = Instant::now();
+        let result = self.try_fetchers(file, store, &mut download).await;
 
-            let full_url = match layout {
-                Layout::FileNameHashBlake2B => format!(
-                    "{}/distfiles/{}/{}",
-                    mirror.url,
-                    utils::filename_hash_dir_blake2b(file).unwrap(),
-                    file
-                ),
+        if result.is_ok() {
+            download.bytes = match store.fetch_location(file).await {
+                Ok(path) => fs::metadata(path).await.ok().map(|metadata| metadata.len()),
+                Err(_) => None,
             };
+        }
+        download.duration = start.elapsed().as_secs_f64();
+        self.record(&download).await;
+        if result.is_ok() {
+            self.record_source(&download).await;
+        }
 
-            println!("Fetching {}", &full_url);
+        result
+    }
 
-            let mut stream = match reqwest::get(&full_url).await {
-                Err(e) => {
-                    eprintln!("{}", e);
-                    continue;
+    /// the import and all fetchers of fetch() noting the outcome in download
+    ///
+    /// @param file      Name of the distfile
+    /// @param store     BlobStorage use for storing the file
+    /// @param download  history entry to fill in
+    async fn try_fetchers(
+        &self,
+        file: &String,
+        store: &BlobStorage,
+        download: &mut Download,
+    ) -> Result<(), ()> {
+        if let Some(import) = &self.import {
+            match import.ingest(file, store).await {
+                Ok(_) => {
+                    download.outcome = String::from("imported");
+                    return Ok(());
                 }
-                Ok(response) => match response.error_for_status_ref() {
-                    Err(e) => {
-                        eprintln!("{}", e);
-                        continue;
-                    }
-                    Ok(_) => response.bytes_stream(),
-                },
-            };
+                Err(e) if e.kind == FetchErrorKind::NotFound => (),
+                Err(e) => req_eprintln!("Import failed: {}", e),
+            }
+        }
 
-            match self.store(file, store, &mut stream).await {
-                Ok(_) => (),
+        for backend in self.order(file).await {
+            let fetcher = &self.fetchers[backend];
+            let mut span = telemetry::span(
+                format!("fetch {}", fetcher.name().to_lowercase()),
+                SpanKind::Internal,
+            );
+            span.set("portcache.fetcher", fetcher.name());
+            let source = Arc::new(Mutex::new(None));
+            let result = SOURCE
+                .scope(
+                    source.clone(),
+                    telemetry::within(span.context(), fetcher.fetch(file, store)),
+                )
+                .await;
+            download.fetcher = Some(fetcher.name().to_string());
+            download.source = source.lock().unwrap().take();
+
+            if let Err(e) = result {
+                span.set("portcache.fetch_error", format!("{:?}", e.kind));
+                span.fail(&e);
+                self.log.error(
+                    &format!("{} fetch ({:?})", fetcher.name(), e.kind),
+                    format!("{} fetch failed ({:?}): {}", fetcher.name(), e.kind, e),
+                );
+                download.error = Some(e.to_string());
+                continue;
+            }
+
+            match self.verify(file, store).await {
+                Ok(_) => {
+                    download.outcome = String::from("fetched");
+                    download.error = None;
+                    self.announce(backend, file, store).await;
+                    return Ok(());
+                }
                 Err(e) => {
-                    eprintln!("GET {} failed: {}", &full_url, e);
-                    continue;
+                    span.fail(format!("Verification failed: {}", e));
+                    req_eprintln!("{} fetch failed verification: {}", fetcher.name(), e);
+                    download.error = Some(format!("Verification failed: {}", e));
                 }
-            };
+            }
+        }
+
+        req_eprintln!("All fetches failed for {}", &file);
+        Err(())
+    }
 
-            // only Ok when entire pipeline was success
-            return Ok(());
+    /// let the other fetchers know about a verified blob, e.g. so IPFS can pin it
+    ///
+    /// @param source  backend the blob was fetched with
+    /// @param file    Name of the distfile
+    /// @param store   BlobStorage the blob was fetched into
+    async fn announce(&self, source: &FetchBackend, file: &str, store: &BlobStorage) {
+        let path = match store.fetch_location(file).await {
+            Ok(path) => path,
+            Err(e) => {
+                req_eprintln!("Failed to locate fetched {}: {}", file, e);
+                return;
+            }
+        };
+        for (backend, fetcher) in self.fetchers.iter() {
+            if backend != source {
+                fetcher.stored(file, &path).await;
+            }
         }
+    }
 
-        Err(format!(
-            "Couldn't fetch {} from any configured mirror",
-            file
-        ))
+    /// add a download to the history unless history_retention is zero
+    ///
+    /// @param download  the finished download
+    async fn record(&self, download: &Download) {
+        if self.history_retention.is_zero() {
+            return;
+        }
+
+        let keep_since = download
+            .started
+            .saturating_sub(self.history_retention.as_secs());
+        if let Err(e) = self.repo_db.insert_download(download, keep_since).await {
+            req_eprintln!("Failed to record download of {}: {}", download.file, e);
+        }
     }
 
-    /// utility method for fetching from SRC_URI
+    /// remember the upstream of a fetched blob for as long as it's cached
+    /// so reports of corrupted files can be traced back to where they came from
     ///
-    /// @param file  Name of the distfile
-    /// @param store BlobStorage use for storing the file
-    async fn fetch_src_uri(
-        &self,
-        file: &String,
-        store: &BlobStorage,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        // try all uris
-        let uris = match self.repo_db.get_src_uri(file).await {
-            Ok(x) => x,
-            Err(e) => return Err(e.into()),
+    /// @param download  the successful download
+    async fn record_source(&self, download: &Download) {
+        let source = BlobSource {
+            fetcher: download
+                .fetcher
+                .clone()
+                .unwrap_or_else(|| String::from("Import")),
+            source: download.source.clone(),
+            fetched: utils::unix_time(),
+            request_id: download.request_id.clone(),
         };
-        for uri in uris {
-            println!("Fetching {}", &uri);
+        req_println!(
+            "Stored {} from {} via {}",
+            download.file,
+            source.source.as_deref().unwrap_or("unknown source"),
+            source.fetcher
+        );
+        if let Err(e) = self.repo_db.set_blob_source(&download.file, &source).await {
+            req_eprintln!("Failed to record upstream of {}: {}", download.file, e);
+        }
+    }
 
-            let mut stream = match reqwest::get(uri).await {
-                Err(e) => {
-                    eprintln!("{}", e);
-                    continue;
+    /// verify a fetched blob against its Manifest entry if we know one
+    /// blobs without Manifest hashes are checked against their first download instead
+    async fn verify(&self, file: &str, store: &BlobStorage) -> Result<(), String> {
+        let entry = self
+            .repo_db
+            .get_manifest_entry(file)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let path = store.fetch_location(file).await?;
+        if let Some(entry) = &entry {
+            verify_manifest_checksum(&path, entry).await?;
+            if entry.checksum().is_some() {
+                return Ok(());
+            }
+        }
+
+        match self.trust_on_first_use {
+            // refetches of cached blobs are expected to differ from their first download
+            // the recorded checksum gets replaced once they're swapped in, see record_refetch()
+            true if path != store.blob_location(file).await? => Ok(()),
+            true => self.verify_first_use(file, &path).await,
+            false => Ok(()),
+        }
+    }
+
+    /// replace the checksum recorded on the first download of a blob
+    /// with the one of its refetch once it verified and replaced the cached blob
+    ///
+    /// @param file  name of the distfile
+    /// @param path  location of the swapped in blob
+    pub async fn record_refetch(&self, file: &str, path: &Path) {
+        if !self.trust_on_first_use {
+            return;
+        }
+        match self.repo_db.get_manifest_entry(file).await {
+            Ok(Some(entry)) if entry.checksum().is_some() => return,
+            Ok(_) => (),
+            Err(e) => {
+                req_eprintln!("Failed to look up Manifest entry of {}: {}", file, e);
+                return;
+            }
+        }
+
+        let recorded = async {
+            let size = fs::metadata(path).await.map_err(|e| e.to_string())?.len();
+            let blake2b = utils::file_checksum(path, HashType::Blake2b)
+                .await
+                .map_err(|e| e.to_string())?;
+            self.repo_db
+                .insert_tofu_checksum(file, size, &blake2b)
+                .await
+                .map_err(|e| e.to_string())
+        };
+        match recorded.await {
+            Ok(_) => req_println!("Recording checksum of refetched {}", file),
+            Err(e) => req_eprintln!("Failed to record checksum of refetched {}: {}", file, e),
+        }
+    }
+
+    /// compare a blob with the checksum recorded on its first download
+    /// or record it if this is the first download
+    /// the blob gets removed on mismatch and the mismatch counted
+    ///
+    /// @param file  name of the distfile
+    /// @param path  location of the blob
+    async fn verify_first_use(&self, file: &str, path: &Path) -> Result<(), String> {
+        let size = fs::metadata(path).await.map_err(|e| e.to_string())?.len();
+        let actual = utils::file_checksum(path, HashType::Blake2b)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let known = self
+            .repo_db
+            .get_tofu_checksum(file)
+            .await
+            .map_err(|e| e.to_string())?;
+        match known {
+            None => {
+                req_println!("Recording checksum of first download of {}", file);
+                self.repo_db
+                    .insert_tofu_checksum(file, size, &actual)
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+            Some(known) if known.blake2b == actual && known.size == size => Ok(()),
+            Some(known) => {
+                let _ = fs::remove_file(path).await;
+                if let Err(e) = self.repo_db.insert_tofu_mismatch(file, &actual).await {
+                    req_eprintln!("Failed to record checksum mismatch of {}: {}", file, e);
                 }
-                Ok(response) => match response.error_for_status_ref() {
-                    Err(e) => {
-                        eprintln!("{}", e);
-                        continue;
-                    }
-                    Ok(_) => response.bytes_stream(),
-                },
-            };
+                Err(format!(
+                    "BLAKE2B mismatch for {}: Expected {} as recorded on its first download, Got {}",
+                    file, known.blake2b, actual
+                ))
+            }
+        }
+    }
+}
+
+/// probe the layout.conf of the first entry of fetcher.mirrors
+///
+/// @param config   a reference to Config
+/// @param repo_db  repo database the mirror fetcher gets set up with
+pub async fn primary_mirror_layout(
+    config: &config::Config,
+    repo_db: Arc<RepoDB>,
+) -> Result<Layout, String> {
+    MirrorFetcher::new(config, repo_db)?.primary_layout().await
+}
+
+/// store a blob from a stream in the storage
+/// @param name      name of the blob
+/// @param blob      a bytes stream with the blob
+/// @param modified  upstream mtime of the blob to keep
+pub async fn store_stream(
+    name: &str,
+    blob_storage: &BlobStorage,
+    blob: &mut (impl Stream<Item = Result<bytes::Bytes, reqwest::Error>> + std::marker::Unpin),
+    modified: Option<SystemTime>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = blob_storage.fetch_location(name).await?;
+
+    // file exists - for now just exit
+    // although best case we don't even attempt to re-download
+    if path.is_file() {
+        return Ok(());
+    }
+
+    // create dir for this blob if needed
+    utils::create_parent_dir(&path).await?;
+    #[cfg(feature = "chaos")]
+    if let Some(e) = blob_storage.chaos().disk_error(name) {
+        return Err(e.into());
+    }
 
-            self.store(file, store, &mut stream).await?;
+    // write file chunks
+    let file = fs::File::create(&path).await?;
+    let mut writer = io::BufWriter::new(file);
+
+    while let Some(chunk) = blob.next().await {
+        #[cfg(feature = "chaos")]
+        if let Some(delay) = blob_storage.chaos().stream_delay(name) {
+            tokio::time::sleep(delay).await;
+        }
+        if chunk.is_err() {
+            writer.flush().await?;
+            req_eprintln!("Error while downloading {}: {}", name, chunk.err().unwrap());
+            fs::remove_file(&path).await?;
+            return Err("Download failed".into());
         }
 
-        Ok(())
+        writer.write_all(&chunk?).await?;
     }
 
-    /// attempt to fetch a distfile
-    ///  1. try from a gentoo mirror
-    ///  2. try parsing from SRC_URI
-    ///     @param file  Name of the distfile
-    ///     @param store BlobStorage use for storing the file
-    pub async fn fetch(&self, file: &String, store: &BlobStorage) -> Result<(), ()> {
-        // first try a mirror fetch
-        match self.fetch_mirror(file, store).await {
-            Ok(_) => return Ok(()),
-            Err(e) => eprintln!("Mirror fetch failed: {}", e),
+    writer.flush().await?;
+    drop(writer);
+
+    // served back as Last-Modified like a real mirror would
+    if let Some(modified) = modified
+        && let Err(e) = utils::set_mtime(&path, modified)
+    {
+        req_eprintln!("Failed to set mtime of {}: {}", name, e);
+    }
+
+    Ok(())
+}
+
+/// download a single url into the storage
+///
+/// @param client  client to download with
+/// @param url     full url to fetch
+/// @param file    Name of the distfile
+/// @param store   BlobStorage use for storing the file
+pub async fn fetch_url(
+    client: &tls::Client,
+    url: &str,
+    file: &str,
+    store: &BlobStorage,
+) -> Result<(), FetchError> {
+    req_println!("Fetching {}", url);
+    note_source(url);
+
+    let response = client.send(client.get(url)).await?;
+    store_response(url, file, store, response).await
+}
+
+/// store the body of a response to url in the storage
+///
+/// @param url       url the response came from, used in errors
+/// @param file      Name of the distfile
+/// @param store     BlobStorage use for storing the file
+/// @param response  response to store, rejected unless successful
+pub async fn store_response(
+    url: &str,
+    file: &str,
+    store: &BlobStorage,
+    response: reqwest::Response,
+) -> Result<(), FetchError> {
+    note_source(url);
+    if let Err(e) = response.error_for_status_ref() {
+        return Err(FetchError::from_reqwest(&e));
+    }
+    #[cfg(feature = "chaos")]
+    if let Some(status) = store.chaos().upstream_failure(file) {
+        return Err(FetchError::from_status(
+            status,
+            format!("GET {} failed: injected status {}", url, status),
+        ));
+    }
+    let modified = utils::last_modified(response.headers());
+    let mut stream = response.bytes_stream();
+
+    store_stream(file, store, &mut stream, modified)
+        .await
+        .map_err(|e| {
+            // local IO errors won't go away by asking again
+            let kind = match e.downcast_ref::<std::io::Error>() {
+                Some(_) => FetchErrorKind::Other,
+                None => FetchErrorKind::Transient,
+            };
+            FetchError {
+                kind,
+                message: format!("GET {} failed: {}", url, e),
+            }
+        })
+}
+
+/// download url next to path as <path>.part
+/// returns the location of the partial download and its size
+/// so callers can verify it before moving it into place
+/// the partial download is removed again on failure
+///
+/// @param client  client to download with
+/// @param url     full url to fetch
+/// @param path    final location of the download
+pub async fn download_part(
+    client: &tls::Client,
+    url: &str,
+    path: &Path,
+) -> Result<(PathBuf, u64), FetchError> {
+    req_println!("Fetching {}", url);
+    note_source(url);
+
+    let response = client
+        .send(client.get(url))
+        .await?
+        .error_for_status()
+        .map_err(|e| FetchError::from_reqwest(&e))?;
+
+    utils::create_parent_dir(path)
+        .await
+        .map_err(|e| FetchError::from(e.to_string()))?;
+
+    let part = part_location(path);
+    let mut size = 0;
+    let result: Result<(), FetchError> = async {
+        let file = fs::File::create(&part)
+            .await
+            .map_err(|e| FetchError::from(e.to_string()))?;
+        let mut writer = io::BufWriter::new(file);
+        let mut stream = response.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|e| FetchError::from_reqwest(&e))?;
+            size += chunk.len() as u64;
+            writer
+                .write_all(&chunk)
+                .await
+                .map_err(|e| FetchError::from(e.to_string()))?;
         }
+        writer
+            .flush()
+            .await
+            .map_err(|e| FetchError::from(e.to_string()))
+    }
+    .await;
 
-        // then try a SRC_URI fetch
-        match self.fetch_src_uri(file, store).await {
-            Ok(_) => return Ok(()),
-            Err(e) => eprintln!("SRC_URI fetch failed: {}", e),
+    match result {
+        Ok(_) => Ok((part, size)),
+        Err(e) => {
+            let _ = fs::remove_file(&part).await;
+            Err(e)
         }
+    }
+}
 
-        eprintln!("All fetches failed for {}", &file);
-        Err(())
+/// location a download is written to before it's complete
+pub fn part_location(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+/// verify a stored blob against the size and checksums from its Manifest entry
+/// prefers BLAKE2B and falls back to SHA512 and SHA256
+/// the blob gets removed on mismatch
+///
+/// @param path   location of the blob
+/// @param entry  Manifest entry of the blob
+pub async fn verify_manifest_checksum(path: &Path, entry: &ManifestEntry) -> Result<(), String> {
+    match manifest_mismatch(path, entry).await? {
+        None => Ok(()),
+        Some(mismatch) => {
+            let _ = fs::remove_file(path).await;
+            Err(mismatch)
+        }
     }
 }
 
-/// get the mirror layout
-/// for now this just matches that of the master mirror
-/// TODO: actually make this a proper lookup
-async fn mirror_layout(url: &String) -> Result<Layout, String> {
-    let layout = match reqwest::get(format!("{}/{}", url, "distfiles/layout.conf")).await {
-        Ok(res) => match res.text().await {
-            Ok(text) => text,
-            Err(e) => return Err(e.to_string()),
-        },
-        Err(e) => return Err(e.to_string()),
+/// compare a stored blob with the size and checksums from its Manifest entry
+/// prefers BLAKE2B and falls back to SHA512 and SHA256
+/// returns a description of the mismatch if the blob doesn't match
+///
+/// @param path   location of the blob
+/// @param entry  Manifest entry of the blob
+pub async fn manifest_mismatch(
+    path: &Path,
+    entry: &ManifestEntry,
+) -> Result<Option<String>, String> {
+    // cheap check before hashing the whole file
+    let size = fs::metadata(path).await.map_err(|e| e.to_string())?.len();
+    if size != entry.size {
+        return Ok(Some(format!(
+            "Size mismatch for {}: Expected {}, Got {}",
+            entry.file, entry.size, size
+        )));
+    }
+
+    let (hash, expected) = match entry.checksum() {
+        Some(checksum) => checksum,
+        None => return Ok(None),
     };
 
-    match layout.as_str() {
-        "[structure]\n0=filename-hash BLAKE2B 8\n" => Ok(Layout::FileNameHashBlake2B),
-        _ => Err(format!("Unknown layout in layout.conf: {}", layout)),
+    let actual = utils::file_checksum(path, hash)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if actual != expected.to_lowercase() {
+        return Ok(Some(format!(
+            "{:?} mismatch for {}: Expected {}, Got {}",
+            hash, entry.file, expected, actual
+        )));
     }
+
+    Ok(None)
 }
@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+use crate::config;
+use crate::request_id::{req_eprintln, req_println};
+
+/// consecutive failures of a host and until when it's skipped
+struct HostState {
+    /// transient failures in a row
+    failures: u32,
+
+    /// the host gets skipped until then once failures reached the threshold
+    open_until: Option<Instant>,
+}
+
+/// circuit breaker per upstream host
+/// after threshold transient failures in a row a host is skipped for the cool-down,
+/// then a single attempt is let through which either closes the circuit again or restarts the cool-down
+pub struct HostBreaker {
+    /// failures in a row opening the circuit, 0 disables the breaker
+    threshold: u32,
+
+    /// how long an opened circuit stays open
+    cooldown: Duration,
+
+    /// state of every host that failed recently
+    hosts: Mutex<HashMap<String, HostState>>,
+}
+
+impl HostBreaker {
+    /// create a HostBreaker from config
+    pub fn new(config: &config::SrcUriConfig) -> Self {
+        Self {
+            threshold: config.breaker_threshold,
+            cooldown: config.breaker_cooldown,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// whether host may be tried
+    /// returns how long it's still skipped if not
+    /// the first caller after the cool-down gets the trial attempt
+    ///
+    /// @param host  host of the url about to be fetched
+    pub fn check(&self, host: &str) -> Result<(), Duration> {
+        let mut hosts = self.hosts.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(open_until) = hosts
+            .get_mut(host)
+            .and_then(|state| state.open_until.as_mut())
+        else {
+            return Ok(());
+        };
+
+        let now = Instant::now();
+        if now < *open_until {
+            return Err(*open_until - now);
+        }
+        // everyone else keeps skipping the host while the trial runs
+        *open_until = now + self.cooldown;
+        Ok(())
+    }
+
+    /// note a successful attempt or one the host answered, closing its circuit
+    ///
+    /// @param host  host of the fetched url
+    pub fn success(&self, host: &str) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut hosts = self.hosts.lock().unwrap_or_else(|e| e.into_inner());
+        if hosts
+            .remove(host)
+            .is_some_and(|state| state.open_until.is_some())
+        {
+            req_println!("{} answers again, closing its circuit", host);
+        }
+    }
+
+    /// note a transient failure, opening the circuit of host once it failed too often
+    ///
+    /// @param host  host of the fetched url
+    pub fn failure(&self, host: &str) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut hosts = self.hosts.lock().unwrap_or_else(|e| e.into_inner());
+        let state = hosts.entry(host.to_string()).or_insert(HostState {
+            failures: 0,
+            open_until: None,
+        });
+        state.failures += 1;
+        if state.failures >= self.threshold {
+            if state.open_until.is_none() {
+                req_eprintln!(
+                    "{} failed {} times in a row, skipping it for {}s",
+                    host,
+                    state.failures,
+                    self.cooldown.as_secs()
+                );
+            }
+            state.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{self, StreamExt};
+use std::hash::BuildHasher;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::fs;
+use tokio_util::io::ReaderStream;
+
+use crate::blob_storage::BlobStorage;
+use crate::config;
+use crate::fetcher::tls;
+use crate::fetcher::{FetchError, FetchErrorKind, Fetcher, fetch_url, verify_manifest_checksum};
+use crate::repo_db::RepoDB;
+use crate::request_id::{req_eprintln, req_println};
+
+/// Manifest hashes distfiles are looked up by on IPFS mirrors, strongest first
+const HASHES: [&str; 3] = ["BLAKE2B", "SHA512", "SHA256"];
+
+/// fetch distfiles by content hash from IPFS mirrors through an HTTP gateway
+/// mirrors are laid out as <mirror>/<hash>/<checksum>, e.g. /ipns/<name>/blake2b/<BLAKE2B>,
+/// so only distfiles with a Manifest entry can be found
+/// blobs are pinned once they matched their Manifest entry
+pub struct IpfsFetcher {
+    /// sanitized url of the HTTP gateway
+    gateway: String,
+
+    /// sanitized IPFS paths of the mirrors
+    mirrors: Vec<String>,
+
+    /// sanitized url of the Kubo RPC API used for pinning
+    api: Option<String>,
+
+    /// whether blobs of other fetchers get added to IPFS and pinned too
+    pin_stored: bool,
+
+    /// client for the gateway and the RPC API
+    client: tls::Client,
+
+    /// repo database holding the Manifest checksums
+    repo_db: Arc<RepoDB>,
+}
+
+impl IpfsFetcher {
+    /// create a new IpfsFetcher
+    pub fn new(config: &config::Config, repo_db: Arc<RepoDB>) -> Result<Self, String> {
+        let ipfs = match &config.fetcher.ipfs {
+            Some(ipfs) => ipfs,
+            None => return Err("IPFS fetcher enabled but [fetcher.ipfs] missing".to_string()),
+        };
+
+        let mirrors: Vec<String> = ipfs
+            .mirrors
+            .iter()
+            .map(|mirror| format!("/{}", mirror.trim_matches('/')))
+            .collect();
+
+        if mirrors.is_empty() {
+            return Err("IPFS mirror list is empty".to_string());
+        }
+
+        Ok(Self {
+            gateway: String::from(ipfs.gateway.trim_end_matches("/")),
+            mirrors,
+            api: ipfs
+                .api
+                .as_ref()
+                .map(|api| String::from(api.trim_end_matches("/"))),
+            pin_stored: ipfs.pin_stored,
+            client: tls::Client::new(&config.fetcher.tls)?,
+            repo_db,
+        })
+    }
+
+    /// pin an IPFS path on the local node so it stays available to others
+    ///
+    /// @param api   Kubo RPC API url
+    /// @param path  IPFS path to pin
+    async fn pin(&self, api: &str, path: &str) -> Result<(), String> {
+        let url = format!("{}/api/v0/pin/add", api);
+        self.client
+            .send(self.client.post(&url).query(&[("arg", path)]))
+            .await
+            .map_err(|e| e.to_string())?
+            .error_for_status()
+            .map_err(|e| e.to_string())?;
+
+        Ok(())
+    }
+
+    /// add a blob to the local node and pin it
+    /// uploaded as multipart form like `ipfs add` does
+    ///
+    /// @param api   Kubo RPC API url
+    /// @param file  name of the distfile
+    /// @param path  location of the blob
+    async fn add(&self, api: &str, file: &str, path: &Path) -> Result<(), String> {
+        let blob = fs::File::open(path).await.map_err(|e| e.to_string())?;
+        let state = std::collections::hash_map::RandomState::new();
+        let boundary = format!(
+            "portcache{:016x}{:016x}",
+            state.hash_one(file),
+            state.hash_one(path)
+        );
+        let head = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n",
+            boundary, file
+        );
+        let tail = format!("\r\n--{}--\r\n", boundary);
+        let body = stream::iter([Ok::<_, std::io::Error>(Bytes::from(head))])
+            .chain(ReaderStream::new(blob))
+            .chain(stream::iter([Ok(Bytes::from(tail))]));
+
+        let url = format!("{}/api/v0/add", api);
+        let request = self
+            .client
+            .post(&url)
+            .query(&[("pin", "true"), ("quieter", "true")])
+            .header(
+                reqwest::header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", boundary),
+            )
+            .body(reqwest::Body::wrap_stream(body));
+        self.client
+            .send(request)
+            .await
+            .map_err(|e| e.to_string())?
+            .error_for_status()
+            .map_err(|e| e.to_string())?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Fetcher for IpfsFetcher {
+    fn name(&self) -> &'static str {
+        "IPFS"
+    }
+
+    /// fetch from IPFS mirrors in configured order by the Manifest checksums of file
+    async fn fetch(&self, file: &str, store: &BlobStorage) -> Result<(), FetchError> {
+        let entry = match self.repo_db.get_manifest_entry(file).await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => {
+                return Err(FetchError::new(
+                    FetchErrorKind::NotFound,
+                    format!("No Manifest checksum to look up {} by on IPFS", file),
+                ));
+            }
+            Err(e) => return Err(e.to_string().into()),
+        };
+        let checksums: Vec<(&str, &String)> = HASHES
+            .iter()
+            .filter_map(|hash| Some((*hash, entry.hashes.get(*hash)?)))
+            .collect();
+
+        let mut errors = Vec::new();
+        for mirror in self.mirrors.iter() {
+            for (hash, checksum) in &checksums {
+                let path = format!(
+                    "{}/{}/{}",
+                    mirror,
+                    hash.to_lowercase(),
+                    checksum.to_lowercase()
+                );
+                let full_url = format!("{}{}", self.gateway, path);
+                if let Err(e) = fetch_url(&self.client, &full_url, file, store).await {
+                    // one hash missing doesn't make the others worth skipping
+                    if e.kind != FetchErrorKind::NotFound {
+                        req_eprintln!("{}", e);
+                    }
+                    errors.push(e);
+                    continue;
+                }
+
+                // /ipns mirrors are mutable so the content isn't trusted before it matched
+                let stored = store.fetch_location(file).await?;
+                if let Err(e) = verify_manifest_checksum(&stored, &entry).await {
+                    req_eprintln!("{} from {}", e, path);
+                    errors.push(e.into());
+                    continue;
+                }
+
+                // failing to pin doesn't invalidate the fetched blob
+                if let Some(api) = &self.api {
+                    match self.pin(api, &path).await {
+                        Ok(_) => req_println!("Pinned {}", path),
+                        Err(e) => req_eprintln!("Failed to pin {}: {}", path, e),
+                    }
+                }
+
+                return Ok(());
+            }
+        }
+
+        Err(FetchError::combine(
+            &errors,
+            format!("Couldn't fetch {} from any configured IPFS mirror", file),
+        ))
+    }
+
+    /// add a verified blob of another fetcher to the local node if fetcher.ipfs.pin_stored is set
+    async fn stored(&self, file: &str, path: &Path) {
+        let Some(api) = self.api.as_ref().filter(|_| self.pin_stored) else {
+            return;
+        };
+        match self.add(api, file, path).await {
+            Ok(_) => req_println!("Added {} to IPFS", file),
+            Err(e) => req_eprintln!("Failed to add {} to IPFS: {}", file, e),
+        }
+    }
+}
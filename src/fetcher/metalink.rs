@@ -0,0 +1,252 @@
+use async_trait::async_trait;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::blob_storage::BlobStorage;
+use crate::config;
+use crate::fetcher::ranged::{commit_part, fetch_ranged};
+use crate::fetcher::tls;
+use crate::fetcher::{FetchError, FetchErrorKind, Fetcher, download_part};
+use crate::repo_db::RepoDB;
+use crate::request_id::{req_eprintln, req_println};
+use crate::utils::{self, HashType};
+
+/// suffixes under which upstreams publish metalinks next to the file
+const METALINK_SUFFIXES: [&str; 2] = [".meta4", ".metalink"];
+
+/// the parts of a metalink file we care about
+struct Metalink {
+    /// file size in bytes if declared
+    size: Option<u64>,
+
+    /// declared checksums
+    hashes: Vec<(HashType, String)>,
+
+    /// http(s) sources ordered by preference
+    urls: Vec<String>,
+}
+
+/// fetch via metalinks published next to SRC_URIs
+/// downloads in parallel from all listed sources and verifies
+/// the result against the hashes declared in the metalink
+/// torrent metaurls are ignored
+pub struct MetalinkFetcher {
+    /// repo database
+    repo_db: Arc<RepoDB>,
+
+    /// size of each range request in bytes
+    chunk_size: u64,
+
+    /// client used for all requests
+    client: tls::Client,
+}
+
+impl MetalinkFetcher {
+    /// create a new MetalinkFetcher
+    pub fn new(config: &config::Config, repo_db: Arc<RepoDB>) -> Result<Self, String> {
+        Ok(Self {
+            repo_db,
+            chunk_size: config.fetcher.metalink.chunk_size,
+            client: tls::Client::new(&config.fetcher.tls)?,
+        })
+    }
+
+    /// download the file described by a metalink and verify it
+    /// the download only gets moved into place once it matches the declared hashes
+    async fn fetch_metalink(
+        &self,
+        file: &str,
+        store: &BlobStorage,
+        metalink: Metalink,
+    ) -> Result<(), String> {
+        let path = store.fetch_location(file).await?;
+
+        let part = match metalink.size {
+            Some(size) if metalink.urls.len() > 1 => {
+                fetch_ranged(&self.client, &metalink.urls, size, self.chunk_size, &path).await?
+            }
+            _ => self.fetch_single(&metalink.urls, &path).await?,
+        };
+
+        let check = verify(&part, file, &metalink).await;
+        commit_part(&part, &path, check).await
+    }
+
+    /// download from the first source that works
+    /// returns the location of the partial download
+    ///
+    /// @param urls  sources ordered by preference
+    /// @param path  final location of the file
+    async fn fetch_single(&self, urls: &[String], path: &Path) -> Result<PathBuf, String> {
+        for url in urls {
+            match download_part(&self.client, url, path).await {
+                Ok((part, _)) => return Ok(part),
+                Err(e) => req_eprintln!("{}", e),
+            }
+        }
+
+        Err("Couldn't fetch from any metalink source".to_string())
+    }
+}
+
+/// check a download against the size and the strongest hash declared in its metalink
+///
+/// @param part      location of the download
+/// @param file      Name of the distfile
+/// @param metalink  the metalink describing file
+async fn verify(part: &Path, file: &str, metalink: &Metalink) -> Result<(), String> {
+    if let Some(size) = metalink.size {
+        let actual = tokio::fs::metadata(part)
+            .await
+            .map_err(|e| e.to_string())?
+            .len();
+        if actual != size {
+            return Err(format!(
+                "Size mismatch for {}: Expected {}, Got {}",
+                file, size, actual
+            ));
+        }
+    }
+
+    // prefer the strongest declared hash
+    let strongest = [HashType::Sha512, HashType::Sha256]
+        .into_iter()
+        .find_map(|t| metalink.hashes.iter().find(|(h, _)| *h == t));
+
+    let (hash, expected) = match strongest {
+        Some(x) => x,
+        None => {
+            req_eprintln!("Metalink for {} declares no supported hash", file);
+            return Ok(());
+        }
+    };
+
+    let actual = utils::file_checksum(part, *hash)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if actual != expected.to_lowercase() {
+        return Err(format!(
+            "{:?} mismatch for {}: Expected {}, Got {}",
+            hash, file, expected, actual
+        ));
+    }
+
+    Ok(())
+}
+
+#[async_trait]
+impl Fetcher for MetalinkFetcher {
+    fn name(&self) -> &'static str {
+        "Metalink"
+    }
+
+    /// look for metalinks next to every known SRC_URI
+    async fn fetch(&self, file: &str, store: &BlobStorage) -> Result<(), FetchError> {
+        let uris = self
+            .repo_db
+            .get_src_uri(file)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let mut found = false;
+        for uri in uris {
+            for suffix in METALINK_SUFFIXES {
+                let url = format!("{}{}", uri, suffix);
+                let xml = match self.client.send(self.client.get(&url)).await {
+                    Ok(res) if res.status().is_success() => match res.text().await {
+                        Ok(text) => text,
+                        Err(_) => continue,
+                    },
+                    _ => continue,
+                };
+
+                let metalink = match parse_metalink(&xml, file) {
+                    Ok(m) => m,
+                    Err(e) => {
+                        req_eprintln!("Ignoring bad metalink {}: {}", url, e);
+                        continue;
+                    }
+                };
+
+                req_println!("Using metalink {}", url);
+                found = true;
+                match self.fetch_metalink(file, store, metalink).await {
+                    Ok(_) => return Ok(()),
+                    Err(e) => req_eprintln!("{}", e),
+                }
+            }
+        }
+
+        // without any usable metalink this is the same as a 404
+        let kind = match found {
+            true => FetchErrorKind::Other,
+            false => FetchErrorKind::NotFound,
+        };
+        Err(FetchError::new(
+            kind,
+            format!("Couldn't fetch {} via any metalink", file),
+        ))
+    }
+}
+
+/// parse the entry for file from a Metalink 4 (RFC 5854) or Metalink 3 document
+///
+/// @param xml   the metalink document
+/// @param file  Name of the distfile
+fn parse_metalink(xml: &str, file: &str) -> Result<Metalink, String> {
+    let doc = roxmltree::Document::parse(xml).map_err(|e| e.to_string())?;
+
+    let entry = doc
+        .descendants()
+        .find(|n| {
+            n.tag_name().name() == "file"
+                && n.attribute("name")
+                    .is_some_and(|name| name.rsplit('/').next() == Some(file))
+        })
+        .ok_or(format!("No entry for {}", file))?;
+
+    let mut size = None;
+    let mut hashes = Vec::new();
+    let mut urls: Vec<(u32, String)> = Vec::new();
+
+    for node in entry.descendants().filter(|n| n.is_element()) {
+        let text = node.text().unwrap_or_default().trim();
+        match node.tag_name().name() {
+            "size" => size = text.parse().ok(),
+            "hash" => {
+                let hash = match node.attribute("type") {
+                    Some("sha-256") | Some("sha256") => HashType::Sha256,
+                    Some("sha-512") | Some("sha512") => HashType::Sha512,
+                    _ => continue,
+                };
+                hashes.push((hash, text.to_string()));
+            }
+            "url" => {
+                if !(text.starts_with("http://") || text.starts_with("https://")) {
+                    continue;
+                }
+                // v4 priority: lower is better, v3 preference: higher is better
+                let rank = match (node.attribute("priority"), node.attribute("preference")) {
+                    (Some(p), _) => p.parse().unwrap_or(u32::MAX),
+                    (None, Some(p)) => 100u32.saturating_sub(p.parse().unwrap_or(0)),
+                    (None, None) => u32::MAX,
+                };
+                urls.push((rank, text.to_string()));
+            }
+            _ => (),
+        }
+    }
+
+    if urls.is_empty() {
+        return Err(format!("No http(s) sources for {}", file));
+    }
+
+    urls.sort_by_key(|(rank, _)| *rank);
+
+    Ok(Metalink {
+        size,
+        hashes,
+        urls: urls.into_iter().map(|(_, url)| url).collect(),
+    })
+}
@@ -0,0 +1,400 @@
+use async_trait::async_trait;
+use futures::lock::Mutex;
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
+
+use crate::blob_storage::BlobStorage;
+use crate::config;
+use crate::distfile_name;
+use crate::fetcher::ranged::{commit_part, fetch_ranged};
+use crate::fetcher::retry::RetryPolicy;
+use crate::fetcher::tls;
+use crate::fetcher::{FetchError, FetchErrorKind, Fetcher, fetch_url, verify_manifest_checksum};
+use crate::log_limiter::LogLimiter;
+use crate::manifest_walker::ManifestEntry;
+use crate::repo_db::RepoDB;
+use crate::request_id::req_eprintln;
+use crate::utils;
+
+/// distfiles layout of a mirror as announced by its layout.conf
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    /// BLAKE2B filename-hash directories with names of this many bits
+    FileNameHash(u8),
+
+    /// all distfiles at the top level
+    Flat,
+}
+
+impl Layout {
+    /// the first layout of a layout.conf portcache can fetch with
+    /// portage tries the entries in order so earlier ones are preferred
+    ///
+    /// @param conf  content of the layout.conf
+    pub fn parse(conf: &str) -> Option<Self> {
+        let mut entries: Vec<(u32, &str)> = conf
+            .lines()
+            .skip_while(|line| line.trim() != "[structure]")
+            .skip(1)
+            .take_while(|line| !line.trim_start().starts_with('['))
+            .filter_map(|line| {
+                let (index, value) = line.split_once('=')?;
+                Some((index.trim().parse().ok()?, value.trim()))
+            })
+            .collect();
+        entries.sort_unstable_by_key(|(index, _)| *index);
+
+        entries.into_iter().find_map(|(_, value)| {
+            match value.split_whitespace().collect::<Vec<_>>()[..] {
+                ["filename-hash", "BLAKE2B", bits] => bits
+                    .parse()
+                    .ok()
+                    .filter(|bits: &u8| bits.is_multiple_of(4) && (4..=32).contains(bits))
+                    .map(Layout::FileNameHash),
+                ["flat"] => Some(Layout::Flat),
+                _ => None,
+            }
+        })
+    }
+}
+
+/// how long to stick to http:// after the https:// variant of an upgraded mirror failed
+const UPGRADE_RECHECK: Duration = Duration::from_secs(3600);
+
+struct Mirror {
+    /// sanitized url of the mirror
+    url: String,
+
+    /// base urls to try in order
+    /// the https:// variant of url comes first if it gets upgraded
+    bases: Vec<String>,
+
+    /// when the https:// variant last failed while falling back to http:// is allowed
+    https_failed: std::sync::Mutex<Option<Instant>>,
+}
+
+/// fetch from Gentoo mirrors with round robin load balancing
+pub struct MirrorFetcher {
+    /// list of mirrors to fetch from
+    mirrors: Vec<Mirror>,
+
+    /// next mirror tracker for round robin load balancing
+    next_mirror: Mutex<usize>,
+
+    /// repo database used to look up file sizes and checksums
+    repo_db: Arc<RepoDB>,
+
+    /// chunked download settings
+    chunked: config::ChunkedConfig,
+
+    /// client used for all requests
+    client: tls::Client,
+
+    /// retry policy applied to each mirror
+    retry: RetryPolicy,
+
+    /// files no mirror had, mapped to when that was noticed
+    /// lets repeated requests skip straight to the next fetcher
+    not_found: Mutex<HashMap<String, Instant>>,
+
+    /// how long not_found entries are valid
+    not_found_ttl: Duration,
+
+    /// aggregates repeated failures per mirror
+    log: LogLimiter,
+}
+
+impl MirrorFetcher {
+    /// create a new MirrorFetcher
+    pub fn new(config: &config::Config, repo_db: Arc<RepoDB>) -> Result<Self, String> {
+        let mut mirrors: Vec<Mirror> = Vec::new();
+        let mut client = tls::Client::new(&config.fetcher.tls)?;
+        let policy = tls::HttpPolicy::new(&config.fetcher.tls);
+
+        for mirror in config.fetcher.mirrors.iter() {
+            // credentials never end up in urls or logs
+            let url = mirror.public_url();
+            let upgrade = mirror.upgrade_http.unwrap_or(policy.upgrade_http());
+            let bases: Vec<String> = policy
+                .candidates(&url, upgrade)
+                .iter()
+                .map(|base| base.trim_end_matches('/').to_string())
+                .collect();
+            if bases.is_empty() {
+                return Err(format!(
+                    "Mirror {} uses plain HTTP which fetcher.tls.allow_http forbids",
+                    url
+                ));
+            }
+
+            if let Some(credentials) = mirror.credentials()? {
+                warn_if_readable(mirror.password_file.as_deref());
+                for base in &bases {
+                    client = client.authenticate(base, credentials.clone());
+                }
+            }
+
+            mirrors.push(Mirror {
+                url,
+                bases,
+                https_failed: std::sync::Mutex::new(None),
+            })
+        }
+
+        if mirrors.is_empty() {
+            return Err("Mirror list is empty".to_string());
+        }
+
+        Ok(Self {
+            mirrors,
+            next_mirror: Mutex::new(0),
+            repo_db,
+            chunked: config.fetcher.chunked.clone(),
+            client,
+            retry: RetryPolicy::new(&config.fetcher.retry),
+            not_found: Mutex::new(HashMap::new()),
+            not_found_ttl: config.fetcher.not_found_ttl,
+            log: LogLimiter::new(config.fetcher.log_window),
+        })
+    }
+
+    /// select a mirror in round robin fashion
+    async fn select_mirror(&self) -> &Mirror {
+        let mut next = self.next_mirror.lock().await;
+        let mirror = &self.mirrors[*next];
+        *next = if *next == self.mirrors.len() - 1 {
+            0
+        } else {
+            *next + 1
+        };
+
+        mirror
+    }
+
+    /// build the full url of file on a mirror
+    ///
+    /// @param mirror  the Mirror to use
+    /// @param file    Name of the distfile
+    async fn mirror_url(&self, mirror: &Mirror, file: &str) -> Result<String, String> {
+        // get mirror layout and ignore mirror if it's invalid
+        let (base, layout) = self
+            .mirror_base(mirror)
+            .await
+            .map_err(|e| format!("bad layout.conf: {}", e))?;
+
+        Ok(match layout {
+            Layout::FileNameHash(bits) => format!(
+                "{}/distfiles/{}/{}",
+                base,
+                utils::filename_hash_dir(file, bits),
+                distfile_name::encode(file)
+            ),
+            Layout::Flat => format!("{}/distfiles/{}", base, distfile_name::encode(file)),
+        })
+    }
+
+    /// find the base url of a mirror to fetch from along with its layout
+    /// upgraded mirrors use https:// while it works and fall back to http:// if allowed
+    ///
+    /// @param mirror  the Mirror to use
+    async fn mirror_base<'a>(&self, mirror: &'a Mirror) -> Result<(&'a str, Layout), String> {
+        let mut error = String::new();
+        for base in &mirror.bases {
+            let fallback = base != &mirror.url && mirror.bases.len() > 1;
+            let recently_failed = mirror
+                .https_failed
+                .lock()
+                .unwrap()
+                .is_some_and(|since| since.elapsed() < UPGRADE_RECHECK);
+            if fallback && recently_failed {
+                continue;
+            }
+
+            match mirror_layout(&self.client, base).await {
+                Ok(layout) => return Ok((base, layout)),
+                Err(e) if fallback => {
+                    req_eprintln!(
+                        "{} isn't available over HTTPS, falling back to {}: {}",
+                        base,
+                        mirror.url,
+                        e
+                    );
+                    *mirror.https_failed.lock().unwrap() = Some(Instant::now());
+                }
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    /// layout of the first configured mirror
+    /// the one expected to follow changes of the Gentoo infrastructure first
+    pub async fn primary_layout(&self) -> Result<Layout, String> {
+        self.mirror_base(&self.mirrors[0])
+            .await
+            .map(|(_, layout)| layout)
+    }
+
+    /// check whether all mirrors recently reported file as missing
+    /// expired entries get dropped along the way
+    async fn recently_not_found(&self, file: &str) -> bool {
+        let mut not_found = self.not_found.lock().await;
+        not_found.retain(|_, since| since.elapsed() < self.not_found_ttl);
+        not_found.contains_key(file)
+    }
+
+    /// check whether file is large enough to be fetched in chunks
+    /// returns its Manifest entry if so
+    async fn chunked_candidate(&self, file: &str) -> Option<ManifestEntry> {
+        let min_size = self.chunked.min_size?;
+        if self.mirrors.len() < 2 {
+            return None;
+        }
+
+        match self.repo_db.get_manifest_entry(file).await {
+            Ok(Some(entry)) if entry.size >= min_size => Some(entry),
+            _ => None,
+        }
+    }
+
+    /// fetch different chunks of one file from all mirrors at once
+    /// and verify the result against the Manifest
+    ///
+    /// @param entry  Manifest entry of the distfile
+    /// @param store  BlobStorage use for storing the file
+    async fn fetch_chunked(
+        &self,
+        entry: &ManifestEntry,
+        store: &BlobStorage,
+    ) -> Result<(), String> {
+        let mut urls = Vec::new();
+        for mirror in self.mirrors.iter() {
+            match self.mirror_url(mirror, &entry.file).await {
+                Ok(url) => urls.push(url),
+                Err(e) => req_eprintln!("Ignoring mirror {}: {}", &mirror.url, e),
+            }
+        }
+
+        if urls.len() < 2 {
+            return Err("Not enough usable mirrors".to_string());
+        }
+
+        let path = store.fetch_location(&entry.file).await?;
+        let part = fetch_ranged(
+            &self.client,
+            &urls,
+            entry.size,
+            self.chunked.chunk_size,
+            &path,
+        )
+        .await?;
+
+        // only a verified file gets moved into place
+        let check = verify_manifest_checksum(&part, entry).await;
+        commit_part(&part, &path, check).await
+    }
+}
+
+#[async_trait]
+impl Fetcher for MirrorFetcher {
+    fn name(&self) -> &'static str {
+        "Mirror"
+    }
+
+    /// fetch from Gentoo mirrors
+    /// will try all configured mirrors before failing
+    async fn fetch(&self, file: &str, store: &BlobStorage) -> Result<(), FetchError> {
+        if self.recently_not_found(file).await {
+            return Err(FetchError::new(
+                FetchErrorKind::NotFound,
+                format!("{} was recently not found on any mirror", file),
+            ));
+        }
+
+        // large files get split across all mirrors
+        if let Some(entry) = self.chunked_candidate(file).await {
+            match self.fetch_chunked(&entry, store).await {
+                Ok(_) => return Ok(()),
+                Err(e) => req_eprintln!("Chunked fetch of {} failed: {}", file, e),
+            }
+        }
+
+        let mut errors = Vec::new();
+        for _ in 0..self.mirrors.len() {
+            // select mirror
+            let mirror = self.select_mirror().await;
+
+            let full_url = match self.mirror_url(mirror, file).await {
+                Ok(url) => url,
+                Err(e) => {
+                    req_eprintln!("Ignoring mirror {}: {}", &mirror.url, e);
+                    errors.push(FetchError::new(FetchErrorKind::Transient, e));
+                    continue;
+                }
+            };
+
+            match self
+                .retry
+                .run(&full_url, &self.log, || {
+                    fetch_url(&self.client, &full_url, file, store)
+                })
+                .await
+            {
+                // only Ok when entire pipeline was success
+                Ok(_) => return Ok(()),
+                // local errors won't get better on another mirror
+                Err(e) if e.kind == FetchErrorKind::Other => return Err(e),
+                Err(e) => {
+                    self.log.error(&format!("mirror {}", mirror.url), &e);
+                    errors.push(e);
+                }
+            }
+        }
+
+        let error = FetchError::combine(
+            &errors,
+            format!("Couldn't fetch {} from any configured mirror", file),
+        );
+
+        if error.kind == FetchErrorKind::NotFound {
+            self.not_found
+                .lock()
+                .await
+                .insert(file.to_string(), Instant::now());
+        }
+
+        Err(error)
+    }
+}
+
+/// warn about password files other users can read
+fn warn_if_readable(password_file: Option<&Path>) {
+    let Some(path) = password_file else {
+        return;
+    };
+    if let Ok(metadata) = std::fs::metadata(path)
+        && metadata.permissions().mode() & 0o077 != 0
+    {
+        req_eprintln!(
+            "WARNING: password_file {} is accessible by other users, consider chmod 600",
+            path.to_string_lossy()
+        );
+    }
+}
+
+/// get the mirror layout from its layout.conf
+async fn mirror_layout(client: &tls::Client, url: &str) -> Result<Layout, String> {
+    let url = format!("{}/{}", url, "distfiles/layout.conf");
+    let layout = match client.send(client.get(&url)).await {
+        Ok(res) => match res.text().await {
+            Ok(text) => text,
+            Err(e) => return Err(e.to_string()),
+        },
+        Err(e) => return Err(e.to_string()),
+    };
+
+    Layout::parse(&layout).ok_or_else(|| format!("Unknown layout in layout.conf: {}", layout))
+}
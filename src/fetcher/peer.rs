@@ -0,0 +1,74 @@
+use async_trait::async_trait;
+
+use crate::blob_storage::BlobStorage;
+use crate::config;
+use crate::distfile_name;
+use crate::fetcher::tls;
+use crate::fetcher::{FetchError, Fetcher, fetch_url};
+use crate::request_id::req_eprintln;
+use crate::utils;
+
+/// fetch from other portcache instances
+/// peers always use the same layout as we do so no layout.conf lookup is needed
+pub struct PeerFetcher {
+    /// sanitized urls of the peers
+    peers: Vec<String>,
+
+    /// client used for all requests
+    client: tls::Client,
+}
+
+impl PeerFetcher {
+    /// create a new PeerFetcher
+    pub fn new(config: &config::Config) -> Result<Self, String> {
+        let peers: Vec<String> = config
+            .fetcher
+            .peers
+            .iter()
+            .map(|url| String::from(url.trim_end_matches("/")))
+            .collect();
+
+        if peers.is_empty() {
+            return Err("Peer list is empty".to_string());
+        }
+
+        Ok(Self {
+            peers,
+            client: tls::Client::new(&config.fetcher.tls)?,
+        })
+    }
+}
+
+#[async_trait]
+impl Fetcher for PeerFetcher {
+    fn name(&self) -> &'static str {
+        "Peer"
+    }
+
+    /// fetch from peers in configured order
+    async fn fetch(&self, file: &str, store: &BlobStorage) -> Result<(), FetchError> {
+        let digest = utils::filename_hash_dir_blake2b(file).map_err(|e| e.to_string())?;
+
+        let mut errors = Vec::new();
+        for peer in self.peers.iter() {
+            let full_url = format!(
+                "{}/distfiles/{}/{}",
+                peer,
+                digest,
+                distfile_name::encode(file)
+            );
+            match fetch_url(&self.client, &full_url, file, store).await {
+                Ok(_) => return Ok(()),
+                Err(e) => {
+                    req_eprintln!("{}", e);
+                    errors.push(e);
+                }
+            }
+        }
+
+        Err(FetchError::combine(
+            &errors,
+            format!("Couldn't fetch {} from any configured peer", file),
+        ))
+    }
+}
@@ -0,0 +1,65 @@
+use async_trait::async_trait;
+
+use crate::blob_storage::BlobStorage;
+use crate::config;
+use crate::distfile_name;
+use crate::fetcher::tls;
+use crate::fetcher::{FetchError, Fetcher, fetch_url};
+use crate::request_id::req_eprintln;
+
+/// fetch from pass-through upstreams which serve files by plain name
+pub struct ProxyFetcher {
+    /// sanitized urls of the upstreams
+    upstreams: Vec<String>,
+
+    /// client used for all requests
+    client: tls::Client,
+}
+
+impl ProxyFetcher {
+    /// create a new ProxyFetcher
+    pub fn new(config: &config::Config) -> Result<Self, String> {
+        let upstreams: Vec<String> = config
+            .fetcher
+            .proxies
+            .iter()
+            .map(|url| String::from(url.trim_end_matches("/")))
+            .collect();
+
+        if upstreams.is_empty() {
+            return Err("Proxy list is empty".to_string());
+        }
+
+        Ok(Self {
+            upstreams,
+            client: tls::Client::new(&config.fetcher.tls)?,
+        })
+    }
+}
+
+#[async_trait]
+impl Fetcher for ProxyFetcher {
+    fn name(&self) -> &'static str {
+        "Proxy"
+    }
+
+    /// fetch from upstreams in configured order
+    async fn fetch(&self, file: &str, store: &BlobStorage) -> Result<(), FetchError> {
+        let mut errors = Vec::new();
+        for upstream in self.upstreams.iter() {
+            let full_url = format!("{}/{}", upstream, distfile_name::encode(file));
+            match fetch_url(&self.client, &full_url, file, store).await {
+                Ok(_) => return Ok(()),
+                Err(e) => {
+                    req_eprintln!("{}", e);
+                    errors.push(e);
+                }
+            }
+        }
+
+        Err(FetchError::combine(
+            &errors,
+            format!("Couldn't fetch {} from any configured proxy", file),
+        ))
+    }
+}
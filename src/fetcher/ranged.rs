@@ -0,0 +1,199 @@
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::fs;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+use crate::fetcher::{part_location, tls};
+use crate::request_id::{req_eprintln, req_println};
+use crate::utils;
+
+/// download a file of known size by splitting it into byte ranges
+/// which get fetched from all sources in parallel
+/// the ranges are written to <path>.part so no reader sees the file half done,
+/// its location is returned so callers can verify it before moving it into place
+/// the partial download is removed again on failure
+///
+/// @param client      reqwest Client to use
+/// @param urls        sources which all serve the same file
+/// @param size        expected file size in bytes
+/// @param chunk_size  size of each range request in bytes
+/// @param path        final location of the file
+pub async fn fetch_ranged(
+    client: &tls::Client,
+    urls: &[String],
+    size: u64,
+    chunk_size: u64,
+    path: &Path,
+) -> Result<PathBuf, String> {
+    if urls.is_empty() {
+        return Err("No sources to fetch from".to_string());
+    }
+
+    // create dir for this blob if needed
+    utils::create_parent_dir(path)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let part = part_location(path);
+    match download_ranges(client, urls, size, chunk_size, &part).await {
+        Ok(_) => Ok(part),
+        Err(e) => {
+            let _ = fs::remove_file(&part).await;
+            Err(e)
+        }
+    }
+}
+
+/// move a downloaded part file into place once check passed
+/// the part file is removed if the check or the move fails
+///
+/// @param part   location of the partial download
+/// @param path   final location of the file
+/// @param check  outcome of verifying the part file
+pub async fn commit_part(
+    part: &Path,
+    path: &Path,
+    check: Result<(), String>,
+) -> Result<(), String> {
+    let result = match check {
+        Ok(_) => fs::rename(part, path).await.map_err(|e| e.to_string()),
+        Err(e) => Err(e),
+    };
+    if result.is_err() {
+        let _ = fs::remove_file(part).await;
+    }
+    result
+}
+
+/// fetch all ranges of the file into part
+///
+/// @param client      reqwest Client to use
+/// @param urls        sources which all serve the same file
+/// @param size        expected file size in bytes
+/// @param chunk_size  size of each range request in bytes
+/// @param part        where to write the ranges
+async fn download_ranges(
+    client: &tls::Client,
+    urls: &[String],
+    size: u64,
+    chunk_size: u64,
+    part: &Path,
+) -> Result<(), String> {
+    // preallocate so every range can be written in place
+    let file = fs::File::create(part).await.map_err(|e| e.to_string())?;
+    file.set_len(size).await.map_err(|e| e.to_string())?;
+    drop(file);
+
+    let chunk_size = chunk_size.max(1);
+    let ranges = (0..size.div_ceil(chunk_size)).map(|i| {
+        let start = i * chunk_size;
+        (i as usize, start, (start + chunk_size).min(size) - 1)
+    });
+
+    req_println!(
+        "Fetching {} in {} byte ranges from {} sources",
+        part.to_string_lossy(),
+        chunk_size,
+        urls.len()
+    );
+
+    let modified = stream::iter(ranges)
+        .map(|(index, start, end)| fetch_range(client, urls, index, start, end, part))
+        .buffer_unordered(urls.len())
+        .try_collect::<Vec<Option<SystemTime>>>()
+        .await?
+        .into_iter()
+        .flatten()
+        .min();
+
+    // served back as Last-Modified like a real mirror would
+    if let Some(modified) = modified
+        && let Err(e) = utils::set_mtime(part, modified)
+    {
+        req_eprintln!("Failed to set mtime of {}: {}", part.to_string_lossy(), e);
+    }
+
+    Ok(())
+}
+
+/// fetch a single range
+/// ranges are spread across sources and fall back to the others on error
+/// returns the Last-Modified time of the source
+async fn fetch_range(
+    client: &tls::Client,
+    urls: &[String],
+    index: usize,
+    start: u64,
+    end: u64,
+    path: &Path,
+) -> Result<Option<SystemTime>, String> {
+    for attempt in 0..urls.len() {
+        let url = &urls[(index + attempt) % urls.len()];
+        match fetch_range_from(client, url, start, end, path).await {
+            Ok(modified) => return Ok(modified),
+            Err(e) => req_eprintln!("Range {}-{} from {} failed: {}", start, end, url, e),
+        }
+    }
+
+    Err(format!(
+        "Couldn't fetch range {}-{} from any source",
+        start, end
+    ))
+}
+
+/// fetch bytes start..=end from url and write them at the same offset in path
+/// returns the Last-Modified time of the source
+async fn fetch_range_from(
+    client: &tls::Client,
+    url: &str,
+    start: u64,
+    end: u64,
+    path: &Path,
+) -> Result<Option<SystemTime>, String> {
+    let response = client
+        .send(
+            client
+                .get(url)
+                .header(reqwest::header::RANGE, format!("bytes={}-{}", start, end)),
+        )
+        .await
+        .map_err(|e| e.to_string())?
+        .error_for_status()
+        .map_err(|e| e.to_string())?;
+
+    if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+        return Err("Server doesn't support range requests".to_string());
+    }
+    let modified = utils::last_modified(response.headers());
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .open(path)
+        .await
+        .map_err(|e| e.to_string())?;
+    file.seek(SeekFrom::Start(start))
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let expected = end - start + 1;
+    let mut written: u64 = 0;
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| e.to_string())?;
+        written += chunk.len() as u64;
+        if written > expected {
+            return Err("Server sent more data than requested".to_string());
+        }
+        file.write_all(&chunk).await.map_err(|e| e.to_string())?;
+    }
+
+    file.flush().await.map_err(|e| e.to_string())?;
+
+    if written != expected {
+        return Err(format!("Expected {} bytes, got {}", expected, written));
+    }
+
+    Ok(modified)
+}
@@ -0,0 +1,83 @@
+use std::future::Future;
+use tokio::time::{self, Duration};
+
+use crate::config;
+use crate::fetcher::FetchError;
+use crate::log_limiter::LogLimiter;
+
+/// exponential backoff with full jitter for retrying transient failures
+pub struct RetryPolicy {
+    /// total attempts including the first one
+    max_attempts: u32,
+
+    /// backoff before the first retry
+    initial_backoff: Duration,
+
+    /// upper bound for the backoff
+    max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// create a RetryPolicy from config
+    pub fn new(config: &config::RetryConfig) -> Self {
+        Self {
+            max_attempts: config.max_attempts.max(1),
+            initial_backoff: config.initial_backoff,
+            max_backoff: config.max_backoff,
+        }
+    }
+
+    /// backoff before retry number `retry` (starting at 0)
+    /// picks a random delay up to the exponential bound
+    fn backoff(&self, retry: u32) -> Duration {
+        let bound = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff);
+        let millis = u64::try_from(bound.as_millis()).unwrap_or(u64::MAX);
+        Duration::from_millis(fastrand::u64(0..=millis))
+    }
+
+    /// run an attempt until it succeeds, fails permanently
+    /// or the attempts are used up
+    ///
+    /// @param what     url of the attempt used in logs
+    /// @param log      limiter retry messages get aggregated by host in
+    /// @param attempt  closure starting a new attempt
+    pub async fn run<F, Fut>(
+        &self,
+        what: &str,
+        log: &LogLimiter,
+        mut attempt: F,
+    ) -> Result<(), FetchError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<(), FetchError>>,
+    {
+        let mut retry = 0;
+        loop {
+            match attempt().await {
+                Ok(_) => return Ok(()),
+                Err(e) if e.is_retryable() && retry + 1 < self.max_attempts => {
+                    let backoff = self.backoff(retry);
+                    let host = reqwest::Url::parse(what)
+                        .ok()
+                        .and_then(|url| url.host_str().map(|host| host.to_string()))
+                        .unwrap_or_else(|| what.to_string());
+                    log.error(
+                        &format!("retry against {}", host),
+                        format!(
+                            "{} failed ({}), retrying in {}ms",
+                            what,
+                            e,
+                            backoff.as_millis()
+                        ),
+                    );
+                    time::sleep(backoff).await;
+                    retry += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use async_trait::async_trait;
+use cookie::Cookie;
+use reqwest::Url;
+use reqwest::header::{COOKIE, HeaderMap, HeaderName, HeaderValue, LOCATION, SET_COOKIE};
+use reqwest::redirect::Policy;
+use std::sync::Arc;
+
+use crate::blob_storage::BlobStorage;
+use crate::config;
+use crate::fetcher::breaker::HostBreaker;
+use crate::fetcher::tls::{self, domain_matches};
+use crate::fetcher::{FetchError, FetchErrorKind, Fetcher, store_response};
+use crate::repo_db::RepoDB;
+use crate::request_id::{req_eprintln, req_println};
+
+/// fetch from the SRC_URIs recorded in the repo database
+pub struct SrcUriFetcher {
+    /// repo database
+    repo_db: Arc<RepoDB>,
+
+    /// client which leaves redirects to us
+    client: tls::Client,
+
+    /// redirects followed per SRC_URI
+    max_redirects: usize,
+
+    /// whether cookies set along redirects get sent back
+    cookies: bool,
+
+    /// extra request headers per domain
+    headers: Vec<(String, HeaderMap)>,
+
+    /// skips hosts failing over and over
+    breaker: HostBreaker,
+}
+
+impl SrcUriFetcher {
+    /// create a new SrcUriFetcher
+    pub fn new(config: &config::Config, repo_db: Arc<RepoDB>) -> Result<Self, String> {
+        let src_uri = &config.fetcher.src_uri;
+        let client = tls::Client::with(&config.fetcher.tls, || {
+            reqwest::Client::builder().redirect(Policy::none())
+        })
+        .map_err(|e| format!("Failed to build SRC_URI client: {}", e))?;
+
+        let mut headers = Vec::new();
+        for (domain, overrides) in &src_uri.headers {
+            let mut map = HeaderMap::new();
+            for (name, value) in overrides {
+                let name = HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|e| format!("Bad header {} for {}: {}", name, domain, e))?;
+                let value = HeaderValue::from_str(value)
+                    .map_err(|e| format!("Bad value of {} for {}: {}", name, domain, e))?;
+                map.insert(name, value);
+            }
+            headers.push((domain.trim_start_matches('.').to_lowercase(), map));
+        }
+
+        Ok(Self {
+            repo_db,
+            client,
+            max_redirects: src_uri.max_redirects,
+            cookies: src_uri.cookies,
+            headers,
+            breaker: HostBreaker::new(src_uri),
+        })
+    }
+
+    /// GET uri following redirects with a fresh cookie jar
+    /// returns the first response which isn't a redirect
+    ///
+    /// @param uri  SRC_URI to fetch
+    async fn get(&self, uri: &str) -> Result<reqwest::Response, FetchError> {
+        let mut url = Url::parse(uri).map_err(|e| {
+            FetchError::new(
+                FetchErrorKind::Rejected,
+                format!("Bad SRC_URI {}: {}", uri, e),
+            )
+        })?;
+        let mut jar = CookieJar::default();
+
+        for _ in 0..=self.max_redirects {
+            // also keeps redirects from downgrading to plain HTTP
+            if !self.client.http_policy().allows(&url) {
+                return Err(FetchError::new(
+                    FetchErrorKind::Rejected,
+                    format!(
+                        "Refusing to fetch {} over plain HTTP (see fetcher.tls.allow_http)",
+                        url
+                    ),
+                ));
+            }
+
+            let mut request = self.client.get(url.as_str());
+            if self.cookies
+                && let Some(cookies) = jar.header(&url)
+            {
+                request = request.header(COOKIE, cookies);
+            }
+            let host = url.host_str().unwrap_or_default();
+            for (_, headers) in self
+                .headers
+                .iter()
+                .filter(|(domain, _)| domain_matches(host, domain))
+            {
+                request = request.headers(headers.clone());
+            }
+
+            let response = self.client.send(request).await?;
+            if self.cookies {
+                jar.store(&url, response.headers());
+            }
+            if !response.status().is_redirection() {
+                return Ok(response);
+            }
+
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .ok_or_else(|| {
+                    FetchError::new(
+                        FetchErrorKind::Rejected,
+                        format!("Redirect from {} without Location", url),
+                    )
+                })?;
+            url = url.join(location).map_err(|e| {
+                FetchError::new(
+                    FetchErrorKind::Rejected,
+                    format!("Bad redirect from {} to {}: {}", url, location, e),
+                )
+            })?;
+            req_println!("Following redirect to {}", url);
+        }
+
+        Err(FetchError::new(
+            FetchErrorKind::Rejected,
+            format!("Too many redirects fetching {}", uri),
+        ))
+    }
+}
+
+#[async_trait]
+impl Fetcher for SrcUriFetcher {
+    fn name(&self) -> &'static str {
+        "SRC_URI"
+    }
+
+    /// fetch from SRC_URI
+    /// will try all known uris before failing
+    async fn fetch(&self, file: &str, store: &BlobStorage) -> Result<(), FetchError> {
+        let uris = self
+            .repo_db
+            .get_src_uri(file)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let policy = self.client.http_policy();
+        let mut errors = Vec::new();
+        for uri in uris {
+            // http:// SRC_URIs may get tried as https:// first
+            let candidates = policy.candidates(&uri, policy.upgrade_http());
+            if candidates.is_empty() {
+                let e = FetchError::new(
+                    FetchErrorKind::Rejected,
+                    format!(
+                        "Refusing plain HTTP SRC_URI {} (see fetcher.tls.allow_http)",
+                        uri
+                    ),
+                );
+                req_eprintln!("{}", e);
+                errors.push(e);
+                continue;
+            }
+
+            for candidate in candidates {
+                let host = breaker_key(&candidate);
+                if let Err(remaining) = self.breaker.check(&host) {
+                    let e = FetchError::new(
+                        FetchErrorKind::Transient,
+                        format!(
+                            "Skipping {}: {} keeps failing, next try in {}s",
+                            candidate,
+                            host,
+                            remaining.as_secs()
+                        ),
+                    );
+                    req_eprintln!("{}", e);
+                    errors.push(e);
+                    continue;
+                }
+
+                req_println!("Fetching {}", candidate);
+                let result = match self.get(&candidate).await {
+                    Ok(response) => store_response(&candidate, file, store, response).await,
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok(_) => {
+                        self.breaker.success(&host);
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        match e.kind {
+                            FetchErrorKind::Transient => self.breaker.failure(&host),
+                            FetchErrorKind::NotFound | FetchErrorKind::Rejected => {
+                                self.breaker.success(&host)
+                            }
+                            FetchErrorKind::Other => (),
+                        }
+                        req_eprintln!("{}", e);
+                        errors.push(e);
+                    }
+                }
+            }
+        }
+
+        Err(FetchError::combine(
+            &errors,
+            format!("Couldn't fetch {} from any known SRC_URI", file),
+        ))
+    }
+}
+
+/// host and explicit port of a url the circuit breaker tracks it by
+fn breaker_key(uri: &str) -> String {
+    let Ok(url) = Url::parse(uri) else {
+        return String::new();
+    };
+    let host = url.host_str().unwrap_or_default().to_lowercase();
+    match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host,
+    }
+}
+
+/// cookies collected while following the redirects of a single SRC_URI
+#[derive(Default)]
+struct CookieJar {
+    /// cookies with the domain they were set for
+    /// and whether they are only sent to exactly that host
+    cookies: Vec<(String, bool, Cookie<'static>)>,
+}
+
+impl CookieJar {
+    /// keep the cookies a response to url sets
+    fn store(&mut self, url: &Url, headers: &HeaderMap) {
+        let host = url.host_str().unwrap_or_default().to_lowercase();
+        for value in headers.get_all(SET_COOKIE) {
+            let cookie = match value.to_str().map(|value| Cookie::parse(value.to_string())) {
+                Ok(Ok(cookie)) => cookie,
+                _ => continue,
+            };
+
+            let (domain, host_only) = match cookie.domain() {
+                Some(domain) => (domain.to_lowercase(), false),
+                None => (host.clone(), true),
+            };
+            // don't let hosts set cookies for foreign domains
+            if !domain_matches(&host, &domain) {
+                continue;
+            }
+
+            self.cookies.retain(|(known, _, known_cookie)| {
+                known != &domain || known_cookie.name() != cookie.name()
+            });
+            let expired = cookie
+                .max_age()
+                .is_some_and(|max_age| max_age.whole_seconds() <= 0);
+            if !expired {
+                self.cookies.push((domain, host_only, cookie));
+            }
+        }
+    }
+
+    /// value of the Cookie header for a request to url
+    fn header(&self, url: &Url) -> Option<String> {
+        let host = url.host_str().unwrap_or_default().to_lowercase();
+        let cookies: Vec<String> = self
+            .cookies
+            .iter()
+            .filter(|(domain, host_only, cookie)| {
+                let domain_ok = match host_only {
+                    true => &host == domain,
+                    false => domain_matches(&host, domain),
+                };
+                domain_ok
+                    && url.path().starts_with(cookie.path().unwrap_or("/"))
+                    && (!cookie.secure().unwrap_or(false) || url.scheme() == "https")
+            })
+            .map(|(_, _, cookie)| format!("{}={}", cookie.name(), cookie.value()))
+            .collect();
+
+        (!cookies.is_empty()).then(|| cookies.join("; "))
+    }
+}
@@ -0,0 +1,315 @@
+use reqwest::tls::TlsInfo;
+use reqwest::{Certificate, ClientBuilder, RequestBuilder, Response, Url};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::config::{Credentials, TlsConfig};
+use crate::fetcher::{FetchError, FetchErrorKind};
+use crate::telemetry;
+
+/// parse a SHA-256 certificate fingerprint
+/// accepts plain hex as well as openssl's colon separated form
+/// returns it as lowercase hex
+///
+/// @param fingerprint  the fingerprint as configured
+pub fn parse_fingerprint(fingerprint: &str) -> Option<String> {
+    let hex: String = fingerprint
+        .trim()
+        .to_lowercase()
+        .trim_start_matches("sha256 fingerprint=")
+        .chars()
+        .filter(|c| *c != ':')
+        .collect();
+
+    (hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit())).then_some(hex)
+}
+
+/// SHA-256 fingerprint of a DER encoded certificate as lowercase hex
+///
+/// @param der  the certificate
+pub fn fingerprint(der: &[u8]) -> String {
+    hex::encode(Sha256::digest(der))
+}
+
+/// which upstream urls may be requested over plain HTTP
+/// and whether to try HTTPS first, from [fetcher.tls]
+pub struct HttpPolicy {
+    /// whether plain http:// is allowed for every host
+    allow_http: bool,
+
+    /// whether http:// urls get tried as https:// first by default
+    upgrade_http: bool,
+
+    /// domains allowed over plain http:// regardless of allow_http
+    http_hosts: Vec<String>,
+}
+
+impl HttpPolicy {
+    /// create a HttpPolicy from config
+    ///
+    /// @param config  the [fetcher.tls] settings
+    pub fn new(config: &TlsConfig) -> Self {
+        Self {
+            allow_http: config.allow_http,
+            upgrade_http: config.upgrade_http,
+            http_hosts: config
+                .http_hosts
+                .iter()
+                .map(|host| host.trim_start_matches('.').to_lowercase())
+                .collect(),
+        }
+    }
+
+    /// whether http:// urls get tried as https:// first unless overridden
+    pub fn upgrade_http(&self) -> bool {
+        self.upgrade_http
+    }
+
+    /// whether url may be requested
+    /// anything but plain http:// always may
+    ///
+    /// @param url  the url to request
+    pub fn allows(&self, url: &Url) -> bool {
+        url.scheme() != "http"
+            || self.allow_http
+            || url.host_str().is_some_and(|host| {
+                self.http_hosts
+                    .iter()
+                    .any(|domain| domain_matches(host, domain))
+            })
+    }
+
+    /// the urls to try for url in order
+    /// http:// urls come with their https:// variant first when upgrading
+    /// and are dropped if plain http:// isn't allowed for their host
+    /// empty if url can't be requested at all
+    ///
+    /// @param url      the url as configured or recorded
+    /// @param upgrade  whether to try https:// first
+    pub fn candidates(&self, url: &str, upgrade: bool) -> Vec<String> {
+        let parsed = match Url::parse(url) {
+            Ok(parsed) if parsed.scheme() == "http" => parsed,
+            _ => return vec![url.to_string()],
+        };
+
+        let mut candidates = Vec::new();
+        let mut https = parsed.clone();
+        if upgrade && https.set_scheme("https").is_ok() {
+            candidates.push(https.to_string());
+        }
+        if self.allows(&parsed) {
+            candidates.push(url.to_string());
+        }
+        candidates
+    }
+}
+
+/// whether host is domain or one of its subdomains
+pub(crate) fn domain_matches(host: &str, domain: &str) -> bool {
+    host.eq_ignore_ascii_case(domain)
+        || host
+            .to_lowercase()
+            .strip_suffix(&domain.to_lowercase())
+            .is_some_and(|rest| rest.ends_with('.'))
+}
+
+/// reqwest client for upstream requests applying [fetcher.tls]
+/// and the credentials of authenticated mirrors
+/// cheap to clone, clones share their connection pools
+#[derive(Clone)]
+pub struct Client {
+    /// client verifying certificates against the system and custom CAs
+    verified: reqwest::Client,
+
+    /// client skipping verification, only built with insecure_hosts set
+    insecure: Option<reqwest::Client>,
+
+    /// hosts requests go through the insecure client for
+    insecure_hosts: Arc<Vec<String>>,
+
+    /// allowed certificate fingerprints per host
+    pins: Arc<HashMap<String, Vec<String>>>,
+
+    /// credentials sent to urls below each prefix
+    credentials: Arc<Vec<(String, Credentials)>>,
+
+    /// which urls may be requested over plain HTTP
+    http_policy: Arc<HttpPolicy>,
+}
+
+impl Client {
+    /// create a Client with reqwest's default settings
+    ///
+    /// @param config  the [fetcher.tls] settings
+    pub fn new(config: &TlsConfig) -> Result<Self, String> {
+        Self::with(config, reqwest::Client::builder)
+    }
+
+    /// create a Client from builders configured by the caller
+    ///
+    /// @param config   the [fetcher.tls] settings
+    /// @param builder  creates the builder of each underlying client
+    pub fn with(config: &TlsConfig, builder: impl Fn() -> ClientBuilder) -> Result<Self, String> {
+        let roots = match &config.ca_bundle {
+            Some(path) => {
+                let pem = std::fs::read(path)
+                    .map_err(|e| format!("Cannot read {}: {}", path.to_string_lossy(), e))?;
+                Certificate::from_pem_bundle(&pem)
+                    .map_err(|e| format!("Bad CA bundle {}: {}", path.to_string_lossy(), e))?
+            }
+            None => Vec::new(),
+        };
+
+        let mut pins = HashMap::new();
+        for (host, fingerprints) in &config.pins {
+            let fingerprints = fingerprints
+                .iter()
+                .map(|pin| {
+                    parse_fingerprint(pin)
+                        .ok_or_else(|| format!("Bad certificate pin {} for {}", pin, host))
+                })
+                .collect::<Result<Vec<String>, String>>()?;
+            pins.insert(host.to_lowercase(), fingerprints);
+        }
+
+        let build = |insecure: bool| {
+            let mut builder = builder()
+                .tls_info(!pins.is_empty())
+                .danger_accept_invalid_certs(insecure)
+                .danger_accept_invalid_hostnames(insecure);
+            for root in &roots {
+                builder = builder.add_root_certificate(root.clone());
+            }
+            builder
+                .build()
+                .map_err(|e| format!("Failed to build upstream client: {}", e))
+        };
+
+        let insecure = match config.insecure_hosts.is_empty() {
+            true => None,
+            false => Some(build(true)?),
+        };
+
+        Ok(Self {
+            verified: build(false)?,
+            insecure,
+            insecure_hosts: Arc::new(
+                config
+                    .insecure_hosts
+                    .iter()
+                    .map(|host| host.to_lowercase())
+                    .collect(),
+            ),
+            pins: Arc::new(pins),
+            credentials: Arc::new(Vec::new()),
+            http_policy: Arc::new(HttpPolicy::new(config)),
+        })
+    }
+
+    /// send credentials with every request below a url
+    /// e.g. all files of an authenticated mirror
+    ///
+    /// @param prefix       url the credentials apply to
+    /// @param credentials  the credentials sent via basic auth
+    pub fn authenticate(mut self, prefix: &str, credentials: Credentials) -> Self {
+        let prefix = format!("{}/", prefix.trim_end_matches('/'));
+        Arc::make_mut(&mut self.credentials).push((prefix, credentials));
+        self
+    }
+
+    /// which urls may be requested over plain HTTP
+    pub fn http_policy(&self) -> &HttpPolicy {
+        &self.http_policy
+    }
+
+    /// the underlying client used for requests to url
+    fn client(&self, url: &str) -> &reqwest::Client {
+        let host = Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_lowercase));
+        match (&self.insecure, host) {
+            (Some(insecure), Some(host)) if self.insecure_hosts.contains(&host) => insecure,
+            _ => &self.verified,
+        }
+    }
+
+    /// start a GET request to url
+    pub fn get(&self, url: &str) -> RequestBuilder {
+        self.with_credentials(url, self.client(url).get(url))
+    }
+
+    /// start a POST request to url
+    pub fn post(&self, url: &str) -> RequestBuilder {
+        self.with_credentials(url, self.client(url).post(url))
+    }
+
+    /// add the credentials configured for url to request
+    fn with_credentials(&self, url: &str, request: RequestBuilder) -> RequestBuilder {
+        match self
+            .credentials
+            .iter()
+            .find(|(prefix, _)| url.starts_with(prefix))
+        {
+            Some((_, credentials)) => {
+                request.basic_auth(&credentials.username, credentials.password.as_ref())
+            }
+            None => request,
+        }
+    }
+
+    /// send a request built by this client
+    /// and reject the response if its host is pinned to other certificates
+    /// or it was served over plain HTTP against fetcher.tls.allow_http
+    ///
+    /// @param request  the request to send
+    pub async fn send(&self, request: RequestBuilder) -> Result<Response, FetchError> {
+        let response = telemetry::send(request)
+            .await
+            .map_err(|e| FetchError::from_reqwest(&e))?;
+        if !self.http_policy.allows(response.url()) {
+            return Err(FetchError::new(
+                FetchErrorKind::Rejected,
+                format!(
+                    "{} was served over plain HTTP which fetcher.tls.allow_http forbids",
+                    response.url()
+                ),
+            ));
+        }
+        self.check_pin(&response)?;
+        Ok(response)
+    }
+
+    /// check the certificate a response was served with against the pins of its host
+    fn check_pin(&self, response: &Response) -> Result<(), FetchError> {
+        let host = response.url().host_str().unwrap_or_default().to_lowercase();
+        let pins = match self.pins.get(&host) {
+            Some(pins) => pins,
+            None => return Ok(()),
+        };
+
+        let certificate = response
+            .extensions()
+            .get::<TlsInfo>()
+            .and_then(|info| info.peer_certificate())
+            .map(fingerprint);
+        match certificate {
+            Some(certificate) if pins.contains(&certificate) => Ok(()),
+            Some(certificate) => Err(FetchError::new(
+                FetchErrorKind::Rejected,
+                format!(
+                    "{} presented certificate {} which isn't pinned in fetcher.tls.pins",
+                    host, certificate
+                ),
+            )),
+            None => Err(FetchError::new(
+                FetchErrorKind::Rejected,
+                format!(
+                    "{} is pinned in fetcher.tls.pins but {} wasn't served over TLS",
+                    host,
+                    response.url()
+                ),
+            )),
+        }
+    }
+}
@@ -1,54 +1,792 @@
-use rocket::http;
-use rocket::response::stream::ReaderStream;
-use rocket::tokio::fs::File;
-use rocket::{State, get};
+use bytes::Bytes;
+use rocket::http::{self, ContentType, Header};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::{self, Redirect, Responder, Response};
+use rocket::tokio::fs::{self, File};
+use rocket::tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncSeek, BufReader, ReadBuf};
+use rocket::{Either, State, get};
+use std::io::{Cursor, SeekFrom};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::SharedData;
+use crate::admin::Admin;
+use crate::api_keys::ClientKey;
+use crate::app::SharedData;
+use crate::blob_storage::QueueBusy;
+use crate::compression::{self, Decompressed};
+use crate::config::FlatLayout;
+use crate::distfile_name::{self, DistfileName, InvalidName};
+use crate::manifest_walker::ManifestEntry;
+use crate::repo_db::{ApiKey, ServedDistfile};
+use crate::request_id::{self, req_eprintln};
+use crate::telemetry::RequestTrace;
 use crate::utils;
 
-/// the layout.conf file indicating how files
-/// are structures in this mirror
-/// for now we just use the filename-hash mode
-/// using the first 2 chars of the hex encoded blake2b521
+/// request guard for conditional requests
+/// holds the If-Modified-Since time the client sent if any
+pub struct IfModifiedSince(Option<SystemTime>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfModifiedSince {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let since = req
+            .headers()
+            .get_one("If-Modified-Since")
+            .and_then(|value| httpdate::parse_http_date(value).ok());
+        Outcome::Success(IfModifiedSince(since))
+    }
+}
+
+/// a file served with its mtime as Last-Modified like a real mirror
+pub enum Served {
+    /// the client's copy is still current
+    NotModified {
+        /// mtime of the file
+        modified: SystemTime,
+
+        /// Cache-Control header
+        cache_control: String,
+    },
+
+    /// the file to stream
+    File {
+        /// content of the file
+        body: Blob,
+
+        /// name the file gets saved as
+        name: String,
+
+        /// size of the file's content
+        size: u64,
+
+        /// size of the zstd stream after the header if it's stored compressed
+        compressed: Option<u64>,
+
+        /// mtime of the file
+        modified: Option<SystemTime>,
+
+        /// X-Checksum-* headers as (hash name, hex digest)
+        checksums: Vec<(&'static str, String)>,
+
+        /// Cache-Control header
+        cache_control: String,
+    },
+}
+
+impl Served {
+    /// let caches in front keep the file for max_age without revalidating
+    /// for distfiles whose content never changes under the same name
+    ///
+    /// @param shared  shared data with the max age
+    pub(crate) fn immutable(&mut self, shared: &SharedData) {
+        if shared.cache_max_age.is_zero() {
+            return;
+        }
+        let immutable = format!(
+            "{}, max-age={}, immutable",
+            cache_visibility(shared),
+            shared.cache_max_age.as_secs()
+        );
+        match self {
+            Served::NotModified { cache_control, .. } | Served::File { cache_control, .. } => {
+                *cache_control = immutable
+            }
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Served {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        match self {
+            Served::NotModified {
+                modified,
+                cache_control,
+            } => Response::build()
+                .status(http::Status::NotModified)
+                .header(Header::new(
+                    "Last-Modified",
+                    httpdate::fmt_http_date(modified),
+                ))
+                .header(Header::new("Cache-Control", cache_control))
+                .ok(),
+            Served::File {
+                body,
+                name,
+                size,
+                compressed,
+                modified,
+                checksums,
+                cache_control,
+            } => {
+                let mut response = Response::build();
+                if let Some(modified) = modified {
+                    response.header(Header::new(
+                        "Last-Modified",
+                        httpdate::fmt_http_date(modified),
+                    ));
+                }
+                for (hash, digest) in checksums {
+                    response.header(Header::new(format!("X-Checksum-{}", hash), digest));
+                }
+                response
+                    .header(content_type(&name))
+                    .header(Header::new(
+                        "Content-Disposition",
+                        content_disposition(&name),
+                    ))
+                    .header(Header::new("Cache-Control", cache_control));
+                match compressed {
+                    Some(compressed) if accepts_zstd(req) => response
+                        .header(Header::new("Content-Encoding", "zstd"))
+                        .header(Header::new("Vary", "Accept-Encoding"))
+                        .sized_body(compressed as usize, body),
+                    Some(_) => response
+                        .header(Header::new("Vary", "Accept-Encoding"))
+                        .sized_body(size as usize, Decompressed::new(body)),
+                    None => response.sized_body(size as usize, body),
+                }
+                .ok()
+            }
+        }
+    }
+}
+
+/// content of a served file
+pub enum Blob {
+    /// streamed from disk
+    File(Box<BufReader<File>>),
+
+    /// kept in the memory cache
+    Memory(Cursor<Bytes>),
+
+    /// streamed from disk via io_uring
+    #[cfg(feature = "uring")]
+    Uring(crate::uring::UringBody),
+}
+
+impl AsyncRead for Blob {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Blob::File(file) => Pin::new(file).poll_read(cx, buf),
+            Blob::Memory(content) => Pin::new(content).poll_read(cx, buf),
+            #[cfg(feature = "uring")]
+            Blob::Uring(body) => Pin::new(body).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncBufRead for Blob {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<&[u8]>> {
+        match self.get_mut() {
+            Blob::File(file) => Pin::new(file).poll_fill_buf(cx),
+            Blob::Memory(content) => Pin::new(content).poll_fill_buf(cx),
+            #[cfg(feature = "uring")]
+            Blob::Uring(body) => Pin::new(body).poll_fill_buf(cx),
+        }
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        match self.get_mut() {
+            Blob::File(file) => Pin::new(file).consume(amt),
+            Blob::Memory(content) => Pin::new(content).consume(amt),
+            #[cfg(feature = "uring")]
+            Blob::Uring(body) => Pin::new(body).consume(amt),
+        }
+    }
+}
+
+impl AsyncSeek for Blob {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        match self.get_mut() {
+            Blob::File(file) => Pin::new(file).start_seek(position),
+            Blob::Memory(content) => Pin::new(content).start_seek(position),
+            #[cfg(feature = "uring")]
+            Blob::Uring(body) => Pin::new(body).start_seek(position),
+        }
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        match self.get_mut() {
+            Blob::File(file) => Pin::new(file).poll_complete(cx),
+            Blob::Memory(content) => Pin::new(content).poll_complete(cx),
+            #[cfg(feature = "uring")]
+            Blob::Uring(body) => Pin::new(body).poll_complete(cx),
+        }
+    }
+}
+
+/// whether the client accepts zstd content encoding
+/// i.e. lists zstd in Accept-Encoding without q=0
+///
+/// @param req  the request
+fn accepts_zstd(req: &Request<'_>) -> bool {
+    req.headers()
+        .get("Accept-Encoding")
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            params
+                .next()
+                .is_some_and(|name| name.eq_ignore_ascii_case("zstd"))
+                && params.all(|param| {
+                    param
+                        .strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .is_none_or(|q| q > 0.0)
+                })
+        })
+}
+
+/// whether shared caches may keep responses
+/// not if they're only served with an API key since a cache would hand them out without one
+///
+/// @param shared  shared data with the API key settings
+fn cache_visibility(shared: &SharedData) -> &'static str {
+    match shared.api_keys_required {
+        true => "private",
+        false => "public",
+    }
+}
+
+/// Content-Type of a served file by its extension
+/// compressed tarballs are sent as what they are on disk without Content-Encoding
+/// so nothing in between unpacks them
+///
+/// @param name  name of the file
+fn content_type(name: &str) -> ContentType {
+    let extension = name.rsplit_once('.').map_or("", |(_, extension)| extension);
+    match extension.to_ascii_lowercase().as_str() {
+        "gz" | "tgz" => ContentType::new("application", "gzip"),
+        "xz" | "txz" => ContentType::new("application", "x-xz"),
+        "bz2" | "tbz2" | "tbz" => ContentType::new("application", "x-bzip2"),
+        "zst" => ContentType::new("application", "zstd"),
+        "lz" => ContentType::new("application", "x-lzip"),
+        "lzma" => ContentType::new("application", "x-lzma"),
+        "tar" => ContentType::new("application", "x-tar"),
+        "zip" => ContentType::ZIP,
+        "7z" => ContentType::new("application", "x-7z-compressed"),
+        "rpm" => ContentType::new("application", "x-rpm"),
+        "deb" => ContentType::new("application", "vnd.debian.binary-package"),
+        "asc" | "sig" => ContentType::new("application", "pgp-signature"),
+        "txt" | "md5sum" | "digests" => ContentType::Plain,
+        _ => ContentType::Binary,
+    }
+}
+
+/// Content-Disposition saving a served file under its own name
+/// names with characters not allowed in a quoted string are sent percent encoded
+///
+/// @param name  name of the file
+fn content_disposition(name: &str) -> String {
+    if name
+        .chars()
+        .all(|c| c.is_ascii_graphic() && c != '"' && c != '\\' || c == ' ')
+    {
+        return format!("attachment; filename=\"{}\"", name);
+    }
+    format!(
+        "attachment; filename*=UTF-8''{}",
+        percent_encoding::utf8_percent_encode(name, percent_encoding::NON_ALPHANUMERIC)
+    )
+}
+
+/// why a distfile isn't served
+pub enum Refused {
+    /// plain error status
+    Status(http::Status),
+
+    /// its fetch is stuck in the queue, the client should come back later
+    Busy(QueueBusy),
+
+    /// the request lacks a valid API key
+    Unauthorized,
+}
+
+impl From<http::Status> for Refused {
+    fn from(status: http::Status) -> Self {
+        Refused::Status(status)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Refused {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        match self {
+            Refused::Status(status) => status.respond_to(req),
+            Refused::Busy(busy) => {
+                let body = format!("{}, retry in {}s\n", busy, busy.retry_after.as_secs());
+                Response::build()
+                    .status(http::Status::ServiceUnavailable)
+                    .header(Header::new(
+                        "Retry-After",
+                        busy.retry_after.as_secs().to_string(),
+                    ))
+                    .header(Header::new("X-Queue-Position", busy.position.to_string()))
+                    .sized_body(body.len(), Cursor::new(body))
+                    .ok()
+            }
+            // wget and curl only send mirror url credentials after a challenge
+            Refused::Unauthorized => Response::build()
+                .status(http::Status::Unauthorized)
+                .header(Header::new("WWW-Authenticate", "Basic realm=\"portcache\""))
+                .ok(),
+        }
+    }
+}
+
+/// serve the layout.conf of the blob storage
+/// so clients compute the same paths the storage uses
+/// flat paths are announced as fallback while server.flat_layout answers them
 #[get("/distfiles/layout.conf")]
-pub(crate) async fn layout_conf() -> &'static str {
-    "[structure]\n0=filename-hash BLAKE2B 8\n"
+pub(crate) async fn layout_conf(shared: &State<SharedData>) -> String {
+    shared
+        .blob_storage
+        .layout_conf(shared.flat_layout != FlatLayout::Disabled)
 }
 
 /// map requests to distfiles
-#[get("/distfiles/<digest>/<file>")]
+/// with refresh=1 and the admin token the cached blob gets refetched and replaced first
+#[get("/distfiles/<digest>/<file>?<refresh>")]
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn distfiles(
     digest: &str,
-    file: &str,
+    file: Result<DistfileName, InvalidName>,
+    refresh: Option<&str>,
+    admin: Option<Admin>,
+    client: Option<IpAddr>,
+    key: ClientKey,
+    since: IfModifiedSince,
+    trace: RequestTrace,
     shared: &State<SharedData>,
-) -> Result<ReaderStream![File], http::Status> {
-    // verify that digest matches file
-    match utils::filename_hash_dir_blake2b(&file.to_string()) {
-        Ok(x) if x == *digest => {}
-        Ok(x) => {
-            eprintln!(
-                "Bad digest for file {}: Expected {}, Got {}",
-                file, x, digest
-            );
-            return Err(http::Status::BadRequest);
+) -> Result<Served, Refused> {
+    let file = validate(file)?;
+    let refresh = refresh_requested(&file, refresh, admin)?;
+
+    // verify that digest matches the decoded file name
+    let expected = shared.blob_storage.hash_dir(file.as_str());
+    if expected != digest {
+        req_eprintln!(
+            "Bad digest for file {}: Expected {}, Got {}",
+            file,
+            expected,
+            digest
+        );
+        return Err(http::Status::BadRequest.into());
+    }
+
+    trace
+        .within(open_blob(&file, refresh, client, key, since, shared))
+        .await
+}
+
+/// map legacy flat requests without hash directory to distfiles
+/// depending on config these get redirected, served or rejected
+#[get("/distfiles/<file>?<refresh>")]
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn distfiles_flat(
+    file: Result<DistfileName, InvalidName>,
+    refresh: Option<&str>,
+    admin: Option<Admin>,
+    client: Option<IpAddr>,
+    key: ClientKey,
+    since: IfModifiedSince,
+    trace: RequestTrace,
+    shared: &State<SharedData>,
+) -> Result<Either<Redirect, Served>, Refused> {
+    if shared.flat_layout == FlatLayout::Disabled {
+        return Err(http::Status::NotFound.into());
+    }
+
+    let file = validate(file)?;
+    let refresh = refresh_requested(&file, refresh, admin)?;
+    match shared.flat_layout {
+        FlatLayout::Disabled => Err(http::Status::NotFound.into()),
+        FlatLayout::Redirect => {
+            let digest = shared.blob_storage.hash_dir(file.as_str());
+            Ok(Either::Left(Redirect::permanent(format!(
+                "/distfiles/{}/{}",
+                digest,
+                distfile_name::encode(file.as_str())
+            ))))
         }
-        Err(_) => {
-            eprintln!("Something went wrong when calculating digest for {}", file);
-            return Err(http::Status::InternalServerError);
+        FlatLayout::Serve => Ok(Either::Right(
+            trace
+                .within(open_blob(&file, refresh, client, key, since, shared))
+                .await?,
+        )),
+    }
+}
+
+/// whether a flag query parameter like refresh=1 is set
+fn is_set(value: Option<&str>) -> bool {
+    matches!(value, Some("" | "1" | "true" | "yes" | "on"))
+}
+
+/// whether a request asks for its distfile to be refetched
+/// only the admin may do so
+///
+/// @param file     requested distfile
+/// @param refresh  value of the refresh query parameter
+/// @param admin    whether the request carries the admin token
+fn refresh_requested(
+    file: &DistfileName,
+    refresh: Option<&str>,
+    admin: Option<Admin>,
+) -> Result<bool, Refused> {
+    match (is_set(refresh), admin) {
+        (false, _) => Ok(false),
+        (true, Some(_)) => Ok(true),
+        (true, None) => {
+            req_eprintln!("Refresh of {} without admin token, rejecting", file);
+            Err(Refused::Unauthorized)
         }
     }
+}
 
-    // file can be anything but not contain a / path seperator
-    // rocket already shouldn't match files with / since that's implies a different route
-    if digest.contains("/") {
-        eprintln!("Received file with bad name: {}", file);
-        return Err(http::Status::BadRequest);
+/// turn a rejected file name into a 400
+fn validate(file: Result<DistfileName, InvalidName>) -> Result<DistfileName, http::Status> {
+    file.map_err(|e| {
+        req_eprintln!("Received file with bad name: {}", e);
+        http::Status::BadRequest
+    })
+}
+
+/// request a blob from storage and open it for serving
+/// its Manifest checksums get sent along so clients can verify it in-flight
+///
+/// @param refresh  refetch a cached blob first, the admin token was checked by the caller
+async fn open_blob(
+    file: &DistfileName,
+    refresh: bool,
+    client: Option<IpAddr>,
+    key: ClientKey,
+    since: IfModifiedSince,
+    shared: &SharedData,
+) -> Result<Served, Refused> {
+    // the admin token isn't an API key, refreshes are only accounted to the subnet
+    let key = match refresh {
+        true => None,
+        false => accounted_key(file.as_str(), key, shared)?,
+    };
+    let key_name = key.as_ref().map(|key| key.name.clone());
+
+    // blobs fetched in read-only mode are removed once opened
+    let transient = std::sync::Mutex::new(None);
+    let served = open_within_quota(file.as_str(), client, key, since, shared, async {
+        let path = match refresh {
+            true => shared.blob_storage.refresh(file).await,
+            false => shared.blob_storage.serve(file).await,
+        };
+        let path = path.map_err(|e| match e.downcast::<QueueBusy>() {
+            Ok(busy) => Refused::Busy(*busy),
+            // the cached blob is still the suspect one
+            Err(_) if refresh => Refused::Status(http::Status::BadGateway),
+            Err(_) => Refused::Status(http::Status::NotFound),
+        })?;
+        if shared.blob_storage.is_transient(&path) {
+            *transient.lock().unwrap() = Some(path.clone());
+        }
+        Ok::<_, Refused>(path)
+    })
+    .await;
+    if let Some(path) = transient.into_inner().unwrap()
+        && let Err(e) = fs::remove_file(&path).await
+    {
+        req_eprintln!("Failed to remove {}: {}", path.to_string_lossy(), e);
+    }
+    let mut served = served?;
+    served.immutable(shared);
+
+    if let Served::File {
+        checksums, size, ..
+    } = &mut served
+    {
+        let entry = shared
+            .repo_db
+            .get_manifest_entry(file.as_str())
+            .await
+            .unwrap_or_else(|e| {
+                req_eprintln!("Failed to look up checksums of {}: {}", file, e);
+                None
+            });
+        if let Some(entry) = &entry {
+            checksums.extend(entry.blake2b.clone().map(|digest| ("Blake2b", digest)));
+            checksums.extend(entry.sha512.clone().map(|digest| ("Sha512", digest)));
+        }
+        if !shared.provenance_retention.is_zero() {
+            log_provenance(file, client, key_name, *size, entry, shared).await;
+        }
     }
 
-    let blob = match shared.blob_storage.request(&file.to_string()).await {
-        Ok(b) => b,
-        Err(_) => return Err(http::Status::NotFound),
+    Ok(served)
+}
+
+/// add a served distfile to the provenance log
+/// failures are only logged, the client gets its file regardless
+///
+/// @param file      name of the distfile
+/// @param client    address of the client if known
+/// @param key_name  name of the API key the client sent
+/// @param bytes     size being served
+/// @param entry     Manifest entry of the distfile
+/// @param shared    shared data holding the repo database
+async fn log_provenance(
+    file: &DistfileName,
+    client: Option<IpAddr>,
+    key_name: Option<String>,
+    bytes: u64,
+    entry: Option<ManifestEntry>,
+    shared: &SharedData,
+) {
+    let upstream = match shared
+        .repo_db
+        .get_blob_sources(&[file.as_str().to_string()])
+        .await
+    {
+        Ok(mut sources) => sources
+            .remove(file.as_str())
+            .and_then(|source| source.source),
+        Err(e) => {
+            req_eprintln!("Failed to look up upstream of {}: {}", file, e);
+            None
+        }
+    };
+
+    // Manifests live at <repo>/<category>/<package>/Manifest
+    let package = entry.as_ref().and_then(|entry| {
+        let dir = entry.origin.parent()?;
+        Some(format!(
+            "{}/{}",
+            dir.parent()?.file_name()?.to_string_lossy(),
+            dir.file_name()?.to_string_lossy()
+        ))
+    });
+    let (blake2b, sha512) = entry
+        .map(|entry| (entry.blake2b, entry.sha512))
+        .unwrap_or_default();
+
+    let served = ServedDistfile {
+        file: file.as_str().to_string(),
+        served: utils::unix_time(),
+        client: client.map(|ip| ip.to_string()),
+        api_key: key_name,
+        bytes,
+        blake2b,
+        sha512,
+        package,
+        upstream,
+        request_id: request_id::current(),
     };
-    let file = File::open(blob).await.map_err(|e| e.to_string()).unwrap();
-    Ok(ReaderStream::one(file))
+    let keep_since = served
+        .served
+        .saturating_sub(shared.provenance_retention.as_secs());
+    if let Err(e) = shared.repo_db.add_served(&served, keep_since).await {
+        req_eprintln!("Failed to log provenance of {}: {}", file, e);
+    }
+}
+
+/// open a file for serving once the client's subnet and API key are within their quota
+/// the served size gets accounted to the subnet and the key
+/// unless the client's copy is current and it only gets a 304
+///
+/// @param name    name of the file used in logs
+/// @param client  address of the client if known
+/// @param key     API key the client sent
+/// @param since   If-Modified-Since of the request
+/// @param shared  shared data holding the quota
+/// @param locate  looks up (and fetches) the file, only awaited within quota
+pub(crate) async fn open_accounted<E: Into<Refused>>(
+    name: &str,
+    client: Option<IpAddr>,
+    key: ClientKey,
+    since: IfModifiedSince,
+    shared: &SharedData,
+    locate: impl Future<Output = Result<PathBuf, E>>,
+) -> Result<Served, Refused> {
+    let key = accounted_key(name, key, shared)?;
+    open_within_quota(name, client, key, since, shared, locate).await
+}
+
+/// the issued key a request gets accounted to
+/// requests without one are rejected if api_keys.required is set
+/// and requests with an unknown key once keys are required or issued
+///
+/// @param name    name of the file used in logs
+/// @param key     API key the client sent
+/// @param shared  shared data holding the key requirement
+fn accounted_key(
+    name: &str,
+    key: ClientKey,
+    shared: &SharedData,
+) -> Result<Option<ApiKey>, Refused> {
+    match key {
+        ClientKey::Valid(key) => Ok(Some(key)),
+        ClientKey::Anonymous if !shared.api_keys_required => Ok(None),
+        ClientKey::Anonymous => {
+            req_eprintln!("No API key given, rejecting {}", name);
+            Err(Refused::Unauthorized)
+        }
+        ClientKey::Invalid => {
+            req_eprintln!("Unknown API key given, rejecting {}", name);
+            Err(Refused::Unauthorized)
+        }
+    }
+}
+
+/// open_accounted() for a request whose key was already checked
+///
+/// @param name    name of the file used in logs
+/// @param client  address of the client if known
+/// @param key     issued key the request gets accounted to
+/// @param since   If-Modified-Since of the request
+/// @param shared  shared data holding the quota
+/// @param locate  looks up (and fetches) the file, only awaited within quota
+async fn open_within_quota<E: Into<Refused>>(
+    name: &str,
+    client: Option<IpAddr>,
+    key: Option<ApiKey>,
+    since: IfModifiedSince,
+    shared: &SharedData,
+    locate: impl Future<Output = Result<PathBuf, E>>,
+) -> Result<Served, Refused> {
+    if let Some(key) = &key
+        && shared.quota.key_exceeded(key).await
+    {
+        req_eprintln!("Quota exceeded for key {}, rejecting {}", key.name, name);
+        return Err(http::Status::TooManyRequests.into());
+    }
+
+    let subnet = client.map(|ip| shared.quota.subnet(ip));
+    if let Some(subnet) = &subnet
+        && shared.quota.exceeded(subnet).await
+    {
+        req_eprintln!("Quota exceeded for {}, rejecting {}", subnet, name);
+        return Err(http::Status::TooManyRequests.into());
+    }
+
+    let path = locate.await.map_err(Into::into)?;
+    let metadata = fs::metadata(&path).await.map_err(|e| {
+        req_eprintln!("Failed to stat {}: {}", name, e);
+        http::Status::InternalServerError
+    })?;
+    let modified = metadata.modified().ok();
+
+    // http dates only have second precision
+    let secs = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or(0)
+    };
+    if let (Some(modified), IfModifiedSince(Some(since))) = (modified, since)
+        && secs(modified) <= secs(since)
+    {
+        return Ok(Served::NotModified {
+            modified,
+            cache_control: format!("{}, no-cache", cache_visibility(shared)),
+        });
+    }
+
+    let (body, stored, original) = open_body(name, &path, metadata, shared).await?;
+    // distfiles stored compressed are accounted by their content
+    let size = original.unwrap_or(stored);
+    if let Some(subnet) = &subnet {
+        shared.quota.record(subnet, size).await;
+    }
+    if let Some(key) = &key {
+        shared.quota.record_key(key, size).await;
+    }
+
+    // releases are served by their path
+    let name = name.rsplit('/').next().unwrap_or(name).to_string();
+    Ok(Served::File {
+        body,
+        name,
+        size,
+        compressed: original.map(|_| stored - compression::HEADER_LEN as u64),
+        modified,
+        checksums: Vec::new(),
+        cache_control: format!("{}, no-cache", cache_visibility(shared)),
+    })
+}
+
+/// open a located file for serving, from the memory cache if it holds the current version
+/// small files read from disk get added to it
+/// returns the body positioned at the content, the size as stored
+/// and the original size if it's stored compressed
+///
+/// @param name      name of the file used in logs
+/// @param path      location of the file
+/// @param metadata  metadata of the file
+/// @param shared    shared data holding the memory cache
+async fn open_body(
+    name: &str,
+    path: &Path,
+    metadata: std::fs::Metadata,
+    shared: &SharedData,
+) -> Result<(Blob, u64, Option<u64>), http::Status> {
+    let failed = |e: std::io::Error| {
+        req_eprintln!("Failed to read {}: {}", name, e);
+        http::Status::InternalServerError
+    };
+
+    let cache = shared
+        .memory_cache
+        .as_ref()
+        .filter(|cache| cache.fits(metadata.len()) && !shared.blob_storage.is_transient(path));
+    if let Some(cache) = cache
+        && let Some(content) = cache.get(path, &metadata)
+    {
+        return Ok(memory_body(content));
+    }
+
+    let mut file = File::open(path).await.map_err(failed)?;
+    if let Some(cache) = cache {
+        // the metadata of what was opened, the file may have been replaced since
+        let metadata = file.metadata().await.map_err(failed)?;
+        if cache.fits(metadata.len()) {
+            let mut content = Vec::with_capacity(metadata.len() as usize);
+            file.read_to_end(&mut content).await.map_err(failed)?;
+            let content = Bytes::from(content);
+            cache.insert(path, &metadata, content.clone());
+            return Ok(memory_body(content));
+        }
+    }
+
+    let stored = file.metadata().await.map_err(failed)?.len();
+    let original = compression::read_header_async(&mut file)
+        .await
+        .map_err(failed)?;
+    #[cfg(feature = "uring")]
+    if let Some(uring) = &shared.uring {
+        let offset = match original {
+            Some(_) => compression::HEADER_LEN as u64,
+            None => 0,
+        };
+        let body = uring.read(file.into_std().await, offset);
+        return Ok((Blob::Uring(body), stored, original));
+    }
+    Ok((Blob::File(Box::new(BufReader::new(file))), stored, original))
+}
+
+/// body of a file held in memory positioned at its content
+///
+/// @param content  the file as stored
+fn memory_body(content: Bytes) -> (Blob, u64, Option<u64>) {
+    let original = content
+        .get(..compression::HEADER_LEN)
+        .and_then(|header| compression::parse_header(header.try_into().unwrap()));
+    let stored = content.len() as u64;
+    let mut body = Cursor::new(content);
+    if original.is_some() {
+        body.set_position(compression::HEADER_LEN as u64);
+    }
+    (Blob::Memory(body), stored, original)
 }
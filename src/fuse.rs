@@ -0,0 +1,739 @@
+use nix::errno::Errno;
+use nix::mount::{MntFlags, MsFlags};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::blob_storage::{BlobStorage, QueueBusy};
+use crate::compression;
+use crate::config::Config;
+use crate::distfile_name::DistfileName;
+
+/// version of the FUSE kernel protocol spoken
+/// kernels speaking an older minor version answer with theirs
+const PROTOCOL_MAJOR: u32 = 7;
+const PROTOCOL_MINOR: u32 = 31;
+
+/// opcodes of the requests answered
+const LOOKUP: u32 = 1;
+const FORGET: u32 = 2;
+const GETATTR: u32 = 3;
+const OPEN: u32 = 14;
+const READ: u32 = 15;
+const STATFS: u32 = 17;
+const RELEASE: u32 = 18;
+const FLUSH: u32 = 25;
+const INIT: u32 = 26;
+const OPENDIR: u32 = 27;
+const READDIR: u32 = 28;
+const RELEASEDIR: u32 = 29;
+const INTERRUPT: u32 = 36;
+const DESTROY: u32 = 38;
+const BATCH_FORGET: u32 = 42;
+
+/// size of fuse_in_header and fuse_out_header
+const IN_HEADER_SIZE: usize = 40;
+const OUT_HEADER_SIZE: usize = 16;
+
+/// large enough for any request of a read-only filesystem
+const BUFFER_SIZE: usize = 1024 * 1024 + 4096;
+
+/// FUSE_ASYNC_READ: reads of the same file may be sent in parallel
+const ASYNC_READ: u32 = 1;
+
+/// how long the kernel may cache names and attributes in seconds
+/// short since stale blobs get replaced by their refetch
+const ATTR_TIMEOUT: u64 = 1;
+
+/// file types of directory entries
+const DT_DIR: u32 = 4;
+const DT_REG: u32 = 8;
+
+/// nodes of the view, laid out like a mirror
+/// /distfiles/layout.conf and /distfiles/<hash>/<file>
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Node {
+    Root,
+    Distfiles,
+    LayoutConf,
+    Bucket(String),
+    Blob(String),
+}
+
+/// inode numbers handed to the kernel
+/// they stay valid for the lifetime of the mount
+struct Inodes {
+    /// node of every inode number
+    nodes: HashMap<u64, Node>,
+
+    /// inode number of every node
+    ids: HashMap<Node, u64>,
+
+    /// next inode number to hand out
+    next: u64,
+}
+
+impl Inodes {
+    fn new() -> Self {
+        let mut inodes = Self {
+            nodes: HashMap::new(),
+            ids: HashMap::new(),
+            next: 1,
+        };
+        // the kernel knows the root as 1
+        inodes.id(Node::Root);
+        inodes
+    }
+
+    /// inode number of a node, handing out a new one on first use
+    fn id(&mut self, node: Node) -> u64 {
+        if let Some(id) = self.ids.get(&node) {
+            return *id;
+        }
+        let id = self.next;
+        self.next += 1;
+        self.nodes.insert(id, node.clone());
+        self.ids.insert(node, id);
+        id
+    }
+}
+
+/// read-only FUSE mount of the cache laid out like a mirror
+/// opening a distfile that isn't cached fetches it first
+/// so NFS exports and file based workflows can use the cache without HTTP
+pub struct FuseMount {
+    /// where the view is mounted
+    mountpoint: PathBuf,
+
+    /// connection to the kernel
+    device: File,
+}
+
+impl FuseMount {
+    /// mount the view from config
+    /// returns None without a [fuse] section
+    /// has to run before the sandbox and dropping privileges since both prevent mounting
+    /// a view left behind by a crashed or replaced instance gets unmounted first
+    ///
+    /// @param config  a reference to Config
+    pub fn mount(config: &Config) -> Result<Option<Self>, String> {
+        let Some(fuse) = &config.fuse else {
+            return Ok(None);
+        };
+        let path = fuse.mountpoint.to_string_lossy();
+
+        if mounted(&fuse.mountpoint) {
+            nix::mount::umount2(&fuse.mountpoint, MntFlags::MNT_DETACH)
+                .map_err(|e| format!("Cannot unmount previous view at {}: {}", path, e))?;
+        }
+
+        let device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/fuse")
+            .map_err(|e| format!("Cannot open /dev/fuse: {}", e))?;
+
+        let mut options = format!(
+            "fd={},rootmode=40000,user_id={},group_id={},default_permissions",
+            device.as_raw_fd(),
+            nix::unistd::getuid(),
+            nix::unistd::getgid()
+        );
+        if fuse.allow_other {
+            options.push_str(",allow_other");
+        }
+        nix::mount::mount(
+            Some("portcache"),
+            &fuse.mountpoint,
+            Some("fuse.portcache"),
+            MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+            Some(options.as_str()),
+        )
+        .map_err(|e| format!("Cannot mount FUSE view at {}: {}", path, e))?;
+
+        Ok(Some(Self {
+            mountpoint: fuse.mountpoint.clone(),
+            device,
+        }))
+    }
+
+    /// where the view is mounted
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    /// answer requests of the kernel on a thread of its own
+    /// requests get handled as tasks on the runtime this is called from
+    /// and consumes the FuseMount
+    ///
+    /// @param blob_storage  storage to serve and fetch into
+    pub fn start(self, blob_storage: Arc<BlobStorage>) {
+        println!(
+            "Serving the cache as FUSE view at {}",
+            self.mountpoint.to_string_lossy()
+        );
+
+        let view = Arc::new(View {
+            blob_storage,
+            device: self.device,
+            inodes: Mutex::new(Inodes::new()),
+            handles: Mutex::new(HashMap::new()),
+            next_handle: AtomicU64::new(1),
+        });
+        let runtime = tokio::runtime::Handle::current();
+        std::thread::spawn(move || view.receive(runtime));
+    }
+}
+
+/// unmount the view
+/// fails without the privileges to do so, it's unmounted on the next start then
+///
+/// @param mountpoint  where the view is mounted
+pub fn unmount(mountpoint: &Path) -> Result<(), String> {
+    nix::mount::umount2(mountpoint, MntFlags::MNT_DETACH).map_err(|e| {
+        format!(
+            "Cannot unmount FUSE view at {}: {}",
+            mountpoint.to_string_lossy(),
+            e
+        )
+    })
+}
+
+/// whether a view of portcache is mounted at mountpoint
+///
+/// @param mountpoint  where the view gets mounted
+fn mounted(mountpoint: &Path) -> bool {
+    // /proc/self/mounts escapes whitespace in octal
+    let escaped = mountpoint
+        .to_string_lossy()
+        .replace('\\', "\\134")
+        .replace(' ', "\\040")
+        .replace('\t', "\\011")
+        .replace('\n', "\\012");
+    std::fs::read_to_string("/proc/self/mounts").is_ok_and(|mounts| {
+        mounts.lines().any(|line| {
+            let mut fields = line.split(' ').skip(1);
+            fields.next() == Some(escaped.as_str()) && fields.next() == Some("fuse.portcache")
+        })
+    })
+}
+
+/// state of a mounted view
+struct View {
+    /// storage to serve and fetch into
+    blob_storage: Arc<BlobStorage>,
+
+    /// connection to the kernel
+    device: File,
+
+    /// inode numbers handed out
+    inodes: Mutex<Inodes>,
+
+    /// open blobs by file handle
+    handles: Mutex<HashMap<u64, Arc<File>>>,
+
+    /// next file handle to hand out
+    next_handle: AtomicU64,
+}
+
+/// fields of fuse_in_header needed to answer a request
+struct Request {
+    opcode: u32,
+    unique: u64,
+    nodeid: u64,
+}
+
+impl View {
+    /// read requests from the kernel until the view gets unmounted
+    ///
+    /// @param runtime  runtime the requests get handled on
+    fn receive(self: Arc<Self>, runtime: tokio::runtime::Handle) {
+        let mut buffer = vec![0; BUFFER_SIZE];
+        loop {
+            let size = match (&self.device).read(&mut buffer) {
+                Ok(size) => size,
+                // interrupted before we got to read it
+                Err(e)
+                    if matches!(
+                        Errno::from_raw(e.raw_os_error().unwrap_or(0)),
+                        Errno::ENOENT | Errno::EINTR | Errno::EAGAIN
+                    ) =>
+                {
+                    continue;
+                }
+                Err(e) if e.raw_os_error() == Some(Errno::ENODEV as i32) => {
+                    println!("FUSE view got unmounted");
+                    return;
+                }
+                Err(e) => {
+                    eprintln!("Failed to read from the FUSE view: {}", e);
+                    return;
+                }
+            };
+            if size < IN_HEADER_SIZE {
+                continue;
+            }
+
+            let message = buffer[..size].to_vec();
+            let view = self.clone();
+            runtime.spawn(async move { view.handle(message).await });
+        }
+    }
+
+    /// answer one request
+    ///
+    /// @param message  the request including its header
+    async fn handle(&self, message: Vec<u8>) {
+        let request = Request {
+            opcode: u32_at(&message, 4),
+            unique: u64_at(&message, 8),
+            nodeid: u64_at(&message, 16),
+        };
+        let body = &message[IN_HEADER_SIZE..];
+
+        let reply = match request.opcode {
+            // answered without a reply
+            FORGET | BATCH_FORGET | INTERRUPT => return,
+            INIT => self.init(body),
+            LOOKUP => self.lookup(request.nodeid, name(body)).await,
+            GETATTR => self.getattr(request.nodeid).await,
+            OPEN => self.open(request.nodeid, body).await,
+            READ => self.read(request.nodeid, body).await,
+            RELEASE => self.release(body),
+            OPENDIR => Ok(open_out(0)),
+            READDIR => self.readdir(request.nodeid, body).await,
+            STATFS => self.statfs(),
+            FLUSH | RELEASEDIR | DESTROY => Ok(Vec::new()),
+            _ => Err(Errno::ENOSYS),
+        };
+        self.reply(request.unique, reply);
+    }
+
+    /// send the reply to a request
+    ///
+    /// @param unique  id of the request
+    /// @param reply   payload or error of the reply
+    fn reply(&self, unique: u64, reply: Result<Vec<u8>, Errno>) {
+        let (error, payload) = match reply {
+            Ok(payload) => (0, payload),
+            Err(errno) => (-(errno as i32), Vec::new()),
+        };
+        let mut message = Vec::with_capacity(OUT_HEADER_SIZE + payload.len());
+        message.extend(((OUT_HEADER_SIZE + payload.len()) as u32).to_ne_bytes());
+        message.extend(error.to_ne_bytes());
+        message.extend(unique.to_ne_bytes());
+        message.extend(payload);
+
+        // ENOENT means the request got interrupted meanwhile
+        if let Err(e) = (&self.device).write_all(&message)
+            && e.raw_os_error() != Some(Errno::ENOENT as i32)
+        {
+            eprintln!("Failed to answer the FUSE view: {}", e);
+        }
+    }
+
+    /// node of an inode number
+    fn node(&self, nodeid: u64) -> Result<Node, Errno> {
+        let inodes = self.inodes.lock().unwrap();
+        inodes.nodes.get(&nodeid).cloned().ok_or(Errno::ENOENT)
+    }
+
+    /// inode number of a node
+    fn id(&self, node: Node) -> u64 {
+        self.inodes.lock().unwrap().id(node)
+    }
+
+    /// agree on the protocol version
+    ///
+    /// @param body  fuse_init_in
+    fn init(&self, body: &[u8]) -> Result<Vec<u8>, Errno> {
+        if body.len() < 16 || u32_at(body, 0) != PROTOCOL_MAJOR {
+            eprintln!("FUSE view: unsupported kernel protocol version");
+            return Err(Errno::EPROTO);
+        }
+
+        // fuse_init_out
+        let mut out = Vec::with_capacity(64);
+        out.extend(PROTOCOL_MAJOR.to_ne_bytes());
+        out.extend(PROTOCOL_MINOR.to_ne_bytes());
+        out.extend(u32_at(body, 8).to_ne_bytes()); // max_readahead
+        out.extend((u32_at(body, 12) & ASYNC_READ).to_ne_bytes()); // flags
+        out.extend(16u16.to_ne_bytes()); // max_background
+        out.extend(12u16.to_ne_bytes()); // congestion_threshold
+        out.extend(4096u32.to_ne_bytes()); // max_write
+        out.extend(1u32.to_ne_bytes()); // time_gran
+        out.resize(64, 0);
+        Ok(out)
+    }
+
+    /// look up a name in a directory
+    /// blobs that aren't cached get fetched here so open can't fail on them
+    ///
+    /// @param parent  inode number of the directory
+    /// @param name    name looked up
+    async fn lookup(&self, parent: u64, name: Option<&str>) -> Result<Vec<u8>, Errno> {
+        let name = name.ok_or(Errno::ENOENT)?;
+        let node = match (self.node(parent)?, name) {
+            (Node::Root, "distfiles") => Node::Distfiles,
+            (Node::Distfiles, "layout.conf") => Node::LayoutConf,
+            (Node::Distfiles, hash) if self.is_hash_dir(hash) => Node::Bucket(hash.to_string()),
+            (Node::Bucket(hash), file) if self.blob_storage.hash_dir(file) == hash => {
+                self.fetch(file).await?;
+                Node::Blob(file.to_string())
+            }
+            _ => return Err(Errno::ENOENT),
+        };
+
+        // fuse_entry_out
+        let attr = self.attr(&node).await?;
+        let mut out = Vec::with_capacity(128);
+        out.extend(self.id(node).to_ne_bytes());
+        out.extend(0u64.to_ne_bytes()); // generation
+        out.extend(ATTR_TIMEOUT.to_ne_bytes()); // entry_valid
+        out.extend(ATTR_TIMEOUT.to_ne_bytes()); // attr_valid
+        out.extend([0; 8]); // entry_valid_nsec, attr_valid_nsec
+        out.extend(attr);
+        Ok(out)
+    }
+
+    /// attributes of a node
+    ///
+    /// @param nodeid  inode number of the node
+    async fn getattr(&self, nodeid: u64) -> Result<Vec<u8>, Errno> {
+        let node = self.node(nodeid)?;
+
+        // fuse_attr_out
+        let mut out = Vec::with_capacity(104);
+        out.extend(ATTR_TIMEOUT.to_ne_bytes());
+        out.extend([0; 8]); // attr_valid_nsec, dummy
+        out.extend(self.attr(&node).await?);
+        Ok(out)
+    }
+
+    /// open a blob for reading
+    ///
+    /// @param nodeid  inode number of the blob
+    /// @param body    fuse_open_in
+    async fn open(&self, nodeid: u64, body: &[u8]) -> Result<Vec<u8>, Errno> {
+        if u32_at(body, 0) & nix::libc::O_ACCMODE as u32 != nix::libc::O_RDONLY as u32 {
+            return Err(Errno::EROFS);
+        }
+
+        let file = match self.node(nodeid)? {
+            Node::LayoutConf => return Ok(open_out(0)),
+            Node::Blob(file) => file,
+            _ => return Err(Errno::EISDIR),
+        };
+        let path = self.locate(&file).await?;
+        // compressed blobs can't be read at random offsets
+        let scratch = self.blob_storage.location().to_path_buf();
+        let blob = tokio::task::spawn_blocking(move || compression::open_unpacked(&path, &scratch))
+            .await
+            .map_err(|_| Errno::EIO)?
+            .map_err(|e| Errno::from_raw(e.raw_os_error().unwrap_or(Errno::EIO as i32)))?;
+
+        let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        self.handles.lock().unwrap().insert(handle, Arc::new(blob));
+        Ok(open_out(handle))
+    }
+
+    /// read from an open blob
+    ///
+    /// @param nodeid  inode number of the blob
+    /// @param body    fuse_read_in
+    async fn read(&self, nodeid: u64, body: &[u8]) -> Result<Vec<u8>, Errno> {
+        let (handle, offset, size) = (u64_at(body, 0), u64_at(body, 8), u32_at(body, 16));
+
+        if self.node(nodeid)? == Node::LayoutConf {
+            let content = self.layout_conf().into_bytes();
+            let start = (offset as usize).min(content.len());
+            let end = (start + size as usize).min(content.len());
+            return Ok(content[start..end].to_vec());
+        }
+
+        let blob = self
+            .handles
+            .lock()
+            .unwrap()
+            .get(&handle)
+            .cloned()
+            .ok_or(Errno::EBADF)?;
+        tokio::task::spawn_blocking(move || {
+            let mut data = vec![0; size as usize];
+            let mut filled = 0;
+            while filled < data.len() {
+                match blob.read_at(&mut data[filled..], offset + filled as u64) {
+                    Ok(0) => break,
+                    Ok(read) => filled += read,
+                    Err(e) => {
+                        return Err(Errno::from_raw(
+                            e.raw_os_error().unwrap_or(Errno::EIO as i32),
+                        ));
+                    }
+                }
+            }
+            data.truncate(filled);
+            Ok(data)
+        })
+        .await
+        .map_err(|_| Errno::EIO)?
+    }
+
+    /// close an open blob
+    ///
+    /// @param body  fuse_release_in
+    fn release(&self, body: &[u8]) -> Result<Vec<u8>, Errno> {
+        self.handles.lock().unwrap().remove(&u64_at(body, 0));
+        Ok(Vec::new())
+    }
+
+    /// list a directory
+    /// entries are numbered so the listing continues at the offset the kernel asks for
+    ///
+    /// @param nodeid  inode number of the directory
+    /// @param body    fuse_read_in
+    async fn readdir(&self, nodeid: u64, body: &[u8]) -> Result<Vec<u8>, Errno> {
+        let (offset, size) = (u64_at(body, 8), u32_at(body, 16) as usize);
+
+        let mut entries = vec![
+            (nodeid, DT_DIR, String::from(".")),
+            (1, DT_DIR, String::from("..")),
+        ];
+        match self.node(nodeid)? {
+            Node::Root => {
+                entries.push((self.id(Node::Distfiles), DT_DIR, String::from("distfiles")))
+            }
+            Node::Distfiles => {
+                entries.push((
+                    self.id(Node::LayoutConf),
+                    DT_REG,
+                    String::from("layout.conf"),
+                ));
+                for hash in self
+                    .list(self.blob_storage.location().to_path_buf(), true)
+                    .await
+                {
+                    entries.push((self.id(Node::Bucket(hash.clone())), DT_DIR, hash));
+                }
+            }
+            Node::Bucket(hash) => {
+                let dir = self.blob_storage.location().join(&hash);
+                for file in self.list(dir, false).await {
+                    entries.push((self.id(Node::Blob(file.clone())), DT_REG, file));
+                }
+            }
+            _ => return Err(Errno::ENOTDIR),
+        }
+
+        // fuse_dirent each padded to 8 bytes
+        let mut out = Vec::new();
+        for (index, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            let length = (24 + name.len()).next_multiple_of(8);
+            if out.len() + length > size {
+                break;
+            }
+            out.extend(ino.to_ne_bytes());
+            out.extend((index as u64 + 1).to_ne_bytes());
+            out.extend((name.len() as u32).to_ne_bytes());
+            out.extend(kind.to_ne_bytes());
+            out.extend(name.as_bytes());
+            out.resize(out.len().next_multiple_of(8), 0);
+        }
+        Ok(out)
+    }
+
+    /// usage of the filesystem the storage lives on
+    fn statfs(&self) -> Result<Vec<u8>, Errno> {
+        let stat = nix::sys::statvfs::statvfs(self.blob_storage.location())?;
+
+        // fuse_kstatfs
+        let mut out = Vec::with_capacity(80);
+        out.extend(stat.blocks().to_ne_bytes());
+        out.extend(stat.blocks_free().to_ne_bytes());
+        out.extend(stat.blocks_available().to_ne_bytes());
+        out.extend(stat.files().to_ne_bytes());
+        out.extend(stat.files_free().to_ne_bytes());
+        out.extend((stat.block_size() as u32).to_ne_bytes());
+        out.extend(255u32.to_ne_bytes()); // namelen
+        out.extend((stat.fragment_size() as u32).to_ne_bytes());
+        out.resize(80, 0);
+        Ok(out)
+    }
+
+    /// fuse_attr of a node
+    ///
+    /// @param node  the node
+    async fn attr(&self, node: &Node) -> Result<Vec<u8>, Errno> {
+        let (path, kind, size) = match node {
+            Node::Root | Node::Distfiles => (
+                self.blob_storage.location().to_path_buf(),
+                nix::libc::S_IFDIR | 0o555,
+                None,
+            ),
+            Node::Bucket(hash) => {
+                let dir = self.blob_storage.location().join(hash);
+                match dir.is_dir() {
+                    true => (dir, nix::libc::S_IFDIR | 0o555, None),
+                    false => (
+                        self.blob_storage.location().to_path_buf(),
+                        nix::libc::S_IFDIR | 0o555,
+                        None,
+                    ),
+                }
+            }
+            Node::LayoutConf => (
+                self.blob_storage.location().to_path_buf(),
+                nix::libc::S_IFREG | 0o444,
+                Some(self.layout_conf().len() as u64),
+            ),
+            Node::Blob(file) => (self.locate(file).await?, nix::libc::S_IFREG | 0o444, None),
+        };
+        let metadata = tokio::fs::metadata(&path)
+            .await
+            .map_err(|e| Errno::from_raw(e.raw_os_error().unwrap_or(Errno::EIO as i32)))?;
+        let size = match (size, metadata.is_file()) {
+            (Some(size), _) => size,
+            (None, true) => compression::content_size(&path, metadata.len()).await,
+            (None, false) => 0,
+        };
+
+        let mut out = Vec::with_capacity(88);
+        out.extend(self.id(node.clone()).to_ne_bytes());
+        out.extend(size.to_ne_bytes());
+        out.extend(size.div_ceil(512).to_ne_bytes());
+        out.extend((metadata.atime() as u64).to_ne_bytes());
+        out.extend((metadata.mtime() as u64).to_ne_bytes());
+        out.extend((metadata.ctime() as u64).to_ne_bytes());
+        out.extend((metadata.atime_nsec() as u32).to_ne_bytes());
+        out.extend((metadata.mtime_nsec() as u32).to_ne_bytes());
+        out.extend((metadata.ctime_nsec() as u32).to_ne_bytes());
+        out.extend(kind.to_ne_bytes());
+        out.extend(
+            match kind & nix::libc::S_IFDIR {
+                0 => 1u32,
+                _ => 2u32,
+            }
+            .to_ne_bytes(),
+        ); // nlink
+        out.extend(metadata.uid().to_ne_bytes());
+        out.extend(metadata.gid().to_ne_bytes());
+        out.extend(0u32.to_ne_bytes()); // rdev
+        out.extend(4096u32.to_ne_bytes()); // blksize
+        out.extend(0u32.to_ne_bytes()); // flags
+        Ok(out)
+    }
+
+    /// layout.conf of the view
+    /// flat requests don't work on the view
+    fn layout_conf(&self) -> String {
+        self.blob_storage.layout_conf(false)
+    }
+
+    /// whether name is a hash directory of the storage
+    ///
+    /// @param name  name looked up
+    fn is_hash_dir(&self, name: &str) -> bool {
+        name.len() == usize::from(self.blob_storage.hash_bits() / 4)
+            && name
+                .chars()
+                .all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase())
+    }
+
+    /// request a distfile from the storage, fetching it if it isn't cached
+    ///
+    /// @param file  name of the distfile
+    async fn fetch(&self, file: &str) -> Result<PathBuf, Errno> {
+        let name = DistfileName::parse(file).map_err(|_| Errno::ENOENT)?;
+        self.blob_storage
+            .request(&name)
+            .await
+            .map_err(|e| match e.is::<QueueBusy>() {
+                true => Errno::EAGAIN,
+                false => Errno::ENOENT,
+            })
+    }
+
+    /// location of a distfile looked up before
+    /// cached blobs are used as they are, others get requested again
+    /// e.g. aliases of other blobs or blobs evicted meanwhile
+    ///
+    /// @param file  name of the distfile
+    async fn locate(&self, file: &str) -> Result<PathBuf, Errno> {
+        let path = self
+            .blob_storage
+            .blob_location(file)
+            .await
+            .map_err(|_| Errno::ENOENT)?;
+        match path.is_file() {
+            true => Ok(path),
+            false => self.fetch(file).await,
+        }
+    }
+
+    /// names of the entries of a storage directory
+    /// partial and stale blobs are left out
+    ///
+    /// @param dir   the directory
+    /// @param dirs  whether to list hash directories instead of blobs
+    async fn list(&self, dir: PathBuf, dirs: bool) -> Vec<String> {
+        let mut names = Vec::new();
+        let Ok(mut entries) = tokio::fs::read_dir(&dir).await else {
+            return names;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let is_dir = entry.file_type().await.is_ok_and(|kind| kind.is_dir());
+            let listed = match dirs {
+                true => is_dir && self.is_hash_dir(&name),
+                false => {
+                    !is_dir
+                        && !name.starts_with('.')
+                        && !name.ends_with(".part")
+                        && !name.ends_with(".stale")
+                }
+            };
+            if listed {
+                names.push(name);
+            }
+        }
+        names.sort_unstable();
+        names
+    }
+}
+
+/// fuse_open_out for a file handle
+///
+/// @param handle  the file handle
+fn open_out(handle: u64) -> Vec<u8> {
+    let mut out = Vec::with_capacity(16);
+    out.extend(handle.to_ne_bytes());
+    out.extend([0; 8]); // open_flags, padding
+    out
+}
+
+/// NUL terminated name in the body of a request
+///
+/// @param body  the body
+fn name(body: &[u8]) -> Option<&str> {
+    let end = body.iter().position(|byte| *byte == 0)?;
+    std::str::from_utf8(&body[..end]).ok()
+}
+
+/// native endian u32 at offset of a message, 0 if it's too short
+fn u32_at(message: &[u8], offset: usize) -> u32 {
+    message
+        .get(offset..offset + 4)
+        .map_or(0, |bytes| u32::from_ne_bytes(bytes.try_into().unwrap()))
+}
+
+/// native endian u64 at offset of a message, 0 if it's too short
+fn u64_at(message: &[u8], offset: usize) -> u64 {
+    message
+        .get(offset..offset + 8)
+        .map_or(0, |bytes| u64::from_ne_bytes(bytes.try_into().unwrap()))
+}
@@ -0,0 +1,162 @@
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use std::net::IpAddr;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process::Command;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::time::{self, Instant};
+
+use crate::config::Config;
+
+/// environment variable telling a successor the pid of the instance it replaces
+pub const ENV: &str = "PORTCACHE_HANDOFF";
+
+/// why a running instance can't hand over to a successor with config
+/// None if it can
+///
+/// @param config  a reference to Config
+pub fn unsupported(config: &Config) -> Option<String> {
+    if config.server.user.is_some() {
+        Some(String::from(
+            "server.user is set - the successor couldn't bind the port without root",
+        ))
+    } else if config.rsync.enabled && config.rsync.spawn {
+        Some(String::from(
+            "rsync.spawn is set - the rsync daemon would be started twice",
+        ))
+    } else {
+        None
+    }
+}
+
+/// pid of the instance this process was started to replace
+pub fn predecessor() -> Option<Pid> {
+    std::env::var(ENV)
+        .ok()
+        .and_then(|pid| pid.parse().ok())
+        .map(Pid::from_raw)
+}
+
+/// become the main process and ask the predecessor to drain
+/// then wait until it gave up the server address
+/// downloads it's still running keep going until they finish or drain_timeout ends
+/// requests for those files wait for them instead of fetching again
+///
+/// @param predecessor  pid of the running instance
+/// @param address      address the server listens on
+/// @param port         port the server listens on
+/// @param timeout      how long to wait for the address to become free
+pub async fn take_over(
+    predecessor: Pid,
+    address: IpAddr,
+    port: u16,
+    timeout: Duration,
+) -> Result<(), String> {
+    println!("Taking over from portcache instance {}", predecessor);
+    // systemd has to know the new main pid before the old one exits
+    // or it considers the service stopped and kills this instance too
+    notify_systemd(&format!("MAINPID={}", std::process::id()));
+    signal::kill(predecessor, Signal::SIGTERM)
+        .map_err(|e| format!("Cannot stop instance {}: {}", predecessor, e))?;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        match TcpListener::bind((address, port)).await {
+            Ok(_) => return Ok(()),
+            Err(e) if Instant::now() >= deadline => {
+                return Err(format!(
+                    "Instance {} didn't release {}:{} within {}s: {}",
+                    predecessor,
+                    address,
+                    port,
+                    timeout.as_secs(),
+                    e
+                ));
+            }
+            Err(_) => time::sleep(Duration::from_millis(100)).await,
+        }
+    }
+}
+
+/// start a successor on SIGUSR2
+/// it runs the binary this instance was started as with the same arguments
+/// so upgrading the binary and sending SIGUSR2 (systemctl reload) replaces
+/// the running instance without dropping in-flight downloads
+///
+/// @param unsupported  why handoff can't work with the config, see unsupported()
+pub async fn listen(unsupported: Option<String>) {
+    let mut signals = match signal(SignalKind::user_defined2()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            eprintln!("Cannot listen for SIGUSR2: {}", e);
+            return;
+        }
+    };
+
+    while signals.recv().await.is_some() {
+        if let Some(reason) = &unsupported {
+            eprintln!(
+                "Ignoring SIGUSR2: Cannot hand over to a new instance: {}",
+                reason
+            );
+            continue;
+        }
+
+        match spawn_successor() {
+            Ok(pid) => {
+                println!("Started successor {} - draining once it is ready", pid);
+                return;
+            }
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+/// start this binary again as the successor of this instance
+/// returns the pid of the successor
+fn spawn_successor() -> Result<u32, String> {
+    let mut args = std::env::args_os();
+    let binary = args
+        .next()
+        .ok_or_else(|| String::from("Cannot start successor: Unknown binary"))?;
+
+    Command::new(&binary)
+        .args(args)
+        .env(ENV, std::process::id().to_string())
+        .spawn()
+        .map(|child| child.id())
+        .map_err(|e| format!("Cannot start successor {}: {}", binary.to_string_lossy(), e))
+}
+
+/// tell the service manager about the state of this instance
+/// a no-op when not started by systemd with Type=notify
+///
+/// @param state  newline separated assignments like "READY=1"
+pub fn notify_systemd(state: &str) {
+    if let Some(socket) = std::env::var_os("NOTIFY_SOCKET")
+        && let Err(e) = notify(&socket.to_string_lossy(), state)
+    {
+        eprintln!("Cannot notify systemd: {}", e);
+    }
+}
+
+/// send state to a sd_notify socket
+/// names starting with @ are in the abstract namespace
+///
+/// @param socket  path of the socket
+/// @param state   newline separated assignments like "READY=1"
+pub fn notify(socket: &str, state: &str) -> Result<(), String> {
+    let address = match socket.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+        None => SocketAddr::from_pathname(socket),
+    }
+    .map_err(|e| format!("Bad notify socket {}: {}", socket, e))?;
+
+    UnixDatagram::unbound()
+        .and_then(|sender| sender.send_to_addr(state.as_bytes(), &address))
+        .map(|_| ())
+        .map_err(|e| format!("Cannot send to {}: {}", socket, e))
+}
@@ -0,0 +1,303 @@
+use notify::event::{AccessKind, AccessMode, EventKind, ModifyKind};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::{fs, time};
+
+use crate::blob_storage::BlobStorage;
+use crate::config::{Config, ImportConfig};
+use crate::distfile_name::DistfileName;
+use crate::fetcher::{self, FetchError, FetchErrorKind};
+use crate::log_limiter::LogLimiter;
+use crate::repo_db::RepoDB;
+use crate::request_id::{req_eprintln, req_println};
+use crate::utils;
+
+/// subdirectory of the drop directory files failing verification are moved to
+const REJECTED: &str = "rejected";
+
+/// drop directory distfiles get imported from
+/// e.g. copied from a USB drive on an air-gapped network
+pub struct ImportDir {
+    /// the drop directory
+    directory: PathBuf,
+
+    /// how long a file must stay unchanged before it gets imported
+    settle: Duration,
+
+    /// also import files without a Manifest entry
+    unverified: bool,
+
+    /// repo database with the Manifest entries files are verified against
+    repo_db: Arc<RepoDB>,
+}
+
+impl ImportDir {
+    /// create an ImportDir from config
+    /// returns None without an [import] section
+    pub fn new(config: &Config, repo_db: Arc<RepoDB>) -> Option<Self> {
+        let ImportConfig {
+            directory,
+            settle,
+            unverified,
+        } = config.import.clone()?;
+        Some(Self {
+            directory,
+            settle,
+            unverified,
+            repo_db,
+        })
+    }
+
+    /// location of file in the drop directory
+    /// None unless it's there and stopped changing at least settle ago
+    ///
+    /// @param file  name of the distfile
+    fn settled(&self, file: &str) -> Option<PathBuf> {
+        let path = self.directory.join(file);
+        let metadata = std::fs::metadata(&path).ok()?;
+        // ctime changes with every write, even when the copy keeps the mtime
+        let changed = u64::try_from(metadata.ctime()).unwrap_or_default();
+        (metadata.is_file() && changed + self.settle.as_secs() <= utils::unix_time())
+            .then_some(path)
+    }
+
+    /// verify file from the drop directory and move it into the storage
+    /// NotFound if it isn't there or still being written
+    /// Rejected if it fails verification, it's moved to rejected/ then
+    /// or has no Manifest entry without unverified, it stays in place then
+    ///
+    /// @param file   name of the distfile
+    /// @param store  storage to import into
+    pub async fn ingest(&self, file: &str, store: &BlobStorage) -> Result<(), FetchError> {
+        let source = self.settled(file).ok_or_else(|| {
+            FetchError::new(
+                FetchErrorKind::NotFound,
+                format!("{} isn't in the import directory", file),
+            )
+        })?;
+
+        match self.repo_db.get_manifest_entry(file).await {
+            Ok(Some(entry)) => {
+                if let Some(mismatch) = fetcher::manifest_mismatch(&source, &entry).await? {
+                    self.reject(file, &source).await;
+                    return Err(FetchError::new(FetchErrorKind::Rejected, mismatch));
+                }
+            }
+            Ok(None) if self.unverified => (),
+            Ok(None) => {
+                return Err(FetchError::new(
+                    FetchErrorKind::Rejected,
+                    format!(
+                        "{} has no Manifest entry to verify it against (see import.unverified)",
+                        file
+                    ),
+                ));
+            }
+            Err(e) => return Err(FetchError::from(e.to_string())),
+        }
+
+        let dest = store.fetch_location(file).await?;
+        move_file(&source, &dest)
+            .await
+            .map_err(|e| FetchError::from(format!("Failed to import {}: {}", file, e)))?;
+        req_println!(
+            "Imported {} from {}",
+            file,
+            self.directory.to_string_lossy()
+        );
+        Ok(())
+    }
+
+    /// move a file failing verification out of the way
+    /// so it doesn't get verified again and again
+    async fn reject(&self, file: &str, source: &Path) {
+        let rejected = self.directory.join(REJECTED);
+        let result = match fs::create_dir_all(&rejected).await {
+            Ok(_) => fs::rename(source, rejected.join(file)).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            req_eprintln!("Failed to move {} to {}: {}", file, REJECTED, e);
+        }
+    }
+}
+
+/// move a file into the storage
+/// copies when the drop directory is on another filesystem
+///
+/// @param source  file in the drop directory
+/// @param dest    location in the storage
+async fn move_file(source: &Path, dest: &Path) -> std::io::Result<()> {
+    utils::create_parent_dir(dest).await?;
+    match fs::rename(source, dest).await {
+        Err(e) if e.raw_os_error() == Some(nix::libc::EXDEV) => {
+            let part = fetcher::part_location(dest);
+            fs::copy(source, &part).await?;
+            fs::rename(&part, dest).await?;
+            fs::remove_file(source).await
+        }
+        result => result,
+    }
+}
+
+/// result of a scan of the drop directory
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    /// files moved into the storage
+    pub imported: usize,
+
+    /// files already cached which got removed
+    pub duplicates: usize,
+
+    /// files which couldn't be imported
+    pub failed: usize,
+}
+
+/// imports files as soon as they show up in the drop directory
+pub struct Importer {
+    /// the drop directory
+    import: ImportDir,
+
+    /// storage to import into
+    blob_storage: Arc<BlobStorage>,
+
+    /// aggregates files failing over and over e.g. for lack of a Manifest entry
+    log: LogLimiter,
+}
+
+impl Importer {
+    /// create an Importer from config
+    /// returns None without an [import] section
+    pub fn new(
+        config: &Config,
+        blob_storage: Arc<BlobStorage>,
+        repo_db: Arc<RepoDB>,
+    ) -> Option<Self> {
+        Some(Self {
+            import: ImportDir::new(config, repo_db)?,
+            blob_storage,
+            log: LogLimiter::new(Duration::from_secs(3600)),
+        })
+    }
+
+    /// start the Importer
+    /// this is expected to be called from a tokio::spawn
+    /// and consumes the Importer
+    pub async fn start(self) {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let _watcher = match watch(&self.import.directory, tx) {
+            Ok(watcher) => {
+                println!(
+                    "Watching {} for distfiles to import",
+                    self.import.directory.to_string_lossy()
+                );
+                Some(watcher)
+            }
+            Err(e) => {
+                eprintln!(
+                    "Failed to watch {}, scanning it every {}s instead: {}",
+                    self.import.directory.to_string_lossy(),
+                    self.import.settle.as_secs(),
+                    e
+                );
+                None
+            }
+        };
+
+        // files only get imported once settled
+        // so keep scanning while some are still being written
+        let mut interval = time::interval(self.import.settle.max(Duration::from_secs(1)));
+        loop {
+            tokio::select! {
+                Some(_) = rx.recv() => (),
+                _ = interval.tick() => (),
+            }
+            // the rest of a burst of events is handled by this scan
+            while rx.try_recv().is_ok() {}
+
+            // failures are logged as they happen
+            match self.scan().await {
+                Ok(report) if report.imported + report.duplicates > 0 => println!(
+                    "Imported {} distfiles, {} already cached, {} failed",
+                    report.imported, report.duplicates, report.failed
+                ),
+                Ok(_) => (),
+                Err(e) => eprintln!("Import scan failed: {}", e),
+            }
+        }
+    }
+
+    /// import every settled file in the drop directory
+    pub async fn scan(&self) -> Result<ImportReport, String> {
+        let mut report = ImportReport::default();
+        let mut entries = fs::read_dir(&self.import.directory)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        while let Some(entry) = entries.next_entry().await.map_err(|e| e.to_string())? {
+            // hidden files are usually temporary files of rsync and the like
+            let name = entry.file_name().to_string_lossy().to_string();
+            let file = match DistfileName::parse(&name) {
+                Ok(file) if !name.starts_with('.') => file,
+                _ => continue,
+            };
+            if self.import.settled(file.as_str()).is_none() {
+                continue;
+            }
+
+            // requests fetching the same file wait for the import
+            let lock = self.blob_storage.lock_fetch(file.as_str()).await;
+            let dest = self.blob_storage.blob_location(file.as_str()).await?;
+            if dest.is_file() {
+                match fs::remove_file(entry.path()).await {
+                    Ok(_) => report.duplicates += 1,
+                    Err(e) => eprintln!("Failed to remove duplicate {}: {}", name, e),
+                }
+                continue;
+            }
+
+            match self.import.ingest(file.as_str(), &self.blob_storage).await {
+                Ok(_) => {
+                    self.blob_storage.note_change(file.as_str());
+                    report.imported += 1;
+                }
+                Err(e) => {
+                    report.failed += 1;
+                    self.log
+                        .error(&format!("import {}", name), format!("Import failed: {}", e));
+                }
+            }
+            drop(lock);
+        }
+
+        Ok(report)
+    }
+}
+
+/// watch the drop directory for new or changed files
+/// dropping the returned watcher stops watching
+///
+/// @param directory  the drop directory
+/// @param tx         channel notified on changes
+fn watch(directory: &Path, tx: mpsc::UnboundedSender<()>) -> notify::Result<RecommendedWatcher> {
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event
+            && matches!(
+                event.kind,
+                EventKind::Create(_)
+                    | EventKind::Modify(ModifyKind::Data(_))
+                    | EventKind::Modify(ModifyKind::Name(_))
+                    | EventKind::Access(AccessKind::Close(AccessMode::Write))
+            )
+        {
+            let _ = tx.send(());
+        }
+    })?;
+
+    watcher.watch(directory, RecursiveMode::NonRecursive)?;
+    Ok(watcher)
+}
@@ -0,0 +1,63 @@
+use std::path::Path;
+
+/// fully commented config with the defaults of every setting
+pub const SAMPLE_CONFIG: &str = include_str!("../meta/portcache.toml");
+
+/// hardened systemd unit running portcache as its own user
+const SYSTEMD_UNIT: &str = include_str!("../meta/portcache.service");
+
+/// the systemd unit pointed at a config file
+///
+/// @param config  absolute path of the config portcache gets started with
+pub fn systemd_unit(config: &Path) -> String {
+    SYSTEMD_UNIT
+        .lines()
+        .map(|line| {
+            if line.starts_with("ExecStart=") {
+                format!(
+                    "ExecStart=/usr/bin/portcache -c {}\n",
+                    config.to_string_lossy()
+                )
+            } else {
+                format!("{}\n", line)
+            }
+        })
+        .collect()
+}
+
+/// write the sample config and optionally the systemd unit
+/// existing files are only replaced with force
+///
+/// @param config  where to write the config
+/// @param unit    where to write the systemd unit, None skips it
+/// @param force   overwrite existing files
+pub fn write(config: &Path, unit: Option<&Path>, force: bool) -> Result<(), String> {
+    let mut files = vec![(config, SAMPLE_CONFIG.to_string())];
+    if let Some(unit) = unit {
+        let absolute = std::path::absolute(config)
+            .map_err(|e| format!("Cannot resolve {}: {}", config.to_string_lossy(), e))?;
+        files.push((unit, systemd_unit(&absolute)));
+    }
+
+    // check everything first so nothing is written half way
+    if !force && let Some((path, _)) = files.iter().find(|(path, _)| path.exists()) {
+        return Err(format!(
+            "{} already exists, pass --force to overwrite it",
+            path.to_string_lossy()
+        ));
+    }
+
+    for (path, content) in files {
+        if let Some(parent) = path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| format!("Cannot create {}: {}", parent.to_string_lossy(), e))?;
+        }
+        std::fs::write(path, content)
+            .map_err(|e| format!("Cannot write {}: {}", path.to_string_lossy(), e))?;
+        println!("Wrote {}", path.to_string_lossy());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,119 @@
+//! Cache server for portage distfiles
+//!
+//! The caching engine is split into independent components which the
+//! `portcache` binary wires together via [`app::build_rocket`]:
+//!
+//! - [`blob_storage::BlobStorage`] serves cached blobs and fetches misses
+//! - [`fetcher::FetchChain`] tries the configured [`fetcher::Fetcher`] backends
+//! - [`repo_syncer::RepoSyncer`] keeps ebuild repos up to date
+//! - [`repo_db::RepoDB`] indexes Manifest entries and SRC_URIs of those repos
+//! - [`evictor::Evictor`] keeps the storage below its configured size
+//! - [`snapshot::Snapshotter`] takes periodic snapshots of the repo database
+//! - [`binhost::Binhost`] caches binary packages of an upstream binhost
+//! - [`releases::Releases`] caches verified release media like stage3 tarballs
+//! - [`webrsync::Webrsync`] serves snapshot tarballs of the repos to emerge-webrsync
+//! - [`control::ControlSocket`] takes commands from local scripts via JSON-RPC
+//! - [`fuse::FuseMount`] exposes the cache as read-only filesystem laid out like a mirror
+//!
+//! ```no_run
+//! use portcache::app::{self, Deps};
+//! use portcache::config::Config;
+//! use portcache::distfile_name::DistfileName;
+//!
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! let config = Config::parse(Some("portcache.toml".to_string()))?;
+//! let deps = Deps::new(&config).await?;
+//! let file = DistfileName::parse("foo-1.0.tar.gz")?;
+//! let path = deps.blob_storage.request(&file).await?;
+//! println!("cached at {}", path.to_string_lossy());
+//! # Ok(())
+//! # }
+//! ```
+
+#![warn(missing_docs)]
+
+// import vars from build.rs
+include!(concat!(env!("OUT_DIR"), "/build_vars.rs"));
+
+/// access times of cached blobs written in batches
+pub mod access_log;
+/// authenticated admin API
+pub mod admin;
+/// API keys identifying clients of shared caches
+pub mod api_keys;
+/// composing the components into a server
+pub mod app;
+/// caching of binary packages from an upstream binhost
+pub mod binhost;
+/// storage for cached blobs
+pub mod blob_storage;
+/// noticing blobs changed outside of portcache
+pub mod blob_watch;
+/// fault injection for resilience testing
+#[cfg(feature = "chaos")]
+pub mod chaos;
+/// signed index of the cached distfiles for mirroring tools
+pub mod checksum_index;
+/// transparent zstd compression of blobs at rest
+pub mod compression;
+/// configuration file parsing
+pub mod config;
+/// local JSON-RPC control socket for scripts
+pub mod control;
+/// hard links of cached blobs in the local Portage DISTDIR
+pub mod distdir;
+/// validated distfile names from requests
+pub mod distfile_name;
+/// extracting SRC_URIs from ebuilds via portage
+pub mod ebuild_parser;
+/// evicting blobs when the storage grows too large
+pub mod evictor;
+/// fetch backends for missing blobs
+pub mod fetcher;
+/// HTTP routes
+pub mod frontend;
+/// read-only FUSE view of the cache
+pub mod fuse;
+/// replacing a running instance without dropping in-flight downloads
+pub mod handoff;
+/// importing distfiles from a drop directory
+pub mod import;
+/// generation of a sample config and systemd unit
+pub mod init;
+/// suppression of repeated log lines
+pub mod log_limiter;
+/// Manifest file parsing
+pub mod manifest_walker;
+/// in-memory cache of small files served often
+pub mod memory_cache;
+/// switching to an unprivileged user
+pub mod privileges;
+/// per client usage tracking and soft quotas
+pub mod quota;
+/// rebuilding a lost database from disk
+pub mod rebuild;
+/// caching of Gentoo release media
+pub mod releases;
+/// database of repo metadata
+pub mod repo_db;
+/// cloning and syncing of ebuild repos
+pub mod repo_syncer;
+/// request ids in responses and log lines
+pub mod request_id;
+/// exporting the cache via rsync
+pub mod rsync;
+/// landlock sandboxing of the daemon
+pub mod sandbox;
+/// snapshots of the repo database
+pub mod snapshot;
+/// statistics API
+pub mod stats;
+/// OpenTelemetry tracing of requests
+pub mod telemetry;
+/// io_uring reads of served files
+#[cfg(feature = "uring")]
+pub mod uring;
+/// small shared helpers
+pub mod utils;
+/// repo snapshots for emerge-webrsync
+pub mod webrsync;
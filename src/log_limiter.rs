@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::request_id::req_eprintln;
+
+/// aggregates repeated log lines of the same kind
+/// the first line per key and window gets printed, the rest only counted
+/// the count gets reported once the key shows up again after its window
+pub struct LogLimiter {
+    /// how long lines of one key get suppressed after being printed
+    /// zero disables suppression
+    window: Duration,
+
+    /// keys mapped to when they were last printed
+    /// and how many lines were suppressed since
+    seen: Mutex<HashMap<String, (Instant, u64)>>,
+}
+
+impl LogLimiter {
+    /// create a new LogLimiter
+    ///
+    /// @param window  how long repeated lines get suppressed
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// log an error unless key was already logged in the current window
+    /// returns whether the error was printed
+    ///
+    /// @param key      what failed e.g. "mirror https://example.org"
+    /// @param message  the error to log
+    pub fn error(&self, key: &str, message: impl Display) -> bool {
+        if self.window.is_zero() {
+            req_eprintln!("{}", message);
+            return true;
+        }
+
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        match seen.get_mut(key) {
+            Some((since, suppressed)) if now.duration_since(*since) < self.window => {
+                *suppressed += 1;
+                false
+            }
+            Some((since, suppressed)) => {
+                if *suppressed > 0 {
+                    req_eprintln!(
+                        "{} failed {} more times in the last {}",
+                        key,
+                        suppressed,
+                        format_window(now.duration_since(*since))
+                    );
+                }
+                *since = now;
+                *suppressed = 0;
+                req_eprintln!("{}", message);
+                true
+            }
+            None => {
+                seen.insert(key.to_string(), (now, 0));
+                req_eprintln!("{}", message);
+                true
+            }
+        }
+    }
+}
+
+/// short human readable duration e.g. 10m or 45s
+fn format_window(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 60 {
+        format!("{}m", secs / 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
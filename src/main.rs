@@ -1,26 +1,29 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use rocket::fairing::AdHoc;
 use rocket::{Build, Rocket};
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::time::Duration;
 use tokio::task;
 
-// import vars from build.rs
-include!(concat!(env!("OUT_DIR"), "/build_vars.rs"));
-
-// modules in this crate
-mod blob_storage;
-mod config;
-mod ebuild_parser;
-mod fetcher;
-mod frontend;
-mod manifest_walker;
-mod repo_db;
-mod repo_syncer;
-mod utils;
-
-use crate::blob_storage::BlobStorage;
-use crate::config::Config;
-use crate::repo_db::RepoDB;
-use crate::repo_syncer::RepoSyncer;
+use portcache::api_keys;
+use portcache::app::{self, Deps};
+use portcache::blob_storage;
+use portcache::blob_watch::BlobWatcher;
+use portcache::checksum_index::SigningKey;
+use portcache::config::{self, Config};
+use portcache::control::ControlSocket;
+use portcache::evictor::{EvictionTarget, Evictor};
+use portcache::fuse::{self, FuseMount};
+use portcache::handoff;
+use portcache::import::Importer;
+use portcache::init;
+use portcache::privileges::RunAs;
+use portcache::rebuild;
+use portcache::repo_db::{self, RepoDB};
+use portcache::repo_syncer::RepoSyncer;
+use portcache::rsync::{self, Rsyncd};
+use portcache::sandbox;
+use portcache::snapshot::{self, Snapshotter};
 
 /// Portage Distfile Cacher
 #[derive(Parser, Debug)]
@@ -28,50 +31,491 @@ struct Args {
     /// Config File (defaults to ${PWD}/portcache.toml)
     #[arg(short, long)]
     config: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-struct SharedData {
-    /// BlobStorage for requesting blobs
-    blob_storage: BlobStorage,
+/// one-off tasks run instead of the server
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Write a commented sample config with the defaults of every setting
+    InitConfig {
+        /// Where to write the config
+        #[arg(default_value = "portcache.toml")]
+        path: PathBuf,
+
+        /// Also write a systemd unit starting portcache with this config
+        #[arg(long)]
+        systemd_unit: Option<PathBuf>,
+
+        /// Overwrite existing files
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Evict cached distfiles now and exit
+    /// (prefer POST /api/v1/admin/gc while the server is running)
+    Gc {
+        /// Shrink the cache to at most this many bytes (units like "500GB" work)
+        #[arg(long, conflicts_with = "target_free", value_parser = config::parse_size)]
+        target_size: Option<u64>,
+
+        /// Evict until the filesystem has this many bytes available
+        #[arg(long, value_parser = config::parse_size)]
+        target_free: Option<u64>,
+    },
+
+    /// Move cached distfiles into the hash directories of storage.hash_bits and exit
+    /// (stop the server first)
+    Reshard,
+
+    /// Repopulate a lost database from the repos and blobs on disk and exit
+    /// (stop the server first)
+    RebuildIndex,
+
+    /// Snapshot the metadata database and exit (safe while the server is running)
+    DbBackup {
+        /// Where to write the snapshot (defaults to <storage>/snapshots/db-<time>.sqlite3)
+        path: Option<PathBuf>,
+    },
+
+    /// Replace the metadata database with a snapshot and exit
+    /// (stop the server first)
+    DbRestore {
+        /// Snapshot to restore
+        path: PathBuf,
+    },
+
+    /// Generate a key signing the checksum index, print its public key and exit
+    IndexKey {
+        /// Name of the key, e.g. the host name of the cache
+        name: String,
+
+        /// Where to write the key (set it as index.signing_key)
+        path: PathBuf,
+    },
+
+    /// Manage the API keys clients identify with and exit
+    ApiKey {
+        #[command(subcommand)]
+        action: KeyAction,
+    },
+}
+
+/// what to do with API keys
+#[derive(Subcommand, Debug)]
+enum KeyAction {
+    /// Issue a new key and print it (it can't be shown again)
+    Add {
+        /// Name of the key, e.g. the host or team using it
+        name: String,
+
+        /// Bytes the key may be served per quota window (units like "50GiB" work)
+        /// defaults to api_keys.default_limit
+        #[arg(long, value_parser = config::parse_size)]
+        limit: Option<u64>,
+    },
+
+    /// List issued keys
+    List,
+
+    /// Revoke a key along with its usage
+    Revoke {
+        /// Name of the key
+        name: String,
+    },
 }
 
 /// Main
-#[rocket::launch]
-async fn rocket() -> Rocket<Build> {
+fn main() {
     let args = Args::parse();
 
-    let config = Config::parse(args.config).unwrap_or_else(|e| {
+    // runs before there is a config to parse
+    if let Some(Command::InitConfig {
+        path,
+        systemd_unit,
+        force,
+    }) = &args.command
+    {
+        if let Err(e) = init::write(path, systemd_unit.as_deref(), *force) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        std::process::exit(0);
+    }
+
+    if let Some(Command::IndexKey { name, path }) = &args.command {
+        match SigningKey::generate(name).and_then(|key| {
+            key.save(path)?;
+            key.public_key()
+        }) {
+            Ok(public_key) => {
+                println!("{}", public_key);
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let config = Config::parse(args.config.clone()).unwrap_or_else(|e| {
         eprintln!("Failed to parse config: {}", e);
         std::process::exit(1);
     });
 
-    let repo_db = Arc::new(match RepoDB::new(&config) {
-        Ok(db) => db,
-        Err(e) => {
-            eprintln!("Failed to initialize database: {}", e);
+    // mounting is impossible once sandboxed or without privileges
+    // so the view gets mounted before either and served once the runtime runs
+    let fuse = match args.command {
+        None => FuseMount::mount(&config).unwrap_or_else(|e| {
+            eprintln!("{}", e);
             std::process::exit(1);
+        }),
+        Some(_) => None,
+    };
+    let mountpoint = fuse.as_ref().map(|fuse| fuse.mountpoint().to_path_buf());
+
+    // landlock only covers threads started afterwards
+    // so the sandbox has to be in place before the runtime
+    // database backups and restores work on paths given on the command line
+    let one_off_db = matches!(
+        args.command,
+        Some(Command::DbBackup { .. } | Command::DbRestore { .. })
+    );
+    if config.sandbox.enabled
+        && !one_off_db
+        && let Err(e) = sandbox::apply(&config)
+    {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    rocket::async_main(async move {
+        let _ = rocket(args, config, fuse).await.launch().await;
+    });
+
+    // after dropping privileges the view stays until the next start replaces it
+    if let Some(mountpoint) = mountpoint
+        && let Err(e) = fuse::unmount(&mountpoint)
+    {
+        eprintln!("{}", e);
+    }
+}
+
+/// set up all components and build the server
+async fn rocket(args: Args, mut config: Config, fuse: Option<FuseMount>) -> Rocket<Build> {
+    // the blob storage refuses to start on a layout mismatch
+    // so this has to run before the dependencies are set up
+    if let Some(Command::Reshard) = args.command {
+        let result = match RepoDB::new(&config) {
+            Ok(repo_db) => blob_storage::reshard(&config, &repo_db).await,
+            Err(e) => Err(format!("Failed to initialize database: {}", e)),
+        };
+        match result {
+            Ok(report) => {
+                println!(
+                    "Moved {} blobs into {} bit hash directories, {} already in place, {} old directories removed",
+                    report.moved, config.storage.hash_bits, report.kept, report.removed_dirs
+                );
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Reshard failed: {}", e);
+                std::process::exit(1);
+            }
         }
+    }
+
+    match &args.command {
+        Some(Command::DbBackup { path }) => {
+            let result = match RepoDB::new(&config) {
+                Ok(repo_db) => match path {
+                    Some(path) => repo_db.backup(path).await.map(|_| path.clone()),
+                    None => {
+                        snapshot::write_snapshot(&repo_db, &snapshot::snapshot_dir(&config)).await
+                    }
+                },
+                Err(e) => Err(format!("Failed to initialize database: {}", e)),
+            };
+            match result {
+                Ok(path) => {
+                    println!("Wrote database snapshot {}", path.to_string_lossy());
+                    std::process::exit(0);
+                }
+                Err(e) => {
+                    eprintln!("Backup failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(Command::ApiKey { action }) => match manage_keys(&config, action).await {
+            Ok(_) => std::process::exit(0),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        Some(Command::DbRestore { path }) => match repo_db::restore(&config, path) {
+            Ok(_) => {
+                println!("Restored database from {}", path.to_string_lossy());
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Restore failed: {}", e);
+                std::process::exit(1);
+            }
+        },
+        _ => (),
+    }
+
+    // blobs can't be moved under a predecessor still serving them
+    if config.storage.follow_upstream_layout {
+        let reshard = handoff::predecessor().is_none();
+        config.storage.hash_bits = blob_storage::follow_upstream_layout(&config, reshard)
+            .await
+            .unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            });
+    }
+
+    let deps = Deps::new(&config).await.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
     });
 
-    let repo_sync = RepoSyncer::new(&config, repo_db.clone()).await.unwrap();
-    task::spawn(repo_sync.start());
+    if let Some(Command::Gc {
+        target_size,
+        target_free,
+    }) = args.command
+    {
+        let evictor = Evictor::manual(&config, deps.blob_storage.clone(), deps.repo_db.clone());
+        let target = match (target_size, target_free, config.storage.max_size) {
+            (Some(size), _, _) => EvictionTarget::Size(size),
+            (None, Some(free), _) => EvictionTarget::Free(free),
+            (None, None, Some(max_size)) => EvictionTarget::Size(max_size),
+            (None, None, None) => {
+                eprintln!("Neither --target-size, --target-free nor storage.max_size given");
+                std::process::exit(1);
+            }
+        };
+
+        match evictor.run_to(target).await {
+            Ok(report) => {
+                println!(
+                    "Evicted {} blobs freeing {} bytes, {} bytes remaining",
+                    report.removed, report.freed, report.remaining
+                );
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Eviction failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
 
-    let storage = BlobStorage::new(&config, repo_db.clone())
+    if let Some(Command::RebuildIndex) = args.command {
+        match rebuild::rebuild_index(&config, &deps).await {
+            Ok(report) => {
+                println!(
+                    "Checked cached blobs: {} verified, {} mismatched and marked stale, {} unknown, {} failed",
+                    report.verified, report.mismatched, report.unknown, report.failed
+                );
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Rebuilding the index failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let run_as = RunAs::new(&config.server).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+
+    let repo_sync = RepoSyncer::new(&config, deps.repo_db.clone(), deps.sync_progress.clone())
         .await
-        .expect("Failed to initialize blob storage");
+        .unwrap()
+        .prefetch_into(deps.blob_storage.clone());
+    let evictor = Evictor::new(&config, deps.blob_storage.clone(), deps.repo_db.clone());
+    let snapshotter = Snapshotter::new(&config, deps.repo_db.clone());
+    let importer = Importer::new(&config, deps.blob_storage.clone(), deps.repo_db.clone());
+    let control = ControlSocket::new(&config, &deps);
+    let blob_watcher = BlobWatcher::new(&config, deps.blob_storage.clone(), deps.repo_db.clone());
+    let fuse_storage = deps.blob_storage.clone();
+    let access_log = deps.blob_storage.access_log().clone();
+    let pending_access = access_log.clone();
+    let local_distdir = config
+        .storage
+        .local_distdir
+        .is_some()
+        .then(|| deps.blob_storage.clone());
+    let tracer = deps.tracer.clone();
+    let handoff_unsupported = handoff::unsupported(&config);
 
-    let cfg = rocket::config::Config {
-        address: config.server.address,
-        port: config.server.port,
-        ..rocket::config::Config::default()
-    };
+    // started via SIGUSR2 of a running instance
+    // which has to let go of the port first
+    if let Some(predecessor) = handoff::predecessor() {
+        let timeout = config.server.drain_timeout + Duration::from_secs(10);
+        if let Err(e) = handoff::take_over(
+            predecessor,
+            config.server.address,
+            config.server.port,
+            timeout,
+        )
+        .await
+        {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(run_as) = &run_as
+        && let Err(e) = run_as.chown_storage(&config.storage.location)
+    {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    // the rsync daemon may bind a privileged port
+    // and drops privileges on its own via rsyncd.conf
+    if let Some(rsyncd) = Rsyncd::new(&config) {
+        match rsyncd.spawn() {
+            Ok(Some(child)) => {
+                task::spawn(rsync::supervise(child));
+            }
+            Ok(None) => (),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
 
-    let shared = SharedData {
-        blob_storage: storage,
+    // unprivileged ports don't need root to bind
+    // so drop privileges before rocket serves any request
+    let run_as = match run_as {
+        Some(run_as) if !(1..1024).contains(&config.server.port) => {
+            if let Err(e) = run_as.drop_privileges() {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            None
+        }
+        run_as => run_as,
     };
 
-    rocket::custom(cfg).manage(shared).mount(
-        "/",
-        rocket::routes![frontend::layout_conf, frontend::distfiles],
-    )
+    // background tasks only start once the socket is bound
+    // and privileges are dropped so they never touch the storage as root
+    // rocket runs liftoff callbacks after binding a privileged port
+    // and only accepts connections once they completed
+    app::build_rocket(&config, deps)
+        .attach(AdHoc::on_liftoff("Background tasks", move |_| {
+            Box::pin(async move {
+                if let Some(run_as) = run_as
+                    && let Err(e) = run_as.drop_privileges()
+                {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+
+                task::spawn(repo_sync.start());
+                task::spawn(access_log.start());
+                if let Some(evictor) = evictor {
+                    task::spawn(evictor.start());
+                }
+                if let Some(snapshotter) = snapshotter {
+                    task::spawn(snapshotter.start());
+                }
+                if let Some(importer) = importer {
+                    task::spawn(importer.start());
+                }
+                if let Some(control) = control {
+                    task::spawn(control.start());
+                }
+                if let Some(blob_watcher) = blob_watcher {
+                    task::spawn(blob_watcher.start());
+                }
+                if let Some(fuse) = fuse {
+                    fuse.start(fuse_storage);
+                }
+                if let Some(blob_storage) = local_distdir {
+                    task::spawn(async move {
+                        match blob_storage.link_local_distdir().await {
+                            Ok(linked) => {
+                                println!("Linked {} cached blobs into the local DISTDIR", linked)
+                            }
+                            Err(e) => {
+                                eprintln!(
+                                    "Failed to link cached blobs into the local DISTDIR: {}",
+                                    e
+                                )
+                            }
+                        }
+                    });
+                }
+                if let Some(tracer) = tracer {
+                    task::spawn(tracer.start_export());
+                }
+                task::spawn(handoff::listen(handoff_unsupported));
+
+                handoff::notify_systemd("READY=1");
+            })
+        }))
+        .attach(AdHoc::on_shutdown("Flush access times", move |_| {
+            Box::pin(async move {
+                if let Err(e) = pending_access.flush().await {
+                    eprintln!("{}", e);
+                }
+            })
+        }))
+}
+
+/// run an api-key command against the database
+async fn manage_keys(config: &Config, action: &KeyAction) -> Result<(), String> {
+    let repo_db =
+        RepoDB::new(config).map_err(|e| format!("Failed to initialize database: {}", e))?;
+
+    match action {
+        KeyAction::Add { name, limit } => {
+            let key = api_keys::issue(&repo_db, name, *limit).await?;
+            println!("{}", key);
+        }
+        KeyAction::List => {
+            let keys = repo_db
+                .get_api_keys()
+                .await
+                .map_err(|e| format!("Failed to list keys: {}", e))?;
+            for key in keys {
+                let limit = key
+                    .limit
+                    .or(config.api_keys.default_limit)
+                    .map_or_else(|| String::from("unlimited"), |limit| limit.to_string());
+                let last_used = key
+                    .last_used
+                    .map_or_else(|| String::from("never"), |time| time.to_string());
+                println!(
+                    "{}\tlimit {}\tcreated {}\tlast used {}",
+                    key.name, limit, key.created, last_used
+                );
+            }
+        }
+        KeyAction::Revoke { name } => {
+            let deleted = repo_db
+                .delete_api_key(name)
+                .await
+                .map_err(|e| format!("Failed to revoke key {}: {}", name, e))?;
+            if !deleted {
+                return Err(format!("No key named {}", name));
+            }
+            println!("Revoked key {}", name);
+        }
+    }
+
+    Ok(())
 }
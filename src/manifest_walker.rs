@@ -1,12 +1,20 @@
 use async_stream::stream;
+use futures::stream::StreamExt;
 use futures_core::stream::Stream;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tokio::fs;
 use tokio::io;
 use tokio::io::AsyncBufReadExt;
 use walkdir::WalkDir;
 
+use crate::config::LayoutCheck;
+use crate::utils;
+
+/// a DIST entry of a Manifest file
+#[derive(Clone)]
 pub struct ManifestEntry {
     /// origin Manifest file
     pub origin: PathBuf,
@@ -15,107 +23,461 @@ pub struct ManifestEntry {
     pub file: String,
 
     /// file size in bytes
-    pub size: u32,
+    pub size: u64,
 
     /// blake2b checksum
     pub blake2b: Option<String>,
 
     /// sha512 checksum
     pub sha512: Option<String>,
+
+    /// all declared checksums by hash name e.g. "SHA256"
+    /// includes BLAKE2B and SHA512
+    pub hashes: BTreeMap<String, String>,
 }
 
 impl ManifestEntry {
-    /// parse a manifest entry from a manifest line
-    pub fn parse(origin: &PathBuf, line: &String) -> Result<ManifestEntry, String> {
-        let mut file: Option<String> = None;
-        let mut size: Option<u32> = None;
-        let mut blake2b: Option<String> = None;
-        let mut sha512: Option<String> = None;
+    /// strongest declared checksum portcache can verify
+    /// BLAKE2B, then SHA512, then SHA256 - legacy hashes like RMD160 can't be checked
+    pub fn checksum(&self) -> Option<(utils::HashType, &String)> {
+        [
+            ("BLAKE2B", utils::HashType::Blake2b),
+            ("SHA512", utils::HashType::Sha512),
+            ("SHA256", utils::HashType::Sha256),
+        ]
+        .into_iter()
+        .find_map(|(name, hash)| self.hashes.get(name).map(|checksum| (hash, checksum)))
+    }
 
+    /// parse a manifest entry from a manifest line
+    /// EBUILD, MISC and AUX lines of thick Manifests as well as
+    /// empty lines don't describe distfiles and yield None
+    pub fn parse(origin: &PathBuf, line: &String) -> Result<Option<ManifestEntry>, String> {
         let mut parts = line.split_whitespace();
-        while let Some(part) = parts.next() {
-            match part {
-                "DIST" => {
-                    file = match parts.next() {
-                        Some(s) => Some(s.to_string()),
-                        None => {
-                            return Err(format!(
-                                "Expected file name after \"DIST\" in line \"{}\"",
-                                &line
-                            ));
-                        }
-                    };
-                    size = match parts.next() {
-                        Some(s) => match s.parse() {
-                            Ok(i) => Some(i),
-                            Err(e) => return Err(e.to_string()),
-                        },
-                        None => {
-                            return Err(format!(
-                                "Expected file size after \"DIST {}\" in line \"{}\"",
-                                &file.unwrap(),
-                                &line
-                            ));
-                        }
-                    };
-                }
-                "BLAKE2B" => {
-                    blake2b = match parts.next() {
-                        Some(s) => Some(s.to_string()),
-                        None => {
-                            return Err(format!(
-                                "Expected blake2b checksum after \"BLAKE2B\" in line \"{}\"",
-                                &line
-                            ));
-                        }
-                    };
+        match parts.next() {
+            Some("DIST") => {}
+            Some("EBUILD") | Some("MISC") | Some("AUX") | None => return Ok(None),
+            // GLEP 74 metamanifest entries found in category and repo level Manifests
+            Some("MANIFEST") | Some("IGNORE") | Some("DATA") | Some("OPTIONAL")
+            | Some("TIMESTAMP") => return Ok(None),
+            Some(kind) => {
+                return Err(format!(
+                    "Unknown entry type \"{}\" in line \"{}\"",
+                    kind, &line
+                ));
+            }
+        }
+
+        let file = match parts.next() {
+            Some(s) => s.to_string(),
+            None => {
+                return Err(format!(
+                    "Expected file name after \"DIST\" in line \"{}\"",
+                    &line
+                ));
+            }
+        };
+
+        let size = match parts.next() {
+            Some(s) => s
+                .parse()
+                .map_err(|e: std::num::ParseIntError| e.to_string())?,
+            None => {
+                return Err(format!(
+                    "Expected file size after \"DIST {}\" in line \"{}\"",
+                    &file, &line
+                ));
+            }
+        };
+
+        // the rest are pairs of hash name and checksum
+        let mut hashes = BTreeMap::new();
+        while let Some(hash) = parts.next() {
+            match parts.next() {
+                Some(checksum) => {
+                    hashes.insert(hash.to_string(), checksum.to_string());
                 }
-                "SHA512" => {
-                    sha512 = match parts.next() {
-                        Some(s) => Some(s.to_string()),
-                        None => {
-                            return Err(format!(
-                                "Expected sha512 checksum after \"SHA512\" in line \"{}\"",
-                                &line
-                            ));
-                        }
-                    };
+                None => {
+                    return Err(format!(
+                        "Expected checksum after \"{}\" in line \"{}\"",
+                        hash, &line
+                    ));
                 }
-                _ => (),
             }
         }
 
-        // file name and size are required, checksums are optional for now
-        if file.is_none() {
-            return Err(format!("Line {} doesn't contain file name", &line));
+        Ok(Some(ManifestEntry {
+            origin: origin.to_owned(),
+            file,
+            size,
+            blake2b: hashes.get("BLAKE2B").cloned(),
+            sha512: hashes.get("SHA512").cloned(),
+            hashes,
+        }))
+    }
+}
+
+/// depth below the tree root Manifests are looked for at
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ManifestDepth {
+    /// only exactly this many components below the root
+    /// 3 being the standard category/package/Manifest
+    Exactly(usize),
+
+    /// anywhere below the root except for the root itself
+    /// whose Manifest never lists distfiles
+    Any,
+}
+
+impl ManifestDepth {
+    /// depth to walk a tree at
+    /// trees listing their categories in profiles/categories use the standard layout
+    ///
+    /// @param root        ebuild tree root
+    /// @param configured  manifest_depth of the repo config, 0 meaning any depth
+    pub fn detect(root: &Path, configured: Option<usize>) -> Self {
+        match configured {
+            Some(0) => Self::Any,
+            Some(depth) => Self::Exactly(depth),
+            None if root.join("profiles").join("categories").is_file() => Self::Exactly(3),
+            None => Self::Any,
         }
+    }
 
-        if size.is_none() {
-            return Err(format!("Line {} doesn't contain file size", &line));
+    /// whether a Manifest this many components below the root gets parsed
+    pub fn matches(&self, depth: usize) -> bool {
+        match self {
+            Self::Exactly(expected) => depth == *expected,
+            Self::Any => depth >= 2,
         }
+    }
+}
 
-        Ok(ManifestEntry {
-            origin: origin.to_owned(),
-            file: file.unwrap(),
-            size: size.unwrap(),
-            blake2b,
-            sha512,
+/// versions of a package a package.mask atom covers
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum MaskedVersions {
+    /// cat/pkg
+    All,
+
+    /// =cat/pkg-1.0
+    Exact(String),
+
+    /// =cat/pkg-1.0*
+    Prefix(String),
+
+    /// ~cat/pkg-1.0 i.e. any revision of it
+    AnyRevision(String),
+}
+
+impl MaskedVersions {
+    /// whether the atom covers version
+    fn covers(&self, version: &str) -> bool {
+        match self {
+            Self::All => true,
+            Self::Exact(masked) => version == masked,
+            Self::Prefix(masked) => version.starts_with(masked.as_str()),
+            Self::AnyRevision(masked) => strip_revision(version) == masked,
+        }
+    }
+}
+
+/// packages hard-masked in a tree's profiles/package.mask
+/// only unversioned, = and ~ atoms are understood - range and slot atoms
+/// are ignored so no installable package gets skipped
+#[derive(Default)]
+pub struct PackageMask {
+    /// cat/pkg mapped to the masked versions
+    atoms: HashMap<String, Vec<MaskedVersions>>,
+}
+
+impl PackageMask {
+    /// read profiles/package.mask of a tree
+    /// a tree without one masks nothing
+    ///
+    /// @param root  ebuild tree root
+    pub fn load(root: &Path) -> Self {
+        match std::fs::read_to_string(root.join("profiles").join("package.mask")) {
+            Ok(content) => Self::parse(&content),
+            Err(_) => Self::default(),
+        }
+    }
+
+    /// parse the content of a package.mask file
+    pub fn parse(content: &str) -> Self {
+        let mut atoms: HashMap<String, Vec<MaskedVersions>> = HashMap::new();
+        for line in content.lines() {
+            let atom = line.split('#').next().unwrap_or_default().trim();
+            // slots and USE dependencies can't be checked without parsing ebuilds
+            if atom.is_empty() || atom.contains([':', '[']) {
+                continue;
+            }
+
+            let parsed = if let Some(cpv) = atom.strip_prefix('=') {
+                match cpv.strip_suffix('*') {
+                    Some(cpv) => split_version(cpv)
+                        .map(|(cp, version)| (cp, MaskedVersions::Prefix(version.to_string()))),
+                    None => split_version(cpv)
+                        .map(|(cp, version)| (cp, MaskedVersions::Exact(version.to_string()))),
+                }
+            } else if let Some(cpv) = atom.strip_prefix('~') {
+                split_version(cpv).map(|(cp, version)| {
+                    (
+                        cp,
+                        MaskedVersions::AnyRevision(strip_revision(version).to_string()),
+                    )
+                })
+            } else if atom.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+                Some((atom, MaskedVersions::All))
+            } else {
+                // range atoms and -atom unmasks of overlays
+                None
+            };
+
+            if let Some((cp, versions)) = parsed
+                && cp.contains('/')
+            {
+                atoms.entry(cp.to_string()).or_default().push(versions);
+            }
+        }
+        Self { atoms }
+    }
+
+    /// whether every ebuild of a package is masked
+    /// packages without ebuilds are never masked
+    ///
+    /// @param package  directory of the package i.e. <root>/category/package
+    pub fn covers(&self, package: &Path) -> bool {
+        let (Some(name), Some(category)) = (
+            package.file_name().map(|name| name.to_string_lossy()),
+            package
+                .parent()
+                .and_then(Path::file_name)
+                .map(|category| category.to_string_lossy()),
+        ) else {
+            return false;
+        };
+        let atoms = match self.atoms.get(&format!("{}/{}", category, name)) {
+            Some(atoms) => atoms,
+            None => return false,
+        };
+
+        let prefix = format!("{}-", name);
+        let versions: Vec<String> = std::fs::read_dir(package)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| {
+                entry
+                    .file_name()
+                    .to_string_lossy()
+                    .strip_suffix(".ebuild")
+                    .and_then(|file| file.strip_prefix(&prefix))
+                    .map(str::to_string)
+            })
+            .collect();
+
+        !versions.is_empty()
+            && versions
+                .iter()
+                .all(|version| atoms.iter().any(|atom| atom.covers(version)))
+    }
+
+    /// whether nothing is masked
+    pub fn is_empty(&self) -> bool {
+        self.atoms.is_empty()
+    }
+}
+
+/// categories or packages of a tree that get indexed
+/// patterns are category globs like "sci-*" or package globs like "games-*/*" or "dev-lang/rust"
+/// supporting * and ?, a package is indexed if it matches an include pattern
+/// (or none are given) and no exclude pattern
+#[derive(Clone, Debug, Default)]
+pub struct PackageFilter {
+    /// patterns of indexed categories or packages, empty includes everything
+    include: Vec<FilterPattern>,
+
+    /// patterns of categories or packages left out
+    exclude: Vec<FilterPattern>,
+}
+
+/// a single pattern of a PackageFilter
+#[derive(Clone, Debug)]
+struct FilterPattern {
+    /// glob of the category
+    category: String,
+
+    /// glob of the package, None matches every package of the category
+    package: Option<String>,
+}
+
+impl FilterPattern {
+    /// parse category or category/package
+    fn parse(pattern: &str) -> Result<Self, String> {
+        let (category, package) = match pattern.split_once('/') {
+            Some((category, package)) => (category, Some(package)),
+            None => (pattern, None),
+        };
+        if category.is_empty()
+            || package.is_some_and(|package| package.is_empty() || package.contains('/'))
+        {
+            return Err(format!(
+                "\"{}\" is no category or category/package pattern",
+                pattern
+            ));
+        }
+        Ok(Self {
+            category: category.to_string(),
+            package: package.map(str::to_string),
         })
     }
+
+    /// whether the pattern matches a package
+    fn matches(&self, category: &str, package: &str) -> bool {
+        utils::glob_match(&self.category, category)
+            && self
+                .package
+                .as_ref()
+                .is_none_or(|pattern| utils::glob_match(pattern, package))
+    }
+}
+
+impl PackageFilter {
+    /// create a filter from include and exclude patterns
+    /// returns None if both are empty i.e. everything gets indexed
+    ///
+    /// @param include  patterns of indexed categories or packages
+    /// @param exclude  patterns of categories or packages left out
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Option<Self>, String> {
+        if include.is_empty() && exclude.is_empty() {
+            return Ok(None);
+        }
+
+        let parse = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| FilterPattern::parse(pattern))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(Some(Self {
+            include: parse(include)?,
+            exclude: parse(exclude)?,
+        }))
+    }
+
+    /// whether packages of a category may get indexed
+    /// used to skip whole category directories without walking them
+    ///
+    /// @param category  name of the category
+    pub fn allows_category(&self, category: &str) -> bool {
+        let excluded = self.exclude.iter().any(|pattern| {
+            pattern.package.is_none() && utils::glob_match(&pattern.category, category)
+        });
+        let included = self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|pattern| utils::glob_match(&pattern.category, category));
+        included && !excluded
+    }
+
+    /// whether a package gets indexed
+    ///
+    /// @param package  directory of the package i.e. <root>/category/package
+    pub fn allows(&self, package: &Path) -> bool {
+        let (Some(name), Some(category)) = (
+            package.file_name().map(|name| name.to_string_lossy()),
+            package
+                .parent()
+                .and_then(Path::file_name)
+                .map(|category| category.to_string_lossy()),
+        ) else {
+            return true;
+        };
+
+        let included = self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|pattern| pattern.matches(&category, &name));
+        included
+            && !self
+                .exclude
+                .iter()
+                .any(|pattern| pattern.matches(&category, &name))
+    }
+}
+
+/// split cat/pkg-version into cat/pkg and version
+/// the version starts at the first hyphen followed by a valid version
+fn split_version(cpv: &str) -> Option<(&str, &str)> {
+    cpv.match_indices('-')
+        .map(|(index, _)| (&cpv[..index], &cpv[index + 1..]))
+        .find(|(_, version)| is_version(version))
+}
+
+/// version without its -rN revision
+fn strip_revision(version: &str) -> &str {
+    match version.rsplit_once("-r") {
+        Some((base, revision))
+            if !revision.is_empty() && revision.chars().all(|c| c.is_ascii_digit()) =>
+        {
+            base
+        }
+        _ => version,
+    }
+}
+
+/// whether text is a valid package version like 1.2.3b_rc1_p2-r1
+fn is_version(version: &str) -> bool {
+    let version = strip_revision(version);
+    let (base, suffixes) = version.split_once('_').unwrap_or((version, ""));
+
+    // numbers separated by dots with an optional trailing letter
+    let base = base
+        .strip_suffix(|c: char| c.is_ascii_lowercase())
+        .unwrap_or(base);
+    let numbers_valid = base
+        .split('.')
+        .all(|number| !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()));
+
+    numbers_valid
+        && (suffixes.is_empty()
+            || suffixes.split('_').all(|suffix| {
+                ["alpha", "beta", "pre", "rc", "p"].iter().any(|kind| {
+                    suffix
+                        .strip_prefix(kind)
+                        .is_some_and(|number| number.chars().all(|c| c.is_ascii_digit()))
+                })
+            }))
 }
 
 /// walk through Manifest files in a ebuild tree
 pub struct ManifestWalker {
     /// ebuild tree root
     root: PathBuf,
+
+    /// depth Manifests are looked for at
+    depth: ManifestDepth,
+
+    /// packages whose Manifests get skipped
+    mask: Option<Arc<PackageMask>>,
+
+    /// categories and packages that get indexed
+    filter: Option<Arc<PackageFilter>>,
+
+    /// top level directories walked at the same time
+    concurrency: usize,
 }
 
 impl ManifestWalker {
     /// create a new ManifestWalker
     ///
-    /// @param root  ebuild tree root
-    /// @return      Self on success, Error when tree invalid
-    pub fn new(root: PathBuf) -> Result<Self, String> {
+    /// @param root    ebuild tree root
+    /// @param layout  how a tree without metadata/layout.conf is treated
+    /// @param depth   depth Manifests are looked for at
+    /// @return        Self on success, Error when tree invalid
+    pub fn new(root: PathBuf, layout: LayoutCheck, depth: ManifestDepth) -> Result<Self, String> {
         // in a valid tree we expect metadata/layout.conf to exist
         let layout_conf = PathBuf::from_iter([
             root.clone().as_os_str(),
@@ -124,65 +486,264 @@ impl ManifestWalker {
         ]);
 
         if !layout_conf.is_file() {
-            return Err("Could not find metadata/layout.conf in repo root \
-                - this doesn't look like a valid repo"
-                .to_string());
+            match layout {
+                LayoutCheck::Require => {
+                    return Err("Could not find metadata/layout.conf in repo root \
+                        - this doesn't look like a valid repo \
+                        (set layout_conf = \"warn\" in its repo.repos entry if that's expected)"
+                        .to_string());
+                }
+                LayoutCheck::Warn => eprintln!(
+                    "WARNING: No metadata/layout.conf in {} - indexing it anyway",
+                    root.to_string_lossy()
+                ),
+                LayoutCheck::Ignore => (),
+            }
         }
 
-        Ok(Self { root })
+        Ok(Self {
+            root,
+            depth,
+            mask: None,
+            filter: None,
+            concurrency: 1,
+        })
+    }
+
+    /// skip Manifests of packages hard-masked in profiles/package.mask
+    ///
+    /// @param skip  whether to skip them
+    pub fn skip_masked(mut self, skip: bool) -> Self {
+        self.mask = skip
+            .then(|| PackageMask::load(&self.root))
+            .filter(|mask| !mask.is_empty())
+            .map(Arc::new);
+        self
+    }
+
+    /// only walk categories and packages the filter allows
+    ///
+    /// @param filter  the repo's filter, None walks everything
+    pub fn filter(mut self, filter: Option<Arc<PackageFilter>>) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// walk this many top level (category) directories at the same time
+    /// entries still come out in the order of the directories
+    ///
+    /// @param concurrency  number of directories, 0 counts as 1
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
     }
 
     /// get a stream of all Manifest entries in the tree
+    /// the top level directories are walked on blocking threads
+    /// up to concurrency at a time, their entries are yielded in sorted order
     /// TODO: might be useful to return errors
     pub fn entries(&mut self) -> impl Stream<Item = ManifestEntry> {
         stream! {
-            // initialise walkdir
-            // Manifests are always exactly at the 2nd level (category/package/Manifest)
-            let mut candidates = WalkDir::new(self.root.as_os_str())
-                .min_depth(3)
-                .max_depth(3)
-                .into_iter();
-
-            while let Some(file) = candidates.next() {
-                let manifest = match file {
-                    Ok(x) if x.file_name() == "Manifest" => PathBuf::from(x.path()),
-                    Ok(_) => continue,
-                    Err(_) => continue,
-                };
-
-                // create new line reader
-                let mut lines = match fs::File::open(&manifest).await {
-                    Ok(x) => io::BufReader::new(x).lines(),
-                    Err(_) => continue,
-                };
-
-                // parse each line
-                // on IO error the entire Manifest gets skipped
-                // on parse error skip line
-                loop {
-                    let line = match lines.next_line().await {
-                        Err(e) => {
-                            // IO Error
-                            eprintln!("IO error while parsing {}: {}", manifest.to_string_lossy(), e.to_string());
-                            break;
-                        },
-                        Ok(maybe_eof) => match maybe_eof {
-                            None => break, // EOF
-                            Some(line) => line
+            // hidden directories like .git never hold Manifests
+            let mut tops = Vec::new();
+            match fs::read_dir(&self.root).await {
+                Ok(mut dir) => {
+                    while let Ok(Some(entry)) = dir.next_entry().await {
+                        if !entry.file_name().to_string_lossy().starts_with('.') {
+                            tops.push(entry.path());
                         }
-                    };
+                    }
+                }
+                Err(e) => eprintln!("Failed to list {}: {}", self.root.to_string_lossy(), e),
+            }
+            tops.sort_unstable();
 
-                    let ret = match ManifestEntry::parse(&manifest, &line) {
-                        Ok(entry) => entry,
-                        Err(e) => {
-                            eprintln!("Parser error while parsing {}: {}", manifest.to_string_lossy(), e.to_string());
-                            continue;
-                        },
-                    };
+            let depth = self.depth;
+            let mask = self.mask.clone();
+            let filter = self.filter.clone();
+            // categories filtered out aren't walked at all
+            tops.retain(|top| {
+                filter.as_ref().is_none_or(|filter| {
+                    top.file_name()
+                        .is_none_or(|category| filter.allows_category(&category.to_string_lossy()))
+                })
+            });
+            let mut walks = futures::stream::iter(tops)
+                .map(|top| {
+                    let mask = mask.clone();
+                    let filter = filter.clone();
+                    tokio::task::spawn_blocking(move || {
+                        walk_top(&top, depth, mask.as_deref(), filter.as_deref())
+                    })
+                })
+                .buffered(self.concurrency);
 
-                    yield ret;
+            let mut skipped = 0;
+            let mut filtered = 0;
+            while let Some(walk) = walks.next().await {
+                match walk {
+                    Ok(walk) => {
+                        skipped += walk.skipped;
+                        filtered += walk.filtered;
+                        for entry in walk.entries {
+                            yield entry;
+                        }
+                    }
+                    Err(e) => eprintln!("Manifest walk of {} failed: {}", self.root.to_string_lossy(), e),
                 }
             }
+
+            if skipped > 0 {
+                println!(
+                    "Skipped {} hard-masked packages in {}",
+                    skipped,
+                    self.root.to_string_lossy()
+                );
+            }
+            if filtered > 0 {
+                println!(
+                    "Skipped {} filtered packages in {}",
+                    filtered,
+                    self.root.to_string_lossy()
+                );
+            }
+        }
+    }
+}
+
+/// Manifest entries below a top level directory of a tree
+struct TopWalk {
+    /// entries of all Manifests in walk order
+    entries: Vec<ManifestEntry>,
+
+    /// hard-masked packages left out
+    skipped: usize,
+
+    /// packages left out by the repo's filter
+    filtered: usize,
+}
+
+/// parse all Manifests below a top level entry of a tree
+/// blocks so it's expected to run via spawn_blocking
+///
+/// @param top     directory (or file) directly below the tree root
+/// @param depth   depth below the tree root Manifests are looked for at
+/// @param mask    packages whose Manifests get skipped
+/// @param filter  categories and packages that get indexed
+fn walk_top(
+    top: &Path,
+    depth: ManifestDepth,
+    mask: Option<&PackageMask>,
+    filter: Option<&PackageFilter>,
+) -> TopWalk {
+    // depths of walkdir are relative to top which is one below the root
+    // min_depth isn't set as it would exempt shallower entries from filter_entry
+    let max_depth = match depth {
+        ManifestDepth::Exactly(depth) => depth.saturating_sub(1),
+        ManifestDepth::Any => usize::MAX,
+    };
+    let candidates = WalkDir::new(top)
+        .max_depth(max_depth)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() == 0 || !entry.file_name().to_string_lossy().starts_with('.')
+        });
+
+    let mut walk = TopWalk {
+        entries: Vec::new(),
+        skipped: 0,
+        filtered: 0,
+    };
+    for file in candidates {
+        let manifest = match file {
+            Ok(x) if x.file_name() == "Manifest" && depth.matches(x.depth() + 1) => {
+                PathBuf::from(x.path())
+            }
+            Ok(_) => continue,
+            Err(_) => continue,
+        };
+
+        if let Some(filter) = filter
+            && let Some(package) = manifest.parent()
+            && !filter.allows(package)
+        {
+            walk.filtered += 1;
+            continue;
+        }
+
+        if let Some(mask) = mask
+            && let Some(package) = manifest.parent()
+            && mask.covers(package)
+        {
+            walk.skipped += 1;
+            continue;
+        }
+
+        let content = match std::fs::read_to_string(&manifest) {
+            Ok(content) => content,
+            Err(e) => {
+                eprintln!(
+                    "IO error while parsing {}: {}",
+                    manifest.to_string_lossy(),
+                    e
+                );
+                continue;
+            }
+        };
+        for line in content.lines() {
+            match ManifestEntry::parse(&manifest, &line.to_string()) {
+                Ok(Some(entry)) => walk.entries.push(entry),
+                Ok(None) => (),
+                Err(e) => {
+                    eprintln!(
+                        "Parser error while parsing {}: {}",
+                        manifest.to_string_lossy(),
+                        e
+                    )
+                }
+            }
+        }
+    }
+
+    walk
+}
+
+/// get a stream of all entries in a single Manifest file
+/// on IO error the rest of the Manifest gets skipped
+/// on parse error the line gets skipped
+pub fn manifest_entries(manifest: PathBuf) -> impl Stream<Item = ManifestEntry> {
+    stream! {
+        // create new line reader
+        let mut lines = match fs::File::open(&manifest).await {
+            Ok(x) => io::BufReader::new(x).lines(),
+            Err(_) => return,
+        };
+
+        // parse each line
+        loop {
+            let line = match lines.next_line().await {
+                Err(e) => {
+                    // IO Error
+                    eprintln!("IO error while parsing {}: {}", manifest.to_string_lossy(), e);
+                    break;
+                },
+                Ok(maybe_eof) => match maybe_eof {
+                    None => break, // EOF
+                    Some(line) => line
+                }
+            };
+
+            let ret = match ManifestEntry::parse(&manifest, &line) {
+                Ok(Some(entry)) => entry,
+                Ok(None) => continue,
+                Err(e) => {
+                    eprintln!("Parser error while parsing {}: {}", manifest.to_string_lossy(), e);
+                    continue;
+                },
+            };
+
+            yield ret;
         }
     }
 }
@@ -0,0 +1,108 @@
+use bytes::Bytes;
+use moka::sync::Cache;
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use crate::config::Config;
+
+/// content of a file kept in memory along with what identifies its version on disk
+struct Cached {
+    /// device and inode of the file
+    id: (u64, u64),
+
+    /// mtime of the file
+    modified: Option<SystemTime>,
+
+    /// the file as stored
+    content: Bytes,
+}
+
+/// read-through LRU of small files served often, e.g. patches
+/// entries are keyed by path and only used while the file on disk is the same,
+/// so replaced or refetched files are read again without explicit invalidation
+pub struct MemoryCache {
+    /// cached files by path
+    files: Cache<PathBuf, Arc<Cached>>,
+
+    /// files larger than this aren't cached
+    max_file: u64,
+
+    /// requests served from memory
+    pub hits: AtomicU64,
+
+    /// requests for cacheable files read from disk
+    pub misses: AtomicU64,
+}
+
+impl MemoryCache {
+    /// create a MemoryCache from config
+    /// returns None unless storage.memory_cache is set
+    ///
+    /// @param config  a reference to Config
+    pub fn new(config: &Config) -> Option<Self> {
+        let size = config.storage.memory_cache?;
+        let files = Cache::builder()
+            .max_capacity(size)
+            .weigher(|_, cached: &Arc<Cached>| {
+                u32::try_from(cached.content.len()).unwrap_or(u32::MAX)
+            })
+            .build();
+        Some(Self {
+            files,
+            max_file: config.storage.memory_cache_max_file,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    /// whether a file of this size gets cached
+    ///
+    /// @param size  size of the file
+    pub fn fits(&self, size: u64) -> bool {
+        size <= self.max_file
+    }
+
+    /// content of a cached file if it's still the one on disk
+    ///
+    /// @param path      location of the file
+    /// @param metadata  current metadata of the file
+    pub fn get(&self, path: &Path, metadata: &Metadata) -> Option<Bytes> {
+        let cached = self.files.get(path)?;
+        if cached.id != (metadata.dev(), metadata.ino())
+            || cached.modified != metadata.modified().ok()
+            || cached.content.len() as u64 != metadata.len()
+        {
+            self.files.invalidate(path);
+            return None;
+        }
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(cached.content.clone())
+    }
+
+    /// cache a file just read from disk
+    ///
+    /// @param path      location of the file
+    /// @param metadata  metadata of the file when it was read
+    /// @param content   the file as stored
+    pub fn insert(&self, path: &Path, metadata: &Metadata, content: Bytes) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        self.files.insert(
+            path.to_path_buf(),
+            Arc::new(Cached {
+                id: (metadata.dev(), metadata.ino()),
+                modified: metadata.modified().ok(),
+                content,
+            }),
+        );
+    }
+
+    /// bytes currently cached
+    pub fn size(&self) -> u64 {
+        self.files.run_pending_tasks();
+        self.files.weighted_size()
+    }
+}
@@ -0,0 +1,149 @@
+use nix::unistd::{self, Gid, Group, Uid, User};
+use std::fs;
+use std::os::unix::fs::{MetadataExt, lchown};
+use std::path::Path;
+
+use crate::config::ServerConfig;
+
+/// unprivileged user portcache switches to once listening
+pub struct RunAs {
+    /// name of the user
+    user: String,
+
+    /// user id to switch to
+    uid: Uid,
+
+    /// group id to switch to
+    gid: Gid,
+}
+
+impl RunAs {
+    /// resolve server.user and server.group
+    /// returns None when no user is configured
+    pub fn new(config: &ServerConfig) -> Result<Option<Self>, String> {
+        match &config.user {
+            Some(user) => Self::resolve(user, config.group.as_deref()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// look up a user and group by name
+    /// the group defaults to the primary group of the user
+    ///
+    /// @param name   name of the user
+    /// @param group  name of the group
+    pub fn resolve(name: &str, group: Option<&str>) -> Result<Self, String> {
+        let user = User::from_name(name)
+            .map_err(|e| format!("Failed to look up user {}: {}", name, e))?
+            .ok_or_else(|| format!("User {} doesn't exist", name))?;
+
+        let gid = match group {
+            Some(group) => {
+                Group::from_name(group)
+                    .map_err(|e| format!("Failed to look up group {}: {}", group, e))?
+                    .ok_or_else(|| format!("Group {} doesn't exist", group))?
+                    .gid
+            }
+            None => user.gid,
+        };
+
+        Ok(Self {
+            user: user.name,
+            uid: user.uid,
+            gid,
+        })
+    }
+
+    /// user id to switch to
+    pub fn uid(&self) -> u32 {
+        self.uid.as_raw()
+    }
+
+    /// group id to switch to
+    pub fn gid(&self) -> u32 {
+        self.gid.as_raw()
+    }
+
+    /// hand everything below the storage root over to the user
+    /// so it stays writable after dropping privileges
+    /// directories are handed over after their contents, so those already owned
+    /// by the user are skipped instead of walking the whole storage on every start
+    ///
+    /// @param root  storage root
+    pub fn chown_storage(&self, root: &Path) -> Result<(), String> {
+        if !Uid::effective().is_root() {
+            return Ok(());
+        }
+
+        // the root may have been set up for the user by packaging
+        // so its entries get checked regardless of its owner
+        for entry in fs::read_dir(root).map_err(|e| e.to_string())? {
+            let entry = entry.map_err(|e| e.to_string())?;
+            self.chown_tree(&entry.path())?;
+        }
+        match self.owned(root)? {
+            true => Ok(()),
+            false => self.chown(root),
+        }
+    }
+
+    /// hand path and everything below it over to the user
+    /// unless it's owned by the user already
+    ///
+    /// @param path  file or directory to hand over
+    fn chown_tree(&self, path: &Path) -> Result<(), String> {
+        if self.owned(path)? {
+            return Ok(());
+        }
+
+        if path.is_dir() && !path.is_symlink() {
+            for entry in fs::read_dir(path).map_err(|e| e.to_string())? {
+                let entry = entry.map_err(|e| e.to_string())?;
+                self.chown_tree(&entry.path())?;
+            }
+        }
+        self.chown(path)
+    }
+
+    /// whether path is owned by the user and group already
+    ///
+    /// @param path  file or directory to check
+    fn owned(&self, path: &Path) -> Result<bool, String> {
+        let metadata = fs::symlink_metadata(path)
+            .map_err(|e| format!("Failed to stat {}: {}", path.to_string_lossy(), e))?;
+        Ok(metadata.uid() == self.uid() && metadata.gid() == self.gid())
+    }
+
+    /// change the owner of path to the user and group
+    ///
+    /// @param path  file or directory to hand over
+    fn chown(&self, path: &Path) -> Result<(), String> {
+        lchown(path, Some(self.uid()), Some(self.gid()))
+            .map_err(|e| format!("Failed to chown {}: {}", path.to_string_lossy(), e))
+    }
+
+    /// switch to the user for the rest of the process' life
+    /// does nothing when not running as root
+    pub fn drop_privileges(&self) -> Result<(), String> {
+        if !Uid::effective().is_root() {
+            if Uid::effective() != self.uid {
+                eprintln!("Not running as root - can't switch to user {}", self.user);
+            }
+            return Ok(());
+        }
+
+        // groups can only be changed while still root
+        unistd::setgroups(&[self.gid])
+            .map_err(|e| format!("Failed to set supplementary groups: {}", e))?;
+        unistd::setgid(self.gid).map_err(|e| format!("Failed to set group: {}", e))?;
+        unistd::setuid(self.uid).map_err(|e| format!("Failed to set user: {}", e))?;
+
+        // make sure there's no way back
+        if !self.uid.is_root() && unistd::setuid(Uid::from_raw(0)).is_ok() {
+            return Err("Privileges could be regained after dropping them".to_string());
+        }
+
+        println!("Dropped privileges to user {}", self.user);
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use crate::config::{Config, QuotaConfig};
+use crate::repo_db::{ApiKey, KeyUsage, RepoDB};
+use crate::utils;
+
+/// tracks bytes served per client subnet and API key and enforces soft quotas
+pub struct Quota {
+    /// quota settings
+    config: QuotaConfig,
+
+    /// limit of API keys issued without their own
+    key_limit: Option<u64>,
+
+    /// database usage gets recorded in
+    repo_db: Arc<RepoDB>,
+}
+
+/// usage of all subnets in the current window
+pub struct UsageReport {
+    /// start of the window as unix timestamp
+    pub window_start: u64,
+
+    /// length of the window in seconds
+    pub window: u64,
+
+    /// configured limit per subnet
+    pub limit: Option<u64>,
+
+    /// subnets and bytes served to them, heaviest first
+    pub clients: Vec<(String, u64)>,
+}
+
+/// usage of all API keys in the current window
+pub struct KeyReport {
+    /// start of the window as unix timestamp
+    pub window_start: u64,
+
+    /// length of the window in seconds
+    pub window: u64,
+
+    /// issued keys with their limit and usage, heaviest first
+    pub keys: Vec<(ApiKey, Option<u64>, KeyUsage)>,
+}
+
+impl Quota {
+    /// create a new Quota from config
+    pub fn new(config: &Config, repo_db: Arc<RepoDB>) -> Self {
+        Self {
+            config: config.quota.clone(),
+            key_limit: config.api_keys.default_limit,
+            repo_db,
+        }
+    }
+
+    /// subnet a client address gets aggregated into
+    /// e.g. 192.0.2.7 -> 192.0.2.0/24
+    pub fn subnet(&self, ip: IpAddr) -> String {
+        match ip {
+            IpAddr::V4(ip) => {
+                let prefix = self.config.ipv4_prefix.min(32);
+                let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                let net = std::net::Ipv4Addr::from(u32::from(ip) & mask);
+                format!("{}/{}", net, prefix)
+            }
+            IpAddr::V6(ip) => {
+                let prefix = self.config.ipv6_prefix.min(128);
+                let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+                let net = std::net::Ipv6Addr::from(u128::from(ip) & mask);
+                format!("{}/{}", net, prefix)
+            }
+        }
+    }
+
+    /// start of the quota window containing now
+    fn window_start(&self) -> u64 {
+        let now = utils::unix_time();
+        let window = self.config.window.as_secs().max(1);
+        now - now % window
+    }
+
+    /// whether a subnet used up its quota in the current window
+    /// database errors don't lock clients out
+    pub async fn exceeded(&self, subnet: &str) -> bool {
+        let limit = match self.config.limit {
+            Some(limit) => limit,
+            None => return false,
+        };
+
+        match self
+            .repo_db
+            .get_client_usage(subnet, self.window_start())
+            .await
+        {
+            Ok(used) => used >= limit,
+            Err(e) => {
+                eprintln!("Failed to query usage of {}: {}", subnet, e);
+                false
+            }
+        }
+    }
+
+    /// record bytes served to a subnet
+    pub async fn record(&self, subnet: &str, bytes: u64) {
+        if let Err(e) = self
+            .repo_db
+            .add_client_usage(subnet, self.window_start(), bytes)
+            .await
+        {
+            eprintln!("Failed to record usage of {}: {}", subnet, e);
+        }
+    }
+
+    /// usage of all subnets in the current window
+    pub async fn report(&self) -> Result<UsageReport, String> {
+        let window_start = self.window_start();
+        let clients = self
+            .repo_db
+            .get_window_usage(window_start)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(UsageReport {
+            window_start,
+            window: self.config.window.as_secs(),
+            limit: self.config.limit,
+            clients,
+        })
+    }
+
+    /// bytes an API key may be served per window
+    pub fn key_limit(&self, key: &ApiKey) -> Option<u64> {
+        key.limit.or(self.key_limit)
+    }
+
+    /// whether an API key used up its quota in the current window
+    /// database errors don't lock clients out
+    pub async fn key_exceeded(&self, key: &ApiKey) -> bool {
+        let limit = match self.key_limit(key) {
+            Some(limit) => limit,
+            None => return false,
+        };
+
+        match self
+            .repo_db
+            .get_key_usage(&key.name, self.window_start())
+            .await
+        {
+            Ok(used) => used >= limit,
+            Err(e) => {
+                eprintln!("Failed to query usage of key {}: {}", key.name, e);
+                false
+            }
+        }
+    }
+
+    /// record a file served with an API key
+    pub async fn record_key(&self, key: &ApiKey, bytes: u64) {
+        if let Err(e) = self
+            .repo_db
+            .add_key_usage(&key.name, self.window_start(), bytes, utils::unix_time())
+            .await
+        {
+            eprintln!("Failed to record usage of key {}: {}", key.name, e);
+        }
+    }
+
+    /// usage of all API keys in the current window
+    /// keys unused in the window are reported with zero usage
+    pub async fn key_report(&self) -> Result<KeyReport, String> {
+        let window_start = self.window_start();
+        let keys = self
+            .repo_db
+            .get_api_keys()
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut usage = self
+            .repo_db
+            .get_window_key_usage(window_start)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let mut keys: Vec<(ApiKey, Option<u64>, KeyUsage)> = keys
+            .into_iter()
+            .map(|key| {
+                let used = match usage.iter().position(|used| used.name == key.name) {
+                    Some(idx) => usage.swap_remove(idx),
+                    None => KeyUsage {
+                        name: key.name.clone(),
+                        bytes: 0,
+                        requests: 0,
+                    },
+                };
+                (key.clone(), self.key_limit(&key), used)
+            })
+            .collect();
+        keys.sort_by(|a, b| {
+            b.2.bytes
+                .cmp(&a.2.bytes)
+                .then_with(|| a.0.name.cmp(&b.0.name))
+        });
+
+        Ok(KeyReport {
+            window_start,
+            window: self.config.window.as_secs(),
+            keys,
+        })
+    }
+}
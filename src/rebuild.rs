@@ -0,0 +1,82 @@
+use std::sync::Arc;
+
+use crate::app::Deps;
+use crate::blob_storage::BlobStorage;
+use crate::config::Config;
+use crate::fetcher::manifest_mismatch;
+use crate::repo_db::RepoDB;
+use crate::repo_syncer::RepoSyncer;
+
+/// outcome of rebuilding the index
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct RebuildReport {
+    /// blobs matching their Manifest entry
+    pub verified: u64,
+
+    /// blobs not matching their Manifest entry which got marked stale
+    pub mismatched: u64,
+
+    /// blobs no Manifest mentions
+    pub unknown: u64,
+
+    /// blobs which couldn't be checked
+    pub failed: u64,
+}
+
+/// repopulate a lost database from what is on disk
+/// indexes the synced repos without syncing them
+/// and checks every cached blob against its Manifest entry
+///
+/// @param config  Config struct
+/// @param deps    components sharing the database
+pub async fn rebuild_index(config: &Config, deps: &Deps) -> Result<RebuildReport, String> {
+    let syncer = RepoSyncer::new(config, deps.repo_db.clone(), deps.sync_progress.clone()).await?;
+    syncer.reindex().await?;
+
+    verify_blobs(&deps.blob_storage, &deps.repo_db).await
+}
+
+/// recompute the checksums of all cached blobs and compare them
+/// with the indexed Manifest entries
+/// mismatching blobs get marked stale so they get refetched on the next request
+///
+/// @param blob_storage  storage holding the blobs
+/// @param repo_db       database with the Manifest entries
+pub async fn verify_blobs(
+    blob_storage: &Arc<BlobStorage>,
+    repo_db: &RepoDB,
+) -> Result<RebuildReport, String> {
+    let blobs = blob_storage.blobs().await?;
+    let files: Vec<String> = blobs.iter().map(|blob| blob.file.clone()).collect();
+    let entries = repo_db
+        .get_manifest_entries(&files)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let mut report = RebuildReport::default();
+    for file in files {
+        let entry = match entries.get(&file) {
+            Some(entry) => entry,
+            None => {
+                report.unknown += 1;
+                continue;
+            }
+        };
+
+        let path = blob_storage.blob_location(&file).await?;
+        match manifest_mismatch(&path, entry).await {
+            Ok(None) => report.verified += 1,
+            Ok(Some(mismatch)) => {
+                eprintln!("{} - marking stale", mismatch);
+                blob_storage.mark_stale(&file).await?;
+                report.mismatched += 1;
+            }
+            Err(e) => {
+                eprintln!("Failed to check {}: {}", file, e);
+                report.failed += 1;
+            }
+        }
+    }
+
+    Ok(report)
+}
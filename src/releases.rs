@@ -0,0 +1,443 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use rocket::http;
+use rocket::{State, get};
+use std::net::IpAddr;
+use std::path::{Component, Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+use tokio::process::Command;
+
+use crate::api_keys::ClientKey;
+use crate::app::SharedData;
+use crate::config::Config;
+use crate::fetcher::{FetchError, FetchErrorKind, download_part, tls};
+use crate::frontend::{self, IfModifiedSince, Refused, Served};
+use crate::request_id::{req_eprintln, req_println};
+use crate::telemetry::RequestTrace;
+use crate::utils::{self, HashType, PathLocks};
+
+/// suffixes of files describing release media
+/// these change in place and get refetched after metadata_ttl
+const METADATA_SUFFIXES: &[&str] = &[".asc", ".DIGESTS", ".sha256", ".txt"];
+
+/// caches Gentoo release media fetched from the releases/ directory of mirrors
+/// media only get stored once their signature was verified
+pub struct Releases {
+    /// urls of the releases/ directories without trailing slash
+    mirrors: Vec<String>,
+
+    /// root of the release cache
+    root: PathBuf,
+
+    /// binary keyring for gpgv, None disables verification
+    keyring: Option<PathBuf>,
+
+    /// gpgv binary
+    gpgv: PathBuf,
+
+    /// how long metadata is served from cache before refetching
+    metadata_ttl: Duration,
+
+    /// client for mirror requests
+    client: tls::Client,
+
+    /// locks serializing fetches of the same path
+    fetch_locks: PathLocks,
+}
+
+impl Releases {
+    /// create Releases from config
+    /// returns None without a [releases] section
+    pub async fn new(config: &Config) -> Result<Option<Self>, String> {
+        let releases = match &config.releases {
+            Some(releases) => releases,
+            None => return Ok(None),
+        };
+
+        if releases.mirrors.is_empty() {
+            return Err("Release mirror list is empty".to_string());
+        }
+
+        let root = config.storage.location.join("releases");
+        fs::create_dir_all(&root)
+            .await
+            .map_err(|e| format!("Cannot create {}: {}", root.to_string_lossy(), e))?;
+
+        // gpgv only reads binary keyrings
+        // while keys are usually distributed armored
+        let keyring = match releases.verify {
+            true => {
+                let keys = fs::read(&releases.keyring).await.map_err(|e| {
+                    format!("Cannot read {}: {}", releases.keyring.to_string_lossy(), e)
+                })?;
+                let keys = match std::str::from_utf8(&keys) {
+                    Ok(armored) if armored.contains("-----BEGIN PGP PUBLIC KEY BLOCK-----") => {
+                        dearmor(armored).map_err(|e| {
+                            format!("Bad keyring {}: {}", releases.keyring.to_string_lossy(), e)
+                        })?
+                    }
+                    _ => keys,
+                };
+
+                let keyring =
+                    std::path::absolute(config.storage.location.join("release-keyring.gpg"))
+                        .map_err(|e| e.to_string())?;
+                fs::write(&keyring, keys)
+                    .await
+                    .map_err(|e| format!("Cannot write {}: {}", keyring.to_string_lossy(), e))?;
+                Some(keyring)
+            }
+            false => None,
+        };
+
+        Ok(Some(Self {
+            mirrors: releases
+                .mirrors
+                .iter()
+                .map(|mirror| format!("{}/releases", mirror.trim_end_matches('/')))
+                .collect(),
+            root,
+            keyring,
+            gpgv: releases.gpgv.clone(),
+            metadata_ttl: releases.metadata_ttl,
+            client: tls::Client::new(&config.fetcher.tls)?,
+            fetch_locks: PathLocks::default(),
+        }))
+    }
+
+    /// get a release file from cache or fetch it from the mirrors
+    ///
+    /// @param release  path relative to releases/
+    pub async fn get(&self, release: &Path) -> Result<PathBuf, FetchError> {
+        // partial downloads live next to the files they become
+        if release.as_os_str().is_empty()
+            || release.to_string_lossy().ends_with(".part")
+            || !release
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(FetchError::new(
+                FetchErrorKind::Rejected,
+                format!("Bad release path: {}", release.to_string_lossy()),
+            ));
+        }
+
+        if is_metadata(release) {
+            self.metadata(release).await
+        } else {
+            self.media(release).await
+        }
+    }
+
+    /// get a metadata file, refetched once older than metadata_ttl
+    /// the cached one keeps getting served if all mirrors fail
+    async fn metadata(&self, release: &Path) -> Result<PathBuf, FetchError> {
+        let path = self.root.join(release);
+        let _lock = self.fetch_locks.lock(&path).await;
+
+        let age = fs::metadata(&path)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+        if let Some(age) = age
+            && age < self.metadata_ttl
+        {
+            return Ok(path);
+        }
+
+        let mut errors = Vec::new();
+        for mirror in &self.mirrors {
+            let url = format!("{}/{}", mirror, release.to_string_lossy());
+            match download_part(&self.client, &url, &path).await {
+                Ok((part, _)) => {
+                    fs::rename(&part, &path)
+                        .await
+                        .map_err(|e| FetchError::from(e.to_string()))?;
+                    return Ok(path);
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+
+        let error = FetchError::combine(
+            &errors,
+            format!("No mirror had {}", release.to_string_lossy()),
+        );
+        if age.is_some() && error.kind != FetchErrorKind::NotFound {
+            req_eprintln!(
+                "Failed to refresh {}, serving cached one: {}",
+                release.to_string_lossy(),
+                error
+            );
+            return Ok(path);
+        }
+        Err(error)
+    }
+
+    /// get release media, only stored once verified
+    async fn media(&self, release: &Path) -> Result<PathBuf, FetchError> {
+        let path = self.root.join(release);
+        let _lock = self.fetch_locks.lock(&path).await;
+        if path.is_file() {
+            req_println!("Cache hit on release {}", release.to_string_lossy());
+            return Ok(path);
+        }
+
+        let mut errors = Vec::new();
+        for mirror in &self.mirrors {
+            let url = format!("{}/{}", mirror, release.to_string_lossy());
+            let part = match download_part(&self.client, &url, &path).await {
+                Ok((part, _)) => part,
+                Err(e) => {
+                    errors.push(e);
+                    continue;
+                }
+            };
+
+            if let Err(e) = self.verify(release, &part).await {
+                req_eprintln!("Verification of {} failed: {}", url, e);
+                let _ = fs::remove_file(&part).await;
+                errors.push(e);
+                continue;
+            }
+
+            fs::rename(&part, &path)
+                .await
+                .map_err(|e| FetchError::from(e.to_string()))?;
+            return Ok(path);
+        }
+
+        Err(FetchError::combine(
+            &errors,
+            format!("Couldn't fetch {}", release.to_string_lossy()),
+        ))
+    }
+
+    /// verify downloaded media against the signed .DIGESTS next to it
+    /// falls back to a detached .asc signature
+    ///
+    /// @param release  path relative to releases/
+    /// @param part     location of the download
+    async fn verify(&self, release: &Path, part: &Path) -> Result<(), FetchError> {
+        let keyring = match &self.keyring {
+            Some(keyring) => keyring,
+            None => return Ok(()),
+        };
+
+        let name = release
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        // the .DIGESTS of a stage3 also cover its .CONTENTS.gz
+        let digests_of = name.strip_suffix(".CONTENTS.gz").unwrap_or(&name);
+        match self
+            .metadata(&release.with_file_name(format!("{}.DIGESTS", digests_of)))
+            .await
+        {
+            Ok(digests) => {
+                self.gpgv(keyring, &[&digests]).await?;
+                let digests = fs::read_to_string(&digests)
+                    .await
+                    .map_err(|e| FetchError::from(e.to_string()))?;
+                let (hash, expected) = signed_text(&digests)
+                    .and_then(|signed| parse_digests(&signed, &name))
+                    .ok_or_else(|| {
+                        FetchError::from(format!("{} isn't in its signed DIGESTS", name))
+                    })?;
+
+                let actual = utils::file_checksum(part, hash)
+                    .await
+                    .map_err(|e| FetchError::from(e.to_string()))?;
+                if actual != expected {
+                    return Err(FetchError::from(format!(
+                        "{:?} mismatch for {}: Expected {}, Got {}",
+                        hash, name, expected, actual
+                    )));
+                }
+                Ok(())
+            }
+            Err(e) if e.kind == FetchErrorKind::NotFound => {
+                let asc = self
+                    .metadata(&release.with_file_name(format!("{}.asc", name)))
+                    .await
+                    .map_err(|e| match e.kind {
+                        FetchErrorKind::NotFound => FetchError::from(format!(
+                            "{} has neither DIGESTS nor a signature",
+                            name
+                        )),
+                        _ => e,
+                    })?;
+                self.gpgv(keyring, &[&asc, part]).await
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// check signatures with gpgv
+    ///
+    /// @param keyring  binary keyring of trusted keys
+    /// @param files    signed file or detached signature followed by the data
+    async fn gpgv(&self, keyring: &Path, files: &[&Path]) -> Result<(), FetchError> {
+        let output = Command::new(&self.gpgv)
+            .arg("--keyring")
+            .arg(keyring)
+            .arg("--")
+            .args(files)
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .map_err(|e| {
+                FetchError::from(format!(
+                    "Failed to run {}: {}",
+                    self.gpgv.to_string_lossy(),
+                    e
+                ))
+            })?;
+
+        if !output.status.success() {
+            return Err(FetchError::from(format!(
+                "Bad signature on {}: {}",
+                files[0].to_string_lossy(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// whether a release file is metadata rather than media
+fn is_metadata(release: &Path) -> bool {
+    let release = release.to_string_lossy();
+    METADATA_SUFFIXES
+        .iter()
+        .any(|suffix| release.ends_with(suffix))
+}
+
+/// the signed part of a clearsigned message with dash-escaping undone
+/// text outside of it isn't covered by the signature
+///
+/// @param message  clearsigned message
+pub fn signed_text(message: &str) -> Option<String> {
+    let (_, rest) = message.split_once("-----BEGIN PGP SIGNED MESSAGE-----\n")?;
+    // armor headers like Hash: end with an empty line
+    let (_, rest) = rest.split_once("\n\n")?;
+    let (signed, _) = rest.split_once("-----BEGIN PGP SIGNATURE-----")?;
+
+    Some(
+        signed
+            .lines()
+            .map(|line| line.strip_prefix("- ").unwrap_or(line))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    )
+}
+
+/// checksum of a file listed in a DIGESTS file
+/// prefers BLAKE2B and falls back to SHA512
+///
+/// @param digests  content of the DIGESTS file
+/// @param file     name of the file to look up
+pub fn parse_digests(digests: &str, file: &str) -> Option<(HashType, String)> {
+    let mut hash = None;
+    let mut found = Vec::new();
+
+    for line in digests.lines() {
+        if let Some(header) = line.strip_prefix("# ") {
+            hash = match header.trim() {
+                "BLAKE2B HASH" => Some(HashType::Blake2b),
+                "SHA512 HASH" => Some(HashType::Sha512),
+                _ => None,
+            };
+            continue;
+        }
+
+        if let Some(hash) = hash
+            && let Some((digest, name)) = line.split_once(char::is_whitespace)
+            && name.trim() == file
+        {
+            found.push((hash, digest.to_lowercase()));
+        }
+    }
+
+    found
+        .iter()
+        .find(|(hash, _)| *hash == HashType::Blake2b)
+        .or_else(|| found.first())
+        .cloned()
+}
+
+/// convert ASCII armored OpenPGP keys to their binary form
+///
+/// @param armored  one or more armored key blocks
+pub fn dearmor(armored: &str) -> Result<Vec<u8>, String> {
+    let mut keys = Vec::new();
+    for block in armored
+        .split("-----BEGIN PGP PUBLIC KEY BLOCK-----")
+        .skip(1)
+    {
+        let (body, _) = block
+            .split_once("-----END PGP PUBLIC KEY BLOCK-----")
+            .ok_or("Unterminated key block")?;
+
+        // armor headers end with an empty line, the body with a =CRC24 line
+        let base64: String = body
+            .lines()
+            .skip(1)
+            .skip_while(|line| !line.trim().is_empty())
+            .map(str::trim)
+            .take_while(|line| !line.starts_with('='))
+            .collect();
+
+        keys.extend(STANDARD.decode(base64).map_err(|e| e.to_string())?);
+    }
+
+    if keys.is_empty() {
+        return Err("No key block found".to_string());
+    }
+    Ok(keys)
+}
+
+/// turn a failed release fetch into a status
+fn fetch_status(what: &str, e: FetchError) -> http::Status {
+    req_eprintln!("Release fetch of {} failed ({:?}): {}", what, e.kind, e);
+    match e.kind {
+        FetchErrorKind::NotFound | FetchErrorKind::Rejected => http::Status::NotFound,
+        FetchErrorKind::Transient | FetchErrorKind::Other => http::Status::BadGateway,
+    }
+}
+
+/// release media and their metadata, fetched from mirrors on a miss
+/// the served size gets accounted to the client's subnet and API key
+#[get("/releases/<release..>")]
+pub(crate) async fn releases(
+    release: PathBuf,
+    client: Option<IpAddr>,
+    key: ClientKey,
+    since: IfModifiedSince,
+    trace: RequestTrace,
+    shared: &State<SharedData>,
+) -> Result<Served, Refused> {
+    let releases = shared.releases.as_ref().ok_or(http::Status::NotFound)?;
+    let name = release.to_string_lossy();
+
+    trace
+        .within(frontend::open_accounted(
+            &name,
+            client,
+            key,
+            since,
+            shared,
+            async {
+                releases
+                    .get(&release)
+                    .await
+                    .map_err(|e| fetch_status(&name, e))
+            },
+        ))
+        .await
+}
@@ -1,14 +1,497 @@
 use futures::lock::Mutex;
+use moka::sync::Cache;
+use rusqlite::OptionalExtension;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use crate::config;
+use crate::ebuild_parser::SrcUriObj;
+use crate::fetcher::part_location;
 use crate::manifest_walker::ManifestEntry;
+use crate::utils::{self, HashType};
 
+/// schema migrations applied in order on top of the initial tables
+/// the database's user_version tracks how many have been applied
+const MIGRATIONS: &[&str] = &[
+    // 1: repo provenance of manifest entries
+    "ALTER TABLE manifest ADD COLUMN repo TEXT",
+    // 2: blobs marked for revalidation
+    "CREATE TABLE stale_blob (
+        file    TEXT PRIMARY KEY NOT NULL,
+        since   INTEGER NOT NULL
+    )",
+    // 3: bytes served per client subnet and quota window
+    "CREATE TABLE client_usage (
+        subnet  TEXT NOT NULL,
+        window  INTEGER NOT NULL,
+        bytes   INTEGER NOT NULL,
+        PRIMARY KEY (subnet, window)
+    )",
+    // 4: disk usage of repo checkouts
+    "CREATE TABLE repo_stats (
+        name            TEXT PRIMARY KEY NOT NULL,
+        path            TEXT NOT NULL,
+        checkout_size   INTEGER NOT NULL,
+        git_size        INTEGER NOT NULL,
+        updated         INTEGER NOT NULL
+    )",
+    // 5: missing checksums used to be stored as the string "NULL"
+    "UPDATE manifest SET blake2b = NULL WHERE blake2b = 'NULL';
+    UPDATE manifest SET sha512 = NULL WHERE sha512 = 'NULL'",
+    // 6: when entries were last seen in a synced tree
+    "ALTER TABLE manifest ADD COLUMN seen INTEGER NOT NULL DEFAULT 0;
+    CREATE TABLE sync_state (
+        key     TEXT PRIMARY KEY NOT NULL,
+        value   TEXT NOT NULL
+    )",
+    // 7: helper results per ebuild so unchanged ebuilds aren't parsed again
+    "CREATE TABLE parsed_ebuild (
+        path    TEXT PRIMARY KEY NOT NULL,
+        hash    TEXT NOT NULL,
+        src_uri TEXT NOT NULL
+    )",
+    // 8: USE conditionals gating SRC_URIs, space separated
+    "ALTER TABLE src_uri ADD COLUMN use_cond TEXT",
+    // 9: ebuilds the portage helper failed on
+    "CREATE TABLE parse_failure (
+        path            TEXT PRIMARY KEY NOT NULL,
+        hash            TEXT NOT NULL,
+        error           TEXT NOT NULL,
+        attempts        INTEGER NOT NULL,
+        last_attempt    INTEGER NOT NULL
+    )",
+    // 10: outcome of the last syncs per repo
+    "CREATE TABLE repo_sync (
+        name            TEXT PRIMARY KEY NOT NULL,
+        last_success    INTEGER,
+        last_failure    INTEGER,
+        error           TEXT
+    )",
+    // 11: API keys and their usage per quota window
+    "CREATE TABLE api_key (
+        name            TEXT PRIMARY KEY NOT NULL,
+        hash            TEXT UNIQUE NOT NULL,
+        byte_limit      INTEGER,
+        created         INTEGER NOT NULL,
+        last_used       INTEGER
+    );
+    CREATE TABLE key_usage (
+        name            TEXT NOT NULL REFERENCES api_key(name) ON DELETE CASCADE,
+        window          INTEGER NOT NULL,
+        bytes           INTEGER NOT NULL,
+        requests        INTEGER NOT NULL,
+        PRIMARY KEY (name, window)
+    )",
+    // 12: when entries were first seen, entries from before count as always known
+    "ALTER TABLE manifest ADD COLUMN added INTEGER NOT NULL DEFAULT 0;
+    CREATE INDEX manifest_added ON manifest(added)",
+    // 13: completed and failed upstream downloads
+    "CREATE TABLE download (
+        id              INTEGER PRIMARY KEY,
+        file            TEXT NOT NULL,
+        outcome         TEXT NOT NULL,
+        fetcher         TEXT,
+        source          TEXT,
+        started         INTEGER NOT NULL,
+        duration        REAL NOT NULL,
+        bytes           INTEGER,
+        error           TEXT,
+        request_id      TEXT
+    );
+    CREATE INDEX download_started ON download(started)",
+    // 14: distfiles served from the blob of another one with the same content
+    "CREATE TABLE blob_alias (
+        file            TEXT PRIMARY KEY NOT NULL,
+        blob            TEXT NOT NULL,
+        since           INTEGER NOT NULL
+    );
+    CREATE INDEX blob_alias_blob ON blob_alias(blob);
+    CREATE INDEX manifest_blake2b ON manifest(blake2b)",
+    // 15: checksums of distfiles without Manifest hashes seen on their first download
+    "CREATE TABLE tofu_checksum (
+        file            TEXT PRIMARY KEY NOT NULL,
+        size            INTEGER NOT NULL,
+        blake2b         TEXT NOT NULL,
+        recorded        INTEGER NOT NULL,
+        mismatches      INTEGER NOT NULL DEFAULT 0,
+        last_mismatch   INTEGER,
+        mismatch_blake2b TEXT
+    )",
+    // 16: upstream each cached blob was downloaded from
+    "CREATE TABLE blob_source (
+        file            TEXT PRIMARY KEY NOT NULL,
+        fetcher         TEXT NOT NULL,
+        source          TEXT,
+        fetched         INTEGER NOT NULL,
+        request_id      TEXT
+    )",
+    // 17: recent errors of the repo syncer per repo, repeats of an error counted in one row
+    "CREATE TABLE sync_error (
+        id              INTEGER PRIMARY KEY,
+        repo            TEXT NOT NULL,
+        phase           TEXT NOT NULL,
+        error           TEXT NOT NULL,
+        first           INTEGER NOT NULL,
+        last            INTEGER NOT NULL,
+        count           INTEGER NOT NULL
+    );
+    CREATE INDEX sync_error_repo ON sync_error(repo, last)",
+    // 18: last access of cached blobs independent of filesystem atime
+    "CREATE TABLE blob_access (
+        file            TEXT PRIMARY KEY NOT NULL,
+        accessed        INTEGER NOT NULL
+    )",
+    // 19: distfiles served to clients along with their provenance
+    "CREATE TABLE served (
+        id              INTEGER PRIMARY KEY,
+        file            TEXT NOT NULL,
+        served          INTEGER NOT NULL,
+        client          TEXT,
+        api_key         TEXT,
+        bytes           INTEGER NOT NULL,
+        blake2b         TEXT,
+        sha512          TEXT,
+        package         TEXT,
+        upstream        TEXT,
+        request_id      TEXT
+    );
+    CREATE INDEX served_served ON served(served)",
+    // 20: all hashes declared in the Manifest as space separated names and checksums
+    "ALTER TABLE manifest ADD COLUMN hashes TEXT",
+];
+
+/// sync_state key of the start time of the last complete walk of all trees
+const TREE_SWEEP: &str = "tree_sweep";
+
+/// sync_state key prefix of the last commit of a repo that got indexed
+const INDEXED_COMMIT: &str = "indexed_commit:";
+
+/// sync_state key of the hash directory length the blob storage uses
+const HASH_BITS: &str = "hash_bits";
+
+/// a distfile first referenced by a Manifest recently
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct NewDistfile {
+    /// file name
+    pub file: String,
+
+    /// size in bytes as declared in the Manifest
+    pub size: u64,
+
+    /// name of the repo it showed up in
+    pub repo: Option<String>,
+
+    /// when it was first seen as unix timestamp
+    pub added: u64,
+}
+
+/// disk usage of a repo checkout
+pub struct RepoStats {
+    /// name of the repo
+    pub name: String,
+
+    /// location of the checkout
+    pub path: PathBuf,
+
+    /// size of the working tree in bytes
+    pub checkout_size: u64,
+
+    /// size of the .git directory in bytes
+    pub git_size: u64,
+
+    /// time of the measurement as unix timestamp
+    pub updated: u64,
+
+    /// time of the last successful sync as unix timestamp
+    pub last_sync: Option<u64>,
+
+    /// time of the last failed sync as unix timestamp
+    pub last_sync_failure: Option<u64>,
+
+    /// error of the last sync if it failed
+    pub sync_error: Option<String>,
+}
+
+/// an issued API key, the key itself is only stored hashed
+#[derive(Clone, Debug)]
+pub struct ApiKey {
+    /// name the key was issued under
+    pub name: String,
+
+    /// bytes the key may be served per quota window, None for api_keys.default_limit
+    pub limit: Option<u64>,
+
+    /// time of issuance as unix timestamp
+    pub created: u64,
+
+    /// time of the last request with the key as unix timestamp
+    pub last_used: Option<u64>,
+}
+
+/// usage of an API key in a quota window
+pub struct KeyUsage {
+    /// name of the key
+    pub name: String,
+
+    /// bytes served with the key
+    pub bytes: u64,
+
+    /// files served with the key
+    pub requests: u64,
+}
+
+/// seconds before the first retry of an ebuild the helper failed on
+/// doubles with every further failure
+const PARSE_RETRY_BASE: u64 = 60 * 60;
+
+/// upper bound of the retry backoff of failed ebuilds
+const PARSE_RETRY_MAX: u64 = 7 * 24 * 60 * 60;
+
+/// an ebuild the portage helper failed on
+pub struct ParseFailure {
+    /// path of the ebuild
+    pub path: String,
+
+    /// content hash of the ebuild when it failed
+    pub hash: String,
+
+    /// error reported by the helper
+    pub error: String,
+
+    /// number of consecutive failures
+    pub attempts: u64,
+
+    /// time of the last failure as unix timestamp
+    pub last_attempt: u64,
+}
+
+impl ParseFailure {
+    /// earliest time as unix timestamp the unchanged ebuild gets parsed again
+    pub fn retry_at(&self) -> u64 {
+        let exponent = self.attempts.saturating_sub(1).min(32) as u32;
+        let backoff = PARSE_RETRY_BASE
+            .saturating_mul(2u64.saturating_pow(exponent))
+            .min(PARSE_RETRY_MAX);
+        self.last_attempt + backoff
+    }
+}
+
+/// an upstream download in the download history
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Download {
+    /// position in the history, assigned on insert
+    pub id: i64,
+
+    /// name of the distfile
+    pub file: String,
+
+    /// "fetched", "imported" or "failed"
+    pub outcome: String,
+
+    /// fetcher that produced the blob, the last one tried on failure
+    pub fetcher: Option<String>,
+
+    /// url the blob was downloaded from if the fetcher told
+    pub source: Option<String>,
+
+    /// start of the download as unix timestamp
+    pub started: u64,
+
+    /// seconds the download took including all fetchers tried
+    pub duration: f64,
+
+    /// size of the blob
+    pub bytes: Option<u64>,
+
+    /// why the download failed
+    pub error: Option<String>,
+
+    /// id of the request that caused it, None for background fetches like prefetching
+    pub request_id: Option<String>,
+}
+
+/// checksum of a distfile without Manifest hashes recorded on its first download
+/// later downloads have to match it
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct TofuChecksum {
+    /// name of the distfile
+    pub file: String,
+
+    /// size of the first download
+    pub size: u64,
+
+    /// BLAKE2B of the first download
+    pub blake2b: String,
+
+    /// when the first download happened as unix timestamp
+    pub recorded: u64,
+
+    /// number of later downloads with other content
+    pub mismatches: u64,
+
+    /// when the last of these happened as unix timestamp
+    pub last_mismatch: Option<u64>,
+
+    /// BLAKE2B of the last of these
+    pub mismatch_blake2b: Option<String>,
+}
+
+/// an error the repo syncer ran into for a repo
+/// the same error in consecutive cycles is counted instead of recorded again
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct SyncError {
+    /// name of the repo
+    pub repo: String,
+
+    /// step of the sync cycle that failed, "sync" or "manifests"
+    pub phase: String,
+
+    /// what went wrong
+    pub error: String,
+
+    /// first occurrence as unix timestamp
+    pub first: u64,
+
+    /// latest occurrence as unix timestamp
+    pub last: u64,
+
+    /// number of occurrences in a row
+    pub count: u64,
+}
+
+/// where a cached blob came from
+/// kept as long as the blob unlike the download history
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct BlobSource {
+    /// fetcher that produced the blob, "Import" for blobs from the drop directory
+    pub fetcher: String,
+
+    /// url the blob was downloaded from if the fetcher told
+    pub source: Option<String>,
+
+    /// when the download finished as unix timestamp
+    pub fetched: u64,
+
+    /// id of the request that caused the download
+    pub request_id: Option<String>,
+}
+
+/// a distfile served to a client with what it was and where it came from
+/// checksums and upstream are recorded as of the time it was served
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ServedDistfile {
+    /// name of the distfile
+    pub file: String,
+
+    /// when it was served as unix timestamp
+    pub served: u64,
+
+    /// address of the client if known
+    pub client: Option<String>,
+
+    /// name of the API key the client sent
+    pub api_key: Option<String>,
+
+    /// bytes served
+    pub bytes: u64,
+
+    /// blake2b checksum from the Manifest
+    pub blake2b: Option<String>,
+
+    /// sha512 checksum from the Manifest
+    pub sha512: Option<String>,
+
+    /// package whose Manifest lists it as category/package
+    pub package: Option<String>,
+
+    /// url the blob was downloaded from if known
+    pub upstream: Option<String>,
+
+    /// id of the request it was served to
+    pub request_id: Option<String>,
+}
+
+/// filters of a download history query
+#[derive(Clone, Debug, Default)]
+pub struct DownloadFilter {
+    /// only files containing this
+    pub file: Option<String>,
+
+    /// only downloads with this outcome
+    pub outcome: Option<String>,
+
+    /// only downloads started at or after this unix timestamp
+    pub since: Option<u64>,
+
+    /// only downloads started before this unix timestamp
+    pub until: Option<u64>,
+}
+
+/// replace the database in the storage root with a snapshot
+/// the snapshot has to pass an integrity check first
+/// the server must not be running meanwhile
+///
+/// @param config    Config struct
+/// @param snapshot  snapshot written by RepoDB::backup
+pub fn restore(config: &config::Config, snapshot: &Path) -> Result<(), String> {
+    let source =
+        rusqlite::Connection::open_with_flags(snapshot, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(|e| format!("Cannot open {}: {}", snapshot.to_string_lossy(), e))?;
+    let integrity: String = source
+        .query_row("PRAGMA integrity_check", (), |row| row.get(0))
+        .map_err(|e| format!("Cannot check {}: {}", snapshot.to_string_lossy(), e))?;
+    if integrity != "ok" {
+        return Err(format!(
+            "Snapshot {} is corrupt: {}",
+            snapshot.to_string_lossy(),
+            integrity
+        ));
+    }
+    drop(source);
+
+    let mut db = rusqlite::Connection::open(config.storage.location.join("db.sqlite3"))
+        .map_err(|e| e.to_string())?;
+    db.restore(
+        rusqlite::MAIN_DB,
+        snapshot,
+        None::<fn(rusqlite::backup::Progress)>,
+    )
+    .map_err(|e| format!("Failed to restore {}: {}", snapshot.to_string_lossy(), e))?;
+    drop(db);
+
+    // older snapshots get migrated like any older database
+    RepoDB::new(config).map(|_| ())
+}
+
+/// cached result of a manifest lookup
+/// misses are cached too since most requested files don't change
+#[derive(Clone)]
+struct CachedManifest {
+    /// the entry if file is known
+    entry: Option<ManifestEntry>,
+
+    /// repo the entry was first seen in
+    repo: Option<String>,
+}
+
+/// database of Manifest entries and SRC_URIs from the synced repos
 pub struct RepoDB {
     /// sqlite databse connection
     db: Mutex<rusqlite::Connection>,
+
+    /// manifest lookups by file
+    /// only filled and invalidated while holding the db lock
+    /// so a lookup racing an insert can't cache outdated rows
+    manifest_cache: Cache<String, Arc<CachedManifest>>,
+
+    /// src_uri lookups by file, same rules as manifest_cache
+    src_uri_cache: Cache<String, Arc<Vec<String>>>,
 }
 
 impl RepoDB {
+    /// open or create the database in the storage root
     pub fn new(config: &config::Config) -> Result<Self, String> {
         let db = match rusqlite::Connection::open(config.storage.location.join("db.sqlite3")) {
             Ok(db) => db,
@@ -20,6 +503,33 @@ impl RepoDB {
             Err(e) => return Err(e.to_string()),
         };
 
+        // syncs write lots of entries while requests read
+        // so wait for locks instead of failing right away
+        let settings = &config.storage.database;
+        db.busy_timeout(settings.busy_timeout)
+            .map_err(|e| e.to_string())?;
+
+        let journal_mode: String = db
+            .pragma_update_and_check(
+                None,
+                "journal_mode",
+                settings.journal_mode.as_str(),
+                |row| row.get(0),
+            )
+            .map_err(|e| format!("Failed to set journal_mode: {}", e))?;
+        if journal_mode != settings.journal_mode.as_str() {
+            eprintln!(
+                "Database uses journal_mode {} instead of {}",
+                journal_mode,
+                settings.journal_mode.as_str()
+            );
+        }
+
+        db.pragma_update(None, "synchronous", settings.synchronous.as_str())
+            .map_err(|e| format!("Failed to set synchronous: {}", e))?;
+
+        // sqlite INTEGER columns are 64-bit so sizes of distfiles
+        // larger than 4 GiB fit without a schema change
         match db.execute(
             "CREATE TABLE IF NOT EXISTS manifest (
                 file    TEXT PRIMARY KEY NOT NULL,
@@ -45,40 +555,1364 @@ impl RepoDB {
             Err(e) => return Err(e.to_string()),
         };
 
-        Ok(Self { db: Mutex::new(db) })
+        migrate(&db)?;
+
+        Ok(Self {
+            db: Mutex::new(db),
+            manifest_cache: Cache::new(settings.cache_capacity),
+            src_uri_cache: Cache::new(settings.cache_capacity),
+        })
+    }
+
+    /// write a consistent copy of the database to dest via the online backup API
+    /// works while the database is in use, dest gets replaced atomically
+    ///
+    /// @param dest  where to write the snapshot
+    pub async fn backup(&self, dest: &Path) -> Result<(), String> {
+        let part = part_location(dest);
+        self.db
+            .lock()
+            .await
+            .backup(rusqlite::MAIN_DB, &part, None)
+            .map_err(|e| format!("Failed to write snapshot: {}", e))?;
+        std::fs::rename(&part, dest).map_err(|e| {
+            let _ = std::fs::remove_file(&part);
+            format!("Cannot move snapshot to {}: {}", dest.to_string_lossy(), e)
+        })
     }
 
     /// Insert a manifest entry into the database
-    pub async fn insert_manifest_entry(&self, entry: ManifestEntry) -> rusqlite::Result<()> {
+    /// entries already present only get marked as seen
+    /// returns whether the entry is new
+    ///
+    /// @param repo   name of the repo the Manifest belongs to
+    /// @param entry  the ManifestEntry to insert
+    pub async fn insert_manifest_entry(
+        &self,
+        repo: &str,
+        entry: ManifestEntry,
+    ) -> rusqlite::Result<bool> {
+        let inserted = self.insert_manifest_entries(repo, &[entry]).await?;
+        Ok(inserted[0])
+    }
+
+    /// Insert a batch of manifest entries in a single transaction
+    /// entries already present only get marked as seen
+    /// returns for each entry whether it is new
+    ///
+    /// @param repo     name of the repo the Manifests belong to
+    /// @param entries  the ManifestEntries to insert
+    pub async fn insert_manifest_entries(
+        &self,
+        repo: &str,
+        entries: &[ManifestEntry],
+    ) -> rusqlite::Result<Vec<bool>> {
+        let now = utils::unix_time();
+        let mut db_locked = self.db.lock().await;
+        let tx = db_locked.transaction()?;
+
+        let mut inserted = Vec::with_capacity(entries.len());
+        {
+            let mut insert = tx.prepare_cached(
+                "INSERT OR IGNORE INTO manifest (file, origin, size, blake2b, sha512, repo, seen, added, hashes)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7, ?8)",
+            )?;
+            let mut touch = tx.prepare_cached("UPDATE manifest SET seen = ?2 WHERE file = ?1")?;
+
+            for entry in entries {
+                let new = insert.execute(rusqlite::params![
+                    &entry.file,
+                    entry.origin.to_str().unwrap(),
+                    entry.size,
+                    entry.blake2b,
+                    entry.sha512,
+                    repo,
+                    now,
+                    entry
+                        .hashes
+                        .iter()
+                        .map(|(hash, checksum)| format!("{} {}", hash, checksum))
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                ])? > 0;
+
+                if !new {
+                    touch.execute(rusqlite::params![&entry.file, now])?;
+                }
+                inserted.push(new);
+            }
+        }
+        tx.commit()?;
+
+        // only invalidate once the rows are visible
+        for (entry, new) in entries.iter().zip(&inserted) {
+            if *new {
+                self.manifest_cache.invalidate(&entry.file);
+            }
+        }
+
+        Ok(inserted)
+    }
+
+    /// record that all trees were walked completely
+    /// entries not seen since are no longer referenced by any Manifest
+    ///
+    /// @param started  unix timestamp of the start of the walk
+    pub async fn finish_tree_sweep(&self, started: u64) -> rusqlite::Result<()> {
         self.db.lock().await.execute(
-            "INSERT INTO manifest (file, origin, size, blake2b, sha512)
-            VALUES (?1, ?2, ?3, ?4, ?5)",
-            (
-                entry.file,
-                entry.origin.to_str().unwrap(),
-                entry.size,
-                entry.blake2b.unwrap_or(String::from("NULL")),
-                entry.sha512.unwrap_or(String::from("NULL")),
-            ),
+            "INSERT OR REPLACE INTO sync_state (key, value) VALUES (?1, ?2)",
+            rusqlite::params![TREE_SWEEP, started.to_string()],
+        )?;
+
+        Ok(())
+    }
+
+    /// request the start of the last complete walk of all trees
+    /// None before the first one finished
+    pub async fn get_tree_sweep(&self) -> rusqlite::Result<Option<u64>> {
+        let value: Option<String> = self
+            .db
+            .lock()
+            .await
+            .query_row(
+                "SELECT value FROM sync_state WHERE key = ?1",
+                rusqlite::params![TREE_SWEEP],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(value.and_then(|value| value.parse().ok()))
+    }
+
+    /// request the distfiles first referenced by a Manifest since a point in time
+    /// ordered by when they showed up
+    ///
+    /// @param since  unix timestamp
+    pub async fn get_new_distfiles(&self, since: u64) -> rusqlite::Result<Vec<NewDistfile>> {
+        let db_locked = self.db.lock().await;
+        let mut stmt = db_locked.prepare(
+            "SELECT file, size, repo, added FROM manifest
+            WHERE added >= ?1 AND added > 0 ORDER BY added, file",
+        )?;
+        let rows = stmt.query_map(rusqlite::params![since], |row| {
+            Ok(NewDistfile {
+                file: row.get(0)?,
+                size: row.get(1)?,
+                repo: row.get(2)?,
+                added: row.get(3)?,
+            })
+        })?;
+
+        rows.collect()
+    }
+
+    /// request the commit a repo was at when it was last indexed completely
+    ///
+    /// @param repo  name of the repo
+    pub async fn get_indexed_commit(&self, repo: &str) -> rusqlite::Result<Option<String>> {
+        self.db
+            .lock()
+            .await
+            .query_row(
+                "SELECT value FROM sync_state WHERE key = ?1",
+                rusqlite::params![format!("{}{}", INDEXED_COMMIT, repo)],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    /// record the commit a repo got indexed at
+    ///
+    /// @param repo    name of the repo
+    /// @param commit  hex encoded commit id
+    pub async fn set_indexed_commit(&self, repo: &str, commit: &str) -> rusqlite::Result<()> {
+        self.db.lock().await.execute(
+            "INSERT OR REPLACE INTO sync_state (key, value) VALUES (?1, ?2)",
+            rusqlite::params![format!("{}{}", INDEXED_COMMIT, repo), commit],
+        )?;
+
+        Ok(())
+    }
+
+    /// request the hash directory length blobs are stored with
+    /// None for storages created before it got recorded
+    pub async fn get_hash_bits(&self) -> rusqlite::Result<Option<u8>> {
+        let value: Option<String> = self
+            .db
+            .lock()
+            .await
+            .query_row(
+                "SELECT value FROM sync_state WHERE key = ?1",
+                rusqlite::params![HASH_BITS],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(value.and_then(|value| value.parse().ok()))
+    }
+
+    /// record the hash directory length blobs are stored with
+    ///
+    /// @param bits  length of the hash directory names in bits
+    pub async fn set_hash_bits(&self, bits: u8) -> rusqlite::Result<()> {
+        self.db.lock().await.execute(
+            "INSERT OR REPLACE INTO sync_state (key, value) VALUES (?1, ?2)",
+            rusqlite::params![HASH_BITS, bits.to_string()],
         )?;
 
         Ok(())
     }
 
+    /// number of migrations applied to the database
+    pub async fn schema_version(&self) -> rusqlite::Result<usize> {
+        self.db
+            .lock()
+            .await
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+    }
+
+    /// check the database is writable by running a write transaction
+    /// which gets rolled back again
+    pub async fn self_test(&self) -> rusqlite::Result<()> {
+        let mut db_locked = self.db.lock().await;
+        let tx = db_locked.transaction()?;
+        tx.execute(
+            "INSERT OR REPLACE INTO sync_state (key, value) VALUES ('self_test', '')",
+            [],
+        )?;
+        tx.rollback()
+    }
+
+    /// request all files referenced by a Manifest as of the last complete walk
+    /// before the first complete walk every known file counts as referenced
+    pub async fn get_referenced_files(&self) -> rusqlite::Result<HashSet<String>> {
+        let db_locked = self.db.lock().await;
+        let mut stmt = db_locked.prepare(
+            "SELECT file FROM manifest
+            WHERE seen >= COALESCE(
+                (SELECT CAST(value AS INTEGER) FROM sync_state WHERE key = ?1), 0)",
+        )?;
+        let mut rows = stmt.query(rusqlite::params![TREE_SWEEP])?;
+
+        let mut files = HashSet::new();
+        while let Some(row) = rows.next()? {
+            files.insert(row.get(0)?);
+        }
+
+        Ok(files)
+    }
+
+    /// request the repo each known file was first seen in
+    /// files of Manifests indexed before repos were recorded are left out
+    pub async fn get_file_repos(&self) -> rusqlite::Result<HashMap<String, String>> {
+        let db_locked = self.db.lock().await;
+        let mut stmt =
+            db_locked.prepare("SELECT file, repo FROM manifest WHERE repo IS NOT NULL")?;
+        let mut rows = stmt.query(())?;
+
+        let mut repos = HashMap::new();
+        while let Some(row) = rows.next()? {
+            repos.insert(row.get(0)?, row.get(1)?);
+        }
+
+        Ok(repos)
+    }
+
     /// Insert a src_uri entry
     /// foreign key constraints should ensure file exists in manifest table
     pub async fn insert_src_uri(&self, file: String, uri: String) -> rusqlite::Result<()> {
+        self.insert_conditional_src_uri(file, uri, &[]).await
+    }
+
+    /// Insert a src_uri entry gated by USE conditionals
+    ///
+    /// @param file        name of the distfile
+    /// @param uri         url the distfile can be fetched from
+    /// @param conditions  USE conditionals e.g. ["doc", "!test"]
+    pub async fn insert_conditional_src_uri(
+        &self,
+        file: String,
+        uri: String,
+        conditions: &[String],
+    ) -> rusqlite::Result<()> {
+        let use_cond = match conditions {
+            [] => None,
+            conditions => Some(conditions.join(" ")),
+        };
+
+        let db_locked = self.db.lock().await;
+        db_locked.execute(
+            "INSERT INTO src_uri (uri, file, use_cond)
+            VALUES (?1, ?2, ?3)",
+            (uri, &file, use_cond),
+        )?;
+        self.src_uri_cache.invalidate(&file);
+
+        Ok(())
+    }
+
+    /// request src_uris for file along with their USE conditionals
+    pub async fn get_src_uri_conditions(
+        &self,
+        file: &str,
+    ) -> rusqlite::Result<Vec<(String, Vec<String>)>> {
+        let db_locked = self.db.lock().await;
+        let mut stmt = db_locked.prepare("SELECT uri, use_cond FROM src_uri WHERE file = ?1")?;
+        let mut rows = stmt.query(rusqlite::params![file])?;
+
+        let mut src_uri = Vec::new();
+        while let Some(row) = rows.next()? {
+            let use_cond: Option<String> = row.get(1)?;
+            let conditions = use_cond
+                .map(|cond| cond.split_whitespace().map(str::to_string).collect())
+                .unwrap_or_default();
+            src_uri.push((row.get(0)?, conditions));
+        }
+
+        Ok(src_uri)
+    }
+
+    /// request the SRC_URIs extracted from an ebuild
+    /// if it was parsed before with the same content hash
+    ///
+    /// @param path  path of the ebuild
+    /// @param hash  content hash of the ebuild
+    pub async fn get_parsed_ebuild(
+        &self,
+        path: &str,
+        hash: &str,
+    ) -> rusqlite::Result<Option<SrcUriObj>> {
+        let db_locked = self.db.lock().await;
+        let src_uri: Option<String> = db_locked
+            .query_row(
+                "SELECT src_uri FROM parsed_ebuild WHERE path = ?1 AND hash = ?2",
+                (path, hash),
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        // unreadable results just get parsed again
+        Ok(src_uri.and_then(|src_uri| serde_json::from_str(&src_uri).ok()))
+    }
+
+    /// remember the SRC_URIs extracted from an ebuild
+    ///
+    /// @param path     path of the ebuild
+    /// @param hash     content hash of the ebuild
+    /// @param src_uri  files mapped to their SRC_URIs
+    pub async fn set_parsed_ebuild(
+        &self,
+        path: &str,
+        hash: &str,
+        src_uri: &SrcUriObj,
+    ) -> rusqlite::Result<()> {
+        let src_uri = serde_json::to_string(src_uri)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
         self.db.lock().await.execute(
-            "INSERT INTO src_uri (uri, file)
-            VALUES (?1, ?2)",
-            (uri, file),
+            "INSERT OR REPLACE INTO parsed_ebuild (path, hash, src_uri) VALUES (?1, ?2, ?3)",
+            (path, hash, src_uri),
         )?;
 
         Ok(())
     }
 
+    /// record that the portage helper failed on an ebuild
+    /// attempts only keep counting while the ebuild is unchanged
+    ///
+    /// @param path   path of the ebuild
+    /// @param hash   content hash of the ebuild
+    /// @param error  error reported by the helper
+    /// @param now    time of the failure as unix timestamp
+    pub async fn record_parse_failure(
+        &self,
+        path: &str,
+        hash: &str,
+        error: &str,
+        now: u64,
+    ) -> rusqlite::Result<()> {
+        self.db.lock().await.execute(
+            "INSERT INTO parse_failure (path, hash, error, attempts, last_attempt)
+            VALUES (?1, ?2, ?3, 1, ?4)
+            ON CONFLICT(path) DO UPDATE SET
+                attempts = CASE WHEN hash = excluded.hash THEN attempts + 1 ELSE 1 END,
+                hash = excluded.hash,
+                error = excluded.error,
+                last_attempt = excluded.last_attempt",
+            rusqlite::params![path, hash, error, now],
+        )?;
+
+        Ok(())
+    }
+
+    /// forget a recorded failure after the ebuild got parsed
+    pub async fn clear_parse_failure(&self, path: &str) -> rusqlite::Result<()> {
+        self.db.lock().await.execute(
+            "DELETE FROM parse_failure WHERE path = ?1",
+            rusqlite::params![path],
+        )?;
+
+        Ok(())
+    }
+
+    /// request the recorded failure of an ebuild
+    pub async fn get_parse_failure(&self, path: &str) -> rusqlite::Result<Option<ParseFailure>> {
+        let db_locked = self.db.lock().await;
+        db_locked
+            .query_row(
+                "SELECT path, hash, error, attempts, last_attempt
+                FROM parse_failure WHERE path = ?1",
+                rusqlite::params![path],
+                parse_failure,
+            )
+            .optional()
+    }
+
+    /// request all ebuilds the portage helper failed on
+    pub async fn get_parse_failures(&self) -> rusqlite::Result<Vec<ParseFailure>> {
+        let db_locked = self.db.lock().await;
+        let mut stmt = db_locked.prepare(
+            "SELECT path, hash, error, attempts, last_attempt
+            FROM parse_failure ORDER BY path",
+        )?;
+        let mut rows = stmt.query(())?;
+
+        let mut failures = Vec::new();
+        while let Some(row) = rows.next()? {
+            failures.push(parse_failure(row)?);
+        }
+
+        Ok(failures)
+    }
+
+    /// request the manifest entry for file
+    pub async fn get_manifest_entry(&self, file: &str) -> rusqlite::Result<Option<ManifestEntry>> {
+        Ok(self.cached_manifest(file).await?.entry.clone())
+    }
+
+    /// request the manifest entries of many files at once
+    /// bypasses manifest_cache so bulk lookups don't evict hot entries
+    /// unknown files are left out
+    ///
+    /// @param files  names of the files
+    pub async fn get_manifest_entries(
+        &self,
+        files: &[String],
+    ) -> rusqlite::Result<HashMap<String, ManifestEntry>> {
+        let db_locked = self.db.lock().await;
+        let mut stmt = db_locked.prepare_cached(
+            "SELECT file, origin, size, blake2b, sha512, hashes FROM manifest WHERE file = ?1",
+        )?;
+
+        let mut entries = HashMap::new();
+        for file in files {
+            let mut rows = stmt.query(rusqlite::params![file])?;
+            if let Some(row) = rows.next()? {
+                entries.insert(file.clone(), manifest_entry(row)?);
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// look up the manifest row of file via manifest_cache
+    async fn cached_manifest(&self, file: &str) -> rusqlite::Result<Arc<CachedManifest>> {
+        if let Some(cached) = self.manifest_cache.get(file) {
+            return Ok(cached);
+        }
+
+        let db_locked = self.db.lock().await;
+        let mut stmt = db_locked.prepare(
+            "SELECT file, origin, size, blake2b, sha512, hashes, repo FROM manifest WHERE file = ?1",
+        )?;
+        let mut rows = stmt.query(rusqlite::params![file])?;
+
+        let cached = Arc::new(match rows.next()? {
+            Some(row) => CachedManifest {
+                entry: Some(manifest_entry(row)?),
+                repo: row.get(6)?,
+            },
+            None => CachedManifest {
+                entry: None,
+                repo: None,
+            },
+        });
+        self.manifest_cache.insert(file.to_string(), cached.clone());
+
+        Ok(cached)
+    }
+
+    /// request the manifest entries of a package
+    ///
+    /// @param atom  package as category/name e.g. app-misc/hello
+    pub async fn get_files_by_package(&self, atom: &str) -> rusqlite::Result<Vec<ManifestEntry>> {
+        // entries only know the Manifest they came from
+        // i.e. <repo>/<category>/<name>/Manifest
+        let suffix = format!("/{}/Manifest", atom.trim_matches('/'));
+
+        let db_locked = self.db.lock().await;
+        let mut stmt = db_locked.prepare(
+            "SELECT file, origin, size, blake2b, sha512, hashes FROM manifest
+            WHERE substr(origin, -length(?1)) = ?1 ORDER BY file",
+        )?;
+        let mut rows = stmt.query(rusqlite::params![suffix])?;
+
+        let mut entries: Vec<ManifestEntry> = Vec::new();
+        while let Some(row) = rows.next()? {
+            entries.push(manifest_entry(row)?);
+        }
+
+        Ok(entries)
+    }
+
+    /// request the sum of all sizes declared in Manifests in bytes
+    pub async fn get_total_declared_size(&self) -> rusqlite::Result<u64> {
+        self.db
+            .lock()
+            .await
+            .query_row("SELECT COALESCE(SUM(size), 0) FROM manifest", (), |row| {
+                row.get(0)
+            })
+    }
+
+    /// request a single checksum of file
+    /// None if the file is unknown, its Manifest didn't declare
+    /// the hash or the hash isn't stored
+    pub async fn get_checksum(
+        &self,
+        file: &str,
+        hash: HashType,
+    ) -> rusqlite::Result<Option<String>> {
+        let column = match hash {
+            HashType::Blake2b => "blake2b",
+            HashType::Sha512 => "sha512",
+            HashType::Sha256 => {
+                return Ok(self
+                    .get_manifest_entry(file)
+                    .await?
+                    .and_then(|entry| entry.hashes.get("SHA256").cloned()));
+            }
+        };
+
+        let db_locked = self.db.lock().await;
+        let mut stmt =
+            db_locked.prepare(&format!("SELECT {} FROM manifest WHERE file = ?1", column))?;
+        let mut rows = stmt.query(rusqlite::params![file])?;
+
+        match rows.next()? {
+            Some(row) => row.get(0),
+            None => Ok(None),
+        }
+    }
+
+    /// request the name of the repo file was first seen in
+    pub async fn get_manifest_repo(&self, file: &str) -> rusqlite::Result<Option<String>> {
+        Ok(self.cached_manifest(file).await?.repo.clone())
+    }
+
+    /// mark a blob as stale
+    pub async fn mark_stale_blob(&self, file: &str) -> rusqlite::Result<()> {
+        self.db.lock().await.execute(
+            "INSERT OR REPLACE INTO stale_blob (file, since)
+            VALUES (?1, CAST(strftime('%s', 'now') AS INTEGER))",
+            rusqlite::params![file],
+        )?;
+
+        Ok(())
+    }
+
+    /// remove the stale mark of a blob
+    pub async fn clear_stale_blob(&self, file: &str) -> rusqlite::Result<()> {
+        self.db.lock().await.execute(
+            "DELETE FROM stale_blob WHERE file = ?1",
+            rusqlite::params![file],
+        )?;
+
+        Ok(())
+    }
+
+    /// request all blobs marked stale
+    pub async fn get_stale_blobs(&self) -> rusqlite::Result<Vec<String>> {
+        let db_locked = self.db.lock().await;
+        let mut stmt = db_locked.prepare("SELECT file FROM stale_blob")?;
+        let mut rows = stmt.query(())?;
+
+        let mut files: Vec<String> = Vec::new();
+        while let Some(row) = rows.next()? {
+            files.push(row.get(0)?);
+        }
+
+        Ok(files)
+    }
+
+    /// other distfiles whose Manifest entries have the same size and checksums as file
+    /// entries without BLAKE2B checksum have none
+    ///
+    /// @param file  name of the distfile
+    pub async fn get_same_content(&self, file: &str) -> rusqlite::Result<Vec<String>> {
+        let db_locked = self.db.lock().await;
+        let mut stmt = db_locked.prepare_cached(
+            "SELECT other.file FROM manifest AS this
+            JOIN manifest AS other ON other.blake2b = this.blake2b
+            WHERE this.file = ?1 AND other.file != ?1 AND other.size = this.size
+            AND (this.sha512 IS NULL OR other.sha512 IS NULL OR other.sha512 = this.sha512)
+            ORDER BY other.file",
+        )?;
+        let mut rows = stmt.query(rusqlite::params![file])?;
+
+        let mut files: Vec<String> = Vec::new();
+        while let Some(row) = rows.next()? {
+            files.push(row.get(0)?);
+        }
+
+        Ok(files)
+    }
+
+    /// record that file is served from the blob of another distfile
+    ///
+    /// @param file  name of the requested distfile
+    /// @param blob  name of the cached distfile with the same content
+    pub async fn set_alias(&self, file: &str, blob: &str) -> rusqlite::Result<()> {
+        self.db.lock().await.execute(
+            "INSERT OR REPLACE INTO blob_alias (file, blob, since)
+            VALUES (?1, ?2, CAST(strftime('%s', 'now') AS INTEGER))",
+            rusqlite::params![file, blob],
+        )?;
+
+        Ok(())
+    }
+
+    /// request the blob file is served from
+    ///
+    /// @param file  name of the requested distfile
+    pub async fn get_alias(&self, file: &str) -> rusqlite::Result<Option<String>> {
+        let db_locked = self.db.lock().await;
+        let mut stmt = db_locked.prepare_cached("SELECT blob FROM blob_alias WHERE file = ?1")?;
+        let mut rows = stmt.query(rusqlite::params![file])?;
+        match rows.next()? {
+            Some(row) => row.get(0),
+            None => Ok(None),
+        }
+    }
+
+    /// forget the aliases of file and those served from its blob
+    ///
+    /// @param file  name of the distfile
+    pub async fn remove_aliases(&self, file: &str) -> rusqlite::Result<()> {
+        self.db.lock().await.execute(
+            "DELETE FROM blob_alias WHERE file = ?1 OR blob = ?1",
+            rusqlite::params![file],
+        )?;
+
+        Ok(())
+    }
+
+    /// record access times of blobs in a single transaction
+    /// earlier times than the recorded ones are ignored
+    ///
+    /// @param times  names of the blobs with their latest access as unix timestamp
+    pub async fn set_access_times(&self, times: &[(String, u64)]) -> rusqlite::Result<()> {
+        let mut db_locked = self.db.lock().await;
+        let tx = db_locked.transaction()?;
+        {
+            let mut upsert = tx.prepare_cached(
+                "INSERT INTO blob_access (file, accessed) VALUES (?1, ?2)
+                ON CONFLICT (file) DO UPDATE SET accessed = max(accessed, ?2)",
+            )?;
+            for (file, accessed) in times {
+                upsert.execute(rusqlite::params![file, accessed])?;
+            }
+        }
+        tx.commit()
+    }
+
+    /// request the recorded access times of all blobs as unix timestamps
+    pub async fn get_access_times(&self) -> rusqlite::Result<HashMap<String, u64>> {
+        let db_locked = self.db.lock().await;
+        let mut stmt = db_locked.prepare("SELECT file, accessed FROM blob_access")?;
+        let rows = stmt.query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        rows.collect()
+    }
+
+    /// forget the access time of a removed blob
+    ///
+    /// @param file  name of the blob
+    pub async fn remove_access_time(&self, file: &str) -> rusqlite::Result<()> {
+        self.db.lock().await.execute(
+            "DELETE FROM blob_access WHERE file = ?1",
+            rusqlite::params![file],
+        )?;
+
+        Ok(())
+    }
+
+    /// record where the blob of file came from, replacing what a previous blob recorded
+    ///
+    /// @param file    name of the distfile
+    /// @param source  upstream of the blob
+    pub async fn set_blob_source(&self, file: &str, source: &BlobSource) -> rusqlite::Result<()> {
+        self.db.lock().await.execute(
+            "INSERT OR REPLACE INTO blob_source (file, fetcher, source, fetched, request_id)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            rusqlite::params![
+                file,
+                source.fetcher,
+                source.source,
+                source.fetched,
+                source.request_id
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// request where the blobs of many files came from at once
+    /// files without recorded upstream (e.g. cached before it was recorded) are left out
+    ///
+    /// @param files  names of the distfiles
+    pub async fn get_blob_sources(
+        &self,
+        files: &[String],
+    ) -> rusqlite::Result<HashMap<String, BlobSource>> {
+        let db_locked = self.db.lock().await;
+        let mut stmt = db_locked.prepare_cached(
+            "SELECT fetcher, source, fetched, request_id FROM blob_source WHERE file = ?1",
+        )?;
+
+        let mut sources = HashMap::new();
+        for file in files {
+            let mut rows = stmt.query(rusqlite::params![file])?;
+            if let Some(row) = rows.next()? {
+                sources.insert(
+                    file.clone(),
+                    BlobSource {
+                        fetcher: row.get(0)?,
+                        source: row.get(1)?,
+                        fetched: row.get(2)?,
+                        request_id: row.get(3)?,
+                    },
+                );
+            }
+        }
+
+        Ok(sources)
+    }
+
+    /// forget where the blob of a removed file came from
+    ///
+    /// @param file  name of the distfile
+    pub async fn remove_blob_source(&self, file: &str) -> rusqlite::Result<()> {
+        self.db.lock().await.execute(
+            "DELETE FROM blob_source WHERE file = ?1",
+            rusqlite::params![file],
+        )?;
+
+        Ok(())
+    }
+
+    /// add bytes served to a subnet in a quota window
+    ///
+    /// @param subnet  client subnet e.g. 192.0.2.0/24
+    /// @param window  start of the quota window as unix timestamp
+    /// @param bytes   number of bytes served
+    pub async fn add_client_usage(
+        &self,
+        subnet: &str,
+        window: u64,
+        bytes: u64,
+    ) -> rusqlite::Result<()> {
+        self.db.lock().await.execute(
+            "INSERT INTO client_usage (subnet, window, bytes)
+            VALUES (?1, ?2, ?3)
+            ON CONFLICT (subnet, window) DO UPDATE SET bytes = bytes + excluded.bytes",
+            rusqlite::params![subnet, window, bytes],
+        )?;
+
+        Ok(())
+    }
+
+    /// store a new API key
+    /// fails if the name is taken
+    ///
+    /// @param name     name of the key
+    /// @param hash     hex encoded sha256 of the key
+    /// @param limit    bytes per quota window, None for api_keys.default_limit
+    /// @param created  time of issuance as unix timestamp
+    pub async fn insert_api_key(
+        &self,
+        name: &str,
+        hash: &str,
+        limit: Option<u64>,
+        created: u64,
+    ) -> rusqlite::Result<()> {
+        self.db.lock().await.execute(
+            "INSERT INTO api_key (name, hash, byte_limit, created) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![name, hash, limit, created],
+        )?;
+
+        Ok(())
+    }
+
+    /// look up an API key by the hash of the key
+    pub async fn get_api_key(&self, hash: &str) -> rusqlite::Result<Option<ApiKey>> {
+        self.db
+            .lock()
+            .await
+            .query_row(
+                "SELECT name, byte_limit, created, last_used FROM api_key WHERE hash = ?1",
+                rusqlite::params![hash],
+                api_key,
+            )
+            .optional()
+    }
+
+    /// whether any API key was issued
+    pub async fn has_api_keys(&self) -> rusqlite::Result<bool> {
+        self.db
+            .lock()
+            .await
+            .query_row("SELECT EXISTS(SELECT 1 FROM api_key)", (), |row| row.get(0))
+    }
+
+    /// request all API keys ordered by name
+    pub async fn get_api_keys(&self) -> rusqlite::Result<Vec<ApiKey>> {
+        let db_locked = self.db.lock().await;
+        let mut stmt = db_locked
+            .prepare("SELECT name, byte_limit, created, last_used FROM api_key ORDER BY name")?;
+        let mut rows = stmt.query(())?;
+
+        let mut keys: Vec<ApiKey> = Vec::new();
+        while let Some(row) = rows.next()? {
+            keys.push(api_key(row)?);
+        }
+
+        Ok(keys)
+    }
+
+    /// revoke an API key along with its usage
+    /// returns whether the key existed
+    pub async fn delete_api_key(&self, name: &str) -> rusqlite::Result<bool> {
+        let deleted = self.db.lock().await.execute(
+            "DELETE FROM api_key WHERE name = ?1",
+            rusqlite::params![name],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    /// add a served file to the usage of an API key
+    ///
+    /// @param name    name of the key
+    /// @param window  start of the quota window as unix timestamp
+    /// @param bytes   size of the file
+    /// @param now     time of the request as unix timestamp
+    pub async fn add_key_usage(
+        &self,
+        name: &str,
+        window: u64,
+        bytes: u64,
+        now: u64,
+    ) -> rusqlite::Result<()> {
+        let mut db_locked = self.db.lock().await;
+        let tx = db_locked.transaction()?;
+        tx.execute(
+            "INSERT INTO key_usage (name, window, bytes, requests)
+            VALUES (?1, ?2, ?3, 1)
+            ON CONFLICT (name, window) DO UPDATE
+            SET bytes = bytes + excluded.bytes, requests = requests + 1",
+            rusqlite::params![name, window, bytes],
+        )?;
+        tx.execute(
+            "UPDATE api_key SET last_used = ?2 WHERE name = ?1",
+            rusqlite::params![name, now],
+        )?;
+        tx.commit()
+    }
+
+    /// request the bytes served with an API key in a quota window
+    pub async fn get_key_usage(&self, name: &str, window: u64) -> rusqlite::Result<u64> {
+        self.db
+            .lock()
+            .await
+            .query_row(
+                "SELECT bytes FROM key_usage WHERE name = ?1 AND window = ?2",
+                rusqlite::params![name, window],
+                |row| row.get(0),
+            )
+            .optional()
+            .map(|bytes| bytes.unwrap_or(0))
+    }
+
+    /// request the usage of all API keys in a quota window, heaviest first
+    pub async fn get_window_key_usage(&self, window: u64) -> rusqlite::Result<Vec<KeyUsage>> {
+        let db_locked = self.db.lock().await;
+        let mut stmt = db_locked.prepare(
+            "SELECT name, bytes, requests FROM key_usage WHERE window = ?1 ORDER BY bytes DESC",
+        )?;
+        let mut rows = stmt.query(rusqlite::params![window])?;
+
+        let mut usage: Vec<KeyUsage> = Vec::new();
+        while let Some(row) = rows.next()? {
+            usage.push(KeyUsage {
+                name: row.get(0)?,
+                bytes: row.get(1)?,
+                requests: row.get(2)?,
+            });
+        }
+
+        Ok(usage)
+    }
+
+    /// request bytes served to a subnet in a quota window
+    pub async fn get_client_usage(&self, subnet: &str, window: u64) -> rusqlite::Result<u64> {
+        let db_locked = self.db.lock().await;
+        let mut stmt = db_locked
+            .prepare("SELECT bytes FROM client_usage WHERE subnet = ?1 AND window = ?2")?;
+        let mut rows = stmt.query(rusqlite::params![subnet, window])?;
+
+        match rows.next()? {
+            Some(row) => row.get(0),
+            None => Ok(0),
+        }
+    }
+
+    /// request bytes served to every subnet in a quota window
+    /// sorted by usage, heaviest first
+    pub async fn get_window_usage(&self, window: u64) -> rusqlite::Result<Vec<(String, u64)>> {
+        let db_locked = self.db.lock().await;
+        let mut stmt = db_locked.prepare(
+            "SELECT subnet, bytes FROM client_usage WHERE window = ?1 ORDER BY bytes DESC",
+        )?;
+        let mut rows = stmt.query(rusqlite::params![window])?;
+
+        let mut usage: Vec<(String, u64)> = Vec::new();
+        while let Some(row) = rows.next()? {
+            usage.push((row.get(0)?, row.get(1)?));
+        }
+
+        Ok(usage)
+    }
+
+    /// record a successful sync of a repo
+    /// clears the error of earlier failures
+    ///
+    /// @param name  name of the repo
+    /// @param now   unix timestamp of the sync
+    pub async fn record_sync_success(&self, name: &str, now: u64) -> rusqlite::Result<()> {
+        self.db.lock().await.execute(
+            "INSERT INTO repo_sync (name, last_success) VALUES (?1, ?2)
+            ON CONFLICT (name) DO UPDATE SET last_success = ?2, error = NULL",
+            rusqlite::params![name, now],
+        )?;
+
+        Ok(())
+    }
+
+    /// record a failed sync of a repo
+    ///
+    /// @param name   name of the repo
+    /// @param error  why the sync failed
+    /// @param now    unix timestamp of the sync
+    pub async fn record_sync_failure(
+        &self,
+        name: &str,
+        error: &str,
+        now: u64,
+    ) -> rusqlite::Result<()> {
+        self.db.lock().await.execute(
+            "INSERT INTO repo_sync (name, last_failure, error) VALUES (?1, ?2, ?3)
+            ON CONFLICT (name) DO UPDATE SET last_failure = ?2, error = ?3",
+            rusqlite::params![name, now, error],
+        )?;
+
+        Ok(())
+    }
+
+    /// record an error of the repo syncer
+    /// a repeat of the repo's latest error only bumps its count
+    /// only the keep latest errors per repo are kept
+    ///
+    /// @param repo   name of the repo
+    /// @param phase  step of the sync cycle that failed
+    /// @param error  what went wrong
+    /// @param now    unix timestamp of the error
+    /// @param keep   number of errors kept per repo
+    pub async fn record_sync_error(
+        &self,
+        repo: &str,
+        phase: &str,
+        error: &str,
+        now: u64,
+        keep: usize,
+    ) -> rusqlite::Result<()> {
+        let db_locked = self.db.lock().await;
+        let latest: Option<(i64, String, String)> = db_locked
+            .query_row(
+                "SELECT id, phase, error FROM sync_error WHERE repo = ?1 ORDER BY id DESC LIMIT 1",
+                rusqlite::params![repo],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+        match latest {
+            Some((id, latest_phase, latest_error))
+                if latest_phase == phase && latest_error == error =>
+            {
+                db_locked.execute(
+                    "UPDATE sync_error SET last = ?2, count = count + 1 WHERE id = ?1",
+                    rusqlite::params![id, now],
+                )?;
+            }
+            _ => {
+                db_locked.execute(
+                    "INSERT INTO sync_error (repo, phase, error, first, last, count)
+                    VALUES (?1, ?2, ?3, ?4, ?4, 1)",
+                    rusqlite::params![repo, phase, error, now],
+                )?;
+            }
+        }
+        db_locked.execute(
+            "DELETE FROM sync_error WHERE repo = ?1 AND id NOT IN
+                (SELECT id FROM sync_error WHERE repo = ?1 ORDER BY id DESC LIMIT ?2)",
+            rusqlite::params![repo, keep],
+        )?;
+
+        Ok(())
+    }
+
+    /// forget errors of the repo syncer which last happened before keep_since
+    ///
+    /// @param keep_since  unix timestamp of the oldest error to keep
+    pub async fn expire_sync_errors(&self, keep_since: u64) -> rusqlite::Result<()> {
+        self.db.lock().await.execute(
+            "DELETE FROM sync_error WHERE last < ?1",
+            rusqlite::params![keep_since],
+        )?;
+
+        Ok(())
+    }
+
+    /// request the recorded errors of the repo syncer, latest first
+    ///
+    /// @param repo  only errors of this repo
+    pub async fn get_sync_errors(&self, repo: Option<&str>) -> rusqlite::Result<Vec<SyncError>> {
+        let db_locked = self.db.lock().await;
+        let mut stmt = db_locked.prepare(
+            "SELECT repo, phase, error, first, last, count FROM sync_error
+            WHERE ?1 IS NULL OR repo = ?1 ORDER BY last DESC, id DESC",
+        )?;
+        let rows = stmt.query_map(rusqlite::params![repo], |row| {
+            Ok(SyncError {
+                repo: row.get(0)?,
+                phase: row.get(1)?,
+                error: row.get(2)?,
+                first: row.get(3)?,
+                last: row.get(4)?,
+                count: row.get(5)?,
+            })
+        })?;
+
+        rows.collect()
+    }
+
+    /// record disk usage of a repo checkout
+    ///
+    /// @param name           name of the repo
+    /// @param path           location of the checkout
+    /// @param checkout_size  size of the working tree in bytes
+    /// @param git_size       size of the .git directory in bytes
+    pub async fn set_repo_stats(
+        &self,
+        name: &str,
+        path: &Path,
+        checkout_size: u64,
+        git_size: u64,
+    ) -> rusqlite::Result<()> {
+        self.db.lock().await.execute(
+            "INSERT OR REPLACE INTO repo_stats (name, path, checkout_size, git_size, updated)
+            VALUES (?1, ?2, ?3, ?4, CAST(strftime('%s', 'now') AS INTEGER))",
+            rusqlite::params![name, path.to_string_lossy(), checkout_size, git_size],
+        )?;
+
+        Ok(())
+    }
+
+    /// request disk usage of all repo checkouts
+    pub async fn get_repo_stats(&self) -> rusqlite::Result<Vec<RepoStats>> {
+        let db_locked = self.db.lock().await;
+        let mut stmt = db_locked.prepare(
+            "SELECT repo_stats.name, path, checkout_size, git_size, updated,
+                last_success, last_failure, error
+            FROM repo_stats LEFT JOIN repo_sync ON repo_sync.name = repo_stats.name
+            ORDER BY repo_stats.name",
+        )?;
+        let mut rows = stmt.query(())?;
+
+        let mut stats: Vec<RepoStats> = Vec::new();
+        while let Some(row) = rows.next()? {
+            stats.push(RepoStats {
+                name: row.get(0)?,
+                path: PathBuf::from(row.get::<_, String>(1)?),
+                checkout_size: row.get(2)?,
+                git_size: row.get(3)?,
+                updated: row.get(4)?,
+                last_sync: row.get(5)?,
+                last_sync_failure: row.get(6)?,
+                sync_error: row.get(7)?,
+            });
+        }
+
+        Ok(stats)
+    }
+
+    /// request the checksum recorded on the first download of file
+    ///
+    /// @param file  name of the distfile
+    pub async fn get_tofu_checksum(&self, file: &str) -> rusqlite::Result<Option<TofuChecksum>> {
+        self.db
+            .lock()
+            .await
+            .query_row(
+                "SELECT file, size, blake2b, recorded, mismatches, last_mismatch, mismatch_blake2b
+                FROM tofu_checksum WHERE file = ?1",
+                rusqlite::params![file],
+                tofu_checksum,
+            )
+            .optional()
+    }
+
+    /// request all recorded first download checksums
+    ///
+    /// @param mismatched  only those later downloads didn't match
+    pub async fn get_tofu_checksums(
+        &self,
+        mismatched: bool,
+    ) -> rusqlite::Result<Vec<TofuChecksum>> {
+        let db_locked = self.db.lock().await;
+        let mut stmt = db_locked.prepare(
+            "SELECT file, size, blake2b, recorded, mismatches, last_mismatch, mismatch_blake2b
+            FROM tofu_checksum WHERE mismatches > 0 OR NOT ?1 ORDER BY file",
+        )?;
+        let mut rows = stmt.query(rusqlite::params![mismatched])?;
+
+        let mut checksums = Vec::new();
+        while let Some(row) = rows.next()? {
+            checksums.push(tofu_checksum(row)?);
+        }
+
+        Ok(checksums)
+    }
+
+    /// record the checksum of the first download of file
+    ///
+    /// @param file     name of the distfile
+    /// @param size     size of the download
+    /// @param blake2b  BLAKE2B of the download
+    pub async fn insert_tofu_checksum(
+        &self,
+        file: &str,
+        size: u64,
+        blake2b: &str,
+    ) -> rusqlite::Result<()> {
+        self.db.lock().await.execute(
+            "INSERT OR REPLACE INTO tofu_checksum (file, size, blake2b, recorded)
+            VALUES (?1, ?2, ?3, CAST(strftime('%s', 'now') AS INTEGER))",
+            rusqlite::params![file, size, blake2b],
+        )?;
+
+        Ok(())
+    }
+
+    /// count a download of file not matching its recorded checksum
+    ///
+    /// @param file     name of the distfile
+    /// @param blake2b  BLAKE2B of the download
+    pub async fn insert_tofu_mismatch(&self, file: &str, blake2b: &str) -> rusqlite::Result<()> {
+        self.db.lock().await.execute(
+            "UPDATE tofu_checksum SET mismatches = mismatches + 1,
+            last_mismatch = CAST(strftime('%s', 'now') AS INTEGER), mismatch_blake2b = ?2
+            WHERE file = ?1",
+            rusqlite::params![file, blake2b],
+        )?;
+
+        Ok(())
+    }
+
+    /// forget the recorded checksum of file so its next download gets trusted
+    /// returns whether there was one
+    ///
+    /// @param file  name of the distfile
+    pub async fn remove_tofu_checksum(&self, file: &str) -> rusqlite::Result<bool> {
+        let removed = self.db.lock().await.execute(
+            "DELETE FROM tofu_checksum WHERE file = ?1",
+            rusqlite::params![file],
+        )?;
+
+        Ok(removed > 0)
+    }
+
+    /// add a download to the history
+    /// and forget downloads which started before keep_since
+    ///
+    /// @param download    the download, its id is ignored
+    /// @param keep_since  unix timestamp of the oldest download to keep
+    pub async fn insert_download(
+        &self,
+        download: &Download,
+        keep_since: u64,
+    ) -> rusqlite::Result<()> {
+        let db_locked = self.db.lock().await;
+        db_locked.execute(
+            "INSERT INTO download (file, outcome, fetcher, source, started, duration, bytes, error, request_id)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            rusqlite::params![
+                download.file,
+                download.outcome,
+                download.fetcher,
+                download.source,
+                download.started,
+                download.duration,
+                download.bytes,
+                download.error,
+                download.request_id,
+            ],
+        )?;
+        db_locked.execute(
+            "DELETE FROM download WHERE started < ?1",
+            rusqlite::params![keep_since],
+        )?;
+
+        Ok(())
+    }
+
+    /// request a page of the download history, newest first
+    /// returns the number of matching downloads along with the page
+    ///
+    /// @param filter  which downloads to list
+    /// @param limit   page size
+    /// @param offset  matching downloads to skip
+    pub async fn get_downloads(
+        &self,
+        filter: &DownloadFilter,
+        limit: u64,
+        offset: u64,
+    ) -> rusqlite::Result<(u64, Vec<Download>)> {
+        const MATCHES: &str = "(?1 IS NULL OR instr(file, ?1) > 0)
+            AND (?2 IS NULL OR outcome = ?2)
+            AND (?3 IS NULL OR started >= ?3)
+            AND (?4 IS NULL OR started < ?4)";
+
+        let db_locked = self.db.lock().await;
+        let total = db_locked.query_row(
+            &format!("SELECT COUNT(*) FROM download WHERE {}", MATCHES),
+            rusqlite::params![filter.file, filter.outcome, filter.since, filter.until],
+            |row| row.get(0),
+        )?;
+
+        let mut stmt = db_locked.prepare(&format!(
+            "SELECT id, file, outcome, fetcher, source, started, duration, bytes, error, request_id
+            FROM download WHERE {} ORDER BY id DESC LIMIT ?5 OFFSET ?6",
+            MATCHES
+        ))?;
+        let rows = stmt.query_map(
+            rusqlite::params![
+                filter.file,
+                filter.outcome,
+                filter.since,
+                filter.until,
+                limit,
+                offset
+            ],
+            |row| {
+                Ok(Download {
+                    id: row.get(0)?,
+                    file: row.get(1)?,
+                    outcome: row.get(2)?,
+                    fetcher: row.get(3)?,
+                    source: row.get(4)?,
+                    started: row.get(5)?,
+                    duration: row.get(6)?,
+                    bytes: row.get(7)?,
+                    error: row.get(8)?,
+                    request_id: row.get(9)?,
+                })
+            },
+        )?;
+
+        Ok((total, rows.collect::<rusqlite::Result<_>>()?))
+    }
+
+    /// add a served distfile to the provenance log
+    /// entries served before keep_since get dropped along the way
+    ///
+    /// @param served      the served distfile
+    /// @param keep_since  unix timestamp of the oldest entry to keep
+    pub async fn add_served(
+        &self,
+        served: &ServedDistfile,
+        keep_since: u64,
+    ) -> rusqlite::Result<()> {
+        let db_locked = self.db.lock().await;
+        db_locked.execute(
+            "INSERT INTO served (file, served, client, api_key, bytes, blake2b, sha512, package, upstream, request_id)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            rusqlite::params![
+                served.file,
+                served.served,
+                served.client,
+                served.api_key,
+                served.bytes,
+                served.blake2b,
+                served.sha512,
+                served.package,
+                served.upstream,
+                served.request_id,
+            ],
+        )?;
+        db_locked.execute(
+            "DELETE FROM served WHERE served < ?1",
+            rusqlite::params![keep_since],
+        )?;
+
+        Ok(())
+    }
+
+    /// request the distfiles served within a time window, oldest first
+    ///
+    /// @param since  only those served at or after this unix timestamp
+    /// @param until  only those served before this unix timestamp
+    pub async fn get_served(
+        &self,
+        since: Option<u64>,
+        until: Option<u64>,
+    ) -> rusqlite::Result<Vec<ServedDistfile>> {
+        let db_locked = self.db.lock().await;
+        let mut stmt = db_locked.prepare(
+            "SELECT file, served, client, api_key, bytes, blake2b, sha512, package, upstream, request_id
+            FROM served WHERE (?1 IS NULL OR served >= ?1) AND (?2 IS NULL OR served < ?2)
+            ORDER BY id",
+        )?;
+        let rows = stmt.query_map(rusqlite::params![since, until], |row| {
+            Ok(ServedDistfile {
+                file: row.get(0)?,
+                served: row.get(1)?,
+                client: row.get(2)?,
+                api_key: row.get(3)?,
+                bytes: row.get(4)?,
+                blake2b: row.get(5)?,
+                sha512: row.get(6)?,
+                package: row.get(7)?,
+                upstream: row.get(8)?,
+                request_id: row.get(9)?,
+            })
+        })?;
+
+        rows.collect()
+    }
+
     /// request src_uris for file
-    pub async fn get_src_uri(&self, file: &String) -> rusqlite::Result<Vec<String>> {
+    pub async fn get_src_uri(&self, file: &str) -> rusqlite::Result<Vec<String>> {
+        if let Some(cached) = self.src_uri_cache.get(file) {
+            return Ok(cached.to_vec());
+        }
+
         let db_locked = self.db.lock().await;
         let mut stmt = db_locked.prepare("SELECT uri FROM src_uri WHERE file = ?1")?;
         let mut rows = stmt.query(rusqlite::params![file])?;
@@ -87,7 +1921,99 @@ impl RepoDB {
         while let Some(row) = rows.next()? {
             src_uri.push(row.get(0)?);
         }
+        self.src_uri_cache
+            .insert(file.to_string(), Arc::new(src_uri.clone()));
 
         Ok(src_uri)
     }
 }
+
+/// build a ManifestEntry from a row of
+/// SELECT file, origin, size, blake2b, sha512, hashes FROM manifest
+fn manifest_entry(row: &rusqlite::Row) -> rusqlite::Result<ManifestEntry> {
+    let blake2b: Option<String> = row.get(3)?;
+    let sha512: Option<String> = row.get(4)?;
+
+    // entries stored before all hashes were kept only have BLAKE2B and SHA512
+    let mut hashes = BTreeMap::new();
+    match row.get::<_, Option<String>>(5)? {
+        Some(stored) => {
+            let mut parts = stored.split_whitespace();
+            while let (Some(hash), Some(checksum)) = (parts.next(), parts.next()) {
+                hashes.insert(hash.to_string(), checksum.to_string());
+            }
+        }
+        None => {
+            if let Some(blake2b) = &blake2b {
+                hashes.insert("BLAKE2B".to_string(), blake2b.clone());
+            }
+            if let Some(sha512) = &sha512 {
+                hashes.insert("SHA512".to_string(), sha512.clone());
+            }
+        }
+    }
+
+    Ok(ManifestEntry {
+        file: row.get(0)?,
+        origin: PathBuf::from(row.get::<_, String>(1)?),
+        size: row.get(2)?,
+        blake2b,
+        sha512,
+        hashes,
+    })
+}
+
+/// build a ParseFailure from a row of
+/// SELECT path, hash, error, attempts, last_attempt FROM parse_failure
+fn parse_failure(row: &rusqlite::Row) -> rusqlite::Result<ParseFailure> {
+    Ok(ParseFailure {
+        path: row.get(0)?,
+        hash: row.get(1)?,
+        error: row.get(2)?,
+        attempts: row.get(3)?,
+        last_attempt: row.get(4)?,
+    })
+}
+
+/// convert a row of file, size, blake2b, recorded, mismatches, last_mismatch, mismatch_blake2b
+/// into a TofuChecksum
+fn tofu_checksum(row: &rusqlite::Row) -> rusqlite::Result<TofuChecksum> {
+    Ok(TofuChecksum {
+        file: row.get(0)?,
+        size: row.get(1)?,
+        blake2b: row.get(2)?,
+        recorded: row.get(3)?,
+        mismatches: row.get(4)?,
+        last_mismatch: row.get(5)?,
+        mismatch_blake2b: row.get(6)?,
+    })
+}
+
+/// convert a row of name, byte_limit, created, last_used into an ApiKey
+fn api_key(row: &rusqlite::Row) -> rusqlite::Result<ApiKey> {
+    Ok(ApiKey {
+        name: row.get(0)?,
+        limit: row.get(1)?,
+        created: row.get(2)?,
+        last_used: row.get(3)?,
+    })
+}
+
+/// bring the schema up to date by applying pending MIGRATIONS
+fn migrate(db: &rusqlite::Connection) -> Result<(), String> {
+    let version: usize = db
+        .pragma_query_value(None, "user_version", |row| row.get(0))
+        .map_err(|e| e.to_string())?;
+
+    for (idx, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        println!("Migrating database to schema version {}", idx + 1);
+        db.execute_batch(&format!(
+            "BEGIN; {}; PRAGMA user_version = {}; COMMIT;",
+            migration,
+            idx + 1
+        ))
+        .map_err(|e| format!("Migration to schema version {} failed: {}", idx + 1, e))?;
+    }
+
+    Ok(())
+}
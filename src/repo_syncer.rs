@@ -3,26 +3,135 @@ use futures::pin_mut;
 use git2::Direction;
 use git2::Repository;
 use git2::ResetType;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use tokio::fs;
+use tokio::sync::mpsc;
 use tokio::time;
 
-use crate::config::Config;
-use crate::ebuild_parser::Ebuild;
-use crate::manifest_walker::ManifestWalker;
+use crate::blob_storage::BlobStorage;
+use crate::config::{Config, LayoutCheck, ParserConfig};
+use crate::distfile_name::DistfileName;
+use crate::ebuild_parser::{Ebuild, HelperPool, SrcUriObj};
+use crate::manifest_walker::{
+    self, ManifestDepth, ManifestEntry, ManifestWalker, PackageFilter, PackageMask,
+};
 use crate::repo_db::RepoDB;
+use crate::utils::{self, HashType};
+use crate::webrsync::Webrsync;
+
+mod progress;
+mod watcher;
+
+pub use progress::{SyncCounts, SyncPhase, SyncProgress, SyncReport, SyncStatus};
+
+use watcher::ManifestChange;
+
+/// time to collect further Manifest changes before re-parsing
+/// a sync usually touches many Manifests at once
+const WATCH_DEBOUNCE: time::Duration = time::Duration::from_secs(2);
+
+/// number of full syncs after which a repo whose remote rejected
+/// shallow fetches is tried shallow again
+const RESHALLOW_AFTER: u32 = 24;
+
+/// Manifest entries written to the database per transaction
+const MANIFEST_BATCH: usize = 512;
+
+/// entries buffered between the ManifestWalker and the batching stage
+const MANIFEST_QUEUE: usize = MANIFEST_BATCH * 2;
+
+/// batches buffered between the batching stage and the database writer
+/// bounds the memory of a full tree scan regardless of the tree's size
+const BATCH_QUEUE: usize = 4;
+
+/// errors kept per repo, older ones are dropped as new ones come in
+const SYNC_ERRORS_KEPT: usize = 20;
+
+/// a repo checkout known to the syncer
+struct SyncedRepo {
+    /// name the repo's Manifest entries get recorded under
+    name: String,
+
+    /// url the repo is cloned from
+    url: String,
+
+    /// location of the checkout
+    path: PathBuf,
+
+    /// managed by the host rather than cloned by us
+    local: bool,
+
+    /// re-parse Manifests as soon as they change on disk
+    watch: bool,
+
+    /// how a checkout without metadata/layout.conf is treated
+    layout_conf: LayoutCheck,
+
+    /// depth Manifests are at, None detects it from the checkout
+    manifest_depth: Option<usize>,
+
+    /// categories and packages that get indexed, None indexes all
+    filter: Option<Arc<PackageFilter>>,
+}
+
+/// outcome of a successful sync of a single repo
+struct RepoSync {
+    /// hex encoded commit the checkout is at
+    head: String,
+
+    /// whether new commits got fetched
+    fetched: bool,
+
+    /// whether the repo switched back to shallow fetches
+    reshallowed: bool,
+}
 
 /// struct to clone and sync portage repos
 pub struct RepoSyncer {
     /// interval in which to sync repos
     sync_interval: time::Duration,
 
-    /// path where the repos o sync are stored
-    storage_root: PathBuf,
+    /// repos to sync and index
+    repos: Vec<SyncedRepo>,
+
+    /// run git gc after syncs
+    gc: bool,
+
+    /// repos whose remote rejected shallow fetches
+    /// mapped to the number of full syncs since
+    full_history: Mutex<HashMap<PathBuf, u32>>,
 
     /// repo database
     repo_db: Arc<RepoDB>,
+
+    /// portage helper processes parsing ebuilds
+    helper: HelperPool,
+
+    /// which parsed SRC_URIs get indexed
+    parser: ParserConfig,
+
+    /// progress of the sync cycles
+    progress: Arc<SyncProgress>,
+
+    /// bytes of new distfiles fetched after a sync cycle
+    prefetch_budget: Option<u64>,
+
+    /// storage new distfiles get prefetched into
+    blob_storage: Option<Arc<BlobStorage>>,
+
+    /// skip Manifests of hard-masked packages
+    skip_masked: bool,
+
+    /// category directories walked for Manifests at the same time
+    manifest_concurrency: usize,
+
+    /// snapshots of the checkouts for emerge-webrsync
+    webrsync: Option<Webrsync>,
+
+    /// how long errors are kept after they last happened
+    error_retention: time::Duration,
 }
 
 impl RepoSyncer {
@@ -30,10 +139,16 @@ impl RepoSyncer {
     /// will create repo_storage_root if it doesn't exist
     /// and clone all configured repos to it
     ///
-    /// @param config  a reference to Config
-    /// @returns Err   when repo_storage_root couldn't be created or isn't writable
-    pub async fn new(config: &Config, repo_db: Arc<RepoDB>) -> Result<Self, String> {
-        let sync_interval = time::Duration::from_secs(config.repo.sync_interval * 60);
+    /// @param config    a reference to Config
+    /// @param repo_db   database to index the repos in
+    /// @param progress  where the progress of sync cycles gets reported
+    /// @returns Err     when repo_storage_root couldn't be created or isn't writable
+    pub async fn new(
+        config: &Config,
+        repo_db: Arc<RepoDB>,
+        progress: Arc<SyncProgress>,
+    ) -> Result<Self, String> {
+        let sync_interval = config.repo.sync_interval;
         let storage_root = config.storage.location.join("repos");
         let repos = config.repo.repos.clone();
 
@@ -43,16 +158,56 @@ impl RepoSyncer {
                 .map_err(|e| format!("Failed to create repo storage root: {}", e))?;
         }
 
+        let mut synced = Vec::new();
+        let mut full_history = HashMap::new();
         for repo in repos {
-            let mut path = storage_root.clone();
-            match repo.split("/").last() {
-                Some(name) => path.push(name),
-                None => {
-                    eprintln!("Could't get name for repo: {}", repo);
+            let name = match repo.name() {
+                "" => {
+                    eprintln!("Could't get name for repo: {}", repo.url);
                     continue;
                 }
+                name => name.to_string(),
             };
 
+            // validated along with the config
+            let filter = PackageFilter::new(&repo.include, &repo.exclude)?.map(Arc::new);
+
+            // local checkouts are used in place
+            if let Some(path) = repo.local_path() {
+                if !path.is_dir() {
+                    eprintln!(
+                        "Local repo {} doesn't exist - skipping",
+                        path.to_string_lossy()
+                    );
+                    continue;
+                }
+
+                println!("Using local repo at {}", path.to_string_lossy());
+                synced.push(SyncedRepo {
+                    name,
+                    url: repo.url.clone(),
+                    path: path.to_path_buf(),
+                    local: true,
+                    watch: repo.watch.unwrap_or(true),
+                    layout_conf: repo.layout_conf,
+                    manifest_depth: repo.manifest_depth,
+                    filter,
+                });
+                continue;
+            }
+
+            let path = storage_root.join(&name);
+            synced.push(SyncedRepo {
+                name,
+                url: repo.url.clone(),
+                path: path.clone(),
+                local: false,
+                watch: repo.watch.unwrap_or(false),
+                layout_conf: repo.layout_conf,
+                manifest_depth: repo.manifest_depth,
+                filter,
+            });
+
             if path.is_dir() {
                 println!(
                     "Skipping setup of existing repo at {}",
@@ -61,21 +216,20 @@ impl RepoSyncer {
                 continue;
             }
 
-            // perform a shallow clone since we really don't need old commits here
-            let mut options = git2::FetchOptions::new();
-            options.depth(1);
-
-            let mut builder = git2::build::RepoBuilder::new();
-            builder.fetch_options(options);
-            match builder.clone(repo.as_str(), path.as_path()) {
-                Ok(_) => println!(
-                    "Successfully cloned repo {} to {}",
-                    repo,
-                    path.to_string_lossy()
-                ),
+            match clone(&repo.url, &path) {
+                Ok(shallow) => {
+                    if !shallow {
+                        full_history.insert(path.clone(), 0);
+                    }
+                    println!(
+                        "Successfully cloned repo {} to {}",
+                        repo.url,
+                        path.to_string_lossy()
+                    )
+                }
                 Err(e) => eprintln!(
                     "Failed cloning repo {} to {}: {}",
-                    repo,
+                    repo.url,
                     path.to_string_lossy(),
                     e
                 ),
@@ -84,236 +238,757 @@ impl RepoSyncer {
 
         Ok(Self {
             sync_interval,
-            storage_root,
+            repos: synced,
+            gc: config.repo.gc,
+            full_history: Mutex::new(full_history),
             repo_db,
+            helper: HelperPool::new(&config.parser)?,
+            parser: config.parser.clone(),
+            progress,
+            prefetch_budget: config.repo.prefetch_budget,
+            blob_storage: None,
+            skip_masked: config.repo.skip_masked,
+            manifest_concurrency: config.repo.manifest_concurrency,
+            webrsync: Webrsync::new(config),
+            error_retention: config.repo.error_retention,
         })
     }
 
+    /// prefetch distfiles new in a sync cycle into blob_storage
+    /// up to repo.prefetch_budget so tomorrow's stabilizations are already cached
+    ///
+    /// @param blob_storage  storage to fetch into
+    pub fn prefetch_into(mut self, blob_storage: Arc<BlobStorage>) -> Self {
+        self.blob_storage = Some(blob_storage);
+        self
+    }
+
     /// start RepoSyncer
     /// this is expected to be called from a tokio::spawn
     /// and consumes RepoSyncer
     pub async fn start(self) -> Result<(), String> {
+        // watchers stop when dropped so keep them around
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut watchers = Vec::new();
+        for repo in self.repos.iter().filter(|repo| repo.watch) {
+            let depth = ManifestDepth::detect(&repo.path, repo.manifest_depth);
+            match watcher::watch(repo.name.clone(), repo.path.clone(), depth, tx.clone()) {
+                Ok(w) => {
+                    println!("Watching repo {} for changes", repo.path.to_string_lossy());
+                    watchers.push(w);
+                }
+                Err(e) => eprintln!(
+                    "Failed to watch repo {}: {}",
+                    repo.path.to_string_lossy(),
+                    e
+                ),
+            }
+        }
+
         let mut interval = time::interval(self.sync_interval);
         loop {
             tokio::select! {
-                _ = interval.tick() => {
-                    println!("Starting repository operations");
-
-                    println!("Syncing repositories");
-                    if let Err(e) = self.sync().await {
-                        eprintln!("Sync failed: {}", e);
-                        continue;
+                Some(change) = rx.recv() => {
+                    let mut changes = vec![change];
+                    time::sleep(WATCH_DEBOUNCE).await;
+                    while let Ok(change) = rx.try_recv() {
+                        changes.push(change);
                     }
 
-                    println!("Parsing Manifest files for updates");
-                    let changed = match self.parse_manifests().await {
-                        Ok(val) => val,
-                        Err(e) => {
-                            eprintln!("Manifest parsing failed: {}", e);
-                            continue;
-                        }
-                    };
-
-                    // FIXME: this only gets triggered if the file gets added to the manifest
-                    // if we didn't parse ebuilds the first time they won't be present in the DB
-                    // This should probably be rewritten to "check DB for files in Manifest table
-                    // that don't have src_uri entries and parse those ebuilds"
-                    println!("Parsing ebuilds with changed Manifest");
+                    println!("Parsing {} changed Manifest files", changes.len());
+                    let changed = self.parse_changed_manifests(changes).await;
                     if let Err(e) = self.parse_ebuilds(changed).await {
                         eprintln!("Parsing ebuilds failed: {}", e);
-                        continue;
                     }
                 }
+                _ = interval.tick() => {
+                    println!("Starting repository operations");
+                    if let Err(e) = self.sync_and_index().await {
+                        eprintln!("{}", e);
+                    }
+                }
+                _ = self.progress.sync_requested() => {
+                    println!("Starting requested repository operations");
+                    if let Err(e) = self.sync_and_index().await {
+                        eprintln!("{}", e);
+                    }
+                    interval.reset();
+                }
             }
         }
     }
 
-    /// perform a sync for all repos in storage_root
+    /// sync all repos and index them unless nothing changed
+    /// the progress gets reported while running and summarized afterwards
+    /// returns whether indexing happened
+    pub async fn sync_and_index(&self) -> Result<bool, String> {
+        // the initial index makes every distfile new
+        let swept = matches!(self.repo_db.get_tree_sweep().await, Ok(Some(_)));
+        let started = utils::unix_time();
+
+        let keep_since = started.saturating_sub(self.error_retention.as_secs());
+        if let Err(e) = self.repo_db.expire_sync_errors(keep_since).await {
+            eprintln!("Failed to expire sync errors: {}", e);
+        }
+
+        self.progress.start();
+        let result = self.run_cycle().await;
+        let report = self.progress.finish(result == Ok(false));
+
+        let counts = &report.counts;
+        println!(
+            "Sync cycle finished in {:.1}s: {} repos synced, {} failed, \
+            {} Manifests parsed with {} entries changed and {} unchanged, \
+            {} ebuilds parsed, {} failed",
+            report.elapsed,
+            counts.repos_synced,
+            counts.repos_failed,
+            counts.manifests_parsed,
+            counts.entries_changed,
+            counts.entries_unchanged,
+            counts.ebuilds_parsed,
+            counts.ebuilds_failed
+        );
+
+        if result == Ok(true) && swept {
+            self.prefetch_new(started).await;
+        }
+        if result.is_ok() {
+            self.snapshot_repos().await;
+        }
+
+        result
+    }
+
+    /// fetch distfiles first referenced since started until prefetch_budget is used up
+    /// distfiles not fitting into what's left of the budget get skipped
     ///
-    /// @returns
-    async fn sync(&self) -> Result<(), String> {
-        let repos = self
-            .storage_root
-            .clone()
-            .read_dir()
-            .map_err(|e| e.to_string())?;
+    /// @param started  start of the sync cycle as unix timestamp
+    async fn prefetch_new(&self, started: u64) {
+        let (blob_storage, budget) = match (&self.blob_storage, self.prefetch_budget) {
+            (Some(blob_storage), Some(budget)) => (blob_storage, budget),
+            _ => return,
+        };
+        if blob_storage.read_only() {
+            println!("Storage is in read-only mode - not prefetching new distfiles");
+            return;
+        }
 
-        let mut failed = Vec::new();
-        for entry in repos {
-            if let Err(e) = entry {
-                eprintln!(
-                    "IO error while reading {}: {}",
-                    self.storage_root.clone().to_string_lossy(),
-                    e
-                );
-                continue;
+        let new = match self.repo_db.get_new_distfiles(started).await {
+            Ok(new) => new,
+            Err(e) => {
+                eprintln!("Failed to query new distfiles: {}", e);
+                return;
             }
+        };
 
-            let path = entry.unwrap().path();
-            println!("Syncing repo: {}", path.to_string_lossy());
-            let repo = match Repository::open(&path) {
-                Ok(repo) => repo,
+        let mut spent = 0;
+        let mut fetched = 0;
+        for distfile in new {
+            if spent + distfile.size > budget {
+                continue;
+            }
+            let file = match DistfileName::parse(&distfile.file) {
+                Ok(file) => file,
+                Err(_) => continue,
+            };
+            match blob_storage.blob_location(file.as_str()).await {
+                Ok(path) if path.is_file() => continue,
+                Ok(_) => (),
                 Err(e) => {
-                    eprintln!("Failed to open repo: {}", e);
-                    failed.push(String::from(path.to_string_lossy()));
+                    eprintln!("Failed to prefetch {}: {}", file.as_str(), e);
                     continue;
                 }
-            };
+            }
 
-            let mut remote = match repo.find_remote("origin") {
-                Ok(remote) => remote,
-                Err(_) => {
-                    eprintln!(
-                        "Repository at {} doesn't have remote \"origin\" to fetch from - skipping",
-                        path.to_string_lossy()
-                    );
-                    failed.push(String::from(path.to_string_lossy()));
-                    continue;
+            match blob_storage.request(&file).await {
+                Ok(_) => {
+                    spent += distfile.size;
+                    fetched += 1;
                 }
-            };
+                Err(e) => eprintln!("Failed to prefetch {}: {}", file.as_str(), e),
+            }
+        }
+
+        if fetched > 0 {
+            println!(
+                "Prefetched {} new distfiles ({} bytes of {} budget)",
+                fetched, spent, budget
+            );
+        }
+    }
+
+    /// write webrsync snapshots of all checkouts due for one
+    async fn snapshot_repos(&self) {
+        let webrsync = match &self.webrsync {
+            Some(webrsync) => webrsync,
+            None => return,
+        };
 
-            if let Err(e) = remote.connect(Direction::Fetch) {
-                eprintln!("Failed to connect to remote: {}", e);
-                failed.push(String::from(path.to_string_lossy()));
+        for repo in self.repos.iter().filter(|repo| repo.path.is_dir()) {
+            match webrsync.snapshot(&repo.name, &repo.path).await {
+                Ok(true) => println!("Wrote webrsync snapshot of {}", repo.name),
+                Ok(false) => (),
+                Err(e) => eprintln!("Failed to snapshot {}: {}", repo.name, e),
+            }
+        }
+    }
+
+    /// index all repos as they are on disk without syncing them
+    /// used to repopulate a lost database, the indexed commits aren't recorded
+    /// so the next sync cycle indexes the repos once more
+    pub async fn reindex(&self) -> Result<(), String> {
+        self.progress.start();
+        self.progress.phase(SyncPhase::Manifests);
+        let (changed, failed) = self.parse_manifests().await;
+        for name in failed {
+            eprintln!("Repo {} couldn't be indexed", name);
+        }
+
+        self.progress.phase(SyncPhase::Ebuilds);
+        let result = self
+            .parse_ebuilds(changed)
+            .await
+            .map_err(|e| format!("Parsing ebuilds failed: {}", e));
+
+        let counts = self.progress.finish(false).counts;
+        println!(
+            "Indexed {} Manifests with {} entries and {} ebuilds, {} failed",
+            counts.manifests_parsed,
+            counts.entries_changed + counts.entries_unchanged,
+            counts.ebuilds_parsed,
+            counts.ebuilds_failed
+        );
+
+        result
+    }
+
+    /// the steps of a sync cycle
+    /// returns whether indexing happened
+    async fn run_cycle(&self) -> Result<bool, String> {
+        println!("Syncing repositories");
+        let unindexed = self.sync().await;
+        self.record_sizes().await;
+
+        // local repos have no commit to compare so they always get rescanned
+        if unindexed.is_empty() && !self.repos.iter().any(|repo| repo.local) {
+            println!("Repositories unchanged - skipping indexing");
+            return Ok(false);
+        }
+
+        println!("Parsing Manifest files for updates");
+        self.progress.phase(SyncPhase::Manifests);
+        let (changed, failed) = self.parse_manifests().await;
+
+        // FIXME: this only gets triggered if the file gets added to the manifest
+        // if we didn't parse ebuilds the first time they won't be present in the DB
+        // This should probably be rewritten to "check DB for files in Manifest table
+        // that don't have src_uri entries and parse those ebuilds"
+        println!("Parsing ebuilds with changed Manifest");
+        self.progress.phase(SyncPhase::Ebuilds);
+        self.parse_ebuilds(changed)
+            .await
+            .map_err(|e| format!("Parsing ebuilds failed: {}", e))?;
+
+        for (name, commit) in unindexed {
+            // retry the walk on the next sync
+            if failed.contains(&name) {
                 continue;
             }
+            if let Err(e) = self.repo_db.set_indexed_commit(&name, &commit).await {
+                eprintln!("Failed to record indexed commit of {}: {}", name, e);
+            }
+        }
 
-            let default_branch = match remote.default_branch() {
-                Ok(branch) => branch.as_str().unwrap_or("refs/heads/main").to_string(),
+        Ok(true)
+    }
+
+    /// perform a sync for all cloned repos
+    /// local repos are managed by the host and only get rescanned
+    /// a failing repo is reported and doesn't affect the others
+    ///
+    /// @returns names and commits of synced repos which weren't indexed at their HEAD yet
+    async fn sync(&self) -> Vec<(String, String)> {
+        let mut unindexed = Vec::new();
+        for entry in self.repos.iter().filter(|entry| !entry.local) {
+            let indexed = match self.repo_db.get_indexed_commit(&entry.name).await {
+                Ok(indexed) => indexed,
                 Err(e) => {
-                    eprintln!("Failed to get default branch: {}", e);
-                    failed.push(String::from(path.to_string_lossy()));
-                    continue;
+                    eprintln!("Failed to get indexed commit of {}: {}", entry.name, e);
+                    None
                 }
             };
 
-            // perform a shallow clone since we really don't need old commits here
-            let mut options = git2::FetchOptions::new();
-            options.depth(1);
-
-            if let Err(e) =
-                remote.fetch(&[default_branch.clone().as_str()], Some(&mut options), None)
-            {
-                eprintln!("Failed to fetch repo: {}", e);
-                failed.push(String::from(path.to_string_lossy()));
-                continue;
+            println!("Syncing repo: {}", entry.path.to_string_lossy());
+            let result = self.sync_repo(entry);
+            let recorded = match &result {
+                Ok(_) => {
+                    self.repo_db
+                        .record_sync_success(&entry.name, utils::unix_time())
+                        .await
+                }
+                Err(e) => {
+                    eprintln!("Failed to sync repo {}: {}", entry.name, e);
+                    self.record_error(&entry.name, "sync", e).await;
+                    self.repo_db
+                        .record_sync_failure(&entry.name, e, utils::unix_time())
+                        .await
+                }
+            };
+            if let Err(e) = recorded {
+                eprintln!("Failed to record sync of {}: {}", entry.name, e);
             }
 
-            let remote_tracking = format!(
-                "refs/remotes/origin/{}",
-                default_branch
-                    .clone()
-                    .split("/")
-                    .last()
-                    .unwrap_or("main")
-                    .to_owned()
-            );
-            let fetch_head = match repo.find_reference(remote_tracking.as_str()) {
-                Ok(head) => head,
-                Err(e) => {
-                    eprintln!("Failed to find fetch_head in repo: {}", e);
-                    failed.push(String::from(path.to_string_lossy()));
+            let synced = match result {
+                Ok(synced) => synced,
+                Err(_) => {
+                    self.progress.count(|counts| counts.repos_failed += 1);
                     continue;
                 }
             };
+            self.progress.count(|counts| counts.repos_synced += 1);
+            if indexed.as_ref() != Some(&synced.head) {
+                unindexed.push((entry.name.clone(), synced.head));
+            }
+
+            // every fetch + reset leaves the previous commit's objects behind
+            if synced.reshallowed {
+                gc(&entry.path, false).await;
+            } else if synced.fetched && self.gc {
+                gc(&entry.path, true).await;
+            }
+        }
 
-            let target_commit = match fetch_head.peel_to_commit() {
-                Ok(commit) => commit,
+        unindexed
+    }
+
+    /// keep an error of a repo for /api/v1/sync/errors unless error_retention is zero
+    ///
+    /// @param repo   name of the repo
+    /// @param phase  step of the sync cycle that failed
+    /// @param error  what went wrong
+    async fn record_error(&self, repo: &str, phase: &str, error: &str) {
+        if self.error_retention.is_zero() {
+            return;
+        }
+
+        if let Err(e) = self
+            .repo_db
+            .record_sync_error(repo, phase, error, utils::unix_time(), SYNC_ERRORS_KEPT)
+            .await
+        {
+            eprintln!("Failed to record sync error of {}: {}", repo, e);
+        }
+    }
+
+    /// bring a cloned repo up to date with its remote
+    /// fetching is skipped when the remote HEAD matches the local one
+    /// and repos whose initial clone failed get cloned again
+    fn sync_repo(&self, entry: &SyncedRepo) -> Result<RepoSync, String> {
+        let path = &entry.path;
+        if !path.is_dir() {
+            let shallow = clone(&entry.url, path).map_err(|e| format!("Failed to clone: {}", e))?;
+            if !shallow {
+                self.full_history.lock().unwrap().insert(path.clone(), 0);
+            }
+            println!(
+                "Successfully cloned repo {} to {}",
+                entry.url,
+                path.to_string_lossy()
+            );
+        }
+
+        let repo = Repository::open(path).map_err(|e| format!("Failed to open repo: {}", e))?;
+
+        let mut remote = repo
+            .find_remote("origin")
+            .map_err(|_| "Repository doesn't have remote \"origin\" to fetch from".to_string())?;
+
+        remote
+            .connect(Direction::Fetch)
+            .map_err(|e| format!("Failed to connect to remote: {}", e))?;
+
+        let default_branch = remote
+            .default_branch()
+            .map_err(|e| format!("Failed to get default branch: {}", e))?
+            .as_str()
+            .unwrap_or("refs/heads/main")
+            .to_string();
+
+        // ls-remote equivalent so idle syncs don't fetch anything
+        let local_head = repo.head().ok().and_then(|head| head.target());
+        let remote_head = remote.list().ok().and_then(|heads| {
+            heads
+                .iter()
+                .find(|head| head.name() == default_branch)
+                .map(|head| head.oid())
+        });
+        if let Some(head) = local_head
+            && Some(head) == remote_head
+        {
+            println!("Repo {} is up to date", path.to_string_lossy());
+            return Ok(RepoSync {
+                head: head.to_string(),
+                fetched: false,
+                reshallowed: false,
+            });
+        }
+
+        // perform a shallow fetch since we really don't need old commits here
+        // unless the remote rejected those before
+        let shallow = self.try_shallow(path);
+        let mut options = fetch_options(shallow);
+        let mut fetched = remote.fetch(&[default_branch.as_str()], Some(&mut options), None);
+        let mut reshallowed = false;
+
+        if let Err(e) = &fetched
+            && shallow
+            && is_shallow_unsupported(e)
+        {
+            eprintln!(
+                "Shallow fetch of {} unsupported, falling back to full fetch: {}",
+                path.to_string_lossy(),
+                e
+            );
+            self.full_history.lock().unwrap().insert(path.clone(), 0);
+            let mut options = fetch_options(false);
+            fetched = remote.fetch(&[default_branch.as_str()], Some(&mut options), None);
+        } else if fetched.is_ok()
+            && shallow
+            && self.full_history.lock().unwrap().remove(path).is_some()
+        {
+            println!("Repo {} is shallow again", path.to_string_lossy());
+            reshallowed = true;
+        }
+
+        fetched.map_err(|e| format!("Failed to fetch repo: {}", e))?;
+
+        let remote_tracking = format!(
+            "refs/remotes/origin/{}",
+            default_branch.split("/").last().unwrap_or("main")
+        );
+        let fetch_head = repo
+            .find_reference(remote_tracking.as_str())
+            .map_err(|e| format!("Failed to find fetch_head in repo: {}", e))?;
+
+        let target_commit = fetch_head
+            .peel_to_commit()
+            .map_err(|e| format!("Failed to find commit for fetch_head in repo: {}", e))?;
+
+        repo.reset(target_commit.as_object(), ResetType::Hard, None)
+            .map_err(|e| format!("Failed to reset repo to target commit: {}", e))?;
+
+        Ok(RepoSync {
+            head: target_commit.id().to_string(),
+            fetched: true,
+            reshallowed,
+        })
+    }
+
+    /// record disk usage of all repos in the database
+    async fn record_sizes(&self) {
+        for repo in self.repos.iter() {
+            let path = repo.path.clone();
+            let sizes = tokio::task::spawn_blocking(move || checkout_sizes(&path)).await;
+            let (checkout_size, git_size) = match sizes {
+                Ok(sizes) => sizes,
                 Err(e) => {
-                    eprintln!("Failed to find commit for fetch_head in repo: {}", e);
-                    failed.push(String::from(path.to_string_lossy()));
+                    eprintln!("Failed to measure repo {}: {}", repo.name, e);
                     continue;
                 }
             };
 
-            if let Err(e) = repo.reset(target_commit.as_object(), ResetType::Hard, None) {
-                eprintln!("Failed to reset repo to target commit: {}", e);
-                failed.push(String::from(path.to_string_lossy()));
-                continue;
+            if let Err(e) = self
+                .repo_db
+                .set_repo_stats(&repo.name, &repo.path, checkout_size, git_size)
+                .await
+            {
+                eprintln!("Failed to record size of repo {}: {}", repo.name, e);
             }
         }
+    }
 
-        if failed.is_empty() {
-            Ok(())
-        } else {
-            Err(format!("Failed repos: {}", failed.join(", ")))
+    /// whether the next fetch of the repo at path should be shallow
+    /// repos that fell back to full fetches retry shallow ones
+    /// every RESHALLOW_AFTER syncs
+    fn try_shallow(&self, path: &PathBuf) -> bool {
+        let mut full_history = self.full_history.lock().unwrap();
+        match full_history.get_mut(path) {
+            None => true,
+            Some(syncs) if *syncs >= RESHALLOW_AFTER => true,
+            Some(syncs) => {
+                *syncs += 1;
+                false
+            }
         }
     }
 
     /// parse all manifests and update the database
     /// returns Vec of paths with changed manifests
-    async fn parse_manifests(&self) -> Result<Vec<PathBuf>, String> {
-        let repos = self
-            .storage_root
-            .read_dir()
-            .map_err(|e| e.to_string())?
-            .filter_map(|x| match x {
-                Ok(x) if x.path().is_dir() => Some(x),
-                Ok(_) => None,
-                Err(_) => None,
-            });
+    /// and the names of repos which couldn't be walked
+    async fn parse_manifests(&self) -> (Vec<PathBuf>, Vec<String>) {
+        let started = utils::unix_time();
+        let mut complete = self.repos.iter().all(|repo| repo.path.is_dir());
+        let mut failed = Vec::new();
+        let repos = self.repos.iter().filter(|repo| repo.path.is_dir());
 
         // look through manifests
         let mut new = Vec::new();
         for repo in repos {
             println!(
                 "Parsing Manifest files in repo {}",
-                repo.path().to_string_lossy()
+                repo.path.to_string_lossy()
             );
-            let mut manifests = ManifestWalker::new(repo.path()).map_err(|e| e.to_string())?;
+            // a broken repo must not keep the others from being indexed
+            let mut manifests = match ManifestWalker::new(
+                repo.path.clone(),
+                repo.layout_conf,
+                ManifestDepth::detect(&repo.path, repo.manifest_depth),
+            ) {
+                Ok(manifests) => manifests
+                    .filter(repo.filter.clone())
+                    .skip_masked(self.skip_masked)
+                    .concurrency(self.manifest_concurrency),
+                Err(e) => {
+                    eprintln!("Failed to walk repo {}: {}", repo.name, e);
+                    self.record_error(&repo.name, "manifests", &e.to_string())
+                        .await;
+                    failed.push(repo.name.clone());
+                    complete = false;
+                    continue;
+                }
+            };
+            new.extend(self.index_manifests(&repo.name, &mut manifests).await);
+        }
+
+        // only a walk of every tree tells which entries got dropped
+        if complete && let Err(e) = self.repo_db.finish_tree_sweep(started).await {
+            eprintln!("Failed to record tree sweep: {}", e);
+        }
+
+        (new, failed)
+    }
+
+    /// insert all Manifest entries of a repo into the database
+    /// the walk, batching and database writes run as a pipeline
+    /// connected by bounded channels so a slow database holds back the walk
+    /// instead of entries piling up in memory
+    /// returns the Manifests with new or changed entries
+    ///
+    /// @param repo       name of the repo
+    /// @param manifests  walker over the repo's Manifests
+    async fn index_manifests(&self, repo: &str, manifests: &mut ManifestWalker) -> Vec<PathBuf> {
+        let (entry_tx, mut entry_rx) = mpsc::channel::<ManifestEntry>(MANIFEST_QUEUE);
+        let (batch_tx, batch_rx) = mpsc::channel(BATCH_QUEUE);
 
+        let writer = tokio::spawn(write_manifest_batches(
+            self.repo_db.clone(),
+            self.progress.clone(),
+            repo.to_string(),
+            batch_rx,
+        ));
+
+        let walk = async move {
             let entries = manifests.entries();
             pin_mut!(entries); // needed for iteration
+            let mut last_origin = None;
             while let Some(entry) = entries.next().await {
-                let origin = entry.origin.clone();
-                match self.repo_db.insert_manifest_entry(entry).await {
-                    Ok(_) => new.push(origin),
-                    Err(_) => (), // already present (I think)
-                                  // TODO: relying on an error to check this
-                                  //       feels bad
+                if last_origin.as_ref() != Some(&entry.origin) {
+                    self.progress.count(|counts| counts.manifests_parsed += 1);
+                    last_origin = Some(entry.origin.clone());
+                }
+                if entry_tx.send(entry).await.is_err() {
+                    break;
+                }
+            }
+        };
+
+        let batch = async move {
+            let mut batch = Vec::with_capacity(MANIFEST_BATCH);
+            while let Some(entry) = entry_rx.recv().await {
+                batch.push(entry);
+                if batch.len() < MANIFEST_BATCH {
+                    continue;
                 }
+
+                let full = std::mem::replace(&mut batch, Vec::with_capacity(MANIFEST_BATCH));
+                if batch_tx.send(full).await.is_err() {
+                    return;
+                }
+            }
+            if !batch.is_empty() {
+                let _ = batch_tx.send(batch).await;
+            }
+        };
+
+        tokio::join!(walk, batch);
+        writer.await.unwrap_or_else(|e| {
+            eprintln!("Manifest writer of {} failed: {}", repo, e);
+            Vec::new()
+        })
+    }
+
+    /// parse Manifests reported by the watchers and update the database
+    /// returns Vec of paths with changed manifests
+    async fn parse_changed_manifests(&self, mut changes: Vec<ManifestChange>) -> Vec<PathBuf> {
+        changes.sort_by(|a, b| a.manifest.cmp(&b.manifest));
+        changes.dedup_by(|a, b| a.manifest == b.manifest);
+
+        let mut masks = HashMap::new();
+        let mut new = Vec::new();
+        for change in changes {
+            if let Some(repo) = self.repos.iter().find(|repo| repo.name == change.repo)
+                && let Some(filter) = &repo.filter
+                && let Some(package) = change.manifest.parent()
+                && !filter.allows(package)
+            {
+                continue;
+            }
+            if self.skip_masked
+                && let Some(repo) = self.repos.iter().find(|repo| repo.name == change.repo)
+                && let Some(package) = change.manifest.parent()
+                && masks
+                    .entry(change.repo.clone())
+                    .or_insert_with(|| PackageMask::load(&repo.path))
+                    .covers(package)
+            {
+                continue;
+            }
+
+            // a single Manifest is small enough to insert at once
+            let entries: Vec<ManifestEntry> =
+                manifest_walker::manifest_entries(change.manifest.clone())
+                    .collect()
+                    .await;
+            match self
+                .repo_db
+                .insert_manifest_entries(&change.repo, &entries)
+                .await
+            {
+                Ok(inserted) if inserted.contains(&true) => new.push(change.manifest.clone()),
+                Ok(_) => (),
+                Err(e) => eprintln!(
+                    "Failed to insert entries of {}: {}",
+                    change.manifest.to_string_lossy(),
+                    e
+                ),
             }
         }
 
-        // deduplicate vector
-        // dedup() should always work here since
-        // we don't ever go back to a Manifest we already parsed
         new.dedup();
+        new
+    }
+
+    /// SRC_URIs of an ebuild
+    /// only runs the portage helper if the ebuild changed since it was last parsed
+    /// (changed eclasses alone don't trigger a new parse)
+    /// ebuilds the helper failed on are retried with backoff unless they change
+    /// and yield no SRC_URIs meanwhile
+    async fn parse_ebuild(&self, path: PathBuf) -> Result<SrcUriObj, String> {
+        let hash = utils::file_checksum(&path, HashType::Blake2b)
+            .await
+            .map_err(|e| e.to_string())?;
+        let key = path.to_string_lossy().to_string();
+
+        match self.repo_db.get_parsed_ebuild(&key, &hash).await {
+            Ok(Some(src_uri)) => return Ok(src_uri),
+            Ok(None) => (),
+            Err(e) => eprintln!("Failed to look up parsed ebuild {}: {}", key, e),
+        }
+
+        let now = utils::unix_time();
+        if let Ok(Some(failure)) = self.repo_db.get_parse_failure(&key).await
+            && failure.hash == hash
+            && failure.retry_at() > now
+        {
+            return Ok(SrcUriObj::new());
+        }
+
+        println!("Checking {}", key);
+        let parsed = match Ebuild::parse(&path, &self.helper).await {
+            Ok(parsed) => parsed,
+            Err(error) => {
+                if let Err(e) = self
+                    .repo_db
+                    .record_parse_failure(&key, &hash, &error, now)
+                    .await
+                {
+                    eprintln!("Failed to record parse failure of {}: {}", key, e);
+                }
+                return Err(error);
+            }
+        };
 
-        Ok(new)
+        if let Err(e) = self
+            .repo_db
+            .set_parsed_ebuild(&key, &hash, &parsed.src_uri)
+            .await
+        {
+            eprintln!("Failed to remember parsed ebuild {}: {}", key, e);
+        }
+        if let Err(e) = self.repo_db.clear_parse_failure(&key).await {
+            eprintln!("Failed to clear parse failure of {}: {}", key, e);
+        }
+
+        Ok(parsed.src_uri)
     }
 
     async fn parse_ebuilds(&self, manifests: Vec<PathBuf>) -> Result<(), String> {
+        // all ebuilds related to the manifests
+        let mut ebuilds = Vec::new();
         for manifest in manifests {
-            // parse all related ebuilds
-            let ebuilds = manifest
-                .parent()
-                .unwrap()
-                .read_dir()
-                .map_err(|e| e.to_string())?
-                .filter_map(|x| match x {
-                    Ok(x) => match x.path().extension() {
-                        Some(y) if y == "ebuild" => Some(x),
-                        Some(_) => None,
-                        None => None,
-                    },
-                    Err(_) => None,
-                });
+            ebuilds.extend(
+                manifest
+                    .parent()
+                    .unwrap()
+                    .read_dir()
+                    .map_err(|e| e.to_string())?
+                    .filter_map(|x| match x {
+                        Ok(x) => match x.path().extension() {
+                            Some(y) if y == "ebuild" => Some(x.path()),
+                            Some(_) => None,
+                            None => None,
+                        },
+                        Err(_) => None,
+                    }),
+            );
+        }
 
-            for ebuild in ebuilds {
-                println!("Checking {}", ebuild.path().to_string_lossy());
-                let parsed = Ebuild::parse(ebuild.path())
-                    .await
-                    .map_err(|e| e.to_string())?;
-
-                // add src_uris to database
-                for (file, src_uris) in parsed.src_uri {
-                    for uri in src_uris {
-                        match self.repo_db.insert_src_uri(file.clone(), uri.clone()).await {
-                            Ok(_) => println!("Added {} to database", &file),
-                            Err(_) => (), // Same as above, usually means already present
-                                          // TODO: make this less hacky
-                        }
+        // keep every helper process busy
+        let mut parsed = futures::stream::iter(ebuilds)
+            .map(|ebuild| self.parse_ebuild(ebuild))
+            .buffer_unordered(self.helper.size());
+
+        while let Some(src_uri) = parsed.next().await {
+            // one broken ebuild mustn't stop the others from getting indexed
+            let src_uri = match src_uri {
+                Ok(src_uri) => {
+                    self.progress.count(|counts| counts.ebuilds_parsed += 1);
+                    src_uri
+                }
+                Err(e) => {
+                    self.progress.count(|counts| counts.ebuilds_failed += 1);
+                    eprintln!("{}", e);
+                    continue;
+                }
+            };
+
+            // add src_uris to database
+            for (file, src_uris) in src_uri {
+                for src_uri in src_uris {
+                    if !src_uri.wanted(&self.parser) {
+                        continue;
+                    }
+
+                    // Same as above, errors usually mean already present
+                    // TODO: make this less hacky
+                    if self
+                        .repo_db
+                        .insert_conditional_src_uri(file.clone(), src_uri.uri, &src_uri.conditions)
+                        .await
+                        .is_ok()
+                    {
+                        println!("Added {} to database", &file);
                     }
                 }
             }
@@ -322,3 +997,140 @@ impl RepoSyncer {
         Ok(())
     }
 }
+
+/// fetch options for shallow or full fetches
+fn fetch_options<'a>(shallow: bool) -> git2::FetchOptions<'a> {
+    let mut options = git2::FetchOptions::new();
+    if shallow {
+        options.depth(1);
+    }
+    options
+}
+
+/// whether a git error means the remote doesn't support shallow fetches
+/// e.g. dumb HTTP remotes or the local transport
+fn is_shallow_unsupported(e: &git2::Error) -> bool {
+    let message = e.message().to_lowercase();
+    message.contains("shallow") || message.contains("depth")
+}
+
+/// clone a repo, shallow if the remote supports it
+/// returns whether the clone is shallow
+fn clone(url: &str, path: &Path) -> Result<bool, git2::Error> {
+    let mut builder = git2::build::RepoBuilder::new();
+    builder.fetch_options(fetch_options(true));
+    match builder.clone(url, path) {
+        Ok(_) => return Ok(true),
+        Err(e) if is_shallow_unsupported(&e) => eprintln!(
+            "Shallow clone of {} unsupported, falling back to full clone: {}",
+            url, e
+        ),
+        Err(e) => return Err(e),
+    }
+
+    // a failed clone may leave a partial checkout behind
+    if path.exists() {
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    let mut builder = git2::build::RepoBuilder::new();
+    builder.fetch_options(fetch_options(false));
+    builder.clone(url, path)?;
+    Ok(false)
+}
+
+/// drop objects no longer reachable from the checked out commit
+/// libgit2 can't gc so this needs the git cli
+///
+/// @param path  location of the repo
+/// @param auto  let git decide whether enough garbage piled up
+async fn gc(path: &Path, auto: bool) {
+    let mut command = tokio::process::Command::new("git");
+    command
+        .arg("-C")
+        .arg(path)
+        // the clones are ours so there's no point in keeping
+        // unreachable objects or reflogs around
+        .args([
+            "-c",
+            "gc.pruneExpire=now",
+            "-c",
+            "gc.reflogExpire=now",
+            "-c",
+            "gc.reflogExpireUnreachable=now",
+            "gc",
+            "--quiet",
+        ]);
+    if auto {
+        command.arg("--auto");
+    }
+    let status = command.status().await;
+
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => eprintln!("git gc in {} failed: {}", path.to_string_lossy(), status),
+        Err(e) => eprintln!("Failed to run git gc in {}: {}", path.to_string_lossy(), e),
+    }
+}
+
+/// size of a checkout's working tree and its .git directory in bytes
+fn checkout_sizes(path: &Path) -> (u64, u64) {
+    let git_dir = path.join(".git");
+    let mut checkout_size = 0;
+    let mut git_size = 0;
+
+    for entry in walkdir::WalkDir::new(path).into_iter().flatten() {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+        if entry.path().starts_with(&git_dir) {
+            git_size += size;
+        } else {
+            checkout_size += size;
+        }
+    }
+
+    (checkout_size, git_size)
+}
+
+/// write batches of Manifest entries to the database until the channel closes
+/// returns the Manifests with new or changed entries
+///
+/// @param repo_db   database to write to
+/// @param progress  where changed and unchanged entries get counted
+/// @param repo      name of the repo the entries belong to
+/// @param batches   receiving end of the batching stage
+async fn write_manifest_batches(
+    repo_db: Arc<RepoDB>,
+    progress: Arc<SyncProgress>,
+    repo: String,
+    mut batches: mpsc::Receiver<Vec<ManifestEntry>>,
+) -> Vec<PathBuf> {
+    let mut new: Vec<PathBuf> = Vec::new();
+    while let Some(batch) = batches.recv().await {
+        let inserted = match repo_db.insert_manifest_entries(&repo, &batch).await {
+            Ok(inserted) => inserted,
+            Err(e) => {
+                eprintln!("Failed to insert Manifest entries of {}: {}", repo, e);
+                continue;
+            }
+        };
+
+        let changed = inserted.iter().filter(|inserted| **inserted).count() as u64;
+        progress.count(|counts| {
+            counts.entries_changed += changed;
+            counts.entries_unchanged += inserted.len() as u64 - changed;
+        });
+
+        for (entry, inserted) in batch.into_iter().zip(inserted) {
+            // entries of a Manifest arrive in a row
+            if inserted && new.last() != Some(&entry.origin) {
+                new.push(entry.origin);
+            }
+        }
+    }
+
+    new
+}
@@ -0,0 +1,183 @@
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::sync::Notify;
+
+use crate::utils;
+
+/// what a sync cycle is currently doing
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncPhase {
+    /// waiting for the next cycle
+    #[default]
+    Idle,
+
+    /// fetching repos
+    Syncing,
+
+    /// walking Manifests
+    Manifests,
+
+    /// running the portage helper on ebuilds
+    Ebuilds,
+}
+
+/// counters of a sync cycle
+#[derive(Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SyncCounts {
+    /// repos synced successfully
+    pub repos_synced: u64,
+
+    /// repos whose sync failed
+    pub repos_failed: u64,
+
+    /// Manifests walked
+    pub manifests_parsed: u64,
+
+    /// Manifest entries which were new or changed
+    pub entries_changed: u64,
+
+    /// Manifest entries which were already known
+    pub entries_unchanged: u64,
+
+    /// ebuilds whose SRC_URIs got looked up
+    pub ebuilds_parsed: u64,
+
+    /// ebuilds the portage helper failed on
+    pub ebuilds_failed: u64,
+}
+
+/// summary of a finished sync cycle
+#[derive(Serialize, Clone, Debug)]
+pub struct SyncReport {
+    /// start of the cycle as unix timestamp
+    pub started: u64,
+
+    /// end of the cycle as unix timestamp
+    pub finished: u64,
+
+    /// duration of the cycle in seconds
+    pub elapsed: f64,
+
+    /// whether indexing got skipped as nothing changed
+    pub skipped: bool,
+
+    /// what the cycle did
+    pub counts: SyncCounts,
+}
+
+/// snapshot of the sync state
+#[derive(Serialize, Clone, Debug)]
+pub struct SyncStatus {
+    /// what the running cycle is doing
+    pub phase: SyncPhase,
+
+    /// start of the running cycle as unix timestamp
+    pub started: Option<u64>,
+
+    /// seconds the running cycle has taken so far
+    pub elapsed: Option<f64>,
+
+    /// what the running cycle did so far
+    pub counts: SyncCounts,
+
+    /// the last finished cycle
+    pub last: Option<SyncReport>,
+}
+
+/// progress of the sync cycles shared with the HTTP API
+/// so operators can tell a stuck sync from a slow one
+#[derive(Default)]
+pub struct SyncProgress {
+    /// state of the running and the last cycle
+    state: Mutex<ProgressState>,
+
+    /// wakes the syncer for a cycle outside of repo.sync_interval
+    requested: Notify,
+}
+
+/// mutable part of SyncProgress
+#[derive(Default)]
+struct ProgressState {
+    /// what the running cycle is doing
+    phase: SyncPhase,
+
+    /// start of the running cycle
+    started: Option<(u64, Instant)>,
+
+    /// what the running cycle did so far
+    counts: SyncCounts,
+
+    /// the last finished cycle
+    last: Option<SyncReport>,
+}
+
+impl SyncProgress {
+    /// begin a new cycle resetting all counters
+    pub fn start(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.phase = SyncPhase::Syncing;
+        state.started = Some((utils::unix_time(), Instant::now()));
+        state.counts = SyncCounts::default();
+    }
+
+    /// move the running cycle to its next phase
+    pub fn phase(&self, phase: SyncPhase) {
+        self.state.lock().unwrap().phase = phase;
+    }
+
+    /// update the counters of the running cycle
+    ///
+    /// @param update  closure modifying the counters
+    pub fn count(&self, update: impl FnOnce(&mut SyncCounts)) {
+        update(&mut self.state.lock().unwrap().counts);
+    }
+
+    /// end the running cycle and keep its report
+    ///
+    /// @param skipped  whether indexing got skipped
+    pub fn finish(&self, skipped: bool) -> SyncReport {
+        let mut state = self.state.lock().unwrap();
+        let (started, instant) = state
+            .started
+            .take()
+            .unwrap_or((utils::unix_time(), Instant::now()));
+        let report = SyncReport {
+            started,
+            finished: utils::unix_time(),
+            elapsed: instant.elapsed().as_secs_f64(),
+            skipped,
+            counts: std::mem::take(&mut state.counts),
+        };
+
+        state.phase = SyncPhase::Idle;
+        state.last = Some(report.clone());
+        report
+    }
+
+    /// ask the syncer for a cycle right away
+    /// requests while a cycle is running start another one once it finished
+    pub fn request_sync(&self) {
+        self.requested.notify_one();
+    }
+
+    /// wait for request_sync()
+    pub async fn sync_requested(&self) {
+        self.requested.notified().await;
+    }
+
+    /// current state of the sync
+    pub fn status(&self) -> SyncStatus {
+        let state = self.state.lock().unwrap();
+        SyncStatus {
+            phase: state.phase,
+            started: state.started.map(|(started, _)| started),
+            elapsed: state
+                .started
+                .map(|(_, instant)| instant.elapsed().as_secs_f64()),
+            counts: state.counts.clone(),
+            last: state.last.clone(),
+        }
+    }
+}
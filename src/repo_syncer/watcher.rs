@@ -0,0 +1,97 @@
+use notify::event::{EventKind, ModifyKind};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+
+use crate::manifest_walker::ManifestDepth;
+
+/// a Manifest that changed on disk
+pub(super) struct ManifestChange {
+    /// name of the repo the Manifest belongs to
+    pub repo: String,
+
+    /// location of the Manifest
+    pub manifest: PathBuf,
+}
+
+/// watch a repo checkout for changed Manifests
+/// changes get sent to the syncer which re-parses them
+/// dropping the returned watcher stops watching
+///
+/// @param repo   name of the repo
+/// @param root   location of the checkout
+/// @param depth  depth the repo's Manifests are at
+/// @param tx     channel the changes get sent to
+pub(super) fn watch(
+    repo: String,
+    root: PathBuf,
+    depth: ManifestDepth,
+    tx: mpsc::UnboundedSender<ManifestChange>,
+) -> notify::Result<RecommendedWatcher> {
+    let watch_root = root.clone();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                eprintln!("Watcher error for repo {}: {}", repo, e);
+                return;
+            }
+        };
+
+        // only content changes and newly appearing files matter
+        match event.kind {
+            EventKind::Create(_)
+            | EventKind::Modify(ModifyKind::Data(_))
+            | EventKind::Modify(ModifyKind::Name(_))
+            | EventKind::Modify(ModifyKind::Any) => {}
+            _ => return,
+        }
+
+        // directories also appear by being moved into the checkout
+        let created = matches!(
+            event.kind,
+            EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(_))
+        );
+        for path in event.paths {
+            if is_manifest(&watch_root, depth, &path) {
+                let _ = tx.send(ManifestChange {
+                    repo: repo.clone(),
+                    manifest: path,
+                });
+            } else if created && path.is_dir() {
+                // files in new directories may be written before
+                // the watch on them is set up so look for them now
+                // e.g. when a whole package directory gets created or moved in
+                for manifest in walkdir::WalkDir::new(&path)
+                    .into_iter()
+                    .flatten()
+                    .map(|entry| entry.into_path())
+                    .filter(|entry| is_manifest(&watch_root, depth, entry))
+                {
+                    let _ = tx.send(ManifestChange {
+                        repo: repo.clone(),
+                        manifest,
+                    });
+                }
+            }
+        }
+    })?;
+
+    watcher.watch(&root, RecursiveMode::Recursive)?;
+    Ok(watcher)
+}
+
+/// whether path is a Manifest at depth below root
+/// e.g. <root>/category/package/Manifest in standard trees
+fn is_manifest(root: &Path, depth: ManifestDepth, path: &Path) -> bool {
+    match path.strip_prefix(root) {
+        Ok(relative) => {
+            depth.matches(relative.components().count())
+                && relative.ends_with("Manifest")
+                && !relative
+                    .components()
+                    .any(|component| component.as_os_str().to_string_lossy().starts_with('.'))
+        }
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,88 @@
+use rocket::Response;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::request::Request;
+
+tokio::task_local! {
+    /// id of the request the running task serves
+    static CURRENT: RequestId;
+}
+
+/// response header carrying the request id
+pub const HEADER: &str = "X-Request-Id";
+
+/// id of a frontend request
+/// sent back in X-Request-Id and prefixed to the log lines of its fetch
+/// so failed downloads reported by users can be found in the logs
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// id of req
+    /// keeps a sane X-Request-Id set by a reverse proxy in front of us
+    /// and generates a new one otherwise
+    pub fn of(req: &Request<'_>) -> Self {
+        req.local_cache(|| {
+            let forwarded = req.headers().get_one(HEADER).filter(|id| {
+                !id.is_empty()
+                    && id.len() <= 64
+                    && id
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+            });
+            match forwarded {
+                Some(id) => RequestId(id.to_string()),
+                None => RequestId(format!("{:016x}", fastrand::u64(..))),
+            }
+        })
+        .clone()
+    }
+
+    /// run future with this id as the current request id
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CURRENT.scope(self, future).await
+    }
+}
+
+/// id of the request the running task serves if any
+pub fn current() -> Option<String> {
+    CURRENT.try_with(|id| id.0.clone()).ok()
+}
+
+/// prefix for log lines, "[<id>] " within a request and empty otherwise
+pub fn prefix() -> String {
+    current().map(|id| format!("[{}] ", id)).unwrap_or_default()
+}
+
+/// println! prefixed with the current request id
+macro_rules! req_println {
+    ($($arg:tt)*) => {
+        println!("{}{}", $crate::request_id::prefix(), format_args!($($arg)*))
+    };
+}
+
+/// eprintln! prefixed with the current request id
+macro_rules! req_eprintln {
+    ($($arg:tt)*) => {
+        eprintln!("{}{}", $crate::request_id::prefix(), format_args!($($arg)*))
+    };
+}
+
+pub(crate) use {req_eprintln, req_println};
+
+/// fairing sending the id of every request back in X-Request-Id
+pub struct RequestIds;
+
+#[rocket::async_trait]
+impl Fairing for RequestIds {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request ids",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        res.set_header(Header::new(HEADER, RequestId::of(req).0));
+    }
+}
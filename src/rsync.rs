@@ -0,0 +1,162 @@
+use nix::sys::prctl;
+use nix::sys::signal::Signal;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::process::{Child, Command};
+
+use crate::config::{Config, RsyncConfig};
+use crate::utils;
+
+/// files in the blob storage which aren't distfiles
+const EXCLUDES: &[&str] = &["/.portcache-probe", "*.part", "*.stale"];
+
+/// rsync daemon exporting the blob storage
+/// the storage already uses the filename-hash layout of Gentoo mirrors
+/// so the module only needs a layout.conf next to the hash directories
+pub struct Rsyncd {
+    /// [rsync] settings
+    config: RsyncConfig,
+
+    /// root of the blob storage i.e. the exported path
+    root: PathBuf,
+
+    /// location of the generated rsyncd.conf
+    config_path: PathBuf,
+
+    /// location of the lock file used for max connections
+    lock_path: PathBuf,
+
+    /// length of the hash directories announced in layout.conf
+    hash_bits: u8,
+
+    /// address to listen on
+    address: IpAddr,
+
+    /// user and group rsync switches to when started as root
+    user: Option<String>,
+    group: Option<String>,
+}
+
+impl Rsyncd {
+    /// create an Rsyncd from config
+    /// returns None when rsync.enabled is false
+    pub fn new(config: &Config) -> Option<Self> {
+        if !config.rsync.enabled {
+            return None;
+        }
+
+        let storage = &config.storage.location;
+        Some(Self {
+            config: config.rsync.clone(),
+            root: storage.join("distfiles"),
+            config_path: config
+                .rsync
+                .config
+                .clone()
+                .unwrap_or_else(|| storage.join("rsyncd.conf")),
+            lock_path: storage.join("rsyncd.lock"),
+            hash_bits: config.storage.hash_bits,
+            address: config.rsync.address.unwrap_or(config.server.address),
+            user: config.server.user.clone(),
+            group: config.server.group.clone(),
+        })
+    }
+
+    /// location of the generated rsyncd.conf
+    pub fn config_path(&self) -> &Path {
+        &self.config_path
+    }
+
+    /// content of the generated rsyncd.conf
+    pub fn rsyncd_conf(&self) -> String {
+        let mut conf = String::from("# generated by portcache - changes get overwritten\n");
+        conf.push_str(&format!("address = {}\n", self.address));
+        conf.push_str(&format!("port = {}\n", self.config.port));
+        conf.push_str(&format!(
+            "lock file = {}\n",
+            self.lock_path.to_string_lossy()
+        ));
+        conf.push_str("use chroot = no\n");
+
+        conf.push_str(&format!("\n[{}]\n", self.config.module));
+        conf.push_str(&format!("path = {}\n", self.root.to_string_lossy()));
+        conf.push_str("comment = portcache distfiles\n");
+        conf.push_str("read only = yes\n");
+        conf.push_str(&format!(
+            "max connections = {}\n",
+            self.config.max_connections
+        ));
+        conf.push_str(&format!("exclude = {}\n", EXCLUDES.join(" ")));
+        if let Some(user) = &self.user {
+            conf.push_str(&format!("uid = {}\n", user));
+        }
+        if let Some(group) = &self.group {
+            conf.push_str(&format!("gid = {}\n", group));
+        }
+
+        conf
+    }
+
+    /// write rsyncd.conf and the layout.conf of the module
+    pub fn write_config(&self) -> Result<(), String> {
+        let layout_conf = self.root.join("layout.conf");
+        std::fs::write(&layout_conf, utils::layout_conf(self.hash_bits, false))
+            .map_err(|e| format!("Cannot write {}: {}", layout_conf.to_string_lossy(), e))?;
+        std::fs::write(&self.config_path, self.rsyncd_conf())
+            .map_err(|e| format!("Cannot write {}: {}", self.config_path.to_string_lossy(), e))?;
+        Ok(())
+    }
+
+    /// write the config and start rsync --daemon if rsync.spawn is set
+    /// ports below 1024 need this to happen before privileges are dropped
+    pub fn spawn(&self) -> Result<Option<Child>, String> {
+        self.write_config()?;
+        if !self.config.spawn {
+            println!(
+                "Wrote rsync config to {}",
+                self.config_path.to_string_lossy()
+            );
+            return Ok(None);
+        }
+
+        let mut command = Command::new(&self.config.binary);
+        command
+            .arg("--daemon")
+            .arg("--no-detach")
+            .arg(format!("--config={}", self.config_path.to_string_lossy()))
+            .stdin(Stdio::null())
+            .kill_on_drop(true);
+
+        // SAFETY: prctl is async-signal-safe and doesn't allocate
+        unsafe {
+            command.pre_exec(|| {
+                prctl::set_pdeathsig(Signal::SIGTERM)?;
+                Ok(())
+            });
+        }
+
+        let child = command.spawn().map_err(|e| {
+            format!(
+                "Failed to start {}: {}",
+                self.config.binary.to_string_lossy(),
+                e
+            )
+        })?;
+
+        println!(
+            "Serving rsync://{}:{}/{}",
+            self.address, self.config.port, self.config.module
+        );
+        Ok(Some(child))
+    }
+}
+
+/// wait for a spawned rsync daemon and report when it exits
+/// this is expected to be called from a tokio::spawn
+pub async fn supervise(mut child: Child) {
+    match child.wait().await {
+        Ok(status) => eprintln!("rsync daemon exited: {}", status),
+        Err(e) => eprintln!("Failed to wait for rsync daemon: {}", e),
+    }
+}
@@ -0,0 +1,170 @@
+use landlock::{
+    ABI, Access, AccessFs, AccessNet, NetPort, Ruleset, RulesetAttr, RulesetCreatedAttr,
+    RulesetError, RulesetStatus, path_beneath_rules,
+};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+
+/// newest landlock ABI the rules are written for
+/// older kernels enforce the subset they support
+const ABI_VERSION: ABI = ABI::V4;
+
+/// system paths needed to run git, the portage helper and to verify TLS
+const SYSTEM_PATHS: &[&str] = &[
+    "/usr", "/bin", "/sbin", "/lib", "/lib32", "/lib64", "/etc", "/proc", "/dev",
+];
+
+/// devices child processes write to
+const DEVICE_PATHS: &[&str] = &["/dev/null", "/dev/zero", "/dev/full"];
+
+/// restrict filesystem access and TCP connections of the process
+/// this only affects the calling thread and threads or processes
+/// started afterwards so it has to run before the async runtime exists
+/// returns whether the kernel enforces all restrictions
+///
+/// @param config  a reference to Config
+pub fn apply(config: &Config) -> Result<bool, String> {
+    let abi = ABI_VERSION;
+
+    let mut read_only: Vec<PathBuf> = SYSTEM_PATHS.iter().map(PathBuf::from).collect();
+    read_only.extend(
+        config
+            .repo
+            .repos
+            .iter()
+            .filter_map(|repo| repo.local_path().map(Path::to_path_buf)),
+    );
+    read_only.extend(
+        config
+            .releases
+            .iter()
+            .map(|releases| releases.keyring.clone()),
+    );
+    read_only.extend(config.fetcher.tls.ca_bundle.iter().cloned());
+    read_only.extend(
+        config
+            .fetcher
+            .mirrors
+            .iter()
+            .filter_map(|mirror| mirror.password_file.clone()),
+    );
+    read_only.extend(config.sandbox.read_only.iter().cloned());
+
+    let mut read_write = vec![config.storage.location.clone()];
+    read_write.extend(DEVICE_PATHS.iter().map(PathBuf::from));
+    read_write.extend(config.import.iter().map(|import| import.directory.clone()));
+    read_write.extend(config.storage.local_distdir.iter().cloned());
+    // binding the control socket creates it in its directory
+    read_write.extend(
+        config
+            .control
+            .iter()
+            .filter_map(|control| control.socket.parent().map(PathBuf::from)),
+    );
+    // the rsync config gets written once the sandbox is in place
+    if config.rsync.enabled {
+        read_write.extend(
+            config
+                .rsync
+                .config
+                .iter()
+                .filter_map(|path| path.parent().map(PathBuf::from)),
+        );
+    }
+    read_write.extend(config.sandbox.read_write.iter().cloned());
+
+    let status = Ruleset::default()
+        .handle_access(AccessFs::from_all(abi))
+        .and_then(|ruleset| ruleset.handle_access(AccessNet::from_all(abi)))
+        .and_then(|ruleset| ruleset.create())
+        .and_then(|ruleset| {
+            ruleset.add_rules(path_beneath_rules(read_only, AccessFs::from_read(abi)))
+        })
+        .and_then(|ruleset| {
+            ruleset.add_rules(path_beneath_rules(read_write, AccessFs::from_all(abi)))
+        })
+        .and_then(|ruleset| {
+            ruleset.add_rules(
+                bind_ports(config)
+                    .into_iter()
+                    .map(|port| Ok::<_, RulesetError>(NetPort::new(port, AccessNet::BindTcp))),
+            )
+        })
+        .and_then(|ruleset| {
+            ruleset.add_rules(
+                connect_ports(config)
+                    .into_iter()
+                    .map(|port| Ok::<_, RulesetError>(NetPort::new(port, AccessNet::ConnectTcp))),
+            )
+        })
+        .and_then(|ruleset| ruleset.restrict_self())
+        .map_err(|e| format!("Failed to set up sandbox: {}", e))?;
+
+    match status.ruleset {
+        RulesetStatus::FullyEnforced => {
+            println!("Sandbox enforced");
+            Ok(true)
+        }
+        RulesetStatus::PartiallyEnforced => {
+            eprintln!("Sandbox only partially enforced - the kernel lacks some landlock features");
+            Ok(false)
+        }
+        RulesetStatus::NotEnforced => {
+            eprintln!("Sandbox not enforced - the kernel doesn't support landlock");
+            Ok(false)
+        }
+    }
+}
+
+/// TCP ports the server and the rsync daemon listen on
+fn bind_ports(config: &Config) -> BTreeSet<u16> {
+    let mut ports = BTreeSet::from([config.server.port]);
+    if config.rsync.enabled && config.rsync.spawn {
+        ports.insert(config.rsync.port);
+    }
+    ports
+}
+
+/// TCP ports of all configured upstreams, repos and the OTLP endpoint
+/// plus sandbox.connect_ports
+pub fn connect_ports(config: &Config) -> BTreeSet<u16> {
+    let fetcher = &config.fetcher;
+    let urls = fetcher
+        .mirrors
+        .iter()
+        .map(|mirror| &mirror.url)
+        .chain(fetcher.peers.iter())
+        .chain(fetcher.proxies.iter())
+        .chain(
+            fetcher
+                .ipfs
+                .iter()
+                .flat_map(|ipfs| std::iter::once(&ipfs.gateway).chain(ipfs.api.iter())),
+        )
+        .chain(config.repo.repos.iter().map(|repo| &repo.url))
+        .chain(config.binhost.iter().map(|binhost| &binhost.upstream))
+        .chain(
+            config
+                .releases
+                .iter()
+                .flat_map(|releases| releases.mirrors.iter()),
+        )
+        .chain(config.telemetry.otlp_endpoint.iter());
+
+    urls.filter_map(|url| url_port(url))
+        .chain(config.sandbox.connect_ports.iter().copied())
+        .collect()
+}
+
+/// port a url connects to
+/// e.g. https://example.org -> 443
+fn url_port(url: &str) -> Option<u16> {
+    let url = reqwest::Url::parse(url).ok()?;
+    url.port_or_known_default().or(match url.scheme() {
+        "ssh" => Some(22),
+        "git" => Some(9418),
+        _ => None,
+    })
+}
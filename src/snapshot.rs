@@ -0,0 +1,129 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::{fs, time};
+
+use crate::config::Config;
+use crate::repo_db::RepoDB;
+use crate::utils;
+
+/// prefix of snapshot file names
+const PREFIX: &str = "db-";
+
+/// suffix of snapshot file names
+const SUFFIX: &str = ".sqlite3";
+
+/// takes periodic snapshots of the repo database
+/// so it can be restored after corruption without rescanning every repo
+pub struct Snapshotter {
+    /// database to snapshot
+    repo_db: Arc<RepoDB>,
+
+    /// directory the snapshots are written to
+    dir: PathBuf,
+
+    /// interval between snapshots
+    interval: Duration,
+
+    /// number of snapshots to keep
+    keep: usize,
+}
+
+impl Snapshotter {
+    /// create a Snapshotter from config
+    /// returns None without storage.database.snapshot_interval
+    pub fn new(config: &Config, repo_db: Arc<RepoDB>) -> Option<Self> {
+        let database = &config.storage.database;
+        Some(Self {
+            repo_db,
+            dir: snapshot_dir(config),
+            interval: database.snapshot_interval?,
+            keep: database.snapshot_keep,
+        })
+    }
+
+    /// start the Snapshotter
+    /// this is expected to be called from a tokio::spawn
+    /// and consumes the Snapshotter
+    pub async fn start(self) {
+        let mut interval = time::interval(self.interval);
+        // the first tick completes right away
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            match self.snapshot().await {
+                Ok(path) => println!("Wrote database snapshot {}", path.to_string_lossy()),
+                Err(e) => eprintln!("Database snapshot failed: {}", e),
+            }
+        }
+    }
+
+    /// write a snapshot and drop the ones exceeding keep
+    /// returns the location of the new snapshot
+    pub async fn snapshot(&self) -> Result<PathBuf, String> {
+        let path = write_snapshot(&self.repo_db, &self.dir).await?;
+        prune(&self.dir, self.keep).await?;
+        Ok(path)
+    }
+}
+
+/// directory snapshots are written to by default
+pub fn snapshot_dir(config: &Config) -> PathBuf {
+    config.storage.location.join("snapshots")
+}
+
+/// write a snapshot named after the current time into dir
+/// returns its location
+///
+/// @param repo_db  database to snapshot
+/// @param dir      directory to write to
+pub async fn write_snapshot(repo_db: &RepoDB, dir: &Path) -> Result<PathBuf, String> {
+    fs::create_dir_all(dir)
+        .await
+        .map_err(|e| format!("Cannot create {}: {}", dir.to_string_lossy(), e))?;
+    let path = dir.join(format!("{}{}{}", PREFIX, utils::unix_time(), SUFFIX));
+    repo_db.backup(&path).await?;
+    Ok(path)
+}
+
+/// snapshots in dir, oldest first
+///
+/// @param dir  directory to look in
+pub async fn list(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Cannot read {}: {}", dir.to_string_lossy(), e)),
+    };
+
+    let mut snapshots = Vec::new();
+    while let Some(entry) = entries.next_entry().await.map_err(|e| e.to_string())? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let time = name
+            .strip_prefix(PREFIX)
+            .and_then(|rest| rest.strip_suffix(SUFFIX))
+            .and_then(|time| time.parse::<u64>().ok());
+        if let Some(time) = time {
+            snapshots.push((time, entry.path()));
+        }
+    }
+
+    snapshots.sort();
+    Ok(snapshots.into_iter().map(|(_, path)| path).collect())
+}
+
+/// remove all but the newest keep snapshots in dir
+async fn prune(dir: &Path, keep: usize) -> Result<(), String> {
+    let snapshots = list(dir).await?;
+    let excess = snapshots.len().saturating_sub(keep);
+    for old in &snapshots[..excess] {
+        if let Err(e) = fs::remove_file(old).await {
+            eprintln!(
+                "Cannot remove old snapshot {}: {}",
+                old.to_string_lossy(),
+                e
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,511 @@
+use rocket::data::{Data, ToByteUnit};
+use rocket::http::{ContentType, Status};
+use rocket::{State, get, post};
+use std::sync::atomic::Ordering;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::app::SharedData;
+use crate::compression;
+use crate::distfile_name::DistfileName;
+use crate::evictor::Evictor;
+use crate::repo_db::RepoDB;
+use crate::{FEATURES, GIT_COMMIT};
+
+/// statistics about the cache
+/// currently disk usage and sync status of the repo checkouts
+/// and bytes of cached distfiles per repo
+#[get("/api/v1/stats")]
+pub(crate) async fn stats(shared: &State<SharedData>) -> Result<(ContentType, String), Status> {
+    let body = report(&shared.repo_db, &shared.evictor)
+        .await
+        .map_err(|e| {
+            eprintln!("{}", e);
+            Status::InternalServerError
+        })?;
+
+    Ok((ContentType::JSON, body.to_string()))
+}
+
+/// the statistics served at /api/v1/stats
+///
+/// @param repo_db  repo database with the repo stats
+/// @param evictor  evictor knowing the cached bytes per repo
+pub(crate) async fn report(
+    repo_db: &RepoDB,
+    evictor: &Evictor,
+) -> Result<serde_json::Value, String> {
+    let repos = repo_db
+        .get_repo_stats()
+        .await
+        .map_err(|e| format!("Failed to query repo stats: {}", e))?;
+
+    let repos: Vec<serde_json::Value> = repos
+        .iter()
+        .map(|repo| {
+            serde_json::json!({
+                "name": repo.name,
+                "path": repo.path.to_string_lossy(),
+                "checkout_size": repo.checkout_size,
+                "git_size": repo.git_size,
+                "updated": repo.updated,
+                "last_sync": repo.last_sync,
+                "last_sync_failure": repo.last_sync_failure,
+                "sync_error": repo.sync_error,
+            })
+        })
+        .collect();
+
+    let usage = evictor
+        .repo_usage()
+        .await
+        .map_err(|e| format!("Failed to query repo usage: {}", e))?;
+    let usage: Vec<serde_json::Value> = usage
+        .iter()
+        .map(|repo| {
+            serde_json::json!({
+                "name": repo.name,
+                "cached_size": repo.cached_size,
+                "max_size": repo.max_size,
+            })
+        })
+        .collect();
+
+    Ok(serde_json::json!({ "repos": repos, "cached": usage }))
+}
+
+/// number of blobs per hash directory of the storage
+/// shows how evenly blobs are sharded with the configured storage.hash_bits
+#[get("/api/v1/stats/buckets")]
+pub(crate) async fn buckets(shared: &State<SharedData>) -> Result<(ContentType, String), Status> {
+    let buckets = shared.blob_storage.bucket_counts().await.map_err(|e| {
+        eprintln!("Failed to count blobs per bucket: {}", e);
+        Status::InternalServerError
+    })?;
+
+    let body = serde_json::json!({
+        "hash_bits": shared.blob_storage.hash_bits(),
+        "blobs": buckets.values().sum::<u64>(),
+        "buckets_used": buckets.len(),
+        "max": buckets.values().max().copied().unwrap_or(0),
+        "buckets": buckets,
+    });
+
+    Ok((ContentType::JSON, body.to_string()))
+}
+
+/// state of the repo syncer
+/// the running cycle with its phase and counts so far and the last finished one
+#[get("/api/v1/sync")]
+pub(crate) async fn sync(shared: &State<SharedData>) -> (ContentType, String) {
+    let body = serde_json::to_string(&shared.sync_progress.status())
+        .unwrap_or_else(|_| String::from("{}"));
+    (ContentType::JSON, body)
+}
+
+/// recent errors of the repo syncer, latest first
+/// repeats of an error show up once with their count, old ones expire after repo.error_retention
+#[get("/api/v1/sync/errors?<repo>")]
+pub(crate) async fn sync_errors(
+    repo: Option<&str>,
+    shared: &State<SharedData>,
+) -> Result<(ContentType, String), Status> {
+    let errors = shared.repo_db.get_sync_errors(repo).await.map_err(|e| {
+        eprintln!("Failed to query sync errors: {}", e);
+        Status::InternalServerError
+    })?;
+
+    let body = serde_json::json!({ "errors": errors });
+    Ok((ContentType::JSON, body.to_string()))
+}
+
+/// distfiles first referenced by a Manifest since the last sync cycle started
+/// or since the given unix timestamp, e.g. new package versions worth prefetching
+#[get("/api/v1/sync/new?<since>")]
+pub(crate) async fn new_distfiles(
+    since: Option<u64>,
+    shared: &State<SharedData>,
+) -> Result<(ContentType, String), Status> {
+    let since = match since {
+        Some(since) => since,
+        None => match shared.sync_progress.status().last {
+            Some(last) => last.started,
+            None => return Ok((ContentType::JSON, String::from("{\"distfiles\":[]}"))),
+        },
+    };
+
+    let distfiles = shared.repo_db.get_new_distfiles(since).await.map_err(|e| {
+        eprintln!("Failed to query new distfiles: {}", e);
+        Status::InternalServerError
+    })?;
+
+    let body = serde_json::json!({
+        "since": since,
+        "size": distfiles.iter().map(|distfile| distfile.size).sum::<u64>(),
+        "distfiles": distfiles,
+    });
+    Ok((ContentType::JSON, body.to_string()))
+}
+
+/// daemon status for load balancer health checks like HAProxy's httpchk
+/// answers 503 while free space is below server.status_min_free so the node gets drained
+#[get("/status")]
+pub(crate) async fn status(shared: &State<SharedData>) -> (Status, (ContentType, String)) {
+    let fetches = shared.blob_storage.fetch_counts().await;
+    let sync = shared.sync_progress.status();
+    let disk = shared.blob_storage.disk_space();
+
+    let (healthy, disk) = match disk {
+        Ok(disk) => (
+            shared
+                .status_min_free
+                .is_none_or(|min_free| disk.available >= min_free),
+            serde_json::json!({
+                "available": disk.available,
+                "total": disk.total,
+                "min_free": shared.status_min_free,
+            }),
+        ),
+        Err(e) => {
+            eprintln!("{}", e);
+            (false, serde_json::json!({ "error": e }))
+        }
+    };
+
+    let body = serde_json::json!({
+        "healthy": healthy,
+        "downloads": {
+            "running": fetches.running,
+            "queued": fetches.queued,
+        },
+        "disk": disk,
+        "read_only": shared.blob_storage.read_only(),
+        "sync": {
+            "phase": sync.phase,
+            "started": sync.started,
+            "last_finished": sync.last.as_ref().map(|last| last.finished),
+            "last_repos_failed": sync.last.as_ref().map(|last| last.counts.repos_failed),
+        },
+    });
+    let status = match healthy {
+        true => Status::Ok,
+        false => Status::ServiceUnavailable,
+    };
+
+    (status, (ContentType::JSON, body.to_string()))
+}
+
+/// sync and storage metrics in the Prometheus text format
+#[get("/metrics")]
+pub(crate) async fn metrics(shared: &State<SharedData>) -> (ContentType, String) {
+    let status = shared.sync_progress.status();
+    let mut body = String::new();
+    let mut gauge = |name: &str, help: &str, value: String| {
+        body.push_str(&format!("# HELP {} {}\n", name, help));
+        body.push_str(&format!("# TYPE {} gauge\n", name));
+        body.push_str(&format!("{} {}\n", name, value));
+    };
+
+    gauge(
+        "portcache_sync_running",
+        "Whether a sync cycle is running",
+        u8::from(status.started.is_some()).to_string(),
+    );
+
+    if let Some(last) = status.last {
+        let counts = &last.counts;
+        gauge(
+            "portcache_sync_last_duration_seconds",
+            "Duration of the last sync cycle",
+            last.elapsed.to_string(),
+        );
+        gauge(
+            "portcache_sync_last_finished_timestamp_seconds",
+            "End of the last sync cycle",
+            last.finished.to_string(),
+        );
+        gauge(
+            "portcache_sync_last_repos_synced",
+            "Repos synced in the last cycle",
+            counts.repos_synced.to_string(),
+        );
+        gauge(
+            "portcache_sync_last_repos_failed",
+            "Repos failing to sync in the last cycle",
+            counts.repos_failed.to_string(),
+        );
+        gauge(
+            "portcache_sync_last_manifests_parsed",
+            "Manifests parsed in the last cycle",
+            counts.manifests_parsed.to_string(),
+        );
+        gauge(
+            "portcache_sync_last_entries_changed",
+            "Changed Manifest entries in the last cycle",
+            counts.entries_changed.to_string(),
+        );
+        gauge(
+            "portcache_sync_last_entries_unchanged",
+            "Unchanged Manifest entries in the last cycle",
+            counts.entries_unchanged.to_string(),
+        );
+        gauge(
+            "portcache_sync_last_ebuilds_parsed",
+            "Ebuilds parsed in the last cycle",
+            counts.ebuilds_parsed.to_string(),
+        );
+        gauge(
+            "portcache_sync_last_ebuilds_failed",
+            "Ebuilds failing to parse in the last cycle",
+            counts.ebuilds_failed.to_string(),
+        );
+    }
+
+    let external = shared.blob_storage.external_changes();
+    for (name, help, value) in [
+        (
+            "portcache_blobs_removed_externally_total",
+            "Blobs deleted outside of portcache",
+            &external.removed,
+        ),
+        (
+            "portcache_blobs_replaced_externally_total",
+            "Blobs replaced outside of portcache",
+            &external.replaced,
+        ),
+        (
+            "portcache_blobs_mismatched_externally_total",
+            "Blobs replaced outside of portcache not matching their Manifest",
+            &external.mismatched,
+        ),
+    ] {
+        body.push_str(&format!("# HELP {} {}\n", name, help));
+        body.push_str(&format!("# TYPE {} counter\n", name));
+        body.push_str(&format!("{} {}\n", name, value.load(Ordering::Relaxed)));
+    }
+
+    if let Some(cache) = &shared.memory_cache {
+        for (name, help, value) in [
+            (
+                "portcache_memory_cache_hits_total",
+                "Files served from the memory cache",
+                &cache.hits,
+            ),
+            (
+                "portcache_memory_cache_misses_total",
+                "Files read from disk into the memory cache",
+                &cache.misses,
+            ),
+        ] {
+            body.push_str(&format!("# HELP {} {}\n", name, help));
+            body.push_str(&format!("# TYPE {} counter\n", name));
+            body.push_str(&format!("{} {}\n", name, value.load(Ordering::Relaxed)));
+        }
+        body.push_str("# HELP portcache_memory_cache_bytes Bytes held in the memory cache\n");
+        body.push_str("# TYPE portcache_memory_cache_bytes gauge\n");
+        body.push_str(&format!("portcache_memory_cache_bytes {}\n", cache.size()));
+    }
+
+    (ContentType::Plain, body)
+}
+
+/// version and build info of the running binary
+/// so deployed instances can be audited
+#[get("/api/v1/version")]
+pub(crate) async fn version(shared: &State<SharedData>) -> Result<(ContentType, String), Status> {
+    let schema_version = shared.repo_db.schema_version().await.map_err(|e| {
+        eprintln!("Failed to query schema version: {}", e);
+        Status::InternalServerError
+    })?;
+
+    let body = serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_commit": GIT_COMMIT,
+        "features": FEATURES,
+        "schema_version": schema_version,
+    });
+
+    Ok((ContentType::JSON, body.to_string()))
+}
+
+/// listing of all cached blobs with size, checksums, timestamps and upstream
+/// for backup tooling and reconciliation scripts
+/// format is "json" (default) or "csv", timestamps are unix timestamps
+#[get("/api/v1/export?<format>")]
+pub(crate) async fn export(
+    format: Option<&str>,
+    shared: &State<SharedData>,
+) -> Result<(ContentType, String), Status> {
+    let csv = match format.unwrap_or("json") {
+        "json" => false,
+        "csv" => true,
+        _ => return Err(Status::BadRequest),
+    };
+
+    let mut blobs = shared.blob_storage.blobs().await.map_err(|e| {
+        eprintln!("Failed to list blobs: {}", e);
+        Status::InternalServerError
+    })?;
+    blobs.sort_by(|a, b| a.file.cmp(&b.file));
+
+    let files: Vec<String> = blobs.iter().map(|blob| blob.file.clone()).collect();
+    let entries = shared
+        .repo_db
+        .get_manifest_entries(&files)
+        .await
+        .map_err(|e| {
+            eprintln!("Failed to look up checksums: {}", e);
+            Status::InternalServerError
+        })?;
+    let sources = shared.repo_db.get_blob_sources(&files).await.map_err(|e| {
+        eprintln!("Failed to look up upstreams: {}", e);
+        Status::InternalServerError
+    })?;
+
+    let unix = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or(0)
+    };
+    let checksums = |file: &str| {
+        let entry = entries.get(file);
+        (
+            entry.and_then(|entry| entry.blake2b.clone()),
+            entry.and_then(|entry| entry.sha512.clone()),
+        )
+    };
+
+    if csv {
+        let mut body = String::from("file,size,blake2b,sha512,modified,accessed\n");
+        for blob in &blobs {
+            let (blake2b, sha512) = checksums(&blob.file);
+            body.push_str(&format!(
+                "{},{},{},{},{},{}\n",
+                csv_field(&blob.file),
+                blob.size,
+                blake2b.unwrap_or_default(),
+                sha512.unwrap_or_default(),
+                unix(blob.modified),
+                unix(blob.accessed),
+            ));
+        }
+        return Ok((ContentType::CSV, body));
+    }
+
+    let blobs: Vec<serde_json::Value> = blobs
+        .iter()
+        .map(|blob| {
+            let (blake2b, sha512) = checksums(&blob.file);
+            serde_json::json!({
+                "file": blob.file,
+                "size": blob.size,
+                "blake2b": blake2b,
+                "sha512": sha512,
+                "modified": unix(blob.modified),
+                "accessed": unix(blob.accessed),
+                "upstream": sources.get(&blob.file),
+            })
+        })
+        .collect();
+    let body = serde_json::json!({ "blobs": blobs });
+
+    Ok((ContentType::JSON, body.to_string()))
+}
+
+/// most distfiles a single /api/v1/cached request may ask about
+const CACHED_MAX_FILES: usize = 10_000;
+
+/// which distfiles of a JSON array of names are cached
+/// with their sizes (the Manifest size if not cached), checksums and where cached ones came from
+/// so wrapper scripts can decide between the cache and going direct for a large batch
+#[post("/api/v1/cached", data = "<body>")]
+pub(crate) async fn cached(
+    body: Data<'_>,
+    shared: &State<SharedData>,
+) -> Result<(ContentType, String), (Status, String)> {
+    let body = body
+        .open(1.mebibytes())
+        .into_string()
+        .await
+        .map_err(|e| (Status::BadRequest, e.to_string()))?;
+    if !body.is_complete() {
+        return Err((
+            Status::PayloadTooLarge,
+            "Request body too large".to_string(),
+        ));
+    }
+    let files: Vec<String> =
+        serde_json::from_str(&body).map_err(|e| (Status::BadRequest, e.to_string()))?;
+    if files.len() > CACHED_MAX_FILES {
+        return Err((
+            Status::PayloadTooLarge,
+            format!("At most {} files per request", CACHED_MAX_FILES),
+        ));
+    }
+
+    let entries = shared
+        .repo_db
+        .get_manifest_entries(&files)
+        .await
+        .map_err(|e| {
+            eprintln!("Failed to look up checksums: {}", e);
+            (Status::InternalServerError, String::new())
+        })?;
+    let sources = shared.repo_db.get_blob_sources(&files).await.map_err(|e| {
+        eprintln!("Failed to look up upstreams: {}", e);
+        (Status::InternalServerError, String::new())
+    })?;
+
+    let mut results = Vec::new();
+    let (mut cached_files, mut cached_size) = (0u64, 0u64);
+    for name in &files {
+        if let Err(e) = DistfileName::parse(name) {
+            results
+                .push(serde_json::json!({ "file": name, "cached": false, "error": e.to_string() }));
+            continue;
+        }
+
+        let blob = match shared.blob_storage.blob_location(name).await {
+            Ok(path) => match tokio::fs::metadata(&path).await {
+                Ok(metadata) if metadata.is_file() => Some((path, metadata)),
+                _ => None,
+            },
+            Err(_) => None,
+        };
+        let entry = entries.get(name);
+        let size = match &blob {
+            Some((path, metadata)) => {
+                let size = compression::content_size(path, metadata.len()).await;
+                cached_files += 1;
+                cached_size += size;
+                Some(size)
+            }
+            None => entry.map(|entry| entry.size),
+        };
+
+        results.push(serde_json::json!({
+            "file": name,
+            "cached": blob.is_some(),
+            "stale": blob.is_some() && shared.blob_storage.is_stale(name).await,
+            "size": size,
+            "blake2b": entry.and_then(|entry| entry.blake2b.clone()),
+            "sha512": entry.and_then(|entry| entry.sha512.clone()),
+            "upstream": blob.as_ref().and(sources.get(name)),
+        }));
+    }
+
+    let body = serde_json::json!({
+        "cached": cached_files,
+        "cached_size": cached_size,
+        "files": results,
+    });
+    Ok((ContentType::JSON, body.to_string()))
+}
+
+/// quote a CSV field if it contains a separator, quote or line break
+pub(crate) fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
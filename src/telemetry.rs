@@ -0,0 +1,574 @@
+use reqwest::header::{CONTENT_TYPE, HeaderMap, HeaderName, HeaderValue};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::{Data, Response};
+use serde_json::{Value, json};
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time;
+
+use crate::config::Config;
+use crate::request_id::RequestId;
+
+tokio::task_local! {
+    /// span the running request works in
+    /// spans started within become its children
+    static CURRENT: SpanContext;
+}
+
+/// what a span represents, numbered like the OTLP SpanKind
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpanKind {
+    /// work within portcache
+    Internal = 1,
+
+    /// a request portcache serves
+    Server = 2,
+
+    /// a request portcache sends upstream
+    Client = 3,
+}
+
+/// value of a span attribute
+#[derive(Clone, Debug)]
+pub enum AttributeValue {
+    /// string attribute
+    Str(String),
+
+    /// integer attribute
+    Int(i64),
+
+    /// boolean attribute
+    Bool(bool),
+}
+
+impl From<&str> for AttributeValue {
+    fn from(value: &str) -> Self {
+        AttributeValue::Str(value.to_string())
+    }
+}
+
+impl From<String> for AttributeValue {
+    fn from(value: String) -> Self {
+        AttributeValue::Str(value)
+    }
+}
+
+impl From<i64> for AttributeValue {
+    fn from(value: i64) -> Self {
+        AttributeValue::Int(value)
+    }
+}
+
+impl From<u64> for AttributeValue {
+    fn from(value: u64) -> Self {
+        AttributeValue::Int(i64::try_from(value).unwrap_or(i64::MAX))
+    }
+}
+
+impl From<bool> for AttributeValue {
+    fn from(value: bool) -> Self {
+        AttributeValue::Bool(value)
+    }
+}
+
+/// a finished or running span
+struct SpanData {
+    /// trace the span belongs to
+    trace_id: u128,
+
+    /// id of the span
+    span_id: u64,
+
+    /// span this one is a child of, None for the root of a trace
+    parent_id: Option<u64>,
+
+    /// name of the span e.g. "GET /distfiles/<digest>/<file>"
+    name: String,
+
+    /// what the span represents
+    kind: SpanKind,
+
+    /// start of the span
+    start: SystemTime,
+
+    /// end of the span, set once finished
+    end: SystemTime,
+
+    /// attributes like http.response.status_code
+    attributes: Vec<(&'static str, AttributeValue)>,
+
+    /// why the span failed if it did
+    error: Option<String>,
+}
+
+/// identifies a running span so children can be attached to it
+#[derive(Clone)]
+pub struct SpanContext {
+    /// tracer the children report to
+    tracer: Arc<Tracer>,
+
+    /// trace the span belongs to
+    trace_id: u128,
+
+    /// id of the span
+    span_id: u64,
+}
+
+impl SpanContext {
+    /// start a child span
+    fn child(&self, name: String, kind: SpanKind) -> Span {
+        self.tracer
+            .start(self.trace_id, Some(self.span_id), name, kind)
+    }
+}
+
+/// a span which gets recorded when dropped
+/// spans outside of a sampled request don't record anything
+pub struct Span(Option<Box<(Arc<Tracer>, SpanData)>>);
+
+impl Span {
+    /// whether the span gets recorded
+    pub fn is_recording(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// set an attribute
+    ///
+    /// @param key    attribute name e.g. "http.response.status_code"
+    /// @param value  attribute value
+    pub fn set(&mut self, key: &'static str, value: impl Into<AttributeValue>) {
+        if let Some(recording) = &mut self.0 {
+            let attributes = &mut recording.1.attributes;
+            let value = value.into();
+            match attributes.iter_mut().find(|(name, _)| *name == key) {
+                Some((_, existing)) => *existing = value,
+                None => attributes.push((key, value)),
+            }
+        }
+    }
+
+    /// mark the span failed
+    ///
+    /// @param error  what went wrong
+    pub fn fail(&mut self, error: impl Display) {
+        if let Some(recording) = &mut self.0 {
+            recording.1.error = Some(error.to_string());
+        }
+    }
+
+    /// change the name of the span
+    pub fn rename(&mut self, name: impl Into<String>) {
+        if let Some(recording) = &mut self.0 {
+            recording.1.name = name.into();
+        }
+    }
+
+    /// context to attach children to
+    pub fn context(&self) -> Option<SpanContext> {
+        self.0.as_ref().map(|recording| SpanContext {
+            tracer: recording.0.clone(),
+            trace_id: recording.1.trace_id,
+            span_id: recording.1.span_id,
+        })
+    }
+
+    /// W3C traceparent header value continuing the trace from this span
+    pub fn traceparent(&self) -> Option<String> {
+        self.0.as_ref().map(|recording| {
+            format!(
+                "00-{:032x}-{:016x}-01",
+                recording.1.trace_id, recording.1.span_id
+            )
+        })
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(recording) = self.0.take() {
+            let (tracer, mut data) = *recording;
+            data.end = SystemTime::now();
+            tracer.finish(data);
+        }
+    }
+}
+
+/// start a span as child of the span the running request works in
+/// outside of a traced request this doesn't record anything
+///
+/// @param name  name of the span e.g. "blob_storage.request"
+/// @param kind  what the span represents
+pub fn span(name: impl Into<String>, kind: SpanKind) -> Span {
+    CURRENT
+        .try_with(|parent| parent.child(name.into(), kind))
+        .unwrap_or(Span(None))
+}
+
+/// run future with context as the current span
+/// so spans started within become its children
+///
+/// @param context  span to attach children to, None to run untraced
+/// @param future   work to run
+pub async fn within<F: Future>(context: Option<SpanContext>, future: F) -> F::Output {
+    match context {
+        Some(context) => CURRENT.scope(context, future).await,
+        None => future.await,
+    }
+}
+
+/// send an upstream request within a client span
+/// the span lasts until the response headers arrive
+/// and its traceparent is sent along so upstream can join the trace
+///
+/// @param request  request to send
+pub async fn send(request: reqwest::RequestBuilder) -> reqwest::Result<reqwest::Response> {
+    let (client, request) = request.build_split();
+    let mut request = request?;
+
+    let mut span = span(request.method().as_str(), SpanKind::Client);
+    if span.is_recording() {
+        // mirror urls may carry credentials
+        let mut url = request.url().clone();
+        let _ = url.set_username("");
+        let _ = url.set_password(None);
+        span.set("http.request.method", request.method().as_str());
+        span.set("url.full", url.as_str());
+        span.set("server.address", url.host_str().unwrap_or_default());
+    }
+    if let Some(traceparent) = span.traceparent()
+        && let Ok(value) = HeaderValue::from_str(&traceparent)
+    {
+        request.headers_mut().insert("traceparent", value);
+    }
+
+    let result = client.execute(request).await;
+    match &result {
+        Ok(response) => {
+            let status = response.status();
+            span.set("http.response.status_code", u64::from(status.as_u16()));
+            if status.is_client_error() || status.is_server_error() {
+                span.fail(status);
+            }
+        }
+        Err(e) => span.fail(e),
+    }
+    result
+}
+
+/// parse a W3C traceparent header
+/// returns the trace id, parent span id and whether the caller samples
+fn parse_traceparent(value: &str) -> Option<(u128, u64, bool)> {
+    let parts: Vec<&str> = value.trim().split('-').collect();
+    let [version, trace_id, span_id, flags] = parts[..] else {
+        return None;
+    };
+    if version.len() != 2 || version == "ff" || trace_id.len() != 32 || span_id.len() != 16 {
+        return None;
+    }
+
+    let trace_id = u128::from_str_radix(trace_id, 16).ok()?;
+    let span_id = u64::from_str_radix(span_id, 16).ok()?;
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    if trace_id == 0 || span_id == 0 {
+        return None;
+    }
+    Some((trace_id, span_id, flags & 1 == 1))
+}
+
+/// records spans and exports them to an OTLP/HTTP collector
+pub struct Tracer {
+    /// url spans get posted to i.e. <otlp_endpoint>/v1/traces
+    endpoint: String,
+
+    /// service.name the spans are reported under
+    service_name: String,
+
+    /// share of requests which get traced
+    sample_ratio: f64,
+
+    /// how often finished spans get exported
+    export_interval: Duration,
+
+    /// finished spans kept until the next export
+    max_queue: usize,
+
+    /// extra headers sent to the collector
+    headers: HeaderMap,
+
+    /// client for the collector
+    client: reqwest::Client,
+
+    /// finished spans waiting for export
+    finished: Mutex<Vec<SpanData>>,
+
+    /// spans dropped since the last export as the queue was full
+    dropped: AtomicU64,
+}
+
+impl Tracer {
+    /// create a Tracer from config
+    /// returns None while telemetry.otlp_endpoint is unset
+    pub fn new(config: &Config) -> Result<Option<Arc<Self>>, String> {
+        let telemetry = &config.telemetry;
+        let endpoint = match &telemetry.otlp_endpoint {
+            Some(endpoint) => format!("{}/v1/traces", endpoint.trim_end_matches('/')),
+            None => return Ok(None),
+        };
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in &telemetry.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| format!("Bad telemetry header {}: {}", name, e))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|e| format!("Bad value of telemetry header {}: {}", name, e))?;
+            headers.insert(name, value);
+        }
+
+        Ok(Some(Arc::new(Self {
+            endpoint,
+            service_name: telemetry.service_name.clone(),
+            sample_ratio: telemetry.sample_ratio,
+            export_interval: telemetry.export_interval,
+            max_queue: telemetry.max_queue,
+            headers,
+            client: reqwest::Client::new(),
+            finished: Mutex::new(Vec::new()),
+            dropped: AtomicU64::new(0),
+        })))
+    }
+
+    /// start the root span of a served request
+    /// continues the trace of the caller if it sent a traceparent
+    /// and follows its sampling decision, otherwise samples by sample_ratio
+    ///
+    /// @param traceparent  traceparent header of the request
+    /// @param name         name of the span
+    pub fn root(self: &Arc<Self>, traceparent: Option<&str>, name: impl Into<String>) -> Span {
+        let (trace_id, parent_id) = match traceparent.and_then(parse_traceparent) {
+            Some((_, _, false)) => return Span(None),
+            Some((trace_id, parent_id, true)) => (trace_id, Some(parent_id)),
+            None if fastrand::f64() < self.sample_ratio => (fastrand::u128(1..), None),
+            None => return Span(None),
+        };
+        self.start(trace_id, parent_id, name.into(), SpanKind::Server)
+    }
+
+    /// start a recording span
+    fn start(
+        self: &Arc<Self>,
+        trace_id: u128,
+        parent_id: Option<u64>,
+        name: String,
+        kind: SpanKind,
+    ) -> Span {
+        Span(Some(Box::new((
+            self.clone(),
+            SpanData {
+                trace_id,
+                span_id: fastrand::u64(1..),
+                parent_id,
+                name,
+                kind,
+                start: SystemTime::now(),
+                end: SystemTime::now(),
+                attributes: Vec::new(),
+                error: None,
+            },
+        ))))
+    }
+
+    /// queue a finished span for export
+    fn finish(&self, span: SpanData) {
+        let mut finished = self.finished.lock().unwrap_or_else(|e| e.into_inner());
+        if finished.len() >= self.max_queue {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        finished.push(span);
+    }
+
+    /// export spans every export_interval
+    /// this is expected to be called from a tokio::spawn
+    pub async fn start_export(self: Arc<Self>) {
+        println!("Exporting traces to {}", self.endpoint);
+        let mut interval = time::interval(self.export_interval);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.export().await {
+                eprintln!("Failed to export traces: {}", e);
+            }
+        }
+    }
+
+    /// post all finished spans to the collector
+    /// spans of a failed export are dropped rather than piling up
+    /// returns the number of exported spans
+    pub async fn export(&self) -> Result<usize, String> {
+        let spans = std::mem::take(&mut *self.finished.lock().unwrap_or_else(|e| e.into_inner()));
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            eprintln!(
+                "Dropped {} spans as telemetry.max_queue was reached",
+                dropped
+            );
+        }
+        if spans.is_empty() {
+            return Ok(0);
+        }
+
+        let body = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [
+                        attribute("service.name", &AttributeValue::from(self.service_name.as_str())),
+                        attribute("service.version", &AttributeValue::from(env!("CARGO_PKG_VERSION"))),
+                    ],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "portcache", "version": env!("CARGO_PKG_VERSION") },
+                    "spans": spans.iter().map(span_json).collect::<Vec<Value>>(),
+                }],
+            }],
+        });
+
+        self.client
+            .post(&self.endpoint)
+            .headers(self.headers.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| format!("POST {} failed: {}", self.endpoint, e))?;
+        Ok(spans.len())
+    }
+}
+
+/// OTLP/JSON encoding of an attribute
+fn attribute(key: &str, value: &AttributeValue) -> Value {
+    let value = match value {
+        AttributeValue::Str(value) => json!({ "stringValue": value }),
+        // 64 bit integers are strings in OTLP/JSON
+        AttributeValue::Int(value) => json!({ "intValue": value.to_string() }),
+        AttributeValue::Bool(value) => json!({ "boolValue": value }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+/// OTLP/JSON encoding of a span
+fn span_json(span: &SpanData) -> Value {
+    let nanos = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .map(|since| since.as_nanos())
+            .unwrap_or(0)
+            .to_string()
+    };
+    let status = match &span.error {
+        Some(message) => json!({ "code": 2, "message": message }),
+        None => json!({ "code": 0 }),
+    };
+
+    json!({
+        "traceId": format!("{:032x}", span.trace_id),
+        "spanId": format!("{:016x}", span.span_id),
+        "parentSpanId": span.parent_id.map(|id| format!("{:016x}", id)).unwrap_or_default(),
+        "name": span.name,
+        "kind": span.kind as u8,
+        "startTimeUnixNano": nanos(span.start),
+        "endTimeUnixNano": nanos(span.end),
+        "attributes": span
+            .attributes
+            .iter()
+            .map(|(key, value)| attribute(key, value))
+            .collect::<Vec<Value>>(),
+        "status": status,
+    })
+}
+
+/// root span of a request kept in the request's local cache
+#[derive(Default)]
+struct RequestSpan {
+    /// the span, taken when the response is sent
+    span: Mutex<Option<Span>>,
+
+    /// context of the span for the route handler
+    context: Option<SpanContext>,
+}
+
+/// fairing starting a server span per request
+/// it ends once the response is ready, i.e. without the body transfer
+pub struct TraceRequests(pub Arc<Tracer>);
+
+#[rocket::async_trait]
+impl Fairing for TraceRequests {
+    fn info(&self) -> Info {
+        Info {
+            name: "OpenTelemetry tracing",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        let method = req.method().as_str();
+        let mut span = self.0.root(req.headers().get_one("traceparent"), method);
+        span.set("http.request.method", method);
+        span.set("url.path", req.uri().path().as_str());
+        if let Some(ip) = req.client_ip() {
+            span.set("client.address", ip.to_string());
+        }
+        span.set("portcache.request_id", RequestId::of(req).0);
+
+        let context = span.context();
+        req.local_cache(|| RequestSpan {
+            span: Mutex::new(Some(span)),
+            context,
+        });
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let request_span = req.local_cache(RequestSpan::default);
+        let span = request_span
+            .span
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+        if let Some(mut span) = span {
+            if let Some(route) = req.route() {
+                // query parameters like refresh=1 aren't part of the route
+                span.rename(format!("{} {}", req.method(), route.uri.path()));
+                span.set("http.route", route.uri.path().to_string());
+            }
+            span.set("http.response.status_code", u64::from(res.status().code));
+            if res.status().code >= 500 {
+                span.fail(res.status());
+            }
+        }
+    }
+}
+
+/// request guard carrying the request's span and id into the route handler
+/// so the work it does shows up as children of the request
+/// and its log lines carry the request id
+pub struct RequestTrace(Option<SpanContext>, RequestId);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestTrace {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(RequestTrace(
+            req.local_cache(RequestSpan::default).context.clone(),
+            RequestId::of(req),
+        ))
+    }
+}
+
+impl RequestTrace {
+    /// run future within the span and with the id of the request
+    pub async fn within<F: Future>(self, future: F) -> F::Output {
+        self.1.scope(within(self.0, future)).await
+    }
+}
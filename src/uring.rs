@@ -0,0 +1,169 @@
+use bytes::{Buf, Bytes};
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncSeek, ReadBuf};
+use tokio::sync::mpsc;
+
+/// bytes read per submission
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// chunks read ahead of what was sent to the client
+const READ_AHEAD: usize = 4;
+
+/// submission queue entries of the ring
+const RING_ENTRIES: u32 = 256;
+
+/// a file to stream through the ring
+struct ReadJob {
+    /// the opened file
+    file: std::fs::File,
+
+    /// where to start reading
+    offset: u64,
+
+    /// receives the chunks read
+    chunks: mpsc::Sender<io::Result<Bytes>>,
+}
+
+/// reads of served files submitted to io_uring
+/// a dedicated thread runs a tokio-uring runtime reading every file as a series of chunks,
+/// saving the blocking thread pool hop of every read on busy caches
+pub struct UringReader {
+    /// hands files to the ring thread
+    jobs: mpsc::UnboundedSender<ReadJob>,
+}
+
+impl UringReader {
+    /// start the ring thread
+    /// fails if the kernel doesn't support io_uring or forbids it (e.g. via seccomp)
+    pub fn start() -> Result<Self, String> {
+        let (jobs, mut received) = mpsc::unbounded_channel::<ReadJob>();
+        let (started, result) = std::sync::mpsc::channel();
+
+        std::thread::Builder::new()
+            .name("portcache-uring".to_string())
+            .spawn(move || {
+                let runtime =
+                    match tokio_uring::Runtime::new(tokio_uring::builder().entries(RING_ENTRIES)) {
+                        Ok(runtime) => runtime,
+                        Err(e) => {
+                            let _ = started.send(Err(e));
+                            return;
+                        }
+                    };
+                let _ = started.send(Ok(()));
+                runtime.block_on(async move {
+                    while let Some(job) = received.recv().await {
+                        tokio_uring::spawn(read(job));
+                    }
+                });
+            })
+            .map_err(|e| format!("Cannot start io_uring thread: {}", e))?;
+
+        result
+            .recv()
+            .map_err(|e| e.to_string())?
+            .map_err(|e| format!("Cannot set up io_uring: {}", e))?;
+        Ok(Self { jobs })
+    }
+
+    /// stream a file from offset to its end
+    ///
+    /// @param file    the opened file
+    /// @param offset  where to start reading
+    pub fn read(&self, file: std::fs::File, offset: u64) -> UringBody {
+        let (chunks, received) = mpsc::channel(READ_AHEAD);
+        let job = ReadJob {
+            file,
+            offset,
+            chunks,
+        };
+        if let Err(mpsc::error::SendError(job)) = self.jobs.send(job) {
+            // the ring thread is gone, the body ends with this error
+            let _ = job
+                .chunks
+                .try_send(Err(io::Error::other("io_uring thread stopped")));
+        }
+        UringBody {
+            chunks: received,
+            current: Bytes::new(),
+        }
+    }
+}
+
+/// read a file chunk by chunk until its end or the body got dropped
+async fn read(job: ReadJob) {
+    let file = tokio_uring::fs::File::from_std(job.file);
+    let mut offset = job.offset;
+    loop {
+        let (read, buf) = file.read_at(Vec::with_capacity(CHUNK_SIZE), offset).await;
+        let chunk = match read {
+            Ok(0) => break,
+            Ok(read) => {
+                offset += read as u64;
+                Ok(Bytes::from(buf))
+            }
+            Err(e) => Err(e),
+        };
+        let failed = chunk.is_err();
+        if job.chunks.send(chunk).await.is_err() || failed {
+            break;
+        }
+    }
+    let _ = file.close().await;
+}
+
+/// content of a file read through the ring
+/// sized bodies are never seeked so seeking is unsupported
+pub struct UringBody {
+    /// chunks from the ring thread
+    chunks: mpsc::Receiver<io::Result<Bytes>>,
+
+    /// rest of the chunk being consumed
+    current: Bytes,
+}
+
+impl AsyncBufRead for UringBody {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        while this.current.is_empty() {
+            match ready!(this.chunks.poll_recv(cx)) {
+                Some(chunk) => this.current = chunk?,
+                None => break,
+            }
+        }
+        Poll::Ready(Ok(&this.current))
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        self.current.advance(amt);
+    }
+}
+
+impl AsyncRead for UringBody {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let available = ready!(self.as_mut().poll_fill_buf(cx))?;
+        let amt = available.len().min(buf.remaining());
+        buf.put_slice(&available[..amt]);
+        self.consume(amt);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for UringBody {
+    fn start_seek(self: Pin<&mut Self>, _: SeekFrom) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "files read through io_uring can't be seeked",
+        ))
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(0))
+    }
+}
@@ -1,12 +1,198 @@
 use blake2::{Blake2b512, Digest};
+use sha2::{Sha256, Sha512};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::sync::{Mutex, OwnedMutexGuard, Semaphore};
 
 /// convert a distfile name to the directory it's
-/// supposed to be in i.e. the first 2 bytes of the 8 byte BLAKE2B
+/// supposed to be in on Gentoo mirrors i.e. the first 8 bits of the BLAKE2B
 /// https://github.com/gentoo/portage/blob/portage-3.0.67/lib/portage/checksum.py#L27
 /// @param name  File name to hash
-pub fn filename_hash_dir_blake2b(name: &String) -> Result<String, Box<dyn std::error::Error>> {
+pub fn filename_hash_dir_blake2b(name: &str) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(filename_hash_dir(name, 8))
+}
+
+/// convert a distfile name to its directory in a filename-hash layout
+/// i.e. the first bits of the hex encoded BLAKE2B
+/// @param name  File name to hash
+/// @param bits  length of the directory name in bits, a multiple of 4
+pub fn filename_hash_dir(name: &str, bits: u8) -> String {
     let mut hasher = Blake2b512::new();
     hasher.update(name.as_bytes());
-    let res = hasher.finalize();
-    Ok(hex::encode(&res[..1]))
+    let mut digest = hex::encode(hasher.finalize());
+    digest.truncate(bits as usize / 4);
+    digest
+}
+
+/// layout.conf announcing a filename-hash layout to portage
+/// portage tries the layouts in order so flat only serves as fallback
+/// @param bits  length of the hash directory names in bits
+/// @param flat  whether files are also found at the top level
+pub fn layout_conf(bits: u8, flat: bool) -> String {
+    let mut conf = format!("[structure]\n0=filename-hash BLAKE2B {}\n", bits);
+    if flat {
+        conf.push_str("1=flat\n");
+    }
+    conf
+}
+
+/// current time as unix timestamp in seconds
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// hash algorithms downloads can be verified against
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashType {
+    /// BLAKE2B-512 as used in Manifests
+    Blake2b,
+
+    /// SHA-256
+    Sha256,
+
+    /// SHA-512 as used in Manifests
+    Sha512,
+}
+
+/// threads allowed to hash files at once, see set_hash_workers()
+static HASH_WORKERS: OnceLock<Arc<Semaphore>> = OnceLock::new();
+
+/// limit the number of files hashed at once to storage.hash_workers
+/// only the first call has an effect, hashing before it uses 2 threads
+///
+/// @param workers  number of threads
+pub fn set_hash_workers(workers: usize) {
+    let _ = HASH_WORKERS.set(Arc::new(Semaphore::new(workers)));
+}
+
+/// calculate the hex encoded checksum of a file
+/// hashing multi-GB files runs on the blocking thread pool
+/// so it doesn't stall the async runtime
+/// @param path  File to hash
+/// @param hash  Hash algorithm to use
+pub async fn file_checksum(path: &Path, hash: HashType) -> std::io::Result<String> {
+    let _permit = HASH_WORKERS
+        .get_or_init(|| Arc::new(Semaphore::new(2)))
+        .clone()
+        .acquire_owned()
+        .await
+        .map_err(std::io::Error::other)?;
+
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || match hash {
+        HashType::Blake2b => digest_file::<Blake2b512>(&path),
+        HashType::Sha256 => digest_file::<Sha256>(&path),
+        HashType::Sha512 => digest_file::<Sha512>(&path),
+    })
+    .await
+    .map_err(std::io::Error::other)?
+}
+
+/// stream a file through a hasher
+/// blobs stored compressed get hashed by their content
+fn digest_file<D: Digest>(path: &Path) -> std::io::Result<String> {
+    let mut file = crate::compression::open(path)?;
+    let mut hasher = D::new();
+    let mut buf = vec![0u8; 64 * 1024];
+
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// match text against a glob with * (any run of characters) and ? (any single character)
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    // position after the last * and where in text it started matching
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p + 1, t));
+            p += 1;
+        } else if let Some((after, matched)) = star {
+            // let the * swallow one more character
+            p = after;
+            t = matched + 1;
+            star = Some((after, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// create the directory a file gets written to including missing ancestors
+/// concurrent fetches of files sharing a hash directory may race to create it
+/// so a directory showing up in between counts as success
+///
+/// @param path  the file whose parent is needed
+pub async fn create_parent_dir(path: &Path) -> std::io::Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => return Ok(()),
+    };
+
+    match fs::create_dir_all(parent).await {
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && parent.is_dir() => Ok(()),
+        result => result,
+    }
+}
+
+/// Last-Modified time announced by an upstream response
+///
+/// @param headers  headers of the response
+pub fn last_modified(headers: &reqwest::header::HeaderMap) -> Option<SystemTime> {
+    let value = headers.get(reqwest::header::LAST_MODIFIED)?.to_str().ok()?;
+    httpdate::parse_http_date(value).ok()
+}
+
+/// set the mtime of a stored file
+///
+/// @param path   the file
+/// @param mtime  time to set
+pub fn set_mtime(path: &Path, mtime: SystemTime) -> std::io::Result<()> {
+    std::fs::File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(mtime)
+}
+
+/// per path locks serializing checks and fetches of the same file
+#[derive(Default)]
+pub struct PathLocks {
+    /// lock of every path currently in use
+    locks: Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>,
+}
+
+impl PathLocks {
+    /// wait for exclusive access to path
+    /// access ends when the guard is dropped
+    pub async fn lock(&self, path: &Path) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.locks.lock().await;
+            // forget locks nobody holds or waits for anymore
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            locks.entry(path.to_path_buf()).or_default().clone()
+        };
+        lock.lock_owned().await
+    }
 }
@@ -0,0 +1,242 @@
+use rocket::http;
+use rocket::{State, get};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+use tokio::process::Command;
+
+use crate::api_keys::ClientKey;
+use crate::app::SharedData;
+use crate::config::Config;
+use crate::fetcher;
+use crate::frontend::{self, IfModifiedSince, Refused, Served};
+use crate::telemetry::RequestTrace;
+use crate::utils;
+
+/// suffix of snapshot tarballs
+const SUFFIX: &str = ".tar.gz";
+
+/// snapshot tarballs of the repo checkouts served under /snapshots
+/// named like the ones on Gentoo mirrors so emerge-webrsync can sync the tree from us
+#[derive(Clone)]
+pub struct Webrsync {
+    /// directory the snapshots are written to
+    dir: PathBuf,
+
+    /// minimum time between snapshots of the same repo
+    interval: Duration,
+
+    /// number of dated snapshots to keep per repo
+    keep: usize,
+}
+
+impl Webrsync {
+    /// create Webrsync from config
+    /// returns None without a [webrsync] section
+    pub fn new(config: &Config) -> Option<Self> {
+        let webrsync = config.webrsync.as_ref()?;
+        Some(Self {
+            dir: config.storage.location.join("snapshots"),
+            interval: webrsync.interval,
+            keep: webrsync.keep,
+        })
+    }
+
+    /// location of a file in the snapshot directory
+    /// None for anything but plain names of finished files
+    ///
+    /// @param file  requested file name
+    pub fn locate(&self, file: &str) -> Option<PathBuf> {
+        (!file.is_empty()
+            && !file.starts_with('.')
+            && !file.contains('/')
+            && !file.ends_with(".part"))
+        .then(|| self.dir.join(file))
+    }
+
+    /// write a snapshot of a checkout as <name>-YYYYMMDD.tar.gz and <name>-latest.tar.gz
+    /// each with an .md5sum for emerge-webrsync to verify it against
+    /// skipped while the latest snapshot is younger than interval
+    /// returns whether a snapshot was written
+    ///
+    /// @param name      name of the repo, also the top directory in the tarball
+    /// @param checkout  location of the checkout
+    pub async fn snapshot(&self, name: &str, checkout: &Path) -> Result<bool, String> {
+        let latest = format!("{}-latest{}", name, SUFFIX);
+        let age = fs::metadata(self.dir.join(&latest))
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+        if age.is_some_and(|age| age < self.interval) {
+            return Ok(false);
+        }
+
+        fs::create_dir_all(&self.dir)
+            .await
+            .map_err(|e| format!("Cannot create {}: {}", self.dir.to_string_lossy(), e))?;
+
+        let dated = format!("{}-{}{}", name, date(utils::unix_time()), SUFFIX);
+        let path = self.dir.join(&dated);
+        let part = fetcher::part_location(&path);
+        let output = Command::new("tar")
+            .args(["--create", "--gzip", "--exclude-vcs", "--file"])
+            .arg(&part)
+            .arg("--directory")
+            .arg(checkout)
+            .arg(format!("--transform=s,^\\.,{},", name))
+            .arg(".")
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .map_err(|e| format!("Failed to run tar: {}", e))?;
+        if !output.status.success() {
+            let _ = fs::remove_file(&part).await;
+            return Err(format!(
+                "tar of {} failed: {}",
+                checkout.to_string_lossy(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        fs::rename(&part, &path)
+            .await
+            .map_err(|e| format!("Cannot write {}: {}", dated, e))?;
+
+        // the latest snapshot is the same file under a stable name
+        let latest_part = fetcher::part_location(&self.dir.join(&latest));
+        let _ = fs::remove_file(&latest_part).await;
+        let linked = match fs::hard_link(&path, &latest_part).await {
+            Ok(_) => fs::rename(&latest_part, self.dir.join(&latest)).await,
+            Err(e) => Err(e),
+        };
+        linked.map_err(|e| format!("Cannot write {}: {}", latest, e))?;
+
+        let md5 = self.md5sum(&dated).await?;
+        for (file, digest) in [
+            (&dated, md5.clone()),
+            (&latest, md5.replace(&dated, &latest)),
+        ] {
+            fs::write(self.dir.join(format!("{}.md5sum", file)), digest)
+                .await
+                .map_err(|e| format!("Cannot write {}.md5sum: {}", file, e))?;
+        }
+
+        self.prune(name).await;
+        Ok(true)
+    }
+
+    /// md5sum line of a file in the snapshot directory
+    /// the format emerge-webrsync checks with md5sum -c
+    ///
+    /// @param file  name of the file
+    async fn md5sum(&self, file: &str) -> Result<String, String> {
+        let output = Command::new("md5sum")
+            .arg(file)
+            .current_dir(&self.dir)
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .map_err(|e| format!("Failed to run md5sum: {}", e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "md5sum of {} failed: {}",
+                file,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    /// remove dated snapshots of a repo beyond the newest keep
+    ///
+    /// @param name  name of the repo
+    async fn prune(&self, name: &str) {
+        let mut entries = match fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                eprintln!("Failed to list {}: {}", self.dir.to_string_lossy(), e);
+                return;
+            }
+        };
+
+        let prefix = format!("{}-", name);
+        let mut dated = Vec::new();
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let file = entry.file_name().to_string_lossy().to_string();
+            if let Some(date) = file
+                .strip_prefix(&prefix)
+                .and_then(|rest| rest.strip_suffix(SUFFIX))
+                && date.len() == 8
+                && date.chars().all(|c| c.is_ascii_digit())
+            {
+                dated.push(file);
+            }
+        }
+
+        // dates sort like the strings
+        dated.sort_unstable_by(|a, b| b.cmp(a));
+        for file in dated.iter().skip(self.keep) {
+            for path in [
+                self.dir.join(file),
+                self.dir.join(format!("{}.md5sum", file)),
+            ] {
+                if let Err(e) = fs::remove_file(&path).await {
+                    eprintln!("Failed to remove {}: {}", path.to_string_lossy(), e);
+                }
+            }
+        }
+    }
+}
+
+/// UTC date of a unix timestamp as YYYYMMDD like the snapshots on Gentoo mirrors
+/// see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+///
+/// @param unix  seconds since the epoch
+fn date(unix: u64) -> String {
+    let days = (unix / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}{:02}{:02}", year, month, day)
+}
+
+/// snapshot tarballs of the repos for emerge-webrsync
+/// the served size gets accounted to the client's subnet and API key
+#[get("/snapshots/<file>")]
+pub(crate) async fn snapshots(
+    file: &str,
+    client: Option<IpAddr>,
+    key: ClientKey,
+    since: IfModifiedSince,
+    trace: RequestTrace,
+    shared: &State<SharedData>,
+) -> Result<Served, Refused> {
+    let path = shared
+        .webrsync
+        .as_ref()
+        .and_then(|webrsync| webrsync.locate(file))
+        .ok_or(http::Status::NotFound)?;
+
+    trace
+        .within(frontend::open_accounted(
+            file,
+            client,
+            key,
+            since,
+            shared,
+            async {
+                match path.is_file() {
+                    true => Ok(path),
+                    false => Err(http::Status::NotFound),
+                }
+            },
+        ))
+        .await
+}
@@ -0,0 +1,592 @@
+mod common;
+
+use common::{HELLO_CONTENT, Process, TestDaemon, distfile_path, mock_mirror};
+use rocket::http::{Header, Status};
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+const ADMIN: &str = "[admin]\ntoken = \"secret\"";
+
+fn auth() -> Header<'static> {
+    Header::new("Authorization", "Bearer secret")
+}
+
+#[rocket::async_test]
+async fn admin_api_is_disabled_without_token() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+
+    let response = daemon
+        .client
+        .post("/api/v1/admin/stale/hello-1.0.tar.gz")
+        .header(auth())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[rocket::async_test]
+async fn admin_api_rejects_wrong_token() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], ADMIN).await;
+
+    let response = daemon
+        .client
+        .post("/api/v1/admin/stale/hello-1.0.tar.gz")
+        .header(Header::new("Authorization", "Bearer wrong"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Unauthorized);
+}
+
+#[rocket::async_test]
+async fn stale_blob_is_refetched() {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes("old bytes"))
+        .up_to_n_times(1)
+        .mount(&mirror)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .mount(&mirror)
+        .await;
+
+    let daemon = TestDaemon::start(&[mirror.uri()], ADMIN).await;
+    let uri = distfile_path("hello-1.0.tar.gz");
+
+    let response = daemon.client.get(uri.clone()).dispatch().await;
+    assert_eq!(response.into_bytes().await.unwrap(), b"old bytes");
+
+    let response = daemon
+        .client
+        .post("/api/v1/admin/stale/hello-1.0.tar.gz")
+        .header(auth())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NoContent);
+
+    let response = daemon.client.get(uri).dispatch().await;
+    assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+}
+
+#[rocket::async_test]
+async fn stale_blob_is_kept_when_refetch_fails() {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .up_to_n_times(1)
+        .mount(&mirror)
+        .await;
+
+    let daemon = TestDaemon::start(&[mirror.uri()], ADMIN).await;
+    let uri = distfile_path("hello-1.0.tar.gz");
+
+    let response = daemon.client.get(uri.clone()).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let response = daemon
+        .client
+        .post("/api/v1/admin/stale/hello-1.0.tar.gz")
+        .header(auth())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NoContent);
+
+    let response = daemon.client.get(uri).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+}
+
+#[rocket::async_test]
+async fn marking_uncached_blob_is_not_found() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], ADMIN).await;
+
+    let response = daemon
+        .client
+        .post("/api/v1/admin/stale/hello-1.0.tar.gz")
+        .header(auth())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[rocket::async_test]
+async fn gc_evicts_down_to_target_size() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], ADMIN).await;
+    daemon.store_blob("hello-1.0.tar.gz", HELLO_CONTENT);
+    daemon.store_blob("hello-0.9.tar.gz", HELLO_CONTENT);
+
+    let response = daemon
+        .client
+        .post(format!(
+            "/api/v1/admin/gc?target_size={}",
+            HELLO_CONTENT.len()
+        ))
+        .header(auth())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let body: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!(body["removed"], 1);
+    assert_eq!(body["remaining"], HELLO_CONTENT.len());
+}
+
+#[rocket::async_test]
+async fn gc_requires_a_target() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], ADMIN).await;
+
+    let response = daemon
+        .client
+        .post("/api/v1/admin/gc")
+        .header(auth())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::BadRequest);
+}
+
+#[rocket::async_test]
+async fn parse_failures_are_listed() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], ADMIN).await;
+    let path = "/repos/overlay/app-misc/broken/broken-1.ebuild";
+
+    daemon
+        .repo_db
+        .record_parse_failure(path, "aaaa", "unsupported EAPI", 1000)
+        .await
+        .unwrap();
+    daemon
+        .repo_db
+        .record_parse_failure(path, "aaaa", "unsupported EAPI", 5000)
+        .await
+        .unwrap();
+
+    let response = daemon
+        .client
+        .get("/api/v1/admin/parse_failures")
+        .header(auth())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let body: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    let failure = &body["failures"][0];
+    assert_eq!(failure["path"], path);
+    assert_eq!(failure["error"], "unsupported EAPI");
+    assert_eq!(failure["attempts"], 2);
+    // backoff doubles with the second attempt
+    assert_eq!(failure["retry_at"], 5000 + 2 * 3600);
+}
+
+#[rocket::async_test]
+async fn downloads_are_listed_with_filters() {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .mount(&mirror)
+        .await;
+    let daemon = TestDaemon::start(&[mirror.uri()], ADMIN).await;
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let response = daemon
+        .client
+        .get(distfile_path("missing-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+
+    let list = async |query: &str| -> serde_json::Value {
+        let response = daemon
+            .client
+            .get(format!("/api/v1/admin/downloads{}", query))
+            .header(auth())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap()
+    };
+
+    let body = list("").await;
+    assert_eq!(body["total"], 2);
+    assert_eq!(body["downloads"][0]["file"], "missing-1.0.tar.gz");
+    assert_eq!(body["downloads"][1]["file"], "hello-1.0.tar.gz");
+
+    let body = list("?outcome=fetched").await;
+    assert_eq!(body["total"], 1);
+    let download = &body["downloads"][0];
+    assert_eq!(download["fetcher"], "Mirror");
+    assert_eq!(
+        download["source"],
+        format!("{}{}", mirror.uri(), distfile_path("hello-1.0.tar.gz"))
+    );
+    assert_eq!(download["bytes"], HELLO_CONTENT.len());
+    assert!(download["request_id"].is_string());
+
+    let body = list("?file=missing").await;
+    assert_eq!(body["total"], 1);
+    assert_eq!(body["downloads"][0]["outcome"], "failed");
+    assert!(body["downloads"][0]["error"].is_string());
+
+    let body = list("?limit=1&offset=1").await;
+    assert_eq!(body["total"], 2);
+    assert_eq!(body["downloads"].as_array().unwrap().len(), 1);
+    assert_eq!(body["downloads"][0]["file"], "hello-1.0.tar.gz");
+
+    let body = list("?since=4000000000").await;
+    assert_eq!(body["total"], 0);
+}
+
+#[rocket::async_test]
+async fn refresh_replaces_corrupt_blobs() {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_bytes(HELLO_CONTENT)
+                .set_delay(Duration::from_millis(500)),
+        )
+        .expect(1)
+        .mount(&mirror)
+        .await;
+    // doesn't match the checksums of its Manifest entry
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-data-1.0.tar.xz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(vec![0; 1024]))
+        .expect(1)
+        .mount(&mirror)
+        .await;
+    let daemon = TestDaemon::start(&[mirror.uri()], ADMIN).await;
+    daemon.load_fixture_manifests().await;
+    daemon.store_blob("hello-1.0.tar.gz", b"corrupt");
+    daemon.store_blob("hello-data-1.0.tar.xz", b"suspect");
+
+    let url = format!("{}?refresh=1", distfile_path("hello-1.0.tar.gz"));
+    let response = daemon.client.get(&url).dispatch().await;
+    assert_eq!(response.status(), Status::Unauthorized);
+    assert_eq!(
+        std::fs::read(daemon.blob_path("hello-1.0.tar.gz")).unwrap(),
+        b"corrupt"
+    );
+
+    // the cached blob stays in place until the refetch replaces it
+    let refresh = async {
+        let response = daemon.client.get(&url).header(auth()).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+    };
+    let during = async {
+        rocket::tokio::time::sleep(Duration::from_millis(200)).await;
+        std::fs::read(daemon.blob_path("hello-1.0.tar.gz")).unwrap()
+    };
+    let (_, during) = rocket::tokio::join!(refresh, during);
+    assert_eq!(during, b"corrupt");
+    assert_eq!(
+        std::fs::read(daemon.blob_path("hello-1.0.tar.gz")).unwrap(),
+        HELLO_CONTENT
+    );
+
+    // a refetch failing verification keeps the cached blob
+    let response = daemon
+        .client
+        .get(format!(
+            "{}?refresh=1",
+            distfile_path("hello-data-1.0.tar.xz")
+        ))
+        .header(auth())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::BadGateway);
+    let blob = daemon.blob_path("hello-data-1.0.tar.xz");
+    assert_eq!(std::fs::read(&blob).unwrap(), b"suspect");
+    assert!(!portcache::fetcher::part_location(&blob).exists());
+
+    // later requests are served from the cache without asking upstream again
+    let response = daemon
+        .client
+        .get(distfile_path("hello-data-1.0.tar.xz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), b"suspect");
+}
+
+#[rocket::async_test]
+async fn served_distfiles_are_logged_with_provenance() {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .mount(&mirror)
+        .await;
+    let daemon = TestDaemon::start(
+        &[mirror.uri()],
+        &format!("{}\nprovenance_retention = \"90d\"\n", ADMIN),
+    )
+    .await;
+    daemon.load_fixture_manifests().await;
+
+    for _ in 0..2 {
+        let response = daemon
+            .client
+            .get(distfile_path("hello-1.0.tar.gz"))
+            .remote("192.0.2.7:4000".parse().unwrap())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    let response = daemon
+        .client
+        .get("/api/v1/admin/provenance")
+        .header(auth())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let body: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    let served = body["served"].as_array().unwrap();
+    assert_eq!(served.len(), 2);
+    let entry = &served[0];
+    assert_eq!(entry["file"], "hello-1.0.tar.gz");
+    assert_eq!(entry["client"], "192.0.2.7");
+    assert_eq!(entry["bytes"], HELLO_CONTENT.len());
+    assert_eq!(entry["package"], "app-misc/hello");
+    assert!(entry["blake2b"].is_string());
+    assert!(entry["sha512"].is_string());
+    assert_eq!(
+        entry["upstream"],
+        format!("{}{}", mirror.uri(), distfile_path("hello-1.0.tar.gz"))
+    );
+    assert!(entry["request_id"].is_string());
+
+    let response = daemon
+        .client
+        .get("/api/v1/admin/provenance?format=csv&since=4000000000")
+        .header(auth())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        response.into_string().await.unwrap(),
+        "file,served,client,api_key,bytes,blake2b,sha512,package,upstream,request_id\n"
+    );
+}
+
+#[rocket::async_test]
+async fn changed_content_without_manifest_hashes_is_flagged() {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("nohash-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes("first bytes"))
+        .up_to_n_times(1)
+        .mount(&mirror)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("nohash-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes("other bytes"))
+        .mount(&mirror)
+        .await;
+
+    let daemon = TestDaemon::start(&[mirror.uri()], ADMIN).await;
+    let uri = distfile_path("nohash-1.0.tar.gz");
+
+    let response = daemon.client.get(uri.clone()).dispatch().await;
+    assert_eq!(response.into_bytes().await.unwrap(), b"first bytes");
+
+    // the refetch doesn't match what was seen first
+    daemon
+        .blob_storage
+        .remove("nohash-1.0.tar.gz")
+        .await
+        .unwrap();
+    let response = daemon.client.get(uri.clone()).dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+    assert!(!daemon.blob_path("nohash-1.0.tar.gz").exists());
+
+    let response = daemon
+        .client
+        .get("/api/v1/admin/tofu?mismatched=true")
+        .header(auth())
+        .dispatch()
+        .await;
+    let body: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    let checksums = body["checksums"].as_array().unwrap();
+    assert_eq!(checksums.len(), 1);
+    assert_eq!(checksums[0]["file"], "nohash-1.0.tar.gz");
+    assert_eq!(checksums[0]["size"], 11);
+    assert_eq!(checksums[0]["mismatches"], 1);
+    assert!(checksums[0]["mismatch_blake2b"].is_string());
+
+    // accepting the new content
+    let response = daemon
+        .client
+        .delete("/api/v1/admin/tofu/nohash-1.0.tar.gz")
+        .header(auth())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NoContent);
+    let response = daemon.client.get(uri).dispatch().await;
+    assert_eq!(response.into_bytes().await.unwrap(), b"other bytes");
+}
+
+#[rocket::async_test]
+async fn refresh_replaces_the_recorded_checksum_only_once_swapped_in() {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("nohash-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes("first bytes"))
+        .up_to_n_times(1)
+        .mount(&mirror)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("nohash-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(503))
+        .up_to_n_times(1)
+        .mount(&mirror)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("nohash-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes("other bytes"))
+        .mount(&mirror)
+        .await;
+
+    let extra = format!("{}\n[fetcher.retry]\nmax_attempts = 1\n", ADMIN);
+    let daemon = TestDaemon::start(&[mirror.uri()], &extra).await;
+    let uri = distfile_path("nohash-1.0.tar.gz");
+    let refresh = format!("{}?refresh=1", uri);
+
+    let response = daemon.client.get(uri.clone()).dispatch().await;
+    assert_eq!(response.into_bytes().await.unwrap(), b"first bytes");
+    let first = daemon
+        .repo_db
+        .get_tofu_checksum("nohash-1.0.tar.gz")
+        .await
+        .unwrap()
+        .unwrap();
+
+    // a failed refresh keeps both the cached blob and its recorded checksum
+    let response = daemon.client.get(&refresh).header(auth()).dispatch().await;
+    assert_eq!(response.status(), Status::BadGateway);
+    let kept = daemon
+        .repo_db
+        .get_tofu_checksum("nohash-1.0.tar.gz")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(kept.blake2b, first.blake2b);
+
+    // differing content is accepted on refresh and recorded once it replaced the blob
+    let response = daemon.client.get(&refresh).header(auth()).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), b"other bytes");
+    let replaced = daemon
+        .repo_db
+        .get_tofu_checksum("nohash-1.0.tar.gz")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_ne!(replaced.blake2b, first.blake2b);
+    assert_eq!(replaced.mismatches, 0);
+}
+
+#[rocket::async_test]
+async fn manual_eviction_is_logged_with_the_request_id() {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .mount(&mirror)
+        .await;
+
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let storage = TempDir::new().unwrap();
+    let config = storage.path().join("portcache.toml");
+    std::fs::write(
+        &config,
+        format!(
+            "{}\n[storage]\nlocation = \"{}\"\n\
+             [server]\naddress = \"127.0.0.1\"\nport = {}\n\
+             [repo]\nsync_interval = 60\nrepos = []\n\
+             [fetcher]\nmirrors = [\"{}\"]\n",
+            ADMIN,
+            storage.path().to_string_lossy(),
+            port,
+            mirror.uri()
+        ),
+    )
+    .unwrap();
+    let mut server = Process(
+        Command::new(env!("CARGO_BIN_EXE_portcache"))
+            .arg("-c")
+            .arg(&config)
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap(),
+    );
+
+    let base = format!("http://127.0.0.1:{}", port);
+    let url = format!("{}{}", base, distfile_path("hello-1.0.tar.gz"));
+    let deadline = Instant::now() + Duration::from_secs(30);
+    let response = loop {
+        match reqwest::get(&url).await {
+            Ok(response) => break response,
+            Err(e) if e.is_connect() && Instant::now() < deadline => {
+                rocket::tokio::time::sleep(Duration::from_millis(20)).await
+            }
+            Err(e) => panic!("{}", e),
+        }
+    };
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+    // forgetting the aliases of the evicted blob fails
+    rusqlite::Connection::open(storage.path().join("db.sqlite3"))
+        .unwrap()
+        .execute("DROP TABLE blob_alias", [])
+        .unwrap();
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/api/v1/admin/gc?target_size=0", base))
+        .bearer_auth("secret")
+        .header("X-Request-Id", "gc-42")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+    let mut stderr = server.0.stderr.take().unwrap();
+    drop(server);
+    let mut log = String::new();
+    stderr.read_to_string(&mut log).unwrap();
+    assert!(
+        log.contains("[gc-42] Failed to forget aliases of hello-1.0.tar.gz"),
+        "{}",
+        log
+    );
+}
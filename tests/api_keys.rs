@@ -0,0 +1,211 @@
+mod common;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use common::{HELLO_CONTENT, TestDaemon, distfile_path, mock_mirror};
+use portcache::api_keys;
+use rocket::http::{Header, Status};
+
+#[rocket::async_test]
+async fn keys_are_required_when_configured() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "[api_keys]\nrequired = true").await;
+    daemon.store_blob("hello-1.0.tar.gz", HELLO_CONTENT);
+    let key = api_keys::issue(&daemon.repo_db, "builder", None)
+        .await
+        .unwrap();
+    assert!(key.starts_with(api_keys::KEY_PREFIX));
+    let uri = distfile_path("hello-1.0.tar.gz");
+
+    // no key gets a challenge so mirror url credentials get sent
+    let response = daemon.client.get(uri.clone()).dispatch().await;
+    assert_eq!(response.status(), Status::Unauthorized);
+    assert_eq!(
+        response.headers().get_one("WWW-Authenticate"),
+        Some("Basic realm=\"portcache\"")
+    );
+
+    let response = daemon
+        .client
+        .get(uri.clone())
+        .header(Header::new("Authorization", "Bearer pc_unknown"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Unauthorized);
+
+    let response = daemon
+        .client
+        .get(uri.clone())
+        .header(Header::new("Authorization", format!("Bearer {}", key)))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+
+    // as in GENTOO_MIRRORS="http://builder:<key>@host"
+    let basic = STANDARD.encode(format!("builder:{}", key));
+    let response = daemon
+        .client
+        .get(uri)
+        .header(Header::new("Authorization", format!("Basic {}", basic)))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn admin_token_is_no_api_key() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(
+        &[mirror.uri()],
+        "[api_keys]\nrequired = true\n\n[admin]\ntoken = \"secret\"",
+    )
+    .await;
+    daemon.store_blob("hello-1.0.tar.gz", HELLO_CONTENT);
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .header(Header::new("Authorization", "Bearer secret"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Unauthorized);
+}
+
+#[rocket::async_test]
+async fn key_is_limited_after_its_quota() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "[api_keys]\ndefault_limit = 1000").await;
+    daemon.store_blob("hello-1.0.tar.gz", HELLO_CONTENT);
+    let small = api_keys::issue(&daemon.repo_db, "small", Some(40))
+        .await
+        .unwrap();
+    let large = api_keys::issue(&daemon.repo_db, "large", None)
+        .await
+        .unwrap();
+    let uri = distfile_path("hello-1.0.tar.gz");
+
+    let get = |key: &str| {
+        daemon
+            .client
+            .get(uri.clone())
+            .header(Header::new("Authorization", format!("Bearer {}", key)))
+    };
+
+    // two downloads of the 30 byte file exceed the key's own limit
+    for _ in 0..2 {
+        assert_eq!(get(&small).dispatch().await.status(), Status::Ok);
+    }
+    assert_eq!(
+        get(&small).dispatch().await.status(),
+        Status::TooManyRequests
+    );
+
+    // the default limit of other keys isn't reached
+    assert_eq!(get(&large).dispatch().await.status(), Status::Ok);
+
+    // keys stay optional by default
+    let response = daemon.client.get(uri.clone()).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn usage_is_reported_per_key() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(
+        &[mirror.uri()],
+        "[admin]\ntoken = \"secret\"\n[api_keys]\ndefault_limit = 1000",
+    )
+    .await;
+    daemon.store_blob("hello-1.0.tar.gz", HELLO_CONTENT);
+    let key = api_keys::issue(&daemon.repo_db, "builder", None)
+        .await
+        .unwrap();
+    api_keys::issue(&daemon.repo_db, "idle", Some(5))
+        .await
+        .unwrap();
+    assert!(
+        api_keys::issue(&daemon.repo_db, "builder", None)
+            .await
+            .is_err()
+    );
+    assert!(
+        api_keys::issue(&daemon.repo_db, "bad name", None)
+            .await
+            .is_err()
+    );
+
+    for _ in 0..3 {
+        let response = daemon
+            .client
+            .get(distfile_path("hello-1.0.tar.gz"))
+            .header(Header::new("Authorization", format!("Bearer {}", key)))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    let response = daemon
+        .client
+        .get("/api/v1/admin/keys")
+        .header(Header::new("Authorization", "Bearer secret"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let body: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    let keys = body["keys"].as_array().unwrap();
+    assert_eq!(keys.len(), 2);
+
+    assert_eq!(keys[0]["name"], "builder");
+    assert_eq!(keys[0]["bytes"], 3 * HELLO_CONTENT.len() as u64);
+    assert_eq!(keys[0]["requests"], 3);
+    assert_eq!(keys[0]["limit"], 1000);
+    assert!(keys[0]["last_used"].is_u64());
+
+    assert_eq!(keys[1]["name"], "idle");
+    assert_eq!(keys[1]["bytes"], 0);
+    assert_eq!(keys[1]["limit"], 5);
+    assert!(keys[1]["last_used"].is_null());
+
+    // revoking drops the key and its usage
+    assert!(daemon.repo_db.delete_api_key("builder").await.unwrap());
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .header(Header::new("Authorization", format!("Bearer {}", key)))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Unauthorized);
+}
+
+#[rocket::async_test]
+async fn unknown_credentials_are_anonymous_while_keys_are_unused() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    daemon.store_blob("hello-1.0.tar.gz", HELLO_CONTENT);
+    let uri = distfile_path("hello-1.0.tar.gz");
+
+    // mirror url credentials meant for some other proxy
+    let basic = STANDARD.encode("user:password");
+    let response = daemon
+        .client
+        .get(uri.clone())
+        .header(Header::new("Authorization", format!("Basic {}", basic)))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+
+    // once keys are issued a wrong one is a mistake worth reporting
+    api_keys::issue(&daemon.repo_db, "builder", None)
+        .await
+        .unwrap();
+    let response = daemon
+        .client
+        .get(uri)
+        .header(Header::new("Authorization", format!("Basic {}", basic)))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Unauthorized);
+}
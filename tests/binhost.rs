@@ -0,0 +1,194 @@
+mod common;
+
+use common::{TestDaemon, mock_mirror};
+use portcache::binhost::{package_size, rewrite_index};
+use rocket::http::Status;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+/// content of the binary package listed in PACKAGES
+const PACKAGE: &[u8] = b"not really a gpkg\n";
+
+/// Packages index of the mock binhost
+const PACKAGES: &str = "ARCH: amd64\n\
+    PACKAGES: 2\n\
+    URI: https://binhost.example.org/amd64\n\
+    VERSION: 0\n\
+    \n\
+    BUILD_ID: 1\n\
+    CPV: app-misc/hello-1.0\n\
+    PATH: app-misc/hello/hello-1.0-1.gpkg.tar\n\
+    SIZE: 18\n\
+    \n\
+    CPV: app-misc/legacy-2.0\n\
+    SIZE: 42\n\
+    URI: https://elsewhere.example.org/legacy-2.0.tbz2\n";
+
+/// start a portcache with a mock upstream binhost
+async fn binhost_daemon(upstream: &MockServer, index_ttl: u64) -> TestDaemon {
+    let mirror = mock_mirror().await;
+    TestDaemon::start(
+        &[mirror.uri()],
+        &format!(
+            "[binhost]\nupstream = \"{}/binpackages/\"\nindex_ttl = {}\n",
+            upstream.uri(),
+            index_ttl
+        ),
+    )
+    .await
+}
+
+#[test]
+fn index_header_uri_is_dropped() {
+    let rewritten = rewrite_index(PACKAGES);
+    assert!(!rewritten.contains("binhost.example.org"));
+    assert!(rewritten.starts_with("ARCH: amd64\nPACKAGES: 2\nVERSION: 0\n\n"));
+    // only the header gets rewritten
+    assert!(rewritten.contains("URI: https://elsewhere.example.org/legacy-2.0.tbz2\n"));
+}
+
+#[test]
+fn package_sizes_are_read_from_the_index() {
+    assert_eq!(
+        package_size(PACKAGES, "app-misc/hello/hello-1.0-1.gpkg.tar"),
+        Some(18)
+    );
+    assert_eq!(package_size(PACKAGES, "app-misc/legacy-2.0.tbz2"), Some(42));
+    assert_eq!(package_size(PACKAGES, "app-misc/other-1.0.tbz2"), None);
+}
+
+#[rocket::async_test]
+async fn index_is_rewritten_and_cached() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/binpackages/Packages"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(PACKAGES))
+        .expect(1)
+        .mount(&upstream)
+        .await;
+    let daemon = binhost_daemon(&upstream, 3600).await;
+
+    for _ in 0..2 {
+        let response = daemon.client.get("/packages/Packages").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.into_string().await.unwrap(),
+            rewrite_index(PACKAGES)
+        );
+    }
+}
+
+#[rocket::async_test]
+async fn expired_index_is_served_when_upstream_fails() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/binpackages/Packages"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(PACKAGES))
+        .up_to_n_times(1)
+        .mount(&upstream)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/binpackages/Packages"))
+        .respond_with(ResponseTemplate::new(503))
+        .mount(&upstream)
+        .await;
+    let daemon = binhost_daemon(&upstream, 0).await;
+
+    for _ in 0..2 {
+        let response = daemon.client.get("/packages/Packages").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.into_string().await.unwrap(),
+            rewrite_index(PACKAGES)
+        );
+    }
+}
+
+#[rocket::async_test]
+async fn package_miss_is_fetched_then_served_from_cache() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/binpackages/Packages"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(PACKAGES))
+        .mount(&upstream)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/binpackages/app-misc/hello/hello-1.0-1.gpkg.tar"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(PACKAGE))
+        .expect(1)
+        .mount(&upstream)
+        .await;
+    let daemon = binhost_daemon(&upstream, 3600).await;
+
+    for _ in 0..2 {
+        let response = daemon
+            .client
+            .get("/packages/app-misc/hello/hello-1.0-1.gpkg.tar")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_bytes().await.unwrap(), PACKAGE);
+    }
+
+    assert!(
+        daemon
+            .storage
+            .path()
+            .join("packages/app-misc/hello/hello-1.0-1.gpkg.tar")
+            .is_file()
+    );
+}
+
+#[rocket::async_test]
+async fn package_with_wrong_size_is_discarded() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/binpackages/Packages"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(PACKAGES))
+        .mount(&upstream)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/binpackages/app-misc/legacy-2.0.tbz2"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(PACKAGE))
+        .mount(&upstream)
+        .await;
+    let daemon = binhost_daemon(&upstream, 3600).await;
+
+    let response = daemon
+        .client
+        .get("/packages/app-misc/legacy-2.0.tbz2")
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::BadGateway);
+
+    let stored = daemon
+        .storage
+        .path()
+        .join("packages/app-misc/legacy-2.0.tbz2");
+    assert!(!stored.exists());
+    assert!(!stored.with_extension("tbz2.part").exists());
+}
+
+#[rocket::async_test]
+async fn only_packages_are_proxied() {
+    let upstream = MockServer::start().await;
+    let daemon = binhost_daemon(&upstream, 3600).await;
+
+    for uri in [
+        "/packages/app-misc/hello/metadata.xml",
+        "/packages/../db.sqlite3",
+    ] {
+        let response = daemon.client.get(uri).dispatch().await;
+        assert_eq!(response.status(), Status::NotFound, "{}", uri);
+    }
+    assert!(upstream.received_requests().await.unwrap().is_empty());
+}
+
+#[rocket::async_test]
+async fn packages_are_not_served_without_binhost() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+
+    let response = daemon.client.get("/packages/Packages").dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+}
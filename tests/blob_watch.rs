@@ -0,0 +1,101 @@
+mod common;
+
+use common::{HELLO_CONTENT, TestDaemon, distfile_path, mock_mirror};
+use portcache::blob_watch::{BlobChange, BlobWatcher};
+use rocket::http::Status;
+use rocket::tokio::time;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+const WATCH: &str = "[storage]\nwatch_blobs = true";
+
+/// wait until counter reaches value
+async fn wait_for(counter: &AtomicU64, value: u64) {
+    let start = Instant::now();
+    while counter.load(Ordering::Relaxed) < value {
+        assert!(start.elapsed() < Duration::from_secs(10), "timed out");
+        time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+#[rocket::async_test]
+async fn external_changes_are_told_apart_from_our_own() {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .mount(&mirror)
+        .await;
+    let daemon = TestDaemon::start(&[mirror.uri()], WATCH).await;
+    daemon.load_fixture_manifests().await;
+    let watcher = BlobWatcher::new(
+        &daemon.config,
+        daemon.blob_storage.clone(),
+        daemon.repo_db.clone(),
+    )
+    .unwrap();
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        watcher.check("hello-1.0.tar.gz").await.unwrap(),
+        BlobChange::Ours
+    );
+
+    daemon.store_blob("other-1.0.tar.gz", b"by hand");
+    assert_eq!(
+        watcher.check("other-1.0.tar.gz").await.unwrap(),
+        BlobChange::Unverified
+    );
+
+    daemon.store_blob("hello-data-1.0.tar.xz", b"corrupt");
+    assert!(matches!(
+        watcher.check("hello-data-1.0.tar.xz").await.unwrap(),
+        BlobChange::Mismatched(_)
+    ));
+    let stale = daemon.repo_db.get_stale_blobs().await.unwrap();
+    assert_eq!(stale, ["hello-data-1.0.tar.xz"]);
+
+    std::fs::remove_file(daemon.blob_path("hello-data-1.0.tar.xz")).unwrap();
+    assert_eq!(
+        watcher.check("hello-data-1.0.tar.xz").await.unwrap(),
+        BlobChange::Removed
+    );
+    assert!(daemon.repo_db.get_stale_blobs().await.unwrap().is_empty());
+
+    let response = daemon.client.get("/metrics").dispatch().await;
+    let body = response.into_string().await.unwrap();
+    assert!(body.contains("portcache_blobs_removed_externally_total 1\n"));
+    assert!(body.contains("portcache_blobs_replaced_externally_total 1\n"));
+    assert!(body.contains("portcache_blobs_mismatched_externally_total 1\n"));
+}
+
+#[rocket::async_test]
+async fn watcher_notices_blobs_changed_by_hand() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], WATCH).await;
+    let watcher = BlobWatcher::new(
+        &daemon.config,
+        daemon.blob_storage.clone(),
+        daemon.repo_db.clone(),
+    )
+    .unwrap();
+    // the hash directory has to exist before the watch starts
+    daemon.store_blob("other-1.0.tar.gz", b"by hand");
+    rocket::tokio::spawn(watcher.start());
+    time::sleep(Duration::from_millis(300)).await;
+
+    let external = daemon.blob_storage.external_changes();
+    std::fs::write(daemon.blob_path("other-1.0.tar.gz"), b"again").unwrap();
+    wait_for(&external.replaced, 1).await;
+
+    std::fs::remove_file(daemon.blob_path("other-1.0.tar.gz")).unwrap();
+    wait_for(&external.removed, 1).await;
+    assert_eq!(external.mismatched.load(Ordering::Relaxed), 0);
+}
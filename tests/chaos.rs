@@ -0,0 +1,151 @@
+#![cfg(feature = "chaos")]
+
+mod common;
+
+use common::{HELLO_CONTENT, TestDaemon, distfile_path, mock_mirror};
+use rocket::http::{Header, Status};
+use std::time::{Duration, Instant};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+/// a daemon with admin API and its mirror serving hello-1.0.tar.gz
+async fn daemon() -> (TestDaemon, MockServer) {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .mount(&mirror)
+        .await;
+
+    let daemon = TestDaemon::start(
+        &[mirror.uri()],
+        "[admin]\ntoken = \"secret\"\n[fetcher.retry]\nmax_attempts = 1",
+    )
+    .await;
+    (daemon, mirror)
+}
+
+/// inject faults via the admin API
+async fn inject(daemon: &TestDaemon, faults: &str) -> Status {
+    daemon
+        .client
+        .put("/api/v1/admin/chaos")
+        .header(Header::new("Authorization", "Bearer secret"))
+        .body(faults)
+        .dispatch()
+        .await
+        .status()
+}
+
+#[rocket::async_test]
+async fn injected_upstream_errors_fail_fetches() {
+    let (daemon, _mirror) = daemon().await;
+    let uri = distfile_path("hello-1.0.tar.gz");
+
+    assert_eq!(
+        inject(
+            &daemon,
+            r#"{"upstream_error_rate": 1.0, "upstream_status": 502}"#
+        )
+        .await,
+        Status::NoContent
+    );
+    let response = daemon.client.get(uri.clone()).dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+    assert!(!daemon.blob_path("hello-1.0.tar.gz").exists());
+
+    // faults limited to other files don't apply
+    assert_eq!(
+        inject(&daemon, r#"{"upstream_error_rate": 1.0, "only": "other"}"#).await,
+        Status::NoContent
+    );
+    let response = daemon.client.get(uri).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn injected_disk_full_leaves_no_blob() {
+    let (daemon, _mirror) = daemon().await;
+    let uri = distfile_path("hello-1.0.tar.gz");
+
+    assert_eq!(
+        inject(&daemon, r#"{"disk_full": true}"#).await,
+        Status::NoContent
+    );
+    let response = daemon.client.get(uri.clone()).dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+    assert!(!daemon.blob_path("hello-1.0.tar.gz").exists());
+
+    let response = daemon
+        .client
+        .delete("/api/v1/admin/chaos")
+        .header(Header::new("Authorization", "Bearer secret"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NoContent);
+    let response = daemon.client.get(uri).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+}
+
+#[rocket::async_test]
+async fn injected_stream_delay_slows_fetches() {
+    let (daemon, _mirror) = daemon().await;
+
+    assert_eq!(
+        inject(&daemon, r#"{"stream_delay_ms": 300}"#).await,
+        Status::NoContent
+    );
+    let started = Instant::now();
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert!(started.elapsed() >= Duration::from_millis(300));
+}
+
+#[rocket::async_test]
+async fn faults_are_validated_and_reported() {
+    let (daemon, _mirror) = daemon().await;
+
+    assert_eq!(
+        inject(&daemon, r#"{"upstream_error_rate": 2.0}"#).await,
+        Status::BadRequest
+    );
+    assert_eq!(
+        inject(&daemon, r#"{"upstream_status": 200}"#).await,
+        Status::BadRequest
+    );
+    assert_eq!(
+        inject(&daemon, r#"{"no_such_fault": true}"#).await,
+        Status::BadRequest
+    );
+    assert_eq!(
+        inject(&daemon, r#"{"stream_delay_ms": 5}"#).await,
+        Status::NoContent
+    );
+
+    let response = daemon
+        .client
+        .get("/api/v1/admin/chaos")
+        .header(Header::new("Authorization", "Bearer secret"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let faults: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!(faults["stream_delay_ms"], 5);
+    assert_eq!(faults["upstream_status"], 503);
+    assert_eq!(faults["disk_full"], false);
+
+    // the admin token is required
+    let response = daemon
+        .client
+        .put("/api/v1/admin/chaos")
+        .body(r#"{"disk_full": true}"#)
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Unauthorized);
+}
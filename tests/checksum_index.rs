@@ -0,0 +1,102 @@
+mod common;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use common::{HELLO_CONTENT, TestDaemon, distfile_path, mock_mirror};
+use openssl::pkey::{Id, PKey};
+use openssl::sign::Verifier;
+use portcache::checksum_index::{SIGNATURE_HEADER, SigningKey};
+use rocket::http::Status;
+use serde_json::Value;
+use tempfile::TempDir;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+/// verify a <name>:<base64> signature against a <name>:<base64> public key
+fn verify(public_key: &str, signature: &str, data: &[u8]) -> bool {
+    let (key_name, key) = public_key.split_once(':').unwrap();
+    let (signature_name, signature) = signature.split_once(':').unwrap();
+    let key = PKey::public_key_from_raw_bytes(&STANDARD.decode(key).unwrap(), Id::ED25519).unwrap();
+    key_name == signature_name
+        && Verifier::new_without_digest(&key)
+            .unwrap()
+            .verify_oneshot(&STANDARD.decode(signature).unwrap(), data)
+            .unwrap()
+}
+
+#[rocket::async_test]
+async fn index_lists_cached_distfiles_signed() {
+    let mirror = mock_mirror().await;
+    for file in ["hello-1.0.tar.gz", "other-1.0.tar.gz"] {
+        Mock::given(method("GET"))
+            .and(path(distfile_path(file)))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+            .mount(&mirror)
+            .await;
+    }
+
+    let keys = TempDir::new().unwrap();
+    let key_path = keys.path().join("index.key");
+    SigningKey::generate("cache.example")
+        .unwrap()
+        .save(&key_path)
+        .unwrap();
+    let daemon = TestDaemon::start(
+        &[mirror.uri()],
+        &format!("[index]\nsigning_key = \"{}\"", key_path.to_string_lossy()),
+    )
+    .await;
+
+    let public_key = daemon
+        .client
+        .get("/api/v1/index/key")
+        .dispatch()
+        .await
+        .into_string()
+        .await
+        .unwrap();
+    assert!(public_key.starts_with("cache.example:"));
+
+    let mut listed = Vec::new();
+    for file in ["hello-1.0.tar.gz", "other-1.0.tar.gz"] {
+        let response = daemon.client.get(distfile_path(file)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+
+        // rebuilt after every fetch
+        let response = daemon.client.get("/api/v1/index").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let signature = response
+            .headers()
+            .get_one(SIGNATURE_HEADER)
+            .unwrap()
+            .to_string();
+        let body = response.into_bytes().await.unwrap();
+        assert!(verify(&public_key, &signature, &body));
+        assert!(!verify(&public_key, &signature, b"{}"));
+
+        let index: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(index["version"], 1);
+        listed = index["files"].as_array().unwrap().clone();
+    }
+
+    // checksummed on first use since there are no Manifests
+    assert_eq!(listed.len(), 2);
+    assert_eq!(listed[0]["file"], "hello-1.0.tar.gz");
+    assert_eq!(listed[0]["size"], HELLO_CONTENT.len());
+    assert_eq!(
+        listed[0]["path"],
+        format!(
+            "distfiles/{}/hello-1.0.tar.gz",
+            daemon.blob_storage.hash_dir("hello-1.0.tar.gz")
+        )
+    );
+    assert!(listed[0]["blake2b"].is_string());
+}
+
+#[rocket::async_test]
+async fn index_is_missing_without_a_key() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    let response = daemon.client.get("/api/v1/index").dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+}
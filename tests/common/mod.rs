@@ -0,0 +1,192 @@
+//! harness for integration tests
+//! stands up the full server against temp storage and mock mirrors
+
+#![allow(dead_code)]
+
+use futures::StreamExt;
+use futures::pin_mut;
+use portcache::app::{self, Deps};
+use portcache::blob_storage::BlobStorage;
+use portcache::config::{Config, LayoutCheck};
+use portcache::manifest_walker::{ManifestDepth, ManifestWalker};
+use portcache::repo_db::RepoDB;
+use portcache::repo_syncer::SyncProgress;
+use portcache::telemetry::Tracer;
+use portcache::utils;
+use rocket::local::asynchronous::Client;
+use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::sync::Arc;
+use tempfile::TempDir;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+/// layout.conf served by Gentoo mirrors
+pub const LAYOUT_CONF: &str = "[structure]\n0=filename-hash BLAKE2B 8\n";
+
+/// content of hello-1.0.tar.gz as declared in the fixture Manifest
+pub const HELLO_CONTENT: &[u8] = b"hello from the fixture mirror\n";
+
+/// path to the fixture ebuild repo
+pub fn fixture_repo() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/repo")
+}
+
+/// copy the fixture ebuild repo to dest so tests can modify it
+pub fn copy_fixture_repo(dest: &Path) {
+    let root = fixture_repo();
+    for entry in walkdir::WalkDir::new(&root) {
+        let entry = entry.unwrap();
+        let target = dest.join(entry.path().strip_prefix(&root).unwrap());
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(target).unwrap();
+        } else {
+            std::fs::copy(entry.path(), target).unwrap();
+        }
+    }
+}
+
+/// path of a distfile relative to a mirror root
+pub fn distfile_path(file: &str) -> String {
+    format!(
+        "/distfiles/{}/{}",
+        utils::filename_hash_dir_blake2b(file).unwrap(),
+        file
+    )
+}
+
+/// start a mock Gentoo mirror which already serves layout.conf
+pub async fn mock_mirror() -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/distfiles/layout.conf"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(LAYOUT_CONF))
+        .mount(&server)
+        .await;
+    server
+}
+
+/// a portcache server running against temporary storage
+pub struct TestDaemon {
+    /// client to send requests to the server
+    pub client: Client,
+
+    /// repo database of the server
+    pub repo_db: Arc<RepoDB>,
+
+    /// storage root, removed on drop
+    pub storage: TempDir,
+
+    /// config the server was built from
+    pub config: Config,
+
+    /// blob storage of the server
+    pub blob_storage: Arc<BlobStorage>,
+
+    /// sync progress served by the server
+    pub sync_progress: Arc<SyncProgress>,
+
+    /// tracer of the server if telemetry is configured
+    pub tracer: Option<Arc<Tracer>>,
+}
+
+impl TestDaemon {
+    /// start a server fetching from mirrors
+    ///
+    /// @param mirrors  urls of the mirrors to use
+    /// @param extra    additional toml merged into the base config
+    pub async fn start(mirrors: &[String], extra: &str) -> Self {
+        let storage = TempDir::new().unwrap();
+
+        let mirrors: Vec<String> = mirrors.iter().map(|m| format!("\"{}\"", m)).collect();
+        let base = format!(
+            "[storage]\nlocation = \"{}\"\n\n\
+             [server]\naddress = \"127.0.0.1\"\nport = 0\n\n\
+             [repo]\nsync_interval = 60\nrepos = []\n\n\
+             [fetcher]\nmirrors = [{}]\n",
+            storage.path().to_string_lossy(),
+            mirrors.join(", "),
+        );
+        let mut toml: toml::Table = base.parse().unwrap();
+        merge(&mut toml, extra.parse().unwrap());
+
+        let config_path = storage.path().join("portcache.toml");
+        std::fs::write(&config_path, toml.to_string()).unwrap();
+
+        let config = Config::parse(Some(config_path.to_string_lossy().to_string())).unwrap();
+        let deps = Deps::new(&config).await.unwrap();
+        let repo_db = deps.repo_db.clone();
+        let blob_storage = deps.blob_storage.clone();
+        let sync_progress = deps.sync_progress.clone();
+        let tracer = deps.tracer.clone();
+        let client = Client::tracked(app::build_rocket(&config, deps))
+            .await
+            .unwrap();
+
+        Self {
+            client,
+            repo_db,
+            storage,
+            config,
+            blob_storage,
+            sync_progress,
+            tracer,
+        }
+    }
+
+    /// insert all Manifest entries of the fixture repo into the database
+    pub async fn load_fixture_manifests(&self) {
+        let mut walker = ManifestWalker::new(
+            fixture_repo(),
+            LayoutCheck::Require,
+            ManifestDepth::Exactly(3),
+        )
+        .unwrap();
+        let entries = walker.entries();
+        pin_mut!(entries);
+        while let Some(entry) = entries.next().await {
+            self.repo_db
+                .insert_manifest_entry("fixture", entry)
+                .await
+                .unwrap();
+        }
+    }
+
+    /// put a blob into storage as if it was fetched
+    pub fn store_blob(&self, file: &str, content: &[u8]) {
+        let path = self.blob_path(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    /// location a blob is stored at
+    pub fn blob_path(&self, file: &str) -> PathBuf {
+        self.storage
+            .path()
+            .join("distfiles")
+            .join(utils::filename_hash_dir_blake2b(file).unwrap())
+            .join(file)
+    }
+}
+
+/// recursively merge tables from extra into base
+fn merge(base: &mut toml::Table, extra: toml::Table) {
+    for (key, value) in extra {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(extra)) => merge(base, extra),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// child process killed once the test is done with it, even on failure
+pub struct Process(pub Child);
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
@@ -0,0 +1,158 @@
+mod common;
+
+use common::{HELLO_CONTENT, TestDaemon, distfile_path, mock_mirror};
+use portcache::app::Deps;
+use portcache::control::ControlSocket;
+use rocket::tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use rocket::tokio::net::UnixStream;
+use rocket::tokio::time;
+use serde_json::{Value, json};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::time::Duration;
+use tempfile::TempDir;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+/// start a daemon with a control socket at socket
+/// the mirror has to be kept around while the test runs
+async fn daemon_with_control(socket: &Path) -> (TestDaemon, ControlSocket, MockServer) {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .mount(&mirror)
+        .await;
+
+    let daemon = TestDaemon::start(
+        &[mirror.uri()],
+        &format!("[control]\nsocket = \"{}\"", socket.to_string_lossy()),
+    )
+    .await;
+    let deps = Deps {
+        repo_db: daemon.repo_db.clone(),
+        blob_storage: daemon.blob_storage.clone(),
+        binhost: None,
+        releases: None,
+        sync_progress: daemon.sync_progress.clone(),
+        tracer: None,
+    };
+    let control = ControlSocket::new(&daemon.config, &deps).unwrap();
+    (daemon, control, mirror)
+}
+
+/// call method and return the parsed response
+async fn call(control: &ControlSocket, method: &str, params: Value) -> Value {
+    let request = json!({ "jsonrpc": "2.0", "id": 7, "method": method, "params": params });
+    let response = control.handle(&request.to_string()).await.unwrap();
+    serde_json::from_str(&response).unwrap()
+}
+
+#[rocket::async_test]
+async fn control_socket_answers_json_rpc_over_the_socket() {
+    let dir = TempDir::new().unwrap();
+    let socket = dir.path().join("control.sock");
+    let (daemon, control, _mirror) = daemon_with_control(&socket).await;
+    rocket::tokio::spawn(control.start());
+    while !socket.exists() {
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    let mode = std::fs::metadata(&socket).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let stream = UnixStream::connect(&socket).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    // notifications get no response, the next line answers the stats request
+    writer
+        .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"sync\"}\n")
+        .await
+        .unwrap();
+    writer
+        .write_all(b"{\"jsonrpc\":\"2.0\",\"id\":\"a\",\"method\":\"stats\"}\n")
+        .await
+        .unwrap();
+    let response: Value = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+    assert_eq!(response["id"], "a");
+    assert!(response["result"]["repos"].is_array());
+
+    // the sync got requested
+    time::timeout(
+        Duration::from_secs(1),
+        daemon.sync_progress.sync_requested(),
+    )
+    .await
+    .unwrap();
+
+    writer.write_all(b"not json\n").await.unwrap();
+    let response: Value = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+    assert_eq!(response["error"]["code"], -32700);
+    assert_eq!(response["id"], Value::Null);
+}
+
+#[rocket::async_test]
+async fn control_socket_prefetches_and_evicts() {
+    let dir = TempDir::new().unwrap();
+    let (daemon, control, _mirror) = daemon_with_control(&dir.path().join("control.sock")).await;
+
+    let response = call(
+        &control,
+        "prefetch",
+        json!({ "files": ["hello-1.0.tar.gz", "../escape"] }),
+    )
+    .await;
+    assert_eq!(response["id"], 7);
+    let results = response["result"].as_array().unwrap();
+    assert_eq!(results[0]["status"], "fetched");
+    assert_eq!(results[1]["status"], "failed");
+    assert_eq!(
+        std::fs::read(daemon.blob_path("hello-1.0.tar.gz")).unwrap(),
+        HELLO_CONTENT
+    );
+
+    let response = call(
+        &control,
+        "prefetch",
+        json!({ "files": ["hello-1.0.tar.gz"] }),
+    )
+    .await;
+    assert_eq!(response["result"][0]["status"], "cached");
+
+    let response = call(&control, "gc", json!({ "target_size": 0 })).await;
+    assert_eq!(response["result"]["removed"], 1);
+    assert!(!daemon.blob_path("hello-1.0.tar.gz").exists());
+
+    // no storage.max_size to fall back on
+    let response = call(&control, "gc", Value::Null).await;
+    assert_eq!(response["error"]["code"], -32602);
+
+    let response = call(&control, "gc", json!({ "target_sise": 0 })).await;
+    assert_eq!(response["error"]["code"], -32602);
+
+    let response = call(&control, "reboot", Value::Null).await;
+    assert_eq!(response["error"]["code"], -32601);
+
+    let response = call(&control, "sync_status", Value::Null).await;
+    assert_eq!(response["result"]["phase"], "idle");
+}
+
+#[rocket::async_test]
+async fn control_socket_replaces_stale_sockets_only() {
+    let dir = TempDir::new().unwrap();
+    let socket = dir.path().join("control.sock");
+    let (_daemon, control, _mirror) = daemon_with_control(&socket).await;
+
+    // left behind by a crashed instance
+    drop(UnixListener::bind(&socket).unwrap());
+    let listener = control.bind().await.unwrap();
+
+    // still listened on
+    assert!(control.bind().await.unwrap_err().contains("in use"));
+    drop(listener);
+
+    std::fs::remove_file(&socket).unwrap();
+    std::fs::write(&socket, "").unwrap();
+    assert!(control.bind().await.unwrap_err().contains("no socket"));
+}
@@ -0,0 +1,127 @@
+use portcache::config::ParserConfig;
+use portcache::ebuild_parser::{Ebuild, HelperPool, SrcUri};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use tempfile::TempDir;
+
+/// fake bwrap answering like the helper's batch mode
+/// without running python or portage
+const FAKE_HELPER: &str = r#"#!/bin/sh
+while read -r ebuild; do
+    echo "noise from portage"
+    case "$ebuild" in
+        *broken*) echo 'portcache:{"error": "Error parsing ebuild '"$ebuild"'"}' ;;
+        *) echo 'portcache:{"src_uri": {"'"$(basename "$ebuild" .ebuild)"'.tar.gz": [{"uri": "https://example.org/x", "use": ["doc"]}]}}' ;;
+    esac
+done
+"#;
+
+fn fake_pool(dir: &TempDir) -> HelperPool {
+    let helper = dir.path().join("fake-bwrap");
+    std::fs::write(&helper, FAKE_HELPER).unwrap();
+    std::fs::set_permissions(&helper, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let config: ParserConfig = toml::from_str(&format!(
+        "workers = 2\nworker_ebuilds = 2\nbwrap = \"{}\"",
+        helper.to_string_lossy()
+    ))
+    .unwrap();
+    HelperPool::new(&config).unwrap()
+}
+
+#[rocket::async_test]
+async fn helper_pool_answers_many_ebuilds() {
+    let dir = TempDir::new().unwrap();
+    let pool = fake_pool(&dir);
+
+    // more ebuilds than a worker may parse so workers get replaced
+    for version in ["1.0", "1.1", "1.2", "1.3", "1.4"] {
+        let path = format!("/repo/app-misc/hello/hello-{}.ebuild", version);
+        let ebuild = Ebuild::parse(Path::new(&path), &pool).await.unwrap();
+        assert_eq!(
+            ebuild.src_uri[&format!("hello-{}.tar.gz", version)],
+            vec![SrcUri {
+                uri: "https://example.org/x".to_string(),
+                conditions: vec!["doc".to_string()],
+            }]
+        );
+    }
+}
+
+#[rocket::async_test]
+async fn helper_pool_reports_per_ebuild_errors() {
+    let dir = TempDir::new().unwrap();
+    let pool = fake_pool(&dir);
+
+    let error = Ebuild::parse(Path::new("/repo/app-misc/broken/broken-1.ebuild"), &pool)
+        .await
+        .err()
+        .unwrap();
+    assert!(error.contains("Error parsing ebuild"), "{}", error);
+
+    // the worker stays usable
+    let ebuild = Ebuild::parse(Path::new("/repo/app-misc/hello/hello-1.0.ebuild"), &pool)
+        .await
+        .unwrap();
+    assert!(ebuild.src_uri.contains_key("hello-1.0.tar.gz"));
+}
+
+#[test]
+fn use_conditionals_policy_filters_src_uris() {
+    let src_uri = |conditions: &[&str]| SrcUri {
+        uri: "https://example.org/x".to_string(),
+        conditions: conditions.iter().map(|c| c.to_string()).collect(),
+    };
+    let parser = |extra: &str| -> ParserConfig { toml::from_str(extra).unwrap() };
+
+    let all = parser("use_conditionals = \"all\"");
+    assert!(src_uri(&["doc"]).wanted(&all));
+
+    let none = parser("use_conditionals = \"none\"");
+    assert!(src_uri(&[]).wanted(&none));
+    assert!(!src_uri(&["doc"]).wanted(&none));
+
+    let set = parser("use_conditionals = \"set\"\nuse_flags = [\"doc\"]");
+    assert!(src_uri(&["doc"]).wanted(&set));
+    assert!(src_uri(&["doc", "!test"]).wanted(&set));
+    assert!(!src_uri(&["test"]).wanted(&set));
+    assert!(!src_uri(&["!doc"]).wanted(&set));
+}
+
+#[rocket::async_test]
+async fn helper_pool_stops_starting_workers_above_memory_high() {
+    let dir = TempDir::new().unwrap();
+    let started = dir.path().join("started");
+    let helper = dir.path().join("fake-bwrap");
+    std::fs::write(
+        &helper,
+        format!(
+            "#!/bin/sh\necho $$ >> '{}'\nwhile read -r ebuild; do\n    sleep 0.2\n    echo 'portcache:{{\"src_uri\": {{}}}}'\ndone\n",
+            started.to_string_lossy()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&helper, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    // any helper uses more than a byte so only one ever runs
+    let config: ParserConfig = toml::from_str(&format!(
+        "workers = 4\nmemory_high = 1\nbwrap = \"{}\"",
+        helper.to_string_lossy()
+    ))
+    .unwrap();
+    let pool = HelperPool::new(&config).unwrap();
+
+    let parses = ["1.0", "1.1", "1.2", "1.3"].map(|version| {
+        let pool = &pool;
+        async move {
+            let path = format!("/repo/app-misc/hello/hello-{}.ebuild", version);
+            Ebuild::parse(Path::new(&path), pool).await
+        }
+    });
+    for parsed in futures::future::join_all(parses).await {
+        assert!(parsed.is_ok());
+    }
+
+    let started = std::fs::read_to_string(&started).unwrap();
+    assert_eq!(started.lines().count(), 1);
+}
@@ -0,0 +1,221 @@
+mod common;
+
+use common::{HELLO_CONTENT, TestDaemon, distfile_path, mock_mirror};
+use portcache::evictor::{EvictionTarget, Evictor};
+use rocket::http::Status;
+use std::fs::{File, FileTimes};
+use std::time::{Duration, SystemTime};
+
+/// set the access time of a blob to secs seconds ago
+fn accessed_ago(daemon: &TestDaemon, file: &str, secs: u64) {
+    let time = SystemTime::now() - Duration::from_secs(secs);
+    File::options()
+        .write(true)
+        .open(daemon.blob_path(file))
+        .unwrap()
+        .set_times(FileTimes::new().set_accessed(time).set_modified(time))
+        .unwrap();
+}
+
+/// daemon holding a referenced blob accessed long ago
+/// and a dropped one accessed recently
+async fn daemon_with_blobs(policy: &str) -> TestDaemon {
+    let mirror = mock_mirror().await;
+    let extra = format!("[storage]\nmax_size = 40\neviction = \"{}\"", policy);
+    let daemon = TestDaemon::start(&[mirror.uri()], &extra).await;
+    daemon.load_fixture_manifests().await;
+
+    daemon.store_blob("hello-1.0.tar.gz", HELLO_CONTENT);
+    daemon.store_blob("hello-0.9.tar.gz", HELLO_CONTENT);
+    accessed_ago(&daemon, "hello-1.0.tar.gz", 3600);
+    accessed_ago(&daemon, "hello-0.9.tar.gz", 60);
+
+    daemon
+}
+
+fn evictor(daemon: &TestDaemon) -> Evictor {
+    Evictor::new(
+        &daemon.config,
+        daemon.blob_storage.clone(),
+        daemon.repo_db.clone(),
+    )
+    .unwrap()
+}
+
+#[rocket::async_test]
+async fn lru_evicts_least_recently_used() {
+    let daemon = daemon_with_blobs("lru").await;
+
+    let report = evictor(&daemon).run().await.unwrap();
+    assert_eq!(report.removed, 1);
+    assert_eq!(report.remaining, HELLO_CONTENT.len() as u64);
+    assert!(!daemon.blob_path("hello-1.0.tar.gz").exists());
+    assert!(daemon.blob_path("hello-0.9.tar.gz").exists());
+}
+
+#[rocket::async_test]
+async fn lru_uses_recorded_cache_hits() {
+    let daemon = daemon_with_blobs("lru").await;
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    // like on a noatime mount
+    accessed_ago(&daemon, "hello-1.0.tar.gz", 3600);
+
+    // pending hits count before they're written
+    let times = daemon.blob_storage.access_log().times().await.unwrap();
+    assert!(times.contains_key("hello-1.0.tar.gz"));
+    assert!(daemon.repo_db.get_access_times().await.unwrap().is_empty());
+    assert_eq!(daemon.blob_storage.access_log().flush().await.unwrap(), 1);
+    assert_eq!(daemon.blob_storage.access_log().flush().await.unwrap(), 0);
+    assert!(
+        daemon
+            .repo_db
+            .get_access_times()
+            .await
+            .unwrap()
+            .contains_key("hello-1.0.tar.gz")
+    );
+
+    let report = evictor(&daemon).run().await.unwrap();
+    assert_eq!(report.removed, 1);
+    assert!(daemon.blob_path("hello-1.0.tar.gz").exists());
+    assert!(!daemon.blob_path("hello-0.9.tar.gz").exists());
+
+    // forgotten along with the blob
+    assert!(
+        daemon
+            .blob_storage
+            .remove("hello-1.0.tar.gz")
+            .await
+            .unwrap()
+    );
+    assert!(daemon.repo_db.get_access_times().await.unwrap().is_empty());
+}
+
+#[rocket::async_test]
+async fn tree_aware_evicts_dropped_versions_first() {
+    let daemon = daemon_with_blobs("tree_aware").await;
+
+    let report = evictor(&daemon).run().await.unwrap();
+    assert_eq!(report.removed, 1);
+    assert!(daemon.blob_path("hello-1.0.tar.gz").exists());
+    assert!(!daemon.blob_path("hello-0.9.tar.gz").exists());
+}
+
+#[rocket::async_test]
+async fn size_aware_evicts_large_idle_blobs_first() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(
+        &[mirror.uri()],
+        "[storage]\nmax_size = 2000\neviction = \"size_aware\"",
+    )
+    .await;
+
+    // LRU would evict the patches, freeing next to nothing
+    daemon.store_blob("big-1.0.tar.gz", &[0; 2000]);
+    accessed_ago(&daemon, "big-1.0.tar.gz", 3600);
+    for patch in ["a-1.0.patch", "b-1.0.patch", "c-1.0.patch"] {
+        daemon.store_blob(patch, &[0; 10]);
+        accessed_ago(&daemon, patch, 86400);
+    }
+
+    let report = evictor(&daemon).run().await.unwrap();
+    assert_eq!(report.removed, 1);
+    assert_eq!(report.freed, 2000);
+    assert!(!daemon.blob_path("big-1.0.tar.gz").exists());
+    assert!(daemon.blob_path("a-1.0.patch").exists());
+}
+
+#[rocket::async_test]
+async fn nothing_is_evicted_below_max_size() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "[storage]\nmax_size = 1000").await;
+    daemon.store_blob("hello-1.0.tar.gz", HELLO_CONTENT);
+
+    let report = evictor(&daemon).run().await.unwrap();
+    assert_eq!(report.removed, 0);
+    assert!(daemon.blob_path("hello-1.0.tar.gz").exists());
+}
+
+#[rocket::async_test]
+async fn evictor_is_disabled_without_max_size() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    assert!(
+        Evictor::new(
+            &daemon.config,
+            daemon.blob_storage.clone(),
+            daemon.repo_db.clone()
+        )
+        .is_none()
+    );
+}
+
+#[rocket::async_test]
+async fn scheduled_runs_respect_windows() {
+    let mirror = mock_mirror().await;
+    let extra = "[storage]\nmax_size = 1000\neviction_windows = [\"01:00-05:00\", \"23:00-00:30\"]";
+    let daemon = TestDaemon::start(&[mirror.uri()], extra).await;
+    let evictor = evictor(&daemon);
+
+    let at = |hours: u64, minutes: u64| 19_000 * 86400 + hours * 3600 + minutes * 60;
+    assert!(evictor.in_window(at(3, 0)));
+    assert!(!evictor.in_window(at(5, 0)));
+    assert!(evictor.in_window(at(23, 30)));
+    assert!(evictor.in_window(at(0, 15)));
+    assert!(!evictor.in_window(at(12, 0)));
+}
+
+#[rocket::async_test]
+async fn manual_run_works_without_max_size() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    daemon.store_blob("hello-1.0.tar.gz", HELLO_CONTENT);
+
+    let evictor = Evictor::manual(
+        &daemon.config,
+        daemon.blob_storage.clone(),
+        daemon.repo_db.clone(),
+    );
+    let report = evictor.run_to(EvictionTarget::Size(0)).await.unwrap();
+    assert_eq!(report.removed, 1);
+    assert!(!daemon.blob_path("hello-1.0.tar.gz").exists());
+}
+
+#[rocket::async_test]
+async fn repos_over_quota_are_evicted_first() {
+    let mirror = mock_mirror().await;
+    let extra = "[[repo.repos]]\nurl = \"https://example.org/fixture\"\nmax_size = 40";
+    let daemon = TestDaemon::start(&[mirror.uri()], extra).await;
+    daemon.load_fixture_manifests().await;
+
+    daemon.store_blob("hello-1.0.tar.gz", HELLO_CONTENT);
+    daemon.store_blob("hello-data-1.0.tar.xz", HELLO_CONTENT);
+    daemon.store_blob("unrelated-1.0.tar.gz", HELLO_CONTENT);
+    accessed_ago(&daemon, "unrelated-1.0.tar.gz", 7200);
+    accessed_ago(&daemon, "hello-1.0.tar.gz", 3600);
+    accessed_ago(&daemon, "hello-data-1.0.tar.xz", 60);
+
+    // quotas alone enable scheduled runs
+    let evictor = evictor(&daemon);
+    let usage = evictor.repo_usage().await.unwrap();
+    assert_eq!(usage.len(), 1);
+    assert_eq!(usage[0].name, "fixture");
+    assert_eq!(usage[0].cached_size, 2 * HELLO_CONTENT.len() as u64);
+    assert_eq!(usage[0].max_size, Some(40));
+
+    let report = evictor.run().await.unwrap();
+    assert_eq!(report.removed, 1);
+    assert!(!daemon.blob_path("hello-1.0.tar.gz").exists());
+    assert!(daemon.blob_path("hello-data-1.0.tar.xz").exists());
+    // older but not part of a repo over its quota
+    assert!(daemon.blob_path("unrelated-1.0.tar.gz").exists());
+
+    let usage = evictor.repo_usage().await.unwrap();
+    assert_eq!(usage[0].cached_size, HELLO_CONTENT.len() as u64);
+}
@@ -0,0 +1,1575 @@
+mod common;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use blake2::Blake2b512;
+use common::{HELLO_CONTENT, TestDaemon, distfile_path, mock_mirror};
+use portcache::manifest_walker::ManifestEntry;
+use rocket::http::{Header, Status};
+use sha2::{Digest, Sha256, Sha512};
+use std::path::PathBuf;
+use std::time::Duration;
+use wiremock::matchers::{header, header_exists, method, path, query_param};
+use wiremock::{Mock, Request, Respond, ResponseTemplate};
+
+#[rocket::async_test]
+async fn layout_conf_is_served() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+
+    let response = daemon.client.get("/distfiles/layout.conf").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_string().await.unwrap(), common::LAYOUT_CONF);
+}
+
+#[rocket::async_test]
+async fn miss_is_fetched_from_mirror_then_served_from_cache() {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .expect(1)
+        .mount(&mirror)
+        .await;
+
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+
+    for _ in 0..2 {
+        let response = daemon
+            .client
+            .get(distfile_path("hello-1.0.tar.gz"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+    }
+
+    assert!(daemon.blob_path("hello-1.0.tar.gz").is_file());
+}
+
+#[rocket::async_test]
+async fn concurrent_requests_are_coalesced() {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_bytes(HELLO_CONTENT)
+                .set_delay(Duration::from_millis(500)),
+        )
+        .expect(1)
+        .mount(&mirror)
+        .await;
+
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+
+    let uri = distfile_path("hello-1.0.tar.gz");
+    let (a, b, c) = futures::join!(
+        daemon.client.get(uri.clone()).dispatch(),
+        daemon.client.get(uri.clone()).dispatch(),
+        daemon.client.get(uri.clone()).dispatch(),
+    );
+
+    for response in [a, b, c] {
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+    }
+}
+
+#[rocket::async_test]
+async fn parallel_fetches_share_a_new_hash_directory() {
+    // files landing in the same not yet existing hash directory
+    let dir = |name: &str| portcache::utils::filename_hash_dir_blake2b(name).unwrap();
+    let target = dir("same-dir-0.tar.gz");
+    let names: Vec<String> = (0..)
+        .map(|i| format!("same-dir-{}.tar.gz", i))
+        .filter(|name| dir(name) == target)
+        .take(4)
+        .collect();
+
+    let mirror = mock_mirror().await;
+    for name in &names {
+        Mock::given(method("GET"))
+            .and(path(distfile_path(name)))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_bytes(name.as_bytes())
+                    .set_delay(Duration::from_millis(200)),
+            )
+            .mount(&mirror)
+            .await;
+    }
+
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    assert!(!daemon.blob_path(&names[0]).parent().unwrap().exists());
+
+    let responses = futures::future::join_all(
+        names
+            .iter()
+            .map(|name| daemon.client.get(distfile_path(name)).dispatch()),
+    )
+    .await;
+
+    for (name, response) in names.iter().zip(responses) {
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_bytes().await.unwrap(), name.as_bytes());
+    }
+}
+
+#[rocket::async_test]
+async fn queued_fetch_gets_retry_after() {
+    let mirror = mock_mirror().await;
+    for (name, delay) in [("slow-1.0.tar.gz", 3000), ("queued-1.0.tar.gz", 0)] {
+        Mock::given(method("GET"))
+            .and(path(distfile_path(name)))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_bytes(name.as_bytes())
+                    .set_delay(Duration::from_millis(delay)),
+            )
+            .mount(&mirror)
+            .await;
+    }
+
+    let daemon = TestDaemon::start(
+        &[mirror.uri()],
+        "[fetcher.queue]\nmax_fetches = 1\nwait = \"1s\"\nretry_after = 5\n",
+    )
+    .await;
+
+    let slow = daemon
+        .client
+        .get(distfile_path("slow-1.0.tar.gz"))
+        .dispatch();
+    let queued = async {
+        // let the slow fetch take the only slot
+        rocket::tokio::time::sleep(Duration::from_millis(500)).await;
+        daemon
+            .client
+            .get(distfile_path("queued-1.0.tar.gz"))
+            .dispatch()
+            .await
+    };
+    let (slow, queued) = rocket::tokio::join!(slow, queued);
+
+    assert_eq!(slow.status(), Status::Ok);
+    assert_eq!(queued.status(), Status::ServiceUnavailable);
+    assert_eq!(queued.headers().get_one("Retry-After"), Some("5"));
+    assert_eq!(queued.headers().get_one("X-Queue-Position"), Some("1"));
+
+    // the slot is free again
+    let response = daemon
+        .client
+        .get(distfile_path("queued-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        response.into_bytes().await.unwrap(),
+        b"queued-1.0.tar.gz".to_vec()
+    );
+}
+
+#[rocket::async_test]
+async fn upstream_mtime_is_kept_and_served() {
+    let last_modified = "Wed, 21 Oct 2015 07:28:00 GMT";
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_bytes(HELLO_CONTENT)
+                .insert_header("Last-Modified", last_modified),
+        )
+        .expect(1)
+        .mount(&mirror)
+        .await;
+
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    let uri = distfile_path("hello-1.0.tar.gz");
+
+    let response = daemon.client.get(uri.clone()).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        response.headers().get_one("Last-Modified"),
+        Some(last_modified)
+    );
+
+    let mtime = std::fs::metadata(daemon.blob_path("hello-1.0.tar.gz"))
+        .unwrap()
+        .modified()
+        .unwrap();
+    assert_eq!(httpdate::fmt_http_date(mtime), last_modified);
+
+    // the client's copy is current
+    let response = daemon
+        .client
+        .get(uri.clone())
+        .header(Header::new("If-Modified-Since", last_modified))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotModified);
+    assert!(response.into_bytes().await.unwrap_or_default().is_empty());
+
+    // the client's copy is older
+    let response = daemon
+        .client
+        .get(uri)
+        .header(Header::new(
+            "If-Modified-Since",
+            "Tue, 20 Oct 2015 07:28:00 GMT",
+        ))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+}
+
+#[rocket::async_test]
+async fn checksum_mismatch_is_rejected() {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes("not the real tarball"))
+        .mount(&mirror)
+        .await;
+
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    daemon.load_fixture_manifests().await;
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+    assert!(!daemon.blob_path("hello-1.0.tar.gz").exists());
+}
+
+#[rocket::async_test]
+async fn sha256_only_manifest_entries_are_verified() {
+    let mirror = mock_mirror().await;
+    let forged = vec![b'x'; HELLO_CONTENT.len()];
+    for (name, body) in [
+        ("good-1.0.tar.gz", HELLO_CONTENT),
+        ("forged-1.0.tar.gz", &forged[..]),
+    ] {
+        Mock::given(method("GET"))
+            .and(path(distfile_path(name)))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(body))
+            .mount(&mirror)
+            .await;
+    }
+
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    for name in ["good-1.0.tar.gz", "forged-1.0.tar.gz"] {
+        let line = format!(
+            "DIST {} {} SHA256 {}",
+            name,
+            HELLO_CONTENT.len(),
+            hello_sha256()
+        );
+        let entry = ManifestEntry::parse(&PathBuf::from("Manifest"), &line)
+            .unwrap()
+            .unwrap();
+        daemon
+            .repo_db
+            .insert_manifest_entry("fixture", entry)
+            .await
+            .unwrap();
+    }
+
+    let response = daemon
+        .client
+        .get(distfile_path("good-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+
+    let response = daemon
+        .client
+        .get(distfile_path("forged-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+    assert!(!daemon.blob_path("forged-1.0.tar.gz").exists());
+}
+
+#[rocket::async_test]
+async fn file_missing_everywhere_is_not_found() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+
+    let response = daemon
+        .client
+        .get(distfile_path("missing-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[rocket::async_test]
+async fn renamed_distfiles_are_served_from_the_identical_blob() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    daemon.load_fixture_manifests().await;
+    daemon.store_blob("hello-1.0.tar.gz", HELLO_CONTENT);
+
+    let manifest =
+        std::fs::read_to_string(common::fixture_repo().join("app-misc/hello/Manifest")).unwrap();
+    let line = manifest
+        .lines()
+        .find(|line| line.starts_with("DIST hello-1.0.tar.gz "))
+        .unwrap()
+        .replace("hello-1.0.tar.gz", "hello-renamed-1.0.tar.gz");
+    let entry = ManifestEntry::parse(&PathBuf::from("Manifest"), &line)
+        .unwrap()
+        .unwrap();
+    daemon
+        .repo_db
+        .insert_manifest_entry("fixture", entry)
+        .await
+        .unwrap();
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-renamed-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+    assert!(!daemon.blob_path("hello-renamed-1.0.tar.gz").exists());
+    assert_eq!(
+        daemon
+            .repo_db
+            .get_alias("hello-renamed-1.0.tar.gz")
+            .await
+            .unwrap()
+            .as_deref(),
+        Some("hello-1.0.tar.gz")
+    );
+
+    // gone with the blob it pointed to, the mirror doesn't have the new name
+    daemon
+        .blob_storage
+        .remove("hello-1.0.tar.gz")
+        .await
+        .unwrap();
+    assert_eq!(
+        daemon
+            .repo_db
+            .get_alias("hello-renamed-1.0.tar.gz")
+            .await
+            .unwrap(),
+        None
+    );
+    let response = daemon
+        .client
+        .get(distfile_path("hello-renamed-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[rocket::async_test]
+async fn bad_digest_is_rejected() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+
+    let response = daemon
+        .client
+        .get("/distfiles/zz/hello-1.0.tar.gz")
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::BadRequest);
+}
+
+#[rocket::async_test]
+async fn transient_mirror_errors_are_retried() {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(503))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&mirror)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .expect(1)
+        .mount(&mirror)
+        .await;
+
+    let daemon = TestDaemon::start(
+        &[mirror.uri()],
+        "[fetcher.retry]\ninitial_backoff = 10\nmax_backoff = 20",
+    )
+    .await;
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn not_found_is_not_retried() {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("missing-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(404))
+        .expect(1)
+        .mount(&mirror)
+        .await;
+
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+
+    let response = daemon
+        .client
+        .get(distfile_path("missing-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[rocket::async_test]
+async fn not_found_on_one_mirror_tries_the_next() {
+    let empty = mock_mirror().await;
+    let full = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .mount(&full)
+        .await;
+
+    let daemon = TestDaemon::start(&[empty.uri(), full.uri()], "").await;
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn not_found_on_all_mirrors_is_remembered() {
+    let mut mirrors = Vec::new();
+    for _ in 0..2 {
+        let mirror = mock_mirror().await;
+        Mock::given(method("GET"))
+            .and(path(distfile_path("missing-1.0.tar.gz")))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(1)
+            .mount(&mirror)
+            .await;
+        mirrors.push(mirror);
+    }
+
+    let urls: Vec<String> = mirrors.iter().map(|m| m.uri()).collect();
+    let daemon = TestDaemon::start(&urls, "").await;
+
+    for _ in 0..2 {
+        let response = daemon
+            .client
+            .get(distfile_path("missing-1.0.tar.gz"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+}
+
+#[rocket::async_test]
+async fn mirror_credentials_from_url_are_sent() {
+    let mirror = mock_mirror().await;
+    let basic = STANDARD.encode("builder:s@cret");
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .and(header("Authorization", format!("Basic {}", basic)))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .expect(1)
+        .mount(&mirror)
+        .await;
+
+    let url = mirror.uri().replace("http://", "http://builder:s%40cret@");
+    let daemon = TestDaemon::start(&[url], "").await;
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn mirror_password_file_is_used() {
+    let mirror = mock_mirror().await;
+    let basic = STANDARD.encode("builder:hunter2");
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .and(header("Authorization", format!("Basic {}", basic)))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .expect(1)
+        .mount(&mirror)
+        .await;
+
+    let secrets = tempfile::TempDir::new().unwrap();
+    let password_file = secrets.path().join("mirror.pass");
+    std::fs::write(&password_file, "hunter2\n").unwrap();
+    let extra = format!(
+        "[fetcher]\nmirrors = [{{ url = \"{}\", username = \"builder\", password_file = \"{}\" }}]",
+        mirror.uri(),
+        password_file.to_string_lossy()
+    );
+    let daemon = TestDaemon::start(&[], &extra).await;
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn repo_fetch_order_overrides_chain() {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .expect(0)
+        .mount(&mirror)
+        .await;
+
+    let proxy = wiremock::MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/hello-1.0.tar.gz"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .expect(1)
+        .mount(&proxy)
+        .await;
+
+    let extra = format!(
+        "[fetcher]\nproxies = [\"{}\"]\n\n\
+         [repo]\nrepos = [{{ url = \"https://example.invalid/fixture\", fetch_order = [\"proxy\"] }}]",
+        proxy.uri()
+    );
+    let daemon = TestDaemon::start(&[mirror.uri()], &extra).await;
+    daemon.load_fixture_manifests().await;
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn src_uri_follows_redirects_with_cookies_and_headers() {
+    let upstream = wiremock::MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/download"))
+        .respond_with(
+            ResponseTemplate::new(302)
+                .insert_header("Location", "/landing")
+                .insert_header("Set-Cookie", "session=abc; Path=/"),
+        )
+        .mount(&upstream)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/landing"))
+        .and(header("Cookie", "session=abc"))
+        .respond_with(
+            ResponseTemplate::new(303).insert_header("Location", "/files/hello-1.0.tar.gz"),
+        )
+        .mount(&upstream)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/files/hello-1.0.tar.gz"))
+        .and(header("Cookie", "session=abc"))
+        .and(header("Referer", "https://example.org/"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .expect(1)
+        .mount(&upstream)
+        .await;
+
+    let extra = "[fetcher]\nchain = [\"src_uri\"]\n\n\
+         [fetcher.src_uri.headers.\"127.0.0.1\"]\nReferer = \"https://example.org/\"\n";
+    let daemon = TestDaemon::start(&[], extra).await;
+    daemon.load_fixture_manifests().await;
+    daemon
+        .repo_db
+        .insert_src_uri(
+            "hello-1.0.tar.gz".to_string(),
+            format!("{}/download", upstream.uri()),
+        )
+        .await
+        .unwrap();
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+}
+
+#[rocket::async_test]
+async fn src_uri_redirect_limit_is_enforced() {
+    let upstream = wiremock::MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/loop"))
+        .respond_with(ResponseTemplate::new(302).insert_header("Location", "/loop"))
+        .expect(3)
+        .mount(&upstream)
+        .await;
+
+    let extra = "[fetcher]\nchain = [\"src_uri\"]\n\n[fetcher.src_uri]\nmax_redirects = 2\n";
+    let daemon = TestDaemon::start(&[], extra).await;
+    daemon.load_fixture_manifests().await;
+    daemon
+        .repo_db
+        .insert_src_uri(
+            "hello-1.0.tar.gz".to_string(),
+            format!("{}/loop", upstream.uri()),
+        )
+        .await
+        .unwrap();
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[rocket::async_test]
+async fn checksums_are_sent_as_headers() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    daemon.load_fixture_manifests().await;
+    daemon.store_blob("hello-1.0.tar.gz", HELLO_CONTENT);
+
+    let entry = daemon
+        .repo_db
+        .get_manifest_entry("hello-1.0.tar.gz")
+        .await
+        .unwrap()
+        .unwrap();
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        response.headers().get_one("X-Checksum-Blake2b"),
+        entry.blake2b.as_deref()
+    );
+    assert_eq!(
+        response.headers().get_one("X-Checksum-Sha512"),
+        entry.sha512.as_deref()
+    );
+    assert!(entry.blake2b.is_some() && entry.sha512.is_some());
+
+    // files without Manifest entry get no checksums
+    daemon.store_blob("unknown-1.0.tar.gz", b"unknown");
+    let response = daemon
+        .client
+        .get(distfile_path("unknown-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert!(response.headers().get_one("X-Checksum-Blake2b").is_none());
+}
+
+#[rocket::async_test]
+async fn small_files_are_served_from_memory() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(
+        &[mirror.uri()],
+        "[storage]\nmemory_cache = \"1MiB\"\nmemory_cache_max_file = 1024",
+    )
+    .await;
+    daemon.store_blob("hello-1.0.tar.gz", HELLO_CONTENT);
+    daemon.store_blob("big-1.0.tar.gz", &[0; 4096]);
+
+    for _ in 0..2 {
+        let response = daemon
+            .client
+            .get(distfile_path("hello-1.0.tar.gz"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+    }
+    let response = daemon
+        .client
+        .get(distfile_path("big-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.into_bytes().await.unwrap().len(), 4096);
+
+    let metrics = daemon
+        .client
+        .get("/metrics")
+        .dispatch()
+        .await
+        .into_string()
+        .await
+        .unwrap();
+    assert!(
+        metrics.contains("portcache_memory_cache_hits_total 1\n"),
+        "{}",
+        metrics
+    );
+    assert!(
+        metrics.contains("portcache_memory_cache_misses_total 1\n"),
+        "{}",
+        metrics
+    );
+
+    // a replaced blob isn't served from memory
+    daemon.store_blob("hello-1.0.tar.gz", b"replaced");
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.into_string().await.unwrap(), "replaced");
+}
+
+#[cfg(feature = "uring")]
+#[rocket::async_test]
+async fn files_are_served_via_io_uring() {
+    let content = "io_uring ".repeat(100_000);
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(
+        &[mirror.uri()],
+        "[server]\nio = \"uring\"\n\n[storage]\ncompress = [\"*.txt\"]",
+    )
+    .await;
+    daemon.store_blob("hello-1.0.tar.gz", HELLO_CONTENT);
+    Mock::given(method("GET"))
+        .and(path(distfile_path("notes-1.0.txt")))
+        .respond_with(ResponseTemplate::new(200).set_body_string(content.clone()))
+        .mount(&mirror)
+        .await;
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+
+    // spans several chunks and gets decompressed on the way
+    let response = daemon
+        .client
+        .get(distfile_path("notes-1.0.txt"))
+        .dispatch()
+        .await;
+    assert_eq!(response.into_string().await.unwrap(), content);
+}
+
+#[rocket::async_test]
+async fn distfiles_are_cacheable_by_cdns() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    daemon.store_blob("hello-1.0.tar.gz", HELLO_CONTENT);
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let cache_control = "public, max-age=31536000, immutable";
+    assert_eq!(
+        response.headers().get_one("Cache-Control"),
+        Some(cache_control)
+    );
+    assert_eq!(
+        response.headers().get_one("Content-Type"),
+        Some("application/gzip")
+    );
+    assert_eq!(
+        response.headers().get_one("Content-Disposition"),
+        Some("attachment; filename=\"hello-1.0.tar.gz\"")
+    );
+    assert!(response.headers().get_one("Content-Encoding").is_none());
+    let last_modified = response
+        .headers()
+        .get_one("Last-Modified")
+        .unwrap()
+        .to_string();
+
+    // revalidations keep it cacheable
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .header(Header::new("If-Modified-Since", last_modified))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotModified);
+    assert_eq!(
+        response.headers().get_one("Cache-Control"),
+        Some(cache_control)
+    );
+}
+
+#[rocket::async_test]
+async fn matching_distfiles_are_stored_compressed() {
+    let content = "--- a/hello.c\n+++ b/hello.c\n@@ -1 +1 @@\n-old\n+new\n".repeat(500);
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0-fix.patch")))
+        .respond_with(ResponseTemplate::new(200).set_body_string(content.clone()))
+        .expect(1)
+        .mount(&mirror)
+        .await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "[storage]\ncompress = [\"*.patch\"]").await;
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0-fix.patch"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("Vary"), Some("Accept-Encoding"));
+    assert!(response.headers().get_one("Content-Encoding").is_none());
+    assert_eq!(response.into_string().await.unwrap(), content);
+
+    let stored = std::fs::read(daemon.blob_path("hello-1.0-fix.patch")).unwrap();
+    assert!(stored.len() < content.len() / 10);
+
+    // clients accepting zstd get the stored stream
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0-fix.patch"))
+        .header(Header::new("Accept-Encoding", "gzip, zstd"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("Content-Encoding"), Some("zstd"));
+    let body = response.into_bytes().await.unwrap();
+    assert_eq!(zstd::decode_all(&body[..]).unwrap(), content.as_bytes());
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0-fix.patch"))
+        .header(Header::new("Accept-Encoding", "zstd;q=0"))
+        .dispatch()
+        .await;
+    assert!(response.headers().get_one("Content-Encoding").is_none());
+    assert_eq!(response.into_string().await.unwrap(), content);
+}
+
+#[rocket::async_test]
+async fn concurrent_misses_share_one_fetch_while_low_on_space() {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_bytes(HELLO_CONTENT)
+                .set_delay(Duration::from_millis(500)),
+        )
+        .expect(1)
+        .mount(&mirror)
+        .await;
+
+    let daemon = TestDaemon::start(&[mirror.uri()], "[storage]\nmin_free = \"1000TB\"").await;
+
+    let uri = distfile_path("hello-1.0.tar.gz");
+    let (a, b, c) = futures::join!(
+        daemon.client.get(uri.clone()).dispatch(),
+        daemon.client.get(uri.clone()).dispatch(),
+        daemon.client.get(uri.clone()).dispatch(),
+    );
+
+    for response in [a, b, c] {
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+    }
+    assert!(!daemon.blob_path("hello-1.0.tar.gz").exists());
+
+    let transient = daemon.config.storage.location.join("transient");
+    assert_eq!(std::fs::read_dir(transient).unwrap().count(), 0);
+}
+
+#[rocket::async_test]
+async fn misses_are_passed_through_while_low_on_space() {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .expect(2)
+        .mount(&mirror)
+        .await;
+
+    let daemon = TestDaemon::start(&[mirror.uri()], "[storage]\nmin_free = \"1000TB\"").await;
+
+    // cached before the disk filled up
+    let cached = daemon.blob_path("cached-1.0.tar.gz");
+    std::fs::create_dir_all(cached.parent().unwrap()).unwrap();
+    std::fs::write(&cached, HELLO_CONTENT).unwrap();
+    let response = daemon
+        .client
+        .get(distfile_path("cached-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+
+    // every miss gets fetched again instead of stored
+    for _ in 0..2 {
+        let response = daemon
+            .client
+            .get(distfile_path("hello-1.0.tar.gz"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+    }
+    assert!(!daemon.blob_path("hello-1.0.tar.gz").exists());
+
+    let transient = daemon.config.storage.location.join("transient");
+    assert_eq!(std::fs::read_dir(transient).unwrap().count(), 0);
+
+    let response = daemon.client.get("/status").dispatch().await;
+    let status: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!(status["read_only"], true);
+}
+
+#[rocket::async_test]
+async fn cached_blobs_are_linked_into_the_local_distdir() {
+    use std::os::unix::fs::MetadataExt;
+
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .mount(&mirror)
+        .await;
+
+    let distdir = tempfile::TempDir::new().unwrap();
+    let daemon = TestDaemon::start(
+        &[mirror.uri()],
+        &format!(
+            "[storage]\nlocal_distdir = \"{}\"",
+            distdir.path().to_string_lossy()
+        ),
+    )
+    .await;
+
+    // cached before, linked on startup
+    daemon.store_blob("old-1.0.tar.gz", HELLO_CONTENT);
+    // downloaded by Portage itself
+    std::fs::write(distdir.path().join("own-1.0.tar.gz"), HELLO_CONTENT).unwrap();
+    daemon.store_blob("own-1.0.tar.gz", HELLO_CONTENT);
+    assert_eq!(daemon.blob_storage.link_local_distdir().await.unwrap(), 1);
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let inode = |path: PathBuf| std::fs::metadata(path).unwrap().ino();
+    for file in ["old-1.0.tar.gz", "hello-1.0.tar.gz"] {
+        assert_eq!(
+            inode(distdir.path().join(file)),
+            inode(daemon.blob_path(file))
+        );
+    }
+
+    // evicted blobs take their links along, Portage's own copies stay
+    for file in ["hello-1.0.tar.gz", "own-1.0.tar.gz"] {
+        assert!(daemon.blob_storage.remove(file).await.unwrap());
+    }
+    assert!(!distdir.path().join("hello-1.0.tar.gz").exists());
+    assert!(distdir.path().join("own-1.0.tar.gz").exists());
+}
+
+#[rocket::async_test]
+async fn failing_src_uri_hosts_are_skipped() {
+    let broken = wiremock::MockServer::start().await;
+    for file in ["/hello-1.0.tar.gz", "/hello-data-1.0.tar.xz"] {
+        Mock::given(method("GET"))
+            .and(path(file))
+            .respond_with(ResponseTemplate::new(503))
+            .expect(1)
+            .mount(&broken)
+            .await;
+    }
+    let working = wiremock::MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/hello-1.0.tar.gz"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .expect(1)
+        .mount(&working)
+        .await;
+
+    let extra = "[fetcher]\nchain = [\"src_uri\"]\n\n[fetcher.src_uri]\nbreaker_threshold = 2\n";
+    let daemon = TestDaemon::start(&[], extra).await;
+    daemon.load_fixture_manifests().await;
+    for file in ["hello-1.0.tar.gz", "hello-data-1.0.tar.xz"] {
+        daemon
+            .repo_db
+            .insert_src_uri(file.to_string(), format!("{}/{}", broken.uri(), file))
+            .await
+            .unwrap();
+    }
+
+    // the second failure in a row opens the circuit of the host
+    for file in ["hello-1.0.tar.gz", "hello-data-1.0.tar.xz"] {
+        let response = daemon.client.get(distfile_path(file)).dispatch().await;
+        assert_ne!(response.status(), Status::Ok);
+    }
+
+    // other hosts are still tried
+    daemon
+        .repo_db
+        .insert_src_uri(
+            "hello-1.0.tar.gz".to_string(),
+            format!("{}/hello-1.0.tar.gz", working.uri()),
+        )
+        .await
+        .unwrap();
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+}
+
+/// mirror answering range requests with the requested part of HELLO_CONTENT
+/// and requests without range with all of it
+struct Ranges {
+    /// ranges starting at or after this offset fail with a 500
+    fail_from: Option<usize>,
+}
+
+impl Respond for Ranges {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        let Some(range) = request.headers.get("range") else {
+            return ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT);
+        };
+        let (start, end) = range
+            .to_str()
+            .unwrap()
+            .strip_prefix("bytes=")
+            .and_then(|range| range.split_once('-'))
+            .unwrap();
+        let (start, end): (usize, usize) = (start.parse().unwrap(), end.parse().unwrap());
+        if self.fail_from.is_some_and(|from| start >= from) {
+            return ResponseTemplate::new(500);
+        }
+        ResponseTemplate::new(206)
+            .insert_header(
+                "Content-Range",
+                format!("bytes {}-{}/{}", start, end, HELLO_CONTENT.len()),
+            )
+            .set_body_bytes(&HELLO_CONTENT[start..=end])
+    }
+}
+
+/// config splitting hello-1.0.tar.gz into 4 chunks
+const CHUNKED: &str = "[fetcher.chunked]\nmin_size = 16\nchunk_size = 8\n";
+
+#[rocket::async_test]
+async fn chunks_are_fetched_from_all_mirrors_and_reassembled() {
+    let mut mirrors = Vec::new();
+    for _ in 0..2 {
+        let mirror = mock_mirror().await;
+        Mock::given(method("GET"))
+            .and(path(distfile_path("hello-1.0.tar.gz")))
+            .and(header_exists("range"))
+            .respond_with(Ranges { fail_from: None })
+            .expect(1..)
+            .mount(&mirror)
+            .await;
+        mirrors.push(mirror);
+    }
+    let uris: Vec<String> = mirrors.iter().map(|mirror| mirror.uri()).collect();
+    let daemon = TestDaemon::start(&uris, CHUNKED).await;
+    daemon.load_fixture_manifests().await;
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+    assert_eq!(
+        std::fs::read(daemon.blob_path("hello-1.0.tar.gz")).unwrap(),
+        HELLO_CONTENT
+    );
+}
+
+#[rocket::async_test]
+async fn mirrors_ignoring_ranges_fall_back_to_a_whole_download() {
+    let mut mirrors = Vec::new();
+    for _ in 0..2 {
+        let mirror = mock_mirror().await;
+        Mock::given(method("GET"))
+            .and(path(distfile_path("hello-1.0.tar.gz")))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+            .mount(&mirror)
+            .await;
+        mirrors.push(mirror);
+    }
+    let uris: Vec<String> = mirrors.iter().map(|mirror| mirror.uri()).collect();
+    let daemon = TestDaemon::start(&uris, CHUNKED).await;
+    daemon.load_fixture_manifests().await;
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+}
+
+#[rocket::async_test]
+async fn files_below_min_size_are_not_chunked() {
+    let mut mirrors = Vec::new();
+    for _ in 0..2 {
+        let mirror = mock_mirror().await;
+        Mock::given(method("GET"))
+            .and(path(distfile_path("hello-1.0.tar.gz")))
+            .and(header_exists("range"))
+            .respond_with(Ranges { fail_from: None })
+            .expect(0)
+            .mount(&mirror)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(distfile_path("hello-1.0.tar.gz")))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+            .mount(&mirror)
+            .await;
+        mirrors.push(mirror);
+    }
+    let uris: Vec<String> = mirrors.iter().map(|mirror| mirror.uri()).collect();
+    let extra = "[fetcher.chunked]\nmin_size = 31\nchunk_size = 8\n";
+    let daemon = TestDaemon::start(&uris, extra).await;
+    daemon.load_fixture_manifests().await;
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+}
+
+#[rocket::async_test]
+async fn failed_chunks_leave_no_partial_blob() {
+    let mut mirrors = Vec::new();
+    for _ in 0..2 {
+        let mirror = mock_mirror().await;
+        Mock::given(method("GET"))
+            .and(path(distfile_path("hello-1.0.tar.gz")))
+            .and(header_exists("range"))
+            .respond_with(Ranges {
+                fail_from: Some(16),
+            })
+            .expect(1..)
+            .mount(&mirror)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(distfile_path("hello-1.0.tar.gz")))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&mirror)
+            .await;
+        mirrors.push(mirror);
+    }
+    let uris: Vec<String> = mirrors.iter().map(|mirror| mirror.uri()).collect();
+    let daemon = TestDaemon::start(&uris, CHUNKED).await;
+    daemon.load_fixture_manifests().await;
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+
+    let blob = daemon.blob_path("hello-1.0.tar.gz");
+    assert!(!blob.exists());
+    assert!(!portcache::fetcher::part_location(&blob).exists());
+}
+
+/// SHA-256 of HELLO_CONTENT as declared in metalinks
+fn hello_sha256() -> String {
+    hex::encode(Sha256::digest(HELLO_CONTENT))
+}
+
+/// daemon fetching only via metalinks
+/// with small ranges so hello-1.0.tar.gz gets split
+async fn metalink_daemon() -> TestDaemon {
+    let extra = "[fetcher]\nchain = [\"metalink\"]\n\n[fetcher.metalink]\nchunk_size = 8\n";
+    let daemon = TestDaemon::start(&[], extra).await;
+    daemon.load_fixture_manifests().await;
+    daemon
+}
+
+/// start a server publishing metalink for hello-1.0.tar.gz at suffix
+/// and register it as the distfile's SRC_URI
+///
+/// @param daemon    daemon to register the SRC_URI with
+/// @param suffix    suffix the metalink gets published under
+/// @param metalink  the metalink document
+async fn publish_metalink(
+    daemon: &TestDaemon,
+    suffix: &str,
+    metalink: String,
+) -> wiremock::MockServer {
+    let upstream = wiremock::MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(format!("/hello-1.0.tar.gz{}", suffix)))
+        .respond_with(ResponseTemplate::new(200).set_body_string(metalink))
+        .mount(&upstream)
+        .await;
+    daemon
+        .repo_db
+        .insert_src_uri(
+            "hello-1.0.tar.gz".to_string(),
+            format!("{}/hello-1.0.tar.gz", upstream.uri()),
+        )
+        .await
+        .unwrap();
+    upstream
+}
+
+/// start a source serving hello-1.0.tar.gz in ranges or as a whole
+///
+/// @param expected  how many requests the source expects
+async fn metalink_source(expected: impl Into<wiremock::Times>) -> wiremock::MockServer {
+    let source = wiremock::MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/hello-1.0.tar.gz"))
+        .respond_with(Ranges { fail_from: None })
+        .expect(expected)
+        .mount(&source)
+        .await;
+    source
+}
+
+/// a Metalink 4 document for hello-1.0.tar.gz
+///
+/// @param size    declared size if any
+/// @param sha256  declared SHA-256
+/// @param urls    sources with their priority
+fn metalink4(size: Option<usize>, sha256: &str, urls: &[(u32, String)]) -> String {
+    let size = size
+        .map(|size| format!("<size>{}</size>\n", size))
+        .unwrap_or_default();
+    let urls: String = urls
+        .iter()
+        .map(|(priority, url)| format!("<url priority=\"{}\">{}</url>\n", priority, url))
+        .collect();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <metalink xmlns=\"urn:ietf:params:xml:ns:metalink\">\n\
+         <file name=\"hello-1.0.tar.gz\">\n{}<hash type=\"sha-256\">{}</hash>\n{}</file>\n\
+         </metalink>\n",
+        size, sha256, urls
+    )
+}
+
+#[rocket::async_test]
+async fn metalink4_sources_are_fetched_in_ranges() {
+    let sources = [metalink_source(1..).await, metalink_source(1..).await];
+    let daemon = metalink_daemon().await;
+    let urls: Vec<(u32, String)> = sources
+        .iter()
+        .map(|source| (1, format!("{}/hello-1.0.tar.gz", source.uri())))
+        .collect();
+    let metalink = metalink4(Some(HELLO_CONTENT.len()), &hello_sha256(), &urls);
+    let _upstream = publish_metalink(&daemon, ".meta4", metalink).await;
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+}
+
+#[rocket::async_test]
+async fn metalink4_without_size_uses_the_preferred_source() {
+    let preferred = metalink_source(1).await;
+    let other = metalink_source(0).await;
+    let daemon = metalink_daemon().await;
+    let metalink = metalink4(
+        None,
+        &hello_sha256(),
+        &[
+            (2, format!("{}/hello-1.0.tar.gz", other.uri())),
+            (1, format!("{}/hello-1.0.tar.gz", preferred.uri())),
+        ],
+    );
+    let _upstream = publish_metalink(&daemon, ".meta4", metalink).await;
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+}
+
+#[rocket::async_test]
+async fn metalink3_preference_is_respected() {
+    let preferred = metalink_source(1).await;
+    let other = metalink_source(0).await;
+    let daemon = metalink_daemon().await;
+    let metalink = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <metalink version=\"3.0\" xmlns=\"http://www.metalinker.org/\">\n\
+         <files>\n<file name=\"hello-1.0.tar.gz\">\n\
+         <verification><hash type=\"sha256\">{}</hash></verification>\n\
+         <resources>\n\
+         <url type=\"http\" preference=\"10\">{}/hello-1.0.tar.gz</url>\n\
+         <url type=\"http\" preference=\"90\">{}/hello-1.0.tar.gz</url>\n\
+         </resources>\n\
+         </file>\n</files>\n</metalink>\n",
+        hello_sha256(),
+        other.uri(),
+        preferred.uri()
+    );
+    let _upstream = publish_metalink(&daemon, ".metalink", metalink).await;
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+}
+
+#[rocket::async_test]
+async fn metalink_hash_mismatch_is_rejected() {
+    let sources = [metalink_source(1..).await, metalink_source(1..).await];
+    let daemon = metalink_daemon().await;
+    let urls: Vec<(u32, String)> = sources
+        .iter()
+        .map(|source| (1, format!("{}/hello-1.0.tar.gz", source.uri())))
+        .collect();
+    let metalink = metalink4(Some(HELLO_CONTENT.len()), &"0".repeat(64), &urls);
+    let _upstream = publish_metalink(&daemon, ".meta4", metalink).await;
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+
+    let blob = daemon.blob_path("hello-1.0.tar.gz");
+    assert!(!blob.exists());
+    assert!(!portcache::fetcher::part_location(&blob).exists());
+}
+
+/// config fetching via IPFS from a gateway at gateway
+///
+/// @param chain    fetcher.chain to use
+/// @param gateway  url of the gateway which also serves the RPC API
+fn ipfs_config(chain: &str, gateway: &str) -> String {
+    format!(
+        "[fetcher]\nchain = {}\n\n\
+         [fetcher.ipfs]\ngateway = \"{}/\"\nmirrors = [\"/ipns/distfiles.example.org/\"]\napi = \"{}\"\n",
+        chain, gateway, gateway
+    )
+}
+
+/// IPFS path of HELLO_CONTENT by its Manifest hash
+///
+/// @param hash  lowercase name of the Manifest hash
+fn hello_ipfs_path(hash: &str) -> String {
+    let checksum = match hash {
+        "blake2b" => hex::encode(Blake2b512::digest(HELLO_CONTENT)),
+        "sha512" => hex::encode(Sha512::digest(HELLO_CONTENT)),
+        _ => hello_sha256(),
+    };
+    format!("/ipns/distfiles.example.org/{}/{}", hash, checksum)
+}
+
+#[rocket::async_test]
+async fn ipfs_mirrors_are_read_through_the_gateway_and_pinned() {
+    let gateway = wiremock::MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(hello_ipfs_path("blake2b")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .expect(1)
+        .mount(&gateway)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/api/v0/pin/add"))
+        .and(query_param("arg", hello_ipfs_path("blake2b")))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&gateway)
+        .await;
+
+    let daemon = TestDaemon::start(&[], &ipfs_config("[\"ipfs\"]", &gateway.uri())).await;
+    daemon.load_fixture_manifests().await;
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+}
+
+#[rocket::async_test]
+async fn ipfs_blobs_are_only_pinned_once_verified() {
+    let gateway = wiremock::MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(hello_ipfs_path("blake2b")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(b"forged content of 30 bytes...."))
+        .expect(1)
+        .mount(&gateway)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(hello_ipfs_path("sha512")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .expect(1)
+        .mount(&gateway)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/api/v0/pin/add"))
+        .and(query_param("arg", hello_ipfs_path("blake2b")))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&gateway)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/api/v0/pin/add"))
+        .and(query_param("arg", hello_ipfs_path("sha512")))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&gateway)
+        .await;
+
+    let daemon = TestDaemon::start(&[], &ipfs_config("[\"ipfs\"]", &gateway.uri())).await;
+    daemon.load_fixture_manifests().await;
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+}
+
+#[rocket::async_test]
+async fn ipfs_needs_a_manifest_entry() {
+    let gateway = wiremock::MockServer::start().await;
+    let daemon = TestDaemon::start(&[], &ipfs_config("[\"ipfs\"]", &gateway.uri())).await;
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+    assert!(gateway.received_requests().await.unwrap().is_empty());
+}
+
+#[rocket::async_test]
+async fn blobs_of_other_fetchers_are_added_to_ipfs_with_pin_stored() {
+    let gateway = wiremock::MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/api/v0/add"))
+        .and(query_param("pin", "true"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&gateway)
+        .await;
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .expect(1)
+        .mount(&mirror)
+        .await;
+
+    let extra = ipfs_config("[\"ipfs\", \"mirror\"]", &gateway.uri()) + "pin_stored = true\n";
+    let daemon = TestDaemon::start(&[mirror.uri()], &extra).await;
+    daemon.load_fixture_manifests().await;
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+
+    let requests = gateway.received_requests().await.unwrap();
+    let add = requests
+        .iter()
+        .find(|request| request.url.path() == "/api/v0/add")
+        .unwrap();
+    let body = &add.body;
+    assert!(
+        body.windows(HELLO_CONTENT.len())
+            .any(|window| window == HELLO_CONTENT)
+    );
+}
+
+#[rocket::async_test]
+async fn ipfs_misses_fall_through_to_the_next_fetcher() {
+    let gateway = wiremock::MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/api/v0/pin/add"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&gateway)
+        .await;
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .expect(1)
+        .mount(&mirror)
+        .await;
+
+    let extra = ipfs_config("[\"ipfs\", \"mirror\"]", &gateway.uri());
+    let daemon = TestDaemon::start(&[mirror.uri()], &extra).await;
+    daemon.load_fixture_manifests().await;
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+    // one lookup per Manifest hash
+    assert_eq!(gateway.received_requests().await.unwrap().len(), 2);
+}
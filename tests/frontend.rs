@@ -0,0 +1,219 @@
+mod common;
+
+use common::{HELLO_CONTENT, TestDaemon, distfile_path, mock_mirror};
+use portcache::request_id::{self, RequestId};
+use rocket::http::{Header, Status};
+use std::time::Duration;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+#[rocket::async_test]
+async fn flat_requests_are_rejected_by_default() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+
+    let response = daemon
+        .client
+        .get("/distfiles/hello-1.0.tar.gz")
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[rocket::async_test]
+async fn flat_requests_are_redirected() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "[server]\nflat_layout = \"redirect\"").await;
+
+    let response = daemon
+        .client
+        .get("/distfiles/hello-1.0.tar.gz")
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::PermanentRedirect);
+    assert_eq!(
+        response.headers().get_one("Location"),
+        Some(distfile_path("hello-1.0.tar.gz").as_str())
+    );
+
+    // clients lacking filename-hash support may fall back to flat paths
+    let response = daemon.client.get("/distfiles/layout.conf").dispatch().await;
+    assert_eq!(
+        response.into_string().await.unwrap(),
+        format!("{}1=flat\n", common::LAYOUT_CONF)
+    );
+}
+
+#[rocket::async_test]
+async fn flat_requests_are_served() {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .expect(1)
+        .mount(&mirror)
+        .await;
+
+    let daemon = TestDaemon::start(&[mirror.uri()], "[server]\nflat_layout = \"serve\"").await;
+
+    let response = daemon
+        .client
+        .get("/distfiles/hello-1.0.tar.gz")
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+    assert!(daemon.blob_path("hello-1.0.tar.gz").is_file());
+}
+
+#[rocket::async_test]
+async fn percent_encoded_names_are_decoded() {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello+1.0~rc1.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .expect(1)
+        .mount(&mirror)
+        .await;
+
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+
+    let digest = portcache::utils::filename_hash_dir_blake2b("hello+1.0~rc1.tar.gz").unwrap();
+    let response = daemon
+        .client
+        .get(format!("/distfiles/{}/hello%2B1.0%7Erc1.tar.gz", digest))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert!(daemon.blob_path("hello+1.0~rc1.tar.gz").is_file());
+}
+
+#[rocket::async_test]
+async fn invalid_names_are_rejected() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+
+    for name in ["%2E%2E", "foo%2Fbar", "foo%0Abar", "foo%5Cbar", "%FF"] {
+        let response = daemon
+            .client
+            .get(format!("/distfiles/00/{}", name))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest, "{}", name);
+    }
+}
+
+#[rocket::async_test]
+async fn equivalent_names_share_one_fetch() {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello+1.0.tar.gz")))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_bytes(HELLO_CONTENT)
+                .set_delay(Duration::from_millis(300)),
+        )
+        .expect(1)
+        .mount(&mirror)
+        .await;
+
+    let daemon = TestDaemon::start(&[mirror.uri()], "[server]\nflat_layout = \"serve\"").await;
+
+    let digest = portcache::utils::filename_hash_dir_blake2b("hello+1.0.tar.gz").unwrap();
+    let encoded = format!("/distfiles/{}/hello%2B1.0.tar.gz", digest);
+    let lowercase = format!("/distfiles/{}/hello%2b1.0.tar.gz", digest);
+    let (a, b, c, d) = futures::join!(
+        daemon.client.get(encoded).dispatch(),
+        daemon.client.get(lowercase).dispatch(),
+        daemon
+            .client
+            .get(distfile_path("hello+1.0.tar.gz"))
+            .dispatch(),
+        daemon
+            .client
+            .get("/distfiles/hello%2B1.0.tar.gz")
+            .dispatch(),
+    );
+    for response in [a, b, c, d] {
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+    }
+    assert!(daemon.blob_path("hello+1.0.tar.gz").is_file());
+}
+
+#[rocket::async_test]
+async fn reserved_characters_are_encoded_upstream() {
+    let mirror = mock_mirror().await;
+    let digest = portcache::utils::filename_hash_dir_blake2b("hello#1%.tar.gz").unwrap();
+    Mock::given(method("GET"))
+        .and(path(format!("/distfiles/{}/hello%231%25.tar.gz", digest)))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .expect(1)
+        .mount(&mirror)
+        .await;
+
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    let response = daemon
+        .client
+        .get(format!("/distfiles/{}/hello%231%25.tar.gz", digest))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert!(daemon.blob_path("hello#1%.tar.gz").is_file());
+
+    assert_eq!(
+        portcache::distfile_name::encode("a+b~c d#e%f?g"),
+        "a+b~c%20d%23e%25f%3Fg"
+    );
+}
+
+#[rocket::async_test]
+async fn responses_carry_request_ids() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    daemon.store_blob("hello-1.0.tar.gz", HELLO_CONTENT);
+
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        let response = daemon
+            .client
+            .get(distfile_path("hello-1.0.tar.gz"))
+            .dispatch()
+            .await;
+        let id = response.headers().get_one("X-Request-Id").unwrap();
+        assert_eq!(id.len(), 16);
+        assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+        ids.push(id.to_string());
+    }
+    assert_ne!(ids[0], ids[1]);
+
+    // ids of a reverse proxy are kept unless they're garbage
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .header(Header::new("X-Request-Id", "lb-42.a_b"))
+        .dispatch()
+        .await;
+    assert_eq!(
+        response.headers().get_one("X-Request-Id"),
+        Some("lb-42.a_b")
+    );
+    let response = daemon
+        .client
+        .get("/distfiles/layout.conf")
+        .header(Header::new("X-Request-Id", "two words"))
+        .dispatch()
+        .await;
+    assert_ne!(
+        response.headers().get_one("X-Request-Id"),
+        Some("two words")
+    );
+}
+
+#[rocket::async_test]
+async fn log_lines_are_prefixed_within_a_request() {
+    assert_eq!(request_id::prefix(), "");
+    let id = RequestId("abc123".to_string());
+    let prefix = id.scope(async { request_id::prefix() }).await;
+    assert_eq!(prefix, "[abc123] ");
+}
@@ -0,0 +1,61 @@
+mod common;
+
+use common::{HELLO_CONTENT, TestDaemon, distfile_path, mock_mirror};
+use portcache::fuse::{self, FuseMount};
+use rocket::tokio::task;
+use tempfile::TempDir;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+#[rocket::async_test]
+async fn fuse_view_fetches_distfiles_on_open() {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .expect(1)
+        .mount(&mirror)
+        .await;
+
+    let mountpoint = TempDir::new().unwrap();
+    let daemon = TestDaemon::start(
+        &[mirror.uri()],
+        &format!(
+            "[fuse]\nmountpoint = \"{}\"",
+            mountpoint.path().to_string_lossy()
+        ),
+    )
+    .await;
+    let view = match FuseMount::mount(&daemon.config) {
+        Ok(view) => view.unwrap(),
+        Err(e) => {
+            eprintln!("{}, skipping", e);
+            return;
+        }
+    };
+    view.start(daemon.blob_storage.clone());
+
+    // the view is served by this runtime so blocking calls go elsewhere
+    let root = mountpoint.path().to_path_buf();
+    let hash = daemon.blob_storage.hash_dir("hello-1.0.tar.gz");
+    let (layout, content, listed, written) = task::spawn_blocking(move || {
+        let distfiles = root.join("distfiles");
+        let layout = std::fs::read_to_string(distfiles.join("layout.conf")).unwrap();
+        let content = std::fs::read(distfiles.join(&hash).join("hello-1.0.tar.gz")).unwrap();
+        let listed: Vec<String> = std::fs::read_dir(distfiles.join(&hash))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        let written = std::fs::write(distfiles.join(&hash).join("new-1.0.tar.gz"), "x");
+        (layout, content, listed, written)
+    })
+    .await
+    .unwrap();
+    fuse::unmount(mountpoint.path()).unwrap();
+
+    assert_eq!(layout, daemon.blob_storage.layout_conf(false));
+    assert_eq!(content, HELLO_CONTENT);
+    assert_eq!(listed, vec!["hello-1.0.tar.gz"]);
+    assert!(written.is_err());
+    assert!(daemon.blob_path("hello-1.0.tar.gz").is_file());
+}
@@ -0,0 +1,179 @@
+mod common;
+
+use common::{HELLO_CONTENT, Process, TestDaemon, distfile_path, mock_mirror};
+use nix::fcntl::{Flock, FlockArg};
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use portcache::handoff;
+use rocket::http::Status;
+use rocket::tokio::net::TcpListener;
+use rocket::tokio::time;
+use std::os::unix::net::UnixDatagram;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+#[rocket::async_test]
+async fn drain_timeout_is_the_shutdown_grace_period() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "[server]\ndrain_timeout = \"2m\"").await;
+    assert_eq!(daemon.config.server.drain_timeout, Duration::from_secs(120));
+    assert_eq!(daemon.client.rocket().config().shutdown.grace, 120);
+}
+
+#[rocket::async_test]
+async fn requests_wait_for_fetches_of_other_instances() {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(b"refetched"))
+        .expect(0)
+        .mount(&mirror)
+        .await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+
+    // the previous instance is still downloading the file
+    let lock_path = daemon.storage.path().join("fetching/hello-1.0.tar.gz");
+    let lock = std::fs::File::create(&lock_path).unwrap();
+    let lock = Flock::lock(lock, FlockArg::LockExclusiveNonblock).unwrap();
+    daemon.store_blob("hello-1.0.tar.gz", &HELLO_CONTENT[..5]);
+
+    let blob = daemon.blob_path("hello-1.0.tar.gz");
+    let finish = rocket::tokio::spawn(async move {
+        time::sleep(Duration::from_millis(500)).await;
+        std::fs::write(&blob, HELLO_CONTENT).unwrap();
+        std::fs::remove_file(&lock_path).unwrap();
+        drop(lock);
+    });
+
+    let start = Instant::now();
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+    assert!(start.elapsed() >= Duration::from_millis(500));
+    finish.await.unwrap();
+}
+
+#[rocket::async_test]
+async fn successor_stops_predecessor_and_waits_for_the_port() {
+    let mut predecessor = Command::new("sleep").arg("30").spawn().unwrap();
+    let pid = Pid::from_raw(predecessor.id() as i32);
+
+    // the port is released a while after the predecessor got asked to drain
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    rocket::tokio::spawn(async move {
+        time::sleep(Duration::from_millis(500)).await;
+        drop(listener);
+    });
+
+    let start = Instant::now();
+    handoff::take_over(pid, address.ip(), address.port(), Duration::from_secs(10))
+        .await
+        .unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(500));
+    let status = predecessor.wait().unwrap();
+    assert_eq!(status.signal(), Some(Signal::SIGTERM as i32));
+}
+
+#[test]
+fn systemd_gets_notified() {
+    let dir = TempDir::new().unwrap();
+    let socket = dir.path().join("notify");
+    let receiver = UnixDatagram::bind(&socket).unwrap();
+
+    handoff::notify(&socket.to_string_lossy(), "MAINPID=42\nREADY=1").unwrap();
+    let mut buf = [0u8; 64];
+    let len = receiver.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"MAINPID=42\nREADY=1");
+
+    assert!(handoff::notify(&dir.path().join("missing").to_string_lossy(), "READY=1").is_err());
+}
+
+/// whether sig is pending for the process pid
+fn signal_pending(pid: Pid, sig: Signal) -> bool {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).unwrap();
+    status
+        .lines()
+        .filter_map(|line| {
+            line.strip_prefix("SigPnd:")
+                .or_else(|| line.strip_prefix("ShdPnd:"))
+        })
+        .filter_map(|mask| u64::from_str_radix(mask.trim(), 16).ok())
+        .any(|mask| mask & (1 << (sig as i32 - 1)) != 0)
+}
+
+#[test]
+fn successor_becomes_main_pid_before_stopping_the_predecessor() {
+    let dir = TempDir::new().unwrap();
+    let socket = dir.path().join("notify");
+    let receiver = UnixDatagram::bind(&socket).unwrap();
+    receiver
+        .set_read_timeout(Some(Duration::from_secs(30)))
+        .unwrap();
+    let config = dir.path().join("portcache.toml");
+    std::fs::write(
+        &config,
+        format!(
+            "[storage]\nlocation = \"{}\"\n\
+             [server]\naddress = \"127.0.0.1\"\nport = 0\n\
+             [repo]\nsync_interval = 60\nrepos = []\n\
+             [fetcher]\nmirrors = [\"http://127.0.0.1:9\"]\n",
+            dir.path().to_string_lossy()
+        ),
+    )
+    .unwrap();
+
+    // a stopped predecessor keeps the SIGTERM pending so it can be observed
+    let predecessor = Process(
+        Command::new("sleep")
+            .arg("30")
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+    let predecessor_pid = Pid::from_raw(predecessor.0.id() as i32);
+    signal::kill(predecessor_pid, Signal::SIGSTOP).unwrap();
+
+    let mut successor = Process(
+        Command::new(env!("CARGO_BIN_EXE_portcache"))
+            .arg("-c")
+            .arg(&config)
+            .env(handoff::ENV, predecessor_pid.to_string())
+            .env("NOTIFY_SOCKET", &socket)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while !signal_pending(predecessor_pid, Signal::SIGTERM) {
+        assert!(
+            successor.0.try_wait().unwrap().is_none(),
+            "successor exited"
+        );
+        assert!(Instant::now() < deadline, "predecessor never got SIGTERM");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    // MAINPID has to be queued by the time the predecessor got asked to stop
+    receiver.set_nonblocking(true).unwrap();
+    let mut buf = [0u8; 64];
+    let len = receiver.recv(&mut buf).expect("no MAINPID before SIGTERM");
+    assert_eq!(
+        &buf[..len],
+        format!("MAINPID={}", successor.0.id()).as_bytes()
+    );
+
+    // readiness follows once the successor serves
+    receiver.set_nonblocking(false).unwrap();
+    let len = receiver.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"READY=1");
+}
@@ -0,0 +1,101 @@
+mod common;
+
+use common::{HELLO_CONTENT, TestDaemon, distfile_path, mock_mirror};
+use portcache::import::{ImportReport, Importer};
+use rocket::http::Status;
+use tempfile::TempDir;
+use wiremock::matchers::method;
+use wiremock::{Mock, ResponseTemplate};
+
+/// daemon importing from a fresh drop directory
+/// whose mirror fails the test if it gets asked for anything
+async fn daemon_with_import_dir(extra: &str) -> (TestDaemon, TempDir) {
+    let dir = TempDir::new().unwrap();
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(0)
+        .mount(&mirror)
+        .await;
+
+    let extra = format!(
+        "[import]\ndirectory = \"{}\"\nsettle = 0\n{}",
+        dir.path().to_string_lossy(),
+        extra
+    );
+    let daemon = TestDaemon::start(&[mirror.uri()], &extra).await;
+    daemon.load_fixture_manifests().await;
+    (daemon, dir)
+}
+
+fn importer(daemon: &TestDaemon) -> Importer {
+    Importer::new(
+        &daemon.config,
+        daemon.blob_storage.clone(),
+        daemon.repo_db.clone(),
+    )
+    .unwrap()
+}
+
+#[rocket::async_test]
+async fn verified_files_are_moved_into_the_cache() {
+    let (daemon, dir) = daemon_with_import_dir("").await;
+    std::fs::write(dir.path().join("hello-1.0.tar.gz"), HELLO_CONTENT).unwrap();
+    std::fs::write(dir.path().join("hello-data-1.0.tar.xz"), b"corrupted").unwrap();
+    std::fs::write(dir.path().join("unknown-1.0.tar.gz"), b"unknown").unwrap();
+
+    let report = importer(&daemon).scan().await.unwrap();
+    assert_eq!(
+        report,
+        ImportReport {
+            imported: 1,
+            duplicates: 0,
+            failed: 2,
+        }
+    );
+
+    assert_eq!(
+        std::fs::read(daemon.blob_path("hello-1.0.tar.gz")).unwrap(),
+        HELLO_CONTENT
+    );
+    assert!(!dir.path().join("hello-1.0.tar.gz").exists());
+    // mismatches are moved aside, files without Manifest entry stay for later
+    assert!(!daemon.blob_path("hello-data-1.0.tar.xz").exists());
+    assert!(dir.path().join("rejected/hello-data-1.0.tar.xz").is_file());
+    assert!(!daemon.blob_path("unknown-1.0.tar.gz").exists());
+    assert!(dir.path().join("unknown-1.0.tar.gz").is_file());
+
+    // a copy of a cached file is dropped
+    std::fs::write(dir.path().join("hello-1.0.tar.gz"), HELLO_CONTENT).unwrap();
+    let report = importer(&daemon).scan().await.unwrap();
+    assert_eq!(report.duplicates, 1);
+    assert!(!dir.path().join("hello-1.0.tar.gz").exists());
+}
+
+#[rocket::async_test]
+async fn unverified_files_can_be_allowed() {
+    let (daemon, dir) = daemon_with_import_dir("unverified = true").await;
+    std::fs::write(dir.path().join("unknown-1.0.tar.gz"), b"unknown").unwrap();
+
+    let report = importer(&daemon).scan().await.unwrap();
+    assert_eq!(report.imported, 1);
+    assert_eq!(
+        std::fs::read(daemon.blob_path("unknown-1.0.tar.gz")).unwrap(),
+        b"unknown"
+    );
+}
+
+#[rocket::async_test]
+async fn requests_check_the_import_directory_before_upstream() {
+    let (daemon, dir) = daemon_with_import_dir("").await;
+    std::fs::write(dir.path().join("hello-1.0.tar.gz"), HELLO_CONTENT).unwrap();
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), HELLO_CONTENT);
+    assert!(!dir.path().join("hello-1.0.tar.gz").exists());
+}
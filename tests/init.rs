@@ -0,0 +1,53 @@
+use portcache::config::Config;
+use portcache::init;
+use tempfile::TempDir;
+
+#[test]
+fn sample_config_is_valid() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("etc/portcache.toml");
+    init::write(&path, None, false).unwrap();
+
+    let config = Config::parse(Some(path.to_string_lossy().to_string())).unwrap();
+    assert_eq!(
+        config.storage.location.to_string_lossy(),
+        "/var/cache/portcache"
+    );
+    assert!(!config.repo.repos.is_empty());
+}
+
+#[test]
+fn existing_files_are_kept_without_force() {
+    let dir = TempDir::new().unwrap();
+    let config = dir.path().join("portcache.toml");
+    let unit = dir.path().join("portcache.service");
+    std::fs::write(&unit, "custom").unwrap();
+
+    let error = init::write(&config, Some(&unit), false).unwrap_err();
+    assert!(error.contains("--force"), "{}", error);
+    // nothing got written half way
+    assert!(!config.exists());
+    assert_eq!(std::fs::read_to_string(&unit).unwrap(), "custom");
+
+    init::write(&config, Some(&unit), true).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&config).unwrap(),
+        init::SAMPLE_CONFIG
+    );
+}
+
+#[test]
+fn systemd_unit_points_at_config() {
+    let dir = TempDir::new().unwrap();
+    let config = dir.path().join("portcache.toml");
+    let unit = dir.path().join("portcache.service");
+    init::write(&config, Some(&unit), false).unwrap();
+
+    let unit = std::fs::read_to_string(&unit).unwrap();
+    let exec_start = format!(
+        "ExecStart=/usr/bin/portcache -c {}\n",
+        config.to_string_lossy()
+    );
+    assert!(unit.contains(&exec_start), "{}", unit);
+    assert!(unit.contains("[Install]"));
+}
@@ -0,0 +1,197 @@
+mod common;
+
+use common::{TestDaemon, mock_mirror};
+use portcache::blob_storage::{self, BlobStorage};
+use portcache::fetcher::Layout;
+use portcache::utils;
+use rocket::http::Status;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+/// blob names spread over several hash directories
+const BLOBS: &[&str] = &[
+    "a-1.0.tar.gz",
+    "b-1.0.tar.gz",
+    "c-1.0.tar.gz",
+    "d-1.0.tar.gz",
+];
+
+#[rocket::async_test]
+async fn configured_hash_bits_are_served() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "[storage]\nhash_bits = 16\n").await;
+    let file = "hello-1.0.tar.gz";
+    let dir = utils::filename_hash_dir(file, 16);
+    assert_eq!(dir.len(), 4);
+
+    let response = daemon.client.get("/distfiles/layout.conf").dispatch().await;
+    assert_eq!(
+        response.into_string().await.unwrap(),
+        "[structure]\n0=filename-hash BLAKE2B 16\n"
+    );
+
+    let storage = daemon.storage.path().join("distfiles");
+    std::fs::create_dir_all(storage.join(&dir)).unwrap();
+    std::fs::write(storage.join(&dir).join(file), "hello").unwrap();
+
+    let response = daemon
+        .client
+        .get(format!("/distfiles/{}/{}", dir, file))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+
+    // the 8 bit directory of Gentoo mirrors doesn't match anymore
+    let response = daemon
+        .client
+        .get(format!("/distfiles/{}/{}", &dir[..2], file))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::BadRequest);
+}
+
+#[rocket::async_test]
+async fn bucket_counts_are_reported() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    for blob in BLOBS {
+        daemon.store_blob(blob, b"blob");
+    }
+
+    let response = daemon.client.get("/api/v1/stats/buckets").dispatch().await;
+    let stats: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+
+    assert_eq!(stats["hash_bits"], 8);
+    assert_eq!(stats["blobs"], BLOBS.len());
+    for blob in BLOBS {
+        let dir = utils::filename_hash_dir(blob, 8);
+        assert!(stats["buckets"][&dir].as_u64().unwrap() >= 1);
+    }
+}
+
+#[rocket::async_test]
+async fn reshard_moves_blobs_to_new_layout() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    for blob in BLOBS {
+        daemon.store_blob(blob, blob.as_bytes());
+    }
+
+    let mut config = daemon.config.clone();
+    config.storage.hash_bits = 12;
+
+    // the existing blobs are still in 8 bit directories
+    let error = match BlobStorage::new(&config, daemon.repo_db.clone()).await {
+        Ok(_) => panic!("storage started with mismatching layout"),
+        Err(e) => e.to_string(),
+    };
+    assert!(error.contains("portcache reshard"), "{}", error);
+
+    let report = blob_storage::reshard(&config, &daemon.repo_db)
+        .await
+        .unwrap();
+    assert_eq!(report.moved, BLOBS.len() as u64);
+    assert_eq!(report.kept, 0);
+
+    let storage = BlobStorage::new(&config, daemon.repo_db.clone())
+        .await
+        .unwrap();
+    for blob in BLOBS {
+        let path = storage.blob_location(blob).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), blob.as_bytes());
+        assert!(!daemon.blob_path(blob).exists());
+    }
+
+    // nothing left to move
+    let report = blob_storage::reshard(&config, &daemon.repo_db)
+        .await
+        .unwrap();
+    assert_eq!(report.moved, 0);
+    assert_eq!(report.kept, BLOBS.len() as u64);
+}
+
+/// a mirror serving layout_conf as its layout.conf
+async fn mirror_with_layout(layout_conf: &'static str) -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/distfiles/layout.conf"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(layout_conf))
+        .mount(&server)
+        .await;
+    server
+}
+
+#[rocket::async_test]
+async fn upstream_layout_is_followed() {
+    let mirror = mirror_with_layout("[structure]\n0=filename-hash BLAKE2B 16\n").await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    for blob in BLOBS {
+        daemon.store_blob(blob, blob.as_bytes());
+    }
+
+    // a predecessor still serves the blobs, they stay until the next restart
+    let bits = blob_storage::follow_upstream_layout(&daemon.config, false)
+        .await
+        .unwrap();
+    assert_eq!(bits, 8);
+    assert!(daemon.blob_path(BLOBS[0]).exists());
+
+    let bits = blob_storage::follow_upstream_layout(&daemon.config, true)
+        .await
+        .unwrap();
+    assert_eq!(bits, 16);
+    assert_eq!(daemon.repo_db.get_hash_bits().await.unwrap(), Some(16));
+
+    let mut config = daemon.config.clone();
+    config.storage.hash_bits = bits;
+    let storage = BlobStorage::new(&config, daemon.repo_db.clone())
+        .await
+        .unwrap();
+    for blob in BLOBS {
+        let path = storage.blob_location(blob).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), blob.as_bytes());
+        assert!(!daemon.blob_path(blob).exists());
+    }
+}
+
+#[rocket::async_test]
+async fn unreachable_upstream_keeps_the_layout() {
+    let mirror = MockServer::start().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "[storage]\nhash_bits = 12\n").await;
+
+    let bits = blob_storage::follow_upstream_layout(&daemon.config, true)
+        .await
+        .unwrap();
+    assert_eq!(bits, 12);
+    assert_eq!(daemon.repo_db.get_hash_bits().await.unwrap(), Some(12));
+}
+
+#[test]
+fn layout_conf_is_parsed() {
+    assert_eq!(
+        Layout::parse("[structure]\n0=filename-hash BLAKE2B 8\n1=flat\n"),
+        Some(Layout::FileNameHash(8))
+    );
+    // unsupported entries are skipped in order of their index
+    assert_eq!(
+        Layout::parse("[structure]\n1=filename-hash BLAKE2B 16\n0=filename-hash SHA1 8\n"),
+        Some(Layout::FileNameHash(16))
+    );
+    assert_eq!(
+        Layout::parse("[structure]\n0=filename-hash BLAKE2B 6\n1=flat\n"),
+        Some(Layout::Flat)
+    );
+    assert_eq!(
+        Layout::parse("[structure]\n0=content-hash SHA512 8:8\n"),
+        None
+    );
+}
+
+#[test]
+fn hash_dirs_extend_the_mirror_layout() {
+    let file = "hello-1.0.tar.gz";
+    let mirror = utils::filename_hash_dir_blake2b(file).unwrap();
+    assert_eq!(utils::filename_hash_dir(file, 8), mirror);
+    assert!(utils::filename_hash_dir(file, 16).starts_with(&mirror));
+}
@@ -0,0 +1,32 @@
+use portcache::log_limiter::LogLimiter;
+use std::time::Duration;
+
+#[test]
+fn repeated_errors_are_suppressed_within_window() {
+    let log = LogLimiter::new(Duration::from_secs(600));
+
+    assert!(log.error("mirror https://a.example", "first"));
+    assert!(!log.error("mirror https://a.example", "second"));
+    assert!(!log.error("mirror https://a.example", "third"));
+
+    // other keys are tracked separately
+    assert!(log.error("mirror https://b.example", "first"));
+}
+
+#[test]
+fn errors_are_logged_again_after_window() {
+    let log = LogLimiter::new(Duration::from_millis(20));
+
+    assert!(log.error("mirror https://a.example", "first"));
+    assert!(!log.error("mirror https://a.example", "second"));
+    std::thread::sleep(Duration::from_millis(30));
+    assert!(log.error("mirror https://a.example", "third"));
+}
+
+#[test]
+fn zero_window_logs_everything() {
+    let log = LogLimiter::new(Duration::ZERO);
+
+    assert!(log.error("mirror https://a.example", "first"));
+    assert!(log.error("mirror https://a.example", "second"));
+}
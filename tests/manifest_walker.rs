@@ -0,0 +1,345 @@
+mod common;
+
+use common::fixture_repo;
+use futures::StreamExt;
+use futures::pin_mut;
+use portcache::config::LayoutCheck;
+use portcache::manifest_walker::{
+    ManifestDepth, ManifestEntry, ManifestWalker, PackageFilter, PackageMask,
+};
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+#[rocket::async_test]
+async fn fixture_repo_entries_are_found() {
+    let mut walker = ManifestWalker::new(
+        fixture_repo(),
+        LayoutCheck::Require,
+        ManifestDepth::detect(&fixture_repo(), None),
+    )
+    .unwrap();
+    let entries = walker.entries();
+    pin_mut!(entries);
+
+    let mut found = Vec::new();
+    while let Some(entry) = entries.next().await {
+        found.push(entry);
+    }
+    found.sort_by(|a, b| a.file.cmp(&b.file));
+
+    assert_eq!(found.len(), 2);
+    assert_eq!(found[0].file, "hello-1.0.tar.gz");
+    assert_eq!(found[0].size, 30);
+    assert!(found[0].blake2b.is_some());
+    assert!(found[0].sha512.is_some());
+    assert_eq!(found[1].file, "hello-data-1.0.tar.xz");
+    assert!(found[1].sha512.is_none());
+}
+
+#[test]
+fn tree_without_layout_conf_is_rejected() {
+    let root = fixture_repo().join("app-misc");
+    assert!(ManifestWalker::new(root.clone(), LayoutCheck::Require, ManifestDepth::Any).is_err());
+    assert!(ManifestWalker::new(root.clone(), LayoutCheck::Warn, ManifestDepth::Any).is_ok());
+    assert!(ManifestWalker::new(root, LayoutCheck::Ignore, ManifestDepth::Any).is_ok());
+}
+
+/// names of all distfiles a walk finds, sorted
+async fn walk(root: &Path, depth: ManifestDepth) -> Vec<String> {
+    let mut walker = ManifestWalker::new(root.to_path_buf(), LayoutCheck::Ignore, depth).unwrap();
+    let entries = walker.entries();
+    pin_mut!(entries);
+
+    let mut found = Vec::new();
+    while let Some(entry) = entries.next().await {
+        found.push(entry.file);
+    }
+    found.sort();
+    found
+}
+
+#[rocket::async_test]
+async fn manifests_are_found_at_any_depth_in_nonstandard_trees() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path();
+    let write = |relative: &str, content: &str| {
+        let path = root.join(relative);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    };
+    write(
+        "Manifest",
+        "MANIFEST category/Manifest 40 SHA512 aa\nTIMESTAMP 2026-01-01T00:00:00Z\n",
+    );
+    write("category/Manifest", "DIST shallow-1.0.tar.gz 1 SHA512 aa\n");
+    write(
+        "category/package/Manifest",
+        "DIST standard-1.0.tar.gz 1 SHA512 aa\n",
+    );
+    write(
+        "nested/deeper/package/Manifest",
+        "DIST deep-1.0.tar.gz 1 SHA512 aa\n",
+    );
+    write(
+        ".git/objects/Manifest",
+        "DIST hidden-1.0.tar.gz 1 SHA512 aa\n",
+    );
+
+    assert_eq!(ManifestDepth::detect(root, None), ManifestDepth::Any);
+    assert_eq!(ManifestDepth::detect(root, Some(0)), ManifestDepth::Any);
+    assert_eq!(
+        ManifestDepth::detect(root, Some(4)),
+        ManifestDepth::Exactly(4)
+    );
+    assert_eq!(
+        ManifestDepth::detect(&fixture_repo(), None),
+        ManifestDepth::Exactly(3)
+    );
+
+    assert_eq!(
+        walk(root, ManifestDepth::Any).await,
+        [
+            "deep-1.0.tar.gz",
+            "shallow-1.0.tar.gz",
+            "standard-1.0.tar.gz"
+        ]
+    );
+    assert_eq!(
+        walk(root, ManifestDepth::Exactly(3)).await,
+        ["standard-1.0.tar.gz"]
+    );
+    assert_eq!(
+        walk(root, ManifestDepth::Exactly(4)).await,
+        ["deep-1.0.tar.gz"]
+    );
+}
+
+#[test]
+fn package_mask_covers_packages_without_unmasked_versions() {
+    let dir = TempDir::new().unwrap();
+    let package = |name: &str, versions: &[&str]| {
+        let path = dir.path().join("app-misc").join(name);
+        std::fs::create_dir_all(&path).unwrap();
+        for version in versions {
+            std::fs::write(path.join(format!("{}-{}.ebuild", name, version)), "").unwrap();
+        }
+        path
+    };
+
+    let mask = PackageMask::parse(
+        "# Dev Eloper <dev@gentoo.org> (2026-01-01)\n\
+         # Removal on 2026-02-01\n\
+         app-misc/gone\n\
+         =app-misc/old-1.0\n\
+         =app-misc/old-1.1*\n\
+         ~app-misc/rev-2.0\n\
+         <app-misc/range-2\n\
+         app-misc/slotted:0\n\
+         =app-misc/font-100dpi-1.0-r1 # trailing comment\n",
+    );
+
+    assert!(mask.covers(&package("gone", &["1.0", "2.0_rc1"])));
+    assert!(mask.covers(&package("old", &["1.0", "1.1.2"])));
+    assert!(!mask.covers(&package("old", &["1.0", "1.2"])));
+    assert!(mask.covers(&package("rev", &["2.0", "2.0-r3"])));
+    assert!(!mask.covers(&package("range", &["1.0"])));
+    assert!(!mask.covers(&package("slotted", &["1.0"])));
+    assert!(mask.covers(&package("font-100dpi", &["1.0-r1"])));
+    assert!(!mask.covers(&package("unrelated", &["1.0"])));
+    // without ebuilds there's nothing to tell
+    assert!(!mask.covers(&package("empty", &[])));
+
+    assert!(PackageMask::load(&fixture_repo()).is_empty());
+}
+
+/// parse a single Manifest line
+fn parse(line: &str) -> Result<Option<ManifestEntry>, String> {
+    ManifestEntry::parse(&PathBuf::from("Manifest"), &line.to_string())
+}
+
+#[test]
+fn thick_manifest_lines_are_skipped() {
+    let lines = [
+        "AUX fix-build.patch 512 BLAKE2B aa SHA512 bb",
+        "MANIFEST app-misc/Manifest.gz 1234 BLAKE2B aa SHA512 bb",
+        "IGNORE distfiles",
+        "EBUILD hello-1.0.ebuild 800 BLAKE2B cc SHA512 dd",
+        "MISC metadata.xml 300 BLAKE2B ee SHA512 ff",
+        "",
+    ];
+    for line in lines {
+        assert!(parse(line).unwrap().is_none(), "{}", line);
+    }
+
+    let entry = parse("DIST hello-1.0.tar.gz 30 BLAKE2B aa SHA512 bb")
+        .unwrap()
+        .unwrap();
+    assert_eq!(entry.file, "hello-1.0.tar.gz");
+    assert_eq!(entry.size, 30);
+}
+
+#[test]
+fn legacy_hashes_are_captured() {
+    let entry =
+        parse("DIST old-1.0.tar.bz2 1234 RMD160 r160 SHA1 s1 SHA256 s256 SHA512 s512 WHIRLPOOL w")
+            .unwrap()
+            .unwrap();
+
+    assert_eq!(entry.blake2b, None);
+    assert_eq!(entry.sha512.as_deref(), Some("s512"));
+    assert_eq!(entry.hashes.len(), 5);
+    assert_eq!(entry.hashes["SHA256"], "s256");
+    assert_eq!(entry.hashes["RMD160"], "r160");
+    assert_eq!(entry.hashes["WHIRLPOOL"], "w");
+}
+
+#[test]
+fn malformed_lines_are_rejected() {
+    let lines = [
+        "DIST",
+        "DIST hello-1.0.tar.gz",
+        "DIST hello-1.0.tar.gz big BLAKE2B aa",
+        "DIST hello-1.0.tar.gz 30 BLAKE2B",
+        "BOGUS hello-1.0.tar.gz 30",
+    ];
+    for line in lines {
+        assert!(parse(line).is_err(), "{}", line);
+    }
+}
+
+#[test]
+fn sizes_beyond_4gib_are_parsed() {
+    let entry = parse("DIST texlive-2025-texmf.tar.xz 5368709120 BLAKE2B aa SHA512 bb")
+        .unwrap()
+        .unwrap();
+    assert_eq!(entry.size, 5 * 1024 * 1024 * 1024);
+}
+
+#[rocket::async_test]
+async fn concurrent_walks_keep_category_order() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path();
+    for category in ["app-misc", "dev-libs", "net-misc", "sys-apps", "x11-libs"] {
+        for package in ["alpha", "beta", "gamma"] {
+            let path = root.join(category).join(package);
+            std::fs::create_dir_all(&path).unwrap();
+            std::fs::write(
+                path.join("Manifest"),
+                format!(
+                    "DIST {c}-{p}-1.0.tar.gz 1 SHA512 aa\nDIST {c}-{p}-2.0.tar.gz 1 SHA512 aa\n",
+                    c = category,
+                    p = package
+                ),
+            )
+            .unwrap();
+        }
+    }
+
+    let walk = async |concurrency: usize| {
+        let mut walker = ManifestWalker::new(
+            root.to_path_buf(),
+            LayoutCheck::Ignore,
+            ManifestDepth::Exactly(3),
+        )
+        .unwrap()
+        .concurrency(concurrency);
+        let entries = walker.entries();
+        pin_mut!(entries);
+
+        let mut found = Vec::new();
+        while let Some(entry) = entries.next().await {
+            found.push(entry.file);
+        }
+        found
+    };
+
+    let sequential = walk(1).await;
+    assert_eq!(sequential.len(), 30);
+    assert_eq!(sequential[0], "app-misc-alpha-1.0.tar.gz");
+    assert_eq!(sequential[29], "x11-libs-gamma-2.0.tar.gz");
+    let mut sorted = sequential.clone();
+    sorted.sort();
+    assert_eq!(sequential, sorted);
+    assert_eq!(walk(4).await, sequential);
+}
+
+#[test]
+fn package_filter_matches_categories_and_packages() {
+    let patterns = |patterns: &[&str]| -> Vec<String> {
+        patterns.iter().map(|pattern| pattern.to_string()).collect()
+    };
+    assert!(PackageFilter::new(&[], &[]).unwrap().is_none());
+
+    let filter = PackageFilter::new(
+        &patterns(&["kde-*", "media-*/*", "dev-lang/rust*"]),
+        &patterns(&["media-sound/*-bin", "kde-apps"]),
+    )
+    .unwrap()
+    .unwrap();
+    assert!(filter.allows(Path::new("/repo/kde-plasma/plasma-meta")));
+    assert!(filter.allows(Path::new("/repo/media-video/mpv")));
+    assert!(filter.allows(Path::new("/repo/dev-lang/rust-bin")));
+    assert!(!filter.allows(Path::new("/repo/dev-lang/python")));
+    assert!(!filter.allows(Path::new("/repo/media-sound/spotify-bin")));
+    assert!(!filter.allows(Path::new("/repo/kde-apps/dolphin")));
+    assert!(filter.allows_category("dev-lang"));
+    assert!(!filter.allows_category("sci-physics"));
+    assert!(!filter.allows_category("kde-apps"));
+    // only some of its packages are excluded
+    assert!(filter.allows_category("media-sound"));
+
+    let filter = PackageFilter::new(&[], &patterns(&["sci-*", "games-??????"]))
+        .unwrap()
+        .unwrap();
+    assert!(filter.allows(Path::new("/repo/app-misc/hello")));
+    assert!(!filter.allows(Path::new("/repo/sci-physics/root")));
+    assert!(!filter.allows(Path::new("/repo/games-arcade/foo")));
+    assert!(filter.allows(Path::new("/repo/games-rpg/foo")));
+
+    for invalid in ["", "/hello", "app-misc/", "app-misc/hello/extra"] {
+        assert!(
+            PackageFilter::new(&patterns(&[invalid]), &[]).is_err(),
+            "{}",
+            invalid
+        );
+    }
+}
+
+#[rocket::async_test]
+async fn filtered_packages_are_not_walked() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path();
+    for (category, package) in [
+        ("app-misc", "hello"),
+        ("games-arcade", "pacman"),
+        ("sci-libs", "blas"),
+        ("sci-physics", "root"),
+    ] {
+        let path = root.join(category).join(package);
+        std::fs::create_dir_all(&path).unwrap();
+        std::fs::write(
+            path.join("Manifest"),
+            format!("DIST {}-1.0.tar.gz 1 SHA512 aa\n", package),
+        )
+        .unwrap();
+    }
+
+    let filter = PackageFilter::new(&[], &[String::from("games-*"), String::from("sci-*/root")])
+        .unwrap()
+        .map(std::sync::Arc::new);
+    let mut walker = ManifestWalker::new(
+        root.to_path_buf(),
+        LayoutCheck::Ignore,
+        ManifestDepth::Exactly(3),
+    )
+    .unwrap()
+    .filter(filter);
+    let entries = walker.entries();
+    pin_mut!(entries);
+
+    let mut found = Vec::new();
+    while let Some(entry) = entries.next().await {
+        found.push(entry.file);
+    }
+    assert_eq!(found, ["hello-1.0.tar.gz", "blas-1.0.tar.gz"]);
+}
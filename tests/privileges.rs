@@ -0,0 +1,157 @@
+mod common;
+
+use common::{HELLO_CONTENT, Process, distfile_path, mock_mirror};
+use nix::unistd::{Uid, User};
+use portcache::config::{ParserConfig, ServerConfig};
+use portcache::ebuild_parser::HelperSandbox;
+use portcache::privileges::RunAs;
+use std::os::unix::fs::{MetadataExt, lchown};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+fn server_config(extra: &str) -> ServerConfig {
+    toml::from_str(&format!("address = \"127.0.0.1\"\nport = 0\n{}", extra)).unwrap()
+}
+
+#[test]
+fn run_as_is_disabled_without_user() {
+    assert!(RunAs::new(&server_config("")).unwrap().is_none());
+}
+
+#[test]
+fn run_as_resolves_user_and_primary_group() {
+    let run_as = RunAs::new(&server_config("user = \"root\""))
+        .unwrap()
+        .unwrap();
+    assert_eq!(run_as.uid(), 0);
+    assert_eq!(run_as.gid(), 0);
+}
+
+#[test]
+fn run_as_rejects_unknown_user() {
+    let error = RunAs::new(&server_config("user = \"portcache-no-such-user\""))
+        .err()
+        .unwrap();
+    assert!(error.contains("doesn't exist"), "{}", error);
+}
+
+#[test]
+fn helper_sandbox_rejects_unknown_user() {
+    let config: ParserConfig = toml::from_str("user = \"portcache-no-such-user\"").unwrap();
+    assert!(HelperSandbox::new(&config).is_err());
+}
+
+#[test]
+fn chown_storage_skips_subtrees_owned_by_the_user() {
+    // handing files over needs root
+    if !Uid::effective().is_root() {
+        return;
+    }
+    let nobody = User::from_name("nobody").unwrap().unwrap();
+    let run_as = RunAs::resolve("nobody", None).unwrap();
+    let storage = TempDir::new().unwrap();
+    let blob = storage.path().join("distfiles/ab/blob");
+    std::fs::create_dir_all(blob.parent().unwrap()).unwrap();
+    std::fs::write(&blob, "blob").unwrap();
+
+    run_as.chown_storage(storage.path()).unwrap();
+    for path in [storage.path(), &storage.path().join("distfiles"), &blob] {
+        let metadata = std::fs::symlink_metadata(path).unwrap();
+        assert_eq!(metadata.uid(), nobody.uid.as_raw());
+        assert_eq!(metadata.gid(), run_as.gid());
+    }
+
+    // directories owned by the user are assumed to be handed over with their contents
+    let skipped = storage.path().join("distfiles/cd/blob");
+    std::fs::create_dir_all(skipped.parent().unwrap()).unwrap();
+    std::fs::write(&skipped, "blob").unwrap();
+    lchown(
+        skipped.parent().unwrap(),
+        Some(run_as.uid()),
+        Some(run_as.gid()),
+    )
+    .unwrap();
+    let handed_over = storage.path().join("new");
+    std::fs::write(&handed_over, "new").unwrap();
+
+    run_as.chown_storage(storage.path()).unwrap();
+    assert_eq!(std::fs::metadata(&skipped).unwrap().uid(), 0);
+    assert_eq!(std::fs::metadata(&handed_over).unwrap().uid(), run_as.uid());
+}
+
+#[rocket::async_test]
+async fn privileges_are_dropped_before_the_first_request() {
+    // dropping privileges needs root
+    if !Uid::effective().is_root() {
+        return;
+    }
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .expect(1)
+        .mount(&mirror)
+        .await;
+
+    // a privileged port is bound as root and privileges are dropped afterwards
+    let port = (600..1024)
+        .find(|port| std::net::TcpListener::bind(("127.0.0.1", *port)).is_ok())
+        .unwrap();
+    let storage = TempDir::new().unwrap();
+    let config = storage.path().join("portcache.toml");
+    std::fs::write(
+        &config,
+        format!(
+            "[storage]\nlocation = \"{}\"\n\
+             [server]\naddress = \"127.0.0.1\"\nport = {}\nuser = \"nobody\"\n\
+             [repo]\nsync_interval = 60\nrepos = []\n\
+             [fetcher]\nmirrors = [\"{}\"]\n",
+            storage.path().to_string_lossy(),
+            port,
+            mirror.uri()
+        ),
+    )
+    .unwrap();
+    let _server = Process(
+        Command::new(env!("CARGO_BIN_EXE_portcache"))
+            .arg("-c")
+            .arg(&config)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+
+    // the request is sent as soon as the port is bound
+    let url = format!(
+        "http://127.0.0.1:{}{}",
+        port,
+        distfile_path("hello-1.0.tar.gz")
+    );
+    let deadline = Instant::now() + Duration::from_secs(30);
+    let response = loop {
+        match reqwest::get(&url).await {
+            Ok(response) => break response,
+            Err(e) if e.is_connect() && Instant::now() < deadline => {
+                rocket::tokio::time::sleep(Duration::from_millis(5)).await
+            }
+            Err(e) => panic!("{}", e),
+        }
+    };
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    assert_eq!(response.bytes().await.unwrap(), HELLO_CONTENT);
+
+    // the blob got written by the unprivileged user
+    let blob = storage
+        .path()
+        .join("distfiles")
+        .join(portcache::utils::filename_hash_dir_blake2b("hello-1.0.tar.gz").unwrap())
+        .join("hello-1.0.tar.gz");
+    let owner = std::fs::metadata(blob).unwrap().uid();
+    assert_eq!(
+        owner,
+        User::from_name("nobody").unwrap().unwrap().uid.as_raw()
+    );
+}
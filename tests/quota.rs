@@ -0,0 +1,99 @@
+mod common;
+
+use common::{HELLO_CONTENT, TestDaemon, distfile_path, mock_mirror};
+use rocket::http::{Header, Status};
+use std::net::SocketAddr;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+#[rocket::async_test]
+async fn subnet_is_limited_after_quota() {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .mount(&mirror)
+        .await;
+
+    // two downloads of the 30 byte file exceed the limit
+    let daemon = TestDaemon::start(&[mirror.uri()], "[quota]\nlimit = 40").await;
+    let uri = distfile_path("hello-1.0.tar.gz");
+
+    for _ in 0..2 {
+        let response = daemon
+            .client
+            .get(uri.clone())
+            .remote(addr("192.0.2.7:4000"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    // same /24 is limited
+    let response = daemon
+        .client
+        .get(uri.clone())
+        .remote(addr("192.0.2.200:4000"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::TooManyRequests);
+
+    // other subnets aren't
+    let response = daemon
+        .client
+        .get(uri)
+        .remote(addr("198.51.100.1:4000"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn usage_is_reported_per_subnet() {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .mount(&mirror)
+        .await;
+
+    let daemon = TestDaemon::start(
+        &[mirror.uri()],
+        "[admin]\ntoken = \"secret\"\n[quota]\nipv6_prefix = 48",
+    )
+    .await;
+    let uri = distfile_path("hello-1.0.tar.gz");
+
+    for remote in ["192.0.2.7:4000", "192.0.2.8:4000", "[2001:db8:1:2::1]:4000"] {
+        let response = daemon
+            .client
+            .get(uri.clone())
+            .remote(addr(remote))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    let response = daemon
+        .client
+        .get("/api/v1/admin/usage")
+        .header(Header::new("Authorization", "Bearer secret"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let report: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!(report["limit"], serde_json::Value::Null);
+    assert_eq!(
+        report["clients"],
+        serde_json::json!([
+            { "subnet": "192.0.2.0/24", "bytes": 60 },
+            { "subnet": "2001:db8:1::/48", "bytes": 30 },
+        ])
+    );
+}
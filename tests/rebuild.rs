@@ -0,0 +1,54 @@
+mod common;
+
+use common::{HELLO_CONTENT, TestDaemon, fixture_repo, mock_mirror};
+use portcache::app::Deps;
+use portcache::rebuild::{self, RebuildReport};
+
+#[rocket::async_test]
+async fn lost_database_is_rebuilt_from_disk() {
+    let extra = format!("[repo]\nrepos = [\"{}\"]", fixture_repo().to_string_lossy());
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], &extra).await;
+    daemon.store_blob("hello-1.0.tar.gz", HELLO_CONTENT);
+    daemon.store_blob("hello-data-1.0.tar.xz", b"truncated");
+    daemon.store_blob("orphan-1.0.tar.gz", b"orphan");
+
+    // the database got lost
+    std::fs::remove_file(daemon.storage.path().join("db.sqlite3")).unwrap();
+
+    let deps = Deps::new(&daemon.config).await.unwrap();
+    assert!(
+        deps.repo_db
+            .get_manifest_entry("hello-1.0.tar.gz")
+            .await
+            .unwrap()
+            .is_none()
+    );
+
+    let report = rebuild::rebuild_index(&daemon.config, &deps).await.unwrap();
+    assert_eq!(
+        report,
+        RebuildReport {
+            verified: 1,
+            mismatched: 1,
+            unknown: 1,
+            failed: 0,
+        }
+    );
+
+    assert_eq!(
+        deps.repo_db
+            .get_manifest_repo("hello-1.0.tar.gz")
+            .await
+            .unwrap()
+            .as_deref(),
+        Some("repo")
+    );
+    assert_eq!(
+        deps.repo_db.get_stale_blobs().await.unwrap(),
+        ["hello-data-1.0.tar.xz"]
+    );
+    // nothing got deleted
+    assert!(daemon.blob_path("orphan-1.0.tar.gz").is_file());
+    assert!(daemon.blob_path("hello-data-1.0.tar.xz").is_file());
+}
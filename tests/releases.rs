@@ -0,0 +1,297 @@
+mod common;
+
+use common::{TestDaemon, mock_mirror};
+use portcache::releases::{dearmor, parse_digests, signed_text};
+use portcache::utils::HashType;
+use rocket::http::Status;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use tempfile::TempDir;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+/// content of the fake stage3 tarball
+const STAGE3: &[u8] = b"not really a stage3\n";
+
+/// directory of the fake stage3 relative to releases/
+const STAGE3_DIR: &str = "amd64/autobuilds/20261011T170000Z";
+
+/// name of the fake stage3
+const STAGE3_NAME: &str = "stage3-amd64-openrc-20261011T170000Z.tar.xz";
+
+/// a throwaway OpenPGP key standing in for the release key
+struct Signer {
+    /// GNUPGHOME of the key
+    home: TempDir,
+}
+
+impl Signer {
+    /// generate a new key
+    /// returns None when gpg isn't installed
+    fn new() -> Option<Self> {
+        let home = TempDir::new().unwrap();
+        let status = Command::new("gpg")
+            .env("GNUPGHOME", home.path())
+            .args(["--batch", "--passphrase", "", "--quick-gen-key"])
+            .args(["Release <release@example.org>", "ed25519", "sign", "never"])
+            .stderr(Stdio::null())
+            .status()
+            .ok()?;
+        status.success().then_some(Self { home })
+    }
+
+    /// run gpg with input on stdin and return its stdout
+    fn gpg(&self, args: &[&str], input: &[u8]) -> Vec<u8> {
+        let mut child = Command::new("gpg")
+            .env("GNUPGHOME", self.home.path())
+            .arg("--batch")
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        output.stdout
+    }
+
+    /// write the armored public key to path
+    fn export(&self, path: &Path) {
+        std::fs::write(path, self.gpg(&["--armor", "--export"], b"")).unwrap();
+    }
+
+    /// clearsign text
+    fn clearsign(&self, text: &str) -> String {
+        String::from_utf8(self.gpg(&["--clearsign"], text.as_bytes())).unwrap()
+    }
+
+    /// detached armored signature of data
+    fn detach_sign(&self, data: &[u8]) -> Vec<u8> {
+        self.gpg(&["--armor", "--detach-sign"], data)
+    }
+}
+
+/// signed DIGESTS file listing content as name
+fn digests(signer: &Signer, name: &str, content: &[u8]) -> String {
+    let sha512 = {
+        use sha2::Digest;
+        hex::encode(sha2::Sha512::digest(content))
+    };
+    signer.clearsign(&format!("# SHA512 HASH\n{}  {}\n", sha512, name))
+}
+
+/// mount a file on the mock mirror
+async fn serve(mirror: &MockServer, release: &str, content: impl Into<Vec<u8>>) {
+    Mock::given(method("GET"))
+        .and(path(format!("/releases/{}", release)))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(content.into()))
+        .mount(mirror)
+        .await;
+}
+
+/// start a portcache caching releases from mirror
+/// trusting the key of signer
+async fn releases_daemon(mirror: &MockServer, signer: &Signer, extra: &str) -> TestDaemon {
+    let keys = TempDir::new().unwrap();
+    let keyring = keys.path().join("release.asc");
+    signer.export(&keyring);
+
+    let distfiles = mock_mirror().await;
+    TestDaemon::start(
+        &[distfiles.uri()],
+        &format!(
+            "[releases]\nmirrors = [\"{}\"]\nkeyring = \"{}\"\n{}",
+            mirror.uri(),
+            keyring.to_string_lossy(),
+            extra
+        ),
+    )
+    .await
+}
+
+#[test]
+fn digests_prefer_blake2b() {
+    let digests = "# BLAKE2B HASH\nAAAA  foo.tar.xz\nbbbb  foo.tar.xz.CONTENTS.gz\n\
+                   # SHA512 HASH\ncccc  foo.tar.xz\n";
+    assert_eq!(
+        parse_digests(digests, "foo.tar.xz"),
+        Some((HashType::Blake2b, "aaaa".to_string()))
+    );
+    assert_eq!(
+        parse_digests("# SHA512 HASH\ncccc  foo.tar.xz\n", "foo.tar.xz"),
+        Some((HashType::Sha512, "cccc".to_string()))
+    );
+    assert_eq!(parse_digests(digests, "bar.tar.xz"), None);
+}
+
+#[test]
+fn only_the_signed_text_counts() {
+    let message = "-----BEGIN PGP SIGNED MESSAGE-----\nHash: SHA512\n\n\
+                   # SHA512 HASH\n- -- escaped\n\
+                   -----BEGIN PGP SIGNATURE-----\nxyz\n-----END PGP SIGNATURE-----\n\
+                   dddd  foo.tar.xz\n";
+    assert_eq!(
+        signed_text(message),
+        Some("# SHA512 HASH\n-- escaped".to_string())
+    );
+    assert_eq!(signed_text("# SHA512 HASH\ndddd  foo.tar.xz\n"), None);
+}
+
+#[test]
+fn armored_keys_match_their_binary_export() {
+    let Some(signer) = Signer::new() else {
+        eprintln!("gpg not installed, skipping");
+        return;
+    };
+
+    let armored = String::from_utf8(signer.gpg(&["--armor", "--export"], b"")).unwrap();
+    assert_eq!(dearmor(&armored).unwrap(), signer.gpg(&["--export"], b""));
+    assert!(dearmor("no keys here").is_err());
+}
+
+#[rocket::async_test]
+async fn verified_media_is_fetched_then_served_from_cache() {
+    let Some(signer) = Signer::new() else {
+        eprintln!("gpg not installed, skipping");
+        return;
+    };
+    let mirror = MockServer::start().await;
+    let release = format!("{}/{}", STAGE3_DIR, STAGE3_NAME);
+    Mock::given(method("GET"))
+        .and(path(format!("/releases/{}", release)))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(STAGE3))
+        .expect(1)
+        .mount(&mirror)
+        .await;
+    serve(
+        &mirror,
+        &format!("{}.DIGESTS", release),
+        digests(&signer, STAGE3_NAME, STAGE3),
+    )
+    .await;
+    let daemon = releases_daemon(&mirror, &signer, "").await;
+
+    for _ in 0..2 {
+        let response = daemon
+            .client
+            .get(format!("/releases/{}", release))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_bytes().await.unwrap(), STAGE3);
+    }
+}
+
+#[rocket::async_test]
+async fn tampered_media_is_rejected() {
+    let Some(signer) = Signer::new() else {
+        eprintln!("gpg not installed, skipping");
+        return;
+    };
+    let mirror = MockServer::start().await;
+    let release = format!("{}/{}", STAGE3_DIR, STAGE3_NAME);
+    serve(&mirror, &release, b"backdoored stage3\n".to_vec()).await;
+    serve(
+        &mirror,
+        &format!("{}.DIGESTS", release),
+        digests(&signer, STAGE3_NAME, STAGE3),
+    )
+    .await;
+    let daemon = releases_daemon(&mirror, &signer, "").await;
+
+    let response = daemon
+        .client
+        .get(format!("/releases/{}", release))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::BadGateway);
+    assert!(
+        !daemon
+            .storage
+            .path()
+            .join("releases")
+            .join(&release)
+            .exists()
+    );
+}
+
+#[rocket::async_test]
+async fn digests_signed_by_other_keys_are_rejected() {
+    let (Some(signer), Some(attacker)) = (Signer::new(), Signer::new()) else {
+        eprintln!("gpg not installed, skipping");
+        return;
+    };
+    let mirror = MockServer::start().await;
+    let release = format!("{}/{}", STAGE3_DIR, STAGE3_NAME);
+    serve(&mirror, &release, STAGE3).await;
+    serve(
+        &mirror,
+        &format!("{}.DIGESTS", release),
+        digests(&attacker, STAGE3_NAME, STAGE3),
+    )
+    .await;
+    let daemon = releases_daemon(&mirror, &signer, "").await;
+
+    let response = daemon
+        .client
+        .get(format!("/releases/{}", release))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::BadGateway);
+}
+
+#[rocket::async_test]
+async fn detached_signature_is_used_without_digests() {
+    let Some(signer) = Signer::new() else {
+        eprintln!("gpg not installed, skipping");
+        return;
+    };
+    let mirror = MockServer::start().await;
+    let release = "amd64/install-amd64-minimal.iso";
+    serve(&mirror, release, STAGE3).await;
+    serve(
+        &mirror,
+        &format!("{}.asc", release),
+        signer.detach_sign(STAGE3),
+    )
+    .await;
+    let daemon = releases_daemon(&mirror, &signer, "").await;
+
+    let response = daemon
+        .client
+        .get(format!("/releases/{}", release))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().await.unwrap(), STAGE3);
+}
+
+#[rocket::async_test]
+async fn unsigned_media_is_only_served_without_verification() {
+    let Some(signer) = Signer::new() else {
+        eprintln!("gpg not installed, skipping");
+        return;
+    };
+    let mirror = MockServer::start().await;
+    let release = "amd64/install-amd64-minimal.iso";
+    serve(&mirror, release, STAGE3).await;
+
+    let daemon = releases_daemon(&mirror, &signer, "").await;
+    let response = daemon
+        .client
+        .get(format!("/releases/{}", release))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::BadGateway);
+
+    let daemon = releases_daemon(&mirror, &signer, "verify = false\n").await;
+    let response = daemon
+        .client
+        .get(format!("/releases/{}", release))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+}
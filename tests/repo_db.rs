@@ -0,0 +1,632 @@
+mod common;
+
+use common::{TestDaemon, mock_mirror};
+use portcache::config::Config;
+use portcache::ebuild_parser::SrcUri;
+use portcache::manifest_walker::ManifestEntry;
+use portcache::repo_db::{self, Download, DownloadFilter, RepoDB};
+use portcache::snapshot::{self, Snapshotter};
+use portcache::utils::HashType;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tempfile::TempDir;
+
+/// parse a single Manifest line
+fn entry(line: &str) -> ManifestEntry {
+    ManifestEntry::parse(&PathBuf::from("Manifest"), &line.to_string())
+        .unwrap()
+        .unwrap()
+}
+
+#[rocket::async_test]
+async fn large_manifest_sizes_round_trip_through_db() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+
+    let entry = entry("DIST huge-1.0.tar.xz 5368709120 BLAKE2B aa SHA512 bb");
+    daemon
+        .repo_db
+        .insert_manifest_entry("fixture", entry)
+        .await
+        .unwrap();
+
+    let entry = daemon
+        .repo_db
+        .get_manifest_entry("huge-1.0.tar.xz")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(entry.size, 5_368_709_120);
+}
+
+#[rocket::async_test]
+async fn all_manifest_hashes_round_trip_through_db() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+
+    let entry = entry("DIST legacy-1.0.tar.gz 42 RMD160 r160 SHA256 s256 WHIRLPOOL w");
+    daemon
+        .repo_db
+        .insert_manifest_entry("fixture", entry)
+        .await
+        .unwrap();
+
+    let entry = daemon
+        .repo_db
+        .get_manifest_entry("legacy-1.0.tar.gz")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(entry.hashes.len(), 3);
+    assert_eq!(entry.hashes["RMD160"], "r160");
+    assert_eq!(entry.hashes["WHIRLPOOL"], "w");
+    assert_eq!(
+        entry.checksum(),
+        Some((HashType::Sha256, &"s256".to_string()))
+    );
+    assert_eq!(
+        daemon
+            .repo_db
+            .get_checksum("legacy-1.0.tar.gz", HashType::Sha256)
+            .await
+            .unwrap()
+            .as_deref(),
+        Some("s256")
+    );
+}
+
+#[rocket::async_test]
+async fn missing_checksums_are_stored_as_null() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+
+    daemon
+        .repo_db
+        .insert_manifest_entry("fixture", entry("DIST only-b2-1.0.tar.gz 10 BLAKE2B aa"))
+        .await
+        .unwrap();
+
+    let db = &daemon.repo_db;
+    assert_eq!(
+        db.get_checksum("only-b2-1.0.tar.gz", HashType::Blake2b)
+            .await
+            .unwrap()
+            .as_deref(),
+        Some("aa")
+    );
+    assert_eq!(
+        db.get_checksum("only-b2-1.0.tar.gz", HashType::Sha512)
+            .await
+            .unwrap(),
+        None
+    );
+
+    let conn = rusqlite::Connection::open(daemon.storage.path().join("db.sqlite3")).unwrap();
+    let nulls: u32 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM manifest WHERE sha512 IS NULL",
+            (),
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(nulls, 1);
+}
+
+#[rocket::async_test]
+async fn null_strings_are_cleaned_up_by_migration() {
+    let storage = TempDir::new().unwrap();
+
+    // database as written before missing checksums became real NULLs
+    let conn = rusqlite::Connection::open(storage.path().join("db.sqlite3")).unwrap();
+    conn.execute_batch(
+        "CREATE TABLE manifest (
+            file    TEXT PRIMARY KEY NOT NULL,
+            origin  TEXT NOT NULL,
+            size    INTEGER NOT NULL,
+            blake2b TEXT,
+            sha512  TEXT
+        );
+        INSERT INTO manifest VALUES ('old-1.0.tar.gz', 'Manifest', 10, 'aa', 'NULL');
+        INSERT INTO manifest VALUES ('older-1.0.tar.gz', 'Manifest', 10, 'NULL', 'NULL');",
+    )
+    .unwrap();
+    drop(conn);
+
+    let config: Config = toml::from_str(&format!(
+        "[storage]\nlocation = \"{}\"\n\
+         [server]\naddress = \"127.0.0.1\"\nport = 0\n\
+         [repo]\nsync_interval = 60\nrepos = []\n\
+         [fetcher]\nmirrors = []\n",
+        storage.path().to_string_lossy()
+    ))
+    .unwrap();
+    let db = RepoDB::new(&config).unwrap();
+
+    let old = db
+        .get_manifest_entry("old-1.0.tar.gz")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(old.blake2b.as_deref(), Some("aa"));
+    assert_eq!(old.sha512, None);
+
+    let older = db
+        .get_checksum("older-1.0.tar.gz", HashType::Blake2b)
+        .await
+        .unwrap();
+    assert_eq!(older, None);
+}
+
+#[rocket::async_test]
+async fn files_are_found_by_package() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    daemon.load_fixture_manifests().await;
+
+    let files: Vec<String> = daemon
+        .repo_db
+        .get_files_by_package("app-misc/hello")
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.file)
+        .collect();
+    assert_eq!(files, ["hello-1.0.tar.gz", "hello-data-1.0.tar.xz"]);
+
+    // no prefix matches
+    let files = daemon
+        .repo_db
+        .get_files_by_package("misc/hello")
+        .await
+        .unwrap();
+    assert!(files.is_empty());
+}
+
+#[rocket::async_test]
+async fn total_declared_size_sums_all_entries() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    assert_eq!(daemon.repo_db.get_total_declared_size().await.unwrap(), 0);
+
+    daemon.load_fixture_manifests().await;
+    assert_eq!(
+        daemon.repo_db.get_total_declared_size().await.unwrap(),
+        30 + 1024
+    );
+}
+
+/// journal_mode of the database in storage
+fn journal_mode(daemon: &TestDaemon) -> String {
+    rusqlite::Connection::open(daemon.storage.path().join("db.sqlite3"))
+        .unwrap()
+        .query_row("PRAGMA journal_mode", (), |row| row.get(0))
+        .unwrap()
+}
+
+#[rocket::async_test]
+async fn database_uses_wal_by_default() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    assert_eq!(journal_mode(&daemon), "wal");
+}
+
+#[rocket::async_test]
+async fn journal_mode_is_configurable() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(
+        &[mirror.uri()],
+        "[storage.database]\njournal_mode = \"delete\"\nsynchronous = \"full\"",
+    )
+    .await;
+    assert_eq!(journal_mode(&daemon), "delete");
+}
+
+#[rocket::async_test]
+async fn cached_misses_are_invalidated_by_inserts() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    let db = &daemon.repo_db;
+
+    assert!(
+        db.get_manifest_entry("new-1.0.tar.gz")
+            .await
+            .unwrap()
+            .is_none()
+    );
+    assert!(db.get_src_uri("new-1.0.tar.gz").await.unwrap().is_empty());
+
+    db.insert_manifest_entry("fixture", entry("DIST new-1.0.tar.gz 10 BLAKE2B aa"))
+        .await
+        .unwrap();
+    db.insert_src_uri(
+        "new-1.0.tar.gz".to_string(),
+        "https://example.org/new-1.0.tar.gz".to_string(),
+    )
+    .await
+    .unwrap();
+
+    assert!(
+        db.get_manifest_entry("new-1.0.tar.gz")
+            .await
+            .unwrap()
+            .is_some()
+    );
+    assert_eq!(
+        db.get_manifest_repo("new-1.0.tar.gz")
+            .await
+            .unwrap()
+            .as_deref(),
+        Some("fixture")
+    );
+    assert_eq!(
+        db.get_src_uri("new-1.0.tar.gz").await.unwrap(),
+        ["https://example.org/new-1.0.tar.gz"]
+    );
+}
+
+#[rocket::async_test]
+async fn lookups_are_served_from_memory() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    let db = &daemon.repo_db;
+
+    db.insert_manifest_entry("fixture", entry("DIST new-1.0.tar.gz 10 BLAKE2B aa"))
+        .await
+        .unwrap();
+    assert_eq!(
+        db.get_manifest_entry("new-1.0.tar.gz")
+            .await
+            .unwrap()
+            .unwrap()
+            .size,
+        10
+    );
+
+    // changes behind RepoDB's back don't show up while cached
+    rusqlite::Connection::open(daemon.storage.path().join("db.sqlite3"))
+        .unwrap()
+        .execute("UPDATE manifest SET size = 20", ())
+        .unwrap();
+    assert_eq!(
+        db.get_manifest_entry("new-1.0.tar.gz")
+            .await
+            .unwrap()
+            .unwrap()
+            .size,
+        10
+    );
+}
+
+#[rocket::async_test]
+async fn entries_missing_from_last_sweep_are_unreferenced() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    let db = &daemon.repo_db;
+
+    db.insert_manifest_entry("fixture", entry("DIST old-1.0.tar.gz 10 BLAKE2B aa"))
+        .await
+        .unwrap();
+    assert!(
+        db.get_referenced_files()
+            .await
+            .unwrap()
+            .contains("old-1.0.tar.gz")
+    );
+
+    // a later walk of all trees didn't see the entry again
+    let later = portcache::utils::unix_time() + 10;
+    db.finish_tree_sweep(later).await.unwrap();
+    assert!(db.get_referenced_files().await.unwrap().is_empty());
+
+    // inserting again only marks it seen
+    let new = db
+        .insert_manifest_entry("fixture", entry("DIST old-1.0.tar.gz 10 BLAKE2B aa"))
+        .await
+        .unwrap();
+    assert!(!new);
+}
+
+#[rocket::async_test]
+async fn parsed_ebuilds_are_keyed_by_content_hash() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    let path = "/repos/gentoo/app-misc/hello/hello-1.0.ebuild";
+
+    let mut src_uri = HashMap::new();
+    src_uri.insert(
+        "hello-1.0.tar.gz".to_string(),
+        vec![SrcUri {
+            uri: "https://example.org/hello-1.0.tar.gz".to_string(),
+            conditions: vec!["doc".to_string()],
+        }],
+    );
+    daemon
+        .repo_db
+        .set_parsed_ebuild(path, "aaaa", &src_uri)
+        .await
+        .unwrap();
+
+    let cached = daemon
+        .repo_db
+        .get_parsed_ebuild(path, "aaaa")
+        .await
+        .unwrap();
+    assert_eq!(cached, Some(src_uri));
+
+    // a changed ebuild has to be parsed again
+    let changed = daemon
+        .repo_db
+        .get_parsed_ebuild(path, "bbbb")
+        .await
+        .unwrap();
+    assert_eq!(changed, None);
+}
+
+#[rocket::async_test]
+async fn src_uri_conditions_are_recorded() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    daemon.load_fixture_manifests().await;
+
+    daemon
+        .repo_db
+        .insert_conditional_src_uri(
+            "hello-1.0.tar.gz".to_string(),
+            "https://example.org/hello-1.0.tar.gz".to_string(),
+            &["doc".to_string(), "!test".to_string()],
+        )
+        .await
+        .unwrap();
+    daemon
+        .repo_db
+        .insert_src_uri(
+            "hello-1.0.tar.gz".to_string(),
+            "https://mirror.example.org/hello-1.0.tar.gz".to_string(),
+        )
+        .await
+        .unwrap();
+
+    let mut conditions = daemon
+        .repo_db
+        .get_src_uri_conditions("hello-1.0.tar.gz")
+        .await
+        .unwrap();
+    conditions.sort();
+    assert_eq!(
+        conditions,
+        vec![
+            (
+                "https://example.org/hello-1.0.tar.gz".to_string(),
+                vec!["doc".to_string(), "!test".to_string()]
+            ),
+            (
+                "https://mirror.example.org/hello-1.0.tar.gz".to_string(),
+                vec![]
+            ),
+        ]
+    );
+}
+
+#[rocket::async_test]
+async fn parse_failures_reset_when_ebuild_changes() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    let path = "/repos/overlay/app-misc/broken/broken-1.ebuild";
+
+    for _ in 0..3 {
+        daemon
+            .repo_db
+            .record_parse_failure(path, "aaaa", "syntax error", 1000)
+            .await
+            .unwrap();
+    }
+    let failure = daemon
+        .repo_db
+        .get_parse_failure(path)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(failure.attempts, 3);
+
+    daemon
+        .repo_db
+        .record_parse_failure(path, "bbbb", "syntax error", 2000)
+        .await
+        .unwrap();
+    let failure = daemon
+        .repo_db
+        .get_parse_failure(path)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(failure.attempts, 1);
+    assert_eq!(failure.retry_at(), 2000 + 3600);
+
+    daemon.repo_db.clear_parse_failure(path).await.unwrap();
+    assert!(
+        daemon
+            .repo_db
+            .get_parse_failure(path)
+            .await
+            .unwrap()
+            .is_none()
+    );
+}
+
+#[rocket::async_test]
+async fn batched_inserts_report_new_entries() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    let db = &daemon.repo_db;
+
+    db.insert_manifest_entry("fixture", entry("DIST known-1.0.tar.gz 10 BLAKE2B aa"))
+        .await
+        .unwrap();
+
+    let batch = [
+        entry("DIST known-1.0.tar.gz 10 BLAKE2B aa"),
+        entry("DIST fresh-1.0.tar.gz 20 BLAKE2B bb"),
+        entry("DIST fresh-1.0.tar.gz 20 BLAKE2B bb"),
+    ];
+    let inserted = db.insert_manifest_entries("fixture", &batch).await.unwrap();
+    assert_eq!(inserted, vec![false, true, false]);
+
+    let fresh = db
+        .get_manifest_entry("fresh-1.0.tar.gz")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(fresh.size, 20);
+}
+
+#[rocket::async_test]
+async fn snapshot_restores_earlier_state() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    daemon
+        .repo_db
+        .insert_manifest_entry("fixture", entry("DIST kept-1.0.tar.gz 10 BLAKE2B aa"))
+        .await
+        .unwrap();
+
+    let backup = daemon.storage.path().join("backup.sqlite3");
+    daemon.repo_db.backup(&backup).await.unwrap();
+
+    daemon
+        .repo_db
+        .insert_manifest_entry("fixture", entry("DIST later-1.0.tar.gz 10 BLAKE2B bb"))
+        .await
+        .unwrap();
+
+    repo_db::restore(&daemon.config, &backup).unwrap();
+
+    let restored = RepoDB::new(&daemon.config).unwrap();
+    assert!(
+        restored
+            .get_manifest_entry("kept-1.0.tar.gz")
+            .await
+            .unwrap()
+            .is_some()
+    );
+    assert!(
+        restored
+            .get_manifest_entry("later-1.0.tar.gz")
+            .await
+            .unwrap()
+            .is_none()
+    );
+}
+
+#[rocket::async_test]
+async fn corrupt_snapshot_is_not_restored() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+
+    let garbage = daemon.storage.path().join("garbage.sqlite3");
+    std::fs::write(&garbage, b"not a database").unwrap();
+
+    assert!(repo_db::restore(&daemon.config, &garbage).is_err());
+    assert!(daemon.repo_db.self_test().await.is_ok());
+}
+
+#[rocket::async_test]
+async fn old_snapshots_get_pruned() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(
+        &[mirror.uri()],
+        "[storage.database]\nsnapshot_interval = \"1h\"\nsnapshot_keep = 2\n",
+    )
+    .await;
+    let dir = snapshot::snapshot_dir(&daemon.config);
+
+    // older snapshots left by earlier runs
+    std::fs::create_dir_all(&dir).unwrap();
+    for time in [100, 200, 300] {
+        std::fs::write(dir.join(format!("db-{}.sqlite3", time)), b"old").unwrap();
+    }
+    std::fs::write(dir.join("notes.txt"), b"unrelated").unwrap();
+
+    let snapshotter = Snapshotter::new(&daemon.config, daemon.repo_db.clone()).unwrap();
+    let latest = snapshotter.snapshot().await.unwrap();
+
+    let snapshots = snapshot::list(&dir).await.unwrap();
+    assert_eq!(snapshots, vec![dir.join("db-300.sqlite3"), latest]);
+    assert!(dir.join("notes.txt").exists());
+}
+
+#[rocket::async_test]
+async fn download_history_forgets_old_downloads() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    let download = |file: &str, started: u64| Download {
+        id: 0,
+        file: file.to_string(),
+        outcome: String::from("fetched"),
+        fetcher: Some(String::from("Mirror")),
+        source: None,
+        started,
+        duration: 1.5,
+        bytes: Some(42),
+        error: None,
+        request_id: None,
+    };
+
+    let repo_db = &daemon.repo_db;
+    repo_db
+        .insert_download(&download("old-1.0.tar.gz", 1000), 0)
+        .await
+        .unwrap();
+    repo_db
+        .insert_download(&download("new-1.0.tar.gz", 5000), 2000)
+        .await
+        .unwrap();
+
+    let (total, downloads) = repo_db
+        .get_downloads(&DownloadFilter::default(), 10, 0)
+        .await
+        .unwrap();
+    assert_eq!(total, 1);
+    assert_eq!(downloads[0].file, "new-1.0.tar.gz");
+    assert_eq!(downloads[0].bytes, Some(42));
+    assert_eq!(downloads[0].duration, 1.5);
+}
+
+#[rocket::async_test]
+async fn sync_errors_are_rotated_and_expired() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    let db = &daemon.repo_db;
+
+    // repeats only bump the count
+    db.record_sync_error("gentoo", "sync", "timeout", 100, 3)
+        .await
+        .unwrap();
+    db.record_sync_error("gentoo", "sync", "timeout", 200, 3)
+        .await
+        .unwrap();
+    let errors = db.get_sync_errors(Some("gentoo")).await.unwrap();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(
+        (errors[0].first, errors[0].last, errors[0].count),
+        (100, 200, 2)
+    );
+
+    // only the latest are kept per repo
+    for (now, error) in [(300, "a"), (400, "b"), (500, "c")] {
+        db.record_sync_error("gentoo", "sync", error, now, 3)
+            .await
+            .unwrap();
+    }
+    db.record_sync_error("guru", "manifests", "broken", 450, 3)
+        .await
+        .unwrap();
+    let errors = db.get_sync_errors(Some("gentoo")).await.unwrap();
+    let messages: Vec<&str> = errors.iter().map(|e| e.error.as_str()).collect();
+    assert_eq!(messages, ["c", "b", "a"]);
+    assert_eq!(db.get_sync_errors(None).await.unwrap().len(), 4);
+
+    db.expire_sync_errors(420).await.unwrap();
+    let errors = db.get_sync_errors(None).await.unwrap();
+    let messages: Vec<&str> = errors.iter().map(|e| e.error.as_str()).collect();
+    assert_eq!(messages, ["c", "broken"]);
+}
@@ -0,0 +1,572 @@
+mod common;
+
+use common::{
+    HELLO_CONTENT, TestDaemon, copy_fixture_repo, distfile_path, fixture_repo, mock_mirror,
+};
+use portcache::repo_db::RepoDB;
+use portcache::repo_syncer::RepoSyncer;
+use std::time::Duration;
+use tempfile::TempDir;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+/// wait up to timeout for file to show up in the database
+async fn wait_for_repo(repo_db: &RepoDB, file: &str, timeout: Duration) -> Option<String> {
+    let start = std::time::Instant::now();
+    while start.elapsed() < timeout {
+        if let Some(repo) = repo_db.get_manifest_repo(file).await.unwrap() {
+            return Some(repo);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    None
+}
+
+#[rocket::async_test]
+async fn local_repo_is_indexed_in_place() {
+    let extra = format!("[repo]\nrepos = [\"{}\"]", fixture_repo().to_string_lossy());
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], &extra).await;
+
+    let syncer = RepoSyncer::new(
+        &daemon.config,
+        daemon.repo_db.clone(),
+        daemon.sync_progress.clone(),
+    )
+    .await
+    .unwrap();
+    let task = tokio::spawn(syncer.start());
+
+    let repo = wait_for_repo(&daemon.repo_db, "hello-1.0.tar.gz", Duration::from_secs(5)).await;
+    task.abort();
+
+    assert_eq!(repo.as_deref(), Some("repo"));
+
+    // nothing got cloned
+    let clones = daemon.storage.path().join("repos").read_dir().unwrap();
+    assert_eq!(clones.count(), 0);
+}
+
+#[rocket::async_test]
+async fn changed_manifest_is_parsed_without_sync() {
+    let checkout = TempDir::new().unwrap();
+    let root = checkout.path().join("watched");
+    copy_fixture_repo(&root);
+
+    // the interval won't tick a second time during the test
+    let mirror = mock_mirror().await;
+    let extra = format!(
+        "[repo]\nsync_interval = 600\nrepos = [\"{}\"]",
+        root.to_string_lossy()
+    );
+    let daemon = TestDaemon::start(&[mirror.uri()], &extra).await;
+
+    let syncer = RepoSyncer::new(
+        &daemon.config,
+        daemon.repo_db.clone(),
+        daemon.sync_progress.clone(),
+    )
+    .await
+    .unwrap();
+    let task = tokio::spawn(syncer.start());
+
+    let repo = wait_for_repo(&daemon.repo_db, "hello-1.0.tar.gz", Duration::from_secs(5)).await;
+    assert_eq!(repo.as_deref(), Some("watched"));
+
+    let package = root.join("app-misc/added");
+    std::fs::create_dir_all(&package).unwrap();
+    std::fs::write(
+        package.join("Manifest"),
+        "DIST added-1.0.tar.gz 42 BLAKE2B abc SHA512 def\n",
+    )
+    .unwrap();
+
+    let repo = wait_for_repo(&daemon.repo_db, "added-1.0.tar.gz", Duration::from_secs(10)).await;
+    task.abort();
+
+    assert_eq!(repo.as_deref(), Some("watched"));
+}
+
+#[rocket::async_test]
+async fn manifests_in_directories_predating_their_watch_are_parsed() {
+    let checkout = TempDir::new().unwrap();
+    let root = checkout.path().join("watched");
+    copy_fixture_repo(&root);
+
+    let mirror = mock_mirror().await;
+    let extra = format!(
+        "[repo]\nsync_interval = 600\nrepos = [\"{}\"]",
+        root.to_string_lossy()
+    );
+    let daemon = TestDaemon::start(&[mirror.uri()], &extra).await;
+
+    let syncer = RepoSyncer::new(
+        &daemon.config,
+        daemon.repo_db.clone(),
+        daemon.sync_progress.clone(),
+    )
+    .await
+    .unwrap();
+    let task = tokio::spawn(syncer.start());
+
+    let repo = wait_for_repo(&daemon.repo_db, "hello-1.0.tar.gz", Duration::from_secs(5)).await;
+    assert_eq!(repo.as_deref(), Some("watched"));
+
+    // the Manifest is written before the watch on its directory could be set up
+    let staging = checkout.path().join("app-moved");
+    std::fs::create_dir_all(staging.join("moved")).unwrap();
+    std::fs::write(
+        staging.join("moved/Manifest"),
+        "DIST moved-1.0.tar.gz 42 BLAKE2B abc SHA512 def\n",
+    )
+    .unwrap();
+    std::fs::rename(&staging, root.join("app-moved")).unwrap();
+
+    let repo = wait_for_repo(&daemon.repo_db, "moved-1.0.tar.gz", Duration::from_secs(10)).await;
+    task.abort();
+
+    assert_eq!(repo.as_deref(), Some("watched"));
+}
+
+/// turn dir into a git repo with everything committed
+/// on top of the current HEAD if there is one
+fn commit_all(dir: &std::path::Path) {
+    let repo = git2::Repository::init(dir).unwrap();
+    let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+    let mut index = repo.index().unwrap();
+    index
+        .add_all(["*"], git2::IndexAddOption::DEFAULT, None)
+        .unwrap();
+    index.write().unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let sig = git2::Signature::now("portcache", "portcache@localhost").unwrap();
+    let parents: Vec<&git2::Commit> = parent.iter().collect();
+    repo.commit(Some("HEAD"), &sig, &sig, "update", &tree, &parents)
+        .unwrap();
+}
+
+#[rocket::async_test]
+async fn remote_without_shallow_support_is_cloned_in_full() {
+    // libgit2's local transport doesn't support shallow fetches
+    let upstream = TempDir::new().unwrap();
+    let root = upstream.path().join("upstream");
+    copy_fixture_repo(&root);
+    commit_all(&root);
+
+    let mirror = mock_mirror().await;
+    let extra = format!("[repo]\nrepos = [\"file://{}\"]", root.to_string_lossy());
+    let daemon = TestDaemon::start(&[mirror.uri()], &extra).await;
+
+    let syncer = RepoSyncer::new(
+        &daemon.config,
+        daemon.repo_db.clone(),
+        daemon.sync_progress.clone(),
+    )
+    .await
+    .unwrap();
+    let clone = daemon.storage.path().join("repos/upstream");
+    assert!(clone.join("app-misc/hello/Manifest").is_file());
+
+    // syncing falls back to full fetches as well
+    let task = tokio::spawn(syncer.start());
+    let repo = wait_for_repo(&daemon.repo_db, "hello-1.0.tar.gz", Duration::from_secs(5)).await;
+    task.abort();
+
+    assert_eq!(repo.as_deref(), Some("upstream"));
+}
+
+#[rocket::async_test]
+async fn repo_sizes_are_reported() {
+    let mirror = mock_mirror().await;
+    let extra = format!("[repo]\nrepos = [\"{}\"]", fixture_repo().to_string_lossy());
+    let daemon = TestDaemon::start(&[mirror.uri()], &extra).await;
+
+    let syncer = RepoSyncer::new(
+        &daemon.config,
+        daemon.repo_db.clone(),
+        daemon.sync_progress.clone(),
+    )
+    .await
+    .unwrap();
+    let task = tokio::spawn(syncer.start());
+    wait_for_repo(&daemon.repo_db, "hello-1.0.tar.gz", Duration::from_secs(5)).await;
+    task.abort();
+
+    let response = daemon.client.get("/api/v1/stats").dispatch().await;
+    let stats: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+
+    let repo = &stats["repos"][0];
+    assert_eq!(repo["name"], "repo");
+    assert!(repo["checkout_size"].as_u64().unwrap() > 0);
+    assert_eq!(repo["git_size"], 0);
+}
+
+#[rocket::async_test]
+async fn unchanged_remote_skips_indexing() {
+    let upstream = TempDir::new().unwrap();
+    let root = upstream.path().join("upstream");
+    copy_fixture_repo(&root);
+    commit_all(&root);
+
+    let mirror = mock_mirror().await;
+    let extra = format!("[repo]\nrepos = [\"file://{}\"]", root.to_string_lossy());
+    let daemon = TestDaemon::start(&[mirror.uri()], &extra).await;
+    let syncer = RepoSyncer::new(
+        &daemon.config,
+        daemon.repo_db.clone(),
+        daemon.sync_progress.clone(),
+    )
+    .await
+    .unwrap();
+
+    // the fresh clone still has to be indexed once
+    assert!(syncer.sync_and_index().await.unwrap());
+    assert!(!syncer.sync_and_index().await.unwrap());
+
+    let package = root.join("app-misc/added");
+    std::fs::create_dir_all(&package).unwrap();
+    std::fs::write(
+        package.join("Manifest"),
+        "DIST added-1.0.tar.gz 42 BLAKE2B abc SHA512 def\n",
+    )
+    .unwrap();
+    commit_all(&root);
+
+    assert!(syncer.sync_and_index().await.unwrap());
+    assert_eq!(
+        daemon
+            .repo_db
+            .get_manifest_repo("added-1.0.tar.gz")
+            .await
+            .unwrap()
+            .as_deref(),
+        Some("upstream")
+    );
+    assert!(!syncer.sync_and_index().await.unwrap());
+}
+
+#[rocket::async_test]
+async fn failing_repo_does_not_block_others() {
+    let upstream = TempDir::new().unwrap();
+    let good = upstream.path().join("good");
+    copy_fixture_repo(&good);
+    commit_all(&good);
+    // doesn't exist yet so cloning it fails
+    let late = upstream.path().join("late");
+
+    let mirror = mock_mirror().await;
+    let extra = format!(
+        "[repo]\nrepos = [\"file://{}\", \"file://{}\"]",
+        late.to_string_lossy(),
+        good.to_string_lossy()
+    );
+    let daemon = TestDaemon::start(&[mirror.uri()], &extra).await;
+    let syncer = RepoSyncer::new(
+        &daemon.config,
+        daemon.repo_db.clone(),
+        daemon.sync_progress.clone(),
+    )
+    .await
+    .unwrap();
+
+    assert!(syncer.sync_and_index().await.unwrap());
+    assert_eq!(
+        daemon
+            .repo_db
+            .get_manifest_repo("hello-1.0.tar.gz")
+            .await
+            .unwrap()
+            .as_deref(),
+        Some("good")
+    );
+
+    let stats = daemon.repo_db.get_repo_stats().await.unwrap();
+    let late_stats = stats.iter().find(|repo| repo.name == "late").unwrap();
+    assert!(late_stats.sync_error.is_some());
+    assert!(late_stats.last_sync.is_none());
+    let good_stats = stats.iter().find(|repo| repo.name == "good").unwrap();
+    assert!(good_stats.sync_error.is_none());
+    assert!(good_stats.last_sync.is_some());
+
+    // the failed clone is retried on the next sync
+    copy_fixture_repo(&late);
+    commit_all(&late);
+    assert!(syncer.sync_and_index().await.unwrap());
+
+    let stats = daemon.repo_db.get_repo_stats().await.unwrap();
+    let late_stats = stats.iter().find(|repo| repo.name == "late").unwrap();
+    assert!(late_stats.sync_error.is_none());
+    assert!(late_stats.last_sync.is_some());
+    assert!(
+        daemon
+            .storage
+            .path()
+            .join("repos/late/app-misc/hello/Manifest")
+            .is_file()
+    );
+}
+
+#[rocket::async_test]
+async fn repeated_sync_errors_are_counted() {
+    let upstream = TempDir::new().unwrap();
+    let missing = upstream.path().join("missing");
+
+    let mirror = mock_mirror().await;
+    let extra = format!("[repo]\nrepos = [\"file://{}\"]", missing.to_string_lossy());
+    let daemon = TestDaemon::start(&[mirror.uri()], &extra).await;
+    let syncer = RepoSyncer::new(
+        &daemon.config,
+        daemon.repo_db.clone(),
+        daemon.sync_progress.clone(),
+    )
+    .await
+    .unwrap();
+
+    syncer.sync_and_index().await.unwrap();
+    syncer.sync_and_index().await.unwrap();
+
+    let response = daemon
+        .client
+        .get("/api/v1/sync/errors?repo=missing")
+        .dispatch()
+        .await;
+    let body: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    let errors = body["errors"].as_array().unwrap();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0]["repo"], "missing");
+    assert_eq!(errors[0]["phase"], "sync");
+    assert_eq!(errors[0]["count"], 2);
+    assert!(errors[0]["error"].is_string());
+    assert!(errors[0]["last"].as_u64() >= errors[0]["first"].as_u64());
+
+    let response = daemon
+        .client
+        .get("/api/v1/sync/errors?repo=other")
+        .dispatch()
+        .await;
+    let body: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert!(body["errors"].as_array().unwrap().is_empty());
+}
+
+#[rocket::async_test]
+async fn sync_progress_is_reported() {
+    let upstream = TempDir::new().unwrap();
+    let root = upstream.path().join("upstream");
+    copy_fixture_repo(&root);
+    commit_all(&root);
+
+    let mirror = mock_mirror().await;
+    let extra = format!("[repo]\nrepos = [\"file://{}\"]", root.to_string_lossy());
+    let daemon = TestDaemon::start(&[mirror.uri()], &extra).await;
+    let syncer = RepoSyncer::new(
+        &daemon.config,
+        daemon.repo_db.clone(),
+        daemon.sync_progress.clone(),
+    )
+    .await
+    .unwrap();
+
+    let response = daemon.client.get("/api/v1/sync").dispatch().await;
+    let status: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!(status["phase"], "idle");
+    assert!(status["last"].is_null());
+
+    assert!(syncer.sync_and_index().await.unwrap());
+
+    let response = daemon.client.get("/api/v1/sync").dispatch().await;
+    let status: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    let last = &status["last"];
+    assert_eq!(status["phase"], "idle");
+    assert_eq!(last["skipped"], false);
+    assert_eq!(last["counts"]["repos_synced"], 1);
+    assert_eq!(last["counts"]["repos_failed"], 0);
+    assert!(last["counts"]["manifests_parsed"].as_u64().unwrap() > 0);
+    assert!(last["counts"]["entries_changed"].as_u64().unwrap() > 0);
+    assert!(last["finished"].as_u64().unwrap() >= last["started"].as_u64().unwrap());
+
+    let response = daemon.client.get("/metrics").dispatch().await;
+    let metrics = response.into_string().await.unwrap();
+    assert!(metrics.contains("portcache_sync_running 0\n"));
+    assert!(metrics.contains("portcache_sync_last_repos_synced 1\n"));
+
+    // nothing changed upstream so the next cycle only syncs
+    assert!(!syncer.sync_and_index().await.unwrap());
+    let last = daemon.sync_progress.status().last.unwrap();
+    assert!(last.skipped);
+    assert_eq!(last.counts.repos_synced, 1);
+    assert_eq!(last.counts.manifests_parsed, 0);
+}
+
+#[rocket::async_test]
+async fn new_distfiles_are_prefetched_within_budget() {
+    let upstream = TempDir::new().unwrap();
+    let root = upstream.path().join("upstream");
+    copy_fixture_repo(&root);
+    commit_all(&root);
+
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .expect(0)
+        .mount(&mirror)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-2.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .expect(1)
+        .mount(&mirror)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("huge-2.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&mirror)
+        .await;
+
+    let extra = format!(
+        "[repo]\nrepos = [\"file://{}\"]\nprefetch_budget = \"1KiB\"",
+        root.to_string_lossy()
+    );
+    let daemon = TestDaemon::start(&[mirror.uri()], &extra).await;
+    let syncer = RepoSyncer::new(
+        &daemon.config,
+        daemon.repo_db.clone(),
+        daemon.sync_progress.clone(),
+    )
+    .await
+    .unwrap()
+    .prefetch_into(daemon.blob_storage.clone());
+
+    // everything is new to the initial index so nothing gets prefetched
+    assert!(syncer.sync_and_index().await.unwrap());
+    assert!(!daemon.blob_path("hello-1.0.tar.gz").exists());
+
+    // entries are dated by the second
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let manifest = root.join("app-misc/hello/Manifest");
+    let mut content = std::fs::read_to_string(&manifest).unwrap();
+    let hashes = content
+        .lines()
+        .next()
+        .unwrap()
+        .split_once(" 30 ")
+        .unwrap()
+        .1
+        .to_string();
+    content.push_str(&format!("DIST hello-2.0.tar.gz 30 {}\n", hashes));
+    content.push_str(&format!("DIST huge-2.0.tar.gz 4096 {}\n", hashes));
+    std::fs::write(&manifest, content).unwrap();
+    commit_all(&root);
+
+    assert!(syncer.sync_and_index().await.unwrap());
+    assert_eq!(
+        std::fs::read(daemon.blob_path("hello-2.0.tar.gz")).unwrap(),
+        HELLO_CONTENT
+    );
+    assert!(!daemon.blob_path("huge-2.0.tar.gz").exists());
+
+    let since = daemon.sync_progress.status().last.unwrap().started;
+    let new: Vec<String> = daemon
+        .repo_db
+        .get_new_distfiles(since)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|distfile| distfile.file)
+        .collect();
+    assert_eq!(new, ["hello-2.0.tar.gz", "huge-2.0.tar.gz"]);
+}
+
+#[rocket::async_test]
+async fn overlay_without_layout_conf_is_indexed_when_relaxed() {
+    let upstream = TempDir::new().unwrap();
+    let strict = upstream.path().join("strict");
+    let relaxed = upstream.path().join("relaxed");
+    for root in [&strict, &relaxed] {
+        copy_fixture_repo(root);
+        std::fs::remove_file(root.join("metadata/layout.conf")).unwrap();
+    }
+    std::fs::rename(
+        relaxed.join("app-misc/hello"),
+        relaxed.join("app-misc/relaxed"),
+    )
+    .unwrap();
+    std::fs::write(
+        relaxed.join("app-misc/relaxed/Manifest"),
+        "DIST relaxed-1.0.tar.gz 42 BLAKE2B abc SHA512 def\n",
+    )
+    .unwrap();
+
+    let mirror = mock_mirror().await;
+    let extra = format!(
+        "[repo]\nrepos = [\"{}\", {{ url = \"{}\", layout_conf = \"warn\" }}]",
+        strict.to_string_lossy(),
+        relaxed.to_string_lossy()
+    );
+    let daemon = TestDaemon::start(&[mirror.uri()], &extra).await;
+    let syncer = RepoSyncer::new(
+        &daemon.config,
+        daemon.repo_db.clone(),
+        daemon.sync_progress.clone(),
+    )
+    .await
+    .unwrap();
+    syncer.sync_and_index().await.unwrap();
+
+    assert_eq!(
+        daemon
+            .repo_db
+            .get_manifest_repo("relaxed-1.0.tar.gz")
+            .await
+            .unwrap()
+            .as_deref(),
+        Some("relaxed")
+    );
+    assert!(
+        daemon
+            .repo_db
+            .get_manifest_repo("hello-1.0.tar.gz")
+            .await
+            .unwrap()
+            .is_none()
+    );
+}
+
+#[rocket::async_test]
+async fn masked_packages_are_skipped_when_asked() {
+    let upstream = TempDir::new().unwrap();
+    let root = upstream.path().join("masked");
+    copy_fixture_repo(&root);
+    std::fs::write(root.join("profiles/package.mask"), "app-misc/hello\n").unwrap();
+
+    let mirror = mock_mirror().await;
+    for skip_masked in [false, true] {
+        let extra = format!(
+            "[repo]\nrepos = [\"{}\"]\nskip_masked = {}",
+            root.to_string_lossy(),
+            skip_masked
+        );
+        let daemon = TestDaemon::start(&[mirror.uri()], &extra).await;
+        let syncer = RepoSyncer::new(
+            &daemon.config,
+            daemon.repo_db.clone(),
+            daemon.sync_progress.clone(),
+        )
+        .await
+        .unwrap();
+        syncer.sync_and_index().await.unwrap();
+
+        let repo = daemon
+            .repo_db
+            .get_manifest_repo("hello-1.0.tar.gz")
+            .await
+            .unwrap();
+        assert_eq!(repo.is_none(), skip_masked);
+    }
+}
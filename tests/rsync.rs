@@ -0,0 +1,74 @@
+mod common;
+
+use common::*;
+use portcache::rsync::Rsyncd;
+
+#[rocket::async_test]
+async fn rsync_is_disabled_by_default() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+
+    assert!(Rsyncd::new(&daemon.config).is_none());
+}
+
+#[rocket::async_test]
+async fn rsyncd_conf_exports_the_blob_storage() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(
+        &[mirror.uri()],
+        "[rsync]\nenabled = true\nport = 8873\nmodule = \"gentoo-distfiles\"\n\
+         max_connections = 4\n\n[server]\nuser = \"portage\"\n",
+    )
+    .await;
+    let rsyncd = Rsyncd::new(&daemon.config).unwrap();
+    let conf = rsyncd.rsyncd_conf();
+
+    assert!(conf.contains("address = 127.0.0.1\n"));
+    assert!(conf.contains("port = 8873\n"));
+    assert!(conf.contains("\n[gentoo-distfiles]\n"));
+    assert!(conf.contains(&format!(
+        "path = {}\n",
+        daemon.storage.path().join("distfiles").to_string_lossy()
+    )));
+    assert!(conf.contains("read only = yes\n"));
+    assert!(conf.contains("max connections = 4\n"));
+    assert!(conf.contains("exclude = /.portcache-probe *.part *.stale\n"));
+    assert!(conf.contains("uid = portage\n"));
+    assert!(!conf.contains("gid ="));
+}
+
+#[rocket::async_test]
+async fn unspawned_rsync_only_writes_config() {
+    let mirror = mock_mirror().await;
+    let daemon =
+        TestDaemon::start(&[mirror.uri()], "[rsync]\nenabled = true\nspawn = false\n").await;
+    let rsyncd = Rsyncd::new(&daemon.config).unwrap();
+
+    assert!(rsyncd.spawn().unwrap().is_none());
+    assert_eq!(
+        rsyncd.config_path(),
+        daemon.storage.path().join("rsyncd.conf")
+    );
+    assert_eq!(
+        std::fs::read_to_string(rsyncd.config_path()).unwrap(),
+        rsyncd.rsyncd_conf()
+    );
+    assert_eq!(
+        std::fs::read_to_string(daemon.storage.path().join("distfiles/layout.conf")).unwrap(),
+        LAYOUT_CONF
+    );
+}
+
+#[rocket::async_test]
+async fn missing_rsync_binary_fails_startup() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(
+        &[mirror.uri()],
+        "[rsync]\nenabled = true\nbinary = \"/nonexistent/rsync\"\n",
+    )
+    .await;
+    let rsyncd = Rsyncd::new(&daemon.config).unwrap();
+
+    let error = rsyncd.spawn().unwrap_err();
+    assert!(error.contains("/nonexistent/rsync"), "{}", error);
+}
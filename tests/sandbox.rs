@@ -0,0 +1,70 @@
+use portcache::config::Config;
+use portcache::rsync::Rsyncd;
+use portcache::sandbox;
+use tempfile::TempDir;
+
+#[test]
+fn sandbox_restricts_writes_to_storage() {
+    let storage = TempDir::new().unwrap();
+    let outside = TempDir::new().unwrap();
+
+    let config: Config = toml::from_str(&format!(
+        "[storage]\nlocation = \"{}\"\n\
+         [server]\naddress = \"127.0.0.1\"\nport = 0\n\
+         [repo]\nsync_interval = 60\nrepos = []\n\
+         [fetcher]\nmirrors = []\n\
+         [sandbox]\nenabled = true\n",
+        storage.path().to_string_lossy()
+    ))
+    .unwrap();
+
+    // landlock restricts the calling thread for good
+    // so keep it away from the test harness
+    let inside_path = storage.path().join("inside");
+    let outside_path = outside.path().join("outside");
+    let (enforced, inside, outside_written) = std::thread::spawn(move || {
+        let enforced = sandbox::apply(&config).unwrap();
+        (
+            enforced,
+            std::fs::write(&inside_path, "ok").is_ok(),
+            std::fs::write(&outside_path, "nope").is_ok(),
+        )
+    })
+    .join()
+    .unwrap();
+
+    assert!(inside);
+    if enforced {
+        assert!(!outside_written);
+    }
+}
+
+#[test]
+fn sandbox_allows_writing_the_rsync_config() {
+    let storage = TempDir::new().unwrap();
+    let etc = TempDir::new().unwrap();
+    let rsyncd_conf = etc.path().join("rsyncd.conf");
+    std::fs::create_dir(storage.path().join("distfiles")).unwrap();
+
+    let config: Config = toml::from_str(&format!(
+        "[storage]\nlocation = \"{}\"\n\
+         [server]\naddress = \"127.0.0.1\"\nport = 0\n\
+         [repo]\nsync_interval = 60\nrepos = []\n\
+         [fetcher]\nmirrors = []\n\
+         [rsync]\nenabled = true\nspawn = false\nconfig = \"{}\"\n\
+         [sandbox]\nenabled = true\n",
+        storage.path().to_string_lossy(),
+        rsyncd_conf.to_string_lossy()
+    ))
+    .unwrap();
+
+    let written = std::thread::spawn(move || {
+        sandbox::apply(&config).unwrap();
+        Rsyncd::new(&config).unwrap().write_config()
+    })
+    .join()
+    .unwrap();
+
+    assert_eq!(written, Ok(()));
+    assert!(rsyncd_conf.is_file());
+}
@@ -0,0 +1,26 @@
+use portcache::app::Deps;
+use portcache::config::Config;
+use tempfile::TempDir;
+
+#[rocket::async_test]
+async fn unwritable_blob_storage_fails_startup() {
+    let storage = TempDir::new().unwrap();
+    // a file in place of the blob directory can't be written into
+    // even when running as root
+    std::fs::write(storage.path().join("distfiles"), "").unwrap();
+
+    let config: Config = toml::from_str(&format!(
+        "[storage]\nlocation = \"{}\"\n\
+         [server]\naddress = \"127.0.0.1\"\nport = 0\n\
+         [repo]\nsync_interval = 60\nrepos = []\n\
+         [fetcher]\nmirrors = [\"http://127.0.0.1:1\"]\n",
+        storage.path().to_string_lossy()
+    ))
+    .unwrap();
+
+    let error = match Deps::new(&config).await {
+        Ok(_) => panic!("startup succeeded on unwritable storage"),
+        Err(e) => e,
+    };
+    assert!(error.contains("Blob storage self-test failed"), "{}", error);
+}
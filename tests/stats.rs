@@ -0,0 +1,257 @@
+mod common;
+
+use common::{HELLO_CONTENT, TestDaemon, distfile_path, mock_mirror};
+use rocket::http::Status;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+#[rocket::async_test]
+async fn version_reports_build_info() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+
+    let response = daemon.client.get("/api/v1/version").dispatch().await;
+    let version: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+
+    assert_eq!(version["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(version["git_commit"], portcache::GIT_COMMIT);
+    assert!(version["features"].is_array());
+    // a fresh database has every migration applied
+    assert!(version["schema_version"].as_u64().unwrap() > 0);
+    assert_eq!(
+        version["schema_version"].as_u64().unwrap(),
+        daemon.repo_db.schema_version().await.unwrap() as u64
+    );
+}
+
+#[rocket::async_test]
+async fn cached_reports_which_files_are_cached() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    daemon.load_fixture_manifests().await;
+    daemon.store_blob("hello-1.0.tar.gz", HELLO_CONTENT);
+
+    let response = daemon
+        .client
+        .post("/api/v1/cached")
+        .body(r#"["hello-1.0.tar.gz", "hello-data-1.0.tar.xz", "unknown-1.0.tar.gz", "../escape"]"#)
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let cached: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!(cached["cached"], 1);
+    assert_eq!(cached["cached_size"], HELLO_CONTENT.len() as u64);
+
+    let files = cached["files"].as_array().unwrap();
+    assert_eq!(files[0]["cached"], true);
+    assert_eq!(files[0]["stale"], false);
+    assert_eq!(files[0]["size"], HELLO_CONTENT.len() as u64);
+    assert!(files[0]["blake2b"].is_string());
+    // not cached, sized by its Manifest entry
+    assert_eq!(files[1]["cached"], false);
+    assert_eq!(files[1]["size"], 1024);
+    assert_eq!(files[2]["cached"], false);
+    assert_eq!(files[2]["size"], serde_json::Value::Null);
+    assert!(files[3]["error"].is_string());
+
+    let response = daemon
+        .client
+        .post("/api/v1/cached")
+        .body(r#"{"files": []}"#)
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::BadRequest);
+}
+
+#[rocket::async_test]
+async fn export_lists_cached_blobs() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    daemon.load_fixture_manifests().await;
+    daemon.store_blob("hello-1.0.tar.gz", HELLO_CONTENT);
+    daemon.store_blob("odd,name-1.0.tar.gz", b"odd");
+
+    let entry = daemon
+        .repo_db
+        .get_manifest_entry("hello-1.0.tar.gz")
+        .await
+        .unwrap()
+        .unwrap();
+
+    let response = daemon.client.get("/api/v1/export").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let export: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    let blobs = export["blobs"].as_array().unwrap();
+    assert_eq!(blobs.len(), 2);
+    let hello = blobs
+        .iter()
+        .find(|blob| blob["file"] == "hello-1.0.tar.gz")
+        .unwrap();
+    assert_eq!(hello["size"], HELLO_CONTENT.len() as u64);
+    assert_eq!(hello["blake2b"].as_str(), entry.blake2b.as_deref());
+    assert_eq!(hello["sha512"].as_str(), entry.sha512.as_deref());
+    assert!(hello["modified"].as_u64().unwrap() > 0);
+    assert!(hello["accessed"].as_u64().unwrap() > 0);
+
+    let response = daemon
+        .client
+        .get("/api/v1/export?format=csv")
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let csv = response.into_string().await.unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines[0], "file,size,blake2b,sha512,modified,accessed");
+    assert!(lines.iter().any(|line| line.starts_with(&format!(
+        "hello-1.0.tar.gz,{},{},{},",
+        HELLO_CONTENT.len(),
+        entry.blake2b.as_deref().unwrap(),
+        entry.sha512.as_deref().unwrap()
+    ))));
+    assert!(
+        lines
+            .iter()
+            .any(|line| line.starts_with("\"odd,name-1.0.tar.gz\",3,,,"))
+    );
+
+    let response = daemon
+        .client
+        .get("/api/v1/export?format=xml")
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::BadRequest);
+}
+
+#[rocket::async_test]
+async fn sync_new_lists_recently_added_distfiles() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+
+    // no sync cycle ran yet
+    let response = daemon.client.get("/api/v1/sync/new").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let new: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!(new["distfiles"].as_array().unwrap().len(), 0);
+
+    daemon.load_fixture_manifests().await;
+    let response = daemon
+        .client
+        .get("/api/v1/sync/new?since=1")
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let new: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!(new["since"], 1);
+    assert_eq!(new["size"], 30 + 1024);
+    let hello = new["distfiles"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|distfile| distfile["file"] == "hello-1.0.tar.gz")
+        .unwrap();
+    assert_eq!(hello["size"], 30);
+    assert_eq!(hello["repo"], "fixture");
+    assert!(hello["added"].as_u64().unwrap() > 0);
+
+    let response = daemon
+        .client
+        .get(format!("/api/v1/sync/new?since={}", u64::MAX / 2))
+        .dispatch()
+        .await;
+    let new: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!(new["distfiles"].as_array().unwrap().len(), 0);
+}
+
+#[rocket::async_test]
+async fn status_drains_nodes_with_full_disks() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+
+    let response = daemon.client.get("/status").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let status: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!(status["healthy"], true);
+    assert_eq!(status["downloads"]["running"], 0);
+    assert_eq!(status["downloads"]["queued"], 0);
+    assert!(status["disk"]["total"].as_u64().unwrap() > 0);
+    assert_eq!(status["sync"]["phase"], "idle");
+
+    // no disk has this much room left
+    let daemon = TestDaemon::start(&[mirror.uri()], "[server]\nstatus_min_free = \"1000TB\"").await;
+    let response = daemon.client.get("/status").dispatch().await;
+    assert_eq!(response.status(), Status::ServiceUnavailable);
+    let status: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    assert_eq!(status["healthy"], false);
+    assert_eq!(status["disk"]["min_free"], 1_000_000_000_000_000u64);
+}
+
+#[rocket::async_test]
+async fn upstream_of_cached_blobs_is_reported() {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .mount(&mirror)
+        .await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    daemon.store_blob("manual-1.0.tar.gz", b"manual");
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let response = daemon
+        .client
+        .post("/api/v1/cached")
+        .body(r#"["hello-1.0.tar.gz", "manual-1.0.tar.gz"]"#)
+        .dispatch()
+        .await;
+    let cached: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    let upstream = &cached["files"][0]["upstream"];
+    assert_eq!(upstream["fetcher"], "Mirror");
+    assert_eq!(
+        upstream["source"].as_str(),
+        Some(format!("{}{}", mirror.uri(), distfile_path("hello-1.0.tar.gz")).as_str())
+    );
+    assert!(upstream["fetched"].as_u64().unwrap() > 0);
+    // put there by hand, nobody knows
+    assert!(cached["files"][1]["upstream"].is_null());
+
+    let response = daemon.client.get("/api/v1/export").dispatch().await;
+    let export: serde_json::Value =
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+    let hello = export["blobs"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|blob| blob["file"] == "hello-1.0.tar.gz")
+        .unwrap();
+    assert_eq!(&hello["upstream"], upstream);
+
+    // forgotten along with the blob
+    assert!(
+        daemon
+            .blob_storage
+            .remove("hello-1.0.tar.gz")
+            .await
+            .unwrap()
+    );
+    let sources = daemon
+        .repo_db
+        .get_blob_sources(&[String::from("hello-1.0.tar.gz")])
+        .await
+        .unwrap();
+    assert!(sources.is_empty());
+}
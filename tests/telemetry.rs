@@ -0,0 +1,200 @@
+mod common;
+
+use common::{HELLO_CONTENT, TestDaemon, distfile_path, mock_mirror};
+use rocket::http::{Header, Status};
+use serde_json::Value;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+const PARENT_ID: &str = "00f067aa0ba902b7";
+
+/// a collector accepting OTLP/HTTP exports
+async fn mock_collector() -> MockServer {
+    let collector = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/traces"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&collector)
+        .await;
+    collector
+}
+
+/// all spans the collector received
+async fn exported_spans(collector: &MockServer) -> Vec<Value> {
+    let mut spans = Vec::new();
+    for request in collector.received_requests().await.unwrap() {
+        let body: Value = serde_json::from_slice(&request.body).unwrap();
+        for resource in body["resourceSpans"].as_array().unwrap() {
+            assert!(
+                resource["resource"]["attributes"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .any(|attribute| attribute["key"] == "service.name"
+                        && attribute["value"]["stringValue"] == "portcache")
+            );
+            for scope in resource["scopeSpans"].as_array().unwrap() {
+                spans.extend(scope["spans"].as_array().unwrap().iter().cloned());
+            }
+        }
+    }
+    spans
+}
+
+/// the span named name
+fn named<'a>(spans: &'a [Value], name: &str) -> &'a Value {
+    spans
+        .iter()
+        .find(|span| span["name"] == name)
+        .unwrap_or_else(|| panic!("no span {} in {:?}", name, spans))
+}
+
+#[rocket::async_test]
+async fn fetch_is_traced_end_to_end() {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .mount(&mirror)
+        .await;
+    let collector = mock_collector().await;
+
+    let daemon = TestDaemon::start(
+        &[mirror.uri()],
+        &format!("[telemetry]\notlp_endpoint = \"{}\"", collector.uri()),
+    )
+    .await;
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .header(Header::new(
+            "traceparent",
+            format!("00-{}-{}-01", TRACE_ID, PARENT_ID),
+        ))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let tracer = daemon.tracer.as_ref().unwrap();
+    assert!(tracer.export().await.unwrap() >= 4);
+    let spans = exported_spans(&collector).await;
+    assert!(spans.iter().all(|span| span["traceId"] == TRACE_ID));
+
+    // request -> blob storage -> fetcher -> upstream
+    let server = named(&spans, "GET /distfiles/<digest>/<file>");
+    assert_eq!(server["parentSpanId"], PARENT_ID);
+    assert_eq!(server["kind"], 2);
+    let storage = named(&spans, "blob_storage.request");
+    assert_eq!(storage["parentSpanId"], server["spanId"]);
+    let fetch = named(&spans, "fetch mirror");
+    assert_eq!(fetch["parentSpanId"], storage["spanId"]);
+    let upstream = spans
+        .iter()
+        .find(|span| {
+            span["name"] == "GET"
+                && span["parentSpanId"] == fetch["spanId"]
+                && span["attributes"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .any(|attribute| {
+                        attribute["key"] == "url.full"
+                            && attribute["value"]["stringValue"]
+                                .as_str()
+                                .unwrap()
+                                .ends_with("hello-1.0.tar.gz")
+                    })
+        })
+        .unwrap();
+    assert_eq!(upstream["kind"], 3);
+    assert_eq!(upstream["status"]["code"], 0);
+
+    // upstream got the client span as parent
+    let fetched = mirror
+        .received_requests()
+        .await
+        .unwrap()
+        .into_iter()
+        .find(|request| request.url.path().ends_with("hello-1.0.tar.gz"))
+        .unwrap();
+    assert_eq!(
+        fetched
+            .headers
+            .get("traceparent")
+            .unwrap()
+            .to_str()
+            .unwrap(),
+        format!(
+            "00-{}-{}-01",
+            TRACE_ID,
+            upstream["spanId"].as_str().unwrap()
+        )
+    );
+
+    // cache hits only get the request and storage spans
+    collector.reset().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/traces"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&collector)
+        .await;
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(tracer.export().await.unwrap(), 2);
+    let spans = exported_spans(&collector).await;
+    let storage = named(&spans, "blob_storage.request");
+    assert!(
+        storage["attributes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|attribute| attribute["key"] == "portcache.cache_hit"
+                && attribute["value"]["boolValue"] == true)
+    );
+    let server = named(&spans, "GET /distfiles/<digest>/<file>");
+    assert_eq!(server["parentSpanId"], "");
+    assert_ne!(server["traceId"], TRACE_ID);
+}
+
+#[rocket::async_test]
+async fn unsampled_requests_are_not_traced() {
+    let mirror = mock_mirror().await;
+    let collector = mock_collector().await;
+    let daemon = TestDaemon::start(
+        &[mirror.uri()],
+        &format!(
+            "[telemetry]\notlp_endpoint = \"{}\"\nsample_ratio = 0.0",
+            collector.uri()
+        ),
+    )
+    .await;
+    daemon.store_blob("hello-1.0.tar.gz", HELLO_CONTENT);
+
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+
+    // callers which don't sample aren't traced either
+    let response = daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .header(Header::new(
+            "traceparent",
+            format!("00-{}-{}-00", TRACE_ID, PARENT_ID),
+        ))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let tracer = daemon.tracer.as_ref().unwrap();
+    assert_eq!(tracer.export().await.unwrap(), 0);
+    assert!(collector.received_requests().await.unwrap().is_empty());
+}
@@ -0,0 +1,280 @@
+mod common;
+
+use common::{HELLO_CONTENT, TestDaemon, distfile_path, mock_mirror};
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509, X509NameBuilder};
+use rocket::http::Status;
+use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt};
+use rocket::tokio::net::TcpListener;
+use std::path::PathBuf;
+use tempfile::TempDir;
+use tokio_native_tls::TlsAcceptor;
+use tokio_native_tls::native_tls::{self, Identity};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+/// an HTTPS upstream serving HELLO_CONTENT for every path
+/// with a freshly generated self-signed certificate for localhost
+struct HttpsUpstream {
+    /// url of the upstream
+    url: String,
+
+    /// the certificate it serves
+    certificate: X509,
+
+    /// holds ca.pem with the certificate
+    dir: TempDir,
+}
+
+impl HttpsUpstream {
+    async fn start() -> Self {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns("localhost")
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(san).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        let certificate = builder.build();
+
+        let identity = Identity::from_pkcs8(
+            &certificate.to_pem().unwrap(),
+            &key.private_key_to_pem_pkcs8().unwrap(),
+        )
+        .unwrap();
+        let acceptor = TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "https://localhost:{}",
+            listener.local_addr().unwrap().port()
+        );
+        rocket::tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                rocket::tokio::spawn(async move {
+                    // clients refusing the certificate fail the handshake
+                    let Ok(mut stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let head = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        HELLO_CONTENT.len()
+                    );
+                    let _ = stream.write_all(head.as_bytes()).await;
+                    let _ = stream.write_all(HELLO_CONTENT).await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+
+        let dir = TempDir::new().unwrap();
+        std::fs::write(dir.path().join("ca.pem"), certificate.to_pem().unwrap()).unwrap();
+
+        Self {
+            url,
+            certificate,
+            dir,
+        }
+    }
+
+    /// PEM file with the certificate
+    fn ca_bundle(&self) -> PathBuf {
+        self.dir.path().join("ca.pem")
+    }
+
+    /// fingerprint of the certificate as printed by openssl x509 -fingerprint
+    fn fingerprint(&self) -> String {
+        let digest = self.certificate.digest(MessageDigest::sha256()).unwrap();
+        digest
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<String>>()
+            .join(":")
+    }
+
+    /// start a daemon fetching from this upstream as its only proxy
+    ///
+    /// @param tls  contents of [fetcher.tls]
+    async fn daemon(&self, tls: &str) -> TestDaemon {
+        let extra = format!(
+            "[fetcher]\nchain = [\"proxy\"]\nproxies = [\"{}\"]\n\n[fetcher.tls]\n{}",
+            self.url, tls
+        );
+        TestDaemon::start(&[], &extra).await
+    }
+}
+
+/// status of requesting hello-1.0.tar.gz from daemon
+async fn fetch_status(daemon: &TestDaemon) -> Status {
+    daemon
+        .client
+        .get(distfile_path("hello-1.0.tar.gz"))
+        .dispatch()
+        .await
+        .status()
+}
+
+#[rocket::async_test]
+async fn custom_ca_bundle_is_trusted() {
+    let upstream = HttpsUpstream::start().await;
+
+    let daemon = upstream.daemon("").await;
+    assert_eq!(fetch_status(&daemon).await, Status::NotFound);
+
+    let tls = format!("ca_bundle = \"{}\"", upstream.ca_bundle().to_string_lossy());
+    let daemon = upstream.daemon(&tls).await;
+    assert_eq!(fetch_status(&daemon).await, Status::Ok);
+    assert_eq!(
+        std::fs::read(daemon.blob_path("hello-1.0.tar.gz")).unwrap(),
+        HELLO_CONTENT
+    );
+}
+
+#[rocket::async_test]
+async fn insecure_hosts_skip_verification() {
+    let upstream = HttpsUpstream::start().await;
+
+    let daemon = upstream.daemon("insecure_hosts = [\"example.org\"]").await;
+    assert_eq!(fetch_status(&daemon).await, Status::NotFound);
+
+    let daemon = upstream.daemon("insecure_hosts = [\"localhost\"]").await;
+    assert_eq!(fetch_status(&daemon).await, Status::Ok);
+}
+
+#[rocket::async_test]
+async fn pinned_hosts_reject_other_certificates() {
+    let upstream = HttpsUpstream::start().await;
+    let ca_bundle = format!("ca_bundle = \"{}\"", upstream.ca_bundle().to_string_lossy());
+
+    let other = "00".repeat(32);
+    let tls = format!(
+        "{}\n[fetcher.tls.pins]\nlocalhost = [\"{}\"]",
+        ca_bundle, other
+    );
+    let daemon = upstream.daemon(&tls).await;
+    assert_eq!(fetch_status(&daemon).await, Status::NotFound);
+
+    let tls = format!(
+        "{}\n[fetcher.tls.pins]\nlocalhost = [\"{}\", \"{}\"]",
+        ca_bundle,
+        other,
+        upstream.fingerprint()
+    );
+    let daemon = upstream.daemon(&tls).await;
+    assert_eq!(fetch_status(&daemon).await, Status::Ok);
+
+    // pins still apply to hosts which aren't verified
+    let tls = format!(
+        "insecure_hosts = [\"localhost\"]\n[fetcher.tls.pins]\nlocalhost = [\"{}\"]",
+        other
+    );
+    let daemon = upstream.daemon(&tls).await;
+    assert_eq!(fetch_status(&daemon).await, Status::NotFound);
+}
+
+/// start a daemon fetching hello-1.0.tar.gz only from SRC_URI uri
+///
+/// @param uri  the SRC_URI
+/// @param tls  contents of [fetcher.tls]
+async fn src_uri_daemon(uri: &str, tls: &str) -> TestDaemon {
+    let extra = format!("[fetcher]\nchain = [\"src_uri\"]\n\n[fetcher.tls]\n{}", tls);
+    let daemon = TestDaemon::start(&[], &extra).await;
+    daemon.load_fixture_manifests().await;
+    daemon
+        .repo_db
+        .insert_src_uri("hello-1.0.tar.gz".to_string(), uri.to_string())
+        .await
+        .unwrap();
+    daemon
+}
+
+#[rocket::async_test]
+async fn plain_http_src_uris_are_refused_unless_allowed() {
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .mount(&upstream)
+        .await;
+    let uri = format!("{}/hello-1.0.tar.gz", upstream.uri());
+
+    let daemon = src_uri_daemon(&uri, "allow_http = false").await;
+    assert_eq!(fetch_status(&daemon).await, Status::NotFound);
+    assert!(upstream.received_requests().await.unwrap().is_empty());
+
+    let daemon = src_uri_daemon(&uri, "allow_http = false\nhttp_hosts = [\"127.0.0.1\"]").await;
+    assert_eq!(fetch_status(&daemon).await, Status::Ok);
+}
+
+#[rocket::async_test]
+async fn http_src_uris_get_upgraded_to_https() {
+    let upstream = HttpsUpstream::start().await;
+    let uri = format!(
+        "{}/hello-1.0.tar.gz",
+        upstream.url.replace("https://", "http://")
+    );
+
+    let tls = format!(
+        "ca_bundle = \"{}\"\nallow_http = false\nupgrade_http = true",
+        upstream.ca_bundle().to_string_lossy()
+    );
+    let daemon = src_uri_daemon(&uri, &tls).await;
+    assert_eq!(fetch_status(&daemon).await, Status::Ok);
+    assert_eq!(
+        std::fs::read(daemon.blob_path("hello-1.0.tar.gz")).unwrap(),
+        HELLO_CONTENT
+    );
+}
+
+#[rocket::async_test]
+async fn upgraded_mirrors_fall_back_to_http() {
+    let mirror = mock_mirror().await;
+    Mock::given(method("GET"))
+        .and(path(distfile_path("hello-1.0.tar.gz")))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(HELLO_CONTENT))
+        .mount(&mirror)
+        .await;
+
+    // the mock mirror doesn't speak TLS
+    let daemon = TestDaemon::start(&[mirror.uri()], "[fetcher.tls]\nupgrade_http = true").await;
+    assert_eq!(fetch_status(&daemon).await, Status::Ok);
+
+    let extra = format!(
+        "[fetcher]\nmirrors = [{{ url = \"{}\", upgrade_http = true }}]\n\n\
+         [fetcher.tls]\nallow_http = false",
+        mirror.uri()
+    );
+    let daemon = TestDaemon::start(&[], &extra).await;
+    assert_eq!(fetch_status(&daemon).await, Status::NotFound);
+}
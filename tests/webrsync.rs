@@ -0,0 +1,150 @@
+mod common;
+
+use common::{TestDaemon, copy_fixture_repo, mock_mirror};
+use portcache::repo_syncer::RepoSyncer;
+use portcache::webrsync::Webrsync;
+use rocket::http::Status;
+use std::process::Command;
+use tempfile::TempDir;
+
+#[rocket::async_test]
+async fn snapshots_are_served_for_webrsync() {
+    let upstream = TempDir::new().unwrap();
+    let root = upstream.path().join("tree");
+    copy_fixture_repo(&root);
+    std::fs::create_dir_all(root.join(".git")).unwrap();
+    std::fs::write(root.join(".git/HEAD"), "ref: refs/heads/master\n").unwrap();
+
+    let mirror = mock_mirror().await;
+    let extra = format!(
+        "[repo]\nrepos = [\"{}\"]\n[webrsync]\nkeep = 1",
+        root.to_string_lossy()
+    );
+    let daemon = TestDaemon::start(&[mirror.uri()], &extra).await;
+    let syncer = RepoSyncer::new(
+        &daemon.config,
+        daemon.repo_db.clone(),
+        daemon.sync_progress.clone(),
+    )
+    .await
+    .unwrap();
+    assert!(syncer.sync_and_index().await.unwrap());
+
+    let date = Command::new("date")
+        .args(["-u", "+%Y%m%d"])
+        .output()
+        .unwrap();
+    let dated = format!(
+        "tree-{}.tar.gz",
+        String::from_utf8_lossy(&date.stdout).trim()
+    );
+    let snapshots = daemon.storage.path().join("snapshots");
+    for file in [&dated, "tree-latest.tar.gz"] {
+        let response = daemon
+            .client
+            .get(format!("/snapshots/{}", file))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert!(!response.into_bytes().await.unwrap().is_empty());
+
+        let response = daemon
+            .client
+            .get(format!("/snapshots/{}.md5sum", file))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert!(
+            response
+                .into_string()
+                .await
+                .unwrap()
+                .ends_with(&format!("  {}\n", file))
+        );
+
+        let check = Command::new("md5sum")
+            .args(["-c", &format!("{}.md5sum", file)])
+            .current_dir(&snapshots)
+            .output()
+            .unwrap();
+        assert!(check.status.success());
+    }
+
+    // the tree sits in a directory named after the repo, without the git metadata
+    let listing = Command::new("tar")
+        .arg("-tzf")
+        .arg(snapshots.join("tree-latest.tar.gz"))
+        .output()
+        .unwrap();
+    let listing = String::from_utf8_lossy(&listing.stdout);
+    assert!(
+        listing
+            .lines()
+            .any(|line| line == "tree/app-misc/hello/Manifest")
+    );
+    assert!(!listing.contains(".git"));
+
+    // the next cycle is within the interval so nothing gets rewritten
+    let modified = std::fs::metadata(snapshots.join(&dated))
+        .unwrap()
+        .modified()
+        .unwrap();
+    syncer.sync_and_index().await.unwrap();
+    assert_eq!(
+        std::fs::metadata(snapshots.join(&dated))
+            .unwrap()
+            .modified()
+            .unwrap(),
+        modified
+    );
+
+    let response = daemon
+        .client
+        .get("/snapshots/missing.tar.gz")
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[rocket::async_test]
+async fn old_snapshots_are_pruned() {
+    let upstream = TempDir::new().unwrap();
+    let root = upstream.path().join("tree");
+    copy_fixture_repo(&root);
+
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "[webrsync]\ninterval = 0\nkeep = 2").await;
+    let snapshots = daemon.storage.path().join("snapshots");
+    std::fs::create_dir_all(&snapshots).unwrap();
+    for date in ["20200101", "20200102"] {
+        std::fs::write(snapshots.join(format!("tree-{}.tar.gz", date)), "old").unwrap();
+        std::fs::write(
+            snapshots.join(format!("tree-{}.tar.gz.md5sum", date)),
+            "old",
+        )
+        .unwrap();
+    }
+    std::fs::write(snapshots.join("other-20200101.tar.gz"), "old").unwrap();
+
+    let webrsync = Webrsync::new(&daemon.config).unwrap();
+    assert!(webrsync.snapshot("tree", &root).await.unwrap());
+    assert!(webrsync.snapshot("tree", &root).await.unwrap());
+
+    assert!(!snapshots.join("tree-20200101.tar.gz").exists());
+    assert!(!snapshots.join("tree-20200101.tar.gz.md5sum").exists());
+    assert!(snapshots.join("tree-20200102.tar.gz").exists());
+    assert!(snapshots.join("other-20200101.tar.gz").exists());
+    assert!(snapshots.join("tree-latest.tar.gz").exists());
+}
+
+#[rocket::async_test]
+async fn snapshots_are_not_served_without_webrsync() {
+    let mirror = mock_mirror().await;
+    let daemon = TestDaemon::start(&[mirror.uri()], "").await;
+    let response = daemon
+        .client
+        .get("/snapshots/gentoo-latest.tar.gz")
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+}